@@ -0,0 +1,207 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use criterion::measurement::WallTime;
+use criterion::{
+    criterion_group, criterion_main, BatchSize, BenchmarkGroup, BenchmarkId, Criterion,
+};
+use vm_device::bus::{
+    AccessSize, BTreeStorage, Bus, IntervalTreeStorage, MmioAddress, MmioOffset, MmioRange,
+    SmallBus, SortedVecStorage, Storage,
+};
+use vm_device::device_manager::{IoManager, MmioManager};
+use vm_device::DeviceMmio;
+
+const RANGE_SIZE: u64 = 0x1000;
+const DEVICE_COUNTS: [u64; 3] = [10, 100, 1000];
+const THREAD_COUNTS: [usize; 3] = [1, 2, 4];
+const SMALL_COUNTS: [u64; 3] = [2, 4, 8];
+
+// Device with a single register, which counts the writes it receives.
+#[derive(Default)]
+struct CounterDevice {
+    count: AtomicU64,
+}
+
+impl DeviceMmio for CounterDevice {
+    fn mmio_read(&self, _base: MmioAddress, _offset: MmioOffset, data: &mut [u8]) {
+        let count = self.count.load(Ordering::Relaxed).to_le_bytes();
+        let len = data.len().min(count.len());
+        data[..len].copy_from_slice(&count[..len]);
+    }
+
+    fn mmio_write(&self, _base: MmioAddress, _offset: MmioOffset, _data: &[u8]) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn range(idx: u64) -> MmioRange {
+    MmioRange::new(MmioAddress(idx * RANGE_SIZE), RANGE_SIZE).unwrap()
+}
+
+// Build a manager with `count` adjacent devices.
+fn io_manager(count: u64) -> IoManager {
+    let mut io_mgr = IoManager::new();
+    for idx in 0..count {
+        io_mgr
+            .register_mmio(range(idx), Arc::new(CounterDevice::default()))
+            .unwrap();
+    }
+    io_mgr
+}
+
+fn bench_mmio_read(c: &mut Criterion) {
+    let mut group = c.benchmark_group("mmio_read");
+    for count in DEVICE_COUNTS.iter() {
+        let io_mgr = io_manager(*count);
+        // Hit the device in the middle of the map, so lookups don't take a shortcut.
+        let addr = MmioAddress(count / 2 * RANGE_SIZE + 8);
+        group.bench_with_input(BenchmarkId::from_parameter(count), &addr, |b, addr| {
+            let mut data = [0u8; 4];
+            b.iter(|| io_mgr.mmio_read(*addr, &mut data).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn bench_small_bus(c: &mut Criterion) {
+    let mut group = c.benchmark_group("mmio_read_small");
+    for count in SMALL_COUNTS.iter() {
+        let io_mgr = io_manager(*count);
+        let mut small_bus = SmallBus::<MmioAddress, Arc<CounterDevice>, 8>::new();
+        for idx in 0..*count {
+            small_bus
+                .register_mmio(range(idx), Arc::new(CounterDevice::default()))
+                .unwrap();
+        }
+        // The last device is the worst case for the linear search of the small bus.
+        let addr = MmioAddress((count - 1) * RANGE_SIZE + 8);
+        group.bench_with_input(BenchmarkId::new("io_manager", count), &addr, |b, addr| {
+            let mut data = [0u8; 4];
+            b.iter(|| io_mgr.mmio_read(*addr, &mut data).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("small_bus", count), &addr, |b, addr| {
+            let mut data = [0u8; 4];
+            b.iter(|| small_bus.mmio_read(*addr, &mut data).unwrap())
+        });
+    }
+    group.finish();
+}
+
+// Measure the lookups of a bus of `count` devices using the storage `S`, named `name`.
+fn bench_lookup<S: Storage>(group: &mut BenchmarkGroup<'_, WallTime>, name: &str, count: u64) {
+    let mut bus = Bus::<MmioAddress, CounterDevice, S>::default();
+    for idx in 0..count {
+        bus.register(range(idx), CounterDevice::default()).unwrap();
+    }
+    let addr = MmioAddress(count / 2 * RANGE_SIZE + 8);
+    group.bench_with_input(BenchmarkId::new(name, count), &addr, |b, addr| {
+        b.iter(|| bus.access(*addr, 4).unwrap().count.load(Ordering::Relaxed))
+    });
+}
+
+fn bench_storage(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bus_lookup");
+    for count in DEVICE_COUNTS.iter() {
+        bench_lookup::<SortedVecStorage>(&mut group, "sorted_vec", *count);
+        bench_lookup::<BTreeStorage>(&mut group, "btree", *count);
+        bench_lookup::<IntervalTreeStorage>(&mut group, "interval_tree", *count);
+    }
+    group.finish();
+}
+
+fn bench_access_size(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bus_access_size");
+    let mut bus = Bus::<MmioAddress, CounterDevice>::new();
+    for idx in 0..100 {
+        bus.register(range(idx), CounterDevice::default()).unwrap();
+    }
+    let addr = MmioAddress(50 * RANGE_SIZE + 8);
+    // Lengths validated by each lookup, and ahead of time.
+    group.bench_function("usize", |b| {
+        b.iter(|| bus.access(addr, 4).unwrap().count.load(Ordering::Relaxed))
+    });
+    let size = AccessSize::new(4).unwrap();
+    group.bench_function("precomputed", |b| {
+        b.iter(|| {
+            bus.access(addr, size)
+                .unwrap()
+                .count
+                .load(Ordering::Relaxed)
+        })
+    });
+    group.finish();
+}
+
+fn bench_contention(c: &mut Criterion) {
+    let mut group = c.benchmark_group("mmio_write_threads");
+    let io_mgr = Arc::new(io_manager(100));
+    for threads in THREAD_COUNTS.iter() {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(threads),
+            threads,
+            |b, &threads| {
+                // Every thread performs the requested number of writes to the same device,
+                // and the slowest one determines the reported time.
+                b.iter_custom(|iters| {
+                    let barrier = Arc::new(Barrier::new(threads));
+                    let handles: Vec<_> = (0..threads)
+                        .map(|_| {
+                            let io_mgr = io_mgr.clone();
+                            let barrier = barrier.clone();
+                            thread::spawn(move || {
+                                barrier.wait();
+                                let start = Instant::now();
+                                for _ in 0..iters {
+                                    io_mgr.mmio_write(MmioAddress(0), &[1]).unwrap();
+                                }
+                                start.elapsed()
+                            })
+                        })
+                        .collect();
+                    handles
+                        .into_iter()
+                        .map(|h| h.join().unwrap())
+                        .max()
+                        .unwrap_or(Duration::from_secs(0))
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+fn bench_register(c: &mut Criterion) {
+    let mut group = c.benchmark_group("register_mmio");
+    for count in DEVICE_COUNTS.iter() {
+        group.bench_with_input(BenchmarkId::from_parameter(count), count, |b, &count| {
+            let device = Arc::new(CounterDevice::default());
+            // Measure the registration of one more device into a map of `count` devices.
+            b.iter_batched(
+                || io_manager(count),
+                |mut io_mgr| {
+                    io_mgr.register_mmio(range(count), device.clone()).unwrap();
+                    io_mgr
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_mmio_read,
+    bench_small_bus,
+    bench_storage,
+    bench_access_size,
+    bench_contention,
+    bench_register
+);
+criterion_main!(benches);
@@ -0,0 +1,561 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Derive macros generating `vm-device` device models from a register map.
+//!
+//! The registers are fields of the device, holding one of the cells of
+//! `vm_device::registers` (`ReadWrite`, `ReadOnly`, `WriteClear`, `W1C`), and are described
+//! by a `#[register(offset = .., size = .., <access>)]` attribute, where `<access>` is one of
+//! `rw`, `ro`, `wc` and `w1c`, and must match the cell type. The attribute also accepts a
+//! `default = <expr>` value (zero otherwise), and a `mask = <expr>` of the bits writable by
+//! the guest for `rw` registers. The size must match the one of the cell, which is checked
+//! at compile time. The byte order of the registers is little endian, unless the struct is
+//! marked with `#[registers(big_endian)]`.
+//!
+//! `#[derive(MmioDevice)]` and `#[derive(PioDevice)]` implement `RegisterMap`, `Default`
+//! (with the default register values, and `Default::default()` for the other fields), and
+//! respectively `DeviceMmio` or `DevicePio`.
+//!
+//! `#[derive(DeviceEnum)]` forwards the device traits of an enum to its variants, which hold
+//! a single device each, so the enum can be the device type of a `TypedIoManager`. Variants
+//! are marked with `#[device(pio)]`, `#[device(mmio)]`, or both, depending on the traits
+//! their device implements. Accesses which reach a variant not implementing the trait read
+//! as all ones, and their writes are ignored, as for unclaimed bus cycles.
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::{Literal, TokenStream as TokenStream2};
+use quote::{quote, quote_spanned};
+use syn::spanned::Spanned;
+use syn::{parse_macro_input, Data, DeriveInput, Error, Expr, Fields, Ident, LitInt, Variant};
+
+struct RegisterField {
+    ident: Ident,
+    offset: u64,
+    size: u64,
+    // Offset of the last byte of the register.
+    last: u64,
+    access: Ident,
+    default: Option<Expr>,
+    mask: Option<Expr>,
+}
+
+// Parse the `#[register(..)]` attribute of `ident`.
+fn parse_register(ident: &Ident, attr: &syn::Attribute) -> syn::Result<RegisterField> {
+    let (mut offset, mut size, mut access) = (None, None, None);
+    let (mut default, mut mask) = (None, None);
+    attr.parse_nested_meta(|meta| {
+        let name = meta
+            .path
+            .get_ident()
+            .ok_or_else(|| meta.error("unsupported register property"))?;
+        match name.to_string().as_str() {
+            "offset" => offset = Some(meta.value()?.parse::<LitInt>()?.base10_parse()?),
+            "size" => size = Some(meta.value()?.parse::<LitInt>()?.base10_parse()?),
+            "default" => default = Some(meta.value()?.parse::<Expr>()?),
+            "mask" => mask = Some(meta.value()?.parse::<Expr>()?),
+            "rw" | "ro" | "wc" | "w1c" => {
+                if access.is_some() {
+                    return Err(meta.error("the register access is already set"));
+                }
+                access = Some(name.clone());
+            }
+            _ => return Err(meta.error("unsupported register property")),
+        }
+        Ok(())
+    })?;
+    let missing = |what| Error::new(attr.span(), format!("the register {} is missing", what));
+    let access = access.ok_or_else(|| missing("access"))?;
+    if mask.is_some() && access != "rw" {
+        return Err(Error::new(
+            access.span(),
+            "only rw registers have a writable mask",
+        ));
+    }
+    let offset: u64 = offset.ok_or_else(|| missing("offset"))?;
+    let size: u64 = size.ok_or_else(|| missing("size"))?;
+    if size == 0 || size > 8 || !size.is_power_of_two() {
+        return Err(Error::new(
+            attr.span(),
+            "the register size must be 1, 2, 4 or 8",
+        ));
+    }
+    let last = offset
+        .checked_add(size - 1)
+        .ok_or_else(|| Error::new(attr.span(), "the register ends past the last offset"))?;
+    Ok(RegisterField {
+        ident: ident.clone(),
+        offset,
+        size,
+        last,
+        access,
+        default,
+        mask,
+    })
+}
+
+// Generate the `RegisterMap` and `Default` implementations shared by both derives.
+fn register_map(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let mut big_endian = false;
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|a| a.path().is_ident("registers"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("big_endian") {
+                big_endian = true;
+                Ok(())
+            } else {
+                Err(meta.error("unsupported registers property"))
+            }
+        })?;
+    }
+    let endianness = if big_endian {
+        quote!(::vm_device::registers::Endianness::Big)
+    } else {
+        quote!(::vm_device::registers::Endianness::Little)
+    };
+
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => return Err(Error::new(input.span(), "expected named fields")),
+        },
+        _ => return Err(Error::new(input.span(), "expected a struct")),
+    };
+
+    let mut registers: Vec<RegisterField> = Vec::new();
+    let mut defaults = Vec::new();
+    let mut checks = Vec::new();
+    let mut sizes = Vec::new();
+    for field in fields.iter() {
+        let ident = field.ident.as_ref().unwrap();
+        let ty = &field.ty;
+        let attr = match field.attrs.iter().find(|a| a.path().is_ident("register")) {
+            Some(attr) => attr,
+            None => {
+                defaults.push(quote!(#ident: ::std::default::Default::default()));
+                continue;
+            }
+        };
+        let reg = parse_register(ident, attr)?;
+        if let Some(other) = registers
+            .iter()
+            .find(|r| reg.offset <= r.last && r.offset <= reg.last)
+        {
+            return Err(Error::new(
+                attr.span(),
+                format!("the register overlaps {}", other.ident),
+            ));
+        }
+
+        let value = reg.default.clone().map_or(quote!(0), |d| quote!(#d));
+        let mask = reg.mask.as_ref().map(|m| quote!(.with_mask(#m)));
+        defaults.push(quote!(#ident: <#ty>::new(#value) #mask));
+        let cell = match reg.access.to_string().as_str() {
+            "rw" => quote!(ReadWrite),
+            "ro" => quote!(ReadOnly),
+            "wc" => quote!(WriteClear),
+            _ => quote!(W1C),
+        };
+        checks.push(quote!(let _: &::vm_device::registers::#cell<_> = &dev.#ident;));
+        let size = reg.size as usize;
+        let message = format!("the size of register `{}` doesn't match its type", ident);
+        sizes.push(quote_spanned! {attr.span()=>
+            assert!(
+                <#ty as ::vm_device::registers::RegisterCell>::SIZE == #size,
+                #message
+            )
+        });
+        registers.push(reg);
+    }
+
+    let lookups = registers.iter().map(|reg| {
+        let ident = &reg.ident;
+        let offset = Literal::u64_suffixed(reg.offset);
+        let size = Literal::u64_suffixed(reg.size);
+        quote! {
+            if offset >= #offset && offset - #offset < #size {
+                return Some((#offset, &self.#ident));
+            }
+        }
+    });
+    // Check the declared sizes once the types are known, which for generic structs is when
+    // `register_at` is instantiated.
+    let (size_checks, generic_size_checks) = if input.generics.params.is_empty() {
+        (quote!(#(const _: () = #sizes;)*), quote!())
+    } else {
+        (quote!(), quote!(#(const { #sizes };)*))
+    };
+    let resets = registers.iter().map(|reg| {
+        let ident = &reg.ident;
+        let value = reg.default.clone().map_or(quote!(0), |d| quote!(#d));
+        quote!(::vm_device::registers::Register::reset(&self.#ident, (#value) as u64);)
+    });
+
+    Ok(quote! {
+        impl #impl_generics ::vm_device::registers::RegisterMap for #name #ty_generics #where_clause {
+            const ENDIANNESS: ::vm_device::registers::Endianness = #endianness;
+
+            fn register_at(
+                &self,
+                offset: u64,
+            ) -> ::std::option::Option<(u64, &dyn ::vm_device::registers::Register)> {
+                #generic_size_checks
+                #(#lookups)*
+                None
+            }
+
+            fn reset_registers(&self) {
+                #(#resets)*
+            }
+        }
+
+        impl #impl_generics ::std::default::Default for #name #ty_generics #where_clause {
+            fn default() -> Self {
+                #name {
+                    #(#defaults,)*
+                }
+            }
+        }
+
+        const _: () = {
+            // Check the register cells match the declared accesses.
+            #[allow(dead_code)]
+            fn check #impl_generics (dev: &#name #ty_generics) #where_clause {
+                #(#checks)*
+            }
+
+            #size_checks
+        };
+    })
+}
+
+/// Implement `DeviceMmio`, `RegisterMap` and `Default` for a struct whose fields are the
+/// registers of the device.
+#[proc_macro_derive(MmioDevice, attributes(register, registers))]
+pub fn derive_mmio_device(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let map = match register_map(&input) {
+        Ok(map) => map,
+        Err(e) => return e.to_compile_error().into(),
+    };
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    quote!(
+        #map
+
+        impl #impl_generics ::vm_device::DeviceMmio for #name #ty_generics #where_clause {
+            fn mmio_read(&self, _base: ::vm_device::bus::MmioAddress, offset: ::vm_device::bus::MmioOffset, data: &mut [u8]) {
+                ::vm_device::registers::read_registers(
+                    <Self as ::vm_device::registers::RegisterMap>::ENDIANNESS,
+                    |offset| ::vm_device::registers::RegisterMap::register_at(self, offset),
+                    offset.raw(),
+                    data,
+                )
+            }
+
+            fn mmio_write(&self, _base: ::vm_device::bus::MmioAddress, offset: ::vm_device::bus::MmioOffset, data: &[u8]) {
+                ::vm_device::registers::write_registers(
+                    <Self as ::vm_device::registers::RegisterMap>::ENDIANNESS,
+                    |offset| ::vm_device::registers::RegisterMap::register_at(self, offset),
+                    offset.raw(),
+                    data,
+                    |_| {},
+                )
+            }
+        }
+    )
+    .into()
+}
+
+/// Implement `DevicePio`, `RegisterMap` and `Default` for a struct whose fields are the
+/// registers of the device.
+#[proc_macro_derive(PioDevice, attributes(register, registers))]
+pub fn derive_pio_device(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let map = match register_map(&input) {
+        Ok(map) => map,
+        Err(e) => return e.to_compile_error().into(),
+    };
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    quote!(
+        #map
+
+        impl #impl_generics ::vm_device::DevicePio for #name #ty_generics #where_clause {
+            fn pio_read(
+                &self,
+                _base: ::vm_device::bus::PioAddress,
+                offset: ::vm_device::bus::PioOffset,
+                data: &mut [u8],
+            ) {
+                ::vm_device::registers::read_registers(
+                    <Self as ::vm_device::registers::RegisterMap>::ENDIANNESS,
+                    |offset| ::vm_device::registers::RegisterMap::register_at(self, offset),
+                    u64::from(offset),
+                    data,
+                )
+            }
+
+            fn pio_write(
+                &self,
+                _base: ::vm_device::bus::PioAddress,
+                offset: ::vm_device::bus::PioOffset,
+                data: &[u8],
+            ) {
+                ::vm_device::registers::write_registers(
+                    <Self as ::vm_device::registers::RegisterMap>::ENDIANNESS,
+                    |offset| ::vm_device::registers::RegisterMap::register_at(self, offset),
+                    u64::from(offset),
+                    data,
+                    |_| {},
+                )
+            }
+        }
+    )
+    .into()
+}
+
+// Parse the `#[device(..)]` attribute of `variant`, and return whether its device
+// implements `DevicePio` and `DeviceMmio`.
+fn parse_variant(variant: &Variant) -> syn::Result<(bool, bool)> {
+    match &variant.fields {
+        Fields::Unnamed(fields) if fields.unnamed.len() == 1 => {}
+        _ => {
+            return Err(Error::new(
+                variant.span(),
+                "expected a variant holding a single device",
+            ))
+        }
+    }
+    let (mut pio, mut mmio) = (false, false);
+    for attr in variant.attrs.iter().filter(|a| a.path().is_ident("device")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("pio") {
+                pio = true;
+            } else if meta.path.is_ident("mmio") {
+                mmio = true;
+            } else {
+                return Err(meta.error("unsupported device property"));
+            }
+            Ok(())
+        })?;
+    }
+    if !pio && !mmio {
+        return Err(Error::new(
+            variant.span(),
+            "the variant needs a #[device(pio)] or #[device(mmio)] attribute",
+        ));
+    }
+    Ok((pio, mmio))
+}
+
+// Generate the body of a trait method of `DeviceEnum`, which evaluates `call` with the device
+// of the `forwarded` variants bound to `dev`, and evaluates `unclaimed` for the other ones.
+fn forward(forwarded: &[&Ident], call: TokenStream2, unclaimed: TokenStream2) -> TokenStream2 {
+    quote! {
+        match self {
+            #(Self::#forwarded(dev) => #call,)*
+            #[allow(unreachable_patterns)]
+            _ => #unclaimed,
+        }
+    }
+}
+
+fn device_enum(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let variants = match &input.data {
+        Data::Enum(data) => &data.variants,
+        _ => return Err(Error::new(input.span(), "expected an enum")),
+    };
+    let (mut pio, mut mmio) = (Vec::new(), Vec::new());
+    for variant in variants.iter() {
+        let (is_pio, is_mmio) = parse_variant(variant)?;
+        if is_pio {
+            pio.push(&variant.ident);
+        }
+        if is_mmio {
+            mmio.push(&variant.ident);
+        }
+    }
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let fill = quote!(data.fill(0xff));
+    let ignore = quote!({});
+    let mut impls = TokenStream2::new();
+    if !pio.is_empty() {
+        let dev_trait = quote!(::vm_device::DevicePio);
+        let read = forward(
+            &pio,
+            quote!(#dev_trait::pio_read(dev, base, offset, data)),
+            fill.clone(),
+        );
+        let write = forward(
+            &pio,
+            quote!(#dev_trait::pio_write(dev, base, offset, data)),
+            ignore.clone(),
+        );
+        let read_with = forward(
+            &pio,
+            quote!(#dev_trait::pio_read_with(
+                dev, access, base, offset, data
+            )),
+            fill.clone(),
+        );
+        let write_with = forward(
+            &pio,
+            quote!(#dev_trait::pio_write_with(
+                dev, access, base, offset, data
+            )),
+            ignore.clone(),
+        );
+        let try_read = forward(
+            &pio,
+            quote!(#dev_trait::try_pio_read(
+                dev, access, base, offset, data
+            )),
+            quote!({
+                #fill;
+                Ok(())
+            }),
+        );
+        let try_write = forward(
+            &pio,
+            quote!(#dev_trait::try_pio_write(
+                dev, access, base, offset, data
+            )),
+            quote!(Ok(())),
+        );
+        let as_any = forward(&pio, quote!(#dev_trait::as_any(dev)), quote!(None));
+        impls.extend(quote! {
+            impl #impl_generics ::vm_device::DevicePio for #name #ty_generics #where_clause {
+                fn pio_read(&self, base: ::vm_device::bus::PioAddress, offset: ::vm_device::bus::PioOffset, data: &mut [u8]) {
+                    #read
+                }
+
+                fn pio_write(&self, base: ::vm_device::bus::PioAddress, offset: ::vm_device::bus::PioOffset, data: &[u8]) {
+                    #write
+                }
+
+                fn pio_read_with(&self, access: ::vm_device::IoAccess, base: ::vm_device::bus::PioAddress, offset: ::vm_device::bus::PioOffset, data: &mut [u8]) {
+                    #read_with
+                }
+
+                fn pio_write_with(&self, access: ::vm_device::IoAccess, base: ::vm_device::bus::PioAddress, offset: ::vm_device::bus::PioOffset, data: &[u8]) {
+                    #write_with
+                }
+
+                fn try_pio_read(&self, access: ::vm_device::IoAccess, base: ::vm_device::bus::PioAddress, offset: ::vm_device::bus::PioOffset, data: &mut [u8]) -> ::std::result::Result<(), ::vm_device::poison::DeviceError> {
+                    #try_read
+                }
+
+                fn try_pio_write(&self, access: ::vm_device::IoAccess, base: ::vm_device::bus::PioAddress, offset: ::vm_device::bus::PioOffset, data: &[u8]) -> ::std::result::Result<(), ::vm_device::poison::DeviceError> {
+                    #try_write
+                }
+
+                fn as_any(&self) -> ::std::option::Option<&dyn ::std::any::Any> {
+                    #as_any
+                }
+            }
+        });
+    }
+    if !mmio.is_empty() {
+        let dev_trait = quote!(::vm_device::DeviceMmio);
+        let read = forward(
+            &mmio,
+            quote!(#dev_trait::mmio_read(dev, base, offset, data)),
+            fill.clone(),
+        );
+        let write = forward(
+            &mmio,
+            quote!(#dev_trait::mmio_write(dev, base, offset, data)),
+            ignore.clone(),
+        );
+        let read_with = forward(
+            &mmio,
+            quote!(#dev_trait::mmio_read_with(
+                dev, access, base, offset, data
+            )),
+            fill.clone(),
+        );
+        let write_with = forward(
+            &mmio,
+            quote!(#dev_trait::mmio_write_with(
+                dev, access, base, offset, data
+            )),
+            ignore,
+        );
+        let try_read = forward(
+            &mmio,
+            quote!(#dev_trait::try_mmio_read(
+                dev, access, base, offset, data
+            )),
+            quote!({
+                #fill;
+                Ok(())
+            }),
+        );
+        let try_write = forward(
+            &mmio,
+            quote!(#dev_trait::try_mmio_write(
+                dev, access, base, offset, data
+            )),
+            quote!(Ok(())),
+        );
+        let zero_copy = forward(&mmio, quote!(#dev_trait::as_zero_copy(dev)), quote!(None));
+        let as_any = forward(&mmio, quote!(#dev_trait::as_any(dev)), quote!(None));
+        impls.extend(quote! {
+            impl #impl_generics ::vm_device::DeviceMmio for #name #ty_generics #where_clause {
+                fn mmio_read(&self, base: ::vm_device::bus::MmioAddress, offset: ::vm_device::bus::MmioOffset, data: &mut [u8]) {
+                    #read
+                }
+
+                fn mmio_write(&self, base: ::vm_device::bus::MmioAddress, offset: ::vm_device::bus::MmioOffset, data: &[u8]) {
+                    #write
+                }
+
+                fn mmio_read_with(&self, access: ::vm_device::IoAccess, base: ::vm_device::bus::MmioAddress, offset: ::vm_device::bus::MmioOffset, data: &mut [u8]) {
+                    #read_with
+                }
+
+                fn mmio_write_with(&self, access: ::vm_device::IoAccess, base: ::vm_device::bus::MmioAddress, offset: ::vm_device::bus::MmioOffset, data: &[u8]) {
+                    #write_with
+                }
+
+                fn try_mmio_read(&self, access: ::vm_device::IoAccess, base: ::vm_device::bus::MmioAddress, offset: ::vm_device::bus::MmioOffset, data: &mut [u8]) -> ::std::result::Result<(), ::vm_device::poison::DeviceError> {
+                    #try_read
+                }
+
+                fn try_mmio_write(&self, access: ::vm_device::IoAccess, base: ::vm_device::bus::MmioAddress, offset: ::vm_device::bus::MmioOffset, data: &[u8]) -> ::std::result::Result<(), ::vm_device::poison::DeviceError> {
+                    #try_write
+                }
+
+                fn as_zero_copy(&self) -> ::std::option::Option<&dyn ::vm_device::DeviceMmioZeroCopy> {
+                    #zero_copy
+                }
+
+                fn as_any(&self) -> ::std::option::Option<&dyn ::std::any::Any> {
+                    #as_any
+                }
+            }
+        });
+    }
+    Ok(impls)
+}
+
+/// Implement `DevicePio` and `DeviceMmio` for an enum of devices, by forwarding the accesses
+/// to the device of the variant.
+#[proc_macro_derive(DeviceEnum, attributes(device))]
+pub fn derive_device_enum(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match device_enum(&input) {
+        Ok(impls) => impls.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
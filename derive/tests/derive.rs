@@ -0,0 +1,119 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+use vm_device::bus::{MmioAddress, MmioOffset, PioAddress, PioOffset};
+use vm_device::registers::{ReadOnly, ReadWrite, RegisterMap, W1C};
+use vm_device::{DeviceEnum, DeviceMmio, DevicePio, MmioDevice, PioDevice};
+
+#[derive(MmioDevice)]
+struct Timer {
+    #[register(offset = 0x0, size = 4, rw, default = 0x10, mask = 0xff)]
+    ctrl: ReadWrite<u32>,
+    #[register(offset = 0x4, size = 4, ro, default = 0x5449_4d52)]
+    id: ReadOnly<u32>,
+    #[register(offset = 0x8, size = 2, w1c)]
+    status: W1C<u16>,
+    ticks: u64,
+}
+
+#[derive(PioDevice)]
+#[registers(big_endian)]
+struct Port {
+    #[register(offset = 0x1, size = 2, rw)]
+    value: ReadWrite<u16>,
+}
+
+#[derive(MmioDevice)]
+struct Last {
+    #[register(offset = 0xffff_ffff_ffff_fff8, size = 8, rw)]
+    value: ReadWrite<u64>,
+}
+
+#[derive(MmioDevice)]
+struct Generic<T: Default> {
+    #[register(offset = 0x0, size = 1, ro, default = 0x42)]
+    value: ReadOnly<u8>,
+    extra: T,
+}
+
+#[derive(DeviceEnum)]
+enum Device {
+    #[device(mmio)]
+    Timer(Timer),
+    #[device(pio)]
+    Port(Port),
+}
+
+#[test]
+fn test_mmio_device() {
+    let timer = Timer::default();
+    assert_eq!(
+        (timer.ctrl.get(), timer.id.get(), timer.ticks),
+        (0x10, 0x5449_4d52, 0)
+    );
+    let mut data = [0; 4];
+    timer.mmio_read(MmioAddress(0), MmioOffset(0x4), &mut data);
+    assert_eq!(&data, b"RMIT");
+
+    // Only the writable bits of the control register change.
+    timer.mmio_write(MmioAddress(0), MmioOffset(0x0), &[0x34, 0x12, 0, 0]);
+    assert_eq!(timer.ctrl.get(), 0x34);
+    timer.status.set_bits(0x3);
+    timer.mmio_write(MmioAddress(0), MmioOffset(0x8), &[0x1, 0]);
+    assert_eq!(timer.status.get(), 0x2);
+
+    // Offsets without a register read as zero.
+    timer.mmio_read(MmioAddress(0), MmioOffset(0xc), &mut data);
+    assert_eq!(data, [0; 4]);
+
+    timer.reset_registers();
+    assert_eq!((timer.ctrl.get(), timer.status.get()), (0x10, 0));
+}
+
+#[test]
+fn test_pio_device() {
+    let port = Port::default();
+    port.pio_write(PioAddress(0), PioOffset(1), &[0x12, 0x34]);
+    assert_eq!(port.value.get(), 0x1234);
+    let mut data = [0; 1];
+    port.pio_read(PioAddress(0), PioOffset(2), &mut data);
+    assert_eq!(data, [0x34]);
+}
+
+#[test]
+fn test_register_map() {
+    let last = Last::default();
+    assert!(last.register_at(0xffff_ffff_ffff_fff7).is_none());
+    let (base, reg) = last.register_at(u64::MAX).unwrap();
+    assert_eq!((base, reg.size()), (0xffff_ffff_ffff_fff8, 8));
+
+    let generic = Generic::<String>::default();
+    assert!(generic.extra.is_empty());
+    let mut data = [0; 2];
+    generic.mmio_read(MmioAddress(0), MmioOffset(0), &mut data);
+    assert_eq!(data, [0x42, 0]);
+}
+
+#[test]
+fn test_device_enum() {
+    let timer = Device::Timer(Timer::default());
+    let port = Device::Port(Port::default());
+    let mut data = [0; 2];
+    timer.mmio_read(MmioAddress(0), MmioOffset(0x8), &mut data);
+    assert_eq!(data, [0, 0]);
+
+    // Accesses to the variants not implementing the trait are unclaimed.
+    timer.pio_read(PioAddress(0), PioOffset(1), &mut data);
+    assert_eq!(data, [0xff, 0xff]);
+    port.pio_write(PioAddress(0), PioOffset(1), &[0xab, 0xcd]);
+    port.pio_read(PioAddress(0), PioOffset(1), &mut data);
+    assert_eq!(data, [0xab, 0xcd]);
+    port.mmio_read(MmioAddress(0), MmioOffset(0), &mut data);
+    assert_eq!(data, [0xff, 0xff]);
+}
+
+#[test]
+fn test_compile_errors() {
+    let cases = trybuild::TestCases::new();
+    cases.compile_fail("tests/ui/*.rs");
+}
@@ -0,0 +1,10 @@
+use vm_device::registers::ReadOnly;
+use vm_device::MmioDevice;
+
+#[derive(MmioDevice)]
+struct AccessMismatch {
+    #[register(offset = 0x0, size = 4, rw)]
+    value: ReadOnly<u32>,
+}
+
+fn main() {}
@@ -0,0 +1,23 @@
+use vm_device::registers::{ReadOnly, ReadWrite};
+use vm_device::MmioDevice;
+
+#[derive(MmioDevice)]
+struct BadAttribute {
+    #[register(offset = 0x0, size = 4, rw, width = 4)]
+    value: ReadWrite<u32>,
+}
+
+#[derive(MmioDevice)]
+struct MaskedReadOnly {
+    #[register(offset = 0x0, size = 4, ro, mask = 0xff)]
+    value: ReadOnly<u32>,
+}
+
+#[derive(MmioDevice)]
+#[registers(little_endian)]
+struct BadEndianness {
+    #[register(offset = 0x0, size = 4, rw)]
+    value: ReadWrite<u32>,
+}
+
+fn main() {}
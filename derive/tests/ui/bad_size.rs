@@ -0,0 +1,10 @@
+use vm_device::registers::ReadWrite;
+use vm_device::MmioDevice;
+
+#[derive(MmioDevice)]
+struct BadSize {
+    #[register(offset = 0x0, size = 3, rw)]
+    value: ReadWrite<u32>,
+}
+
+fn main() {}
@@ -0,0 +1,10 @@
+use vm_device::registers::ReadWrite;
+use vm_device::MmioDevice;
+
+#[derive(MmioDevice)]
+struct EndOverflow {
+    #[register(offset = 0xffff_ffff_ffff_fffc, size = 8, rw)]
+    value: ReadWrite<u64>,
+}
+
+fn main() {}
@@ -0,0 +1,12 @@
+use vm_device::registers::ReadWrite;
+use vm_device::MmioDevice;
+
+#[derive(MmioDevice)]
+struct Overlap {
+    #[register(offset = 0x0, size = 4, rw)]
+    first: ReadWrite<u32>,
+    #[register(offset = 0x2, size = 2, rw)]
+    second: ReadWrite<u16>,
+}
+
+fn main() {}
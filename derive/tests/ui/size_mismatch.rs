@@ -0,0 +1,11 @@
+use vm_device::registers::ReadWrite;
+use vm_device::PioDevice;
+
+#[derive(PioDevice)]
+#[registers(big_endian)]
+struct SizeMismatch {
+    #[register(offset = 0x0, size = 4, rw)]
+    value: ReadWrite<u16>,
+}
+
+fn main() {}
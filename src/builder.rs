@@ -0,0 +1,470 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Assembly of an `IoManager` from the complete list of platform devices.
+//!
+//! Registering devices one by one only reports a conflict when the second device of the pair
+//! is registered, which may be far from where the machine model is described. The
+//! [`IoManagerBuilder`](struct.IoManagerBuilder.html) instead collects all devices together
+//! with their resources, validates the whole layout, and only then builds the manager.
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+
+use crate::bus::{MmioAddress, MmioRange, PioAddress, PioAddressValue, PioRange};
+use crate::dependency::{self, DependencyGraph};
+use crate::device_manager::{self, IoManager};
+use crate::resources::Resource;
+use crate::{DeviceMmio, DevicePio};
+
+/// Errors found while validating the layout of the platform.
+#[derive(Debug)]
+pub enum Error {
+    /// The device has a zero-sized range, or a range which overflows the address space.
+    InvalidRange(String),
+    /// The ranges of the two devices overlap.
+    Overlap(String, String),
+    /// The device has a range outside of the allowed windows.
+    OutsideWindow(String),
+    /// The two devices use the same IRQ, which is not shareable.
+    IrqConflict(u32, String, String),
+    /// Failed to register a device with the manager.
+    Register(String, device_manager::Error),
+    /// The dependencies of the devices are invalid.
+    Dependency(dependency::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::InvalidRange(dev) => write!(f, "invalid range for device {}", dev),
+            Error::Overlap(first, second) => {
+                write!(f, "ranges of devices {} and {} overlap", first, second)
+            }
+            Error::OutsideWindow(dev) => {
+                write!(f, "range of device {} is outside the allowed windows", dev)
+            }
+            Error::IrqConflict(irq, first, second) => write!(
+                f,
+                "devices {} and {} both use the non-shareable IRQ {}",
+                first, second, irq
+            ),
+            Error::Register(dev, _) => write!(f, "failed to register device {}", dev),
+            Error::Dependency(_) => write!(f, "invalid device dependencies"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Register(_, e) => Some(e),
+            Error::Dependency(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// Device waiting to be registered.
+struct DeviceEntry {
+    name: String,
+    mmio: Option<Arc<dyn DeviceMmio + Send + Sync>>,
+    pio: Option<Arc<dyn DevicePio + Send + Sync>>,
+    resources: Vec<Resource>,
+}
+
+// Check that the `(base, last, device)` ranges are disjoint, and within `windows` when any
+// window is defined. The `reserved` ranges can't overlap any other range, but they can lie
+// outside of the windows.
+fn check_ranges<'a>(
+    ranges: Vec<(u64, u64, &'a str)>,
+    reserved: Vec<(u64, u64, &'a str)>,
+    windows: &[(u64, u64)],
+) -> Result<(), Error> {
+    if !windows.is_empty() {
+        if let Some(range) = ranges
+            .iter()
+            .find(|r| !windows.iter().any(|w| w.0 <= r.0 && r.1 <= w.1))
+        {
+            return Err(Error::OutsideWindow(range.2.to_string()));
+        }
+    }
+    let mut ranges: Vec<_> = reserved.into_iter().chain(ranges).collect();
+    ranges.sort_by_key(|range| range.0);
+    for pair in ranges.windows(2) {
+        if pair[1].0 <= pair[0].1 {
+            return Err(Error::Overlap(pair[0].2.to_string(), pair[1].2.to_string()));
+        }
+    }
+    Ok(())
+}
+
+// Range where no device can be placed.
+struct Reserved {
+    name: String,
+    base: u64,
+    size: u64,
+}
+
+impl Reserved {
+    // Return the `(base, last, name)` description of the range, if valid.
+    fn check(&self, max: u64) -> Result<(u64, u64, &str), Error> {
+        self.size
+            .checked_sub(1)
+            .and_then(|len| self.base.checked_add(len))
+            .filter(|last| *last <= max)
+            .map(|last| (self.base, last, self.name.as_str()))
+            .ok_or_else(|| Error::InvalidRange(self.name.clone()))
+    }
+}
+
+/// Collects the platform devices and their resources, and builds an `IoManager` once the
+/// complete layout is known to be valid.
+#[derive(Default)]
+pub struct IoManagerBuilder {
+    devices: Vec<DeviceEntry>,
+    // Allowed windows, as `(base, last)` pairs.
+    mmio_windows: Vec<(u64, u64)>,
+    pio_windows: Vec<(u64, u64)>,
+    mmio_reserved: Vec<Reserved>,
+    pio_reserved: Vec<Reserved>,
+    // Highest port address of the guest, if narrower than `PioAddressValue`.
+    pio_address_limit: Option<PioAddressValue>,
+    shared_irqs: Vec<u32>,
+    // Dependencies between the devices, as `(device, dependency)` pairs of names.
+    dependencies: Vec<(String, String)>,
+}
+
+impl IoManagerBuilder {
+    /// Create a builder without any device. All addresses are allowed until windows are
+    /// added.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allow MMIO ranges within the `size` bytes at `base`.
+    pub fn mmio_window(mut self, base: u64, size: u64) -> Self {
+        let last = base.saturating_add(size.saturating_sub(1));
+        self.mmio_windows.push((base, last));
+        self
+    }
+
+    /// Allow PIO ranges within the `size` ports at `base`.
+    pub fn pio_window(mut self, base: PioAddressValue, size: PioAddressValue) -> Self {
+        let last = u64::from(base) + u64::from(size.saturating_sub(1));
+        self.pio_windows.push((u64::from(base), last));
+        self
+    }
+
+    /// Only allow PIO ranges up to the port address `limit` (e.g. `MAX_X86_PIO_ADDRESS` for
+    /// x86 guests), which is also set as the PIO address limit of the built manager.
+    pub fn pio_address_limit(mut self, limit: PioAddressValue) -> Self {
+        self.pio_address_limit = Some(limit);
+        self
+    }
+
+    /// Reserve the `size` bytes at `base` for the use described by `name`, so no device can
+    /// be placed there. Reserved ranges may lie outside of the windows.
+    pub fn reserve_mmio(mut self, name: &str, base: u64, size: u64) -> Self {
+        self.mmio_reserved.push(Reserved {
+            name: name.to_string(),
+            base,
+            size,
+        });
+        self
+    }
+
+    /// Reserve the `size` ports at `base` for the use described by `name`, so no device can
+    /// be placed there.
+    pub fn reserve_pio(mut self, name: &str, base: PioAddressValue, size: PioAddressValue) -> Self {
+        self.pio_reserved.push(Reserved {
+            name: name.to_string(),
+            base: u64::from(base),
+            size: u64::from(size),
+        });
+        self
+    }
+
+    /// Allow several devices to use `irq` (e.g. for level triggered PCI interrupts).
+    pub fn shared_irq(mut self, irq: u32) -> Self {
+        self.shared_irqs.push(irq);
+        self
+    }
+
+    /// Make the device `name` depend on the device `dependency`, which must be added to the
+    /// builder as well.
+    pub fn depends_on(mut self, name: &str, dependency: &str) -> Self {
+        self.dependencies
+            .push((name.to_string(), dependency.to_string()));
+        self
+    }
+
+    // Return the dependency graph of the devices.
+    fn dependency_graph(&self) -> DependencyGraph {
+        let mut graph = DependencyGraph::new();
+        for dev in self.devices.iter() {
+            graph.add(&dev.name, &[]);
+        }
+        for (name, dependency) in self.dependencies.iter() {
+            graph.add(name, &[dependency]);
+        }
+        graph
+    }
+
+    /// Add a device registered with the MMIO ranges of `resources`.
+    pub fn mmio_device(
+        mut self,
+        name: &str,
+        device: Arc<dyn DeviceMmio + Send + Sync>,
+        resources: &[Resource],
+    ) -> Self {
+        self.devices.push(DeviceEntry {
+            name: name.to_string(),
+            mmio: Some(device),
+            pio: None,
+            resources: resources.to_vec(),
+        });
+        self
+    }
+
+    /// Add a device registered with the PIO ranges of `resources`.
+    pub fn pio_device(
+        mut self,
+        name: &str,
+        device: Arc<dyn DevicePio + Send + Sync>,
+        resources: &[Resource],
+    ) -> Self {
+        self.devices.push(DeviceEntry {
+            name: name.to_string(),
+            mmio: None,
+            pio: Some(device),
+            resources: resources.to_vec(),
+        });
+        self
+    }
+
+    /// Add a device registered with both the MMIO and the PIO ranges of `resources`.
+    pub fn device<T: DeviceMmio + DevicePio + Send + Sync + 'static>(
+        mut self,
+        name: &str,
+        device: Arc<T>,
+        resources: &[Resource],
+    ) -> Self {
+        self.devices.push(DeviceEntry {
+            name: name.to_string(),
+            mmio: Some(device.clone()),
+            pio: Some(device),
+            resources: resources.to_vec(),
+        });
+        self
+    }
+
+    /// Check the complete layout: ranges must be valid, disjoint, within the allowed
+    /// windows, and outside of the reserved ranges, and IRQs can only be used by a single device unless marked as shared.
+    /// The dependencies of the devices must be added to the builder, and can't form a cycle.
+    pub fn validate(&self) -> Result<(), Error> {
+        self.dependency_graph()
+            .validate()
+            .map_err(Error::Dependency)?;
+
+        let max_pio_address = self.pio_address_limit.unwrap_or(PioAddressValue::MAX);
+        let mut mmio = Vec::new();
+        let mut pio = Vec::new();
+        let mut irqs: BTreeMap<u32, &str> = BTreeMap::new();
+
+        for dev in self.devices.iter() {
+            let name = dev.name.as_str();
+            for res in dev.resources.iter() {
+                match *res {
+                    Resource::MmioAddressRange { base, size } if dev.mmio.is_some() => {
+                        let last = size
+                            .checked_sub(1)
+                            .and_then(|len| base.checked_add(len))
+                            .ok_or_else(|| Error::InvalidRange(name.to_string()))?;
+                        mmio.push((base, last, name));
+                    }
+                    Resource::PioAddressRange { base, size } if dev.pio.is_some() => {
+                        let last = size
+                            .checked_sub(1)
+                            .and_then(|len| base.checked_add(len))
+                            .filter(|last| *last <= max_pio_address)
+                            .ok_or_else(|| Error::InvalidRange(name.to_string()))?;
+                        pio.push((u64::from(base), u64::from(last), name));
+                    }
+                    Resource::LegacyIrq(irq) if !self.shared_irqs.contains(&irq) => {
+                        if let Some(other) = irqs.insert(irq, name) {
+                            return Err(Error::IrqConflict(
+                                irq,
+                                other.to_string(),
+                                name.to_string(),
+                            ));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let mmio_reserved = self
+            .mmio_reserved
+            .iter()
+            .map(|r| r.check(u64::MAX))
+            .collect::<Result<_, _>>()?;
+        let pio_reserved = self
+            .pio_reserved
+            .iter()
+            .map(|r| r.check(u64::from(max_pio_address)))
+            .collect::<Result<_, _>>()?;
+        check_ranges(mmio, mmio_reserved, &self.mmio_windows)?;
+        check_ranges(pio, pio_reserved, &self.pio_windows)
+    }
+
+    /// Validate the layout, and build the manager with all the devices registered.
+    pub fn build(self) -> Result<IoManager, Error> {
+        self.validate()?;
+        let mut io_mgr = IoManager::new();
+        io_mgr.set_pio_address_limit(self.pio_address_limit.map(PioAddress));
+        io_mgr.dependencies = self.dependency_graph();
+        // The reserved ranges were validated.
+        for reserved in self.mmio_reserved.iter() {
+            let range = MmioRange::new(MmioAddress(reserved.base), reserved.size).unwrap();
+            io_mgr
+                .reserve_mmio(range, &reserved.name)
+                .map_err(|e| Error::Register(reserved.name.clone(), e))?;
+        }
+        for reserved in self.pio_reserved.iter() {
+            let range = PioRange::new(
+                PioAddress(reserved.base as PioAddressValue),
+                reserved.size as PioAddressValue,
+            )
+            .unwrap();
+            io_mgr
+                .reserve_pio(range, &reserved.name)
+                .map_err(|e| Error::Register(reserved.name.clone(), e))?;
+        }
+        for dev in self.devices {
+            let name = dev.name;
+            if let Some(device) = dev.mmio {
+                io_mgr
+                    .register_mmio_resources(device, &dev.resources)
+                    .map_err(|e| Error::Register(name.clone(), e))?;
+            }
+            if let Some(device) = dev.pio {
+                io_mgr
+                    .register_pio_resources(device, &dev.resources)
+                    .map_err(|e| Error::Register(name.clone(), e))?;
+            }
+        }
+        Ok(io_mgr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::bus::MAX_X86_PIO_ADDRESS;
+    use crate::device_manager::{MmioManager, PioManager};
+    use crate::testing::EchoDevice;
+
+    fn mmio(base: u64, size: u64) -> Resource {
+        Resource::MmioAddressRange { base, size }
+    }
+
+    fn pio(base: PioAddressValue, size: PioAddressValue) -> Resource {
+        Resource::PioAddressRange { base, size }
+    }
+
+    #[test]
+    fn test_builder() {
+        let dev = Arc::new(EchoDevice::new());
+        let builder = || {
+            IoManagerBuilder::new()
+                .mmio_window(0xd000_0000, 0x1000_0000)
+                .pio_window(0, 0x1000)
+                .device(
+                    "serial",
+                    dev.clone(),
+                    &[pio(0x3f8, 8), Resource::LegacyIrq(4)],
+                )
+                .mmio_device("rtc", dev.clone(), &[mmio(0xd000_0000, 0x1000)])
+        };
+
+        let err = builder()
+            .mmio_device("virtio", dev.clone(), &[mmio(0xd000_0800, 0x1000)])
+            .validate()
+            .unwrap_err();
+        assert!(matches!(err, Error::Overlap(ref a, ref b) if a == "rtc" && b == "virtio"));
+        assert!(matches!(
+            builder()
+                .mmio_device("virtio", dev.clone(), &[mmio(0xc000_0000, 0x1000)])
+                .validate(),
+            Err(Error::OutsideWindow(_))
+        ));
+        assert!(matches!(
+            builder()
+                .pio_address_limit(MAX_X86_PIO_ADDRESS)
+                .pio_device("bad", dev.clone(), &[pio(0xffff, 2)])
+                .validate(),
+            Err(Error::InvalidRange(_))
+        ));
+        assert!(matches!(
+            builder()
+                .pio_device("bad", dev.clone(), &[pio(0xffff, 2)])
+                .validate(),
+            Err(Error::OutsideWindow(_))
+        ));
+        assert!(matches!(
+            builder()
+                .pio_device(
+                    "serial2",
+                    dev.clone(),
+                    &[pio(0x2f8, 8), Resource::LegacyIrq(4)]
+                )
+                .validate(),
+            Err(Error::IrqConflict(4, _, _))
+        ));
+        // Devices can't be placed in reserved ranges, which may lie outside of the windows.
+        let err = builder()
+            .reserve_mmio("kvm-tss", 0xd000_0000, 0x3000)
+            .validate()
+            .unwrap_err();
+        assert!(matches!(err, Error::Overlap(ref a, ref b) if a == "kvm-tss" && b == "rtc"));
+        // PIO ranges of MMIO-only devices are not registered, so they can't conflict.
+        let io_mgr = builder()
+            .reserve_mmio("kvm-tss", 0xfffb_d000, 0x3000)
+            .shared_irq(4)
+            .mmio_device("virtio", dev, &[pio(0x3f8, 8), Resource::LegacyIrq(4)])
+            .build()
+            .unwrap();
+        assert!(io_mgr.pio_device(PioAddress(0x3f8)).is_some());
+        assert!(io_mgr.mmio_device(MmioAddress(0xd000_0fff)).is_some());
+        assert_eq!(io_mgr.layout().mmio_reserved[0].name, "kvm-tss");
+    }
+
+    #[test]
+    fn test_builder_dependencies() {
+        let dev = Arc::new(EchoDevice::new());
+        let builder = || {
+            IoManagerBuilder::new()
+                .pio_device("serial", dev.clone(), &[pio(0x3f8, 8)])
+                .mmio_device("ioapic", dev.clone(), &[mmio(0xfec0_0000, 0x1000)])
+                .depends_on("serial", "ioapic")
+        };
+
+        assert!(matches!(
+            builder().depends_on("serial", "pic").validate(),
+            Err(Error::Dependency(dependency::Error::UnknownDependency(
+                _,
+                _
+            )))
+        ));
+        assert!(matches!(
+            builder().depends_on("ioapic", "serial").validate(),
+            Err(Error::Dependency(dependency::Error::Cycle(_)))
+        ));
+        let io_mgr = builder().build().unwrap();
+        assert_eq!(io_mgr.device_order().unwrap(), ["ioapic", "serial"]);
+    }
+}
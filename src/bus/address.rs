@@ -3,12 +3,14 @@
 
 use std::cmp::Ordering;
 use std::convert::TryFrom;
+use std::fmt::Debug;
 use std::ops::{Add, Sub};
 
 /// This trait defines the operations we expect to apply to bus address values.
 pub trait BusAddress:
     Add<<Self as BusAddress>::V, Output = Self>
     + Copy
+    + Debug
     + Eq
     + Ord
     + Sub<Output = <Self as BusAddress>::V>
@@ -16,7 +18,9 @@ pub trait BusAddress:
     /// Defines the underlying value type of the `BusAddress`.
     type V: Add<Output = Self::V>
         + Copy
+        + Debug
         + From<u8>
+        + Into<u64>
         + PartialEq
         + Ord
         + Sub<Output = Self::V>
@@ -34,14 +38,46 @@ pub trait BusAddress:
 #[derive(Clone, Copy, Debug)]
 pub struct MmioAddress(pub u64);
 
-/// This type defines the underlying value type for PIO addresses, which might be different
-/// for different platforms.
-pub type PioAddressValue = u16;
+/// Bus addresses that can be used with MMIO buses. Devices always observe 64-bit MMIO
+/// addresses and offsets, regardless of the width of the bus they are registered with.
+pub trait MmioBusAddress: BusAddress {
+    /// Return the equivalent 64-bit MMIO address.
+    fn to_mmio_address(&self) -> MmioAddress;
+
+    /// Return the equivalent 64-bit MMIO offset for `value`.
+    fn offset_to_u64(value: Self::V) -> u64;
+}
+
+/// Represents a MMIO address on a bus with a 32-bit wide address space.
+#[derive(Clone, Copy, Debug)]
+pub struct Mmio32Address(pub u32);
+
+/// This type defines the underlying value type for PIO addresses. It is wide enough for the
+/// port addresses of every supported target, and hypervisor; the ports which actually exist
+/// for the guest are bounded by the address limit of the PIO bus (see
+/// `Bus::set_address_limit`).
+pub type PioAddressValue = u32;
+
+/// Highest port address of x86 guests, which have a 16-bit wide port space.
+pub const MAX_X86_PIO_ADDRESS: PioAddressValue = 0xffff;
 
 /// Represents a PIO address.
 #[derive(Clone, Copy, Debug)]
 pub struct PioAddress(pub PioAddressValue);
 
+impl PioAddress {
+    /// Return the address `value`, if it fits in a port address.
+    pub fn new(value: u64) -> Option<Self> {
+        PioAddressValue::try_from(value).ok().map(PioAddress)
+    }
+}
+
+impl From<u16> for PioAddress {
+    fn from(port: u16) -> Self {
+        PioAddress(PioAddressValue::from(port))
+    }
+}
+
 // Implementing `BusAddress` and its prerequisites for `MmioAddress`.
 
 impl PartialEq for MmioAddress {
@@ -54,7 +90,7 @@ impl Eq for MmioAddress {}
 
 impl PartialOrd for MmioAddress {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        self.0.partial_cmp(&other.0)
+        Some(self.cmp(other))
     }
 }
 
@@ -92,6 +128,130 @@ impl BusAddress for MmioAddress {
     }
 }
 
+impl MmioBusAddress for MmioAddress {
+    fn to_mmio_address(&self) -> MmioAddress {
+        *self
+    }
+
+    fn offset_to_u64(value: Self::V) -> u64 {
+        value
+    }
+}
+
+// Implementing `BusAddress` and its prerequisites for `Mmio32Address`.
+
+impl PartialEq for Mmio32Address {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl Eq for Mmio32Address {}
+
+impl PartialOrd for Mmio32Address {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Mmio32Address {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.cmp(&other.0)
+    }
+}
+
+impl Add<u32> for Mmio32Address {
+    type Output = Self;
+
+    fn add(self, rhs: u32) -> Self::Output {
+        Mmio32Address(self.0 + rhs)
+    }
+}
+
+impl Sub for Mmio32Address {
+    type Output = u32;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        self.0 - rhs.0
+    }
+}
+
+impl BusAddress for Mmio32Address {
+    type V = u32;
+
+    fn value(&self) -> Self::V {
+        self.0
+    }
+
+    fn checked_add(&self, value: Self::V) -> Option<Self> {
+        self.0.checked_add(value).map(Mmio32Address)
+    }
+}
+
+impl MmioBusAddress for Mmio32Address {
+    fn to_mmio_address(&self) -> MmioAddress {
+        MmioAddress(u64::from(self.0))
+    }
+
+    fn offset_to_u64(value: Self::V) -> u64 {
+        u64::from(value)
+    }
+}
+
+impl From<Mmio32Address> for MmioAddress {
+    fn from(addr: Mmio32Address) -> Self {
+        addr.to_mmio_address()
+    }
+}
+
+// Conversions between the MMIO address types and `vm_memory::GuestAddress`.
+
+#[cfg(feature = "vm-memory")]
+impl From<vm_memory::GuestAddress> for MmioAddress {
+    fn from(addr: vm_memory::GuestAddress) -> Self {
+        MmioAddress(addr.0)
+    }
+}
+
+#[cfg(feature = "vm-memory")]
+impl From<MmioAddress> for vm_memory::GuestAddress {
+    fn from(addr: MmioAddress) -> Self {
+        vm_memory::GuestAddress(addr.0)
+    }
+}
+
+#[cfg(feature = "vm-memory")]
+impl TryFrom<vm_memory::GuestAddress> for Mmio32Address {
+    type Error = std::num::TryFromIntError;
+
+    fn try_from(addr: vm_memory::GuestAddress) -> Result<Self, Self::Error> {
+        u32::try_from(addr.0).map(Mmio32Address)
+    }
+}
+
+#[cfg(feature = "vm-memory")]
+impl From<Mmio32Address> for vm_memory::GuestAddress {
+    fn from(addr: Mmio32Address) -> Self {
+        vm_memory::GuestAddress(u64::from(addr.0))
+    }
+}
+
+#[cfg(feature = "vm-memory")]
+impl Sub<vm_memory::GuestAddress> for MmioAddress {
+    type Output = u64;
+
+    fn sub(self, rhs: vm_memory::GuestAddress) -> Self::Output {
+        self.0 - rhs.0
+    }
+}
+
+#[cfg(feature = "vm-memory")]
+impl PartialEq<vm_memory::GuestAddress> for MmioAddress {
+    fn eq(&self, other: &vm_memory::GuestAddress) -> bool {
+        self.0 == other.0
+    }
+}
+
 // Implementing `BusAddress` and its prerequisites for `PioAddress`.
 
 impl PartialEq for PioAddress {
@@ -104,7 +264,7 @@ impl Eq for PioAddress {}
 
 impl PartialOrd for PioAddress {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        self.0.partial_cmp(&other.0)
+        Some(self.cmp(other))
     }
 }
 
@@ -142,6 +302,198 @@ impl BusAddress for PioAddress {
     }
 }
 
+/// This type defines the underlying value type for system register addresses.
+pub type SysRegAddressValue = u32;
+
+/// Represents the encoded number of a trapped system register (aarch64) or control and
+/// status register (RISC-V).
+#[derive(Clone, Copy, Debug)]
+pub struct SysRegAddress(pub SysRegAddressValue);
+
+impl SysRegAddress {
+    /// Return the address of the aarch64 system register with the specified encoding. The
+    /// fields are packed the same way as in the ISS of a trapped `MSR`/`MRS` instruction.
+    pub fn aarch64(op0: u8, op1: u8, crn: u8, crm: u8, op2: u8) -> Self {
+        SysRegAddress(
+            u32::from(op0 & 0x3) << 14
+                | u32::from(op1 & 0x7) << 11
+                | u32::from(crn & 0xf) << 7
+                | u32::from(crm & 0xf) << 3
+                | u32::from(op2 & 0x7),
+        )
+    }
+
+    /// Return the address of the RISC-V control and status register with number `csr`.
+    pub fn riscv_csr(csr: u16) -> Self {
+        SysRegAddress(u32::from(csr & 0xfff))
+    }
+}
+
+// Implementing `BusAddress` and its prerequisites for `SysRegAddress`.
+
+impl PartialEq for SysRegAddress {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl Eq for SysRegAddress {}
+
+impl PartialOrd for SysRegAddress {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SysRegAddress {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.cmp(&other.0)
+    }
+}
+
+impl Add<SysRegAddressValue> for SysRegAddress {
+    type Output = Self;
+
+    fn add(self, rhs: SysRegAddressValue) -> Self::Output {
+        SysRegAddress(self.0 + rhs)
+    }
+}
+
+impl Sub for SysRegAddress {
+    type Output = SysRegAddressValue;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        self.0 - rhs.0
+    }
+}
+
+impl BusAddress for SysRegAddress {
+    type V = SysRegAddressValue;
+
+    fn value(&self) -> Self::V {
+        self.0
+    }
+
+    fn checked_add(&self, value: Self::V) -> Option<Self> {
+        self.0.checked_add(value).map(SysRegAddress)
+    }
+}
+
+/// This type defines the underlying value type for MSR addresses.
+pub type MsrAddressValue = u32;
+
+/// Represents the index of an x86 model specific register, as found in `ECX` when a
+/// `RDMSR` or `WRMSR` instruction traps.
+#[derive(Clone, Copy, Debug)]
+pub struct MsrAddress(pub MsrAddressValue);
+
+// Implementing `BusAddress` and its prerequisites for `MsrAddress`.
+
+impl PartialEq for MsrAddress {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl Eq for MsrAddress {}
+
+impl PartialOrd for MsrAddress {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for MsrAddress {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.cmp(&other.0)
+    }
+}
+
+impl Add<MsrAddressValue> for MsrAddress {
+    type Output = Self;
+
+    fn add(self, rhs: MsrAddressValue) -> Self::Output {
+        MsrAddress(self.0 + rhs)
+    }
+}
+
+impl Sub for MsrAddress {
+    type Output = MsrAddressValue;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        self.0 - rhs.0
+    }
+}
+
+impl BusAddress for MsrAddress {
+    type V = MsrAddressValue;
+
+    fn value(&self) -> Self::V {
+        self.0
+    }
+
+    fn checked_add(&self, value: Self::V) -> Option<Self> {
+        self.0.checked_add(value).map(MsrAddress)
+    }
+}
+
+/// This type defines the underlying value type for CPUID leaves.
+pub type CpuidAddressValue = u32;
+
+/// Represents an x86 CPUID leaf, as found in `EAX` when a `CPUID` instruction traps.
+#[derive(Clone, Copy, Debug)]
+pub struct CpuidAddress(pub CpuidAddressValue);
+
+// Implementing `BusAddress` and its prerequisites for `CpuidAddress`.
+
+impl PartialEq for CpuidAddress {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl Eq for CpuidAddress {}
+
+impl PartialOrd for CpuidAddress {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for CpuidAddress {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.cmp(&other.0)
+    }
+}
+
+impl Add<CpuidAddressValue> for CpuidAddress {
+    type Output = Self;
+
+    fn add(self, rhs: CpuidAddressValue) -> Self::Output {
+        CpuidAddress(self.0 + rhs)
+    }
+}
+
+impl Sub for CpuidAddress {
+    type Output = CpuidAddressValue;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        self.0 - rhs.0
+    }
+}
+
+impl BusAddress for CpuidAddress {
+    type V = CpuidAddressValue;
+
+    fn value(&self) -> Self::V {
+        self.0
+    }
+
+    fn checked_add(&self, value: Self::V) -> Option<Self> {
+        self.0.checked_add(value).map(CpuidAddress)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -150,6 +502,7 @@ mod tests {
 
     // `addr_zero` should be an address equivalent to 0, while `max_value` should contain the
     // maximum possible address value.
+    #[allow(clippy::eq_op)]
     fn check_bus_address_ops<A>(addr_zero: A, max_value: A::V)
     where
         A: BusAddress + Debug,
@@ -171,7 +524,53 @@ mod tests {
 
     #[test]
     fn test_address_ops() {
-        check_bus_address_ops(MmioAddress(0), std::u64::MAX);
-        check_bus_address_ops(PioAddress(0), std::u16::MAX);
+        check_bus_address_ops(MmioAddress(0), u64::MAX);
+        check_bus_address_ops(Mmio32Address(0), u32::MAX);
+        check_bus_address_ops(PioAddress(0), u32::MAX);
+        check_bus_address_ops(SysRegAddress(0), u32::MAX);
+        check_bus_address_ops(MsrAddress(0), u32::MAX);
+        check_bus_address_ops(CpuidAddress(0), u32::MAX);
+    }
+
+    #[test]
+    fn test_sysreg_address() {
+        // CNTV_CTL_EL0 is op0 = 3, op1 = 3, CRn = 14, CRm = 3, op2 = 1.
+        assert_eq!(
+            SysRegAddress::aarch64(3, 3, 14, 3, 1),
+            SysRegAddress(0xdf19)
+        );
+        assert_eq!(SysRegAddress::riscv_csr(0x14d), SysRegAddress(0x14d));
+        assert_eq!(SysRegAddress::riscv_csr(0xf14d), SysRegAddress(0x14d));
+    }
+
+    #[test]
+    fn test_mmio_bus_address() {
+        assert_eq!(MmioAddress(5).to_mmio_address(), MmioAddress(5));
+        assert_eq!(MmioAddress::offset_to_u64(5), 5);
+        assert_eq!(
+            MmioAddress::from(Mmio32Address(u32::MAX)),
+            MmioAddress(u64::from(u32::MAX))
+        );
+        assert_eq!(Mmio32Address::offset_to_u64(u32::MAX), u64::from(u32::MAX));
+    }
+
+    #[cfg(feature = "vm-memory")]
+    #[test]
+    fn test_guest_address() {
+        use vm_memory::GuestAddress;
+
+        let addr = MmioAddress::from(GuestAddress(0x1000));
+        assert_eq!(addr, GuestAddress(0x1000));
+        assert_eq!(GuestAddress::from(addr + 0x10), GuestAddress(0x1010));
+        assert_eq!(addr + 0x10 - GuestAddress(0x1000), 0x10);
+        assert_eq!(
+            Mmio32Address::try_from(GuestAddress(0x1000)),
+            Ok(Mmio32Address(0x1000))
+        );
+        assert!(Mmio32Address::try_from(GuestAddress(1 << 32)).is_err());
+        assert_eq!(
+            GuestAddress::from(Mmio32Address(u32::MAX)),
+            GuestAddress(u64::from(u32::MAX))
+        );
     }
 }
@@ -0,0 +1,192 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+use std::convert::TryFrom;
+use std::ops::Range;
+
+use super::BusAddress;
+
+/// What happens to the accesses which don't satisfy the constraints of a range.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AccessPolicy {
+    /// The access fails with `Error::UnsupportedAccess`.
+    Reject,
+    /// The access is split into the largest valid sub-accesses, which are dispatched to the
+    /// device in ascending address order. Accesses which can't be split fail as rejected.
+    Split,
+}
+
+/// Access widths and alignment accepted by the device registered with a range.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct AccessConstraints {
+    // Bit `n` is set when accesses of `1 << n` bytes are allowed.
+    widths: u8,
+    aligned: bool,
+    policy: AccessPolicy,
+}
+
+impl AccessConstraints {
+    /// Allow accesses of the provided widths, each of which must be 1, 2, 4 or 8 bytes. The
+    /// constraints reject invalid accesses, and don't require alignment by default.
+    pub fn new(widths: &[usize]) -> Self {
+        let widths = widths
+            .iter()
+            .filter(|width| width.is_power_of_two() && **width <= 8)
+            .fold(0, |mask, width| mask | *width as u8);
+        AccessConstraints {
+            widths,
+            aligned: false,
+            policy: AccessPolicy::Reject,
+        }
+    }
+
+    /// Require accesses to be naturally aligned.
+    pub fn aligned(mut self) -> Self {
+        self.aligned = true;
+        self
+    }
+
+    /// Set what happens to invalid accesses.
+    pub fn policy(mut self, policy: AccessPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Return the policy applied to invalid accesses.
+    pub fn access_policy(&self) -> AccessPolicy {
+        self.policy
+    }
+
+    // Return whether an access of `len` bytes at `addr` is valid by itself.
+    fn allows(&self, addr: u64, len: usize) -> bool {
+        len.is_power_of_two()
+            && len <= 8
+            && self.widths & len as u8 != 0
+            && (!self.aligned || addr.is_multiple_of(len as u64))
+    }
+
+    // Return the length of the largest valid sub-access at `addr`, with at most `len` bytes.
+    fn chunk_len(&self, addr: u64, len: usize) -> Option<usize> {
+        [8, 4, 2, 1]
+            .iter()
+            .copied()
+            .find(|width| *width <= len && self.allows(addr, *width))
+    }
+
+    // Return whether an access of `len` bytes at `addr` is valid, or can be split into valid
+    // sub-accesses.
+    pub(super) fn check(&self, addr: u64, len: usize) -> bool {
+        if self.allows(addr, len) {
+            return true;
+        }
+        if self.policy == AccessPolicy::Reject {
+            return false;
+        }
+        let mut pos = 0;
+        while pos < len {
+            match self.chunk_len(addr + pos as u64, len - pos) {
+                Some(chunk) => pos += chunk,
+                None => return false,
+            }
+        }
+        true
+    }
+}
+
+/// Iterator over the sub-accesses an access is split into, as pairs of the sub-access
+/// address and the matching range of the access data.
+pub struct AccessChunks<A: BusAddress> {
+    constraints: Option<AccessConstraints>,
+    addr: A,
+    pos: usize,
+    len: usize,
+}
+
+impl<A: BusAddress> AccessChunks<A> {
+    pub(super) fn new(constraints: Option<AccessConstraints>, addr: A, len: usize) -> Self {
+        AccessChunks {
+            constraints,
+            addr,
+            pos: 0,
+            len,
+        }
+    }
+}
+
+impl<A: BusAddress> Iterator for AccessChunks<A> {
+    type Item = (A, Range<usize>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.len {
+            return None;
+        }
+        // The access was checked when it started, so the offsets fit in the address type.
+        let addr = self.addr + A::V::try_from(self.pos).ok()?;
+        let remaining = self.len - self.pos;
+        let chunk = match self.constraints {
+            Some(constraints) if !constraints.allows(addr.value().into(), remaining) => {
+                constraints.chunk_len(addr.value().into(), remaining)?
+            }
+            _ => remaining,
+        };
+        let range = self.pos..self.pos + chunk;
+        self.pos += chunk;
+        Some((addr, range))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::bus::{Bus, Error, MmioAddress, MmioRange};
+
+    #[test]
+    fn test_access_constraints() {
+        let constraints = AccessConstraints::new(&[2, 4]).aligned();
+        assert!(constraints.check(0x10, 4));
+        assert!(constraints.check(0x12, 2));
+        // Unsupported widths, and misaligned accesses.
+        assert!(!constraints.check(0x10, 1));
+        assert!(!constraints.check(0x10, 8));
+        assert!(!constraints.check(0x12, 4));
+
+        // Splitting only helps when the pieces are valid by themselves.
+        let split = constraints.policy(AccessPolicy::Split);
+        assert_eq!(split.access_policy(), AccessPolicy::Split);
+        assert!(split.check(0x10, 8));
+        assert!(split.check(0x12, 4));
+        assert!(!split.check(0x11, 2));
+        let chunks: Vec<_> = AccessChunks::new(Some(split), MmioAddress(0x12), 8).collect();
+        assert_eq!(
+            chunks,
+            vec![
+                (MmioAddress(0x12), 0..2),
+                (MmioAddress(0x14), 2..6),
+                (MmioAddress(0x18), 6..8)
+            ]
+        );
+        let chunks: Vec<_> = AccessChunks::new(None, MmioAddress(0x11), 3).collect();
+        assert_eq!(chunks, vec![(MmioAddress(0x11), 0..3)]);
+    }
+
+    #[test]
+    fn test_constrained_range() {
+        let mut bus = Bus::new();
+        let range = MmioRange::new(MmioAddress(0x1000), 0x100).unwrap();
+        bus.register_with_constraints(range, 1u8, AccessConstraints::new(&[4]).aligned())
+            .unwrap();
+
+        assert_eq!(bus.check_access(MmioAddress(0x1004), 4), Ok((&range, &1)));
+        assert_eq!(
+            bus.check_access(MmioAddress(0x1004), 2),
+            Err(Error::UnsupportedAccess)
+        );
+        assert_eq!(
+            bus.check_access(MmioAddress(0x1002), 4),
+            Err(Error::UnsupportedAccess)
+        );
+        assert!(bus.access(MmioAddress(0x1002), 4).is_err());
+        assert_eq!(*bus.access(MmioAddress(0x1008), 4).unwrap(), 1);
+    }
+}
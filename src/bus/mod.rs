@@ -4,32 +4,89 @@
 //! Provides abstractions for modelling a bus, which is seen here as a mapping between
 //! disjoint intervals (ranges) from an address space and objects (devices) associated with them.
 //! A single device can be registered with multiple ranges, but no two ranges can overlap,
-//! regardless with their device associations.
+//! regardless with their device associations. The only exception are shadow ranges, which
+//! are registered on top of a regular range and take priority over it (e.g. to model the
+//! chipset controlled regions of the legacy BIOS area). A bus can also hold a fallback device,
+//! which handles the accesses that are not claimed by any registered range, and watchpoints,
+//! which let debuggers observe or intercept the accesses to a range. Observers get a copy of
+//! the accesses to a range once they completed; they aren't subject to the overlap checks,
+//! so they can be added on top of the device map without changing it. Reservations mark the
+//! ranges where no device may be registered (e.g. the pages KVM uses for the TSS). Ranges
+//! whose device keeps failing its accesses can be quarantined automatically, which disables
+//! their decoding. Machines with only a few devices can use a [`SmallBus`](struct.SmallBus.html)
+//! instead, which keeps them in a sorted array.
+//!
+//! A bus can be forked (e.g. to clone a VM): the copy shares the devices, but the ranges get
+//! their own state (decoding, quarantine, deferred deregistration and statistics), so
+//! neither copy can affect the other. The range maps of the copy are built once, in time
+//! linear in the number of ranges, and are copied on write afterwards. The data structure
+//! holding them is chosen with the [`Storage`](trait.Storage.html) parameter of the
+//! bus: a sorted vector by default, which makes lookups cheapest, or a `BTreeMap` or an
+//! interval tree for buses whose layout changes often.
 
 mod address;
+mod constraints;
+mod observe;
 mod range;
+mod small;
+mod stats;
+mod storage;
+mod units;
+mod watch;
 
 use std::collections::BTreeMap;
 use std::convert::TryFrom;
 use std::fmt::{Display, Formatter};
+use std::ops::Deref;
 use std::result::Result;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
 
-use address::BusAddress;
+use crate::events::VmEventSender;
 
-pub use address::{MmioAddress, PioAddress, PioAddressValue};
-pub use range::{BusRange, MmioRange, PioRange};
+pub use address::{
+    BusAddress, CpuidAddress, CpuidAddressValue, Mmio32Address, MmioAddress, MmioBusAddress,
+    MsrAddress, MsrAddressValue, PioAddress, PioAddressValue, SysRegAddress, SysRegAddressValue,
+    MAX_X86_PIO_ADDRESS,
+};
+pub use constraints::{AccessChunks, AccessConstraints, AccessPolicy};
+pub use observe::{BusObserver, ObserverId};
+pub use range::{
+    AccessSize, BusRange, CpuidRange, IntoAccessSize, Mmio32Range, MmioRange, MsrRange, PioRange,
+    SysRegRange,
+};
+pub use small::SmallBus;
+pub use stats::{LatencyHistogram, RangeStats, LATENCY_BUCKETS_NS};
+pub use storage::{BTreeStorage, IntervalTreeStorage, SortedVecStorage, Storage};
+pub use units::{MmioOffset, MmioSize, PioOffset, PioSize};
+pub use watch::{WatchAccess, WatchAction, WatchHandler, WatchKind, WatchpointId};
+
+use observe::Observers;
+use stats::EntryStats;
+use storage::RangeMap;
+use watch::Watchpoints;
 
 /// Errors encountered during bus operations.
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Error {
     /// No device is associated with the specified address or range.
     DeviceNotFound,
     /// Specified range overlaps an already registered range.
     DeviceOverlap,
+    /// Accesses to the device are still in flight.
+    DeviceBusy,
+    /// The device has not been marked for deregistration.
+    DeviceNotDraining,
     /// Access with invalid length attempted.
     InvalidAccessLength(usize),
     /// Invalid range provided (either zero-sized, or last address overflows).
     InvalidRange,
+    /// The access width or alignment is not supported by the device.
+    UnsupportedAccess,
+    /// Specified range overlaps a reserved range.
+    RangeReserved,
+    /// The bus can't hold any more ranges.
+    BusFull,
 }
 
 impl Display for Error {
@@ -37,89 +94,1074 @@ impl Display for Error {
         match self {
             Error::DeviceNotFound => write!(f, "device not found"),
             Error::DeviceOverlap => write!(f, "range overlaps with existing device"),
+            Error::DeviceBusy => write!(f, "device accesses still in flight"),
+            Error::DeviceNotDraining => write!(f, "device not marked for deregistration"),
             Error::InvalidAccessLength(len) => write!(f, "invalid access length ({})", len),
             Error::InvalidRange => write!(f, "invalid range provided"),
+            Error::UnsupportedAccess => write!(f, "unsupported access width or alignment"),
+            Error::RangeReserved => write!(f, "range overlaps a reserved range"),
+            Error::BusFull => write!(f, "no room left on the bus"),
         }
     }
 }
 
 impl std::error::Error for Error {}
 
-/// A bus that's agnostic to the range address type and device type.
-pub struct Bus<A: BusAddress, D> {
-    devices: BTreeMap<BusRange<A>, D>,
+// Holds a registered device together with the state used for deferred deregistration.
+struct BusEntry<A, D> {
+    device: D,
+    // Base address observed by the device, when it differs from the one of its range.
+    translated_base: Option<A>,
+    // Set when new accesses should no longer reach the device.
+    draining: AtomicBool,
+    // Cleared while the range is not decoded (e.g. the guest disabled the decoding of the
+    // BARs of a PCI device), so accesses are handled as if it wasn't registered.
+    enabled: AtomicBool,
+    // Number of accesses currently being handled by the device.
+    in_flight: AtomicUsize,
+    // Number of consecutive accesses the device failed to handle.
+    failures: AtomicU32,
+    // Set when the range was disabled because its device failed too many accesses.
+    quarantined: AtomicBool,
+    // Access widths and alignment accepted by the device, if restricted.
+    constraints: Option<AccessConstraints>,
+    // Counters of the accesses dispatched to the device.
+    stats: EntryStats,
+}
+
+impl<A, D> BusEntry<A, D> {
+    fn new(device: D, constraints: Option<AccessConstraints>) -> Arc<Self> {
+        Self::translated(device, constraints, None)
+    }
+
+    fn translated(
+        device: D,
+        constraints: Option<AccessConstraints>,
+        translated_base: Option<A>,
+    ) -> Arc<Self> {
+        Arc::new(BusEntry {
+            device,
+            translated_base,
+            draining: AtomicBool::new(false),
+            enabled: AtomicBool::new(true),
+            in_flight: AtomicUsize::new(0),
+            failures: AtomicU32::new(0),
+            quarantined: AtomicBool::new(false),
+            constraints,
+            stats: EntryStats::default(),
+        })
+    }
+
+    // Return a copy of the entry with its own state, starting from the current decoding,
+    // quarantine and deregistration state. The accesses in progress and the statistics
+    // belong to the original entry.
+    fn detached(&self) -> Arc<Self>
+    where
+        A: Copy,
+        D: Clone,
+    {
+        let copy = Self::translated(self.device.clone(), self.constraints, self.translated_base);
+        copy.draining
+            .store(self.draining.load(Ordering::SeqCst), Ordering::SeqCst);
+        copy.enabled
+            .store(self.enabled.load(Ordering::SeqCst), Ordering::SeqCst);
+        copy.quarantined
+            .store(self.quarantined.load(Ordering::SeqCst), Ordering::SeqCst);
+        copy
+    }
+}
+
+// Ranges and their entries. The maps are copied on write, and the entries are only shared
+// by the copies of a bus made by `duplicate` for the double buffer of `IoManagerOwner`.
+type EntryMap<A, D, S> = Arc<<S as Storage>::Map<A, Arc<BusEntry<A, D>>>>;
+
+/// Represents an access in progress to a device on the bus. The device cannot be returned
+/// by a deferred deregistration while the object is alive.
+///
+/// With the `tracing` feature enabled, each access is covered by a `bus_access` span which
+/// records the address, length, device range, and device. The device is identified by the
+/// key set with `Bus::set_device_key`, or by default by the address of the object it was
+/// registered as, which is the same for all the accesses to the range. The time spent
+/// handling the access is recorded in the `latency_ns` field of the span when the object is
+/// dropped.
+pub struct BusAccess<'a, A: BusAddress, D> {
+    range: &'a BusRange<A>,
+    entry: &'a BusEntry<A, D>,
+    quarantine_threshold: Option<u32>,
+    catch_panics: bool,
+    #[cfg(feature = "tracing")]
+    trace: (tracing::span::EnteredSpan, std::time::Instant),
+    // Start of the access, once it's known to reach the device.
+    #[cfg(feature = "latency")]
+    started: Option<std::time::Instant>,
+}
+
+impl<A: BusAddress, D> BusAccess<'_, A, D> {
+    /// Return the range the device is registered with.
+    pub fn range(&self) -> &BusRange<A> {
+        self.range
+    }
+
+    /// Return the device being accessed.
+    pub fn device(&self) -> &D {
+        &self.entry.device
+    }
+
+    /// Return the base address observed by the device. That's the base of its range, unless
+    /// the range was registered as a translating window.
+    pub fn base(&self) -> A {
+        self.entry
+            .translated_base
+            .unwrap_or_else(|| self.range.base())
+    }
+
+    /// Return the offset of `addr` within the range of the device.
+    pub fn offset(&self, addr: A) -> A::V {
+        addr - self.range.base()
+    }
+
+    /// Return the sub-accesses which the access of `len` bytes at `addr` is dispatched as.
+    /// That's the access itself, unless the constraints of the range ask for splitting it.
+    pub fn chunks(&self, addr: A, len: usize) -> AccessChunks<A> {
+        AccessChunks::new(self.entry.constraints, addr, len)
+    }
+
+    /// Record whether the device failed to handle the access. Return `true` if the failure
+    /// got the range quarantined, i.e. the device failed as many consecutive accesses as the
+    /// quarantine threshold of the bus.
+    pub fn record(&self, failed: bool) -> bool {
+        if !failed {
+            self.entry.failures.store(0, Ordering::Relaxed);
+            return false;
+        }
+        self.entry.stats.count_failure();
+        let failures = self.entry.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        match self.quarantine_threshold {
+            Some(threshold) if failures >= threshold => self.quarantine(),
+            _ => false,
+        }
+    }
+
+    /// Quarantine the range right away, e.g. because its device panicked. Return `true` if
+    /// it wasn't quarantined already.
+    pub fn quarantine(&self) -> bool {
+        self.entry.enabled.store(false, Ordering::SeqCst);
+        !self.entry.quarantined.swap(true, Ordering::SeqCst)
+    }
+
+    /// Return whether the panics of the device should be caught while it handles the
+    /// access.
+    pub fn catches_panics(&self) -> bool {
+        self.catch_panics
+    }
+}
+
+impl<A: BusAddress, D> Deref for BusAccess<'_, A, D> {
+    type Target = D;
+
+    fn deref(&self) -> &D {
+        self.device()
+    }
+}
+
+impl<A: BusAddress, D> Drop for BusAccess<'_, A, D> {
+    fn drop(&mut self) {
+        #[cfg(feature = "tracing")]
+        self.trace
+            .0
+            .record("latency_ns", self.trace.1.elapsed().as_nanos() as u64);
+        #[cfg(feature = "latency")]
+        if let Some(started) = self.started {
+            let ns = started.elapsed().as_nanos() as u64;
+            self.entry.stats.record_latency(ns);
+        }
+        self.entry.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
-impl<A: BusAddress, D> Default for Bus<A, D> {
+/// A bus that's agnostic to the range address type and device type. The ranges are kept in
+/// the data structure selected by `S` (see `Storage`).
+pub struct Bus<A: BusAddress, D, S: Storage = SortedVecStorage> {
+    devices: EntryMap<A, D, S>,
+    // Ranges which take priority over the regular ones they are registered on top of.
+    shadows: EntryMap<A, D, S>,
+    // Ranges where no device can be registered, with the name of their use.
+    reservations: Arc<BTreeMap<BusRange<A>, String>>,
+    // Device which handles the accesses that don't reach any registered range.
+    fallback: Option<D>,
+    watchpoints: Watchpoints<A>,
+    // Observers, in the order they see the accesses.
+    observers: Observers<A>,
+    // Whether accesses spanning several adjacent ranges are split between them.
+    split_accesses: bool,
+    // Number of consecutive failed accesses after which a range is quarantined.
+    quarantine_threshold: Option<u32>,
+    // Whether the panics of the devices are caught during dispatch.
+    catch_panics: bool,
+    // Identifies the devices in the spans of the accesses.
+    device_key: fn(&D) -> usize,
+    // Highest address the registered ranges can cover.
+    address_limit: Option<A>,
+}
+
+impl<A: BusAddress, D, S: Storage> Default for Bus<A, D, S> {
     fn default() -> Self {
         Bus {
-            devices: BTreeMap::new(),
+            devices: Arc::default(),
+            shadows: Arc::default(),
+            reservations: Arc::new(BTreeMap::new()),
+            fallback: None,
+            watchpoints: Watchpoints::default(),
+            observers: Observers::default(),
+            split_accesses: false,
+            quarantine_threshold: None,
+            catch_panics: false,
+            device_key: stored_key::<D>,
+            address_limit: None,
         }
     }
 }
 
+// Identify `device` by the address it's stored at.
+fn stored_key<D>(device: &D) -> usize {
+    device as *const D as usize
+}
+
+// Return the range in `map` which contains `addr`.
+fn map_entry<A: BusAddress, E>(
+    map: &BTreeMap<BusRange<A>, E>,
+    addr: A,
+) -> Option<(&BusRange<A>, &E)> {
+    map.range(..=BusRange::unit(addr))
+        .nth_back(0)
+        .filter(|pair| pair.0.last() >= addr)
+}
+
 impl<A: BusAddress, D> Bus<A, D> {
-    /// Create an empty bus.
+    /// Create an empty bus, using the default storage. Buses using another storage are
+    /// created with `Bus::default`.
     pub fn new() -> Self {
         Self::default()
     }
+}
+
+impl<A: BusAddress, D, S: Storage> Bus<A, D, S> {
+    /// Return a copy of the bus, which shares the devices but not the state of the ranges:
+    /// disabling, quarantining or deregistering a range in either copy doesn't affect the
+    /// other. The ranges start from their current state, so the ones in the middle of a
+    /// deferred deregistration are being drained in both copies. Forking takes time linear
+    /// in the number of ranges.
+    pub fn fork(&self) -> Self
+    where
+        D: Clone,
+    {
+        self.duplicate(false)
+    }
+
+    // Return a copy of the bus. With `share_entries`, the copy shares the entries of the
+    // ranges, and with them their state, which lets `IoManagerOwner` swap the copies of its
+    // double buffer without losing the state changed through either of them. Otherwise, the
+    // ranges of the copy get their own entries, as for `fork`.
+    pub(crate) fn duplicate(&self, share_entries: bool) -> Self
+    where
+        D: Clone,
+    {
+        let copy = |map: &EntryMap<A, D, S>| {
+            if share_entries {
+                return map.clone();
+            }
+            let mut copy = <S as Storage>::Map::default();
+            for (range, entry) in map.iter() {
+                copy.insert(*range, entry.detached());
+            }
+            Arc::new(copy)
+        };
+        Bus {
+            devices: copy(&self.devices),
+            shadows: copy(&self.shadows),
+            reservations: self.reservations.clone(),
+            fallback: self.fallback.clone(),
+            watchpoints: self.watchpoints.clone(),
+            observers: self.observers.clone(),
+            split_accesses: self.split_accesses,
+            quarantine_threshold: self.quarantine_threshold,
+            catch_panics: self.catch_panics,
+            device_key: self.device_key,
+            address_limit: self.address_limit,
+        }
+    }
+
+    /// Return the number of registered ranges, not counting shadow ranges.
+    pub fn len(&self) -> usize {
+        self.devices.len()
+    }
+
+    /// Return `true` if no range is registered. Shadow ranges can't be registered without
+    /// the regular range below them, so this agrees with `len`.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Iterate over the regular ranges and their devices, in ascending address order.
+    pub fn iter(&self) -> impl Iterator<Item = (&BusRange<A>, &D)> {
+        self.devices
+            .iter()
+            .map(|(range, entry)| (range, &entry.device))
+    }
+
+    /// Iterate over the regular ranges, in ascending address order.
+    pub fn ranges(&self) -> impl Iterator<Item = &BusRange<A>> {
+        self.devices.iter().map(|(range, _)| range)
+    }
+
+    /// Iterate over the shadow ranges and their devices, in ascending address order.
+    pub fn shadows(&self) -> impl Iterator<Item = (&BusRange<A>, &D)> {
+        self.shadows
+            .iter()
+            .map(|(range, entry)| (range, &entry.device))
+    }
+
+    /// Reserve `range` for the use described by `name`, so no device can be registered
+    /// within it. The range can't overlap registered ranges or other reservations.
+    pub fn reserve(&mut self, range: BusRange<A>, name: &str) -> Result<(), Error> {
+        if self
+            .devices
+            .first_overlapping(range.base(), range.last())
+            .is_some()
+        {
+            return Err(Error::DeviceOverlap);
+        }
+        if self.is_reserved(&range) {
+            return Err(Error::RangeReserved);
+        }
+        Arc::make_mut(&mut self.reservations).insert(range, name.to_string());
+        Ok(())
+    }
+
+    /// Remove the reservation containing `addr`, and return its range and name.
+    pub fn unreserve(&mut self, addr: A) -> Option<(BusRange<A>, String)> {
+        let range = *map_entry(&self.reservations, addr)?.0;
+        Arc::make_mut(&mut self.reservations)
+            .remove(&range)
+            .map(|name| (range, name))
+    }
+
+    /// Iterate over the reserved ranges and their names, in ascending address order.
+    pub fn reservations(&self) -> impl Iterator<Item = (&BusRange<A>, &str)> {
+        self.reservations
+            .iter()
+            .map(|(range, name)| (range, name.as_str()))
+    }
+
+    /// Return whether `range` overlaps a reserved range.
+    pub fn is_reserved(&self, range: &BusRange<A>) -> bool {
+        self.reservations.keys().any(|r| range.overlaps(r))
+    }
+
+    /// Return the lowest free range of `size` within `within`, whose base is a multiple of
+    /// `alignment` (an alignment of zero is treated as one). Free ranges overlap neither the
+    /// registered ranges nor the reservations, and stay below the address limit of the bus,
+    /// so the result can be registered right away.
+    pub fn find_free_range(
+        &self,
+        size: A::V,
+        alignment: A::V,
+        within: BusRange<A>,
+    ) -> Option<BusRange<A>> {
+        let size: u64 = size.into();
+        let alignment = std::cmp::max(alignment.into(), 1);
+        let align_up = |addr: u64| {
+            addr.checked_add(alignment - 1)
+                .map(|addr| addr / alignment * alignment)
+        };
+        let bounds = |range: &BusRange<A>| -> (u64, u64) {
+            (range.base().value().into(), range.last().value().into())
+        };
+        let (start, end) = bounds(&within);
+        let end = self
+            .address_limit
+            .map_or(end, |limit| end.min(limit.value().into()));
+        let mut used: Vec<_> = self
+            .ranges()
+            .chain(self.reservations.keys())
+            .filter(|range| range.overlaps(&within))
+            .map(bounds)
+            .collect();
+        used.sort_unstable();
+
+        let mut base = align_up(start)?;
+        for (used_base, used_last) in used {
+            if used_last < base {
+                continue;
+            }
+            if base.checked_add(size.checked_sub(1)?)? < used_base {
+                break;
+            }
+            base = align_up(used_last.checked_add(1)?)?;
+        }
+        if base.checked_add(size.checked_sub(1)?)? > end {
+            return None;
+        }
+        let offset = usize::try_from(base - start).ok()?;
+        let size = usize::try_from(size).ok()?;
+        BusRange::new(
+            within.base() + A::V::try_from(offset).ok()?,
+            A::V::try_from(size).ok()?,
+        )
+        .ok()
+    }
+
+    /// Enable or disable the decoding of the range containing `addr`. Accesses to a disabled
+    /// range are handled as if it wasn't registered (e.g. by the fallback device), but the
+    /// registration is kept.
+    pub fn set_enabled(&self, addr: A, enabled: bool) -> Result<(), Error> {
+        let (_, entry) = self.entry(addr).ok_or(Error::DeviceNotFound)?;
+        entry.enabled.store(enabled, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Return whether the decoding of the registered range `range` is enabled.
+    pub fn is_enabled(&self, range: &BusRange<A>) -> bool {
+        self.shadows
+            .get(range)
+            .or_else(|| self.devices.get(range))
+            .is_some_and(|entry| entry.enabled.load(Ordering::SeqCst))
+    }
+
+    /// Quarantine the ranges whose device fails `threshold` consecutive accesses, as
+    /// reported through `BusAccess::record`, by disabling their decoding. `None` (the
+    /// default) never quarantines ranges.
+    pub fn set_quarantine_threshold(&mut self, threshold: Option<u32>) {
+        self.quarantine_threshold = threshold;
+    }
+
+    /// Return the number of consecutive failed accesses after which a range is quarantined.
+    pub fn quarantine_threshold(&self) -> Option<u32> {
+        self.quarantine_threshold
+    }
+
+    /// Ask the code dispatching the accesses to catch the panics of the devices, which then
+    /// fail the access and quarantine the range (see `BusAccess::catches_panics`).
+    pub fn set_catch_panics(&mut self, enabled: bool) {
+        self.catch_panics = enabled;
+    }
+
+    /// Return whether the panics of the devices are caught during dispatch.
+    pub fn catches_panics(&self) -> bool {
+        self.catch_panics
+    }
+
+    /// Identify the devices by the value `key` returns for them in the `bus_access` spans,
+    /// instead of by the address of the objects stored in the bus. E.g. `IoManager` uses the
+    /// address of the allocation behind the `Arc` of the device, like in its layout.
+    pub fn set_device_key(&mut self, key: fn(&D) -> usize) {
+        self.device_key = key;
+    }
+
+    /// Reject the ranges which go past `limit`, when the address space of the guest is
+    /// narrower than the one of `A` (e.g. x86 guests only have `MAX_X86_PIO_ADDRESS` ports).
+    /// The ranges registered before are kept. `None` (the default) accepts every address.
+    pub fn set_address_limit(&mut self, limit: Option<A>) {
+        self.address_limit = limit;
+    }
+
+    /// Return the highest address the ranges of the bus can cover, if limited.
+    pub fn address_limit(&self) -> Option<A> {
+        self.address_limit
+    }
+
+    // Return whether `range` is within the address space of the guest.
+    fn within_limit(&self, range: &BusRange<A>) -> bool {
+        self.address_limit.is_none_or(|limit| range.last() <= limit)
+    }
+
+    /// Return whether the registered range `range` is quarantined.
+    pub fn is_quarantined(&self, range: &BusRange<A>) -> bool {
+        self.shadows
+            .get(range)
+            .or_else(|| self.devices.get(range))
+            .is_some_and(|entry| entry.quarantined.load(Ordering::SeqCst))
+    }
+
+    /// Return the statistics of the regular ranges, in ascending address order.
+    pub fn stats(&self) -> Vec<(BusRange<A>, RangeStats)> {
+        self.devices
+            .iter()
+            .map(|(range, entry)| {
+                let quarantined = entry.quarantined.load(Ordering::SeqCst);
+                (*range, entry.stats.snapshot(quarantined))
+            })
+            .collect()
+    }
+
+    /// Lift the quarantine of the range containing `addr` (e.g. once its device was reset),
+    /// enabling its decoding again.
+    pub fn release(&self, addr: A) -> Result<(), Error> {
+        let (_, entry) = self.entry(addr).ok_or(Error::DeviceNotFound)?;
+        if entry.quarantined.swap(false, Ordering::SeqCst) {
+            entry.failures.store(0, Ordering::Relaxed);
+            entry.enabled.store(true, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    /// Return whether the registered range `range` is marked for deregistration.
+    pub fn is_draining(&self, range: &BusRange<A>) -> bool {
+        self.shadows
+            .get(range)
+            .or_else(|| self.devices.get(range))
+            .is_some_and(|entry| entry.draining.load(Ordering::SeqCst))
+    }
+
+    /// Set the device which handles accesses not claimed by any registered range, and
+    /// return the previous one. Passing `None` removes the current fallback device.
+    pub fn set_fallback(&mut self, device: Option<D>) -> Option<D> {
+        std::mem::replace(&mut self.fallback, device)
+    }
+
+    /// Return the fallback device, if any.
+    pub fn fallback(&self) -> Option<&D> {
+        self.fallback.as_ref()
+    }
+
+    /// Return the fallback device when `error` reports that the access starting at `addr`
+    /// with length `len` is not claimed by any device, and `error` otherwise. Accesses which
+    /// partially overlap a registered range are not forwarded to the fallback device.
+    pub fn fallback_for(&self, error: Error, addr: A, len: usize) -> Result<&D, Error> {
+        let device = match (&error, self.fallback.as_ref()) {
+            (Error::DeviceNotFound, Some(device)) => device,
+            _ => return Err(error),
+        };
+        let access_range = BusRange::new(
+            addr,
+            A::V::try_from(len).map_err(|_| Error::InvalidAccessLength(len))?,
+        )
+        .map_err(|_| Error::InvalidRange)?;
+        if self
+            .devices
+            .iter()
+            .chain(self.shadows.iter())
+            .any(|(r, entry)| {
+                access_range.overlaps(r)
+                    && !entry.draining.load(Ordering::SeqCst)
+                    && entry.enabled.load(Ordering::SeqCst)
+            })
+        {
+            return Err(error);
+        }
+        Ok(device)
+    }
+
+    /// Install a watchpoint on `range`, which runs `handler` for the accesses of `kind` that
+    /// overlap it, before they are dispatched.
+    pub fn add_watchpoint(
+        &mut self,
+        range: BusRange<A>,
+        kind: WatchKind,
+        handler: WatchHandler<A>,
+    ) -> WatchpointId {
+        self.watchpoints.add(range, kind, handler)
+    }
+
+    /// Remove the watchpoint `id`. Return `false` if it's not installed.
+    pub fn remove_watchpoint(&mut self, id: WatchpointId) -> bool {
+        self.watchpoints.remove(id)
+    }
+
+    /// Return whether any watchpoint is installed.
+    pub fn has_watchpoints(&self) -> bool {
+        !self.watchpoints.is_empty()
+    }
+
+    // Run the watchpoints hit by the access of `len` bytes at `addr`.
+    fn watch(&self, addr: A, len: usize, access: WatchAccess<'_>) -> WatchAction {
+        if self.watchpoints.is_empty() {
+            return WatchAction::Continue;
+        }
+        // Invalid accesses are rejected when dispatched, so they don't hit watchpoints.
+        match A::V::try_from(len)
+            .ok()
+            .and_then(|len| BusRange::new(addr, len).ok())
+        {
+            Some(range) => self.watchpoints.check(addr, &range, access),
+            None => WatchAction::Continue,
+        }
+    }
+
+    /// Run the watchpoints hit by a read at `addr`, and return whether the read should
+    /// still be dispatched. `data` holds the result of the read when it's skipped.
+    pub fn watch_read(&self, addr: A, data: &mut [u8]) -> WatchAction {
+        self.watch(addr, data.len(), WatchAccess::Read(data))
+    }
+
+    /// Run the watchpoints hit by a write of `data` at `addr`, and return whether the write
+    /// should still be dispatched.
+    pub fn watch_write(&self, addr: A, data: &[u8]) -> WatchAction {
+        self.watch(addr, data.len(), WatchAccess::Write(data))
+    }
+
+    /// Install `observer` on `range`. Observers run after the access was dispatched, by
+    /// increasing `priority`, and in the order they were installed for equal priorities.
+    /// The range may overlap registered ranges and other observers.
+    pub fn add_observer(
+        &mut self,
+        range: BusRange<A>,
+        priority: u32,
+        observer: Arc<dyn BusObserver<A>>,
+    ) -> ObserverId {
+        self.observers.add(range, priority, observer)
+    }
+
+    /// Remove the observer `id`. Return `false` if it's not installed.
+    pub fn remove_observer(&mut self, id: ObserverId) -> bool {
+        self.observers.remove(id)
+    }
+
+    fn observe(&self, addr: A, data: &[u8], write: bool) {
+        if self.observers.is_empty() {
+            return;
+        }
+        if let Some(range) = A::V::try_from(data.len())
+            .ok()
+            .and_then(|len| BusRange::new(addr, len).ok())
+        {
+            self.observers.notify(addr, &range, data, write);
+        }
+    }
+
+    /// Pass a completed read at `addr`, which returned `data`, to the observers of the
+    /// ranges it overlaps.
+    pub fn observe_read(&self, addr: A, data: &[u8]) {
+        self.observe(addr, data, false)
+    }
+
+    /// Pass a completed write of `data` at `addr` to the observers of the ranges it
+    /// overlaps.
+    pub fn observe_write(&self, addr: A, data: &[u8]) {
+        self.observe(addr, data, true)
+    }
+
+    /// Enable or disable the splitting of accesses which span several adjacent ranges, as
+    /// real buses do for devices with adjacent BARs. Disabled by default, in which case such
+    /// accesses fail.
+    pub fn set_split_accesses(&mut self, enabled: bool) {
+        self.split_accesses = enabled;
+    }
+
+    /// Return whether accesses spanning several adjacent ranges are split between them.
+    pub fn split_accesses(&self) -> bool {
+        self.split_accesses
+    }
+
+    /// Return the pieces of the access of `len` bytes at `addr`, as pairs of the piece
+    /// address and the matching range of the access data, when `error` reports that the
+    /// access doesn't fit in a single range, splitting is enabled, and each piece falls on a
+    /// different registered range. Return `error` otherwise.
+    pub fn split_for(
+        &self,
+        error: Error,
+        addr: A,
+        len: usize,
+    ) -> Result<Vec<(A, std::ops::Range<usize>)>, Error> {
+        if error != Error::DeviceNotFound || !self.split_accesses {
+            return Err(error);
+        }
+        let mut pieces = Vec::new();
+        let mut pos = 0;
+        while pos < len {
+            let offset = A::V::try_from(pos).map_err(|_| Error::InvalidAccessLength(len))?;
+            let cur = addr.checked_add(offset).ok_or(error)?;
+            let (range, entry) = self.decoding_entry(cur).ok_or(error)?;
+            if entry.draining.load(Ordering::SeqCst) || !entry.enabled.load(Ordering::SeqCst) {
+                return Err(error);
+            }
+            let mut available = Into::<u64>::into(range.last() - cur).saturating_add(1);
+            // Pieces of regular ranges stop where an enabled shadow starts.
+            if !self.is_shadow(range, entry) {
+                // No enabled shadow contains `cur`, so look for one in the rest of the range.
+                if let Some(shadow) = cur
+                    .checked_add(1.into())
+                    .filter(|next| *next <= range.last())
+                    .and_then(|next| self.enabled_shadow(next, range.last()))
+                {
+                    available = (shadow.base() - cur).into();
+                }
+            }
+            let piece = usize::try_from(available).map_or(len - pos, |v| v.min(len - pos));
+            pieces.push((cur, pos..pos + piece));
+            pos += piece;
+        }
+        if pieces.len() < 2 {
+            return Err(error);
+        }
+        Ok(pieces)
+    }
+
+    // Return the most specific entry containing `addr`.
+    #[inline]
+    fn entry(&self, addr: A) -> Option<(&BusRange<A>, &BusEntry<A, D>)> {
+        self.shadows
+            .containing(addr)
+            .or_else(|| self.devices.containing(addr))
+            .map(|(range, entry)| (range, &**entry))
+    }
+
+    // Return the entry decoding `addr`, which is the regular range when the shadow on top
+    // of it is disabled.
+    #[inline]
+    fn decoding_entry(&self, addr: A) -> Option<(&BusRange<A>, &BusEntry<A, D>)> {
+        self.shadows
+            .containing(addr)
+            .filter(|(_, entry)| entry.enabled.load(Ordering::SeqCst))
+            .or_else(|| self.devices.containing(addr))
+            .map(|(range, entry)| (range, &**entry))
+    }
+
+    // Return whether `entry`, registered with `range`, is a shadow.
+    #[inline]
+    fn is_shadow(&self, range: &BusRange<A>, entry: &BusEntry<A, D>) -> bool {
+        self.shadows
+            .get(range)
+            .is_some_and(|shadow| std::ptr::eq(&**shadow, entry))
+    }
+
+    // Return the lowest enabled shadow overlapping the addresses from `base` to `last`.
+    fn enabled_shadow(&self, base: A, last: A) -> Option<&BusRange<A>> {
+        match self.shadows.first_overlapping(base, last) {
+            Some((shadow, entry)) if entry.enabled.load(Ordering::SeqCst) => Some(shadow),
+            None => None,
+            Some(_) => self
+                .shadows
+                .iter()
+                .find(|(shadow, entry)| {
+                    shadow.base() <= last
+                        && shadow.last() >= base
+                        && entry.enabled.load(Ordering::SeqCst)
+                })
+                .map(|(shadow, _)| shadow),
+        }
+    }
 
     /// Return the registered range and device associated with `addr`.
     pub fn device(&self, addr: A) -> Option<(&BusRange<A>, &D)> {
-        self.devices
-            .range(..=BusRange::unit(addr))
-            .nth_back(0)
-            .filter(|pair| pair.0.last() >= addr)
+        self.entry(addr)
+            .map(|(range, entry)| (range, &entry.device))
     }
 
     /// Return the registered range and a mutable reference to the device
-    /// associated with `addr`.
-    pub fn device_mut(&mut self, addr: A) -> Option<(&BusRange<A>, &mut D)> {
-        self.devices
-            .range_mut(..=BusRange::unit(addr))
-            .nth_back(0)
-            .filter(|pair| pair.0.last() >= addr)
+    /// associated with `addr`. The device is cloned first if it's shared with another copy.
+    pub fn device_mut(&mut self, addr: A) -> Option<(&BusRange<A>, &mut D)>
+    where
+        D: Clone,
+    {
+        let map = if self.shadows.containing(addr).is_some() {
+            &mut self.shadows
+        } else {
+            &mut self.devices
+        };
+        Arc::make_mut(map)
+            .containing_mut(addr)
+            .map(|(range, entry)| {
+                if Arc::get_mut(entry).is_none() {
+                    // The access tracking of the shared entry isn't relevant to the copy,
+                    // since no access of this bus can be in progress.
+                    *entry = entry.detached();
+                }
+                (range, &mut Arc::get_mut(entry).unwrap().device)
+            })
     }
 
     /// Register a device with the provided range.
     pub fn register(&mut self, range: BusRange<A>, device: D) -> Result<(), Error> {
-        for r in self.devices.keys() {
-            if range.overlaps(r) {
-                return Err(Error::DeviceOverlap);
-            }
+        self.insert(range, BusEntry::new(device, None))
+    }
+
+    fn insert(&mut self, range: BusRange<A>, entry: Arc<BusEntry<A, D>>) -> Result<(), Error> {
+        if !self.within_limit(&range) {
+            return Err(Error::InvalidRange);
+        }
+        if self
+            .devices
+            .first_overlapping(range.base(), range.last())
+            .is_some()
+            || self
+                .shadows
+                .first_overlapping(range.base(), range.last())
+                .is_some()
+        {
+            return Err(Error::DeviceOverlap);
         }
 
-        if self.devices.contains_key(&range) {
+        if self.is_reserved(&range) {
+            return Err(Error::RangeReserved);
+        }
+
+        Arc::make_mut(&mut self.devices).insert(range, entry);
+
+        Ok(())
+    }
+
+    /// Register a device with the provided range, which only accepts accesses satisfying
+    /// `constraints`.
+    pub fn register_with_constraints(
+        &mut self,
+        range: BusRange<A>,
+        device: D,
+        constraints: AccessConstraints,
+    ) -> Result<(), Error> {
+        self.insert(range, BusEntry::new(device, Some(constraints)))
+    }
+
+    /// Register a translating window: a range whose accesses reach `device` as if the range
+    /// started at `base` (e.g. the high MMIO alias of a region registered lower, or the
+    /// window of a PCI bridge). The device observes the offsets within the range as usual.
+    pub fn register_translated(
+        &mut self,
+        range: BusRange<A>,
+        device: D,
+        base: A,
+    ) -> Result<(), Error> {
+        self.insert(range, BusEntry::translated(device, None, Some(base)))
+    }
+
+    /// Register a shadow range, which takes priority over the regular range it is registered
+    /// on top of. The shadow must fit within a single regular range, and can't overlap other
+    /// shadows or reservations. Accesses which partially overlap an enabled shadow are
+    /// rejected, while the accesses to a disabled shadow reach the regular range below it.
+    pub fn register_shadow(&mut self, range: BusRange<A>, device: D) -> Result<(), Error> {
+        self.devices
+            .containing(range.base())
+            .filter(|(r, _)| r.last() >= range.last())
+            .ok_or(Error::DeviceNotFound)?;
+        if self
+            .shadows
+            .first_overlapping(range.base(), range.last())
+            .is_some()
+        {
             return Err(Error::DeviceOverlap);
         }
+        if self.is_reserved(&range) {
+            return Err(Error::RangeReserved);
+        }
+        Arc::make_mut(&mut self.shadows).insert(range, BusEntry::new(device, None));
+        Ok(())
+    }
 
-        self.devices.insert(range, device);
+    /// Deregister the device associated with `addr`. When `addr` is covered by a shadow
+    /// range, the shadow is removed, uncovering the regular range below it. Deregistering a
+    /// regular range also removes the shadows registered on top of it.
+    pub fn deregister(&mut self, addr: A) -> Option<(BusRange<A>, D)>
+    where
+        D: Clone,
+    {
+        let (range, entry) = self.entry(addr)?;
+        let range = *range;
+        // Shadows can share the base of the regular range below them.
+        let map = if self.is_shadow(&range, entry) {
+            &mut self.shadows
+        } else {
+            let shadows: Vec<_> = self
+                .shadows
+                .iter()
+                .filter(|(shadow, _)| shadow.overlaps(&range))
+                .map(|(shadow, _)| *shadow)
+                .collect();
+            if !shadows.is_empty() {
+                let map = Arc::make_mut(&mut self.shadows);
+                for shadow in shadows.iter() {
+                    map.remove(shadow);
+                }
+            }
+            &mut self.devices
+        };
+        let entry = Arc::make_mut(map).remove(&range)?;
+        // The device is left to the forks still holding the range.
+        match Arc::try_unwrap(entry) {
+            Ok(entry) => Some((range, entry.device)),
+            Err(entry) => Some((range, entry.device.clone())),
+        }
+    }
 
+    /// Move the regular range containing `addr` to `base`, keeping its size, together with
+    /// the shadows registered on top of it. The device keeps the state of its registration
+    /// (constraints, translation, decoding, quarantine and statistics). Return the previous
+    /// and the new range.
+    pub fn relocate(&mut self, addr: A, base: A) -> Result<(BusRange<A>, BusRange<A>), Error> {
+        let old = *self
+            .devices
+            .containing(addr)
+            .ok_or(Error::DeviceNotFound)?
+            .0;
+        let new = BusRange::new(base, old.size())?;
+        if !self.within_limit(&new) {
+            return Err(Error::InvalidRange);
+        }
+        if self.is_reserved(&new) {
+            return Err(Error::RangeReserved);
+        }
+        // The shadows fit in the new range, since they fit in the previous one.
+        let shadows = self
+            .shadows
+            .iter()
+            .filter(|(shadow, _)| shadow.overlaps(&old))
+            .map(|(shadow, entry)| {
+                let base = base
+                    .checked_add(shadow.base() - old.base())
+                    .ok_or(Error::InvalidRange)?;
+                Ok((*shadow, BusRange::new(base, shadow.size())?, entry.clone()))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let devices = Arc::make_mut(&mut self.devices);
+        let entry = devices.remove(&old).ok_or(Error::DeviceNotFound)?;
+        // Other shadows are within other regular ranges, so checking the latter is enough.
+        if devices.first_overlapping(new.base(), new.last()).is_some() {
+            devices.insert(old, entry);
+            return Err(Error::DeviceOverlap);
+        }
+        devices.insert(new, entry);
+        if !shadows.is_empty() {
+            let map = Arc::make_mut(&mut self.shadows);
+            for (shadow, _, _) in shadows.iter() {
+                map.remove(shadow);
+            }
+            for (_, shadow, entry) in shadows {
+                map.insert(shadow, entry);
+            }
+        }
+        Ok((old, new))
+    }
+
+    /// Start the deferred deregistration of the device associated with `addr`. New accesses
+    /// are handled as if no device was registered for the range, while accesses already in
+    /// progress are allowed to complete.
+    pub fn begin_deregister(&self, addr: A) -> Result<(), Error> {
+        let (_, entry) = self.entry(addr).ok_or(Error::DeviceNotFound)?;
+        entry.draining.store(true, Ordering::SeqCst);
         Ok(())
     }
 
-    /// Deregister the device associated with `addr`.
-    pub fn deregister(&mut self, addr: A) -> Option<(BusRange<A>, D)> {
-        let range = self.device(addr).map(|(range, _)| *range)?;
-        self.devices.remove(&range).map(|device| (range, device))
+    /// Return whether the device associated with `addr` is marked for deregistration and
+    /// no longer has any accesses in progress.
+    pub fn is_drained(&self, addr: A) -> bool {
+        self.entry(addr).is_some_and(|(_, entry)| {
+            entry.draining.load(Ordering::SeqCst) && entry.in_flight.load(Ordering::SeqCst) == 0
+        })
+    }
+
+    /// Complete the deferred deregistration of the device associated with `addr`, and
+    /// return the device together with its range.
+    pub fn complete_deregister(&mut self, addr: A) -> Result<(BusRange<A>, D), Error>
+    where
+        D: Clone,
+    {
+        let (_, entry) = self.entry(addr).ok_or(Error::DeviceNotFound)?;
+        if !entry.draining.load(Ordering::SeqCst) {
+            return Err(Error::DeviceNotDraining);
+        }
+        if entry.in_flight.load(Ordering::SeqCst) != 0 {
+            return Err(Error::DeviceBusy);
+        }
+        self.deregister(addr).ok_or(Error::DeviceNotFound)
+    }
+
+    #[inline]
+    fn check_entry(
+        &self,
+        addr: A,
+        size: AccessSize<A>,
+    ) -> Result<(&BusRange<A>, &BusEntry<A, D>), Error> {
+        let last = size.last(addr).ok_or(Error::InvalidRange)?;
+        self.decoding_entry(addr)
+            .filter(|(range, _)| range.last() >= last)
+            // An access to a regular range must not reach into an enabled shadow.
+            .filter(|(range, entry)| {
+                self.shadows.len() == 0
+                    || self.is_shadow(range, entry)
+                    || self.enabled_shadow(addr, last).is_none()
+            })
+            .filter(|(_, entry)| entry.enabled.load(Ordering::SeqCst))
+            .ok_or(Error::DeviceNotFound)
+            .and_then(|(range, entry)| match entry.constraints {
+                Some(constraints) if !constraints.check(addr.value().into(), size.as_usize()) => {
+                    Err(Error::UnsupportedAccess)
+                }
+                _ => Ok((range, entry)),
+            })
     }
 
     /// Verify whether an access starting at `addr` with length `len` fits within any of
     /// the registered ranges. Return the range and a handle to the device when present.
-    pub fn check_access(&self, addr: A, len: usize) -> Result<(&BusRange<A>, &D), Error> {
-        let access_range = BusRange::new(
-            addr,
-            A::V::try_from(len).map_err(|_| Error::InvalidAccessLength(len))?,
-        )
-        .map_err(|_| Error::InvalidRange)?;
-        self.device(addr)
-            .filter(|(range, _)| range.last() >= access_range.last())
-            .ok_or(Error::DeviceNotFound)
+    /// The lookup doesn't allocate; passing the length as an `AccessSize` also saves its
+    /// validation.
+    #[inline]
+    pub fn check_access<L: IntoAccessSize<A>>(
+        &self,
+        addr: A,
+        len: L,
+    ) -> Result<(&BusRange<A>, &D), Error> {
+        self.check_entry(addr, len.into_access_size()?)
+            .and_then(|(range, entry)| {
+                if entry.draining.load(Ordering::SeqCst) {
+                    return Err(Error::DeviceNotFound);
+                }
+                Ok((range, &entry.device))
+            })
+    }
+
+    /// Same as `check_access`, but the returned object also tracks the access as being in
+    /// progress until it's dropped, for the purpose of deferred deregistration. Neither
+    /// the lookup nor the tracking allocate.
+    #[inline]
+    pub fn access<L: IntoAccessSize<A>>(
+        &self,
+        addr: A,
+        len: L,
+    ) -> Result<BusAccess<'_, A, D>, Error> {
+        let size = len.into_access_size()?;
+        let (range, entry) = self.check_entry(addr, size)?;
+        // Announce the access before checking the draining flag, so that a concurrent
+        // `begin_deregister` either sees the access, or the access sees the flag.
+        entry.in_flight.fetch_add(1, Ordering::SeqCst);
+        #[cfg_attr(not(feature = "latency"), allow(unused_mut))]
+        let mut access = BusAccess {
+            range,
+            entry,
+            quarantine_threshold: self.quarantine_threshold,
+            catch_panics: self.catch_panics,
+            #[cfg(feature = "tracing")]
+            trace: (
+                tracing::trace_span!(
+                    "bus_access",
+                    addr = ?addr,
+                    len = size.as_usize(),
+                    range = ?range,
+                    device = (self.device_key)(&entry.device),
+                    latency_ns = tracing::field::Empty
+                )
+                .entered(),
+                std::time::Instant::now(),
+            ),
+            #[cfg(feature = "latency")]
+            started: None,
+        };
+        if entry.draining.load(Ordering::SeqCst) {
+            return Err(Error::DeviceNotFound);
+        }
+        entry.stats.count_access();
+        #[cfg(feature = "latency")]
+        {
+            access.started = Some(std::time::Instant::now());
+        }
+        Ok(access)
     }
 }
 
 pub type MmioBus<D> = Bus<MmioAddress, D>;
+pub type Mmio32Bus<D> = Bus<Mmio32Address, D>;
 pub type PioBus<D> = Bus<PioAddress, D>;
+pub type SysRegBus<D> = Bus<SysRegAddress, D>;
+pub type MsrBus<D> = Bus<MsrAddress, D>;
 
 /// Helper trait that can be implemented by types which hold one or more buses.
 pub trait BusManager<A: BusAddress> {
@@ -131,12 +1173,297 @@ pub trait BusManager<A: BusAddress> {
 
     /// Return a mutable reference to the bus.
     fn bus_mut(&mut self) -> &mut Bus<A, Self::D>;
+
+    /// Return the sender of the events reporting the quarantine of ranges of the bus.
+    fn events(&self) -> Option<&VmEventSender> {
+        None
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
+    #[test]
+    fn test_deferred_deregister() {
+        let range = MmioRange::new(MmioAddress(0x1000), 0x100).unwrap();
+        let addr = MmioAddress(0x1010);
+        let mut bus = Bus::new();
+        bus.register(range, 1u8).unwrap();
+
+        assert_eq!(
+            bus.begin_deregister(MmioAddress(0)),
+            Err(Error::DeviceNotFound)
+        );
+        assert_eq!(bus.complete_deregister(addr), Err(Error::DeviceNotDraining));
+        assert!(!bus.is_drained(addr));
+
+        {
+            let access = bus.access(addr, 4).unwrap();
+            assert_eq!(*access.range(), range);
+            assert_eq!(*access, 1);
+
+            // New accesses are rejected while the range is draining, but the one in progress
+            // keeps the deregistration from completing.
+            bus.begin_deregister(addr).unwrap();
+            assert!(bus.is_draining(&range));
+            assert!(!bus.is_drained(addr));
+            assert!(bus.access(addr, 4).is_err());
+            assert_eq!(bus.check_access(addr, 4), Err(Error::DeviceNotFound));
+            // The device is still registered.
+            assert!(bus.device(addr).is_some());
+        }
+
+        assert!(bus.is_drained(addr));
+        assert_eq!(bus.complete_deregister(addr), Ok((range, 1)));
+        assert!(bus.device(addr).is_none());
+    }
+
+    #[test]
+    fn test_shadow() {
+        let mut bus = Bus::new();
+        let range = MmioRange::new(MmioAddress(0x8_0000), 0x8_0000).unwrap();
+        let shadow = MmioRange::new(MmioAddress(0xa_0000), 0x2_0000).unwrap();
+        assert_eq!(bus.register_shadow(shadow, 2u8), Err(Error::DeviceNotFound));
+        bus.register(range, 1u8).unwrap();
+        bus.register_shadow(shadow, 2u8).unwrap();
+        assert_eq!(
+            bus.register_shadow(MmioRange::new(MmioAddress(0xb_0000), 0x10).unwrap(), 3),
+            Err(Error::DeviceOverlap)
+        );
+        assert_eq!(bus.shadows().count(), 1);
+
+        assert_eq!(bus.device(MmioAddress(0x9_ffff)), Some((&range, &1)));
+        assert_eq!(bus.device(MmioAddress(0xa_0000)), Some((&shadow, &2)));
+        assert_eq!(bus.device_mut(MmioAddress(0xb_ffff)).unwrap().1, &mut 2);
+        assert_eq!(bus.device(MmioAddress(0xc_0000)), Some((&range, &1)));
+        // Accesses straddling the edge of the shadow are rejected.
+        assert_eq!(
+            bus.check_access(MmioAddress(0x9_fffe), 4),
+            Err(Error::DeviceNotFound)
+        );
+        assert!(bus.check_access(MmioAddress(0xa_0000), 4).is_ok());
+
+        assert_eq!(bus.deregister(MmioAddress(0xa_1000)), Some((shadow, 2)));
+        assert_eq!(bus.device(MmioAddress(0xa_0000)), Some((&range, &1)));
+        assert!(bus.check_access(MmioAddress(0x9_fffe), 4).is_ok());
+    }
+
+    // Check which device the accesses around a shadow reach, on a bus using `S`.
+    fn check_shadowed_accesses<S: Storage>() {
+        let mut bus = Bus::<MmioAddress, u8, S>::default();
+        bus.register(MmioRange::new(MmioAddress(0x1000), 0x1000).unwrap(), 1)
+            .unwrap();
+        bus.register(MmioRange::new(MmioAddress(0x3000), 0x1000).unwrap(), 3)
+            .unwrap();
+        bus.register_shadow(MmioRange::new(MmioAddress(0x1800), 0x100).unwrap(), 2)
+            .unwrap();
+
+        let device = |addr, len| bus.check_access(MmioAddress(addr), len).map(|pair| *pair.1);
+        // Unshadowed accesses, next to the shadow and on a range without any.
+        assert_eq!(device(0x1000, 8), Ok(1));
+        assert_eq!(device(0x17f8, 8), Ok(1));
+        assert_eq!(device(0x1900, 8), Ok(1));
+        assert_eq!(device(0x3800, 8), Ok(3));
+        // Shadowed accesses, from the first to the last byte of the shadow.
+        assert_eq!(device(0x1800, 8), Ok(2));
+        assert_eq!(device(0x18f8, 8), Ok(2));
+        assert_eq!(device(0x18ff, 1), Ok(2));
+        // Accesses straddling either edge of the shadow.
+        assert_eq!(device(0x17fc, 8), Err(Error::DeviceNotFound));
+        assert_eq!(device(0x18fc, 8), Err(Error::DeviceNotFound));
+    }
+
+    #[test]
+    fn test_shadowed_accesses() {
+        check_shadowed_accesses::<SortedVecStorage>();
+        check_shadowed_accesses::<BTreeStorage>();
+        check_shadowed_accesses::<IntervalTreeStorage>();
+    }
+
+    #[test]
+    fn test_shadow_lifecycle() {
+        let mut bus = Bus::new();
+        let range = MmioRange::new(MmioAddress(0x1000), 0x1000).unwrap();
+        let shadow = MmioRange::new(MmioAddress(0x1800), 0x100).unwrap();
+        bus.register(range, 1u8).unwrap();
+        bus.register(MmioRange::new(MmioAddress(0x2000), 0x1000).unwrap(), 3)
+            .unwrap();
+        assert_eq!(
+            bus.register_shadow(MmioRange::new(MmioAddress(0x2000), 0x100).unwrap(), 4),
+            Ok(())
+        );
+        assert_eq!(
+            bus.deregister(MmioAddress(0x2100))
+                .map(|(r, d)| (r.base(), d)),
+            Some((MmioAddress(0x2000), 3))
+        );
+        assert_eq!(bus.shadows().count(), 0);
+
+        // A disabled shadow lets the accesses reach the regular range below it.
+        bus.register_shadow(shadow, 2).unwrap();
+        bus.set_enabled(shadow.base(), false).unwrap();
+        assert_eq!(*bus.access(MmioAddress(0x1800), 4).unwrap(), 1);
+        assert_eq!(*bus.access(MmioAddress(0x17fe), 4).unwrap(), 1);
+        bus.set_split_accesses(true);
+        assert_eq!(
+            bus.split_for(Error::DeviceNotFound, MmioAddress(0x17fe), 4),
+            Err(Error::DeviceNotFound)
+        );
+        bus.set_enabled(shadow.base(), true).unwrap();
+        assert_eq!(*bus.access(MmioAddress(0x1800), 4).unwrap(), 2);
+        assert_eq!(
+            bus.split_for(Error::DeviceNotFound, MmioAddress(0x17fe), 4),
+            Ok(vec![
+                (MmioAddress(0x17fe), 0..2),
+                (MmioAddress(0x1800), 2..4)
+            ])
+        );
+
+        // Deregistering the regular range takes the shadow along.
+        assert!(!bus.is_empty());
+        assert_eq!(bus.deregister(MmioAddress(0x1000)), Some((range, 1)));
+        assert!(bus.is_empty());
+        assert_eq!(bus.len(), 0);
+        assert_eq!(bus.shadows().count(), 0);
+        assert!(bus.device(shadow.base()).is_none());
+        bus.register(range, 5).unwrap();
+        assert_eq!(*bus.access(shadow.base(), 4).unwrap(), 5);
+    }
+
+    #[test]
+    fn test_relocate() {
+        let mut bus = Bus::new();
+        let range = MmioRange::new(MmioAddress(0x1000), 0x1000).unwrap();
+        let moved = MmioRange::new(MmioAddress(0x8000), 0x1000).unwrap();
+        bus.register_with_constraints(range, 1u8, AccessConstraints::new(&[4]))
+            .unwrap();
+        bus.register_shadow(MmioRange::new(MmioAddress(0x1800), 0x100).unwrap(), 2)
+            .unwrap();
+        bus.register(MmioRange::new(MmioAddress(0x4000), 0x1000).unwrap(), 3)
+            .unwrap();
+        bus.reserve(
+            MmioRange::new(MmioAddress(0x6000), 0x10).unwrap(),
+            "firmware",
+        )
+        .unwrap();
+        bus.set_enabled(range.base(), false).unwrap();
+
+        assert_eq!(
+            bus.relocate(MmioAddress(0x100), moved.base()),
+            Err(Error::DeviceNotFound)
+        );
+        assert_eq!(
+            bus.relocate(range.base(), MmioAddress(0x3800)),
+            Err(Error::DeviceOverlap)
+        );
+        assert_eq!(
+            bus.relocate(range.base(), MmioAddress(0x5800)),
+            Err(Error::RangeReserved)
+        );
+        assert_eq!(
+            bus.relocate(range.base(), MmioAddress(u64::MAX - 0x10)),
+            Err(Error::InvalidRange)
+        );
+        assert_eq!(bus.device(range.base()), Some((&range, &1)));
+
+        assert_eq!(bus.relocate(range.base(), moved.base()), Ok((range, moved)));
+        assert!(bus.device(range.base()).is_none());
+        assert_eq!(bus.device(MmioAddress(0x8800)).unwrap().1, &2);
+        // The registration state moves along with the range.
+        assert!(!bus.is_enabled(&moved));
+        bus.set_enabled(moved.base(), true).unwrap();
+        assert_eq!(
+            bus.check_access(moved.base(), 2),
+            Err(Error::UnsupportedAccess)
+        );
+        assert_eq!(bus.check_access(moved.base(), 4).unwrap().1, &1);
+    }
+
+    #[test]
+    fn test_decode_enable() {
+        let mut bus = Bus::new();
+        let range = MmioRange::new(MmioAddress(0x1000), 0x1000).unwrap();
+        bus.register(range, 1u8).unwrap();
+        bus.set_fallback(Some(2u8));
+        assert_eq!(
+            bus.set_enabled(MmioAddress(0), false),
+            Err(Error::DeviceNotFound)
+        );
+
+        bus.set_enabled(MmioAddress(0x1800), false).unwrap();
+        assert!(!bus.is_enabled(&range));
+        assert_eq!(
+            bus.access(MmioAddress(0x1000), 4).err(),
+            Some(Error::DeviceNotFound)
+        );
+        assert_eq!(
+            bus.fallback_for(Error::DeviceNotFound, MmioAddress(0x1000), 4),
+            Ok(&2)
+        );
+        // The registration is kept.
+        assert_eq!(bus.register(range, 3u8), Err(Error::DeviceOverlap));
+
+        bus.set_enabled(MmioAddress(0x1000), true).unwrap();
+        assert_eq!(*bus.access(MmioAddress(0x1000), 4).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_reservations() {
+        let mut bus = Bus::new();
+        let tss = MmioRange::new(MmioAddress(0xfffb_d000), 0x3000).unwrap();
+        let inside = MmioRange::new(MmioAddress(0xfffb_e000), 0x10).unwrap();
+        bus.reserve(tss, "kvm-tss").unwrap();
+        assert_eq!(bus.reserve(inside, "other"), Err(Error::RangeReserved));
+        assert_eq!(bus.register(inside, 1u8), Err(Error::RangeReserved));
+        assert_eq!(
+            bus.reservations().collect::<Vec<_>>(),
+            vec![(&tss, "kvm-tss")]
+        );
+        assert!(bus.device(inside.base()).is_none());
+
+        let range = MmioRange::new(MmioAddress(0x1000), 0x1000).unwrap();
+        bus.register(range, 1u8).unwrap();
+        assert_eq!(bus.reserve(range, "other"), Err(Error::DeviceOverlap));
+
+        assert_eq!(
+            bus.unreserve(inside.base()),
+            Some((tss, "kvm-tss".to_string()))
+        );
+        bus.register(inside, 1u8).unwrap();
+    }
+
+    #[test]
+    fn test_find_free_range() {
+        let mut bus = Bus::new();
+        let window = MmioRange::new(MmioAddress(0x1000), 0x10000).unwrap();
+        let range = |base, size| MmioRange::new(MmioAddress(base), size).unwrap();
+        let found = bus.find_free_range(0x1000, 0x1000, window).unwrap();
+        assert_eq!((found.base(), found.size()), (MmioAddress(0x1000), 0x1000));
+
+        bus.register(range(0x1000, 0x800), 1u8).unwrap();
+        bus.register(range(0x2000, 0x2000), 2u8).unwrap();
+        bus.reserve(range(0x5000, 0x1000), "reserved").unwrap();
+        // The gap between the first two ranges is too small once aligned.
+        let found = bus.find_free_range(0x1000, 0x1000, window).unwrap();
+        assert_eq!(found.base(), MmioAddress(0x4000));
+        let found = bus.find_free_range(0x800, 0x800, window).unwrap();
+        assert_eq!(found.base(), MmioAddress(0x1800));
+        let found = bus.find_free_range(0x2000, 0, window).unwrap();
+        assert_eq!(found.base(), MmioAddress(0x6000));
+        bus.register(found, 3u8).unwrap();
+
+        assert!(bus.find_free_range(0x10000, 1, window).is_none());
+        assert!(bus.find_free_range(0, 1, window).is_none());
+        let found = bus
+            .find_free_range(0x1000, 0x1000, range(u64::MAX - 0x1fff, 0x2000))
+            .unwrap();
+        assert_eq!(found.last(), MmioAddress(u64::MAX - 0x1000));
+        assert!(bus
+            .find_free_range(0x1000, 0x1000, range(u64::MAX - 0x7ff, 0x800))
+            .is_none());
+    }
+
     #[test]
     fn test_bus() {
         let base = MmioAddress(10);
@@ -150,9 +1477,13 @@ mod test {
         let device = 1u8;
 
         assert_eq!(bus.devices.len(), 0);
+        assert!(bus.is_empty());
 
         bus.register(range, device).unwrap();
         assert_eq!(bus.devices.len(), 1);
+        assert_eq!(bus.len(), 1);
+        assert_eq!(bus.iter().collect::<Vec<_>>(), vec![(&range, &device)]);
+        assert_eq!(bus.ranges().collect::<Vec<_>>(), vec![&range]);
 
         assert!(bus.device(base_prev).is_none());
         assert!(bus.device_mut(base_prev).is_none());
@@ -258,4 +1589,68 @@ mod test {
             );
         }
     }
+
+    // Run the same registrations against a bus using the storage `S`, and return the layout
+    // and lookups it ends up with.
+    fn storage_layout<S: Storage>() -> Vec<(u64, u64, Option<u16>)> {
+        let mut bus = Bus::<MmioAddress, u16, S>::default();
+        // Register the ranges out of order, so the backends have to keep them sorted.
+        for idx in (0..64u64).map(|i| (i * 37) % 64) {
+            let range = MmioRange::new(MmioAddress(idx * 0x100), 0x80).unwrap();
+            bus.register(range, idx as u16).unwrap();
+        }
+        let overlap = MmioRange::new(MmioAddress(0x1070), 0x20).unwrap();
+        assert_eq!(bus.register(overlap, 0), Err(Error::DeviceOverlap));
+        let gap = MmioRange::new(MmioAddress(0x1080), 0x80).unwrap();
+        bus.register(gap, 0xffff).unwrap();
+        for idx in (0..64u64).filter(|i| i % 3 == 0) {
+            assert_eq!(
+                bus.deregister(MmioAddress(idx * 0x100 + 1)).unwrap().1,
+                idx as u16
+            );
+        }
+        let shadow = MmioRange::new(MmioAddress(0x2020), 0x10).unwrap();
+        bus.register_shadow(shadow, 0x8000).unwrap();
+        *bus.device_mut(MmioAddress(0x2021)).unwrap().1 += 1;
+        assert_eq!(bus.len(), 43);
+
+        // Straddling the shadow is rejected, but the access can be split around it.
+        assert!(bus.check_access(MmioAddress(0x201e), 4).is_err());
+        bus.set_split_accesses(true);
+        assert_eq!(
+            bus.split_for(Error::DeviceNotFound, MmioAddress(0x201e), 4),
+            Ok(vec![
+                (MmioAddress(0x201e), 0..2),
+                (MmioAddress(0x2020), 2..4)
+            ])
+        );
+
+        let mut layout: Vec<_> = bus
+            .iter()
+            .map(|(range, _)| {
+                let addr = MmioAddress(range.base().0 + 0x7f);
+                (
+                    range.base().0,
+                    range.size(),
+                    bus.device(addr).map(|pair| *pair.1),
+                )
+            })
+            .collect();
+        layout.extend((0..0x4000).step_by(0x10).map(|addr| {
+            let device = bus.device(MmioAddress(addr)).map(|pair| *pair.1);
+            (addr, 0, device)
+        }));
+        layout
+    }
+
+    #[test]
+    fn test_storage_backends() {
+        let layout = storage_layout::<SortedVecStorage>();
+        assert_eq!(layout[0], (0x100, 0x80, Some(1)));
+        assert!(layout.contains(&(0x1080, 0x80, Some(0xffff))));
+        assert!(layout.contains(&(0x2020, 0, Some(0x8001))));
+        assert!(layout.contains(&(0x3000, 0, None)));
+        assert_eq!(storage_layout::<BTreeStorage>(), layout);
+        assert_eq!(storage_layout::<IntervalTreeStorage>(), layout);
+    }
 }
@@ -0,0 +1,158 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use super::{BusAddress, BusRange};
+
+/// Devices which see a copy of the accesses to a range, after the device owning the range
+/// handled them, e.g. protocol analyzers or coverage tools. Observers can't change the
+/// outcome of an access.
+pub trait BusObserver<A: BusAddress>: Send + Sync {
+    /// Observe a read at `addr`, which returned `data`.
+    fn observe_read(&self, addr: A, data: &[u8]);
+
+    /// Observe a write of `data` at `addr`.
+    fn observe_write(&self, addr: A, data: &[u8]);
+}
+
+/// Identifies an installed observer.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct ObserverId(u64);
+
+#[derive(Clone)]
+struct Observer<A: BusAddress> {
+    range: BusRange<A>,
+    device: Arc<dyn BusObserver<A>>,
+}
+
+// Observers installed on a bus, keyed by their priority and id, so they run in the same
+// order for every access.
+#[derive(Clone)]
+pub(super) struct Observers<A: BusAddress> {
+    next_id: u64,
+    entries: BTreeMap<(u32, ObserverId), Observer<A>>,
+}
+
+impl<A: BusAddress> Default for Observers<A> {
+    fn default() -> Self {
+        Observers {
+            next_id: 0,
+            entries: BTreeMap::new(),
+        }
+    }
+}
+
+impl<A: BusAddress> Observers<A> {
+    pub(super) fn add(
+        &mut self,
+        range: BusRange<A>,
+        priority: u32,
+        device: Arc<dyn BusObserver<A>>,
+    ) -> ObserverId {
+        let id = ObserverId(self.next_id);
+        self.next_id += 1;
+        self.entries
+            .insert((priority, id), Observer { range, device });
+        id
+    }
+
+    pub(super) fn remove(&mut self, id: ObserverId) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|key, _| key.1 != id);
+        self.entries.len() != len
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Pass the access described by `access_range` to the observers of the ranges it overlaps.
+    pub(super) fn notify(&self, addr: A, access_range: &BusRange<A>, data: &[u8], write: bool) {
+        for observer in self.entries.values() {
+            if !observer.range.overlaps(access_range) {
+                continue;
+            }
+            if write {
+                observer.device.observe_write(addr, data);
+            } else {
+                observer.device.observe_read(addr, data);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use crate::bus::{MmioAddress, MmioRange};
+
+    // Whether the access is a write, its address, and its data.
+    type Access = (bool, u64, Vec<u8>);
+
+    #[derive(Default)]
+    struct Recorder {
+        log: Mutex<Vec<Access>>,
+    }
+
+    impl BusObserver<MmioAddress> for Recorder {
+        fn observe_read(&self, addr: MmioAddress, data: &[u8]) {
+            self.log
+                .lock()
+                .unwrap()
+                .push((false, addr.0, data.to_vec()));
+        }
+
+        fn observe_write(&self, addr: MmioAddress, data: &[u8]) {
+            self.log.lock().unwrap().push((true, addr.0, data.to_vec()));
+        }
+    }
+
+    fn range(base: u64, size: u64) -> MmioRange {
+        MmioRange::new(MmioAddress(base), size).unwrap()
+    }
+
+    fn notify(observers: &Observers<MmioAddress>, addr: u64, data: &[u8], write: bool) {
+        let access = range(addr, data.len() as u64);
+        observers.notify(MmioAddress(addr), &access, data, write);
+    }
+
+    #[test]
+    fn test_observers() {
+        let mut observers = Observers::default();
+        assert!(observers.is_empty());
+        let recorder = Arc::new(Recorder::default());
+        let id = observers.add(range(0x1000, 0x10), 0, recorder.clone());
+        assert!(!observers.is_empty());
+
+        // Reads and writes are seen with their address, size and data, including the ones
+        // which only partly overlap the range.
+        notify(&observers, 0x1000, &[0x12, 0x34], true);
+        notify(&observers, 0x1004, &[0x56, 0x78, 0x9a, 0xbc], false);
+        notify(&observers, 0xffe, &[1, 2, 3, 4], false);
+        notify(&observers, 0x100f, &[5], true);
+        // Accesses next to the range aren't seen.
+        notify(&observers, 0xffc, &[0; 4], true);
+        notify(&observers, 0x1010, &[0; 8], false);
+        assert_eq!(
+            *recorder.log.lock().unwrap(),
+            vec![
+                (true, 0x1000, vec![0x12, 0x34]),
+                (false, 0x1004, vec![0x56, 0x78, 0x9a, 0xbc]),
+                (false, 0xffe, vec![1, 2, 3, 4]),
+                (true, 0x100f, vec![5]),
+            ]
+        );
+
+        // Removed observers don't see further accesses.
+        assert!(observers.remove(id));
+        assert!(!observers.remove(id));
+        assert!(observers.is_empty());
+        notify(&observers, 0x1000, &[0; 4], true);
+        assert_eq!(recorder.log.lock().unwrap().len(), 4);
+    }
+}
@@ -2,8 +2,12 @@
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
 use std::cmp::Ordering;
+use std::convert::TryFrom;
 
-use crate::bus::{BusAddress, Error, MmioAddress, PioAddress};
+use crate::bus::{
+    BusAddress, CpuidAddress, Error, Mmio32Address, MmioAddress, MsrAddress, PioAddress,
+    SysRegAddress,
+};
 
 /// An interval in the address space of a bus.
 #[derive(Copy, Clone, Debug)]
@@ -56,6 +60,64 @@ impl<A: BusAddress> BusRange<A> {
     }
 }
 
+/// Length of an access, validated and converted to the value type of the bus ahead of time.
+/// Lookups performed with an `AccessSize` don't convert the length, nor build the range of
+/// the access, so accesses of a fixed width (e.g. the 4-byte registers of a device) can
+/// reuse the same value.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct AccessSize<A: BusAddress> {
+    len: usize,
+    // Distance from the first to the last byte of the access, i.e. the length minus one.
+    span: A::V,
+}
+
+impl<A: BusAddress> AccessSize<A> {
+    /// Validate the access length `len`.
+    pub fn new(len: usize) -> Result<Self, Error> {
+        let size = A::V::try_from(len).map_err(|_| Error::InvalidAccessLength(len))?;
+        if len == 0 {
+            return Err(Error::InvalidRange);
+        }
+        Ok(AccessSize {
+            len,
+            span: size - 1.into(),
+        })
+    }
+
+    /// Return the length of the access in bytes.
+    #[inline]
+    pub fn as_usize(&self) -> usize {
+        self.len
+    }
+
+    /// Return the last address of the access starting at `addr`, if it doesn't overflow.
+    #[inline]
+    pub fn last(&self, addr: A) -> Option<A> {
+        addr.checked_add(self.span)
+    }
+}
+
+/// Access lengths accepted by the lookups of a bus: either a `usize`, validated by each
+/// lookup, or an `AccessSize` validated ahead of time.
+pub trait IntoAccessSize<A: BusAddress>: Copy {
+    /// Return the validated access length.
+    fn into_access_size(self) -> Result<AccessSize<A>, Error>;
+}
+
+impl<A: BusAddress> IntoAccessSize<A> for usize {
+    #[inline]
+    fn into_access_size(self) -> Result<AccessSize<A>, Error> {
+        AccessSize::new(self)
+    }
+}
+
+impl<A: BusAddress> IntoAccessSize<A> for AccessSize<A> {
+    #[inline]
+    fn into_access_size(self) -> Result<AccessSize<A>, Error> {
+        Ok(self)
+    }
+}
+
 // We need to implement the following traits so we can use `BusRange` values with `BTreeMap`s.
 // This usage scenario requires treating ranges as if they supported a total order, but that's
 // not really possible with intervals, so we write the implementations as if `BusRange`s were
@@ -71,7 +133,7 @@ impl<A: BusAddress> Eq for BusRange<A> {}
 
 impl<A: BusAddress> PartialOrd for BusRange<A> {
     fn partial_cmp(&self, other: &BusRange<A>) -> Option<Ordering> {
-        self.base.partial_cmp(&other.base)
+        Some(self.cmp(other))
     }
 }
 
@@ -83,7 +145,11 @@ impl<A: BusAddress> Ord for BusRange<A> {
 
 // Helper type aliases.
 pub type MmioRange = BusRange<MmioAddress>;
+pub type Mmio32Range = BusRange<Mmio32Address>;
 pub type PioRange = BusRange<PioAddress>;
+pub type SysRegRange = BusRange<SysRegAddress>;
+pub type MsrRange = BusRange<MsrAddress>;
+pub type CpuidRange = BusRange<CpuidAddress>;
 
 #[cfg(test)]
 mod tests {
@@ -96,10 +162,10 @@ mod tests {
 
         assert_eq!(BusRange::new(base_zero, 0), Err(Error::InvalidRange));
 
-        assert!(BusRange::new(base_zero, std::u64::MAX).is_ok());
-        assert!(BusRange::new(MmioAddress(1), std::u64::MAX).is_ok());
+        assert!(BusRange::new(base_zero, u64::MAX).is_ok());
+        assert!(BusRange::new(MmioAddress(1), u64::MAX).is_ok());
         assert_eq!(
-            BusRange::new(MmioAddress(2), std::u64::MAX),
+            BusRange::new(MmioAddress(2), u64::MAX),
             Err(Error::InvalidRange)
         );
 
@@ -174,4 +240,18 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_access_size() {
+        let size = AccessSize::<PioAddress>::new(4).unwrap();
+        assert_eq!(size.as_usize(), 4);
+        assert_eq!(size.last(PioAddress(0x3f8)), Some(PioAddress(0x3fb)));
+        assert_eq!(size.last(PioAddress(u32::MAX - 2)), None);
+        assert_eq!(AccessSize::<PioAddress>::new(0), Err(Error::InvalidRange));
+        assert_eq!(
+            AccessSize::<PioAddress>::new(usize::MAX),
+            Err(Error::InvalidAccessLength(usize::MAX))
+        );
+        assert_eq!(8usize.into_access_size(), AccessSize::<MmioAddress>::new(8));
+    }
 }
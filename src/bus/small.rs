@@ -0,0 +1,408 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use super::{
+    AccessSize, BusAddress, BusRange, Error, Mmio32Address, MmioAddress, MmioBusAddress,
+    MmioOffset, PioAddress, PioOffset,
+};
+use crate::device_manager::{MmioManager, PioManager};
+use crate::{DeviceMmio, DevicePio, IoAccess};
+
+struct SmallEntry<A: BusAddress, D> {
+    range: BusRange<A>,
+    device: D,
+    draining: AtomicBool,
+    in_flight: AtomicUsize,
+}
+
+// Access in progress to a device of a `SmallBus`, which holds off its deferred deregistration.
+struct SmallAccess<'a, A: BusAddress, D> {
+    entry: &'a SmallEntry<A, D>,
+}
+
+impl<A: BusAddress, D> Drop for SmallAccess<'_, A, D> {
+    fn drop(&mut self) {
+        self.entry.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Bus holding at most `N` ranges in a sorted array, which is searched linearly.
+///
+/// Machines with a handful of devices (microVMs often have less than 8) dispatch faster
+/// from a few contiguous entries than from the tree of a `Bus`. The small bus implements
+/// `PioManager` or `MmioManager` directly, so it can be used wherever those are expected,
+/// but it only supports plain registrations: there are no shadow ranges, reservations,
+/// fallback device, watchpoints, observers, or health tracking, and accesses which don't
+/// fit within a single range fail.
+pub struct SmallBus<A: BusAddress, D, const N: usize> {
+    // The first `len` entries are set, in ascending address order.
+    entries: [Option<SmallEntry<A, D>>; N],
+    len: usize,
+    // Highest address the registered ranges can cover.
+    address_limit: Option<A>,
+}
+
+impl<A: BusAddress, D, const N: usize> Default for SmallBus<A, D, N> {
+    fn default() -> Self {
+        SmallBus {
+            entries: std::array::from_fn(|_| None),
+            len: 0,
+            address_limit: None,
+        }
+    }
+}
+
+impl<A: BusAddress, D, const N: usize> SmallBus<A, D, N> {
+    /// Create an empty bus.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Return the number of registered ranges.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Return `true` if no range is registered.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Iterate over the ranges and their devices, in ascending address order.
+    pub fn iter(&self) -> impl Iterator<Item = (&BusRange<A>, &D)> {
+        self.entries[..self.len]
+            .iter()
+            .flatten()
+            .map(|entry| (&entry.range, &entry.device))
+    }
+
+    fn position(&self, addr: A) -> Option<usize> {
+        for (idx, entry) in self.entries[..self.len].iter().flatten().enumerate() {
+            if addr < entry.range.base() {
+                break;
+            }
+            if addr <= entry.range.last() {
+                return Some(idx);
+            }
+        }
+        None
+    }
+
+    fn entry(&self, addr: A) -> Option<&SmallEntry<A, D>> {
+        self.position(addr)
+            .and_then(|idx| self.entries[idx].as_ref())
+    }
+
+    /// Return the registered range and device associated with `addr`.
+    pub fn device(&self, addr: A) -> Option<(&BusRange<A>, &D)> {
+        self.entry(addr).map(|entry| (&entry.range, &entry.device))
+    }
+
+    /// Reject the ranges which go past `limit`, like `Bus::set_address_limit`.
+    pub fn set_address_limit(&mut self, limit: Option<A>) {
+        self.address_limit = limit;
+    }
+
+    /// Register `device` with `range`. Fail with `Error::BusFull` if `N` ranges are already
+    /// registered.
+    pub fn register(&mut self, range: BusRange<A>, device: D) -> Result<(), Error> {
+        if self.address_limit.is_some_and(|limit| range.last() > limit) {
+            return Err(Error::InvalidRange);
+        }
+        let mut idx = 0;
+        for entry in self.entries[..self.len].iter().flatten() {
+            if entry.range.overlaps(&range) {
+                return Err(Error::DeviceOverlap);
+            }
+            if entry.range.base() < range.base() {
+                idx += 1;
+            }
+        }
+        if self.len == N {
+            return Err(Error::BusFull);
+        }
+        self.entries[idx..=self.len].rotate_right(1);
+        self.entries[idx] = Some(SmallEntry {
+            range,
+            device,
+            draining: AtomicBool::new(false),
+            in_flight: AtomicUsize::new(0),
+        });
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Deregister the device registered at `addr`, and return it with its range.
+    pub fn deregister(&mut self, addr: A) -> Option<(BusRange<A>, D)> {
+        let idx = self.position(addr)?;
+        let entry = self.entries[idx].take()?;
+        self.entries[idx..self.len].rotate_left(1);
+        self.len -= 1;
+        Some((entry.range, entry.device))
+    }
+
+    /// Start the deferred deregistration of the device registered at `addr`.
+    pub fn begin_deregister(&self, addr: A) -> Result<(), Error> {
+        let entry = self.entry(addr).ok_or(Error::DeviceNotFound)?;
+        entry.draining.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Return whether the device registered at `addr` is draining and no longer has any
+    /// access in progress.
+    pub fn is_drained(&self, addr: A) -> bool {
+        self.entry(addr).is_some_and(|entry| {
+            entry.draining.load(Ordering::SeqCst) && entry.in_flight.load(Ordering::SeqCst) == 0
+        })
+    }
+
+    /// Complete the deferred deregistration of the device registered at `addr`.
+    pub fn complete_deregister(&mut self, addr: A) -> Result<(BusRange<A>, D), Error> {
+        let entry = self.entry(addr).ok_or(Error::DeviceNotFound)?;
+        if !entry.draining.load(Ordering::SeqCst) {
+            return Err(Error::DeviceNotDraining);
+        }
+        if entry.in_flight.load(Ordering::SeqCst) != 0 {
+            return Err(Error::DeviceBusy);
+        }
+        self.deregister(addr).ok_or(Error::DeviceNotFound)
+    }
+
+    #[inline]
+    fn access(&self, addr: A, len: usize) -> Result<SmallAccess<'_, A, D>, Error> {
+        let last = AccessSize::new(len)?
+            .last(addr)
+            .ok_or(Error::InvalidRange)?;
+        let entry = self
+            .entry(addr)
+            .filter(|entry| entry.range.last() >= last)
+            .ok_or(Error::DeviceNotFound)?;
+        // Same ordering as `Bus::access`, so a concurrent `begin_deregister` either sees the
+        // access, or the access sees the flag.
+        entry.in_flight.fetch_add(1, Ordering::SeqCst);
+        let access = SmallAccess { entry };
+        if entry.draining.load(Ordering::SeqCst) {
+            return Err(Error::DeviceNotFound);
+        }
+        Ok(access)
+    }
+}
+
+impl<D: DevicePio, const N: usize> PioManager for SmallBus<PioAddress, D, N> {
+    type D = D;
+
+    fn pio_device(&self, addr: PioAddress) -> Option<(&BusRange<PioAddress>, &D)> {
+        self.device(addr)
+    }
+
+    fn pio_read(&self, addr: PioAddress, data: &mut [u8]) -> Result<(), Error> {
+        self.pio_read_with(addr, IoAccess::default(), data)
+    }
+
+    fn pio_write(&self, addr: PioAddress, data: &[u8]) -> Result<(), Error> {
+        self.pio_write_with(addr, IoAccess::default(), data)
+    }
+
+    fn pio_read_with(
+        &self,
+        addr: PioAddress,
+        access: IoAccess,
+        data: &mut [u8],
+    ) -> Result<(), Error> {
+        let in_flight = self.access(addr, data.len())?;
+        let entry = in_flight.entry;
+        let offset = PioOffset(addr - entry.range.base());
+        entry
+            .device
+            .pio_read_with(access, entry.range.base(), offset, data);
+        Ok(())
+    }
+
+    fn pio_write_with(&self, addr: PioAddress, access: IoAccess, data: &[u8]) -> Result<(), Error> {
+        let in_flight = self.access(addr, data.len())?;
+        let entry = in_flight.entry;
+        let offset = PioOffset(addr - entry.range.base());
+        entry
+            .device
+            .pio_write_with(access, entry.range.base(), offset, data);
+        Ok(())
+    }
+
+    fn register_pio(&mut self, range: BusRange<PioAddress>, device: D) -> Result<(), Error> {
+        self.register(range, device)
+    }
+
+    fn deregister_pio(&mut self, addr: PioAddress) -> Option<(BusRange<PioAddress>, D)> {
+        self.deregister(addr)
+    }
+
+    fn begin_deregister_pio(&self, addr: PioAddress) -> Result<(), Error> {
+        self.begin_deregister(addr)
+    }
+
+    fn pio_drained(&self, addr: PioAddress) -> bool {
+        self.is_drained(addr)
+    }
+
+    fn complete_deregister_pio(
+        &mut self,
+        addr: PioAddress,
+    ) -> Result<(BusRange<PioAddress>, D), Error> {
+        self.complete_deregister(addr)
+    }
+}
+
+impl<A: MmioBusAddress, D: DeviceMmio, const N: usize> SmallBus<A, D, N> {
+    fn dispatch_mmio_read(&self, addr: A, access: IoAccess, data: &mut [u8]) -> Result<(), Error> {
+        let in_flight = self.access(addr, data.len())?;
+        let entry = in_flight.entry;
+        let offset = MmioOffset(A::offset_to_u64(addr - entry.range.base()));
+        let base = entry.range.base().to_mmio_address();
+        entry.device.mmio_read_with(access, base, offset, data);
+        Ok(())
+    }
+
+    fn dispatch_mmio_write(&self, addr: A, access: IoAccess, data: &[u8]) -> Result<(), Error> {
+        let in_flight = self.access(addr, data.len())?;
+        let entry = in_flight.entry;
+        let offset = MmioOffset(A::offset_to_u64(addr - entry.range.base()));
+        let base = entry.range.base().to_mmio_address();
+        entry.device.mmio_write_with(access, base, offset, data);
+        Ok(())
+    }
+}
+
+// `MmioManager` is implemented for each MMIO address type, as a generic implementation
+// would conflict with the one for the `BusManager` implementations.
+macro_rules! small_mmio_manager {
+    ($A:ty) => {
+        impl<D: DeviceMmio, const N: usize> MmioManager<$A> for SmallBus<$A, D, N> {
+            type D = D;
+
+            fn mmio_device(&self, addr: $A) -> Option<(&BusRange<$A>, &D)> {
+                self.device(addr)
+            }
+
+            fn mmio_read(&self, addr: $A, data: &mut [u8]) -> Result<(), Error> {
+                self.dispatch_mmio_read(addr, IoAccess::default(), data)
+            }
+
+            fn mmio_write(&self, addr: $A, data: &[u8]) -> Result<(), Error> {
+                self.dispatch_mmio_write(addr, IoAccess::default(), data)
+            }
+
+            fn mmio_read_with(
+                &self,
+                addr: $A,
+                access: IoAccess,
+                data: &mut [u8],
+            ) -> Result<(), Error> {
+                self.dispatch_mmio_read(addr, access, data)
+            }
+
+            fn mmio_write_with(
+                &self,
+                addr: $A,
+                access: IoAccess,
+                data: &[u8],
+            ) -> Result<(), Error> {
+                self.dispatch_mmio_write(addr, access, data)
+            }
+
+            fn register_mmio(&mut self, range: BusRange<$A>, device: D) -> Result<(), Error> {
+                self.register(range, device)
+            }
+
+            fn deregister_mmio(&mut self, addr: $A) -> Option<(BusRange<$A>, D)> {
+                self.deregister(addr)
+            }
+
+            fn begin_deregister_mmio(&self, addr: $A) -> Result<(), Error> {
+                self.begin_deregister(addr)
+            }
+
+            fn mmio_drained(&self, addr: $A) -> bool {
+                self.is_drained(addr)
+            }
+
+            fn complete_deregister_mmio(&mut self, addr: $A) -> Result<(BusRange<$A>, D), Error> {
+                self.complete_deregister(addr)
+            }
+        }
+    };
+}
+
+small_mmio_manager!(MmioAddress);
+small_mmio_manager!(Mmio32Address);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::bus::{MmioRange, PioRange};
+    use crate::testing::EchoDevice;
+
+    #[test]
+    fn test_small_bus() {
+        let mut bus = SmallBus::<MmioAddress, Arc<EchoDevice>, 3>::new();
+        assert!(bus.is_empty());
+        let dev = Arc::new(EchoDevice::new());
+        for base in [0x3000, 0x1000, 0x2000].iter() {
+            bus.register_mmio(
+                MmioRange::new(MmioAddress(*base), 0x100).unwrap(),
+                dev.clone(),
+            )
+            .unwrap();
+        }
+        let bases: Vec<u64> = bus.iter().map(|(range, _)| range.base().0).collect();
+        assert_eq!(bases, vec![0x1000, 0x2000, 0x3000]);
+
+        let overlap = MmioRange::new(MmioAddress(0x20f0), 0x20).unwrap();
+        assert_eq!(
+            bus.register(overlap, dev.clone()),
+            Err(Error::DeviceOverlap)
+        );
+        let extra = MmioRange::new(MmioAddress(0x4000), 0x100).unwrap();
+        assert_eq!(bus.register(extra, dev.clone()), Err(Error::BusFull));
+
+        let mut data = [0; 4];
+        bus.mmio_write(MmioAddress(0x2010), &[1, 2, 3, 4]).unwrap();
+        bus.mmio_read(MmioAddress(0x2010), &mut data).unwrap();
+        assert_eq!(data, [1, 2, 3, 4]);
+        assert_eq!(
+            bus.mmio_read(MmioAddress(0x20fe), &mut data),
+            Err(Error::DeviceNotFound)
+        );
+        assert_eq!(
+            bus.mmio_read(MmioAddress(0x1800), &mut data),
+            Err(Error::DeviceNotFound)
+        );
+
+        // Deferred deregistration behaves as with a `Bus`.
+        assert_eq!(
+            bus.complete_deregister_mmio(MmioAddress(0x2000)).err(),
+            Some(Error::DeviceNotDraining)
+        );
+        bus.begin_deregister_mmio(MmioAddress(0x2000)).unwrap();
+        assert!(bus.mmio_drained(MmioAddress(0x2000)));
+        assert!(bus.mmio_write(MmioAddress(0x2000), &[0]).is_err());
+        bus.complete_deregister_mmio(MmioAddress(0x2000)).unwrap();
+        assert_eq!(bus.len(), 2);
+        bus.register(extra, dev.clone()).unwrap();
+        assert_eq!(bus.device(MmioAddress(0x40ff)).unwrap().0.base().0, 0x4000);
+
+        let mut pio_bus = SmallBus::<PioAddress, Arc<EchoDevice>, 2>::new();
+        pio_bus
+            .register_pio(PioRange::new(PioAddress(0x3f8), 8).unwrap(), dev)
+            .unwrap();
+        pio_bus.pio_write(PioAddress(0x3f9), &[0x41]).unwrap();
+        pio_bus.pio_read(PioAddress(0x3f9), &mut data[..1]).unwrap();
+        assert_eq!(data[0], 0x41);
+        assert!(pio_bus.deregister_pio(PioAddress(0x3ff)).is_some());
+        assert!(pio_bus.is_empty());
+    }
+}
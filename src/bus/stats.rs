@@ -0,0 +1,177 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Upper bounds, in nanoseconds, of the buckets of the latency histograms. The last bucket
+/// of a histogram holds the accesses slower than all of them.
+pub const LATENCY_BUCKETS_NS: [u64; 8] = [
+    250, 1_000, 4_000, 16_000, 64_000, 256_000, 1_000_000, 10_000_000,
+];
+
+const BUCKETS: usize = LATENCY_BUCKETS_NS.len() + 1;
+
+/// Distribution of the time the device of a range took to handle the accesses.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct LatencyHistogram {
+    /// Number of accesses in each bucket of `LATENCY_BUCKETS_NS`, followed by the number of
+    /// slower accesses. The counts aren't cumulative.
+    pub buckets: [u64; BUCKETS],
+    /// Total time spent handling the accesses, in nanoseconds.
+    pub sum_ns: u64,
+}
+
+impl LatencyHistogram {
+    /// Return the number of measured accesses.
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+}
+
+/// Statistics of the accesses dispatched to a range since it was registered.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct RangeStats {
+    /// Number of accesses dispatched to the device.
+    pub accesses: u64,
+    /// Number of accesses the device failed to handle. The accesses dispatched without their
+    /// context only fail when the device panics.
+    pub failures: u64,
+    /// Whether the range is quarantined.
+    pub quarantined: bool,
+    /// Time spent handling the accesses. Only measured with the `latency` feature.
+    pub latency: LatencyHistogram,
+}
+
+// Counters updated while dispatching the accesses to a range.
+#[derive(Default)]
+pub(super) struct EntryStats {
+    accesses: AtomicU64,
+    failures: AtomicU64,
+    latency: [AtomicU64; BUCKETS],
+    latency_sum_ns: AtomicU64,
+}
+
+impl EntryStats {
+    pub(super) fn count_access(&self) {
+        self.accesses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn count_failure(&self) {
+        self.failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "latency")]
+    pub(super) fn record_latency(&self, ns: u64) {
+        let bucket = LATENCY_BUCKETS_NS
+            .iter()
+            .position(|&bound| ns <= bound)
+            .unwrap_or(BUCKETS - 1);
+        self.latency[bucket].fetch_add(1, Ordering::Relaxed);
+        self.latency_sum_ns.fetch_add(ns, Ordering::Relaxed);
+    }
+
+    pub(super) fn snapshot(&self, quarantined: bool) -> RangeStats {
+        let mut latency = LatencyHistogram {
+            sum_ns: self.latency_sum_ns.load(Ordering::Relaxed),
+            ..Default::default()
+        };
+        for (count, bucket) in latency.buckets.iter_mut().zip(self.latency.iter()) {
+            *count = bucket.load(Ordering::Relaxed);
+        }
+        RangeStats {
+            accesses: self.accesses.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+            quarantined,
+            latency,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::bus::{Bus, MmioAddress, MmioRange};
+
+    #[test]
+    fn test_range_stats() {
+        let first = MmioRange::new(MmioAddress(0x1000), 0x100).unwrap();
+        let second = MmioRange::new(MmioAddress(0x2000), 0x100).unwrap();
+        let mut bus = Bus::new();
+        bus.register(first, 1u8).unwrap();
+        bus.register(second, 2u8).unwrap();
+
+        // Five accesses go to the first range, one of which fails, and a single one to the
+        // second range. Accesses which don't reach a range aren't accounted anywhere.
+        for failed in [false, false, false, true, false].iter() {
+            bus.access(MmioAddress(0x1010), 4).unwrap().record(*failed);
+        }
+        bus.access(MmioAddress(0x2000), 1).unwrap().record(false);
+        assert!(bus.access(MmioAddress(0x3000), 4).is_err());
+
+        let stats = bus.stats();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].0, first);
+        assert_eq!((stats[0].1.accesses, stats[0].1.failures), (5, 1));
+        assert!(!stats[0].1.quarantined);
+        assert_eq!(stats[1].0, second);
+        assert_eq!((stats[1].1.accesses, stats[1].1.failures), (1, 0));
+
+        // Every access is measured once it completes.
+        let count = if cfg!(feature = "latency") { 5 } else { 0 };
+        assert_eq!(stats[0].1.latency.count(), count);
+
+        // Quarantined ranges keep their statistics.
+        bus.set_quarantine_threshold(Some(1));
+        assert!(bus.access(MmioAddress(0x2000), 1).unwrap().record(true));
+        let stats = bus.stats();
+        assert_eq!((stats[1].1.accesses, stats[1].1.failures), (2, 1));
+        assert!(stats[1].1.quarantined);
+    }
+
+    #[test]
+    fn test_entry_stats() {
+        let stats = EntryStats::default();
+        assert_eq!(stats.snapshot(false), RangeStats::default());
+
+        for _ in 0..4 {
+            stats.count_access();
+        }
+        stats.count_failure();
+        let snapshot = stats.snapshot(true);
+        assert_eq!((snapshot.accesses, snapshot.failures), (4, 1));
+        assert!(snapshot.quarantined);
+        assert_eq!(snapshot.latency, LatencyHistogram::default());
+    }
+
+    #[cfg(feature = "latency")]
+    #[test]
+    fn test_latency_buckets() {
+        let stats = EntryStats::default();
+        // The bounds belong to their bucket, and the accesses slower than the last one go
+        // to the overflow bucket.
+        for ns in [
+            0,
+            250,
+            251,
+            1_000,
+            64_000,
+            10_000_000,
+            10_000_001,
+            u32::MAX as u64,
+        ]
+        .iter()
+        {
+            stats.record_latency(*ns);
+        }
+        let latency = stats.snapshot(false).latency;
+        assert_eq!(latency.buckets, [2, 2, 0, 0, 1, 0, 0, 1, 2]);
+        assert_eq!(latency.count(), 8);
+        assert_eq!(
+            latency.sum_ns,
+            250 + 251 + 1_000 + 64_000 + 10_000_000 + 10_000_001 + u32::MAX as u64
+        );
+        // Recording latencies doesn't count accesses, which the bus does on dispatch.
+        assert_eq!(stats.snapshot(false).accesses, 0);
+    }
+}
@@ -0,0 +1,520 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::ops::Bound::{Excluded, Unbounded};
+
+use super::{BusAddress, BusRange};
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// Selects the data structure a `Bus` keeps its ranges in. The trait is sealed; the
+/// available backends are `SortedVecStorage` (the default), `BTreeStorage`, and
+/// `IntervalTreeStorage`.
+pub trait Storage: sealed::Sealed + 'static {
+    #[doc(hidden)]
+    type Map<A: BusAddress, E: Clone>: RangeMap<A, E>;
+}
+
+/// Operations of the maps from disjoint ranges to values a `Bus` is built upon. Ranges are
+/// identified by their base address.
+pub trait RangeMap<A: BusAddress, E>: Clone + Default {
+    /// Return the number of ranges.
+    fn len(&self) -> usize;
+
+    /// Return the value of the range with the same base as `range`.
+    fn get(&self, range: &BusRange<A>) -> Option<&E>;
+
+    /// Return the range containing `addr`, and its value.
+    fn containing(&self, addr: A) -> Option<(&BusRange<A>, &E)>;
+
+    /// Same as `containing`, with a mutable reference to the value.
+    fn containing_mut(&mut self, addr: A) -> Option<(&BusRange<A>, &mut E)>;
+
+    /// Return the lowest range overlapping the addresses from `base` to `last`, and its
+    /// value. The bounds are passed separately, so accesses can be checked without building
+    /// a range.
+    fn first_overlapping(&self, base: A, last: A) -> Option<(&BusRange<A>, &E)>;
+
+    /// Insert `range`, which must not overlap the other ones.
+    fn insert(&mut self, range: BusRange<A>, value: E);
+
+    /// Remove the range with the same base as `range`, and return its value.
+    fn remove(&mut self, range: &BusRange<A>) -> Option<E>;
+
+    /// Iterate over the ranges and their values, in ascending address order.
+    fn iter<'a>(&'a self) -> impl Iterator<Item = (&'a BusRange<A>, &'a E)>
+    where
+        A: 'a,
+        E: 'a;
+}
+
+/// Keeps the ranges in a `BTreeMap`, which scales best when devices are registered and
+/// deregistered often (e.g. hotplug-heavy PCI buses).
+pub struct BTreeStorage;
+
+/// Keeps the ranges in a vector sorted by address, which is searched with a binary search.
+/// Lookups touch less memory than with the other backends, which makes it the default for
+/// buses which are mostly dispatching accesses.
+pub struct SortedVecStorage;
+
+/// Keeps the ranges in a balanced interval tree, so the overlap checks done when registering
+/// ranges and dispatching accesses don't have to walk all the ranges.
+pub struct IntervalTreeStorage;
+
+impl sealed::Sealed for BTreeStorage {}
+impl sealed::Sealed for SortedVecStorage {}
+impl sealed::Sealed for IntervalTreeStorage {}
+
+impl Storage for BTreeStorage {
+    type Map<A: BusAddress, E: Clone> = BTreeMap<BusRange<A>, E>;
+}
+
+impl Storage for SortedVecStorage {
+    type Map<A: BusAddress, E: Clone> = SortedVec<A, E>;
+}
+
+impl Storage for IntervalTreeStorage {
+    type Map<A: BusAddress, E: Clone> = IntervalTree<A, E>;
+}
+
+impl<A: BusAddress, E: Clone> RangeMap<A, E> for BTreeMap<BusRange<A>, E> {
+    fn len(&self) -> usize {
+        BTreeMap::len(self)
+    }
+
+    fn get(&self, range: &BusRange<A>) -> Option<&E> {
+        BTreeMap::get(self, range)
+    }
+
+    fn containing(&self, addr: A) -> Option<(&BusRange<A>, &E)> {
+        self.range(..=BusRange::unit(addr))
+            .next_back()
+            .filter(|pair| pair.0.last() >= addr)
+    }
+
+    fn containing_mut(&mut self, addr: A) -> Option<(&BusRange<A>, &mut E)> {
+        self.range_mut(..=BusRange::unit(addr))
+            .next_back()
+            .filter(|pair| pair.0.last() >= addr)
+    }
+
+    fn first_overlapping(&self, base: A, last: A) -> Option<(&BusRange<A>, &E)> {
+        self.containing(base).or_else(|| {
+            self.range((Excluded(BusRange::unit(base)), Unbounded))
+                .next()
+                .filter(|pair| pair.0.base() <= last)
+        })
+    }
+
+    fn insert(&mut self, range: BusRange<A>, value: E) {
+        BTreeMap::insert(self, range, value);
+    }
+
+    fn remove(&mut self, range: &BusRange<A>) -> Option<E> {
+        BTreeMap::remove(self, range)
+    }
+
+    fn iter<'a>(&'a self) -> impl Iterator<Item = (&'a BusRange<A>, &'a E)>
+    where
+        A: 'a,
+        E: 'a,
+    {
+        BTreeMap::iter(self)
+    }
+}
+
+/// Ranges sorted by address, backing `SortedVecStorage`.
+#[derive(Clone)]
+pub struct SortedVec<A: BusAddress, E> {
+    entries: Vec<(BusRange<A>, E)>,
+}
+
+impl<A: BusAddress, E> Default for SortedVec<A, E> {
+    fn default() -> Self {
+        SortedVec {
+            entries: Vec::new(),
+        }
+    }
+}
+
+impl<A: BusAddress, E> SortedVec<A, E> {
+    // Return the index of the range containing `addr`.
+    fn position(&self, addr: A) -> Option<usize> {
+        let idx = self
+            .entries
+            .partition_point(|(range, _)| range.base() <= addr)
+            .checked_sub(1)?;
+        Some(idx).filter(|&idx| self.entries[idx].0.last() >= addr)
+    }
+
+    fn find(&self, range: &BusRange<A>) -> Result<usize, usize> {
+        self.entries.binary_search_by(|(r, _)| r.cmp(range))
+    }
+}
+
+impl<A: BusAddress, E: Clone> RangeMap<A, E> for SortedVec<A, E> {
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn get(&self, range: &BusRange<A>) -> Option<&E> {
+        self.find(range).ok().map(|idx| &self.entries[idx].1)
+    }
+
+    fn containing(&self, addr: A) -> Option<(&BusRange<A>, &E)> {
+        let (range, value) = &self.entries[self.position(addr)?];
+        Some((range, value))
+    }
+
+    fn containing_mut(&mut self, addr: A) -> Option<(&BusRange<A>, &mut E)> {
+        let idx = self.position(addr)?;
+        let (range, value) = &mut self.entries[idx];
+        Some((&*range, value))
+    }
+
+    fn first_overlapping(&self, base: A, last: A) -> Option<(&BusRange<A>, &E)> {
+        // The ranges are disjoint, so their last addresses are sorted as well.
+        let idx = self.entries.partition_point(|(r, _)| r.last() < base);
+        self.entries
+            .get(idx)
+            .filter(|(r, _)| r.base() <= last)
+            .map(|(r, value)| (r, value))
+    }
+
+    fn insert(&mut self, range: BusRange<A>, value: E) {
+        match self.find(&range) {
+            Ok(idx) => self.entries[idx] = (range, value),
+            Err(idx) => self.entries.insert(idx, (range, value)),
+        }
+    }
+
+    fn remove(&mut self, range: &BusRange<A>) -> Option<E> {
+        let idx = self.find(range).ok()?;
+        Some(self.entries.remove(idx).1)
+    }
+
+    fn iter<'a>(&'a self) -> impl Iterator<Item = (&'a BusRange<A>, &'a E)>
+    where
+        A: 'a,
+        E: 'a,
+    {
+        self.entries.iter().map(|(range, value)| (range, value))
+    }
+}
+
+type Link<A, E> = Option<Box<Node<A, E>>>;
+
+#[derive(Clone)]
+struct Node<A: BusAddress, E> {
+    range: BusRange<A>,
+    value: E,
+    // Highest last address of the ranges in the subtree.
+    max_last: A,
+    // Heap priority, which keeps the tree balanced with a high probability.
+    priority: u64,
+    left: Link<A, E>,
+    right: Link<A, E>,
+}
+
+impl<A: BusAddress, E> Node<A, E> {
+    fn update(&mut self) {
+        self.max_last = [&self.left, &self.right]
+            .iter()
+            .filter_map(|child| child.as_ref().map(|node| node.max_last))
+            .fold(self.range.last(), std::cmp::max);
+    }
+}
+
+// Spread the priorities of the ranges with the finalizer of splitmix64, so the shape of the
+// tree is deterministic without depending on the order of the registrations.
+fn priority<A: BusAddress>(range: &BusRange<A>) -> u64 {
+    let mut z = range
+        .base()
+        .value()
+        .into()
+        .wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+// Split `link` into the ranges below `range`, and the other ones.
+fn split<A: BusAddress, E>(link: Link<A, E>, range: &BusRange<A>) -> (Link<A, E>, Link<A, E>) {
+    match link {
+        None => (None, None),
+        Some(mut node) => {
+            if node.range < *range {
+                let (lower, upper) = split(node.right.take(), range);
+                node.right = lower;
+                node.update();
+                (Some(node), upper)
+            } else {
+                let (lower, upper) = split(node.left.take(), range);
+                node.left = upper;
+                node.update();
+                (lower, Some(node))
+            }
+        }
+    }
+}
+
+// Join two trees, where the ranges of `lower` are all below the ones of `upper`.
+fn merge<A: BusAddress, E>(lower: Link<A, E>, upper: Link<A, E>) -> Link<A, E> {
+    match (lower, upper) {
+        (None, link) | (link, None) => link,
+        (Some(mut lower), Some(mut upper)) => {
+            if lower.priority > upper.priority {
+                lower.right = merge(lower.right.take(), Some(upper));
+                lower.update();
+                Some(lower)
+            } else {
+                upper.left = merge(Some(lower), upper.left.take());
+                upper.update();
+                Some(upper)
+            }
+        }
+    }
+}
+
+fn insert_node<A: BusAddress, E>(link: Link<A, E>, mut new: Box<Node<A, E>>) -> Box<Node<A, E>> {
+    match link {
+        None => new,
+        Some(mut node) if node.priority >= new.priority => {
+            if new.range < node.range {
+                node.left = Some(insert_node(node.left.take(), new));
+            } else {
+                node.right = Some(insert_node(node.right.take(), new));
+            }
+            node.update();
+            node
+        }
+        Some(node) => {
+            let (lower, upper) = split(Some(node), &new.range);
+            new.left = lower;
+            new.right = upper;
+            new.update();
+            new
+        }
+    }
+}
+
+fn remove_node<A: BusAddress, E>(link: &mut Link<A, E>, range: &BusRange<A>) -> Option<E> {
+    let node = link.as_mut()?;
+    let removed = match range.cmp(&node.range) {
+        Ordering::Less => remove_node(&mut node.left, range),
+        Ordering::Greater => remove_node(&mut node.right, range),
+        Ordering::Equal => {
+            let mut node = link.take()?;
+            *link = merge(node.left.take(), node.right.take());
+            return Some(node.value);
+        }
+    };
+    node.update();
+    removed
+}
+
+fn get_node_mut<'a, A: BusAddress, E>(
+    link: &'a mut Link<A, E>,
+    range: &BusRange<A>,
+) -> Option<&'a mut Node<A, E>> {
+    let node = link.as_deref_mut()?;
+    match range.cmp(&node.range) {
+        Ordering::Less => get_node_mut(&mut node.left, range),
+        Ordering::Greater => get_node_mut(&mut node.right, range),
+        Ordering::Equal => Some(node),
+    }
+}
+
+fn first_overlapping_node<A: BusAddress, E>(
+    link: &Link<A, E>,
+    base: A,
+    last: A,
+) -> Option<&Node<A, E>> {
+    let node = link.as_ref().filter(|node| node.max_last >= base)?;
+    if let Some(found) = first_overlapping_node(&node.left, base, last) {
+        return Some(found);
+    }
+    if node.range.base() > last {
+        return None;
+    }
+    if node.range.last() >= base {
+        return Some(node);
+    }
+    first_overlapping_node(&node.right, base, last)
+}
+
+/// Balanced tree of ranges augmented with the highest last address of each subtree,
+/// backing `IntervalTreeStorage`.
+#[derive(Clone)]
+pub struct IntervalTree<A: BusAddress, E> {
+    root: Link<A, E>,
+    len: usize,
+}
+
+impl<A: BusAddress, E> Default for IntervalTree<A, E> {
+    fn default() -> Self {
+        IntervalTree { root: None, len: 0 }
+    }
+}
+
+impl<A: BusAddress, E: Clone> RangeMap<A, E> for IntervalTree<A, E> {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn get(&self, range: &BusRange<A>) -> Option<&E> {
+        let mut link = &self.root;
+        while let Some(node) = link {
+            link = match range.cmp(&node.range) {
+                Ordering::Less => &node.left,
+                Ordering::Greater => &node.right,
+                Ordering::Equal => return Some(&node.value),
+            };
+        }
+        None
+    }
+
+    fn containing(&self, addr: A) -> Option<(&BusRange<A>, &E)> {
+        self.first_overlapping(addr, addr)
+    }
+
+    fn containing_mut(&mut self, addr: A) -> Option<(&BusRange<A>, &mut E)> {
+        let range = *self.containing(addr)?.0;
+        get_node_mut(&mut self.root, &range).map(|node| (&node.range, &mut node.value))
+    }
+
+    fn first_overlapping(&self, base: A, last: A) -> Option<(&BusRange<A>, &E)> {
+        first_overlapping_node(&self.root, base, last).map(|node| (&node.range, &node.value))
+    }
+
+    fn insert(&mut self, range: BusRange<A>, value: E) {
+        // The size of the range may change, which moves it within the tree.
+        self.remove(&range);
+        let node = Box::new(Node {
+            range,
+            value,
+            max_last: range.last(),
+            priority: priority(&range),
+            left: None,
+            right: None,
+        });
+        self.root = Some(insert_node(self.root.take(), node));
+        self.len += 1;
+    }
+
+    fn remove(&mut self, range: &BusRange<A>) -> Option<E> {
+        let value = remove_node(&mut self.root, range)?;
+        self.len -= 1;
+        Some(value)
+    }
+
+    fn iter<'a>(&'a self) -> impl Iterator<Item = (&'a BusRange<A>, &'a E)>
+    where
+        A: 'a,
+        E: 'a,
+    {
+        let mut stack = Vec::new();
+        let mut link = &self.root;
+        std::iter::from_fn(move || {
+            while let Some(node) = link {
+                stack.push(&**node);
+                link = &node.left;
+            }
+            let node = stack.pop()?;
+            link = &node.right;
+            Some((&node.range, &node.value))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::bus::{Bus, Error, MmioAddress, MmioRange};
+
+    fn range(base: u64, size: u64) -> MmioRange {
+        MmioRange::new(MmioAddress(base), size).unwrap()
+    }
+
+    // Run the checks shared by the backends on an empty map of `S`.
+    fn check_storage<S: Storage>() {
+        let mut map = <S as Storage>::Map::<MmioAddress, u32>::default();
+        let ranges = [
+            range(0x3000, 0x1000),
+            range(0x1000, 0x100),
+            range(0x2000, 1),
+        ];
+        for (value, range) in ranges.iter().enumerate() {
+            map.insert(*range, value as u32);
+        }
+        assert_eq!(map.len(), 3);
+        let sorted: Vec<_> = map
+            .iter()
+            .map(|(range, value)| (range.base().0, *value))
+            .collect();
+        assert_eq!(sorted, vec![(0x1000, 1), (0x2000, 2), (0x3000, 0)]);
+        assert_eq!(map.get(&ranges[1]), Some(&1));
+        // Ranges are identified by their base.
+        assert_eq!(map.get(&range(0x1000, 1)), Some(&1));
+        assert_eq!(map.get(&range(0x1001, 1)), None);
+
+        // Boundary lookups: the first and last bytes are in the range, the next one isn't.
+        let containing = |map: &<S as Storage>::Map<MmioAddress, u32>, addr| {
+            map.containing(MmioAddress(addr)).map(|(_, value)| *value)
+        };
+        assert_eq!(containing(&map, 0xfff), None);
+        assert_eq!(containing(&map, 0x1000), Some(1));
+        assert_eq!(containing(&map, 0x10ff), Some(1));
+        assert_eq!(containing(&map, 0x1100), None);
+        assert_eq!(containing(&map, 0x2000), Some(2));
+        assert_eq!(containing(&map, 0x2001), None);
+        assert_eq!(containing(&map, 0x3fff), Some(0));
+        assert_eq!(containing(&map, 0x4000), None);
+        *map.containing_mut(MmioAddress(0x10ff)).unwrap().1 += 10;
+        assert_eq!(containing(&map, 0x1000), Some(11));
+
+        // Overlaps, which the bus rejects registrations with.
+        let overlapping = |map: &<S as Storage>::Map<MmioAddress, u32>, base, last| {
+            map.first_overlapping(MmioAddress(base), MmioAddress(last))
+                .map(|(range, _)| range.base().0)
+        };
+        assert_eq!(overlapping(&map, 0, 0xfff), None);
+        assert_eq!(overlapping(&map, 0, 0x1000), Some(0x1000));
+        assert_eq!(overlapping(&map, 0x10ff, 0x3000), Some(0x1000));
+        assert_eq!(overlapping(&map, 0x1100, 0x1fff), None);
+        assert_eq!(overlapping(&map, 0x1100, 0x2fff), Some(0x2000));
+        assert_eq!(overlapping(&map, 0x2001, 0x3000), Some(0x3000));
+        assert_eq!(overlapping(&map, 0x4000, u64::MAX), None);
+
+        assert_eq!(map.remove(&ranges[1]), Some(11));
+        assert_eq!(map.remove(&ranges[1]), None);
+        assert_eq!(map.len(), 2);
+        assert_eq!(containing(&map, 0x1000), None);
+        assert_eq!(overlapping(&map, 0, 0x1fff), None);
+        assert_eq!(containing(&map, 0x2000), Some(2));
+
+        let mut bus = Bus::<MmioAddress, u32, S>::default();
+        bus.register(range(0x1000, 0x1000), 1).unwrap();
+        for (base, size) in [(0x1000, 0x1000), (0xfff, 2), (0x1fff, 1), (0x800, 0x2000)] {
+            assert_eq!(
+                bus.register(range(base, size), 2),
+                Err(Error::DeviceOverlap)
+            );
+        }
+        bus.register(range(0x2000, 1), 3).unwrap();
+        bus.register(range(0xfff, 1), 4).unwrap();
+        assert_eq!(bus.len(), 3);
+    }
+
+    #[test]
+    fn test_storage() {
+        check_storage::<SortedVecStorage>();
+        check_storage::<BTreeStorage>();
+        check_storage::<IntervalTreeStorage>();
+    }
+}
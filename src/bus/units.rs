@@ -0,0 +1,177 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+use std::fmt;
+use std::ops::{Add, Sub};
+
+use super::{MmioAddress, PioAddress, PioAddressValue};
+
+// Defines a newtype over the `$value` integer type, with the conversions and arithmetic
+// needed by device code.
+macro_rules! unit_type {
+    ($(#[$attr:meta])* $name:ident, $value:ty) => {
+        $(#[$attr])*
+        #[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+        pub struct $name(pub $value);
+
+        impl $name {
+            /// Return the inner value.
+            pub fn raw(self) -> $value {
+                self.0
+            }
+
+            /// Return the inner value as a `usize`, e.g. to index the backing buffer of a
+            /// device.
+            pub fn as_usize(self) -> usize {
+                self.0 as usize
+            }
+
+            /// Return `self + value`, if no overflow occurs.
+            pub fn checked_add(self, value: $value) -> Option<Self> {
+                self.0.checked_add(value).map($name)
+            }
+
+            /// Return `self - value`, if no underflow occurs.
+            pub fn checked_sub(self, value: $value) -> Option<Self> {
+                self.0.checked_sub(value).map($name)
+            }
+        }
+
+        impl From<$value> for $name {
+            fn from(value: $value) -> Self {
+                $name(value)
+            }
+        }
+
+        impl From<$name> for $value {
+            fn from(value: $name) -> Self {
+                value.0
+            }
+        }
+
+        impl PartialEq<$value> for $name {
+            fn eq(&self, other: &$value) -> bool {
+                self.0 == *other
+            }
+        }
+
+        impl PartialOrd<$value> for $name {
+            fn partial_cmp(&self, other: &$value) -> Option<std::cmp::Ordering> {
+                self.0.partial_cmp(other)
+            }
+        }
+
+        impl Add<$value> for $name {
+            type Output = Self;
+
+            fn add(self, rhs: $value) -> Self::Output {
+                $name(self.0 + rhs)
+            }
+        }
+
+        impl Sub<$value> for $name {
+            type Output = Self;
+
+            fn sub(self, rhs: $value) -> Self::Output {
+                $name(self.0 - rhs)
+            }
+        }
+
+        impl fmt::LowerHex for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                fmt::LowerHex::fmt(&self.0, f)
+            }
+        }
+    };
+}
+
+unit_type!(
+    /// Offset of an access from the base of the MMIO range it targets, as observed by
+    /// devices.
+    MmioOffset,
+    u64
+);
+
+unit_type!(
+    /// Size of a MMIO range, in bytes.
+    MmioSize,
+    u64
+);
+
+unit_type!(
+    /// Offset of an access from the base of the PIO range it targets, as observed by
+    /// devices.
+    PioOffset,
+    PioAddressValue
+);
+
+unit_type!(
+    /// Size of a PIO range, in ports.
+    PioSize,
+    PioAddressValue
+);
+
+impl From<PioOffset> for u64 {
+    fn from(offset: PioOffset) -> Self {
+        u64::from(offset.0)
+    }
+}
+
+impl From<PioSize> for u64 {
+    fn from(size: PioSize) -> Self {
+        u64::from(size.0)
+    }
+}
+
+impl MmioSize {
+    /// Return `true` if an access of `len` bytes at `offset` fits within the size.
+    pub fn contains(self, offset: MmioOffset, len: usize) -> bool {
+        offset.0 < self.0 && len as u64 <= self.0 - offset.0
+    }
+}
+
+impl PioSize {
+    /// Return `true` if an access of `len` bytes at `offset` fits within the size.
+    pub fn contains(self, offset: PioOffset, len: usize) -> bool {
+        offset.0 < self.0 && len as u64 <= u64::from(self.0 - offset.0)
+    }
+}
+
+impl Add<MmioOffset> for MmioAddress {
+    type Output = Self;
+
+    fn add(self, rhs: MmioOffset) -> Self::Output {
+        MmioAddress(self.0 + rhs.0)
+    }
+}
+
+impl Add<PioOffset> for PioAddress {
+    type Output = Self;
+
+    fn add(self, rhs: PioOffset) -> Self::Output {
+        PioAddress(self.0 + rhs.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_units() {
+        let offset = MmioOffset(0x10);
+        assert_eq!(offset + 4, 0x14);
+        assert!(offset < 0x11);
+        assert_eq!(u64::from(offset), 0x10);
+        assert_eq!(MmioAddress(0x1000) + offset, MmioAddress(0x1010));
+        assert_eq!(offset.checked_sub(0x11), None);
+        assert_eq!(format!("{:#x}", offset), "0x10");
+
+        assert!(MmioSize(0x14).contains(offset, 4));
+        assert!(!MmioSize(0x14).contains(offset, 5));
+        assert!(!MmioSize(0x10).contains(offset, 0));
+        assert!(PioSize(8).contains(PioOffset(7), 1));
+        assert!(!PioSize(8).contains(PioOffset(7), 2));
+        assert_eq!(PioAddress(0x3f8) + PioOffset(5), PioAddress(0x3fd));
+    }
+}
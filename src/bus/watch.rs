@@ -0,0 +1,245 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+use std::sync::Arc;
+
+use super::{BusAddress, BusRange};
+
+/// Kind of accesses which trigger a watchpoint.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum WatchKind {
+    /// Only reads trigger the watchpoint.
+    Read,
+    /// Only writes trigger the watchpoint.
+    Write,
+    /// Both reads and writes trigger the watchpoint.
+    ReadWrite,
+}
+
+impl WatchKind {
+    fn matches(self, write: bool) -> bool {
+        match self {
+            WatchKind::Read => !write,
+            WatchKind::Write => write,
+            WatchKind::ReadWrite => true,
+        }
+    }
+}
+
+/// Access which hit a watchpoint, as seen by its handler.
+#[derive(Debug)]
+pub enum WatchAccess<'a> {
+    /// A read, with the buffer which receives the data. When the handler returns
+    /// `WatchAction::Skip`, the buffer contents are the result of the read.
+    Read(&'a mut [u8]),
+    /// A write, with the written data.
+    Write(&'a [u8]),
+}
+
+/// What happens to an access after the watchpoint handlers ran.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum WatchAction {
+    /// Dispatch the access to the device.
+    Continue,
+    /// Complete the access without dispatching it to the device.
+    Skip,
+}
+
+/// Handler invoked with the address and the access which hit a watchpoint. It runs on the
+/// thread performing the access, before the access is dispatched, so blocking in the handler
+/// (e.g. while a debugger inspects the VM) pauses the access.
+pub type WatchHandler<A> = Arc<dyn Fn(A, WatchAccess<'_>) -> WatchAction + Send + Sync>;
+
+/// Identifies an installed watchpoint.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct WatchpointId(u64);
+
+#[derive(Clone)]
+struct Watchpoint<A: BusAddress> {
+    id: WatchpointId,
+    range: BusRange<A>,
+    kind: WatchKind,
+    handler: WatchHandler<A>,
+}
+
+// Watchpoints installed on a bus.
+#[derive(Clone)]
+pub(super) struct Watchpoints<A: BusAddress> {
+    next_id: u64,
+    entries: Vec<Watchpoint<A>>,
+}
+
+impl<A: BusAddress> Default for Watchpoints<A> {
+    fn default() -> Self {
+        Watchpoints {
+            next_id: 0,
+            entries: Vec::new(),
+        }
+    }
+}
+
+impl<A: BusAddress> Watchpoints<A> {
+    pub(super) fn add(
+        &mut self,
+        range: BusRange<A>,
+        kind: WatchKind,
+        handler: WatchHandler<A>,
+    ) -> WatchpointId {
+        let id = WatchpointId(self.next_id);
+        self.next_id += 1;
+        self.entries.push(Watchpoint {
+            id,
+            range,
+            kind,
+            handler,
+        });
+        id
+    }
+
+    pub(super) fn remove(&mut self, id: WatchpointId) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|watchpoint| watchpoint.id != id);
+        self.entries.len() != len
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Run the handlers of the watchpoints hit by the access described by `access_range`.
+    // The access is skipped if any of them asks for it.
+    pub(super) fn check(
+        &self,
+        addr: A,
+        access_range: &BusRange<A>,
+        mut access: WatchAccess<'_>,
+    ) -> WatchAction {
+        let write = matches!(access, WatchAccess::Write(_));
+        let mut action = WatchAction::Continue;
+        for watchpoint in self.entries.iter() {
+            if !watchpoint.kind.matches(write) || !watchpoint.range.overlaps(access_range) {
+                continue;
+            }
+            let reborrowed = match access {
+                WatchAccess::Read(ref mut data) => WatchAccess::Read(data),
+                WatchAccess::Write(data) => WatchAccess::Write(data),
+            };
+            if (watchpoint.handler)(addr, reborrowed) == WatchAction::Skip {
+                action = WatchAction::Skip;
+            }
+        }
+        action
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use crate::bus::{MmioAddress, MmioRange};
+
+    fn range(base: u64, size: u64) -> MmioRange {
+        MmioRange::new(MmioAddress(base), size).unwrap()
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut watchpoints = Watchpoints::default();
+        assert!(watchpoints.is_empty());
+        let hits = Arc::new(Mutex::new(Vec::new()));
+
+        let log = hits.clone();
+        let reads = watchpoints.add(
+            range(0x1000, 0x10),
+            WatchKind::Read,
+            Arc::new(move |addr: MmioAddress, access| {
+                if let WatchAccess::Read(data) = access {
+                    data.fill(0xaa);
+                }
+                log.lock().unwrap().push(("read", addr.0));
+                WatchAction::Skip
+            }),
+        );
+        let log = hits.clone();
+        let writes = watchpoints.add(
+            range(0x1008, 0x10),
+            WatchKind::ReadWrite,
+            Arc::new(move |addr: MmioAddress, access| {
+                let kind = match access {
+                    WatchAccess::Read(_) => "any read",
+                    WatchAccess::Write(data) => {
+                        assert_eq!(data, [1, 2]);
+                        "any write"
+                    }
+                };
+                log.lock().unwrap().push((kind, addr.0));
+                WatchAction::Continue
+            }),
+        );
+        assert_ne!(reads, writes);
+        assert!(!watchpoints.is_empty());
+
+        // Only the watchpoints overlapping the access and matching its kind run.
+        let mut data = [0; 4];
+        let action = watchpoints.check(
+            MmioAddress(0x1006),
+            &range(0x1006, 4),
+            WatchAccess::Read(&mut data),
+        );
+        assert_eq!(action, WatchAction::Skip);
+        assert_eq!(data, [0xaa; 4]);
+        let action = watchpoints.check(
+            MmioAddress(0x1000),
+            &range(0x1000, 2),
+            WatchAccess::Write(&[1, 2]),
+        );
+        assert_eq!(action, WatchAction::Continue);
+        let action = watchpoints.check(
+            MmioAddress(0x1016),
+            &range(0x1016, 2),
+            WatchAccess::Write(&[1, 2]),
+        );
+        assert_eq!(action, WatchAction::Continue);
+        let action = watchpoints.check(
+            MmioAddress(0x1018),
+            &range(0x1018, 2),
+            WatchAccess::Write(&[1, 2]),
+        );
+        assert_eq!(action, WatchAction::Continue);
+        assert_eq!(
+            *hits.lock().unwrap(),
+            vec![
+                ("read", 0x1006),
+                ("any read", 0x1006),
+                ("any write", 0x1016)
+            ]
+        );
+
+        // Removed watchpoints don't run anymore.
+        hits.lock().unwrap().clear();
+        assert!(watchpoints.remove(reads));
+        assert!(!watchpoints.remove(reads));
+        let mut data = [0; 4];
+        let action = watchpoints.check(
+            MmioAddress(0x1008),
+            &range(0x1008, 4),
+            WatchAccess::Read(&mut data),
+        );
+        assert_eq!(action, WatchAction::Continue);
+        assert_eq!(data, [0; 4]);
+        assert_eq!(*hits.lock().unwrap(), vec![("any read", 0x1008)]);
+        assert!(watchpoints.remove(writes));
+        assert!(watchpoints.is_empty());
+
+        // Identifiers aren't reused.
+        let id = watchpoints.add(
+            range(0, 1),
+            WatchKind::Write,
+            Arc::new(|_, _| WatchAction::Continue),
+        );
+        assert_ne!(id, reads);
+        assert_ne!(id, writes);
+    }
+}
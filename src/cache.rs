@@ -0,0 +1,286 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Caching of the register reads of devices which are expensive to access.
+//!
+//! Devices implemented in another process (vhost-user backends, remote device processes)
+//! forward every access over a socket, which is costly when the guest keeps polling a
+//! status register. A [`CachedDevice`](struct.CachedDevice.html) answers the reads of the
+//! registers it was told are cacheable from the values it last read, and only forwards the
+//! other accesses. Since the wrapper can't know when the backend changes a register on its
+//! own, the cache is invalidated explicitly through a
+//! [`CacheInvalidator`](struct.CacheInvalidator.html) (e.g. when the backend signals a
+//! state change), after an optional time to live, and on every write to the device.
+
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::bus::{MmioAddress, MmioOffset, PioAddress, PioOffset};
+use crate::time::Clock;
+use crate::{DeviceMmio, DevicePio, IoAccess};
+
+struct CacheEntry {
+    data: Vec<u8>,
+    // Time at which the value was read from the device.
+    read_at: u64,
+}
+
+#[derive(Default)]
+struct CacheState {
+    // Entries keyed by the offset and length of the read.
+    entries: Mutex<HashMap<(u64, usize), CacheEntry>>,
+    // Incremented by every invalidation, so values read concurrently aren't cached.
+    generation: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl CacheState {
+    fn invalidate(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        self.generation.fetch_add(1, Ordering::Relaxed);
+        entries.clear();
+    }
+
+    fn invalidate_range(&self, offset: u64, len: u64) {
+        let mut entries = self.entries.lock().unwrap();
+        self.generation.fetch_add(1, Ordering::Relaxed);
+        entries.retain(|(start, size), _| {
+            *start + *size as u64 <= offset || *start >= offset.saturating_add(len)
+        });
+    }
+}
+
+/// Invalidates the cache of a `CachedDevice`, from any thread.
+#[derive(Clone)]
+pub struct CacheInvalidator(Arc<CacheState>);
+
+impl CacheInvalidator {
+    /// Drop all the cached values.
+    pub fn invalidate(&self) {
+        self.0.invalidate();
+    }
+
+    /// Drop the cached values overlapping the `len` bytes at `offset`.
+    pub fn invalidate_range(&self, offset: u64, len: u64) {
+        self.0.invalidate_range(offset, len);
+    }
+}
+
+/// Answers the reads of the cacheable registers of a device from a cache.
+pub struct CachedDevice<D> {
+    device: D,
+    cacheable: Vec<Range<u64>>,
+    ttl: Option<(Arc<dyn Clock>, u64)>,
+    state: Arc<CacheState>,
+}
+
+impl<D> CachedDevice<D> {
+    /// Wrap `device`, with no cacheable registers until they are added with
+    /// `with_cacheable`.
+    pub fn new(device: D) -> Self {
+        CachedDevice {
+            device,
+            cacheable: Vec::new(),
+            ttl: None,
+            state: Arc::new(CacheState::default()),
+        }
+    }
+
+    /// Cache the reads which fall entirely within the `offsets` of the device range.
+    pub fn with_cacheable(mut self, offsets: Range<u64>) -> Self {
+        self.cacheable.push(offsets);
+        self
+    }
+
+    /// Forward the reads of values which were read from the device more than `ttl`
+    /// nanoseconds ago, as measured by `clock`.
+    pub fn with_ttl(mut self, clock: Arc<dyn Clock>, ttl: u64) -> Self {
+        self.ttl = Some((clock, ttl));
+        self
+    }
+
+    /// Return a handle invalidating the cache of the device.
+    pub fn invalidator(&self) -> CacheInvalidator {
+        CacheInvalidator(self.state.clone())
+    }
+
+    /// Return the wrapped device.
+    pub fn inner(&self) -> &D {
+        &self.device
+    }
+
+    /// Return the number of reads answered from the cache, and the number of cacheable
+    /// reads which were forwarded.
+    pub fn stats(&self) -> (u64, u64) {
+        (
+            self.state.hits.load(Ordering::Relaxed),
+            self.state.misses.load(Ordering::Relaxed),
+        )
+    }
+
+    fn is_cacheable(&self, offset: u64, len: usize) -> bool {
+        let end = offset.saturating_add(len as u64);
+        self.cacheable
+            .iter()
+            .any(|range| range.start <= offset && end <= range.end)
+    }
+
+    fn now(&self) -> u64 {
+        self.ttl.as_ref().map_or(0, |(clock, _)| clock.now())
+    }
+
+    // Fill `data` with the cached value of the register at `offset`, or with the value
+    // returned by `read`, which is then cached.
+    fn read<F: FnOnce(&mut [u8])>(&self, offset: u64, data: &mut [u8], read: F) {
+        if !self.is_cacheable(offset, data.len()) {
+            return read(data);
+        }
+        let now = self.now();
+        let key = (offset, data.len());
+        let generation = {
+            let entries = self.state.entries.lock().unwrap();
+            if let Some(entry) = entries.get(&key) {
+                let fresh = self
+                    .ttl
+                    .as_ref()
+                    .is_none_or(|(_, ttl)| now.saturating_sub(entry.read_at) <= *ttl);
+                if fresh {
+                    data.copy_from_slice(&entry.data);
+                    self.state.hits.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+            }
+            self.state.generation.load(Ordering::Relaxed)
+        };
+        // The device is accessed without holding the lock, so invalidations aren't blocked
+        // while it is slow to answer.
+        read(data);
+        self.state.misses.fetch_add(1, Ordering::Relaxed);
+        let mut entries = self.state.entries.lock().unwrap();
+        if self.state.generation.load(Ordering::Relaxed) == generation {
+            entries.insert(
+                key,
+                CacheEntry {
+                    data: data.to_vec(),
+                    read_at: now,
+                },
+            );
+        }
+    }
+
+    // Writes may change any register, so they drop the whole cache.
+    fn write<F: FnOnce()>(&self, write: F) {
+        write();
+        self.state.invalidate();
+    }
+}
+
+impl<D: DevicePio> DevicePio for CachedDevice<D> {
+    fn pio_read(&self, base: PioAddress, offset: PioOffset, data: &mut [u8]) {
+        self.pio_read_with(IoAccess::default(), base, offset, data);
+    }
+
+    fn pio_write(&self, base: PioAddress, offset: PioOffset, data: &[u8]) {
+        self.pio_write_with(IoAccess::default(), base, offset, data);
+    }
+
+    fn pio_read_with(
+        &self,
+        access: IoAccess,
+        base: PioAddress,
+        offset: PioOffset,
+        data: &mut [u8],
+    ) {
+        self.read(u64::from(offset), data, |data| {
+            self.device.pio_read_with(access, base, offset, data)
+        });
+    }
+
+    fn pio_write_with(&self, access: IoAccess, base: PioAddress, offset: PioOffset, data: &[u8]) {
+        self.write(|| self.device.pio_write_with(access, base, offset, data));
+    }
+}
+
+impl<D: DeviceMmio> DeviceMmio for CachedDevice<D> {
+    fn mmio_read(&self, base: MmioAddress, offset: MmioOffset, data: &mut [u8]) {
+        self.mmio_read_with(IoAccess::default(), base, offset, data);
+    }
+
+    fn mmio_write(&self, base: MmioAddress, offset: MmioOffset, data: &[u8]) {
+        self.mmio_write_with(IoAccess::default(), base, offset, data);
+    }
+
+    fn mmio_read_with(
+        &self,
+        access: IoAccess,
+        base: MmioAddress,
+        offset: MmioOffset,
+        data: &mut [u8],
+    ) {
+        self.read(offset.raw(), data, |data| {
+            self.device.mmio_read_with(access, base, offset, data)
+        });
+    }
+
+    fn mmio_write_with(
+        &self,
+        access: IoAccess,
+        base: MmioAddress,
+        offset: MmioOffset,
+        data: &[u8],
+    ) {
+        self.write(|| self.device.mmio_write_with(access, base, offset, data));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::EchoDevice;
+    use crate::time::ManualClock;
+
+    #[test]
+    fn test_cached_device() {
+        let clock = Arc::new(ManualClock::new());
+        let device = CachedDevice::new(EchoDevice::new())
+            .with_cacheable(0x10..0x14)
+            .with_ttl(clock.clone(), 1000);
+        let invalidator = device.invalidator();
+        let base = MmioAddress(0x1000);
+        let mut data = [0; 4];
+
+        // Only the cacheable registers are answered from the cache.
+        device.mmio_read(base, MmioOffset(0x10), &mut data);
+        device.mmio_read(base, MmioOffset(0x10), &mut data);
+        device.mmio_read(base, MmioOffset(0x12), &mut data[..2]);
+        device.mmio_read(base, MmioOffset(0x12), &mut data[..2]);
+        device.mmio_read(base, MmioOffset(0x14), &mut data);
+        device.mmio_read(base, MmioOffset(0x14), &mut data);
+        assert_eq!(device.inner().accesses().len(), 4);
+        assert_eq!(device.stats(), (2, 2));
+
+        // Writes go through, and drop the cached values.
+        device.mmio_write(base, MmioOffset(0), &[1, 2, 3, 4]);
+        device.mmio_read(base, MmioOffset(0x10), &mut data);
+        assert_eq!(data, [1, 2, 3, 4]);
+        assert_eq!(device.inner().accesses().len(), 6);
+
+        invalidator.invalidate_range(0x13, 1);
+        device.mmio_read(base, MmioOffset(0x10), &mut data);
+        assert_eq!(device.inner().accesses().len(), 7);
+        device.mmio_read(base, MmioOffset(0x10), &mut data);
+        assert_eq!(device.inner().accesses().len(), 7);
+
+        // Stale values are read again.
+        clock.advance(1001);
+        device.mmio_read(base, MmioOffset(0x10), &mut data);
+        assert_eq!(device.inner().accesses().len(), 8);
+        invalidator.invalidate();
+        device.mmio_read(base, MmioOffset(0x10), &mut data);
+        assert_eq!(device.inner().accesses().len(), 9);
+    }
+}
@@ -0,0 +1,260 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Devices made of several sub-devices which are managed as a unit.
+//!
+//! Some chips expose several functions at unrelated addresses, possibly on both buses (e.g. a
+//! super I/O chip with serial ports, an RTC, and an i8042 controller), while sharing state
+//! such as a configuration register block. A [`CompositeDevice`](struct.CompositeDevice.html)
+//! bundles the sub-devices with their resources and the shared state under one ID, so they
+//! are registered with the `IoManager` in a single step, which either registers all of them
+//! or none, and deregistered together by ID.
+
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+
+use crate::bus::{MmioAddress, MmioRange, PioAddress, PioRange};
+use crate::device_manager::{self, IoManager};
+use crate::resources::Resource;
+use crate::{DeviceMmio, DevicePio};
+
+/// Errors encountered while registering composite devices.
+#[derive(Debug)]
+pub enum Error {
+    /// A composite device with the same ID is already registered.
+    DuplicateId(String),
+    /// Failed to register a sub-device.
+    Manager(device_manager::Error),
+    /// No composite device with this ID is registered.
+    NotFound(String),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::DuplicateId(id) => write!(f, "composite device {} already registered", id),
+            Error::Manager(_) => write!(f, "failed to register sub-device"),
+            Error::NotFound(id) => write!(f, "composite device {} not found", id),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Manager(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Sub-devices sharing the state `S`, with their resources.
+pub struct CompositeDevice<S> {
+    id: String,
+    state: Arc<S>,
+    pio: Vec<(Arc<dyn DevicePio + Send + Sync>, Vec<Resource>)>,
+    mmio: Vec<(Arc<dyn DeviceMmio + Send + Sync>, Vec<Resource>)>,
+}
+
+impl<S> CompositeDevice<S> {
+    /// Create a composite device called `id`, without any sub-device. The sub-devices are
+    /// expected to be created with clones of `state`.
+    pub fn new(id: &str, state: Arc<S>) -> Self {
+        CompositeDevice {
+            id: id.to_string(),
+            state,
+            pio: Vec::new(),
+            mmio: Vec::new(),
+        }
+    }
+
+    /// Return the ID of the device.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Return the state shared by the sub-devices.
+    pub fn state(&self) -> &Arc<S> {
+        &self.state
+    }
+
+    /// Add a sub-device registered with the PIO ranges of `resources`.
+    pub fn with_pio(
+        mut self,
+        device: Arc<dyn DevicePio + Send + Sync>,
+        resources: &[Resource],
+    ) -> Self {
+        self.pio.push((device, resources.to_vec()));
+        self
+    }
+
+    /// Add a sub-device registered with the MMIO ranges of `resources`.
+    pub fn with_mmio(
+        mut self,
+        device: Arc<dyn DeviceMmio + Send + Sync>,
+        resources: &[Resource],
+    ) -> Self {
+        self.mmio.push((device, resources.to_vec()));
+        self
+    }
+
+    /// Return the resources of all the sub-devices.
+    pub fn resources(&self) -> Vec<Resource> {
+        self.pio
+            .iter()
+            .map(|(_, res)| res)
+            .chain(self.mmio.iter().map(|(_, res)| res))
+            .flatten()
+            .cloned()
+            .collect()
+    }
+}
+
+impl IoManager {
+    /// Register all the sub-devices of `composite`. When any of them fails to register, the
+    /// ones registered so far are removed.
+    pub fn register_composite<S>(&mut self, composite: &CompositeDevice<S>) -> Result<(), Error> {
+        if self.composites.contains_key(composite.id()) {
+            return Err(Error::DuplicateId(composite.id().to_string()));
+        }
+        let registered = self
+            .transaction(|txn| {
+                let mut registered = Vec::new();
+                for (device, resources) in composite.pio.iter() {
+                    for res in resources.iter() {
+                        if let Resource::PioAddressRange { base, size } = *res {
+                            let range = PioRange::new(PioAddress(base), size)
+                                .map_err(device_manager::Error::Bus)?;
+                            txn.register_pio(range, device.clone())?;
+                            registered.push(res.clone());
+                        }
+                    }
+                }
+                for (device, resources) in composite.mmio.iter() {
+                    for res in resources.iter() {
+                        if let Resource::MmioAddressRange { base, size } = *res {
+                            let range = MmioRange::new(MmioAddress(base), size)
+                                .map_err(device_manager::Error::Bus)?;
+                            txn.register_mmio(range, device.clone())?;
+                            registered.push(res.clone());
+                        }
+                    }
+                }
+                Ok(registered)
+            })
+            .map_err(Error::Manager)?;
+        self.composites
+            .insert(composite.id().to_string(), registered);
+        Ok(())
+    }
+
+    /// Deregister all the sub-devices of the composite device `id`, and return the number
+    /// of ranges removed.
+    pub fn deregister_composite(&mut self, id: &str) -> Result<usize, Error> {
+        let resources = self
+            .composites
+            .remove(id)
+            .ok_or_else(|| Error::NotFound(id.to_string()))?;
+        Ok(self.deregister_resources(&resources))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use crate::bus::{self, MmioOffset, PioOffset};
+    use crate::device_manager::{MmioManager, PioManager};
+
+    #[derive(Default)]
+    struct SuperIo {
+        config: Mutex<u8>,
+    }
+
+    struct Function(Arc<SuperIo>);
+
+    impl DevicePio for Function {
+        fn pio_read(&self, _base: PioAddress, _offset: PioOffset, data: &mut [u8]) {
+            data[0] = *self.0.config.lock().unwrap();
+        }
+
+        fn pio_write(&self, _base: PioAddress, _offset: PioOffset, data: &[u8]) {
+            *self.0.config.lock().unwrap() = data[0];
+        }
+    }
+
+    impl DeviceMmio for Function {
+        fn mmio_read(&self, _base: MmioAddress, _offset: MmioOffset, data: &mut [u8]) {
+            data[0] = *self.0.config.lock().unwrap();
+        }
+
+        fn mmio_write(&self, _base: MmioAddress, _offset: MmioOffset, _data: &[u8]) {}
+    }
+
+    #[test]
+    fn test_composite_device() {
+        let state = Arc::new(SuperIo::default());
+        let composite = || {
+            CompositeDevice::new("superio", state.clone())
+                .with_pio(
+                    Arc::new(Function(state.clone())),
+                    &[Resource::PioAddressRange {
+                        base: 0x2e,
+                        size: 2,
+                    }],
+                )
+                .with_mmio(
+                    Arc::new(Function(state.clone())),
+                    &[Resource::MmioAddressRange {
+                        base: 0x1000,
+                        size: 0x100,
+                    }],
+                )
+        };
+        let mut io_mgr = IoManager::new();
+        io_mgr.register_composite(&composite()).unwrap();
+        assert!(matches!(
+            io_mgr.register_composite(&composite()),
+            Err(Error::DuplicateId(_))
+        ));
+
+        // The sub-devices share their state.
+        io_mgr.pio_write(PioAddress(0x2e), &[0x55]).unwrap();
+        let mut data = [0];
+        io_mgr.mmio_read(MmioAddress(0x1000), &mut data).unwrap();
+        assert_eq!(data, [0x55]);
+
+        // A failed registration leaves no sub-device behind.
+        let conflicting = CompositeDevice::new("other", state.clone())
+            .with_pio(
+                Arc::new(Function(state.clone())),
+                &[Resource::PioAddressRange {
+                    base: 0x4e,
+                    size: 2,
+                }],
+            )
+            .with_mmio(
+                Arc::new(Function(state)),
+                &[Resource::MmioAddressRange {
+                    base: 0x1080,
+                    size: 0x100,
+                }],
+            );
+        assert!(matches!(
+            io_mgr.register_composite(&conflicting),
+            Err(Error::Manager(device_manager::Error::Bus(
+                bus::Error::DeviceOverlap
+            )))
+        ));
+        assert!(io_mgr.pio_device(PioAddress(0x4e)).is_none());
+
+        assert_eq!(io_mgr.deregister_composite("superio").unwrap(), 2);
+        assert!(io_mgr.mmio_device(MmioAddress(0x1000)).is_none());
+        assert!(matches!(
+            io_mgr.deregister_composite("superio"),
+            Err(Error::NotFound(_))
+        ));
+    }
+}
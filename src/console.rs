@@ -0,0 +1,201 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Input and output of console-like devices.
+//!
+//! Serial ports, keyboards and debug consoles exchange bytes with a frontend of the VMM
+//! (stdin, a PTY, a websocket). Devices which take input implement
+//! [`SinkDevice`](trait.SinkDevice.html), and devices which produce output implement
+//! [`SourceDevice`](trait.SourceDevice.html). They are registered with the `IoManager`
+//! under an ID with `register_sink` and `register_source`, so frontends can look them up
+//! with `sink` and `source` without knowing the concrete device types.
+//!
+//! Devices which write their output to a `Write` sink (e.g. the
+//! [`DebugCon`](../devices/debugcon/struct.DebugCon.html)) can be given a
+//! [`ConsoleBuffer`](struct.ConsoleBuffer.html), which is then registered as their source.
+
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+use crate::bus::MmioBusAddress;
+use crate::device_manager::IoManager;
+
+/// Devices which accept input from a frontend.
+pub trait SinkDevice {
+    /// Queue `data` as input of the device (e.g. bytes received by a UART, or typed on a
+    /// keyboard). Return the number of bytes accepted, which is less than `data.len()` when
+    /// the input queue of the device is full; the frontend retries the rest later.
+    fn queue_input(&self, data: &[u8]) -> usize;
+}
+
+/// Devices which produce output for a frontend.
+pub trait SourceDevice {
+    /// Move the output produced since the last call to the end of `out`, and return the
+    /// number of bytes moved.
+    fn drain_output(&self, out: &mut Vec<u8>) -> usize;
+}
+
+/// Buffers the output of a device, up to a limit, until a frontend drains it. Clones share
+/// the same buffer.
+#[derive(Clone)]
+pub struct ConsoleBuffer {
+    data: Arc<Mutex<VecDeque<u8>>>,
+    limit: usize,
+}
+
+impl ConsoleBuffer {
+    /// Create an empty buffer holding up to `limit` bytes. When the buffer is full, the
+    /// oldest bytes are dropped to make room for new ones.
+    pub fn new(limit: usize) -> Self {
+        ConsoleBuffer {
+            data: Arc::new(Mutex::new(VecDeque::new())),
+            limit,
+        }
+    }
+
+    /// Return the number of buffered bytes.
+    pub fn len(&self) -> usize {
+        self.data.lock().unwrap().len()
+    }
+
+    /// Return `true` if no bytes are buffered.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Write for ConsoleBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut data = self.data.lock().unwrap();
+        let kept = &buf[buf.len().saturating_sub(self.limit)..];
+        let excess = (data.len() + kept.len()).saturating_sub(self.limit);
+        data.drain(..excess);
+        data.extend(kept);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SourceDevice for ConsoleBuffer {
+    fn drain_output(&self, out: &mut Vec<u8>) -> usize {
+        let mut data = self.data.lock().unwrap();
+        let len = data.len();
+        out.extend(data.drain(..));
+        len
+    }
+}
+
+impl<M: MmioBusAddress> IoManager<M> {
+    /// Register the device `id` as accepting input, replacing the sink previously registered
+    /// with the same ID.
+    pub fn register_sink(&mut self, id: &str, device: Arc<dyn SinkDevice + Send + Sync>) {
+        self.sinks.insert(id.to_string(), device);
+    }
+
+    /// Register the device `id` as producing output, replacing the source previously
+    /// registered with the same ID.
+    pub fn register_source(&mut self, id: &str, device: Arc<dyn SourceDevice + Send + Sync>) {
+        self.sources.insert(id.to_string(), device);
+    }
+
+    /// Remove the sink and source registered for the device `id`.
+    pub fn deregister_console(&mut self, id: &str) {
+        self.sinks.remove(id);
+        self.sources.remove(id);
+    }
+
+    /// Return the sink registered for the device `id`.
+    pub fn sink(&self, id: &str) -> Option<Arc<dyn SinkDevice + Send + Sync>> {
+        self.sinks.get(id).cloned()
+    }
+
+    /// Return the source registered for the device `id`.
+    pub fn source(&self, id: &str) -> Option<Arc<dyn SourceDevice + Send + Sync>> {
+        self.sources.get(id).cloned()
+    }
+
+    /// Return the IDs of the devices with a registered sink or source, in order.
+    pub fn console_ids(&self) -> Vec<String> {
+        let mut ids: Vec<String> = self
+            .sinks
+            .keys()
+            .chain(self.sources.keys())
+            .cloned()
+            .collect();
+        ids.sort();
+        ids.dedup();
+        ids
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::bus::{PioAddress, PioOffset, PioRange};
+    use crate::device_manager::PioManager;
+    use crate::devices::debugcon::{DebugCon, DEBUGCON_PORT};
+    use crate::DevicePio;
+
+    // Keyboard with an input queue of four bytes, read one at a time from its port.
+    #[derive(Default)]
+    struct Keyboard(Mutex<VecDeque<u8>>);
+
+    impl SinkDevice for Keyboard {
+        fn queue_input(&self, data: &[u8]) -> usize {
+            let mut queue = self.0.lock().unwrap();
+            let accepted = data.len().min(4 - queue.len());
+            queue.extend(&data[..accepted]);
+            accepted
+        }
+    }
+
+    impl DevicePio for Keyboard {
+        fn pio_read(&self, _base: PioAddress, _offset: PioOffset, data: &mut [u8]) {
+            data[0] = self.0.lock().unwrap().pop_front().unwrap_or(0);
+        }
+
+        fn pio_write(&self, _base: PioAddress, _offset: PioOffset, _data: &[u8]) {}
+    }
+
+    #[test]
+    fn test_console_endpoints() {
+        let mut io_mgr = IoManager::new();
+        let keyboard = Arc::new(Keyboard::default());
+        let kbd_range = PioRange::new(PioAddress(0x60), 1).unwrap();
+        io_mgr.register_pio(kbd_range, keyboard.clone()).unwrap();
+        io_mgr.register_sink("kbd", keyboard);
+
+        let buffer = ConsoleBuffer::new(4);
+        let con = Arc::new(Mutex::new(DebugCon::new(buffer.clone())));
+        let con_range = PioRange::new(PioAddress(DEBUGCON_PORT), 1).unwrap();
+        io_mgr.register_pio(con_range, con).unwrap();
+        io_mgr.register_source("debugcon", Arc::new(buffer.clone()));
+        assert_eq!(io_mgr.console_ids(), vec!["debugcon", "kbd"]);
+
+        // The frontend injects input without knowing the device type.
+        let sink = io_mgr.sink("kbd").unwrap();
+        assert_eq!(sink.queue_input(b"hello"), 4);
+        let mut data = [0];
+        io_mgr.pio_read(kbd_range.base(), &mut data).unwrap();
+        assert_eq!(data, [b'h']);
+        assert_eq!(sink.queue_input(b"o"), 1);
+
+        // Only the latest output is kept until it's drained.
+        for byte in b"hello".iter() {
+            io_mgr.pio_write(con_range.base(), &[*byte]).unwrap();
+        }
+        let mut out = Vec::new();
+        assert_eq!(io_mgr.source("debugcon").unwrap().drain_output(&mut out), 4);
+        assert_eq!(out, b"ello");
+        assert!(buffer.is_empty());
+
+        io_mgr.deregister_console("kbd");
+        assert!(io_mgr.sink("kbd").is_none());
+        assert!(io_mgr.source("kbd").is_none());
+    }
+}
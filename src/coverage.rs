@@ -0,0 +1,237 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Register coverage of the device models.
+//!
+//! An [`AccessCoverage`](struct.AccessCoverage.html) observes the accesses to the range of a
+//! device, and counts them by offset, width, and direction. Running a guest driver against
+//! the device then shows which parts of the register surface it exercises, and which ones
+//! the tests of the model have to cover by other means. The coverage is installed as a bus
+//! observer, with `IoManager::add_pio_coverage` or `IoManager::add_mmio_coverage`, so it
+//! doesn't change how the accesses are dispatched.
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+use crate::bus::{BusAddress, BusObserver, BusRange, MmioBusAddress, ObserverId, PioAddress};
+use crate::device_manager::{IoManager, MmioManager, PioManager};
+
+// Coverage observers run after all the other ones, which may be slower to inspect.
+const COVERAGE_PRIORITY: u32 = u32::MAX;
+
+/// Accesses of the same kind to an offset of the device.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CoverageEntry {
+    /// Offset of the accesses within the range of the device.
+    pub offset: u64,
+    /// Width of the accesses, in bytes.
+    pub width: usize,
+    /// Whether the accesses are writes.
+    pub write: bool,
+    /// Number of accesses.
+    pub count: u64,
+}
+
+/// Coverage of the registers of the device registered with a range.
+pub struct AccessCoverage {
+    base: u64,
+    size: u64,
+    // Number of accesses, keyed by their offset, width, and direction.
+    counts: Mutex<BTreeMap<(u64, usize, bool), u64>>,
+}
+
+impl AccessCoverage {
+    /// Create an empty coverage of the accesses to `range`.
+    pub fn new<A: BusAddress>(range: BusRange<A>) -> Self {
+        AccessCoverage {
+            base: range.base().value().into(),
+            size: range.size().into(),
+            counts: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Return the base address of the range whose accesses are covered.
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    /// Return the size of the range whose accesses are covered.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Return the accesses recorded so far, ordered by offset, width, and direction.
+    pub fn entries(&self) -> Vec<CoverageEntry> {
+        self.counts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&(offset, width, write), &count)| CoverageEntry {
+                offset,
+                width,
+                write,
+                count,
+            })
+            .collect()
+    }
+
+    /// Return whether the byte at `offset` was read, or written when `write` is set.
+    pub fn is_covered(&self, offset: u64, write: bool) -> bool {
+        self.counts
+            .lock()
+            .unwrap()
+            .keys()
+            .any(|&(start, width, w)| {
+                w == write && start <= offset && offset - start < width as u64
+            })
+    }
+
+    /// Forget the accesses recorded so far, e.g. between two test runs.
+    pub fn clear(&self) {
+        self.counts.lock().unwrap().clear();
+    }
+
+    /// Export the coverage to `writer`, with one line per entry holding the offset in
+    /// hexadecimal, the width, the direction, and the count (e.g. `0x10 4 write 3`).
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        for entry in self.entries() {
+            let direction = if entry.write { "write" } else { "read" };
+            writeln!(
+                writer,
+                "{:#x} {} {} {}",
+                entry.offset, entry.width, direction, entry.count
+            )?;
+        }
+        Ok(())
+    }
+
+    fn record<A: BusAddress>(&self, addr: A, len: usize, write: bool) {
+        // Accesses split between several ranges may start before this one.
+        let offset = match addr.value().into().checked_sub(self.base) {
+            Some(offset) if offset < self.size => offset,
+            _ => return,
+        };
+        *self
+            .counts
+            .lock()
+            .unwrap()
+            .entry((offset, len, write))
+            .or_insert(0) += 1;
+    }
+}
+
+impl<A: BusAddress> BusObserver<A> for AccessCoverage {
+    fn observe_read(&self, addr: A, data: &[u8]) {
+        self.record(addr, data.len(), false);
+    }
+
+    fn observe_write(&self, addr: A, data: &[u8]) {
+        self.record(addr, data.len(), true);
+    }
+}
+
+impl<M: MmioBusAddress> IoManager<M> {
+    /// Start recording the coverage of the PIO device registered at `addr`. Return `None`
+    /// if there isn't any.
+    pub fn add_pio_coverage(
+        &mut self,
+        addr: PioAddress,
+    ) -> Option<(ObserverId, Arc<AccessCoverage>)> {
+        let range = *self.pio_device(addr)?.0;
+        let coverage = Arc::new(AccessCoverage::new(range));
+        let id = self.add_pio_observer(range, COVERAGE_PRIORITY, coverage.clone());
+        Some((id, coverage))
+    }
+
+    /// Start recording the coverage of the MMIO device registered at `addr`. Return `None`
+    /// if there isn't any.
+    pub fn add_mmio_coverage(&mut self, addr: M) -> Option<(ObserverId, Arc<AccessCoverage>)> {
+        let range = *self.mmio_device(addr)?.0;
+        let coverage = Arc::new(AccessCoverage::new(range));
+        let id = self.add_mmio_observer(range, COVERAGE_PRIORITY, coverage.clone());
+        Some((id, coverage))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::bus::{MmioAddress, MmioRange, PioRange};
+    use crate::testing::EchoDevice;
+
+    #[test]
+    fn test_access_coverage() {
+        let mut io_mgr = IoManager::new();
+        let dev = Arc::new(EchoDevice::new());
+        let regs = MmioRange::new(MmioAddress(0xd000_0000), 0x100).unwrap();
+        io_mgr.register_mmio(regs, dev.clone()).unwrap();
+        io_mgr
+            .register_pio(PioRange::new(PioAddress(0x3f8), 8).unwrap(), dev)
+            .unwrap();
+        assert!(io_mgr.add_mmio_coverage(MmioAddress(0xe000_0000)).is_none());
+        let (id, coverage) = io_mgr.add_mmio_coverage(MmioAddress(0xd000_0010)).unwrap();
+        let (_, pio_coverage) = io_mgr.add_pio_coverage(PioAddress(0x3fd)).unwrap();
+        assert_eq!((coverage.base(), coverage.size()), (0xd000_0000, 0x100));
+
+        let mut data = [0; 4];
+        for _ in 0..2 {
+            io_mgr
+                .mmio_read(MmioAddress(0xd000_0010), &mut data)
+                .unwrap();
+        }
+        io_mgr
+            .mmio_read(MmioAddress(0xd000_0010), &mut data[..1])
+            .unwrap();
+        io_mgr
+            .mmio_write(MmioAddress(0xd000_0004), &[1, 2, 3, 4])
+            .unwrap();
+        io_mgr.pio_write(PioAddress(0x3f9), &[0]).unwrap();
+
+        assert_eq!(
+            coverage.entries(),
+            vec![
+                CoverageEntry {
+                    offset: 0x4,
+                    width: 4,
+                    write: true,
+                    count: 1
+                },
+                CoverageEntry {
+                    offset: 0x10,
+                    width: 1,
+                    write: false,
+                    count: 1
+                },
+                CoverageEntry {
+                    offset: 0x10,
+                    width: 4,
+                    write: false,
+                    count: 2
+                },
+            ]
+        );
+        assert!(coverage.is_covered(0x13, false));
+        assert!(!coverage.is_covered(0x13, true));
+        assert!(coverage.is_covered(0x7, true));
+        assert!(!coverage.is_covered(0x8, true));
+        assert!(pio_coverage.is_covered(1, true));
+
+        let mut report = Vec::new();
+        coverage.write_to(&mut report).unwrap();
+        assert_eq!(
+            String::from_utf8(report).unwrap(),
+            "0x4 4 write 1\n0x10 1 read 1\n0x10 4 read 2\n"
+        );
+
+        // Accesses are no longer recorded once the observer is removed.
+        assert!(io_mgr.remove_mmio_observer(id));
+        io_mgr
+            .mmio_read(MmioAddress(0xd000_0020), &mut data)
+            .unwrap();
+        assert!(!coverage.is_covered(0x20, false));
+        coverage.clear();
+        assert!(coverage.entries().is_empty());
+    }
+}
@@ -0,0 +1,197 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Dispatch of x86 `CPUID` leaves to the objects which provide them.
+//!
+//! A [`CpuidRegistry`](struct.CpuidRegistry.html) answers `CPUID` queries in three steps:
+//! - the provider registered for the range containing the leaf computes the result; leaves
+//!   without a provider use the static entry set for them (e.g. from the values supported
+//!   by the hypervisor), or zeros;
+//! - the filters are then applied in the order they were added, which allows hiding or
+//!   forcing feature bits across all leaves;
+//! - the result is returned to the caller, which loads it into the vCPU registers.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use crate::bus::{self, Bus, CpuidAddress, CpuidAddressValue, CpuidRange};
+
+/// First leaf of the range reserved for hypervisors.
+pub const HYPERVISOR_CPUID_BASE: CpuidAddressValue = 0x4000_0000;
+
+/// Values of the registers returned by `CPUID`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CpuidResult {
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+/// Objects which compute the results of a range of leaves.
+pub trait CpuidProvider {
+    /// Return the result of `CPUID` with `leaf` in `EAX` and `subleaf` in `ECX`. The leaf is
+    /// passed as `base + offset`, with `base` being the start of the registered range.
+    fn cpuid(&self, base: CpuidAddress, offset: CpuidAddressValue, subleaf: u32) -> CpuidResult;
+}
+
+/// Objects which adjust the results of every leaf, after they have been computed.
+pub trait CpuidFilter {
+    /// Adjust `result`, which holds the value of the `leaf` and `subleaf` pair.
+    fn filter(&self, leaf: CpuidAddressValue, subleaf: u32, result: &mut CpuidResult);
+}
+
+impl<F: Fn(CpuidAddressValue, u32, &mut CpuidResult)> CpuidFilter for F {
+    fn filter(&self, leaf: CpuidAddressValue, subleaf: u32, result: &mut CpuidResult) {
+        self(leaf, subleaf, result)
+    }
+}
+
+/// Registry of `CPUID` providers and filters.
+#[derive(Default)]
+pub struct CpuidRegistry {
+    providers: Bus<CpuidAddress, Arc<dyn CpuidProvider + Send + Sync>>,
+    filters: Vec<Arc<dyn CpuidFilter + Send + Sync>>,
+    entries: BTreeMap<(CpuidAddressValue, u32), CpuidResult>,
+}
+
+impl CpuidRegistry {
+    /// Create an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register `provider` as the owner of the leaves in `range`.
+    pub fn register_provider(
+        &mut self,
+        range: CpuidRange,
+        provider: Arc<dyn CpuidProvider + Send + Sync>,
+    ) -> Result<(), bus::Error> {
+        self.providers.register(range, provider)
+    }
+
+    /// Deregister the provider which owns `leaf`.
+    pub fn deregister_provider(
+        &mut self,
+        leaf: CpuidAddress,
+    ) -> Option<(CpuidRange, Arc<dyn CpuidProvider + Send + Sync>)> {
+        self.providers.deregister(leaf)
+    }
+
+    /// Append `filter` to the list of filters applied to every result.
+    pub fn add_filter(&mut self, filter: Arc<dyn CpuidFilter + Send + Sync>) {
+        self.filters.push(filter);
+    }
+
+    /// Set the result of a leaf which has no provider.
+    pub fn set_entry(&mut self, leaf: CpuidAddressValue, subleaf: u32, result: CpuidResult) {
+        self.entries.insert((leaf, subleaf), result);
+    }
+
+    /// Return the result of `CPUID` with `leaf` in `EAX` and `subleaf` in `ECX`.
+    pub fn cpuid(&self, leaf: CpuidAddressValue, subleaf: u32) -> CpuidResult {
+        let addr = CpuidAddress(leaf);
+        let mut result = match self.providers.device(addr) {
+            Some((range, provider)) => provider.cpuid(range.base(), addr - range.base(), subleaf),
+            None => self
+                .entries
+                .get(&(leaf, subleaf))
+                .copied()
+                .unwrap_or_default(),
+        };
+
+        for filter in self.filters.iter() {
+            filter.filter(leaf, subleaf, &mut result);
+        }
+        result
+    }
+}
+
+/// Provides the hypervisor vendor leaf (`HYPERVISOR_CPUID_BASE`), which reports a 12 byte
+/// signature (e.g. `KVMKVMKVM\0\0\0`) and the highest hypervisor leaf.
+pub struct HypervisorIdProvider {
+    signature: [u8; 12],
+    max_leaf: CpuidAddressValue,
+}
+
+impl HypervisorIdProvider {
+    /// Create a provider reporting `signature`, and `max_leaf` as the highest leaf.
+    pub fn new(signature: [u8; 12], max_leaf: CpuidAddressValue) -> Self {
+        HypervisorIdProvider {
+            signature,
+            max_leaf,
+        }
+    }
+}
+
+impl CpuidProvider for HypervisorIdProvider {
+    fn cpuid(&self, _base: CpuidAddress, offset: CpuidAddressValue, _subleaf: u32) -> CpuidResult {
+        if offset != 0 {
+            return CpuidResult::default();
+        }
+        let word = |idx: usize| {
+            let bytes = &self.signature[idx * 4..idx * 4 + 4];
+            u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+        };
+        CpuidResult {
+            eax: self.max_leaf,
+            ebx: word(0),
+            ecx: word(1),
+            edx: word(2),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cpuid_registry() {
+        let mut registry = CpuidRegistry::new();
+        let hv_range = CpuidRange::new(CpuidAddress(HYPERVISOR_CPUID_BASE), 0x100).unwrap();
+        registry
+            .register_provider(
+                hv_range,
+                Arc::new(HypervisorIdProvider::new(*b"KVMKVMKVM\0\0\0", 0x4000_0001)),
+            )
+            .unwrap();
+        assert_eq!(
+            registry.register_provider(hv_range, Arc::new(HypervisorIdProvider::new([0; 12], 0))),
+            Err(bus::Error::DeviceOverlap)
+        );
+
+        let result = registry.cpuid(HYPERVISOR_CPUID_BASE, 0);
+        assert_eq!(result.eax, 0x4000_0001);
+        assert_eq!(result.ebx, 0x4b4d_564b);
+        assert_eq!(
+            registry.cpuid(HYPERVISOR_CPUID_BASE + 1, 0),
+            CpuidResult::default()
+        );
+
+        // Static entries, with a filter which hides the hypervisor present bit of leaf 1.
+        registry.set_entry(
+            1,
+            0,
+            CpuidResult {
+                ecx: 0x8000_0001,
+                ..Default::default()
+            },
+        );
+        registry.add_filter(Arc::new(|leaf, _, result: &mut CpuidResult| {
+            if leaf == 1 {
+                result.ecx &= !(1 << 31);
+            }
+        }));
+        assert_eq!(registry.cpuid(1, 0).ecx, 1);
+        assert_eq!(registry.cpuid(1, 1), CpuidResult::default());
+
+        assert!(registry
+            .deregister_provider(CpuidAddress(HYPERVISOR_CPUID_BASE))
+            .is_some());
+        assert_eq!(
+            registry.cpuid(HYPERVISOR_CPUID_BASE, 0),
+            CpuidResult::default()
+        );
+    }
+}
@@ -0,0 +1,193 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Dependencies between devices.
+//!
+//! Devices often rely on others to operate: a serial port raises its interrupt through the
+//! interrupt controller, and PCI functions are reached through the host bridge. Devices
+//! declare the IDs of the devices they depend on with `IoManager::add_device_dependencies`
+//! (or `IoManagerBuilder::depends_on`, which validates the graph along with the rest of the
+//! layout), and `IoManager::device_order` returns the devices sorted so each one comes after
+//! its dependencies, which is the order the VMM activates and saves them in.
+
+use std::fmt::{Display, Formatter};
+
+use crate::bus::MmioBusAddress;
+use crate::device_manager::IoManager;
+
+/// Errors found while validating the dependencies of the devices.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The device (first) depends on a device which isn't declared (second).
+    UnknownDependency(String, String),
+    /// The dependencies of these devices form a cycle.
+    Cycle(Vec<String>),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::UnknownDependency(id, dependency) => {
+                write!(f, "device {} depends on unknown device {}", id, dependency)
+            }
+            Error::Cycle(ids) => write!(f, "cyclic dependencies between {}", ids.join(", ")),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+// Sort the `count` nodes so each one comes after the nodes listed in its `deps` entry, and
+// the nodes without dependencies between them keep their order. Return the nodes which are
+// part of (or depend on) a cycle on error.
+pub(crate) fn topological_order(deps: &[Vec<usize>]) -> Result<Vec<usize>, Vec<usize>> {
+    let count = deps.len();
+    let mut done = vec![false; count];
+    let mut order = Vec::with_capacity(count);
+    while order.len() < count {
+        match (0..count).find(|idx| !done[*idx] && deps[*idx].iter().all(|dep| done[*dep])) {
+            Some(idx) => {
+                done[idx] = true;
+                order.push(idx);
+            }
+            None => return Err((0..count).filter(|idx| !done[*idx]).collect()),
+        }
+    }
+    Ok(order)
+}
+
+/// Devices and the IDs of the devices each of them depends on.
+#[derive(Clone, Debug, Default)]
+pub struct DependencyGraph {
+    // Devices in the order of their declaration, with their dependencies.
+    devices: Vec<(String, Vec<String>)>,
+}
+
+impl DependencyGraph {
+    /// Create a graph without any device.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Declare the device `id`, which depends on the devices in `dependencies`. Declaring
+    /// the same device again adds to its dependencies.
+    pub fn add(&mut self, id: &str, dependencies: &[&str]) {
+        let idx = match self.devices.iter().position(|(dev, _)| dev == id) {
+            Some(idx) => idx,
+            None => {
+                self.devices.push((id.to_string(), Vec::new()));
+                self.devices.len() - 1
+            }
+        };
+        let deps = &mut self.devices[idx].1;
+        for dependency in dependencies {
+            if !deps.iter().any(|dep| dep == dependency) {
+                deps.push(dependency.to_string());
+            }
+        }
+    }
+
+    /// Return the dependencies of the device `id`.
+    pub fn dependencies(&self, id: &str) -> Option<&[String]> {
+        self.devices
+            .iter()
+            .find(|(dev, _)| dev == id)
+            .map(|(_, deps)| deps.as_slice())
+    }
+
+    /// Return the declared devices, each one after its dependencies. Devices without
+    /// dependencies between them keep the order of their declaration.
+    pub fn order(&self) -> Result<Vec<&str>, Error> {
+        let mut deps = Vec::with_capacity(self.devices.len());
+        for (id, dependencies) in self.devices.iter() {
+            let indices = dependencies
+                .iter()
+                .map(|dependency| {
+                    self.devices
+                        .iter()
+                        .position(|(dev, _)| dev == dependency)
+                        .ok_or_else(|| Error::UnknownDependency(id.clone(), dependency.clone()))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            deps.push(indices);
+        }
+        topological_order(&deps)
+            .map(|order| {
+                order
+                    .into_iter()
+                    .map(|idx| self.devices[idx].0.as_str())
+                    .collect()
+            })
+            .map_err(|cycle| {
+                Error::Cycle(
+                    cycle
+                        .into_iter()
+                        .map(|idx| self.devices[idx].0.clone())
+                        .collect(),
+                )
+            })
+    }
+
+    /// Check that all dependencies are declared, and that they don't form a cycle.
+    pub fn validate(&self) -> Result<(), Error> {
+        self.order().map(|_| ())
+    }
+}
+
+impl<M: MmioBusAddress> IoManager<M> {
+    /// Declare the device `id`, which depends on the devices in `dependencies`. The
+    /// dependencies must be declared as well by the time the order is computed.
+    pub fn add_device_dependencies(&mut self, id: &str, dependencies: &[&str]) {
+        self.dependencies.add(id, dependencies);
+    }
+
+    /// Return the dependency graph of the devices.
+    pub fn dependency_graph(&self) -> &DependencyGraph {
+        &self.dependencies
+    }
+
+    /// Return the IDs of the declared devices, each one after the devices it depends on.
+    pub fn device_order(&self) -> Result<Vec<&str>, Error> {
+        self.dependencies.order()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dependency_graph() {
+        let mut graph = DependencyGraph::new();
+        graph.add("serial", &["ioapic"]);
+        graph.add("virtio-net", &["host-bridge", "ioapic"]);
+        graph.add("ioapic", &[]);
+        graph.add("host-bridge", &["ioapic"]);
+        graph.add("rtc", &[]);
+        graph.add("serial", &["ioapic"]);
+        assert_eq!(graph.dependencies("serial").unwrap(), ["ioapic"]);
+        assert_eq!(
+            graph.order().unwrap(),
+            ["ioapic", "serial", "host-bridge", "virtio-net", "rtc"]
+        );
+
+        graph.add("rtc", &["cmos"]);
+        assert_eq!(
+            graph.validate(),
+            Err(Error::UnknownDependency(
+                "rtc".to_string(),
+                "cmos".to_string()
+            ))
+        );
+        graph.add("cmos", &["rtc"]);
+        assert_eq!(
+            graph.order(),
+            Err(Error::Cycle(vec!["rtc".to_string(), "cmos".to_string()]))
+        );
+
+        let mut io_mgr = IoManager::new();
+        io_mgr.add_device_dependencies("serial", &["pic"]);
+        io_mgr.add_device_dependencies("pic", &[]);
+        assert_eq!(io_mgr.device_order().unwrap(), ["pic", "serial"]);
+    }
+}
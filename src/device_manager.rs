@@ -11,25 +11,65 @@
 //! vm_allocator to allocate the resources, ask vm_device to register the
 //! devices IO ranges, and finally set resources to virtual device.
 
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
 use std::fmt::{Display, Formatter};
+use std::io;
+use std::ops::Range;
+use std::panic::{catch_unwind, AssertUnwindSafe};
 use std::result::Result;
-use std::sync::Arc;
-
-use crate::bus::{self, BusManager, MmioAddress, MmioBus, MmioRange, PioAddress, PioBus, PioRange};
-use crate::resources::Resource;
-use crate::{DeviceMmio, DevicePio};
+use std::sync::{Arc, Weak};
+
+use crate::bus::{
+    self, AccessConstraints, Bus, BusAccess, BusAddress, BusManager, BusObserver, BusRange,
+    Mmio32Address, MmioAddress, MmioBusAddress, MmioOffset, MmioRange, MsrAddress, MsrBus,
+    MsrRange, ObserverId, PioAddress, PioAddressValue, PioBus, PioOffset, PioRange, RangeStats,
+    SysRegAddress, SysRegBus, SysRegRange, WatchAction, WatchHandler, WatchKind, WatchpointId,
+};
+use crate::console::{SinkDevice, SourceDevice};
+use crate::dependency::DependencyGraph;
+use crate::dirty::DirtyBitmap;
+use crate::events::{VmEvent, VmEventSender};
+use crate::exit::AddressSpace;
+use crate::hotplug::{self, HotplugNotifier};
+use crate::interrupt::{self, IrqRouter, LineInterrupt, TriggerMode};
+use crate::lifecycle::Lifecycle;
+use crate::mappable::Mappable;
+use crate::pci::{BarReprogrammingParams, PciBarRegionType};
+use crate::per_cpu::PerCpuDevice;
+use crate::poison::{self, DeviceError};
+use crate::polled::PolledDevices;
+use crate::quiesce::Quiesce;
+use crate::resources::{DeviceConfig, Resource};
+use crate::snapshot::{PostRestore, RestoreContext};
+use crate::{DeviceMmio, DeviceMsr, DevicePio, DeviceSysReg, IoAccess};
 
 /// Error type for `IoManager` usage.
 #[derive(Debug)]
 pub enum Error {
     /// Error during bus operation.
     Bus(bus::Error),
+    /// Error during hotplug operation.
+    Hotplug(hotplug::Error),
+    /// The post-restore hook of a device failed.
+    PostRestore(String, io::Error),
+    /// A device with the same ID is already registered.
+    DuplicateId(String),
+    /// A resource overlaps a registered or reserved range.
+    Conflict(Conflict),
 }
 
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             Error::Bus(_) => write!(f, "device_manager: bus error"),
+            Error::Hotplug(_) => write!(f, "device_manager: hotplug error"),
+            Error::PostRestore(id, _) => {
+                write!(f, "device_manager: post-restore hook of {} failed", id)
+            }
+            Error::DuplicateId(id) => write!(f, "device_manager: device {} already registered", id),
+            Error::Conflict(conflict) => write!(f, "device_manager: {}", conflict),
         }
     }
 }
@@ -38,8 +78,332 @@ impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
             Error::Bus(e) => Some(e),
+            Error::Hotplug(e) => Some(e),
+            Error::PostRestore(_, e) => Some(e),
+            Error::DuplicateId(_) | Error::Conflict(_) => None,
+        }
+    }
+}
+
+/// Range which prevents a resource from being registered.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Conflict {
+    /// The resource which can't be registered.
+    pub resource: Resource,
+    /// The registered or reserved range the resource overlaps.
+    pub existing: Resource,
+    /// ID of the device owning the existing range, or name of the reservation, when known.
+    pub owner: Option<String>,
+    /// Whether the existing range is reserved rather than registered with a device.
+    pub reserved: bool,
+}
+
+// Describe the address range of `res`.
+fn describe_range(res: &Resource) -> String {
+    match res {
+        Resource::PioAddressRange { base, size } => {
+            format!(
+                "PIO range {:#x}-{:#x}",
+                base,
+                base.saturating_add(size.saturating_sub(1))
+            )
+        }
+        Resource::MmioAddressRange { base, size } => {
+            format!(
+                "MMIO range {:#x}-{:#x}",
+                base,
+                base.saturating_add(size.saturating_sub(1))
+            )
+        }
+        other => format!("{:?}", other),
+    }
+}
+
+impl Display for Conflict {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let kind = if self.reserved {
+            "reserved"
+        } else {
+            "registered"
+        };
+        write!(
+            f,
+            "{} overlaps {} {}",
+            describe_range(&self.resource),
+            kind,
+            describe_range(&self.existing)
+        )?;
+        match &self.owner {
+            Some(owner) => write!(f, " ({})", owner),
+            None => Ok(()),
+        }
+    }
+}
+
+// Return the `(base, last, reservation)` ranges of `bus` which overlap the addresses from
+// `base` to `last`, with the name of the reservation for reserved ranges.
+fn overlapping_ranges<A: BusAddress, D>(
+    bus: &Bus<A, D>,
+    base: u64,
+    last: u64,
+) -> Vec<(u64, u64, Option<String>)> {
+    let bounds = |range: &BusRange<A>| -> (u64, u64) {
+        (range.base().value().into(), range.last().value().into())
+    };
+    bus.ranges()
+        .map(|range| (bounds(range), None))
+        .chain(
+            bus.reservations()
+                .map(|(range, name)| (bounds(range), Some(name.to_string()))),
+        )
+        .filter(|((start, end), _)| *start <= last && base <= *end)
+        .map(|((start, end), name)| (start, end, name))
+        .collect()
+}
+
+// Report the quarantine of the range at `base` of the `space` address space.
+fn report_quarantine(events: Option<&VmEventSender>, space: AddressSpace, base: u64) {
+    if let Some(events) = events {
+        // Nobody is left to act on the event if the VMM is gone.
+        let _ = events.send(VmEvent::DeviceQuarantined { space, base });
+    }
+}
+
+// Run `dispatch`, which hands a chunk of an access to the device of `access`. When the bus
+// catches the panics of its devices, a panic fails the chunk instead of unwinding into the
+// caller, and the range at `base` of the `space` address space is quarantined right away.
+fn guard<A: BusAddress, D, F>(
+    access: &BusAccess<'_, A, D>,
+    space: AddressSpace,
+    base: u64,
+    events: Option<&VmEventSender>,
+    dispatch: F,
+) -> Result<(), DeviceError>
+where
+    F: FnOnce() -> Result<(), DeviceError>,
+{
+    if !access.catches_panics() {
+        return dispatch();
+    }
+    catch_unwind(AssertUnwindSafe(dispatch)).unwrap_or_else(|_| {
+        if let Some(events) = events {
+            let _ = events.send(VmEvent::DevicePanicked { space, base });
+        }
+        if access.quarantine() {
+            report_quarantine(events, space, base);
+        }
+        Err(DeviceError::Panicked)
+    })
+}
+
+// Hands the chunks of the accesses dispatched by the managers to the devices of an address
+// space. Accesses carrying their `context` go through the fallible methods of the devices.
+trait SpaceDevice<A: BusAddress> {
+    // Address space of the devices.
+    const SPACE: AddressSpace;
+
+    fn read_at(
+        &self,
+        context: Option<IoAccess>,
+        base: A,
+        offset: A::V,
+        data: &mut [u8],
+    ) -> Result<(), DeviceError>;
+
+    fn write_at(
+        &self,
+        context: Option<IoAccess>,
+        base: A,
+        offset: A::V,
+        data: &[u8],
+    ) -> Result<(), DeviceError>;
+}
+
+impl<D: DevicePio + ?Sized> SpaceDevice<PioAddress> for D {
+    const SPACE: AddressSpace = AddressSpace::Pio;
+
+    fn read_at(
+        &self,
+        context: Option<IoAccess>,
+        base: PioAddress,
+        offset: PioAddressValue,
+        data: &mut [u8],
+    ) -> Result<(), DeviceError> {
+        match context {
+            Some(access) => self.try_pio_read(access, base, PioOffset(offset), data),
+            None => {
+                self.pio_read(base, PioOffset(offset), data);
+                Ok(())
+            }
+        }
+    }
+
+    fn write_at(
+        &self,
+        context: Option<IoAccess>,
+        base: PioAddress,
+        offset: PioAddressValue,
+        data: &[u8],
+    ) -> Result<(), DeviceError> {
+        match context {
+            Some(access) => self.try_pio_write(access, base, PioOffset(offset), data),
+            None => {
+                self.pio_write(base, PioOffset(offset), data);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<A: MmioBusAddress, D: DeviceMmio + ?Sized> SpaceDevice<A> for D {
+    const SPACE: AddressSpace = AddressSpace::Mmio;
+
+    fn read_at(
+        &self,
+        context: Option<IoAccess>,
+        base: A,
+        offset: A::V,
+        data: &mut [u8],
+    ) -> Result<(), DeviceError> {
+        let (base, offset) = (base.to_mmio_address(), MmioOffset(A::offset_to_u64(offset)));
+        match context {
+            Some(access) => self.try_mmio_read(access, base, offset, data),
+            None => {
+                self.mmio_read(base, offset, data);
+                Ok(())
+            }
+        }
+    }
+
+    fn write_at(
+        &self,
+        context: Option<IoAccess>,
+        base: A,
+        offset: A::V,
+        data: &[u8],
+    ) -> Result<(), DeviceError> {
+        let (base, offset) = (base.to_mmio_address(), MmioOffset(A::offset_to_u64(offset)));
+        match context {
+            Some(access) => self.try_mmio_write(access, base, offset, data),
+            None => {
+                self.mmio_write(base, offset, data);
+                Ok(())
+            }
+        }
+    }
+}
+
+// Hand the `len` bytes at `addr` to the devices of `bus`, by calling `chunk` for each chunk of
+// the access (see `BusAccess::chunks`) with the bytes it covers. Accesses spanning several
+// ranges are split when the bus allows it. Returns the fallback device of the bus when the
+// access doesn't reach any range.
+fn dispatch<A, D, F>(
+    bus: &Bus<A, D>,
+    addr: A,
+    len: usize,
+    mut chunk: F,
+) -> Result<Option<&D>, bus::Error>
+where
+    A: BusAddress,
+    F: FnMut(&BusAccess<'_, A, D>, A, Range<usize>),
+{
+    match bus.access(addr, len) {
+        Ok(access) => {
+            for (addr, span) in access.chunks(addr, len) {
+                chunk(&access, addr, span);
+            }
+        }
+        Err(e) => match bus.split_for(e, addr, len) {
+            Ok(pieces) => {
+                for (addr, piece) in pieces {
+                    let access = bus.access(addr, piece.len())?;
+                    for (addr, span) in access.chunks(addr, piece.len()) {
+                        chunk(
+                            &access,
+                            addr,
+                            piece.start + span.start..piece.start + span.end,
+                        );
+                    }
+                }
+            }
+            Err(e) => return bus.fallback_for(e, addr, len).map(Some),
+        },
+    }
+    Ok(None)
+}
+
+// Hand a chunk of an access to the device of `access` with `call`, and return whether it
+// failed. The outcome is recorded for the health tracking of the range, and quarantines are
+// reported to `events`.
+fn dispatch_chunk<A, D, F>(
+    access: &BusAccess<'_, A, D>,
+    events: Option<&VmEventSender>,
+    call: F,
+) -> bool
+where
+    A: BusAddress,
+    D: SpaceDevice<A>,
+    F: FnOnce() -> Result<(), DeviceError>,
+{
+    let range_base = access.range().base().value().into();
+    let failed = guard(access, D::SPACE, range_base, events, call).is_err();
+    if access.record(failed) {
+        report_quarantine(events, D::SPACE, range_base);
+    }
+    failed
+}
+
+// Dispatch a read of `data` at `addr` to the devices of `bus`. Accesses which don't reach any
+// registered range go to the fallback device of the bus, if any, with `addr` as the base
+// address. Failed reads return all ones.
+fn dispatch_read<A: BusAddress, D: SpaceDevice<A>>(
+    bus: &Bus<A, D>,
+    addr: A,
+    context: Option<IoAccess>,
+    data: &mut [u8],
+    events: Option<&VmEventSender>,
+) -> Result<(), bus::Error> {
+    if bus.watch_read(addr, data) == WatchAction::Skip {
+        return Ok(());
+    }
+    let fallback = dispatch(bus, addr, data.len(), |access, addr, span| {
+        let data = &mut data[span];
+        let (base, offset) = (access.base(), access.offset(addr));
+        let call = || access.read_at(context, base, offset, data);
+        if dispatch_chunk(access, events, call) {
+            poison::fail_read(data);
+        }
+    })?;
+    if let Some(device) = fallback {
+        if device.read_at(context, addr, 0.into(), data).is_err() {
+            poison::fail_read(data);
         }
     }
+    bus.observe_read(addr, data);
+    Ok(())
+}
+
+// Same as `dispatch_read`, for writes.
+fn dispatch_write<A: BusAddress, D: SpaceDevice<A>>(
+    bus: &Bus<A, D>,
+    addr: A,
+    context: Option<IoAccess>,
+    data: &[u8],
+    events: Option<&VmEventSender>,
+) -> Result<(), bus::Error> {
+    if bus.watch_write(addr, data) == WatchAction::Skip {
+        return Ok(());
+    }
+    let fallback = dispatch(bus, addr, data.len(), |access, addr, span| {
+        let (base, offset) = (access.base(), access.offset(addr));
+        let call = || access.write_at(context, base, offset, &data[span]);
+        dispatch_chunk(access, events, call);
+    })?;
+    if let Some(device) = fallback {
+        let _ = device.write_at(context, addr, 0.into(), data);
+    }
+    bus.observe_write(addr, data);
+    Ok(())
 }
 
 /// Represents an object that provides PIO manager operations.
@@ -51,26 +415,70 @@ pub trait PioManager {
     /// range, if available.
     fn pio_device(&self, addr: PioAddress) -> Option<(&PioRange, &Self::D)>;
 
-    /// Dispatch a read operation to the device registered at `addr`.
+    /// Return the device registered at `addr` if it's a `T`, for devices exposing their
+    /// concrete type through `DevicePio::as_any`.
+    fn pio_device_downcast<T: Any>(&self, addr: PioAddress) -> Option<&T>
+    where
+        Self: Sized,
+    {
+        self.pio_device(addr)
+            .and_then(|(_, device)| device.as_any())
+            .and_then(|device| device.downcast_ref())
+    }
+
+    /// Dispatch a read operation to the device registered at `addr`. Accesses which don't
+    /// reach any registered range are handled by the fallback device, if any, which sees
+    /// `addr` as the base address.
     fn pio_read(&self, addr: PioAddress, data: &mut [u8]) -> Result<(), bus::Error>;
 
     /// Dispatch a write operation to the device registered at `addr`.
     fn pio_write(&self, addr: PioAddress, data: &[u8]) -> Result<(), bus::Error>;
 
-    /// Register the provided device with the specified range.
+    /// Dispatch a read operation described by the `access` context.
+    fn pio_read_with(
+        &self,
+        addr: PioAddress,
+        access: IoAccess,
+        data: &mut [u8],
+    ) -> Result<(), bus::Error>;
+
+    /// Dispatch a write operation described by the `access` context.
+    fn pio_write_with(
+        &self,
+        addr: PioAddress,
+        access: IoAccess,
+        data: &[u8],
+    ) -> Result<(), bus::Error>;
+
+    /// Register the provided device with the specified range, which must only contain valid
+    /// port addresses for the guest (see `Bus::set_address_limit`).
     fn register_pio(&mut self, range: PioRange, device: Self::D) -> Result<(), bus::Error>;
 
     /// Deregister the device currently registered at `addr` together with the
     /// associated range.
     fn deregister_pio(&mut self, addr: PioAddress) -> Option<(PioRange, Self::D)>;
+
+    /// Start the deferred deregistration of the device registered at `addr`. New accesses
+    /// fail as if no device was registered, while the ones in progress can complete.
+    fn begin_deregister_pio(&self, addr: PioAddress) -> Result<(), bus::Error>;
+
+    /// Return whether the device registered at `addr` is marked for deregistration and no
+    /// longer has any accesses in progress.
+    fn pio_drained(&self, addr: PioAddress) -> bool;
+
+    /// Complete the deferred deregistration of the device registered at `addr`.
+    fn complete_deregister_pio(
+        &mut self,
+        addr: PioAddress,
+    ) -> Result<(PioRange, Self::D), bus::Error>;
 }
 
 // This automatically provides a `PioManager` implementation for types that already implement
-// `BusManager<PioAddress>` if their inner associated type implements `DevicePio` as well.
+// `BusManager<PioAddress>` if their inner associated type implements `DevicePio` and `Clone`.
 impl<T> PioManager for T
 where
     T: BusManager<PioAddress>,
-    T::D: DevicePio,
+    T::D: DevicePio + Clone,
 {
     type D = <Self as BusManager<PioAddress>>::D;
 
@@ -79,15 +487,29 @@ where
     }
 
     fn pio_read(&self, addr: PioAddress, data: &mut [u8]) -> Result<(), bus::Error> {
-        self.bus()
-            .check_access(addr, data.len())
-            .map(|(range, device)| device.pio_read(range.base(), addr - range.base(), data))
+        dispatch_read(self.bus(), addr, None, data, self.events())
     }
 
     fn pio_write(&self, addr: PioAddress, data: &[u8]) -> Result<(), bus::Error> {
-        self.bus()
-            .check_access(addr, data.len())
-            .map(|(range, device)| device.pio_write(range.base(), addr - range.base(), data))
+        dispatch_write(self.bus(), addr, None, data, self.events())
+    }
+
+    fn pio_read_with(
+        &self,
+        addr: PioAddress,
+        access: IoAccess,
+        data: &mut [u8],
+    ) -> Result<(), bus::Error> {
+        dispatch_read(self.bus(), addr, Some(access), data, self.events())
+    }
+
+    fn pio_write_with(
+        &self,
+        addr: PioAddress,
+        access: IoAccess,
+        data: &[u8],
+    ) -> Result<(), bus::Error> {
+        dispatch_write(self.bus(), addr, Some(access), data, self.events())
     }
 
     fn register_pio(&mut self, range: PioRange, device: Self::D) -> Result<(), bus::Error> {
@@ -97,76 +519,534 @@ where
     fn deregister_pio(&mut self, addr: PioAddress) -> Option<(PioRange, Self::D)> {
         self.bus_mut().deregister(addr)
     }
+
+    fn begin_deregister_pio(&self, addr: PioAddress) -> Result<(), bus::Error> {
+        self.bus().begin_deregister(addr)
+    }
+
+    fn pio_drained(&self, addr: PioAddress) -> bool {
+        self.bus().is_drained(addr)
+    }
+
+    fn complete_deregister_pio(
+        &mut self,
+        addr: PioAddress,
+    ) -> Result<(PioRange, Self::D), bus::Error> {
+        self.bus_mut().complete_deregister(addr)
+    }
+}
+
+/// Attributes of an MMIO access which select the address space view it targets, such as
+/// System Management Mode on x86, or the secure world on aarch64.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct AccessAttrs {
+    /// The access is performed while the vCPU is in System Management Mode.
+    pub smm: bool,
+    /// The access is performed by the secure world.
+    pub secure: bool,
 }
 
-/// Represents an object that provides MMIO manager operations.
-pub trait MmioManager {
+/// Identifies one of the independent MMIO address spaces of an `IoManager`, such as a PCI
+/// segment group or a separate SoC bus. Segment 0 is the regular MMIO bus.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct MmioSegment(pub u16);
+
+impl MmioSegment {
+    /// The regular MMIO bus, which the `MmioManager` methods dispatch to.
+    pub const DEFAULT: MmioSegment = MmioSegment(0);
+}
+
+/// Represents an object that provides MMIO manager operations. The trait is generic over the
+/// bus address type, so it can also be used for buses with narrower address spaces (such as
+/// `Mmio32Address`).
+pub trait MmioManager<A: MmioBusAddress = MmioAddress> {
     /// Type of the objects that can be registered with this `MmioManager`.
     type D: DeviceMmio;
 
     /// Return a reference to the device registered at `addr`, together with the associated
     /// range, if available.
-    fn mmio_device(&self, addr: MmioAddress) -> Option<(&MmioRange, &Self::D)>;
+    fn mmio_device(&self, addr: A) -> Option<(&BusRange<A>, &Self::D)>;
+
+    /// Return the device registered at `addr` if it's a `T`, for devices exposing their
+    /// concrete type through `DeviceMmio::as_any`.
+    fn mmio_device_downcast<'a, T: Any>(&'a self, addr: A) -> Option<&'a T>
+    where
+        Self: Sized,
+        A: 'a,
+        Self::D: 'a,
+    {
+        self.mmio_device(addr)
+            .and_then(|(_, device)| device.as_any())
+            .and_then(|device| device.downcast_ref())
+    }
 
-    /// Dispatch a read operation to the device registered at `addr`.
-    fn mmio_read(&self, addr: MmioAddress, data: &mut [u8]) -> Result<(), bus::Error>;
+    /// Dispatch a read operation to the device registered at `addr`. Accesses which don't
+    /// reach any registered range are handled by the fallback device, if any, which sees
+    /// `addr` as the base address.
+    fn mmio_read(&self, addr: A, data: &mut [u8]) -> Result<(), bus::Error>;
 
     /// Dispatch a write operation to the device registered at `addr`.
-    fn mmio_write(&self, addr: MmioAddress, data: &[u8]) -> Result<(), bus::Error>;
+    fn mmio_write(&self, addr: A, data: &[u8]) -> Result<(), bus::Error>;
+
+    /// Dispatch a read operation described by the `access` context.
+    fn mmio_read_with(&self, addr: A, access: IoAccess, data: &mut [u8]) -> Result<(), bus::Error>;
+
+    /// Dispatch a write operation described by the `access` context.
+    fn mmio_write_with(&self, addr: A, access: IoAccess, data: &[u8]) -> Result<(), bus::Error>;
 
     /// Register the provided device with the specified range.
-    fn register_mmio(&mut self, range: MmioRange, device: Self::D) -> Result<(), bus::Error>;
+    fn register_mmio(&mut self, range: BusRange<A>, device: Self::D) -> Result<(), bus::Error>;
 
     /// Deregister the device currently registered at `addr` together with the
     /// associated range.
-    fn deregister_mmio(&mut self, addr: MmioAddress) -> Option<(MmioRange, Self::D)>;
+    fn deregister_mmio(&mut self, addr: A) -> Option<(BusRange<A>, Self::D)>;
+
+    /// Start the deferred deregistration of the device registered at `addr`. New accesses
+    /// fail as if no device was registered, while the ones in progress can complete.
+    fn begin_deregister_mmio(&self, addr: A) -> Result<(), bus::Error>;
+
+    /// Return whether the device registered at `addr` is marked for deregistration and no
+    /// longer has any accesses in progress.
+    fn mmio_drained(&self, addr: A) -> bool;
+
+    /// Complete the deferred deregistration of the device registered at `addr`.
+    fn complete_deregister_mmio(&mut self, addr: A) -> Result<(BusRange<A>, Self::D), bus::Error>;
 }
 
 // This automatically provides a `MmioManager` implementation for types that already implement
-// `BusManager<MmioAddress>` if their inner associated type implements `DeviceMmio` as well.
-impl<T> MmioManager for T
+// `BusManager<A>` for a MMIO address type `A`, if their inner associated type implements
+// `DeviceMmio` and `Clone`.
+impl<T, A> MmioManager<A> for T
 where
-    T: BusManager<MmioAddress>,
-    T::D: DeviceMmio,
+    A: MmioBusAddress,
+    T: BusManager<A>,
+    T::D: DeviceMmio + Clone,
 {
-    type D = <Self as BusManager<MmioAddress>>::D;
+    type D = <Self as BusManager<A>>::D;
 
-    fn mmio_device(&self, addr: MmioAddress) -> Option<(&MmioRange, &Self::D)> {
+    fn mmio_device(&self, addr: A) -> Option<(&BusRange<A>, &Self::D)> {
         self.bus().device(addr)
     }
 
-    fn mmio_read(&self, addr: MmioAddress, data: &mut [u8]) -> Result<(), bus::Error> {
-        self.bus()
-            .check_access(addr, data.len())
-            .map(|(range, device)| device.mmio_read(range.base(), addr - range.base(), data))
+    fn mmio_read(&self, addr: A, data: &mut [u8]) -> Result<(), bus::Error> {
+        dispatch_read(self.bus(), addr, None, data, self.events())
     }
 
-    fn mmio_write(&self, addr: MmioAddress, data: &[u8]) -> Result<(), bus::Error> {
-        self.bus()
-            .check_access(addr, data.len())
-            .map(|(range, device)| device.mmio_write(range.base(), addr - range.base(), data))
+    fn mmio_write(&self, addr: A, data: &[u8]) -> Result<(), bus::Error> {
+        dispatch_write(self.bus(), addr, None, data, self.events())
+    }
+
+    fn mmio_read_with(&self, addr: A, access: IoAccess, data: &mut [u8]) -> Result<(), bus::Error> {
+        dispatch_read(self.bus(), addr, Some(access), data, self.events())
+    }
+
+    fn mmio_write_with(&self, addr: A, access: IoAccess, data: &[u8]) -> Result<(), bus::Error> {
+        dispatch_write(self.bus(), addr, Some(access), data, self.events())
+    }
+
+    fn register_mmio(&mut self, range: BusRange<A>, device: Self::D) -> Result<(), bus::Error> {
+        self.bus_mut().register(range, device)
+    }
+
+    fn deregister_mmio(&mut self, addr: A) -> Option<(BusRange<A>, Self::D)> {
+        self.bus_mut().deregister(addr)
+    }
+
+    fn begin_deregister_mmio(&self, addr: A) -> Result<(), bus::Error> {
+        self.bus().begin_deregister(addr)
+    }
+
+    fn mmio_drained(&self, addr: A) -> bool {
+        self.bus().is_drained(addr)
+    }
+
+    fn complete_deregister_mmio(&mut self, addr: A) -> Result<(BusRange<A>, Self::D), bus::Error> {
+        self.bus_mut().complete_deregister(addr)
+    }
+}
+
+/// Represents an object that provides system register manager operations.
+pub trait SysRegManager {
+    /// Type of the objects that can be registered with this `SysRegManager`.
+    type D: DeviceSysReg;
+
+    /// Return a reference to the device registered at `addr`, together with the associated
+    /// range, if available.
+    fn sysreg_device(&self, addr: SysRegAddress) -> Option<(&SysRegRange, &Self::D)>;
+
+    /// Dispatch a read of the system register `addr` to the device registered for it.
+    fn sysreg_read(&self, addr: SysRegAddress) -> Result<u64, bus::Error>;
+
+    /// Dispatch a write of the system register `addr` to the device registered for it.
+    fn sysreg_write(&self, addr: SysRegAddress, value: u64) -> Result<(), bus::Error>;
+
+    /// Dispatch a read operation described by the `access` context.
+    fn sysreg_read_with(&self, addr: SysRegAddress, access: IoAccess) -> Result<u64, bus::Error>;
+
+    /// Dispatch a write operation described by the `access` context.
+    fn sysreg_write_with(
+        &self,
+        addr: SysRegAddress,
+        access: IoAccess,
+        value: u64,
+    ) -> Result<(), bus::Error>;
+
+    /// Register the provided device with the specified range.
+    fn register_sysreg(&mut self, range: SysRegRange, device: Self::D) -> Result<(), bus::Error>;
+
+    /// Deregister the device currently registered at `addr` together with the
+    /// associated range.
+    fn deregister_sysreg(&mut self, addr: SysRegAddress) -> Option<(SysRegRange, Self::D)>;
+}
+
+// This automatically provides a `SysRegManager` implementation for types that already
+// implement `BusManager<SysRegAddress>` if their inner associated type implements
+// `DeviceSysReg` and `Clone`.
+impl<T> SysRegManager for T
+where
+    T: BusManager<SysRegAddress>,
+    T::D: DeviceSysReg + Clone,
+{
+    type D = <Self as BusManager<SysRegAddress>>::D;
+
+    fn sysreg_device(&self, addr: SysRegAddress) -> Option<(&SysRegRange, &Self::D)> {
+        self.bus().device(addr)
+    }
+
+    fn sysreg_read(&self, addr: SysRegAddress) -> Result<u64, bus::Error> {
+        self.bus().access(addr, 1).map(|access| {
+            let base = access.base();
+            access.sysreg_read(base, access.offset(addr))
+        })
+    }
+
+    fn sysreg_write(&self, addr: SysRegAddress, value: u64) -> Result<(), bus::Error> {
+        self.bus().access(addr, 1).map(|access| {
+            let base = access.base();
+            access.sysreg_write(base, access.offset(addr), value)
+        })
+    }
+
+    fn sysreg_read_with(
+        &self,
+        addr: SysRegAddress,
+        io_access: IoAccess,
+    ) -> Result<u64, bus::Error> {
+        self.bus().access(addr, 1).map(|access| {
+            let base = access.base();
+            access.sysreg_read_with(io_access, base, access.offset(addr))
+        })
+    }
+
+    fn sysreg_write_with(
+        &self,
+        addr: SysRegAddress,
+        io_access: IoAccess,
+        value: u64,
+    ) -> Result<(), bus::Error> {
+        self.bus().access(addr, 1).map(|access| {
+            let base = access.base();
+            access.sysreg_write_with(io_access, base, access.offset(addr), value)
+        })
+    }
+
+    fn register_sysreg(&mut self, range: SysRegRange, device: Self::D) -> Result<(), bus::Error> {
+        self.bus_mut().register(range, device)
+    }
+
+    fn deregister_sysreg(&mut self, addr: SysRegAddress) -> Option<(SysRegRange, Self::D)> {
+        self.bus_mut().deregister(addr)
+    }
+}
+
+/// Represents an object that provides MSR manager operations.
+pub trait MsrManager {
+    /// Type of the objects that can be registered with this `MsrManager`.
+    type D: DeviceMsr;
+
+    /// Return a reference to the device registered at `addr`, together with the associated
+    /// range, if available.
+    fn msr_device(&self, addr: MsrAddress) -> Option<(&MsrRange, &Self::D)>;
+
+    /// Dispatch a read of the MSR `addr` to the device registered for it.
+    fn msr_read(&self, addr: MsrAddress) -> Result<u64, bus::Error>;
+
+    /// Dispatch a write of the MSR `addr` to the device registered for it.
+    fn msr_write(&self, addr: MsrAddress, value: u64) -> Result<(), bus::Error>;
+
+    /// Dispatch a read operation described by the `access` context.
+    fn msr_read_with(&self, addr: MsrAddress, access: IoAccess) -> Result<u64, bus::Error>;
+
+    /// Dispatch a write operation described by the `access` context.
+    fn msr_write_with(
+        &self,
+        addr: MsrAddress,
+        access: IoAccess,
+        value: u64,
+    ) -> Result<(), bus::Error>;
+
+    /// Register the provided device with the specified range.
+    fn register_msr(&mut self, range: MsrRange, device: Self::D) -> Result<(), bus::Error>;
+
+    /// Deregister the device currently registered at `addr` together with the
+    /// associated range.
+    fn deregister_msr(&mut self, addr: MsrAddress) -> Option<(MsrRange, Self::D)>;
+}
+
+// This automatically provides a `MsrManager` implementation for types that already
+// implement `BusManager<MsrAddress>` if their inner associated type implements
+// `DeviceMsr` and `Clone`.
+impl<T> MsrManager for T
+where
+    T: BusManager<MsrAddress>,
+    T::D: DeviceMsr + Clone,
+{
+    type D = <Self as BusManager<MsrAddress>>::D;
+
+    fn msr_device(&self, addr: MsrAddress) -> Option<(&MsrRange, &Self::D)> {
+        self.bus().device(addr)
+    }
+
+    fn msr_read(&self, addr: MsrAddress) -> Result<u64, bus::Error> {
+        self.bus().access(addr, 1).map(|access| {
+            let base = access.base();
+            access.msr_read(base, access.offset(addr))
+        })
+    }
+
+    fn msr_write(&self, addr: MsrAddress, value: u64) -> Result<(), bus::Error> {
+        self.bus().access(addr, 1).map(|access| {
+            let base = access.base();
+            access.msr_write(base, access.offset(addr), value)
+        })
+    }
+
+    fn msr_read_with(&self, addr: MsrAddress, io_access: IoAccess) -> Result<u64, bus::Error> {
+        self.bus().access(addr, 1).map(|access| {
+            let base = access.base();
+            access.msr_read_with(io_access, base, access.offset(addr))
+        })
+    }
+
+    fn msr_write_with(
+        &self,
+        addr: MsrAddress,
+        io_access: IoAccess,
+        value: u64,
+    ) -> Result<(), bus::Error> {
+        self.bus().access(addr, 1).map(|access| {
+            let base = access.base();
+            access.msr_write_with(io_access, base, access.offset(addr), value)
+        })
     }
 
-    fn register_mmio(&mut self, range: MmioRange, device: Self::D) -> Result<(), bus::Error> {
+    fn register_msr(&mut self, range: MsrRange, device: Self::D) -> Result<(), bus::Error> {
         self.bus_mut().register(range, device)
     }
 
-    fn deregister_mmio(&mut self, addr: MmioAddress) -> Option<(MmioRange, Self::D)> {
+    fn deregister_msr(&mut self, addr: MsrAddress) -> Option<(MsrRange, Self::D)> {
         self.bus_mut().deregister(addr)
     }
 }
 
+/// Describes a range registered on one of the buses of an `IoManager`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct LayoutEntry<A: BusAddress> {
+    /// The registered range.
+    pub range: BusRange<A>,
+    /// Identifies the device the range is associated with. Ranges registered with the same
+    /// device object have the same ID.
+    pub device_id: usize,
+    /// The range is marked for deregistration, and no longer accepts new accesses.
+    pub draining: bool,
+    /// The range is a shadow, which takes priority over the regular range below it.
+    pub shadow: bool,
+    /// The range is decoded; accesses to disabled ranges don't reach the device.
+    pub enabled: bool,
+}
+
+/// Describes a range reserved on one of the buses of an `IoManager`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Reservation<A: BusAddress> {
+    /// The reserved range.
+    pub range: BusRange<A>,
+    /// Describes the use of the range.
+    pub name: String,
+}
+
+/// Describes the ranges registered with an `IoManager`, in ascending address order. This is
+/// meant for debugging, snapshots, and generating firmware tables (ACPI, FDT).
+#[derive(Clone, Debug, PartialEq)]
+pub struct IoLayout<M: MmioBusAddress = MmioAddress> {
+    /// Ranges registered on the PIO bus.
+    pub pio: Vec<LayoutEntry<PioAddress>>,
+    /// Ranges registered on the MMIO bus.
+    pub mmio: Vec<LayoutEntry<M>>,
+    /// Ranges registered on the MMIO overlays, together with the attributes selecting them.
+    pub mmio_overlays: Vec<(AccessAttrs, Vec<LayoutEntry<M>>)>,
+    /// Ranges registered on the MMIO segments other than the default one.
+    pub mmio_segments: Vec<(MmioSegment, Vec<LayoutEntry<M>>)>,
+    /// Ranges registered on the system register bus.
+    pub sysreg: Vec<LayoutEntry<SysRegAddress>>,
+    /// Ranges registered on the MSR bus.
+    pub msr: Vec<LayoutEntry<MsrAddress>>,
+    /// Ranges reserved on the PIO bus.
+    pub pio_reserved: Vec<Reservation<PioAddress>>,
+    /// Ranges reserved on the MMIO bus.
+    pub mmio_reserved: Vec<Reservation<M>>,
+}
+
+fn bus_layout<A: BusAddress, T: ?Sized>(bus: &Bus<A, Arc<T>>) -> Vec<LayoutEntry<A>> {
+    let entry = |range: &BusRange<A>, device: &Arc<T>, shadow| LayoutEntry {
+        range: *range,
+        device_id: device_key(device),
+        draining: bus.is_draining(range),
+        shadow,
+        enabled: bus.is_enabled(range),
+    };
+    let mut layout: Vec<_> = bus
+        .iter()
+        .map(|(range, device)| entry(range, device, false))
+        .chain(
+            bus.shadows()
+                .map(|(range, device)| entry(range, device, true)),
+        )
+        .collect();
+    layout.sort_by_key(|entry| entry.range.base());
+    layout
+}
+
+fn bus_reservations<A: BusAddress, D>(bus: &Bus<A, D>) -> Vec<Reservation<A>> {
+    bus.reservations()
+        .map(|(range, name)| Reservation {
+            range: *range,
+            name: name.to_string(),
+        })
+        .collect()
+}
+
 /// System IO manager serving for all devices management and VM exit handling.
-#[derive(Default)]
-pub struct IoManager {
+///
+/// The manager is generic over the address type of the MMIO bus, which defaults to the
+/// 64-bit `MmioAddress`. The helpers that work with `Resource` objects are only available for
+/// the default address type.
+pub struct IoManager<M: MmioBusAddress = MmioAddress> {
     // Range mapping for VM exit pio operations.
     pio_bus: PioBus<Arc<dyn DevicePio + Send + Sync>>,
     // Range mapping for VM exit mmio operations.
-    mmio_bus: MmioBus<Arc<dyn DeviceMmio + Send + Sync>>,
+    pub(crate) mmio_bus: Bus<M, Arc<dyn DeviceMmio + Send + Sync>>,
+    // Overlay range mappings, which take priority over `mmio_bus` for accesses performed with
+    // the matching attributes.
+    mmio_overlays: BTreeMap<AccessAttrs, Bus<M, Arc<dyn DeviceMmio + Send + Sync>>>,
+    // Range mappings of the MMIO segments other than the default one.
+    mmio_segments: BTreeMap<MmioSegment, Bus<M, Arc<dyn DeviceMmio + Send + Sync>>>,
+    // Range mapping for trapped system register accesses.
+    sysreg_bus: SysRegBus<Arc<dyn DeviceSysReg + Send + Sync>>,
+    // Range mapping for trapped MSR accesses.
+    msr_bus: MsrBus<Arc<dyn DeviceMsr + Send + Sync>>,
+    // Resources of hot-unplugged devices, keyed by slot, waiting for the guest to eject them.
+    pending_unplug: BTreeMap<u32, Vec<Resource>>,
+    // Dirty bitmaps of the device-backed memory regions.
+    dirty_regions: Bus<M, Arc<DirtyBitmap>>,
+    // Parts of the MMIO ranges which can be mapped into the guest.
+    pub(crate) mappable: Bus<M, Mappable>,
+    // Channel used by devices to send control plane events to the VMM.
+    events: Option<VmEventSender>,
+    // Routing table of the legacy interrupt lines.
+    irq_router: Option<IrqRouter>,
+    // Ranges registered for each composite device, keyed by ID.
+    pub(crate) composites: BTreeMap<String, Vec<Resource>>,
+    // Hooks run once a restore completes, with the ID of their device, in registration order.
+    post_restore: Vec<(String, Arc<dyn PostRestore + Send + Sync>)>,
+    // Devices quiesced before snapshots, with their ID, in registration order.
+    pub(crate) quiesce_devices: Vec<(String, Arc<dyn Quiesce + Send + Sync>)>,
+    // Devices registered with their concrete type, keyed by the address of their allocation.
+    // The references are weak, so they don't keep deregistered devices alive.
+    device_types: BTreeMap<usize, Weak<dyn Any + Send + Sync>>,
+    // Devices accepting input from the frontends of the VMM, keyed by ID.
+    pub(crate) sinks: BTreeMap<String, Arc<dyn SinkDevice + Send + Sync>>,
+    // Devices producing output for the frontends of the VMM, keyed by ID.
+    pub(crate) sources: BTreeMap<String, Arc<dyn SourceDevice + Send + Sync>>,
+    // Dependencies declared between the devices.
+    pub(crate) dependencies: DependencyGraph,
+    // Devices reset, suspended, and resumed with the VM, with their ID, in registration order.
+    pub(crate) lifecycle_devices: Vec<(String, Arc<dyn Lifecycle + Send + Sync>)>,
+    // Reset ordering constraints, as `(device, dependency)` pairs of IDs: the device is
+    // reset after its dependency.
+    pub(crate) reset_order: Vec<(String, String)>,
+    // Devices publishing registers through shared pages, keyed by the range of the page.
+    pub(crate) polled_devices: PolledDevices<M>,
+    // Configuration and resources of the devices registered with one, keyed by ID.
+    configs: BTreeMap<String, (DeviceConfig, Vec<Resource>)>,
+}
+
+/// Devices held by the buses of an `IoManager`, which are identified by the address of their
+/// allocation.
+pub trait StoredDevice {
+    /// Return the address of the allocation holding the device.
+    fn key(&self) -> usize;
+}
+
+impl<T: ?Sized> StoredDevice for Arc<T> {
+    fn key(&self) -> usize {
+        device_key(self)
+    }
+}
+
+fn device_key<T: ?Sized>(device: &Arc<T>) -> usize {
+    Arc::as_ptr(device) as *const () as usize
+}
+
+// Return an empty bus which identifies its devices the same way as `IoManager::layout`.
+fn arc_bus<A: BusAddress, T: ?Sized>() -> Bus<A, Arc<T>> {
+    let mut bus = Bus::new();
+    bus.set_device_key(device_key::<T>);
+    bus
+}
+
+// Return an empty bus with the same health settings as `like`: quarantining ranges after
+// the same number of consecutive failures, and catching the panics of the devices if it does.
+fn quarantined_bus<A: BusAddress, T: ?Sized, E>(like: &Bus<A, E>) -> Bus<A, Arc<T>> {
+    let mut bus = arc_bus();
+    bus.set_quarantine_threshold(like.quarantine_threshold());
+    bus.set_catch_panics(like.catches_panics());
+    bus
+}
+
+/// IO manager for platforms with a 32-bit wide MMIO address space.
+pub type IoManager32 = IoManager<Mmio32Address>;
+
+impl<M: MmioBusAddress> Default for IoManager<M> {
+    fn default() -> Self {
+        IoManager {
+            pio_bus: arc_bus(),
+            mmio_bus: arc_bus(),
+            mmio_overlays: BTreeMap::new(),
+            mmio_segments: BTreeMap::new(),
+            sysreg_bus: arc_bus(),
+            msr_bus: arc_bus(),
+            pending_unplug: BTreeMap::new(),
+            dirty_regions: Bus::default(),
+            mappable: Bus::default(),
+            events: None,
+            irq_router: None,
+            composites: BTreeMap::new(),
+            post_restore: Vec::new(),
+            quiesce_devices: Vec::new(),
+            device_types: BTreeMap::new(),
+            sinks: BTreeMap::new(),
+            sources: BTreeMap::new(),
+            dependencies: DependencyGraph::new(),
+            lifecycle_devices: Vec::new(),
+            reset_order: Vec::new(),
+            polled_devices: BTreeMap::new(),
+            configs: BTreeMap::new(),
+        }
+    }
 }
 
 // Enables the automatic implementation of `PioManager` for `IoManager`.
-impl BusManager<PioAddress> for IoManager {
+impl<M: MmioBusAddress> BusManager<PioAddress> for IoManager<M> {
     type D = Arc<dyn DevicePio + Send + Sync>;
 
     fn bus(&self) -> &PioBus<Arc<dyn DevicePio + Send + Sync>> {
@@ -176,50 +1056,914 @@ impl BusManager<PioAddress> for IoManager {
     fn bus_mut(&mut self) -> &mut PioBus<Arc<dyn DevicePio + Send + Sync>> {
         &mut self.pio_bus
     }
+
+    fn events(&self) -> Option<&VmEventSender> {
+        self.events.as_ref()
+    }
+}
+
+// Enables the automatic implementation of `SysRegManager` for `IoManager`.
+impl<M: MmioBusAddress> BusManager<SysRegAddress> for IoManager<M> {
+    type D = Arc<dyn DeviceSysReg + Send + Sync>;
+
+    fn bus(&self) -> &SysRegBus<Arc<dyn DeviceSysReg + Send + Sync>> {
+        &self.sysreg_bus
+    }
+
+    fn bus_mut(&mut self) -> &mut SysRegBus<Arc<dyn DeviceSysReg + Send + Sync>> {
+        &mut self.sysreg_bus
+    }
+}
+
+// Enables the automatic implementation of `MsrManager` for `IoManager`.
+impl<M: MmioBusAddress> BusManager<MsrAddress> for IoManager<M> {
+    type D = Arc<dyn DeviceMsr + Send + Sync>;
+
+    fn bus(&self) -> &MsrBus<Arc<dyn DeviceMsr + Send + Sync>> {
+        &self.msr_bus
+    }
+
+    fn bus_mut(&mut self) -> &mut MsrBus<Arc<dyn DeviceMsr + Send + Sync>> {
+        &mut self.msr_bus
+    }
 }
 
 // Enables the automatic implementation of `MmioManager` for `IoManager`.
-impl BusManager<MmioAddress> for IoManager {
+impl<M: MmioBusAddress> BusManager<M> for IoManager<M> {
     type D = Arc<dyn DeviceMmio + Send + Sync>;
 
-    fn bus(&self) -> &MmioBus<Arc<dyn DeviceMmio + Send + Sync>> {
+    fn bus(&self) -> &Bus<M, Arc<dyn DeviceMmio + Send + Sync>> {
         &self.mmio_bus
     }
 
-    fn bus_mut(&mut self) -> &mut MmioBus<Arc<dyn DeviceMmio + Send + Sync>> {
+    fn bus_mut(&mut self) -> &mut Bus<M, Arc<dyn DeviceMmio + Send + Sync>> {
         &mut self.mmio_bus
     }
+
+    fn events(&self) -> Option<&VmEventSender> {
+        self.events.as_ref()
+    }
 }
 
-impl IoManager {
-    /// Create an default IoManager with empty IO member.
-    pub fn new() -> Self {
-        IoManager::default()
+impl<M: MmioBusAddress> IoManager<M> {
+    /// Return a copy of the manager, e.g. for the clone of a forked VM. The devices are
+    /// shared by both copies (the VMM replaces the ones holding per-VM state in the fork),
+    /// but the ranges get their own state: disabling, quarantining or deregistering a range
+    /// in either copy doesn't affect the other. Forking takes time linear in the number of
+    /// registered ranges.
+    pub fn fork(&self) -> Self {
+        self.duplicate(false)
     }
 
-    /// Register a new MMIO device with its allocated resources.
-    /// VMM is responsible for providing the allocated resources to virtual device.
-    ///
-    /// # Arguments
-    ///
-    /// * `device`: device instance object to be registered
-    /// * `resources`: resources that this device owns, might include
-    ///                port I/O and memory-mapped I/O ranges, irq number, etc.
-    pub fn register_mmio_resources(
+    // Same as `fork`, but the copy shares the state of the ranges with the manager, for the
+    // double buffer of `IoManagerOwner`.
+    pub(crate) fn share(&self) -> Self {
+        self.duplicate(true)
+    }
+
+    fn duplicate(&self, share_entries: bool) -> Self {
+        IoManager {
+            pio_bus: self.pio_bus.duplicate(share_entries),
+            mmio_bus: self.mmio_bus.duplicate(share_entries),
+            mmio_overlays: self
+                .mmio_overlays
+                .iter()
+                .map(|(attrs, bus)| (*attrs, bus.duplicate(share_entries)))
+                .collect(),
+            mmio_segments: self
+                .mmio_segments
+                .iter()
+                .map(|(segment, bus)| (*segment, bus.duplicate(share_entries)))
+                .collect(),
+            sysreg_bus: self.sysreg_bus.duplicate(share_entries),
+            msr_bus: self.msr_bus.duplicate(share_entries),
+            pending_unplug: self.pending_unplug.clone(),
+            dirty_regions: self.dirty_regions.duplicate(share_entries),
+            mappable: self.mappable.duplicate(share_entries),
+            events: self.events.clone(),
+            irq_router: self.irq_router.clone(),
+            composites: self.composites.clone(),
+            post_restore: self.post_restore.clone(),
+            quiesce_devices: self.quiesce_devices.clone(),
+            device_types: self.device_types.clone(),
+            sinks: self.sinks.clone(),
+            sources: self.sources.clone(),
+            dependencies: self.dependencies.clone(),
+            lifecycle_devices: self.lifecycle_devices.clone(),
+            reset_order: self.reset_order.clone(),
+            polled_devices: self.polled_devices.clone(),
+            configs: self.configs.clone(),
+        }
+    }
+
+    // Remember the concrete type of `device`, so it can be retrieved with `device_as`.
+    fn remember_type<T: Any + Send + Sync>(&mut self, device: &Arc<T>) {
+        self.device_types
+            .retain(|_, device| device.strong_count() > 0);
+        let device: Arc<dyn Any + Send + Sync> = device.clone();
+        self.device_types
+            .insert(device_key(&device), Arc::downgrade(&device));
+    }
+
+    /// Register `device` with the PIO `range`. Unlike `register_pio`, the device isn't
+    /// wrapped in another `Arc`, and its concrete type can be retrieved with `device_as`.
+    pub fn register_pio_typed<T: DevicePio + Send + Sync + 'static>(
         &mut self,
-        device: Arc<dyn DeviceMmio + Send + Sync>,
-        resources: &[Resource],
+        range: PioRange,
+        device: Arc<T>,
     ) -> Result<(), Error> {
-        // Register and mark device resources
-        // The resources addresses being registered are sucessfully allocated before.
-        for res in resources.iter() {
-            match *res {
-                Resource::MmioAddressRange { base, size } => {
-                    self.register_mmio(
+        self.register_pio(range, device.clone())
+            .map_err(Error::Bus)?;
+        self.remember_type(&device);
+        Ok(())
+    }
+
+    /// Register `device` with the MMIO `range`, keeping its concrete type for `device_as`.
+    pub fn register_mmio_typed<T: DeviceMmio + Send + Sync + 'static>(
+        &mut self,
+        range: BusRange<M>,
+        device: Arc<T>,
+    ) -> Result<(), Error> {
+        self.register_mmio(range, device.clone())
+            .map_err(Error::Bus)?;
+        self.remember_type(&device);
+        Ok(())
+    }
+
+    /// Return the device registered at `addr`, on the bus matching the type of `addr`, if
+    /// it's a `T`. Only the devices registered with their concrete type (through
+    /// `register_pio_typed`, `register_mmio_typed` or `register_resources`) can be
+    /// retrieved, e.g. to save their state or change their configuration.
+    pub fn device_as<T: Any + Send + Sync, A: BusAddress>(&self, addr: A) -> Option<Arc<T>>
+    where
+        Self: BusManager<A>,
+        <Self as BusManager<A>>::D: StoredDevice,
+    {
+        let (_, device) = self.bus().device(addr)?;
+        self.device_types
+            .get(&device.key())
+            .and_then(Weak::upgrade)
+            .and_then(|device| device.downcast::<T>().ok())
+    }
+
+    /// Register a MMIO device which is banked per vCPU: `devices[i]` handles the accesses
+    /// dispatched with `IoAccess::vcpu_id` set to `i`, through the `mmio_*_with` methods.
+    pub fn register_mmio_per_cpu<T: DeviceMmio + Send + Sync + 'static>(
+        &mut self,
+        range: BusRange<M>,
+        devices: Vec<T>,
+    ) -> Result<(), Error> {
+        self.mmio_bus
+            .register(range, Arc::new(PerCpuDevice::new(devices)))
+            .map_err(Error::Bus)
+    }
+
+    /// Register a system register device which is banked per vCPU: `devices[i]` handles the
+    /// accesses dispatched with `IoAccess::vcpu_id` set to `i`, through the `sysreg_*_with`
+    /// methods.
+    pub fn register_sysreg_per_cpu<T: DeviceSysReg + Send + Sync + 'static>(
+        &mut self,
+        range: SysRegRange,
+        devices: Vec<T>,
+    ) -> Result<(), Error> {
+        self.sysreg_bus
+            .register(range, Arc::new(PerCpuDevice::new(devices)))
+            .map_err(Error::Bus)
+    }
+
+    /// Register a MSR device which is banked per vCPU: `devices[i]` handles the accesses
+    /// dispatched with `IoAccess::vcpu_id` set to `i`, through the `msr_*_with` methods.
+    pub fn register_msr_per_cpu<T: DeviceMsr + Send + Sync + 'static>(
+        &mut self,
+        range: MsrRange,
+        devices: Vec<T>,
+    ) -> Result<(), Error> {
+        self.msr_bus
+            .register(range, Arc::new(PerCpuDevice::new(devices)))
+            .map_err(Error::Bus)
+    }
+
+    /// Register a PIO device on top of a smaller part of an already registered range. The
+    /// device takes priority over the one below it until deregistered with `deregister_pio`.
+    pub fn register_pio_shadow(
+        &mut self,
+        range: PioRange,
+        device: Arc<dyn DevicePio + Send + Sync>,
+    ) -> Result<(), Error> {
+        self.pio_bus
+            .register_shadow(range, device)
+            .map_err(Error::Bus)
+    }
+
+    /// Register a MMIO device on top of a smaller part of an already registered range. The
+    /// device takes priority over the one below it until deregistered with `deregister_mmio`.
+    pub fn register_mmio_shadow(
+        &mut self,
+        range: BusRange<M>,
+        device: Arc<dyn DeviceMmio + Send + Sync>,
+    ) -> Result<(), Error> {
+        self.mmio_bus
+            .register_shadow(range, device)
+            .map_err(Error::Bus)
+    }
+
+    /// Register a PIO translating window: accesses to `range` reach `device` as if the range
+    /// started at `base`.
+    pub fn register_pio_translated(
+        &mut self,
+        range: PioRange,
+        device: Arc<dyn DevicePio + Send + Sync>,
+        base: PioAddress,
+    ) -> Result<(), Error> {
+        self.pio_bus
+            .register_translated(range, device, base)
+            .map_err(Error::Bus)
+    }
+
+    /// Register a MMIO translating window: accesses to `range` reach `device` as if the range
+    /// started at `base`. The same device is usually registered at `base` as well, so the
+    /// window acts as an alias of its range.
+    pub fn register_mmio_translated(
+        &mut self,
+        range: BusRange<M>,
+        device: Arc<dyn DeviceMmio + Send + Sync>,
+        base: M,
+    ) -> Result<(), Error> {
+        self.mmio_bus
+            .register_translated(range, device, base)
+            .map_err(Error::Bus)
+    }
+
+    /// Register a PIO device which only accepts the accesses satisfying `constraints`.
+    pub fn register_pio_with_constraints(
+        &mut self,
+        range: PioRange,
+        device: Arc<dyn DevicePio + Send + Sync>,
+        constraints: AccessConstraints,
+    ) -> Result<(), Error> {
+        self.pio_bus
+            .register_with_constraints(range, device, constraints)
+            .map_err(Error::Bus)
+    }
+
+    /// Register a MMIO device which only accepts the accesses satisfying `constraints`.
+    pub fn register_mmio_with_constraints(
+        &mut self,
+        range: BusRange<M>,
+        device: Arc<dyn DeviceMmio + Send + Sync>,
+        constraints: AccessConstraints,
+    ) -> Result<(), Error> {
+        self.mmio_bus
+            .register_with_constraints(range, device, constraints)
+            .map_err(Error::Bus)
+    }
+
+    /// Enable or disable the splitting of the PIO accesses which span several adjacent
+    /// ranges into one sub-access per range.
+    pub fn set_pio_access_splitting(&mut self, enabled: bool) {
+        self.pio_bus.set_split_accesses(enabled);
+    }
+
+    /// Enable or disable the splitting of the MMIO accesses which span several adjacent
+    /// ranges (e.g. the BARs of a device) into one sub-access per range.
+    pub fn set_mmio_access_splitting(&mut self, enabled: bool) {
+        self.mmio_bus.set_split_accesses(enabled);
+    }
+
+    /// Return a description of the ranges registered on all buses.
+    pub fn layout(&self) -> IoLayout<M> {
+        IoLayout {
+            pio: bus_layout(&self.pio_bus),
+            mmio: bus_layout(&self.mmio_bus),
+            mmio_overlays: self
+                .mmio_overlays
+                .iter()
+                .map(|(attrs, bus)| (*attrs, bus_layout(bus)))
+                .collect(),
+            mmio_segments: self
+                .mmio_segments
+                .iter()
+                .map(|(segment, bus)| (*segment, bus_layout(bus)))
+                .collect(),
+            sysreg: bus_layout(&self.sysreg_bus),
+            msr: bus_layout(&self.msr_bus),
+            pio_reserved: bus_reservations(&self.pio_bus),
+            mmio_reserved: bus_reservations(&self.mmio_bus),
+        }
+    }
+
+    /// Enable or disable the decoding of the range registered at `base`, on the PIO or MMIO
+    /// bus depending on the type of `base`. The registration is kept, but accesses to a
+    /// disabled range follow the policy of unclaimed accesses, which lets PCI devices
+    /// implement the memory and I/O space enable bits of their COMMAND register.
+    pub fn set_range_enabled<A: BusAddress>(&self, base: A, enabled: bool) -> Result<(), Error>
+    where
+        Self: BusManager<A>,
+    {
+        self.bus().set_enabled(base, enabled).map_err(Error::Bus)
+    }
+
+    /// Limit the PIO ranges to the ports of the guest, up to `limit` (e.g.
+    /// `MAX_X86_PIO_ADDRESS` for x86 guests). The registrations and BAR moves which go past
+    /// it fail with `bus::Error::InvalidRange`. `None` (the default) accepts every port.
+    pub fn set_pio_address_limit(&mut self, limit: Option<PioAddress>) {
+        self.pio_bus.set_address_limit(limit);
+    }
+
+    /// Return the highest port address the PIO ranges can cover, if limited.
+    pub fn pio_address_limit(&self) -> Option<PioAddress> {
+        self.pio_bus.address_limit()
+    }
+
+    /// Quarantine the PIO and MMIO ranges whose device fails `threshold` consecutive
+    /// accesses, or stop quarantining ranges when `threshold` is `None` (the default).
+    /// Failures are reported by the `try_*` methods of the device traits, which handle the
+    /// accesses dispatched with their context (e.g. from `IoExit::dispatch`), and by the
+    /// panics caught from the devices (see `set_catch_panics`). Quarantined
+    /// ranges are disabled, so the guest sees them as unclaimed, and a
+    /// `VmEvent::DeviceQuarantined` event is sent through the event sender of the manager.
+    pub fn set_quarantine_threshold(&mut self, threshold: Option<u32>) {
+        self.pio_bus.set_quarantine_threshold(threshold);
+        self.mmio_bus.set_quarantine_threshold(threshold);
+        for bus in self
+            .mmio_overlays
+            .values_mut()
+            .chain(self.mmio_segments.values_mut())
+        {
+            bus.set_quarantine_threshold(threshold);
+        }
+    }
+
+    /// Catch the panics of the PIO and MMIO devices while they handle accesses, instead of
+    /// letting them unwind into the exit loop of the VMM. The access then fails as if the
+    /// device returned an error (reads return all ones), the range of the device is
+    /// quarantined right away, and `VmEvent::DevicePanicked` is sent through the event
+    /// sender of the manager. Disabled by default, since the panics of the devices are
+    /// usually bugs worth crashing for. Unlike `poison::CatchUnwindDevice`, this covers
+    /// every registered device, including third-party models the VMM doesn't wrap.
+    pub fn set_catch_panics(&mut self, enabled: bool) {
+        self.pio_bus.set_catch_panics(enabled);
+        self.mmio_bus.set_catch_panics(enabled);
+        for bus in self
+            .mmio_overlays
+            .values_mut()
+            .chain(self.mmio_segments.values_mut())
+        {
+            bus.set_catch_panics(enabled);
+        }
+    }
+
+    /// Return whether the range registered at `base`, on the PIO or MMIO bus depending on
+    /// the type of `base`, is quarantined.
+    pub fn is_quarantined<A: BusAddress>(&self, base: A) -> bool
+    where
+        Self: BusManager<A>,
+    {
+        self.bus()
+            .device(base)
+            .is_some_and(|(range, _)| self.bus().is_quarantined(range))
+    }
+
+    /// Return the statistics of the ranges registered on the PIO or MMIO bus, depending on
+    /// `A`, in ascending address order.
+    pub fn range_stats<A: BusAddress>(&self) -> Vec<(BusRange<A>, RangeStats)>
+    where
+        Self: BusManager<A>,
+    {
+        self.bus().stats()
+    }
+
+    /// Lift the quarantine of the range registered at `base` (e.g. once its device was
+    /// reset), so the guest can access it again.
+    pub fn release_quarantine<A: BusAddress>(&self, base: A) -> Result<(), Error>
+    where
+        Self: BusManager<A>,
+    {
+        self.bus().release(base).map_err(Error::Bus)
+    }
+
+    /// Reserve the PIO `range` for the use described by `name` (e.g. a port decoded by the
+    /// chipset), so no device can be registered within it.
+    pub fn reserve_pio(&mut self, range: PioRange, name: &str) -> Result<(), Error> {
+        self.pio_bus.reserve(range, name).map_err(Error::Bus)
+    }
+
+    /// Reserve the MMIO `range` for the use described by `name` (e.g. the pages KVM uses for
+    /// the TSS, or ACPI NVS memory), so no device can be registered within it.
+    pub fn reserve_mmio(&mut self, range: BusRange<M>, name: &str) -> Result<(), Error> {
+        self.mmio_bus.reserve(range, name).map_err(Error::Bus)
+    }
+
+    /// Remove the PIO reservation containing `addr`.
+    pub fn unreserve_pio(&mut self, addr: PioAddress) -> Option<(PioRange, String)> {
+        self.pio_bus.unreserve(addr)
+    }
+
+    /// Remove the MMIO reservation containing `addr`.
+    pub fn unreserve_mmio(&mut self, addr: M) -> Option<(BusRange<M>, String)> {
+        self.mmio_bus.unreserve(addr)
+    }
+
+    /// Register a MMIO device on the overlay selected by `attrs`. The device takes priority
+    /// over the ones registered on the regular MMIO bus for accesses performed with the same
+    /// attributes (e.g. SMRAM shadowing the legacy VGA window while in SMM).
+    pub fn register_mmio_overlay(
+        &mut self,
+        attrs: AccessAttrs,
+        range: BusRange<M>,
+        device: Arc<dyn DeviceMmio + Send + Sync>,
+    ) -> Result<(), Error> {
+        let mmio_bus = &self.mmio_bus;
+        self.mmio_overlays
+            .entry(attrs)
+            .or_insert_with(|| quarantined_bus(mmio_bus))
+            .register(range, device)
+            .map_err(Error::Bus)
+    }
+
+    /// Deregister the device registered at `addr` on the overlay selected by `attrs`.
+    pub fn deregister_mmio_overlay(
+        &mut self,
+        attrs: AccessAttrs,
+        addr: M,
+    ) -> Option<(BusRange<M>, Arc<dyn DeviceMmio + Send + Sync>)> {
+        self.mmio_overlays
+            .get_mut(&attrs)
+            .and_then(|bus| bus.deregister(addr))
+    }
+
+    /// Register a MMIO device on `segment`. Each segment is an independent address space,
+    /// with its own overlap checks; the default segment is the regular MMIO bus.
+    pub fn register_mmio_segment(
+        &mut self,
+        segment: MmioSegment,
+        range: BusRange<M>,
+        device: Arc<dyn DeviceMmio + Send + Sync>,
+    ) -> Result<(), Error> {
+        self.segment_bus_mut(segment)
+            .register(range, device)
+            .map_err(Error::Bus)
+    }
+
+    /// Deregister the device registered at `addr` on `segment`.
+    pub fn deregister_mmio_segment(
+        &mut self,
+        segment: MmioSegment,
+        addr: M,
+    ) -> Option<(BusRange<M>, Arc<dyn DeviceMmio + Send + Sync>)> {
+        let range = self.segment_bus_mut(segment).deregister(addr);
+        // Drop the segments which no longer hold anything.
+        if self
+            .mmio_segments
+            .get(&segment)
+            .is_some_and(|bus| bus.is_empty())
+        {
+            self.mmio_segments.remove(&segment);
+        }
+        range
+    }
+
+    /// Return the segments holding MMIO devices, the default one included.
+    pub fn mmio_segments(&self) -> Vec<MmioSegment> {
+        std::iter::once(MmioSegment::DEFAULT)
+            .chain(self.mmio_segments.keys().copied())
+            .collect()
+    }
+
+    /// Dispatch a MMIO read to the device registered at `addr` on `segment`.
+    pub fn mmio_read_segment(
+        &self,
+        segment: MmioSegment,
+        addr: M,
+        access: IoAccess,
+        data: &mut [u8],
+    ) -> Result<(), bus::Error> {
+        let bus = self
+            .segment_bus(segment)
+            .ok_or(bus::Error::DeviceNotFound)?;
+        let access = IoAccess {
+            width: data.len(),
+            ..access
+        };
+        dispatch_read(bus, addr, Some(access), data, self.events.as_ref())
+    }
+
+    /// Dispatch a MMIO write to the device registered at `addr` on `segment`.
+    pub fn mmio_write_segment(
+        &self,
+        segment: MmioSegment,
+        addr: M,
+        access: IoAccess,
+        data: &[u8],
+    ) -> Result<(), bus::Error> {
+        let bus = self
+            .segment_bus(segment)
+            .ok_or(bus::Error::DeviceNotFound)?;
+        let access = IoAccess {
+            width: data.len(),
+            ..access
+        };
+        dispatch_write(bus, addr, Some(access), data, self.events.as_ref())
+    }
+
+    fn segment_bus(
+        &self,
+        segment: MmioSegment,
+    ) -> Option<&Bus<M, Arc<dyn DeviceMmio + Send + Sync>>> {
+        if segment == MmioSegment::DEFAULT {
+            Some(&self.mmio_bus)
+        } else {
+            self.mmio_segments.get(&segment)
+        }
+    }
+
+    fn segment_bus_mut(
+        &mut self,
+        segment: MmioSegment,
+    ) -> &mut Bus<M, Arc<dyn DeviceMmio + Send + Sync>> {
+        if segment == MmioSegment::DEFAULT {
+            &mut self.mmio_bus
+        } else {
+            let mmio_bus = &self.mmio_bus;
+            self.mmio_segments
+                .entry(segment)
+                .or_insert_with(|| quarantined_bus(mmio_bus))
+        }
+    }
+
+    /// Set the device which handles the PIO accesses not claimed by any registered range
+    /// (e.g. to log unknown accesses), replacing the previous one.
+    pub fn set_pio_fallback(&mut self, device: Arc<dyn DevicePio + Send + Sync>) {
+        self.pio_bus.set_fallback(Some(device));
+    }
+
+    /// Set the device which handles the MMIO accesses not claimed by any registered range
+    /// (e.g. a catch-all chipset device), replacing the previous one.
+    pub fn set_mmio_fallback(&mut self, device: Arc<dyn DeviceMmio + Send + Sync>) {
+        self.mmio_bus.set_fallback(Some(device));
+    }
+
+    /// Remove the PIO fallback device, and return it.
+    pub fn clear_pio_fallback(&mut self) -> Option<Arc<dyn DevicePio + Send + Sync>> {
+        self.pio_bus.set_fallback(None)
+    }
+
+    /// Remove the MMIO fallback device, and return it.
+    pub fn clear_mmio_fallback(&mut self) -> Option<Arc<dyn DeviceMmio + Send + Sync>> {
+        self.mmio_bus.set_fallback(None)
+    }
+
+    /// Install a watchpoint on the PIO `range`. `handler` runs before each access of `kind`
+    /// overlapping the range, and can complete it instead of the device.
+    pub fn add_pio_watchpoint(
+        &mut self,
+        range: PioRange,
+        kind: WatchKind,
+        handler: WatchHandler<PioAddress>,
+    ) -> WatchpointId {
+        self.pio_bus.add_watchpoint(range, kind, handler)
+    }
+
+    /// Install a watchpoint on the MMIO `range`. Accesses dispatched to an overlay don't hit
+    /// the watchpoint.
+    pub fn add_mmio_watchpoint(
+        &mut self,
+        range: BusRange<M>,
+        kind: WatchKind,
+        handler: WatchHandler<M>,
+    ) -> WatchpointId {
+        self.mmio_bus.add_watchpoint(range, kind, handler)
+    }
+
+    /// Remove the PIO watchpoint `id`. Return `false` if it's not installed.
+    pub fn remove_pio_watchpoint(&mut self, id: WatchpointId) -> bool {
+        self.pio_bus.remove_watchpoint(id)
+    }
+
+    /// Remove the MMIO watchpoint `id`. Return `false` if it's not installed.
+    pub fn remove_mmio_watchpoint(&mut self, id: WatchpointId) -> bool {
+        self.mmio_bus.remove_watchpoint(id)
+    }
+
+    /// Install `observer` on the PIO `range`, which may overlap any registered range. The
+    /// observer sees the accesses overlapping the range once they completed, after the
+    /// observers with a lower `priority`.
+    pub fn add_pio_observer(
+        &mut self,
+        range: PioRange,
+        priority: u32,
+        observer: Arc<dyn BusObserver<PioAddress>>,
+    ) -> ObserverId {
+        self.pio_bus.add_observer(range, priority, observer)
+    }
+
+    /// Install `observer` on the MMIO `range`. Accesses dispatched to an overlay aren't
+    /// observed.
+    pub fn add_mmio_observer(
+        &mut self,
+        range: BusRange<M>,
+        priority: u32,
+        observer: Arc<dyn BusObserver<M>>,
+    ) -> ObserverId {
+        self.mmio_bus.add_observer(range, priority, observer)
+    }
+
+    /// Remove the PIO observer `id`. Return `false` if it's not installed.
+    pub fn remove_pio_observer(&mut self, id: ObserverId) -> bool {
+        self.pio_bus.remove_observer(id)
+    }
+
+    /// Remove the MMIO observer `id`. Return `false` if it's not installed.
+    pub fn remove_mmio_observer(&mut self, id: ObserverId) -> bool {
+        self.mmio_bus.remove_observer(id)
+    }
+
+    /// Read `len` bytes at `addr`, and pass them to `f`. Devices which implement
+    /// `DeviceMmioZeroCopy` lend their contents directly, while the others are read into a
+    /// temporary buffer. The access goes through the same checks, statistics and observers
+    /// as `mmio_read`, and the device can't be deregistered while `f` runs.
+    pub fn mmio_read_ref<R, F: FnOnce(&[u8]) -> R>(
+        &self,
+        addr: M,
+        len: usize,
+        f: F,
+    ) -> Result<R, bus::Error> {
+        // Small accesses which can't be lent are read on the stack.
+        let mut stack = [0u8; 64];
+        let mut heap = Vec::new();
+        let buf = if len <= stack.len() {
+            &mut stack[..len]
+        } else {
+            heap.resize(len, 0);
+            &mut heap[..]
+        };
+        let events = self.events.as_ref();
+        // Watchpoint handlers may replace the data, so they need a buffer. The accesses which
+        // don't fit in a single range go through the regular dispatch as well.
+        let access = match self.mmio_bus.has_watchpoints() {
+            false => self.mmio_bus.access(addr, len).ok(),
+            true => None,
+        };
+        let access = match access {
+            Some(access) => access,
+            None => {
+                dispatch_read(&self.mmio_bus, addr, None, buf, events)?;
+                return Ok(f(buf));
+            }
+        };
+        let mut lent = None;
+        let read = || {
+            let base = access.base();
+            let offset = MmioOffset(M::offset_to_u64(access.offset(addr)));
+            lent = access
+                .as_zero_copy()
+                .and_then(|dev| dev.mmio_slice(base.to_mmio_address(), offset, len))
+                .filter(|slice| slice.len() == len);
+            if lent.is_none() {
+                for (addr, span) in access.chunks(addr, len) {
+                    access.read_at(None, base, access.offset(addr), &mut buf[span])?;
+                }
+            }
+            Ok(())
+        };
+        if dispatch_chunk(&access, events, read) {
+            lent = None;
+            poison::fail_read(buf);
+        }
+        let data = lent.unwrap_or(buf);
+        self.mmio_bus.observe_read(addr, data);
+        Ok(f(data))
+    }
+
+    /// Set the channel used by the devices to send control plane events to the VMM.
+    pub fn set_event_sender(&mut self, sender: VmEventSender) {
+        self.events = Some(sender);
+    }
+
+    /// Return a sender labelled with `device`, to be handed to the device as it's created
+    /// and registered, if an event channel has been set.
+    pub fn event_sender(&self, device: &str) -> Option<VmEventSender> {
+        self.events.as_ref().map(|sender| sender.for_device(device))
+    }
+
+    /// Set the router of the legacy interrupt lines.
+    pub fn set_irq_router(&mut self, router: IrqRouter) {
+        self.irq_router = Some(router);
+    }
+
+    /// Return the router of the legacy interrupt lines, if set.
+    pub fn irq_router(&self) -> Option<&IrqRouter> {
+        self.irq_router.as_ref()
+    }
+
+    /// Register the post-restore hook of the device `id`.
+    pub fn register_post_restore(&mut self, id: &str, hook: Arc<dyn PostRestore + Send + Sync>) {
+        self.post_restore.push((id.to_string(), hook));
+    }
+
+    /// Run the post-restore hooks, in registration order, once all the devices are restored
+    /// and registered again. `resources` holds the restored resources of the devices, keyed
+    /// by ID. Stops at the first hook which fails.
+    pub fn complete_restore(&self, resources: &[(String, Vec<Resource>)]) -> Result<(), Error> {
+        for (id, hook) in self.post_restore.iter() {
+            hook.post_restore(&RestoreContext::new(id, resources))
+                .map_err(|e| Error::PostRestore(id.clone(), e))?;
+        }
+        Ok(())
+    }
+
+    /// Return the handle of the `Resource::LegacyIrq` line from `resources`, to be handed to
+    /// the device as it's created.
+    pub fn line_interrupt(
+        &self,
+        resources: &[Resource],
+        trigger: TriggerMode,
+    ) -> Result<LineInterrupt, interrupt::Error> {
+        let router = self.irq_router.as_ref().ok_or(interrupt::Error::NoRouter)?;
+        let irq = resources
+            .iter()
+            .find_map(|res| match res {
+                Resource::LegacyIrq(irq) => Some(*irq),
+                _ => None,
+            })
+            .ok_or(interrupt::Error::NoLegacyIrq)?;
+        router.line(irq, trigger)
+    }
+
+    /// Track the pages written in the device-backed memory region at `range`, as marked by
+    /// the device in `bitmap`.
+    pub fn track_dirty(
+        &mut self,
+        range: BusRange<M>,
+        bitmap: Arc<DirtyBitmap>,
+    ) -> Result<(), bus::Error> {
+        self.dirty_regions.register(range, bitmap)
+    }
+
+    /// Stop tracking the dirty pages of the region which contains `addr`.
+    pub fn untrack_dirty(&mut self, addr: M) -> Option<(BusRange<M>, Arc<DirtyBitmap>)> {
+        self.dirty_regions.deregister(addr)
+    }
+
+    /// Return the regions written since the last call, together with the indices of their
+    /// dirty pages, and mark them clean.
+    pub fn take_dirty(&self) -> Vec<(BusRange<M>, Vec<u64>)> {
+        self.dirty_regions
+            .iter()
+            .map(|(range, bitmap)| (*range, bitmap.take_dirty()))
+            .filter(|(_, pages)| !pages.is_empty())
+            .collect()
+    }
+
+    // Return the bus which handles an access at `addr` performed with `attrs`.
+    fn mmio_view(&self, addr: M, attrs: AccessAttrs) -> &Bus<M, Arc<dyn DeviceMmio + Send + Sync>> {
+        self.mmio_overlays
+            .get(&attrs)
+            .filter(|bus| bus.device(addr).is_some())
+            .unwrap_or(&self.mmio_bus)
+    }
+
+    /// Dispatch a read operation performed with the specified attributes. The access is
+    /// handled by the overlay selected by `attrs` when it has a device registered at `addr`,
+    /// and by the regular MMIO bus otherwise.
+    pub fn mmio_read_with_attrs(
+        &self,
+        addr: M,
+        attrs: AccessAttrs,
+        data: &mut [u8],
+    ) -> Result<(), bus::Error> {
+        let access = IoAccess {
+            width: data.len(),
+            secure: attrs.secure,
+            ..Default::default()
+        };
+        dispatch_read(
+            self.mmio_view(addr, attrs),
+            addr,
+            Some(access),
+            data,
+            self.events.as_ref(),
+        )
+    }
+
+    /// Dispatch a write operation performed with the specified attributes. The access is
+    /// handled by the overlay selected by `attrs` when it has a device registered at `addr`,
+    /// and by the regular MMIO bus otherwise.
+    pub fn mmio_write_with_attrs(
+        &self,
+        addr: M,
+        attrs: AccessAttrs,
+        data: &[u8],
+    ) -> Result<(), bus::Error> {
+        let access = IoAccess {
+            width: data.len(),
+            secure: attrs.secure,
+            ..Default::default()
+        };
+        dispatch_write(
+            self.mmio_view(addr, attrs),
+            addr,
+            Some(access),
+            data,
+            self.events.as_ref(),
+        )
+    }
+}
+
+impl IoManager {
+    // Return the ID of the device registered with `existing` among its resources.
+    fn range_owner(&self, existing: &Resource) -> Option<String> {
+        self.configs
+            .iter()
+            .map(|(id, (_, resources))| (id, resources))
+            .chain(self.composites.iter())
+            .find(|(_, resources)| resources.contains(existing))
+            .map(|(id, _)| id.clone())
+    }
+
+    /// Return the conflicts between the PIO and MMIO ranges of `resources` and the ranges
+    /// already registered or reserved, so a resource set can be validated before it's
+    /// registered, and every conflict reported at once. The owner of a registered range is
+    /// only known for the devices registered with an ID (e.g. with `register_configured`).
+    pub fn find_conflicts(&self, resources: &[Resource]) -> Vec<Conflict> {
+        let mut conflicts = Vec::new();
+        for res in resources.iter() {
+            let (overlapping, pio) = match *res {
+                Resource::PioAddressRange { base, size } => {
+                    let last = u64::from(base) + u64::from(size.saturating_sub(1));
+                    (
+                        overlapping_ranges(&self.pio_bus, u64::from(base), last),
+                        true,
+                    )
+                }
+                Resource::MmioAddressRange { base, size } => {
+                    let last = base.saturating_add(size.saturating_sub(1));
+                    (overlapping_ranges(&self.mmio_bus, base, last), false)
+                }
+                _ => continue,
+            };
+            for (base, last, reservation) in overlapping {
+                let size = last - base + 1;
+                let existing = if pio {
+                    Resource::PioAddressRange {
+                        base: base as PioAddressValue,
+                        size: size as PioAddressValue,
+                    }
+                } else {
+                    Resource::MmioAddressRange { base, size }
+                };
+                conflicts.push(Conflict {
+                    resource: res.clone(),
+                    reserved: reservation.is_some(),
+                    owner: reservation.or_else(|| self.range_owner(&existing)),
+                    existing,
+                });
+            }
+        }
+        conflicts
+    }
+
+    // Turn the `error` of the registration of `res` into a conflict, if the resource
+    // overlaps an existing range.
+    fn conflict_error(&self, res: &Resource, error: bus::Error) -> Error {
+        match error {
+            bus::Error::DeviceOverlap | bus::Error::RangeReserved => self
+                .find_conflicts(std::slice::from_ref(res))
+                .into_iter()
+                .next()
+                .map_or(Error::Bus(error), Error::Conflict),
+            _ => Error::Bus(error),
+        }
+    }
+
+    /// Create an default IoManager with empty IO member.
+    pub fn new() -> Self {
+        IoManager::default()
+    }
+
+    /// Register a new MMIO device with its allocated resources.
+    /// VMM is responsible for providing the allocated resources to virtual device.
+    ///
+    /// # Arguments
+    ///
+    /// * `device`: device instance object to be registered
+    /// * `resources`: resources that this device owns, might include
+    ///   port I/O and memory-mapped I/O ranges, irq number, etc.
+    pub fn register_mmio_resources(
+        &mut self,
+        device: Arc<dyn DeviceMmio + Send + Sync>,
+        resources: &[Resource],
+    ) -> Result<(), Error> {
+        // Register and mark device resources
+        // The resources addresses being registered are sucessfully allocated before.
+        for res in resources.iter() {
+            match *res {
+                Resource::MmioAddressRange { base, size } => {
+                    self.register_mmio(
                         MmioRange::new(MmioAddress(base), size).unwrap(),
                         device.clone(),
                     )
-                    .map_err(Error::Bus)?;
+                    .map_err(|e| self.conflict_error(res, e))?;
                 }
                 _ => continue,
             }
@@ -234,7 +1978,7 @@ impl IoManager {
     ///
     /// * `device`: device instance object to be registered
     /// * `resources`: resources that this device owns, might include
-    ///                port I/O and memory-mapped I/O ranges, irq number, etc.
+    ///   port I/O and memory-mapped I/O ranges, irq number, etc.
     pub fn register_pio_resources(
         &mut self,
         device: Arc<dyn DevicePio + Send + Sync>,
@@ -249,7 +1993,7 @@ impl IoManager {
                         PioRange::new(PioAddress(base), size).unwrap(),
                         device.clone(),
                     )
-                    .map_err(Error::Bus)?;
+                    .map_err(|e| self.conflict_error(res, e))?;
                 }
                 _ => continue,
             }
@@ -264,14 +2008,55 @@ impl IoManager {
     ///
     /// * `device`: device instance object to be registered
     /// * `resources`: resources that this device owns, might include
-    ///                port I/O and memory-mapped I/O ranges, irq number, etc.
+    ///   port I/O and memory-mapped I/O ranges, irq number, etc.
     pub fn register_resources<T: DeviceMmio + DevicePio + 'static + Send + Sync>(
         &mut self,
         device: Arc<T>,
         resources: &[Resource],
     ) -> Result<(), Error> {
         self.register_mmio_resources(device.clone(), resources)?;
-        self.register_pio_resources(device, resources)
+        self.register_pio_resources(device.clone(), resources)?;
+        self.remember_type(&device);
+        Ok(())
+    }
+
+    /// Register a new MMIO + PIO device with its allocated resources, like
+    /// `register_resources`, and attach `config` to it under `id`.
+    pub fn register_configured<T: DeviceMmio + DevicePio + 'static + Send + Sync>(
+        &mut self,
+        id: &str,
+        device: Arc<T>,
+        resources: &[Resource],
+        config: DeviceConfig,
+    ) -> Result<(), Error> {
+        if self.configs.contains_key(id) {
+            return Err(Error::DuplicateId(id.to_string()));
+        }
+        self.register_resources(device, resources)?;
+        self.configs
+            .insert(id.to_string(), (config, resources.to_vec()));
+        Ok(())
+    }
+
+    /// Deregister the device registered with `register_configured` under `id`, and return
+    /// its configuration.
+    pub fn deregister_configured(&mut self, id: &str) -> Option<DeviceConfig> {
+        let (config, resources) = self.configs.remove(id)?;
+        self.deregister_resources(&resources);
+        Some(config)
+    }
+
+    /// Return the configuration attached to the device `id`.
+    pub fn device_config(&self, id: &str) -> Option<&DeviceConfig> {
+        self.configs.get(id).map(|(config, _)| config)
+    }
+
+    /// Return the devices registered with a configuration, ordered by ID, together with
+    /// their configuration and resources.
+    pub fn configured_devices(&self) -> impl Iterator<Item = (&str, &DeviceConfig, &[Resource])> {
+        self.configs
+            .iter()
+            .map(|(id, (config, resources))| (id.as_str(), config, resources.as_slice()))
     }
 
     /// Deregister a device from `IoManager`, e.g. users specified removing.
@@ -282,7 +2067,7 @@ impl IoManager {
     /// # Arguments
     ///
     /// * `resources`: resources that this device owns, might include
-    ///                port I/O and memory-mapped I/O ranges, irq number, etc.
+    ///   port I/O and memory-mapped I/O ranges, irq number, etc.
     pub fn deregister_resources(&mut self, resources: &[Resource]) -> usize {
         let mut count = 0;
         for res in resources.iter() {
@@ -302,20 +2087,145 @@ impl IoManager {
         }
         count
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    use std::error::Error;
-    use std::sync::Mutex;
 
-    use bus::PioAddressValue;
-
-    const PIO_ADDRESS_SIZE: u16 = 4;
-    const PIO_ADDRESS_BASE: u16 = 0x40;
-    const MMIO_ADDRESS_SIZE: u64 = 0x8765_4321;
+    /// Register a hotplugged device with its allocated resources, and notify the guest
+    /// about its presence.
+    ///
+    /// # Arguments
+    ///
+    /// * `device`: device instance object to be registered
+    /// * `resources`: resources that this device owns
+    /// * `slot`: slot the device is plugged into, as seen by the guest
+    /// * `notifier`: mechanism used to notify the guest
+    pub fn hotplug_device<T: DeviceMmio + DevicePio + 'static + Send + Sync>(
+        &mut self,
+        device: Arc<T>,
+        resources: &[Resource],
+        slot: u32,
+        notifier: &dyn HotplugNotifier,
+    ) -> Result<(), Error> {
+        if self.pending_unplug.contains_key(&slot) {
+            return Err(Error::Hotplug(hotplug::Error::UnplugPending(slot)));
+        }
+
+        // Only roll back the ranges registered here, as the ones that failed to register
+        // might overlap the ranges of other devices.
+        let mut registered = Vec::new();
+        for res in resources.iter() {
+            let result = match *res {
+                Resource::MmioAddressRange { base, size } => {
+                    MmioRange::new(MmioAddress(base), size)
+                        .and_then(|range| self.register_mmio(range, device.clone()))
+                }
+                Resource::PioAddressRange { base, size } => PioRange::new(PioAddress(base), size)
+                    .and_then(|range| self.register_pio(range, device.clone())),
+                _ => continue,
+            };
+            if let Err(e) = result {
+                self.deregister_resources(&registered);
+                return Err(Error::Bus(e));
+            }
+            registered.push(res.clone());
+        }
+
+        notifier.notify_add(slot).map_err(|e| {
+            self.deregister_resources(resources);
+            Error::Hotplug(e)
+        })
+    }
+
+    /// Ask the guest to release the device plugged into `slot`. The resources remain
+    /// registered until the guest ejects the slot, and `process_ejects` is called.
+    ///
+    /// # Arguments
+    ///
+    /// * `slot`: slot the device is plugged into, as seen by the guest
+    /// * `resources`: resources that the device owns
+    /// * `notifier`: mechanism used to notify the guest
+    pub fn unplug_device(
+        &mut self,
+        slot: u32,
+        resources: &[Resource],
+        notifier: &dyn HotplugNotifier,
+    ) -> Result<(), Error> {
+        if self.pending_unplug.contains_key(&slot) {
+            return Err(Error::Hotplug(hotplug::Error::UnplugPending(slot)));
+        }
+
+        notifier.notify_remove(slot).map_err(Error::Hotplug)?;
+        self.pending_unplug.insert(slot, resources.to_vec());
+        Ok(())
+    }
+
+    /// Unregister the resources of the devices that have been ejected by the guest after an
+    /// `unplug_device` request. Returns the slots which have been released.
+    pub fn process_ejects(&mut self, notifier: &dyn HotplugNotifier) -> Vec<u32> {
+        let mut released = Vec::new();
+        for slot in notifier.take_ejected() {
+            if let Some(resources) = self.pending_unplug.remove(&slot) {
+                self.deregister_resources(&resources);
+                released.push(slot);
+            }
+        }
+        released
+    }
+
+    /// Move the registrations affected by guest BAR reprogramming (as reported by
+    /// `PciBus::take_bar_reprogramming`, or queued by a `PciBus` connected to the manager
+    /// with `PciBus::set_io_manager`) to their new addresses. The range registered at the old
+    /// base of each BAR moves to the new base, keeping its size and the state of its
+    /// registration. The moves are applied in order, and either all of them succeed, or
+    /// none is applied and the error of the first one which failed is returned.
+    ///
+    /// # Arguments
+    ///
+    /// * `params`: the BAR moves performed by the guest, in the order they happened.
+    pub fn relocate_bars(&mut self, params: &[BarReprogrammingParams]) -> Result<(), Error> {
+        // The moves are applied to copies of the buses, which share the maps with the
+        // current ones until they change, and replace them once every move succeeded.
+        let mut pio_bus = self.pio_bus.duplicate(true);
+        let mut mmio_bus = self.mmio_bus.duplicate(true);
+        let mut mmio_moves = Vec::new();
+        for p in params.iter() {
+            match p.region_type {
+                PciBarRegionType::IoRegion => {
+                    let port = |addr| {
+                        PioAddressValue::try_from(addr)
+                            .map(PioAddress)
+                            .map_err(|_| Error::Bus(bus::Error::InvalidRange))
+                    };
+                    let (old_base, new_base) = (port(p.old_base)?, port(p.new_base)?);
+                    pio_bus.relocate(old_base, new_base).map_err(Error::Bus)?;
+                }
+                PciBarRegionType::Memory32BitRegion | PciBarRegionType::Memory64BitRegion => {
+                    let moved = mmio_bus
+                        .relocate(MmioAddress(p.old_base), MmioAddress(p.new_base))
+                        .map_err(Error::Bus)?;
+                    mmio_moves.push(moved);
+                }
+            }
+        }
+        self.pio_bus = pio_bus;
+        self.mmio_bus = mmio_bus;
+        self.move_mappable(&mmio_moves);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::error::Error;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Mutex;
+
+    use crate::{DeviceMmioZeroCopy, MutDevicePio};
+    use bus::MAX_X86_PIO_ADDRESS;
+
+    const PIO_ADDRESS_SIZE: PioAddressValue = 4;
+    const PIO_ADDRESS_BASE: PioAddressValue = 0x40;
+    const MMIO_ADDRESS_SIZE: u64 = 0x8765_4321;
     const MMIO_ADDRESS_BASE: u64 = 0x1234_5678;
     const LEGACY_IRQ: u32 = 4;
     const CONFIG_DATA: u32 = 0x1234;
@@ -333,7 +2243,7 @@ mod tests {
     }
 
     impl DevicePio for DummyDevice {
-        fn pio_read(&self, _base: PioAddress, _offset: PioAddressValue, data: &mut [u8]) {
+        fn pio_read(&self, _base: PioAddress, _offset: PioOffset, data: &mut [u8]) {
             if data.len() > 4 {
                 return;
             }
@@ -343,14 +2253,14 @@ mod tests {
             }
         }
 
-        fn pio_write(&self, _base: PioAddress, _offset: PioAddressValue, data: &[u8]) {
+        fn pio_write(&self, _base: PioAddress, _offset: PioOffset, data: &[u8]) {
             let mut config = self.config.lock().expect("failed to acquire lock");
             *config = u32::from(data[0]) & 0xff;
         }
     }
 
     impl DeviceMmio for DummyDevice {
-        fn mmio_read(&self, _base: MmioAddress, _offset: u64, data: &mut [u8]) {
+        fn mmio_read(&self, _base: MmioAddress, _offset: MmioOffset, data: &mut [u8]) {
             if data.len() > 4 {
                 return;
             }
@@ -360,7 +2270,7 @@ mod tests {
             }
         }
 
-        fn mmio_write(&self, _base: MmioAddress, _offset: u64, data: &[u8]) {
+        fn mmio_write(&self, _base: MmioAddress, _offset: MmioOffset, data: &[u8]) {
             let mut config = self.config.lock().expect("failed to acquire lock");
             *config = u32::from(data[0]) & 0xff;
         }
@@ -463,6 +2373,1646 @@ mod tests {
             .is_err());
     }
 
+    #[test]
+    fn test_pio_address_limit() {
+        let mut io_mgr = IoManager::new();
+        let dum = Arc::new(DummyDevice::new(CONFIG_DATA));
+        let wide = PioRange::new(PioAddress(0x1_0000), PIO_ADDRESS_SIZE).unwrap();
+        let straddling = PioRange::new(PioAddress(0xfffe), PIO_ADDRESS_SIZE).unwrap();
+        let last = PioRange::new(PioAddress(0xfffc), PIO_ADDRESS_SIZE).unwrap();
+
+        // Every port can be registered by default, whatever the host.
+        assert_eq!(io_mgr.pio_address_limit(), None);
+        io_mgr.register_pio(wide, dum.clone()).unwrap();
+        assert!(io_mgr.pio_read(wide.base(), &mut [0; 4]).is_ok());
+        io_mgr.deregister_pio(wide.base()).unwrap();
+
+        io_mgr.set_pio_address_limit(Some(PioAddress(MAX_X86_PIO_ADDRESS)));
+        assert_eq!(
+            io_mgr.register_pio(wide, dum.clone()),
+            Err(bus::Error::InvalidRange)
+        );
+        assert_eq!(
+            io_mgr.register_pio(straddling, dum.clone()),
+            Err(bus::Error::InvalidRange)
+        );
+        io_mgr.register_pio(last, dum.clone()).unwrap();
+
+        // BARs can't be moved past the limit either.
+        let wide_move = BarReprogrammingParams {
+            old_base: u64::from(last.base().0),
+            new_base: u64::from(wide.base().0),
+            len: u64::from(PIO_ADDRESS_SIZE),
+            region_type: PciBarRegionType::IoRegion,
+        };
+        assert!(matches!(
+            io_mgr.relocate_bars(&[wide_move]),
+            Err(super::Error::Bus(bus::Error::InvalidRange))
+        ));
+        assert!(io_mgr.pio_device(last.base()).is_some());
+
+        assert_eq!(PioAddress::new(0x1_0000).unwrap().0, 0x1_0000);
+        assert!(PioAddress::new(u64::MAX).is_none());
+    }
+
+    #[test]
+    fn test_device_configs() {
+        use std::path::PathBuf;
+
+        use crate::resources::ConfigValue;
+
+        let mut io_mgr = IoManager::new();
+        let resources = [Resource::PioAddressRange {
+            base: PIO_ADDRESS_BASE,
+            size: PIO_ADDRESS_SIZE,
+        }];
+        let config = DeviceConfig::new("serial")
+            .with_entry("backend", PathBuf::from("/tmp/console.sock"))
+            .with_entry("baud", 115_200u64);
+        io_mgr
+            .register_configured(
+                "com1",
+                Arc::new(DummyDevice::new(CONFIG_DATA)),
+                &resources,
+                config.clone(),
+            )
+            .unwrap();
+        assert!(matches!(
+            io_mgr.register_configured(
+                "com1",
+                Arc::new(DummyDevice::new(CONFIG_DATA)),
+                &[],
+                DeviceConfig::new("serial"),
+            ),
+            Err(super::Error::DuplicateId(_))
+        ));
+
+        let devices: Vec<_> = io_mgr.configured_devices().collect();
+        assert_eq!(devices, vec![("com1", &config, &resources[..])]);
+        let config = io_mgr.device_config("com1").unwrap();
+        assert_eq!(config.kind(), "serial");
+        assert_eq!(config.get("baud"), Some(&ConfigValue::UInt(115_200)));
+        assert_eq!(
+            config.entries().map(|(key, _)| key).collect::<Vec<_>>(),
+            vec!["backend", "baud"]
+        );
+
+        assert!(io_mgr.deregister_configured("com1").is_some());
+        assert!(io_mgr.pio_device(PioAddress(PIO_ADDRESS_BASE)).is_none());
+        assert!(io_mgr.device_config("com1").is_none());
+    }
+
+    #[test]
+    fn test_find_conflicts() {
+        let mut io_mgr = IoManager::new();
+        let serial = [Resource::PioAddressRange {
+            base: 0x3f8,
+            size: 8,
+        }];
+        io_mgr
+            .register_configured(
+                "com1",
+                Arc::new(DummyDevice::new(CONFIG_DATA)),
+                &serial,
+                DeviceConfig::new("serial"),
+            )
+            .unwrap();
+        io_mgr
+            .reserve_mmio(
+                MmioRange::new(MmioAddress(0xfffb_d000), 0x3000).unwrap(),
+                "kvm-tss",
+            )
+            .unwrap();
+
+        let resources = [
+            Resource::PioAddressRange {
+                base: 0x3f0,
+                size: 0x10,
+            },
+            Resource::MmioAddressRange {
+                base: 0xfffb_c000,
+                size: 0x2000,
+            },
+            Resource::LegacyIrq(4),
+        ];
+        let conflicts = io_mgr.find_conflicts(&resources);
+        assert_eq!(
+            conflicts,
+            vec![
+                Conflict {
+                    resource: resources[0].clone(),
+                    existing: serial[0].clone(),
+                    owner: Some("com1".to_string()),
+                    reserved: false,
+                },
+                Conflict {
+                    resource: resources[1].clone(),
+                    existing: Resource::MmioAddressRange {
+                        base: 0xfffb_d000,
+                        size: 0x3000,
+                    },
+                    owner: Some("kvm-tss".to_string()),
+                    reserved: true,
+                },
+            ]
+        );
+        assert_eq!(
+            conflicts[0].to_string(),
+            "PIO range 0x3f0-0x3ff overlaps registered PIO range 0x3f8-0x3ff (com1)"
+        );
+
+        let err = io_mgr
+            .register_resources(Arc::new(DummyDevice::new(CONFIG_DATA)), &resources[..1])
+            .unwrap_err();
+        assert!(matches!(err, super::Error::Conflict(ref c) if c == &conflicts[0]));
+        assert!(io_mgr
+            .find_conflicts(&[Resource::PioAddressRange {
+                base: 0x2f8,
+                size: 8
+            }])
+            .is_empty());
+
+        // Resources coming from the user can wrap around the address space.
+        let conflict = Conflict {
+            resource: Resource::MmioAddressRange {
+                base: u64::MAX - 0xf,
+                size: 0x100,
+            },
+            existing: Resource::PioAddressRange {
+                base: PioAddressValue::MAX - 0xf,
+                size: 0x20,
+            },
+            owner: None,
+            reserved: false,
+        };
+        assert_eq!(
+            conflict.to_string(),
+            "MMIO range 0xfffffffffffffff0-0xffffffffffffffff overlaps registered PIO range \
+             0xfffffff0-0xffffffff"
+        );
+    }
+
+    #[test]
+    fn test_relocate_bars() {
+        let mut io_mgr = IoManager::new();
+        let dum = Arc::new(DummyDevice::new(CONFIG_DATA));
+        let resources = [
+            Resource::MmioAddressRange {
+                base: MMIO_ADDRESS_BASE,
+                size: 0x1000,
+            },
+            Resource::PioAddressRange {
+                base: PIO_ADDRESS_BASE,
+                size: PIO_ADDRESS_SIZE,
+            },
+        ];
+        io_mgr.register_resources(dum, &resources).unwrap();
+
+        let mmio_move = BarReprogrammingParams {
+            old_base: MMIO_ADDRESS_BASE,
+            new_base: MMIO_ADDRESS_BASE + 0x1_0000,
+            len: 0x1000,
+            region_type: PciBarRegionType::Memory64BitRegion,
+        };
+        let pio_move = BarReprogrammingParams {
+            old_base: u64::from(PIO_ADDRESS_BASE),
+            new_base: u64::from(PIO_ADDRESS_BASE + 0x100),
+            len: u64::from(PIO_ADDRESS_SIZE),
+            region_type: PciBarRegionType::IoRegion,
+        };
+        io_mgr.relocate_bars(&[mmio_move, pio_move]).unwrap();
+
+        let mut data = [0; 4];
+        assert!(io_mgr
+            .mmio_read(MmioAddress(MMIO_ADDRESS_BASE), &mut data)
+            .is_err());
+        assert!(io_mgr
+            .mmio_read(MmioAddress(mmio_move.new_base), &mut data)
+            .is_ok());
+        assert_eq!(data, [0x34, 0x12, 0, 0]);
+        assert!(io_mgr
+            .pio_read(PioAddress(PIO_ADDRESS_BASE), &mut data)
+            .is_err());
+        assert!(io_mgr
+            .pio_read(PioAddress(PIO_ADDRESS_BASE + 0x100), &mut data)
+            .is_ok());
+
+        // Nothing is registered at the old base anymore.
+        assert!(io_mgr.relocate_bars(&[mmio_move]).is_err());
+
+        // A move that overlaps another registration keeps the original mapping.
+        let other = Arc::new(DummyDevice::new(0));
+        io_mgr
+            .register_mmio(MmioRange::new(MmioAddress(0x1000), 0x1000).unwrap(), other)
+            .unwrap();
+        let bad_move = BarReprogrammingParams {
+            old_base: mmio_move.new_base,
+            new_base: 0x1000,
+            len: 0x1000,
+            region_type: PciBarRegionType::Memory32BitRegion,
+        };
+        assert!(io_mgr.relocate_bars(&[bad_move]).is_err());
+        assert!(io_mgr
+            .mmio_device(MmioAddress(mmio_move.new_base))
+            .is_some());
+
+        // The range keeps the state of its registration.
+        io_mgr
+            .set_range_enabled(MmioAddress(mmio_move.new_base), false)
+            .unwrap();
+        let back = BarReprogrammingParams {
+            old_base: mmio_move.new_base,
+            new_base: MMIO_ADDRESS_BASE,
+            len: 0x1000,
+            region_type: PciBarRegionType::Memory64BitRegion,
+        };
+        io_mgr.relocate_bars(&[back]).unwrap();
+        let layout = io_mgr.layout();
+        assert_eq!(layout.mmio[1].range.base(), MmioAddress(MMIO_ADDRESS_BASE));
+        assert!(!layout.mmio[1].enabled);
+
+        // Bases beyond the PIO address space are rejected instead of truncated.
+        let wide_move = BarReprogrammingParams {
+            old_base: pio_move.new_base + 0x1_0000_0000,
+            new_base: pio_move.old_base,
+            ..pio_move
+        };
+        assert!(matches!(
+            io_mgr.relocate_bars(&[wide_move]),
+            Err(super::Error::Bus(bus::Error::InvalidRange))
+        ));
+        let wide_move = BarReprogrammingParams {
+            old_base: pio_move.new_base,
+            new_base: pio_move.old_base + 0x1_0000_0000,
+            ..pio_move
+        };
+        assert!(matches!(
+            io_mgr.relocate_bars(&[wide_move]),
+            Err(super::Error::Bus(bus::Error::InvalidRange))
+        ));
+        assert!(io_mgr
+            .pio_device(PioAddress(PIO_ADDRESS_BASE + 0x100))
+            .is_some());
+
+        // A batch with a failing move doesn't apply the moves before it.
+        let pio_back = BarReprogrammingParams {
+            old_base: pio_move.new_base,
+            new_base: pio_move.old_base,
+            ..pio_move
+        };
+        assert!(io_mgr.relocate_bars(&[pio_back, bad_move]).is_err());
+        assert!(io_mgr
+            .pio_device(PioAddress(PIO_ADDRESS_BASE + 0x100))
+            .is_some());
+        assert!(io_mgr.pio_device(PioAddress(PIO_ADDRESS_BASE)).is_none());
+    }
+
+    #[test]
+    fn test_hotplug() {
+        use crate::hotplug::{AcpiPciHotplug, ACPI_PCI_HOTPLUG_SLOTS};
+
+        let mut io_mgr = IoManager::new();
+        let notifier = AcpiPciHotplug::new(Box::new(|| {}));
+        let dum = Arc::new(DummyDevice::new(CONFIG_DATA));
+        let resources = [Resource::MmioAddressRange {
+            base: MMIO_ADDRESS_BASE,
+            size: MMIO_ADDRESS_SIZE,
+        }];
+        let mut data = [0; 4];
+
+        // A failed notification leaves the device unregistered.
+        assert!(io_mgr
+            .hotplug_device(dum.clone(), &resources, ACPI_PCI_HOTPLUG_SLOTS, &notifier)
+            .is_err());
+        assert!(io_mgr.mmio_device(MmioAddress(MMIO_ADDRESS_BASE)).is_none());
+
+        io_mgr
+            .hotplug_device(dum.clone(), &resources, 2, &notifier)
+            .unwrap();
+        assert!(io_mgr
+            .mmio_read(MmioAddress(MMIO_ADDRESS_BASE), &mut data)
+            .is_ok());
+
+        io_mgr.unplug_device(2, &resources, &notifier).unwrap();
+        assert!(io_mgr.unplug_device(2, &resources, &notifier).is_err());
+        assert!(io_mgr
+            .hotplug_device(dum, &resources, 2, &notifier)
+            .is_err());
+
+        // The device stays registered until the guest ejects the slot.
+        assert!(io_mgr.process_ejects(&notifier).is_empty());
+        assert!(io_mgr
+            .mmio_read(MmioAddress(MMIO_ADDRESS_BASE), &mut data)
+            .is_ok());
+
+        notifier.pio_write(PioAddress(0), PioOffset(8), &(1u32 << 2).to_le_bytes());
+        assert_eq!(io_mgr.process_ejects(&notifier), vec![2]);
+        assert!(io_mgr
+            .mmio_read(MmioAddress(MMIO_ADDRESS_BASE), &mut data)
+            .is_err());
+    }
+
+    #[test]
+    fn test_deferred_deregister() {
+        let mut io_mgr = IoManager::new();
+        let dum = Arc::new(DummyDevice::new(CONFIG_DATA));
+        let addr = MmioAddress(MMIO_ADDRESS_BASE);
+        let range = MmioRange::new(addr, MMIO_ADDRESS_SIZE).unwrap();
+        io_mgr.register_mmio(range, dum).unwrap();
+        io_mgr
+            .register_pio(
+                PioRange::new(PioAddress(PIO_ADDRESS_BASE), PIO_ADDRESS_SIZE).unwrap(),
+                Arc::new(DummyDevice::new(CONFIG_DATA)),
+            )
+            .unwrap();
+
+        let mut data = [0; 4];
+        assert!(io_mgr.complete_deregister_mmio(addr).is_err());
+        io_mgr.begin_deregister_mmio(addr).unwrap();
+        assert!(io_mgr.mmio_read(addr, &mut data).is_err());
+        assert!(io_mgr.mmio_drained(addr));
+        let (r, _) = io_mgr.complete_deregister_mmio(addr).unwrap();
+        assert_eq!(r, range);
+        assert!(io_mgr.mmio_device(addr).is_none());
+
+        let pio_addr = PioAddress(PIO_ADDRESS_BASE);
+        io_mgr.begin_deregister_pio(pio_addr).unwrap();
+        assert!(io_mgr.pio_write(pio_addr, &data).is_err());
+        assert!(io_mgr.pio_drained(pio_addr));
+        assert!(io_mgr.complete_deregister_pio(pio_addr).is_ok());
+    }
+
+    #[test]
+    fn test_io_manager_32() {
+        use crate::bus::Mmio32Range;
+
+        let mut io_mgr = IoManager32::default();
+        let dum = Arc::new(DummyDevice::new(CONFIG_DATA));
+        let base = Mmio32Address(0xfee0_0000);
+        io_mgr
+            .register_mmio(Mmio32Range::new(base, 0x1000).unwrap(), dum.clone())
+            .unwrap();
+        io_mgr
+            .register_pio(
+                PioRange::new(PioAddress(PIO_ADDRESS_BASE), PIO_ADDRESS_SIZE).unwrap(),
+                dum.clone(),
+            )
+            .unwrap();
+
+        let mut data = [0; 4];
+        io_mgr.mmio_read(base, &mut data).unwrap();
+        assert_eq!(data, [0x34, 0x12, 0, 0]);
+        assert!(io_mgr
+            .mmio_read(Mmio32Address(0xfee0_1000), &mut data)
+            .is_err());
+        // The end of the 32-bit address space cannot be crossed.
+        assert!(io_mgr
+            .mmio_read(Mmio32Address(u32::MAX - 1), &mut data)
+            .is_err());
+
+        io_mgr.mmio_write(base, &[0; 4]).unwrap();
+        assert_eq!(*dum.config.lock().unwrap(), 0);
+        io_mgr
+            .pio_read(PioAddress(PIO_ADDRESS_BASE), &mut data)
+            .unwrap();
+        assert!(io_mgr.deregister_mmio(base).is_some());
+    }
+
+    #[test]
+    fn test_msr_read_write() {
+        use crate::devices::lapic::{Lapic, X2APIC_MSR_BASE, X2APIC_MSR_COUNT};
+        use crate::time::ManualClock;
+
+        let mut io_mgr = IoManager::new();
+        let lapic = Arc::new(Mutex::new(Lapic::new(
+            3,
+            Arc::new(ManualClock::new()),
+            Box::new(|_| {}),
+        )));
+        lapic.lock().unwrap().set_x2apic(true);
+        let base = MsrAddress(X2APIC_MSR_BASE);
+        io_mgr
+            .register_msr(
+                MsrRange::new(base, X2APIC_MSR_COUNT).unwrap(),
+                lapic.clone(),
+            )
+            .unwrap();
+
+        // APIC ID register.
+        assert_eq!(io_mgr.msr_read(MsrAddress(0x802)).unwrap(), 3);
+        // TPR.
+        io_mgr.msr_write(MsrAddress(0x808), 0x20).unwrap();
+        assert_eq!(io_mgr.msr_read(MsrAddress(0x808)).unwrap(), 0x20);
+        assert_eq!(
+            io_mgr.msr_read(MsrAddress(0x6e0)),
+            Err(bus::Error::DeviceNotFound)
+        );
+        assert_eq!(io_mgr.layout().msr.len(), 1);
+        assert!(io_mgr.deregister_msr(base).is_some());
+    }
+
+    #[test]
+    fn test_sysreg_read_write() {
+        use crate::bus::SysRegAddressValue;
+
+        struct TimerRegs {
+            regs: Mutex<[u64; 4]>,
+        }
+
+        impl DeviceSysReg for TimerRegs {
+            fn sysreg_read(&self, _base: SysRegAddress, offset: SysRegAddressValue) -> u64 {
+                self.regs.lock().unwrap()[offset as usize]
+            }
+
+            fn sysreg_write(&self, _base: SysRegAddress, offset: SysRegAddressValue, value: u64) {
+                self.regs.lock().unwrap()[offset as usize] = value;
+            }
+        }
+
+        let mut io_mgr = IoManager::new();
+        let timer = Arc::new(TimerRegs {
+            regs: Mutex::new([0; 4]),
+        });
+        // CNTV_CTL_EL0 and CNTV_CVAL_EL0 are encoded as two consecutive values.
+        let base = SysRegAddress::aarch64(3, 3, 14, 3, 1);
+        io_mgr
+            .register_sysreg(SysRegRange::new(base, 2).unwrap(), timer.clone())
+            .unwrap();
+
+        io_mgr.sysreg_write(base, 1).unwrap();
+        io_mgr
+            .sysreg_write(SysRegAddress(base.0 + 1), 0x1234)
+            .unwrap();
+        assert_eq!(io_mgr.sysreg_read(base).unwrap(), 1);
+        assert_eq!(timer.regs.lock().unwrap()[1], 0x1234);
+        assert_eq!(
+            io_mgr.sysreg_read(SysRegAddress(base.0 + 2)),
+            Err(bus::Error::DeviceNotFound)
+        );
+
+        assert!(io_mgr.sysreg_device(base).is_some());
+        assert!(io_mgr.deregister_sysreg(base).is_some());
+        assert!(io_mgr.sysreg_write(base, 0).is_err());
+    }
+
+    // Banked per vCPU, and only writable by the secure world.
+    struct BankedDevice {
+        regs: Mutex<Vec<u8>>,
+    }
+
+    impl DeviceMmio for BankedDevice {
+        fn mmio_read(&self, _base: MmioAddress, _offset: MmioOffset, data: &mut [u8]) {
+            data.iter_mut().for_each(|byte| *byte = 0xff);
+        }
+
+        fn mmio_write(&self, _base: MmioAddress, _offset: MmioOffset, _data: &[u8]) {}
+
+        fn mmio_read_with(
+            &self,
+            access: IoAccess,
+            _base: MmioAddress,
+            _: MmioOffset,
+            data: &mut [u8],
+        ) {
+            let regs = self.regs.lock().unwrap();
+            data[0] = regs[access.vcpu_id.unwrap() as usize];
+        }
+
+        fn mmio_write_with(
+            &self,
+            access: IoAccess,
+            _base: MmioAddress,
+            _: MmioOffset,
+            data: &[u8],
+        ) {
+            if access.secure {
+                self.regs.lock().unwrap()[access.vcpu_id.unwrap() as usize] = data[0];
+            }
+        }
+    }
+
+    #[test]
+    fn test_io_access() {
+        let mut io_mgr = IoManager::new();
+        let device = Arc::new(BankedDevice {
+            regs: Mutex::new(vec![0; 2]),
+        });
+        let addr = MmioAddress(0xfee0_0000);
+        io_mgr
+            .register_mmio(MmioRange::new(addr, 0x1000).unwrap(), device)
+            .unwrap();
+
+        let mut secure = IoAccess::new(1, 1);
+        secure.secure = true;
+        io_mgr.mmio_write_with(addr, secure, &[0x12]).unwrap();
+        io_mgr
+            .mmio_write_with(addr, IoAccess::new(0, 1), &[0x34])
+            .unwrap();
+
+        let mut data = [0; 1];
+        io_mgr
+            .mmio_read_with(addr, IoAccess::new(0, 1), &mut data)
+            .unwrap();
+        assert_eq!(data, [0]);
+        io_mgr
+            .mmio_read_with(addr, IoAccess::new(1, 1), &mut data)
+            .unwrap();
+        assert_eq!(data, [0x12]);
+
+        // Accesses without context use the regular methods.
+        io_mgr.mmio_read(addr, &mut data).unwrap();
+        assert_eq!(data, [0xff]);
+
+        // Devices which don't care about the context see the regular accesses.
+        let pio_addr = PioAddress(0x60);
+        io_mgr
+            .register_pio(
+                PioRange::new(pio_addr, 1).unwrap(),
+                Arc::new(Mutex::new(DummyMutDevice(0x56))),
+            )
+            .unwrap();
+        io_mgr
+            .pio_read_with(pio_addr, IoAccess::new(0, 1), &mut data)
+            .unwrap();
+        assert_eq!(data, [0x56]);
+    }
+
+    struct DummyMutDevice(u8);
+
+    impl MutDevicePio for DummyMutDevice {
+        fn pio_read(&mut self, _base: PioAddress, _offset: PioOffset, data: &mut [u8]) {
+            data[0] = self.0;
+        }
+
+        fn pio_write(&mut self, _base: PioAddress, _offset: PioOffset, data: &[u8]) {
+            self.0 = data[0];
+        }
+    }
+
+    impl crate::MutDeviceMmio for DummyMutDevice {
+        fn mmio_read(&mut self, _base: MmioAddress, _offset: MmioOffset, data: &mut [u8]) {
+            data[0] = self.0;
+        }
+
+        fn mmio_write(&mut self, _base: MmioAddress, _offset: MmioOffset, data: &[u8]) {
+            self.0 = data[0];
+        }
+    }
+
+    #[test]
+    fn test_layout() {
+        let mut io_mgr = IoManager::new();
+        let device = Arc::new(DummyDevice::new(0));
+        let pio = PioRange::new(PioAddress(0x60), 1).unwrap();
+        let mmio1 = MmioRange::new(MmioAddress(0x2000), 0x1000).unwrap();
+        let mmio0 = MmioRange::new(MmioAddress(0x1000), 0x1000).unwrap();
+        io_mgr.register_pio(pio, device.clone()).unwrap();
+        io_mgr.register_mmio(mmio1, device.clone()).unwrap();
+        io_mgr
+            .register_mmio(mmio0, Arc::new(DummyDevice::new(0)))
+            .unwrap();
+        io_mgr.begin_deregister_mmio(mmio0.base()).unwrap();
+
+        let layout = io_mgr.layout();
+        assert_eq!(layout.pio.len(), 1);
+        assert_eq!(layout.pio[0].range, pio);
+        let ranges: Vec<_> = layout.mmio.iter().map(|entry| entry.range).collect();
+        assert_eq!(ranges, vec![mmio0, mmio1]);
+        assert!(layout.mmio[0].draining);
+        assert!(!layout.mmio[1].draining);
+        // The same device object is reported with the same ID on both buses.
+        assert_eq!(layout.pio[0].device_id, layout.mmio[1].device_id);
+        assert_ne!(layout.mmio[0].device_id, layout.mmio[1].device_id);
+        assert!(layout.mmio_overlays.is_empty());
+        assert!(layout.mmio_segments.is_empty());
+        assert!(layout.sysreg.is_empty());
+
+        let shadow = MmioRange::new(MmioAddress(0x2800), 0x100).unwrap();
+        io_mgr
+            .register_mmio_shadow(shadow, Arc::new(DummyDevice::new(0x42)))
+            .unwrap();
+        let mut data = [0; 1];
+        io_mgr.mmio_read(shadow.base(), &mut data).unwrap();
+        assert_eq!(data, [0x42]);
+        let layout = io_mgr.layout();
+        assert_eq!(layout.mmio.len(), 3);
+        assert!(layout.mmio[2].shadow);
+        assert_eq!(layout.mmio[2].range, shadow);
+
+        io_mgr.deregister_mmio(shadow.base()).unwrap();
+        io_mgr.mmio_read(shadow.base(), &mut data).unwrap();
+        assert_eq!(data, [0]);
+    }
+
+    #[test]
+    fn test_per_cpu_devices() {
+        use crate::devices::lapic::{Lapic, LAPIC_DEFAULT_BASE, LAPIC_SIZE};
+        use crate::time::ManualClock;
+
+        let mut io_mgr = IoManager::new();
+        let clock = Arc::new(ManualClock::new());
+        let lapics: Vec<_> = (0..2)
+            .map(|id| Mutex::new(Lapic::new(id, clock.clone(), Box::new(|_| {}))))
+            .collect();
+        let base = MmioAddress(LAPIC_DEFAULT_BASE);
+        io_mgr
+            .register_mmio_per_cpu(MmioRange::new(base, LAPIC_SIZE).unwrap(), lapics)
+            .unwrap();
+
+        // Each vCPU reads the ID of its own local APIC.
+        let id_reg = MmioAddress(LAPIC_DEFAULT_BASE + 0x20);
+        let mut data = [0; 4];
+        for vcpu in 0..2 {
+            io_mgr
+                .mmio_read_with(id_reg, IoAccess::new(vcpu, 4), &mut data)
+                .unwrap();
+            assert_eq!(u32::from_le_bytes(data), vcpu << 24);
+        }
+
+        // Accesses which don't identify a vCPU with an instance are ignored.
+        io_mgr
+            .mmio_read_with(id_reg, IoAccess::new(2, 4), &mut data)
+            .unwrap();
+        assert_eq!(data, [0; 4]);
+        data = [0xff; 4];
+        io_mgr.mmio_read(id_reg, &mut data).unwrap();
+        assert_eq!(data, [0; 4]);
+    }
+
+    #[test]
+    fn test_mmio_segments() {
+        let mut io_mgr = IoManager::new();
+        let range = MmioRange::new(MmioAddress(0xe000_0000), 0x1000).unwrap();
+        let segment = MmioSegment(1);
+        io_mgr
+            .register_mmio(range, Arc::new(DummyDevice::new(0x11)))
+            .unwrap();
+        // The same range is available on every segment.
+        io_mgr
+            .register_mmio_segment(segment, range, Arc::new(DummyDevice::new(0x22)))
+            .unwrap();
+        assert!(io_mgr
+            .register_mmio_segment(segment, range, Arc::new(DummyDevice::new(0x33)))
+            .is_err());
+        assert_eq!(io_mgr.mmio_segments(), vec![MmioSegment::DEFAULT, segment]);
+
+        let mut data = [0; 1];
+        io_mgr
+            .mmio_read_segment(
+                MmioSegment::DEFAULT,
+                range.base(),
+                IoAccess::default(),
+                &mut data,
+            )
+            .unwrap();
+        assert_eq!(data, [0x11]);
+        io_mgr
+            .mmio_read_segment(segment, range.base(), IoAccess::default(), &mut data)
+            .unwrap();
+        assert_eq!(data, [0x22]);
+        assert_eq!(
+            io_mgr.mmio_read_segment(MmioSegment(2), range.base(), IoAccess::default(), &mut data),
+            Err(bus::Error::DeviceNotFound)
+        );
+        assert_eq!(io_mgr.layout().mmio_segments[0].0, segment);
+
+        assert!(io_mgr
+            .deregister_mmio_segment(segment, range.base())
+            .is_some());
+        assert_eq!(io_mgr.mmio_segments(), vec![MmioSegment::DEFAULT]);
+        assert!(io_mgr.mmio_device(range.base()).is_some());
+    }
+
+    #[test]
+    fn test_mmio_overlays() {
+        let mut io_mgr = IoManager::new();
+        let vga = Arc::new(DummyDevice::new(0x11));
+        let smram = Arc::new(DummyDevice::new(0x22));
+        let range = MmioRange::new(MmioAddress(0xa_0000), 0x2_0000).unwrap();
+        let smm = AccessAttrs {
+            smm: true,
+            secure: false,
+        };
+
+        io_mgr.register_mmio(range, vga).unwrap();
+        io_mgr
+            .register_mmio_overlay(smm, range, smram.clone())
+            .unwrap();
+        // Overlays have their own overlap checks.
+        assert!(io_mgr
+            .register_mmio_overlay(smm, range, smram.clone())
+            .is_err());
+
+        let mut data = [0; 1];
+        io_mgr
+            .mmio_read_with_attrs(range.base(), AccessAttrs::default(), &mut data)
+            .unwrap();
+        assert_eq!(data, [0x11]);
+        io_mgr
+            .mmio_read_with_attrs(range.base(), smm, &mut data)
+            .unwrap();
+        assert_eq!(data, [0x22]);
+
+        // Accesses outside the overlay ranges fall through to the regular bus.
+        let other = MmioAddress(0x10_0000);
+        io_mgr
+            .register_mmio(
+                MmioRange::new(other, 0x1000).unwrap(),
+                Arc::new(DummyDevice::new(0x33)),
+            )
+            .unwrap();
+        io_mgr.mmio_read_with_attrs(other, smm, &mut data).unwrap();
+        assert_eq!(data, [0x33]);
+        let secure = AccessAttrs {
+            smm: false,
+            secure: true,
+        };
+        io_mgr
+            .mmio_read_with_attrs(range.base(), secure, &mut data)
+            .unwrap();
+        assert_eq!(data, [0x11]);
+
+        io_mgr
+            .mmio_write_with_attrs(range.base(), smm, &[0x44])
+            .unwrap();
+        assert_eq!(*smram.config.lock().unwrap(), 0x44);
+
+        assert!(io_mgr.deregister_mmio_overlay(smm, range.base()).is_some());
+        io_mgr
+            .mmio_read_with_attrs(range.base(), smm, &mut data)
+            .unwrap();
+        assert_eq!(data, [0x11]);
+    }
+
+    #[test]
+    fn test_fallback_device() {
+        let mut io_mgr = IoManager::new();
+        let dev = Arc::new(DummyDevice::new(CONFIG_DATA));
+        let fallback = Arc::new(DummyDevice::new(0xff));
+        let range = MmioRange::new(MmioAddress(MMIO_ADDRESS_BASE), 0x1000).unwrap();
+        io_mgr.register_mmio(range, dev.clone()).unwrap();
+
+        let mut data = [0; 4];
+        assert_eq!(
+            io_mgr.mmio_read(MmioAddress(0), &mut data),
+            Err(bus::Error::DeviceNotFound)
+        );
+
+        io_mgr.set_mmio_fallback(fallback.clone());
+        io_mgr.set_pio_fallback(fallback.clone());
+        io_mgr.mmio_read(MmioAddress(0), &mut data).unwrap();
+        assert_eq!(data, [0xff, 0, 0, 0]);
+        // Registered ranges still take priority.
+        io_mgr
+            .mmio_read(MmioAddress(MMIO_ADDRESS_BASE), &mut data)
+            .unwrap();
+        assert_eq!(data, [0x34, 0x12, 0, 0]);
+        io_mgr.pio_write(PioAddress(0x80), &[0x12]).unwrap();
+        assert_eq!(*fallback.config.lock().unwrap(), 0x12);
+        // Accesses straddling the end of a range are still rejected.
+        assert_eq!(
+            io_mgr.mmio_read(MmioAddress(MMIO_ADDRESS_BASE + 0xffe), &mut data),
+            Err(bus::Error::DeviceNotFound)
+        );
+
+        assert!(io_mgr.clear_mmio_fallback().is_some());
+        assert!(io_mgr.mmio_read(MmioAddress(0), &mut data).is_err());
+    }
+
+    // Records the base and offset of its accesses.
+    #[derive(Default)]
+    struct BaseRecorder(Mutex<Vec<(u64, u64)>>);
+
+    impl DevicePio for BaseRecorder {
+        fn pio_read(&self, base: PioAddress, offset: PioOffset, _data: &mut [u8]) {
+            let access = (u64::from(base.0), u64::from(offset));
+            self.0.lock().unwrap().push(access);
+        }
+
+        fn pio_write(&self, _base: PioAddress, _offset: PioOffset, _data: &[u8]) {}
+    }
+
+    impl DeviceMmio for BaseRecorder {
+        fn mmio_read(&self, base: MmioAddress, offset: MmioOffset, _data: &mut [u8]) {
+            self.0.lock().unwrap().push((base.0, offset.raw()));
+        }
+
+        fn mmio_write(&self, base: MmioAddress, offset: MmioOffset, _data: &[u8]) {
+            self.0.lock().unwrap().push((base.0, offset.raw()));
+        }
+    }
+
+    #[test]
+    fn test_translated_ranges() {
+        let mut io_mgr = IoManager::new();
+        let device = Arc::new(BaseRecorder::default());
+        let low = MmioRange::new(MmioAddress(0xd000_0000), 0x1000).unwrap();
+        let high = MmioRange::new(MmioAddress(0x1_d000_0000), 0x1000).unwrap();
+        io_mgr.register_mmio(low, device.clone()).unwrap();
+        io_mgr
+            .register_mmio_translated(high, device.clone(), low.base())
+            .unwrap();
+        io_mgr
+            .register_pio_translated(
+                PioRange::new(PioAddress(0x1f0), 0x8).unwrap(),
+                device.clone(),
+                PioAddress(0x170),
+            )
+            .unwrap();
+
+        // The alias is indistinguishable from the low range for the device.
+        let mut data = [0; 4];
+        io_mgr
+            .mmio_read(MmioAddress(0xd000_0010), &mut data)
+            .unwrap();
+        io_mgr
+            .mmio_read(MmioAddress(0x1_d000_0010), &mut data)
+            .unwrap();
+        io_mgr
+            .mmio_write_with(MmioAddress(0x1_d000_0020), IoAccess::default(), &data)
+            .unwrap();
+        io_mgr.pio_read(PioAddress(0x1f7), &mut data[..1]).unwrap();
+        assert_eq!(
+            *device.0.lock().unwrap(),
+            vec![
+                (0xd000_0000, 0x10),
+                (0xd000_0000, 0x10),
+                (0xd000_0000, 0x20),
+                (0x170, 0x7)
+            ]
+        );
+
+        // The window is a range of its own.
+        assert_eq!(io_mgr.mmio_device(high.base()).unwrap().0, &high);
+        assert!(io_mgr.deregister_mmio(high.base()).is_some());
+        assert!(io_mgr.mmio_read(high.base(), &mut data).is_err());
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        use std::sync::mpsc;
+
+        use crate::bus::{WatchAccess, WatchAction, WatchKind};
+
+        let mut io_mgr = IoManager::new();
+        let dev = Arc::new(DummyDevice::new(CONFIG_DATA));
+        let range = MmioRange::new(MmioAddress(MMIO_ADDRESS_BASE), 0x1000).unwrap();
+        io_mgr.register_mmio(range, dev.clone()).unwrap();
+        io_mgr
+            .register_pio(
+                PioRange::new(PioAddress(PIO_ADDRESS_BASE), 0x10).unwrap(),
+                dev.clone(),
+            )
+            .unwrap();
+
+        // Report the writes to the first register, and let them reach the device.
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let watch_range = MmioRange::new(MmioAddress(MMIO_ADDRESS_BASE), 2).unwrap();
+        let id = io_mgr.add_mmio_watchpoint(
+            watch_range,
+            WatchKind::Write,
+            Arc::new(move |addr, access| {
+                if let WatchAccess::Write(data) = access {
+                    tx.lock().unwrap().send((addr, data.to_vec())).unwrap();
+                }
+                WatchAction::Continue
+            }),
+        );
+        let mut data = [0; 2];
+        io_mgr
+            .mmio_read(MmioAddress(MMIO_ADDRESS_BASE), &mut data)
+            .unwrap();
+        io_mgr
+            .mmio_write(MmioAddress(MMIO_ADDRESS_BASE + 1), &[0x56, 0x78])
+            .unwrap();
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            (MmioAddress(MMIO_ADDRESS_BASE + 1), vec![0x56, 0x78])
+        );
+        assert!(rx.try_recv().is_err());
+        assert_eq!(*dev.config.lock().unwrap(), 0x56);
+
+        // Reads can be answered by the watchpoint instead of the device.
+        io_mgr.add_pio_watchpoint(
+            PioRange::new(PioAddress(PIO_ADDRESS_BASE), 1).unwrap(),
+            WatchKind::ReadWrite,
+            Arc::new(|_, access| {
+                if let WatchAccess::Read(data) = access {
+                    data.iter_mut().for_each(|byte| *byte = 0xaa);
+                }
+                WatchAction::Skip
+            }),
+        );
+        io_mgr
+            .pio_read(PioAddress(PIO_ADDRESS_BASE), &mut data)
+            .unwrap();
+        assert_eq!(data, [0xaa, 0xaa]);
+        io_mgr
+            .pio_write(PioAddress(PIO_ADDRESS_BASE), &[0, 0])
+            .unwrap();
+        assert_eq!(*dev.config.lock().unwrap(), 0x56);
+
+        assert!(io_mgr.remove_mmio_watchpoint(id));
+        assert!(!io_mgr.remove_mmio_watchpoint(id));
+    }
+
+    // Name of the observer, whether the access is a write, its address, and its data.
+    type ObservedAccess = (&'static str, bool, u64, Vec<u8>);
+
+    // Logs the accesses it observes, tagged with its name.
+    struct Analyzer {
+        name: &'static str,
+        log: Arc<Mutex<Vec<ObservedAccess>>>,
+    }
+
+    impl<A: BusAddress> BusObserver<A> for Analyzer {
+        fn observe_read(&self, addr: A, data: &[u8]) {
+            let entry = (self.name, false, addr.value().into(), data.to_vec());
+            self.log.lock().unwrap().push(entry);
+        }
+
+        fn observe_write(&self, addr: A, data: &[u8]) {
+            let entry = (self.name, true, addr.value().into(), data.to_vec());
+            self.log.lock().unwrap().push(entry);
+        }
+    }
+
+    #[test]
+    fn test_observers() {
+        let mut io_mgr = IoManager::new();
+        let dev = Arc::new(DummyDevice::new(CONFIG_DATA));
+        let range = MmioRange::new(MmioAddress(MMIO_ADDRESS_BASE), 0x1000).unwrap();
+        io_mgr.register_mmio(range, dev.clone()).unwrap();
+        io_mgr
+            .register_pio(
+                PioRange::new(PioAddress(PIO_ADDRESS_BASE), 0x10).unwrap(),
+                dev,
+            )
+            .unwrap();
+
+        // Observers overlap the device and each other, and run by priority.
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let analyzer = |name| {
+            Arc::new(Analyzer {
+                name,
+                log: log.clone(),
+            })
+        };
+        let late = io_mgr.add_mmio_observer(range, 1, analyzer("late"));
+        io_mgr.add_mmio_observer(range, 0, analyzer("first"));
+        io_mgr.add_mmio_observer(range, 0, analyzer("second"));
+        io_mgr.add_mmio_observer(
+            MmioRange::new(MmioAddress(MMIO_ADDRESS_BASE + 0x1000), 0x100).unwrap(),
+            0,
+            analyzer("elsewhere"),
+        );
+        io_mgr.add_pio_observer(
+            PioRange::new(PioAddress(PIO_ADDRESS_BASE), 1).unwrap(),
+            0,
+            analyzer("pio"),
+        );
+        // Observers don't take part in the overlap checks.
+        io_mgr
+            .register_mmio(
+                MmioRange::new(MmioAddress(MMIO_ADDRESS_BASE + 0x1000), 0x10).unwrap(),
+                Arc::new(DummyDevice::new(0)),
+            )
+            .unwrap();
+
+        // Reads are observed with the data returned by the device.
+        let mut data = [0; 2];
+        io_mgr
+            .mmio_write(MmioAddress(MMIO_ADDRESS_BASE), &[0x12, 0x34])
+            .unwrap();
+        io_mgr
+            .mmio_read(MmioAddress(MMIO_ADDRESS_BASE), &mut data)
+            .unwrap();
+        let read = data.to_vec();
+        let base = MMIO_ADDRESS_BASE;
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![
+                ("first", true, base, vec![0x12, 0x34]),
+                ("second", true, base, vec![0x12, 0x34]),
+                ("late", true, base, vec![0x12, 0x34]),
+                ("first", false, base, read.clone()),
+                ("second", false, base, read.clone()),
+                ("late", false, base, read),
+            ]
+        );
+
+        log.lock().unwrap().clear();
+        assert!(io_mgr.remove_mmio_observer(late));
+        assert!(!io_mgr.remove_mmio_observer(late));
+        io_mgr
+            .pio_write(PioAddress(PIO_ADDRESS_BASE + 1), &[0x1])
+            .unwrap();
+        io_mgr
+            .pio_write(PioAddress(PIO_ADDRESS_BASE), &[0x2])
+            .unwrap();
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![("pio", true, u64::from(PIO_ADDRESS_BASE), vec![0x2])]
+        );
+    }
+
+    #[test]
+    fn test_line_interrupt() {
+        use crate::interrupt::{self, IrqRouter, TriggerMode};
+
+        let mut io_mgr = IoManager::new();
+        let resources = [Resource::LegacyIrq(5)];
+        assert_eq!(
+            io_mgr.line_interrupt(&resources, TriggerMode::Level).err(),
+            Some(interrupt::Error::NoRouter)
+        );
+        io_mgr.set_irq_router(IrqRouter::new(Arc::new(|_, _| {})));
+        assert_eq!(
+            io_mgr.line_interrupt(&[], TriggerMode::Level).err(),
+            Some(interrupt::Error::NoLegacyIrq)
+        );
+
+        let line = io_mgr
+            .line_interrupt(&resources, TriggerMode::Level)
+            .unwrap();
+        assert_eq!(line.irq(), 5);
+        line.assert();
+        assert!(io_mgr.irq_router().unwrap().level(5));
+    }
+
+    #[test]
+    fn test_access_constraints() {
+        use crate::bus::AccessPolicy;
+        use crate::testing::{EchoDevice, MockAccess};
+
+        let mut io_mgr = IoManager::new();
+        let strict = Arc::new(EchoDevice::new());
+        let split = Arc::new(EchoDevice::new());
+        io_mgr
+            .register_mmio_with_constraints(
+                MmioRange::new(MmioAddress(0x1000), 0x100).unwrap(),
+                strict,
+                AccessConstraints::new(&[4]).aligned(),
+            )
+            .unwrap();
+        io_mgr
+            .register_mmio_with_constraints(
+                MmioRange::new(MmioAddress(0x2000), 0x100).unwrap(),
+                split.clone(),
+                AccessConstraints::new(&[1, 2, 4])
+                    .aligned()
+                    .policy(AccessPolicy::Split),
+            )
+            .unwrap();
+
+        let mut data = [0; 8];
+        io_mgr
+            .mmio_read(MmioAddress(0x1004), &mut data[..4])
+            .unwrap();
+        for (addr, len) in [(0x1004, 2), (0x1002, 4), (0x1000, 8)].iter() {
+            assert_eq!(
+                io_mgr.mmio_read(MmioAddress(*addr), &mut data[..*len]),
+                Err(bus::Error::UnsupportedAccess)
+            );
+        }
+
+        // An unaligned 8 byte write is split into naturally aligned sub-accesses.
+        io_mgr
+            .mmio_write(MmioAddress(0x2002), &[0, 1, 2, 3, 4, 5, 6, 7])
+            .unwrap();
+        let write = |offset, data: &[u8]| MockAccess::Write {
+            offset,
+            data: data.to_vec(),
+        };
+        assert_eq!(
+            split.accesses(),
+            vec![
+                write(2, &[0, 1]),
+                write(4, &[2, 3, 4, 5]),
+                write(8, &[6, 7])
+            ]
+        );
+    }
+
+    #[test]
+    fn test_access_splitting() {
+        use crate::testing::{EchoDevice, MockAccess};
+
+        let mut io_mgr = IoManager::new();
+        let first = Arc::new(EchoDevice::new());
+        let second = Arc::new(EchoDevice::new());
+        io_mgr
+            .register_mmio(
+                MmioRange::new(MmioAddress(0x1000), 0x10).unwrap(),
+                first.clone(),
+            )
+            .unwrap();
+        io_mgr
+            .register_mmio(
+                MmioRange::new(MmioAddress(0x1010), 0x10).unwrap(),
+                second.clone(),
+            )
+            .unwrap();
+
+        let data = [0, 1, 2, 3, 4, 5, 6, 7];
+        assert_eq!(
+            io_mgr.mmio_write(MmioAddress(0x100c), &data),
+            Err(bus::Error::DeviceNotFound)
+        );
+        io_mgr.set_mmio_access_splitting(true);
+        io_mgr.mmio_write(MmioAddress(0x100c), &data).unwrap();
+        let write = |offset, data: &[u8]| MockAccess::Write {
+            offset,
+            data: data.to_vec(),
+        };
+        assert_eq!(first.accesses(), vec![write(0xc, &[0, 1, 2, 3])]);
+        assert_eq!(second.accesses(), vec![write(0, &[4, 5, 6, 7])]);
+
+        // Accesses which reach unclaimed addresses still fail.
+        assert_eq!(
+            io_mgr.mmio_write(MmioAddress(0x101c), &data),
+            Err(bus::Error::DeviceNotFound)
+        );
+    }
+
+    struct BlobDevice(Vec<u8>);
+
+    impl DeviceMmio for BlobDevice {
+        fn mmio_read(&self, _base: MmioAddress, _offset: MmioOffset, _data: &mut [u8]) {
+            panic!("the copy path should not be used");
+        }
+
+        fn mmio_write(&self, _base: MmioAddress, _offset: MmioOffset, _data: &[u8]) {}
+
+        fn as_zero_copy(&self) -> Option<&dyn DeviceMmioZeroCopy> {
+            Some(self)
+        }
+    }
+
+    impl DeviceMmioZeroCopy for BlobDevice {
+        fn mmio_slice(&self, _base: MmioAddress, offset: MmioOffset, len: usize) -> Option<&[u8]> {
+            self.0.get(offset.as_usize()..offset.as_usize() + len)
+        }
+    }
+
+    #[test]
+    fn test_mmio_read_ref() {
+        let mut io_mgr = IoManager::new();
+        let blob = Arc::new(BlobDevice((0..=255).collect()));
+        let blob_range = MmioRange::new(MmioAddress(0x1000), 0x100).unwrap();
+        let dummy_range = MmioRange::new(MmioAddress(0x2000), 0x100).unwrap();
+        io_mgr.register_mmio(blob_range, blob).unwrap();
+        io_mgr
+            .register_mmio(dummy_range, Arc::new(DummyDevice::new(CONFIG_DATA)))
+            .unwrap();
+
+        let sum = io_mgr
+            .mmio_read_ref(MmioAddress(0x1010), 0x10, |data| {
+                data.iter().map(|b| u32::from(*b)).sum::<u32>()
+            })
+            .unwrap();
+        assert_eq!(sum, (0x10..0x20).sum::<u32>());
+        // Devices without a zero-copy interface are read into a buffer.
+        let data = io_mgr
+            .mmio_read_ref(MmioAddress(0x2000), 4, |data| data.to_vec())
+            .unwrap();
+        assert_eq!(data, [0x34, 0x12, 0, 0]);
+        assert_eq!(
+            io_mgr.mmio_read_ref(MmioAddress(0x3000), 4, |_| ()),
+            Err(bus::Error::DeviceNotFound)
+        );
+    }
+
+    #[test]
+    fn test_mmio_read_ref_dispatch() {
+        let mut io_mgr = IoManager::new();
+        io_mgr.set_catch_panics(true);
+        let blob = Arc::new(BlobDevice((0..0x80).collect()));
+        let range = MmioRange::new(MmioAddress(0x1000), 0x100).unwrap();
+        io_mgr.register_mmio(range, blob).unwrap();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let analyzer = Analyzer {
+            name: "analyzer",
+            log: log.clone(),
+        };
+        io_mgr.add_mmio_observer(range, 0, Arc::new(analyzer));
+
+        // Lent contents are observed and accounted like the other reads.
+        io_mgr
+            .mmio_read_ref(MmioAddress(0x1010), 2, |data| {
+                assert_eq!(data, [0x10, 0x11])
+            })
+            .unwrap();
+        assert_eq!(
+            *log.lock().unwrap(),
+            [("analyzer", false, 0x1010, vec![0x10, 0x11])]
+        );
+        let stats = io_mgr.range_stats::<MmioAddress>();
+        assert_eq!((stats[0].1.accesses, stats[0].1.failures), (1, 0));
+
+        // The copy path is guarded against the panics of the device.
+        io_mgr
+            .mmio_read_ref(MmioAddress(0x1090), 2, |data| {
+                assert_eq!(data, [0xff, 0xff])
+            })
+            .unwrap();
+        assert!(io_mgr.is_quarantined(range.base()));
+        let stats = io_mgr.range_stats::<MmioAddress>();
+        assert_eq!((stats[0].1.accesses, stats[0].1.failures), (2, 1));
+    }
+
+    #[test]
+    fn test_dirty_tracking() {
+        use crate::devices::ram::RamDevice;
+
+        let mut io_mgr = IoManager::new();
+        let ram = RamDevice::new(0x4000).with_dirty_tracking(0x1000).unwrap();
+        let bitmap = ram.dirty_bitmap().unwrap();
+        let range = MmioRange::new(MmioAddress(0x10_0000), 0x4000).unwrap();
+        io_mgr
+            .register_mmio(range, Arc::new(Mutex::new(ram)))
+            .unwrap();
+        io_mgr.track_dirty(range, bitmap.clone()).unwrap();
+        assert_eq!(
+            io_mgr.track_dirty(range, bitmap),
+            Err(bus::Error::DeviceOverlap)
+        );
+
+        assert!(io_mgr.take_dirty().is_empty());
+        io_mgr.mmio_write(MmioAddress(0x10_2ffe), &[1; 4]).unwrap();
+        assert_eq!(io_mgr.take_dirty(), vec![(range, vec![2, 3])]);
+        assert!(io_mgr.take_dirty().is_empty());
+
+        assert!(io_mgr.untrack_dirty(MmioAddress(0x10_0000)).is_some());
+        io_mgr.mmio_write(MmioAddress(0x10_0000), &[1]).unwrap();
+        assert!(io_mgr.take_dirty().is_empty());
+    }
+
+    #[test]
+    fn test_event_sender() {
+        use crate::events::{vm_event_channel, VmEvent};
+
+        let mut io_mgr = IoManager::new();
+        assert!(io_mgr.event_sender("cf9").is_none());
+        let (sender, receiver) = vm_event_channel();
+        io_mgr.set_event_sender(sender);
+
+        let reset = io_mgr.event_sender("cf9").unwrap();
+        reset.send(VmEvent::Reset).unwrap();
+        let message = receiver.try_recv().unwrap();
+        assert_eq!(&*message.source, "cf9");
+        assert_eq!(message.event, VmEvent::Reset);
+    }
+
+    #[test]
+    fn test_fork() {
+        let mut io_mgr = IoManager::new();
+        let dum = Arc::new(DummyDevice::new(CONFIG_DATA));
+        let mmio0 = MmioRange::new(MmioAddress(0x1000), 0x10).unwrap();
+        let mmio1 = MmioRange::new(MmioAddress(0x2000), 0x10).unwrap();
+        io_mgr.register_mmio(mmio0, dum.clone()).unwrap();
+        io_mgr.register_mmio(mmio1, dum.clone()).unwrap();
+
+        // The copies diverge once changed.
+        let mut fork = io_mgr.fork();
+        fork.deregister_mmio(mmio0.base()).unwrap();
+        let pio = PioRange::new(PioAddress(PIO_ADDRESS_BASE), PIO_ADDRESS_SIZE).unwrap();
+        fork.register_pio(pio, dum.clone()).unwrap();
+        assert!(io_mgr.mmio_device(mmio0.base()).is_some());
+        assert!(io_mgr.pio_device(pio.base()).is_none());
+        assert!(fork.mmio_device(mmio0.base()).is_none());
+
+        let mut data = [0; 4];
+        fork.mmio_read(mmio1.base(), &mut data).unwrap();
+        assert_eq!(data, [0x34, 0x12, 0, 0]);
+
+        // The state of the ranges isn't shared.
+        fork.set_range_enabled(mmio1.base(), false).unwrap();
+        assert!(fork.mmio_read(mmio1.base(), &mut data).is_err());
+        io_mgr.mmio_read(mmio1.base(), &mut data).unwrap();
+        fork.set_range_enabled(mmio1.base(), true).unwrap();
+        fork.begin_deregister_mmio(mmio1.base()).unwrap();
+        io_mgr.mmio_read(mmio1.base(), &mut data).unwrap();
+        assert_eq!(io_mgr.range_stats::<MmioAddress>()[1].1.accesses, 2);
+
+        // Ranges being drained at the time of the fork are drained in both copies.
+        io_mgr.begin_deregister_mmio(mmio1.base()).unwrap();
+        let fork = io_mgr.fork();
+        assert!(fork.mmio_read(mmio1.base(), &mut data).is_err());
+        assert!(io_mgr.complete_deregister_mmio(mmio1.base()).is_ok());
+        assert!(fork.mmio_device(mmio1.base()).is_some());
+    }
+
+    #[test]
+    fn test_range_enabled() {
+        let mut io_mgr = IoManager::new();
+        let dum = Arc::new(DummyDevice::new(CONFIG_DATA));
+        let mmio = MmioRange::new(MmioAddress(0x1000), 0x10).unwrap();
+        let pio = PioRange::new(PioAddress(PIO_ADDRESS_BASE), PIO_ADDRESS_SIZE).unwrap();
+        io_mgr.register_mmio(mmio, dum.clone()).unwrap();
+        io_mgr.register_pio(pio, dum).unwrap();
+
+        io_mgr.set_range_enabled(mmio.base(), false).unwrap();
+        let mut data = [0; 4];
+        assert!(io_mgr.mmio_read(mmio.base(), &mut data).is_err());
+        io_mgr.pio_read(pio.base(), &mut data).unwrap();
+        assert!(!io_mgr.layout().mmio[0].enabled);
+
+        io_mgr.set_range_enabled(pio.base(), false).unwrap();
+        assert!(io_mgr.pio_read(pio.base(), &mut data).is_err());
+        io_mgr.set_range_enabled(mmio.base(), true).unwrap();
+        io_mgr.mmio_read(mmio.base(), &mut data).unwrap();
+        assert_eq!(data, [0x34, 0x12, 0, 0]);
+    }
+
+    // Fails the accesses while `failing` is set.
+    struct FlakyDevice {
+        failing: AtomicBool,
+    }
+
+    impl DeviceMmio for FlakyDevice {
+        fn mmio_read(&self, _base: MmioAddress, _offset: MmioOffset, data: &mut [u8]) {
+            data.iter_mut().for_each(|byte| *byte = 0);
+        }
+
+        fn mmio_write(&self, _base: MmioAddress, _offset: MmioOffset, _data: &[u8]) {}
+
+        fn try_mmio_read(
+            &self,
+            _access: IoAccess,
+            base: MmioAddress,
+            offset: MmioOffset,
+            data: &mut [u8],
+        ) -> Result<(), poison::DeviceError> {
+            if self.failing.load(Ordering::SeqCst) {
+                return Err(poison::DeviceError::Poisoned);
+            }
+            self.mmio_read(base, offset, data);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_plain_access_health() {
+        use crate::testing::FailingDevice;
+
+        let mut io_mgr = IoManager::new();
+        io_mgr.set_quarantine_threshold(Some(2));
+        io_mgr.set_catch_panics(true);
+        let device = Arc::new(FlakyDevice {
+            failing: AtomicBool::new(true),
+        });
+        let range = MmioRange::new(MmioAddress(0x1000), 0x10).unwrap();
+        io_mgr.register_mmio(range, device).unwrap();
+        let pio = PioRange::new(PioAddress(0x60), 1).unwrap();
+        io_mgr
+            .register_pio(pio, Arc::new(FailingDevice::new("pio")))
+            .unwrap();
+
+        // Successful accesses without context reset the failure count of the range.
+        let mut data = [0; 2];
+        for _ in 0..3 {
+            io_mgr
+                .mmio_read_with(range.base(), IoAccess::default(), &mut data)
+                .unwrap();
+            io_mgr.mmio_read(range.base(), &mut data).unwrap();
+            assert_eq!(data, [0, 0]);
+        }
+        assert!(!io_mgr.is_quarantined(range.base()));
+        let stats = io_mgr.range_stats::<MmioAddress>();
+        assert_eq!((stats[0].1.accesses, stats[0].1.failures), (6, 3));
+
+        // Their panics are recorded as failures.
+        io_mgr.pio_write(pio.base(), &[1]).unwrap();
+        assert!(io_mgr.is_quarantined(pio.base()));
+        assert_eq!(io_mgr.range_stats::<PioAddress>()[0].1.failures, 1);
+    }
+
+    #[test]
+    fn test_quarantine() {
+        use crate::events::vm_event_channel;
+
+        let mut io_mgr = IoManager::new();
+        let (sender, receiver) = vm_event_channel();
+        io_mgr.set_event_sender(sender);
+        io_mgr.set_quarantine_threshold(Some(3));
+        let device = Arc::new(FlakyDevice {
+            failing: AtomicBool::new(true),
+        });
+        let range = MmioRange::new(MmioAddress(0x1000), 0x10).unwrap();
+        io_mgr.register_mmio(range, device.clone()).unwrap();
+
+        // Failed reads return all ones, and successful accesses reset the failure count.
+        let mut data = [0; 2];
+        let access = IoAccess::default();
+        for _ in 0..2 {
+            io_mgr
+                .mmio_read_with(range.base(), access, &mut data)
+                .unwrap();
+            assert_eq!(data, [0xff, 0xff]);
+        }
+        device.failing.store(false, Ordering::SeqCst);
+        io_mgr
+            .mmio_read_with(range.base(), access, &mut data)
+            .unwrap();
+        assert_eq!(data, [0, 0]);
+        device.failing.store(true, Ordering::SeqCst);
+        for _ in 0..2 {
+            io_mgr
+                .mmio_read_with(range.base(), access, &mut data)
+                .unwrap();
+        }
+        assert!(!io_mgr.is_quarantined(range.base()));
+        assert!(receiver.try_recv().is_none());
+
+        io_mgr
+            .mmio_read_with(range.base(), access, &mut data)
+            .unwrap();
+        assert!(io_mgr.is_quarantined(range.base()));
+        assert_eq!(
+            receiver.try_recv().unwrap().event,
+            VmEvent::DeviceQuarantined {
+                space: AddressSpace::Mmio,
+                base: 0x1000
+            }
+        );
+        assert_eq!(
+            io_mgr.mmio_read_with(range.base(), access, &mut data),
+            Err(bus::Error::DeviceNotFound)
+        );
+
+        device.failing.store(false, Ordering::SeqCst);
+        io_mgr.release_quarantine(range.base()).unwrap();
+        assert!(!io_mgr.is_quarantined(range.base()));
+        io_mgr
+            .mmio_read_with(range.base(), access, &mut data)
+            .unwrap();
+        assert_eq!(data, [0, 0]);
+    }
+
+    // Panics on every access.
+    struct PanickyDevice;
+
+    impl DeviceMmio for PanickyDevice {
+        fn mmio_read(&self, _base: MmioAddress, _offset: MmioOffset, _data: &mut [u8]) {
+            panic!("mmio read");
+        }
+
+        fn mmio_write(&self, _base: MmioAddress, _offset: MmioOffset, _data: &[u8]) {
+            panic!("mmio write");
+        }
+    }
+
+    impl DevicePio for PanickyDevice {
+        fn pio_read(&self, _base: PioAddress, _offset: PioOffset, _data: &mut [u8]) {
+            panic!("pio read");
+        }
+
+        fn pio_write(&self, _base: PioAddress, _offset: PioOffset, _data: &[u8]) {
+            panic!("pio write");
+        }
+    }
+
+    #[test]
+    fn test_catch_panics() {
+        use crate::events::vm_event_channel;
+
+        let mut io_mgr = IoManager::new();
+        let (sender, receiver) = vm_event_channel();
+        io_mgr.set_event_sender(sender);
+        io_mgr.set_catch_panics(true);
+        let device = Arc::new(PanickyDevice);
+        let mmio = MmioRange::new(MmioAddress(0x1000), 0x10).unwrap();
+        let pio = PioRange::new(PioAddress(PIO_ADDRESS_BASE), PIO_ADDRESS_SIZE).unwrap();
+        io_mgr.register_mmio(mmio, device.clone()).unwrap();
+        io_mgr.register_pio(pio, device).unwrap();
+
+        // The panic fails the access, and quarantines the range.
+        let mut data = [0; 2];
+        io_mgr.mmio_read(mmio.base(), &mut data).unwrap();
+        assert_eq!(data, [0xff, 0xff]);
+        assert!(io_mgr.is_quarantined(mmio.base()));
+        let space = AddressSpace::Mmio;
+        let base = 0x1000;
+        assert_eq!(
+            receiver.try_recv().unwrap().event,
+            VmEvent::DevicePanicked { space, base }
+        );
+        assert_eq!(
+            receiver.try_recv().unwrap().event,
+            VmEvent::DeviceQuarantined { space, base }
+        );
+        assert!(io_mgr.mmio_write(mmio.base(), &data).is_err());
+
+        io_mgr
+            .pio_write_with(pio.base(), IoAccess::default(), &[1])
+            .unwrap();
+        assert!(io_mgr.is_quarantined(pio.base()));
+        assert!(io_mgr.pio_read(pio.base(), &mut data).is_err());
+    }
+
+    #[test]
+    fn test_device_as() {
+        let mut io_mgr = IoManager::new();
+        let dum = Arc::new(DummyDevice::new(CONFIG_DATA));
+        let mmio = MmioRange::new(MmioAddress(0x1000), 0x10).unwrap();
+        let pio = PioRange::new(PioAddress(PIO_ADDRESS_BASE), PIO_ADDRESS_SIZE).unwrap();
+        io_mgr.register_mmio_typed(mmio, dum.clone()).unwrap();
+        io_mgr.register_pio(pio, dum.clone()).unwrap();
+
+        let found = io_mgr.device_as::<DummyDevice, _>(mmio.base()).unwrap();
+        assert!(Arc::ptr_eq(&found, &dum));
+        // The same device registered without its type is found through its allocation.
+        assert!(io_mgr.device_as::<DummyDevice, _>(pio.base()).is_some());
+        assert!(io_mgr.device_as::<FlakyDevice, _>(mmio.base()).is_none());
+        assert!(io_mgr.device_as::<DummyDevice, _>(MmioAddress(0)).is_none());
+
+        io_mgr.deregister_mmio(mmio.base()).unwrap();
+        io_mgr.deregister_pio(pio.base()).unwrap();
+        drop((found, dum));
+        let other = Arc::new(DummyDevice::new(0));
+        io_mgr.register_mmio(mmio, other).unwrap();
+        assert!(io_mgr.device_as::<DummyDevice, _>(mmio.base()).is_none());
+    }
+
+    struct Console(Mutex<Vec<u8>>);
+
+    impl DeviceMmio for Console {
+        fn mmio_read(&self, _base: MmioAddress, _offset: MmioOffset, data: &mut [u8]) {
+            let mut input = self.0.lock().unwrap();
+            for byte in data.iter_mut() {
+                *byte = if input.is_empty() { 0 } else { input.remove(0) };
+            }
+        }
+
+        fn mmio_write(&self, _base: MmioAddress, _offset: MmioOffset, _data: &[u8]) {}
+
+        fn as_any(&self) -> Option<&dyn Any> {
+            Some(self)
+        }
+    }
+
+    #[test]
+    fn test_device_downcast() {
+        let mut io_mgr = IoManager::new();
+        let range = MmioRange::new(MmioAddress(0x1000), 0x10).unwrap();
+        io_mgr
+            .register_mmio(range, Arc::new(Console(Mutex::new(Vec::new()))))
+            .unwrap();
+        let pio = PioRange::new(PioAddress(PIO_ADDRESS_BASE), PIO_ADDRESS_SIZE).unwrap();
+        io_mgr
+            .register_pio(pio, Arc::new(DummyDevice::new(CONFIG_DATA)))
+            .unwrap();
+
+        // Inject input into the console found by address.
+        let console = io_mgr
+            .mmio_device_downcast::<Console>(MmioAddress(0x1004))
+            .unwrap();
+        console.0.lock().unwrap().extend_from_slice(b"hi");
+        let mut data = [0; 2];
+        io_mgr.mmio_read(range.base(), &mut data).unwrap();
+        assert_eq!(&data, b"hi");
+
+        assert!(io_mgr
+            .mmio_device_downcast::<DummyDevice>(range.base())
+            .is_none());
+        // The device doesn't expose its type.
+        assert!(io_mgr
+            .pio_device_downcast::<DummyDevice>(pio.base())
+            .is_none());
+    }
+
+    #[test]
+    fn test_mutex_device_downcast() {
+        let mut io_mgr = IoManager::new();
+        let pio = PioRange::new(PioAddress(PIO_ADDRESS_BASE), 1).unwrap();
+        let mmio = MmioRange::new(MmioAddress(0x1000), 0x10).unwrap();
+        let device = Arc::new(Mutex::new(DummyMutDevice(0x56)));
+        io_mgr.register_pio_typed(pio, device.clone()).unwrap();
+        io_mgr.register_mmio_typed(mmio, device.clone()).unwrap();
+
+        // The blanket implementations can't hand out references into the locked device.
+        assert!(DevicePio::as_any(&*device).is_none());
+        assert!(DeviceMmio::as_any(&*device).is_none());
+        assert!(device.as_zero_copy().is_none());
+        assert!(io_mgr
+            .pio_device_downcast::<Mutex<DummyMutDevice>>(pio.base())
+            .is_none());
+        assert!(io_mgr
+            .mmio_device_downcast::<Mutex<DummyMutDevice>>(mmio.base())
+            .is_none());
+
+        // Registering them with their type is what gives them back.
+        let found = io_mgr
+            .device_as::<Mutex<DummyMutDevice>, _>(pio.base())
+            .unwrap();
+        assert!(Arc::ptr_eq(&found, &device));
+        let mut data = [0; 1];
+        io_mgr.mmio_read(mmio.base(), &mut data).unwrap();
+        assert_eq!(data, [0x56]);
+    }
+
     #[test]
     fn test_error_code() {
         let err = super::Error::Bus(bus::Error::DeviceOverlap);
@@ -0,0 +1,411 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! ACPI general purpose event (GPE) register blocks, as described by the `GPE0_BLK` and
+//! `GPE1_BLK` fields of the FADT.
+//!
+//! Each block is made of a status register, with one bit per GPE, followed by an enable
+//! register of the same size. The [`AcpiGpe`](struct.AcpiGpe.html) controller models both
+//! blocks, and raises the SCI while an enabled GPE is pending, so the guest runs the
+//! matching `_Lxx`/`_Exx` method of its DSDT. Platform components signal their GPE with
+//! [`raise`](struct.AcpiGpe.html#method.raise), or through a
+//! [`notifier`](struct.AcpiGpe.html#method.notifier), which is what the hotplug
+//! controllers expect as their `notify_guest` callback.
+//!
+//! The SCI is a level triggered line shared with the PM1 event block: `AcpiGpe` and
+//! `AcpiPm` each get their own handle of the line (e.g. from `IoManager::line_interrupt`),
+//! and the `IrqRouter` keeps it asserted while either of them has a pending event.
+
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Mutex};
+
+use crate::bus::{PioAddress, PioAddressValue, PioOffset, PioRange};
+use crate::interrupt::LineInterrupt;
+use crate::lifecycle::Lifecycle;
+use crate::DevicePio;
+
+/// I/O port of the GPE0 block on the QEMU `q35` machine.
+pub const ACPI_GPE0_DEFAULT_PORT: PioAddressValue = 0x620;
+
+/// Size of the GPE0 block on the QEMU `q35` machine, which has 64 GPEs.
+pub const ACPI_GPE0_DEFAULT_LEN: u8 = 0x10;
+
+/// Interrupt line of the SCI on PC machines.
+pub const ACPI_SCI_DEFAULT_IRQ: u32 = 9;
+
+/// GPE used by the CPU hotplug methods of the QEMU DSDT.
+pub const GPE_CPU_HOTPLUG: u32 = 2;
+
+/// GPE used by the memory hotplug methods of the QEMU DSDT.
+pub const GPE_MEMORY_HOTPLUG: u32 = 3;
+
+/// Errors encountered while configuring or raising GPEs.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The length of a block isn't a non-zero multiple of two.
+    InvalidLength(u8),
+    /// The GPE numbers of the GPE1 block overlap the GPE0 block, or go past the last GPE.
+    InvalidBase(u32),
+    /// The ports of the GPE1 block overlap the GPE0 block.
+    PortOverlap(PioAddressValue),
+    /// The controller already has a GPE1 block.
+    Gpe1Exists,
+    /// The GPE isn't implemented by any block.
+    InvalidGpe(u32),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::InvalidLength(len) => write!(f, "invalid GPE block length {}", len),
+            Error::InvalidBase(base) => {
+                write!(f, "invalid GPE1 block base {}", base)
+            }
+            Error::PortOverlap(port) => {
+                write!(f, "GPE1 block at {:#x} overlaps the GPE0 block", port)
+            }
+            Error::Gpe1Exists => write!(f, "the GPE1 block is already set"),
+            Error::InvalidGpe(gpe) => write!(f, "GPE {} isn't implemented", gpe),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+struct Block {
+    port: PioAddress,
+    // Length of the block in bytes, half of which is the status register.
+    len: u8,
+    // Number of the first GPE of the block.
+    base: u32,
+}
+
+impl Block {
+    fn new(port: PioAddress, len: u8, base: u32) -> Result<Self, Error> {
+        if len == 0 || !len.is_multiple_of(2) {
+            return Err(Error::InvalidLength(len));
+        }
+        Ok(Block { port, len, base })
+    }
+
+    fn gpes(&self) -> u32 {
+        u32::from(self.len) / 2 * 8
+    }
+
+    // Return the index of the byte and the mask of the bit of `gpe` in the registers.
+    fn bit(&self, gpe: u32) -> Option<(usize, u8)> {
+        let idx = gpe
+            .checked_sub(self.base)
+            .filter(|idx| *idx < self.gpes())?;
+        Some(((idx / 8) as usize, 1 << (idx % 8)))
+    }
+}
+
+struct Registers {
+    sts: Vec<u8>,
+    en: Vec<u8>,
+}
+
+/// Model of the ACPI GPE register blocks.
+pub struct AcpiGpe {
+    blocks: Vec<Block>,
+    sci: Option<LineInterrupt>,
+    // Registers of each block, in the order of `blocks`.
+    state: Mutex<Vec<Registers>>,
+}
+
+impl AcpiGpe {
+    /// Create a controller with a GPE0 block of `len` bytes at `port`, for the GPEs from 0
+    /// to `len * 4 - 1`.
+    pub fn new(port: PioAddress, len: u8) -> Result<Self, Error> {
+        let mut gpe = AcpiGpe {
+            blocks: Vec::new(),
+            sci: None,
+            state: Mutex::new(Vec::new()),
+        };
+        gpe.add_block(Block::new(port, len, 0)?);
+        Ok(gpe)
+    }
+
+    /// Add a GPE1 block of `len` bytes at `port`, whose first GPE is `base` (the
+    /// `GPE1_BASE` field of the FADT). Neither the ports nor the GPE numbers of the block
+    /// can overlap the GPE0 block.
+    pub fn with_gpe1(mut self, port: PioAddress, len: u8, base: u32) -> Result<Self, Error> {
+        if self.blocks.len() > 1 {
+            return Err(Error::Gpe1Exists);
+        }
+        let block = Block::new(port, len, base)?;
+        let gpe0 = &self.blocks[0];
+        if base < gpe0.gpes() || base.checked_add(block.gpes()).is_none() {
+            return Err(Error::InvalidBase(base));
+        }
+        let range = |block: &Block| {
+            let start = u64::from(block.port.0);
+            (start, start + u64::from(block.len))
+        };
+        let (start0, end0) = range(gpe0);
+        let (start1, end1) = range(&block);
+        if start1 < end0 && start0 < end1 {
+            return Err(Error::PortOverlap(port.0));
+        }
+        self.add_block(block);
+        Ok(self)
+    }
+
+    /// Raise `sci` while an enabled GPE is pending.
+    pub fn with_sci(mut self, sci: LineInterrupt) -> Self {
+        self.sci = Some(sci);
+        self
+    }
+
+    fn add_block(&mut self, block: Block) {
+        let size = usize::from(block.len / 2);
+        self.state.get_mut().unwrap().push(Registers {
+            sts: vec![0; size],
+            en: vec![0; size],
+        });
+        self.blocks.push(block);
+    }
+
+    /// Return the I/O port ranges of the blocks, which the device has to be registered at.
+    pub fn ranges(&self) -> Vec<PioRange> {
+        self.blocks
+            .iter()
+            .map(|block| {
+                // The length of a block is never zero.
+                PioRange::new(block.port, PioAddressValue::from(block.len)).unwrap()
+            })
+            .collect()
+    }
+
+    // Return the block implementing `gpe`, and the position of its status bit.
+    fn locate(&self, gpe: u32) -> Result<(usize, usize, u8), Error> {
+        self.blocks
+            .iter()
+            .enumerate()
+            .find_map(|(idx, block)| block.bit(gpe).map(|(byte, mask)| (idx, byte, mask)))
+            .ok_or(Error::InvalidGpe(gpe))
+    }
+
+    /// Latch the status bit of `gpe`, and raise the SCI if the guest enabled it.
+    pub fn raise(&self, gpe: u32) -> Result<(), Error> {
+        let (block, byte, mask) = self.locate(gpe)?;
+        let mut state = self.state.lock().unwrap();
+        state[block].sts[byte] |= mask;
+        self.update_sci(&state);
+        Ok(())
+    }
+
+    /// Return whether the status bit of `gpe` is set.
+    pub fn is_pending(&self, gpe: u32) -> Result<bool, Error> {
+        let (block, byte, mask) = self.locate(gpe)?;
+        Ok(self.state.lock().unwrap()[block].sts[byte] & mask != 0)
+    }
+
+    /// Return whether the guest enabled `gpe`.
+    pub fn is_enabled(&self, gpe: u32) -> Result<bool, Error> {
+        let (block, byte, mask) = self.locate(gpe)?;
+        Ok(self.state.lock().unwrap()[block].en[byte] & mask != 0)
+    }
+
+    /// Return a callback raising `gpe`, e.g. the `notify_guest` callback of a hotplug
+    /// controller.
+    pub fn notifier(self: &Arc<Self>, gpe: u32) -> Result<Box<dyn Fn() + Send + Sync>, Error> {
+        self.locate(gpe)?;
+        let controller = self.clone();
+        Ok(Box::new(move || {
+            // The GPE was validated when the callback was created.
+            let _ = controller.raise(gpe);
+        }))
+    }
+
+    // Drive the SCI with the enabled pending GPEs.
+    fn update_sci(&self, state: &[Registers]) {
+        if let Some(sci) = self.sci.as_ref() {
+            let pending = state
+                .iter()
+                .any(|regs| regs.sts.iter().zip(regs.en.iter()).any(|(s, e)| s & e != 0));
+            if pending {
+                sci.assert();
+            } else {
+                sci.deassert();
+            }
+        }
+    }
+}
+
+impl Lifecycle for AcpiGpe {
+    fn reset(&self) {
+        let mut state = self.state.lock().unwrap();
+        for regs in state.iter_mut() {
+            regs.sts.iter_mut().for_each(|byte| *byte = 0);
+            regs.en.iter_mut().for_each(|byte| *byte = 0);
+        }
+        self.update_sci(&state);
+    }
+}
+
+impl DevicePio for AcpiGpe {
+    fn pio_read(&self, base: PioAddress, offset: PioOffset, data: &mut [u8]) {
+        let state = self.state.lock().unwrap();
+        let block = self.blocks.iter().position(|block| block.port == base);
+        for (idx, byte) in data.iter_mut().enumerate() {
+            let addr = u64::from(offset) as usize + idx;
+            *byte = block
+                .and_then(|block| {
+                    let regs = &state[block];
+                    let half = regs.sts.len();
+                    if addr < half {
+                        regs.sts.get(addr)
+                    } else {
+                        regs.en.get(addr - half)
+                    }
+                })
+                .copied()
+                .unwrap_or(0);
+        }
+    }
+
+    fn pio_write(&self, base: PioAddress, offset: PioOffset, data: &[u8]) {
+        let block = match self.blocks.iter().position(|block| block.port == base) {
+            Some(block) => block,
+            None => return,
+        };
+        let mut state = self.state.lock().unwrap();
+        let regs = &mut state[block];
+        let half = regs.sts.len();
+        for (idx, value) in data.iter().enumerate() {
+            let addr = u64::from(offset) as usize + idx;
+            if addr < half {
+                // The status bits are cleared by writing ones.
+                regs.sts[addr] &= !value;
+            } else if let Some(en) = regs.en.get_mut(addr - half) {
+                *en = *value;
+            }
+        }
+        self.update_sci(&state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc::channel;
+
+    use crate::devices::cpuhp::AcpiCpuHotplug;
+    use crate::events::vm_event_channel;
+    use crate::hotplug::HotplugNotifier;
+    use crate::interrupt::{IrqRouter, TriggerMode};
+
+    const GPE1_PORT: PioAddressValue = 0x630;
+
+    fn write(gpe: &AcpiGpe, port: PioAddressValue, offset: PioAddressValue, data: &[u8]) {
+        gpe.pio_write(PioAddress(port), PioOffset(offset), data);
+    }
+
+    fn read(gpe: &AcpiGpe, port: PioAddressValue, offset: PioAddressValue) -> u8 {
+        let mut data = [0];
+        gpe.pio_read(PioAddress(port), PioOffset(offset), &mut data);
+        data[0]
+    }
+
+    #[test]
+    fn test_acpi_gpe() {
+        let port = PioAddress(ACPI_GPE0_DEFAULT_PORT);
+        assert_eq!(AcpiGpe::new(port, 3).err(), Some(Error::InvalidLength(3)));
+        assert_eq!(
+            AcpiGpe::new(port, 4)
+                .unwrap()
+                .with_gpe1(PioAddress(GPE1_PORT), 2, 8)
+                .err(),
+            Some(Error::InvalidBase(8))
+        );
+        assert_eq!(
+            AcpiGpe::new(port, 4)
+                .unwrap()
+                .with_gpe1(PioAddress(GPE1_PORT), 2, u32::MAX - 4)
+                .err(),
+            Some(Error::InvalidBase(u32::MAX - 4))
+        );
+        assert_eq!(
+            AcpiGpe::new(port, 4)
+                .unwrap()
+                .with_gpe1(PioAddress(ACPI_GPE0_DEFAULT_PORT + 2), 2, 64)
+                .err(),
+            Some(Error::PortOverlap(ACPI_GPE0_DEFAULT_PORT + 2))
+        );
+        assert_eq!(
+            AcpiGpe::new(port, 4)
+                .unwrap()
+                .with_gpe1(PioAddress(GPE1_PORT), 2, 64)
+                .unwrap()
+                .with_gpe1(PioAddress(GPE1_PORT + 2), 2, 80)
+                .err(),
+            Some(Error::Gpe1Exists)
+        );
+
+        let (levels, rx) = channel();
+        let levels = Mutex::new(levels);
+        let router = IrqRouter::new(Arc::new(move |irq, level| {
+            levels.lock().unwrap().send((irq, level)).unwrap();
+        }));
+        let gpe = AcpiGpe::new(port, 4)
+            .unwrap()
+            .with_gpe1(PioAddress(GPE1_PORT), 2, 64)
+            .unwrap()
+            .with_sci(
+                router
+                    .line(ACPI_SCI_DEFAULT_IRQ, TriggerMode::Level)
+                    .unwrap(),
+            );
+        assert_eq!(
+            gpe.ranges(),
+            [
+                PioRange::new(port, 4).unwrap(),
+                PioRange::new(PioAddress(GPE1_PORT), 2).unwrap()
+            ]
+        );
+        assert_eq!(gpe.raise(16), Err(Error::InvalidGpe(16)));
+        assert_eq!(gpe.raise(72), Err(Error::InvalidGpe(72)));
+
+        // The GPE is latched, and only raises the SCI once the guest enables it.
+        gpe.raise(GPE_CPU_HOTPLUG).unwrap();
+        assert_eq!(read(&gpe, ACPI_GPE0_DEFAULT_PORT, 0), 1 << GPE_CPU_HOTPLUG);
+        assert!(rx.try_recv().is_err());
+        write(&gpe, ACPI_GPE0_DEFAULT_PORT, 2, &[1 << GPE_CPU_HOTPLUG]);
+        assert!(gpe.is_enabled(GPE_CPU_HOTPLUG).unwrap());
+        assert_eq!(rx.try_recv().unwrap(), (ACPI_SCI_DEFAULT_IRQ, true));
+        write(&gpe, ACPI_GPE0_DEFAULT_PORT, 0, &[1 << GPE_CPU_HOTPLUG]);
+        assert!(!gpe.is_pending(GPE_CPU_HOTPLUG).unwrap());
+        assert_eq!(rx.try_recv().unwrap(), (ACPI_SCI_DEFAULT_IRQ, false));
+
+        // GPEs of the second block.
+        write(&gpe, GPE1_PORT, 1, &[0x80]);
+        gpe.raise(71).unwrap();
+        assert_eq!(read(&gpe, GPE1_PORT, 0), 0x80);
+        assert!(router.level(ACPI_SCI_DEFAULT_IRQ));
+        gpe.reset();
+        assert_eq!(read(&gpe, GPE1_PORT, 1), 0);
+        assert!(!router.level(ACPI_SCI_DEFAULT_IRQ));
+    }
+
+    #[test]
+    fn test_hotplug_notifier() {
+        let gpe = Arc::new(
+            AcpiGpe::new(PioAddress(ACPI_GPE0_DEFAULT_PORT), ACPI_GPE0_DEFAULT_LEN).unwrap(),
+        );
+        assert!(gpe.notifier(64).is_err());
+        let (tx, _rx) = vm_event_channel();
+        let hotplug = AcpiCpuHotplug::new(
+            2,
+            1,
+            tx.for_device("cpuhp"),
+            gpe.notifier(GPE_CPU_HOTPLUG).unwrap(),
+        );
+        hotplug.plug(1).unwrap();
+        assert!(!gpe.is_pending(GPE_CPU_HOTPLUG).unwrap());
+        hotplug.notify_add(1).unwrap();
+        assert!(gpe.is_pending(GPE_CPU_HOTPLUG).unwrap());
+    }
+}
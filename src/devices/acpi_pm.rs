@@ -0,0 +1,291 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! ACPI PM1 event and control registers, as described by the `PM1a_EVT_BLK` and
+//! `PM1a_CNT_BLK` fields of the FADT.
+//!
+//! The guest enters a sleep state by writing one of the `SLP_TYP` values of its `_Sx` DSDT
+//! packages to the control register, together with `SLP_EN`. The
+//! [`AcpiPm`](struct.AcpiPm.html) block translates the request into a `VmEvent::Sleep`
+//! event, or `VmEvent::Shutdown` for the soft off state. The VMM then stops the vCPUs and
+//! calls `IoManager::suspend_devices`. When the VM wakes up, the VMM calls
+//! [`wake`](struct.AcpiPm.html#method.wake) so the guest finds `WAK_STS` set, and
+//! `IoManager::resume_devices` to re-initialize the devices which lost their context.
+
+use std::sync::Mutex;
+
+use crate::bus::{PioAddress, PioAddressValue, PioOffset};
+use crate::events::{VmEvent, VmEventSender};
+use crate::interrupt::LineInterrupt;
+use crate::lifecycle::{Lifecycle, SleepState};
+use crate::DevicePio;
+
+/// I/O port of the PM registers on the QEMU `q35` machine.
+pub const ACPI_PM_DEFAULT_PORT: PioAddressValue = 0x600;
+
+/// Size of the register block: the status, enable, and control registers, with padding.
+pub const ACPI_PM_SIZE: PioAddressValue = 0x8;
+
+const PM1_STS: u64 = 0x0;
+const PM1_EN: u64 = 0x2;
+const PM1_CNT: u64 = 0x4;
+
+/// Power management timer status and enable bits.
+pub const PM1_TMR: u16 = 1 << 0;
+/// Global lock release status and enable bits.
+pub const PM1_GBL: u16 = 1 << 5;
+/// Power button status and enable bits.
+pub const PM1_PWRBTN: u16 = 1 << 8;
+/// Sleep button status and enable bits.
+pub const PM1_SLPBTN: u16 = 1 << 9;
+/// RTC alarm status and enable bits.
+pub const PM1_RTC: u16 = 1 << 10;
+const PM1_WAK_STS: u16 = 1 << 15;
+const PM1_EVENTS: u16 = PM1_TMR | PM1_GBL | PM1_PWRBTN | PM1_SLPBTN | PM1_RTC;
+
+const CNT_SCI_EN: u16 = 1 << 0;
+const CNT_SLP_TYP_SHIFT: u16 = 10;
+const CNT_SLP_TYP_MASK: u16 = 0x7;
+const CNT_SLP_EN: u16 = 1 << 13;
+
+#[derive(Clone, Copy)]
+enum SleepType {
+    Unsupported,
+    Sleep(SleepState),
+    SoftOff,
+}
+
+#[derive(Default)]
+struct State {
+    sts: u16,
+    en: u16,
+    cnt: u16,
+    sleeping: Option<SleepState>,
+}
+
+/// Model of the ACPI PM1 register blocks.
+pub struct AcpiPm {
+    events: VmEventSender,
+    sci: Option<LineInterrupt>,
+    sleep_types: [SleepType; 8],
+    state: Mutex<State>,
+}
+
+impl AcpiPm {
+    /// Create a new register block, sending the sleep requests of the guest through
+    /// `events`. The `SLP_TYP` values are the ones used by QEMU: 0 for S5, 1 for S3, and 2
+    /// for S4.
+    pub fn new(events: VmEventSender) -> Self {
+        let mut sleep_types = [SleepType::Unsupported; 8];
+        sleep_types[0] = SleepType::SoftOff;
+        sleep_types[1] = SleepType::Sleep(SleepState::S3);
+        sleep_types[2] = SleepType::Sleep(SleepState::S4);
+        AcpiPm {
+            events,
+            sci: None,
+            sleep_types,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Raise `sci` while an enabled event is pending.
+    pub fn with_sci(mut self, sci: LineInterrupt) -> Self {
+        self.sci = Some(sci);
+        self
+    }
+
+    /// Enter `state` when the guest writes `slp_typ`, which has to match the `_Sx` packages
+    /// of the DSDT.
+    pub fn with_sleep_type(mut self, slp_typ: u8, state: SleepState) -> Self {
+        self.sleep_types[usize::from(slp_typ & CNT_SLP_TYP_MASK as u8)] = SleepType::Sleep(state);
+        self
+    }
+
+    /// Power off the VM when the guest writes `slp_typ`.
+    pub fn with_soft_off_type(mut self, slp_typ: u8) -> Self {
+        self.sleep_types[usize::from(slp_typ & CNT_SLP_TYP_MASK as u8)] = SleepType::SoftOff;
+        self
+    }
+
+    /// Latch the `events` (a combination of the `PM1_*` bits), e.g. `PM1_PWRBTN` when the
+    /// power button is pressed.
+    pub fn raise(&self, events: u16) {
+        let mut state = self.state.lock().unwrap();
+        state.sts |= events & PM1_EVENTS;
+        self.update_sci(&state);
+    }
+
+    /// Return the sleep state the guest entered, if it is sleeping.
+    pub fn sleeping(&self) -> Option<SleepState> {
+        self.state.lock().unwrap().sleeping
+    }
+
+    /// Wake up the guest, and return the sleep state it was in.
+    pub fn wake(&self) -> Option<SleepState> {
+        let mut state = self.state.lock().unwrap();
+        let sleeping = state.sleeping.take();
+        if sleeping.is_some() {
+            state.sts |= PM1_WAK_STS;
+        }
+        sleeping
+    }
+
+    // Drive the SCI with the enabled pending events.
+    fn update_sci(&self, state: &State) {
+        if let Some(sci) = self.sci.as_ref() {
+            if state.cnt & CNT_SCI_EN != 0 && state.sts & state.en & PM1_EVENTS != 0 {
+                sci.assert();
+            } else {
+                sci.deassert();
+            }
+        }
+    }
+
+    fn sleep(&self, state: &mut State) {
+        let slp_typ = (state.cnt >> CNT_SLP_TYP_SHIFT) & CNT_SLP_TYP_MASK;
+        let event = match self.sleep_types[usize::from(slp_typ)] {
+            SleepType::Unsupported => return,
+            SleepType::Sleep(sleep_state) => {
+                state.sleeping = Some(sleep_state);
+                VmEvent::Sleep(sleep_state)
+            }
+            SleepType::SoftOff => VmEvent::Shutdown,
+        };
+        let _ = self.events.send(event);
+    }
+
+    // Update the bits of the register at `reg` selected by `mask` with `value`.
+    fn write_reg(&self, state: &mut State, reg: u64, value: u16, mask: u16) {
+        match reg {
+            // The status bits are cleared by writing ones.
+            PM1_STS => state.sts &= !(value & mask),
+            PM1_EN => state.en = (state.en & !mask) | (value & mask & PM1_EVENTS),
+            PM1_CNT => {
+                state.cnt = (state.cnt & !mask) | (value & mask & !CNT_SLP_EN);
+                if value & mask & CNT_SLP_EN != 0 {
+                    self.sleep(state);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Lifecycle for AcpiPm {
+    fn reset(&self) {
+        let mut state = self.state.lock().unwrap();
+        *state = State::default();
+        self.update_sci(&state);
+    }
+}
+
+impl DevicePio for AcpiPm {
+    fn pio_read(&self, _base: PioAddress, offset: PioOffset, data: &mut [u8]) {
+        let state = self.state.lock().unwrap();
+        let mut regs = [0; ACPI_PM_SIZE as usize];
+        regs[PM1_STS as usize..PM1_EN as usize].copy_from_slice(&state.sts.to_le_bytes());
+        regs[PM1_EN as usize..PM1_CNT as usize].copy_from_slice(&state.en.to_le_bytes());
+        regs[PM1_CNT as usize..PM1_CNT as usize + 2].copy_from_slice(&state.cnt.to_le_bytes());
+        let start = u64::from(offset) as usize;
+        for (idx, byte) in data.iter_mut().enumerate() {
+            *byte = regs.get(start + idx).copied().unwrap_or(0);
+        }
+    }
+
+    fn pio_write(&self, _base: PioAddress, offset: PioOffset, data: &[u8]) {
+        // Gather the bytes of each register, so `SLP_EN` acts on the `SLP_TYP` value written
+        // along with it.
+        let mut values = [(0u16, 0u16); ACPI_PM_SIZE as usize / 2];
+        for (idx, byte) in data.iter().enumerate() {
+            let addr = u64::from(offset) as usize + idx;
+            if let Some((value, mask)) = values.get_mut(addr / 2) {
+                let shift = (addr % 2) * 8;
+                *value |= u16::from(*byte) << shift;
+                *mask |= 0xff << shift;
+            }
+        }
+        let mut state = self.state.lock().unwrap();
+        for (idx, (value, mask)) in values.iter().enumerate() {
+            if *mask != 0 {
+                self.write_reg(&mut state, idx as u64 * 2, *value, *mask);
+            }
+        }
+        self.update_sci(&state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::events::vm_event_channel;
+    use crate::interrupt::{IrqRouter, TriggerMode};
+
+    fn read(pm: &AcpiPm, offset: u64) -> u16 {
+        let mut data = [0; 2];
+        pm.pio_read(
+            PioAddress(ACPI_PM_DEFAULT_PORT),
+            PioOffset(offset as PioAddressValue),
+            &mut data,
+        );
+        u16::from_le_bytes(data)
+    }
+
+    fn write(pm: &AcpiPm, offset: u64, value: u16) {
+        pm.pio_write(
+            PioAddress(ACPI_PM_DEFAULT_PORT),
+            PioOffset(offset as PioAddressValue),
+            &value.to_le_bytes(),
+        );
+    }
+
+    #[test]
+    fn test_acpi_pm() {
+        let (tx, rx) = vm_event_channel();
+        let router = IrqRouter::new(Arc::new(|_, _| {}));
+        let pm = AcpiPm::new(tx.for_device("pm"))
+            .with_sci(router.line(9, TriggerMode::Level).unwrap())
+            .with_sleep_type(5, SleepState::S3);
+
+        // The power button raises the SCI once the guest enables it.
+        pm.raise(PM1_PWRBTN);
+        assert_eq!(read(&pm, PM1_STS), PM1_PWRBTN);
+        assert!(!router.level(9));
+        write(&pm, PM1_EN, PM1_PWRBTN);
+        write(&pm, PM1_CNT, CNT_SCI_EN);
+        assert!(router.level(9));
+        write(&pm, PM1_STS, PM1_PWRBTN);
+        assert_eq!(read(&pm, PM1_STS), 0);
+        assert!(!router.level(9));
+
+        // Suspend to RAM, through both the default and the custom sleep types.
+        for slp_typ in [1, 5].iter() {
+            write(
+                &pm,
+                PM1_CNT,
+                CNT_SCI_EN | (slp_typ << CNT_SLP_TYP_SHIFT) | CNT_SLP_EN,
+            );
+            assert_eq!(rx.try_recv().unwrap().event, VmEvent::Sleep(SleepState::S3));
+            assert_eq!(read(&pm, PM1_CNT) & CNT_SLP_EN, 0);
+            assert_eq!(pm.sleeping(), Some(SleepState::S3));
+            assert_eq!(pm.wake(), Some(SleepState::S3));
+            assert_eq!(read(&pm, PM1_STS), PM1_WAK_STS);
+            write(&pm, PM1_STS, PM1_WAK_STS);
+        }
+        assert_eq!(pm.wake(), None);
+
+        // Writes without `SLP_EN`, or with unsupported sleep types, are ignored.
+        write(&pm, PM1_CNT, 2 << CNT_SLP_TYP_SHIFT);
+        write(&pm, PM1_CNT, (7 << CNT_SLP_TYP_SHIFT) | CNT_SLP_EN);
+        assert!(rx.try_recv().is_none());
+        write(&pm, PM1_CNT, CNT_SLP_EN);
+        assert_eq!(rx.try_recv().unwrap().event, VmEvent::Shutdown);
+
+        pm.raise(PM1_PWRBTN);
+        pm.reset();
+        assert_eq!(read(&pm, PM1_STS), 0);
+        assert_eq!(read(&pm, PM1_EN), 0);
+    }
+}
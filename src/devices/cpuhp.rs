@@ -0,0 +1,363 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! ACPI CPU hotplug controller, compatible with the register block used by the `CPUS`
+//! methods of the usual DSDT (the "modern" CPU hotplug interface of QEMU).
+//!
+//! The guest selects a vCPU through the selector register, and then reads its status, acks
+//! its insert and remove events, or ejects it. Adding a vCPU takes two steps:
+//! [`plug`](struct.AcpiCpuHotplug.html#method.plug) sends a `VmEvent::AddCpu` event asking the
+//! VMM to create the vCPU, and `HotplugNotifier::notify_add` then lets the guest know about
+//! it. Removal is requested with `HotplugNotifier::notify_remove`, and the controller sends a
+//! `VmEvent::RemoveCpu` event once the guest ejects the vCPU.
+
+use std::sync::Mutex;
+
+use crate::bus::{PioAddress, PioAddressValue, PioOffset};
+use crate::events::{VmEvent, VmEventSender};
+use crate::hotplug::{Error, HotplugNotifier};
+use crate::DevicePio;
+
+/// I/O port of the register block on the QEMU `pc` and `q35` machines.
+pub const CPUHP_DEFAULT_PORT: PioAddressValue = 0x0cd8;
+
+/// Size of the register block.
+pub const CPUHP_SIZE: PioAddressValue = 0x0c;
+
+// Read: upper half of the architectural ID. Write: vCPU selector.
+const CMD_DATA2: u64 = 0x00;
+const SELECTOR: u64 = 0x00;
+// Read: status of the selected vCPU. Write: control of the selected vCPU.
+const STATUS: u64 = 0x04;
+const CONTROL: u64 = 0x04;
+const COMMAND: u64 = 0x05;
+const CMD_DATA: u64 = 0x08;
+
+const STATUS_ENABLED: u8 = 1 << 0;
+const STATUS_INSERT: u8 = 1 << 1;
+const STATUS_REMOVE: u8 = 1 << 2;
+const CONTROL_EJECT: u8 = 1 << 3;
+
+// Select the next vCPU with a pending event.
+const CMD_NEXT_EVENT: u8 = 0;
+const CMD_GET_ARCH_ID: u8 = 3;
+
+#[derive(Default)]
+struct Cpu {
+    arch_id: u64,
+    // The VMM was asked to create the vCPU.
+    plugged: bool,
+    enabled: bool,
+    insert: bool,
+    remove: bool,
+}
+
+struct State {
+    selector: u32,
+    command: u8,
+    cpus: Vec<Cpu>,
+    ejected: Vec<u32>,
+}
+
+impl State {
+    fn selected(&mut self) -> Option<&mut Cpu> {
+        self.cpus.get_mut(self.selector as usize)
+    }
+
+    // Select the first vCPU with a pending event, starting from the current selection.
+    fn select_next_event(&mut self) {
+        let count = self.cpus.len();
+        let start = self.selector as usize;
+        if let Some(cpu) = (0..count)
+            .map(|idx| (start + idx) % count)
+            .find(|cpu| self.cpus[*cpu].insert || self.cpus[*cpu].remove)
+        {
+            self.selector = cpu as u32;
+        }
+    }
+}
+
+/// ACPI CPU hotplug register block.
+pub struct AcpiCpuHotplug {
+    events: VmEventSender,
+    notify_guest: Box<dyn Fn() + Send + Sync>,
+    state: Mutex<State>,
+}
+
+impl AcpiCpuHotplug {
+    /// Create a new controller for up to `max_cpus` vCPUs, whose architectural IDs are their
+    /// indices. The first `boot_cpus` are enabled. The vCPU events are sent through
+    /// `events`, and `notify_guest` is invoked to raise the notification interrupt (usually
+    /// the ACPI SCI) whenever a new guest event becomes pending.
+    pub fn new(
+        max_cpus: u32,
+        boot_cpus: u32,
+        events: VmEventSender,
+        notify_guest: Box<dyn Fn() + Send + Sync>,
+    ) -> Self {
+        let cpus = (0..max_cpus)
+            .map(|cpu| Cpu {
+                arch_id: u64::from(cpu),
+                plugged: cpu < boot_cpus,
+                enabled: cpu < boot_cpus,
+                ..Default::default()
+            })
+            .collect();
+        AcpiCpuHotplug {
+            events,
+            notify_guest,
+            state: Mutex::new(State {
+                selector: 0,
+                command: CMD_NEXT_EVENT,
+                cpus,
+                ejected: Vec::new(),
+            }),
+        }
+    }
+
+    /// Use `arch_ids` as the architectural IDs of the vCPUs, in order.
+    pub fn with_arch_ids(self, arch_ids: &[u64]) -> Self {
+        {
+            let mut state = self.state.lock().unwrap();
+            for (cpu, arch_id) in state.cpus.iter_mut().zip(arch_ids) {
+                cpu.arch_id = *arch_id;
+            }
+        }
+        self
+    }
+
+    /// Ask the VMM to create the vCPU `cpu`, which isn't visible to the guest until
+    /// `notify_add` is called for it.
+    pub fn plug(&self, cpu: u32) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        let entry = state
+            .cpus
+            .get_mut(cpu as usize)
+            .filter(|entry| !entry.plugged)
+            .ok_or(Error::InvalidSlot(cpu))?;
+        entry.plugged = true;
+        let _ = self.events.send(VmEvent::AddCpu {
+            cpu,
+            arch_id: entry.arch_id,
+        });
+        Ok(())
+    }
+
+    /// Return whether the vCPU `cpu` is in use by the guest.
+    pub fn is_enabled(&self, cpu: u32) -> bool {
+        let state = self.state.lock().unwrap();
+        state.cpus.get(cpu as usize).is_some_and(|cpu| cpu.enabled)
+    }
+
+    fn read(&self, offset: u64, data: &mut [u8]) {
+        let mut state = self.state.lock().unwrap();
+        let command = state.command;
+        let selector = state.selector;
+        let value = match (state.selected(), offset & !0x3) {
+            (Some(cpu), CMD_DATA2) if command == CMD_GET_ARCH_ID => (cpu.arch_id >> 32) as u32,
+            (Some(cpu), STATUS) => {
+                let mut status = 0;
+                if cpu.enabled {
+                    status |= STATUS_ENABLED;
+                }
+                if cpu.insert {
+                    status |= STATUS_INSERT;
+                }
+                if cpu.remove {
+                    status |= STATUS_REMOVE;
+                }
+                u32::from(status)
+            }
+            (Some(_), CMD_DATA) if command == CMD_NEXT_EVENT => selector,
+            (Some(cpu), CMD_DATA) if command == CMD_GET_ARCH_ID => cpu.arch_id as u32,
+            _ => 0,
+        };
+        let bytes = value.to_le_bytes();
+        let start = (offset & 0x3) as usize;
+        for (idx, byte) in data.iter_mut().enumerate() {
+            *byte = bytes.get(start + idx).copied().unwrap_or(0);
+        }
+    }
+
+    fn write(&self, offset: u64, data: &[u8]) {
+        let mut bytes = [0; 4];
+        let len = data.len().min(4);
+        bytes[..len].copy_from_slice(&data[..len]);
+        let value = u32::from_le_bytes(bytes);
+
+        let mut state = self.state.lock().unwrap();
+        match offset {
+            SELECTOR => state.selector = value,
+            CONTROL => {
+                let selector = state.selector;
+                let cpu = match state.selected() {
+                    Some(cpu) => cpu,
+                    None => return,
+                };
+                let control = value as u8;
+                if control & STATUS_INSERT != 0 {
+                    cpu.insert = false;
+                }
+                if control & STATUS_REMOVE != 0 {
+                    cpu.remove = false;
+                }
+                if control & CONTROL_EJECT != 0 && cpu.enabled {
+                    cpu.enabled = false;
+                    cpu.plugged = false;
+                    cpu.remove = false;
+                    let event = VmEvent::RemoveCpu {
+                        cpu: selector,
+                        arch_id: cpu.arch_id,
+                    };
+                    state.ejected.push(selector);
+                    let _ = self.events.send(event);
+                }
+            }
+            COMMAND => {
+                state.command = value as u8;
+                if state.command == CMD_NEXT_EVENT {
+                    state.select_next_event();
+                }
+            }
+            // The `_OST` status reports are only informative.
+            _ => {}
+        }
+    }
+}
+
+impl HotplugNotifier for AcpiCpuHotplug {
+    fn notify_add(&self, slot: u32) -> Result<(), Error> {
+        {
+            let mut state = self.state.lock().unwrap();
+            let cpu = state
+                .cpus
+                .get_mut(slot as usize)
+                .filter(|cpu| cpu.plugged && !cpu.enabled)
+                .ok_or(Error::InvalidSlot(slot))?;
+            cpu.enabled = true;
+            cpu.insert = true;
+        }
+        (self.notify_guest)();
+        Ok(())
+    }
+
+    fn notify_remove(&self, slot: u32) -> Result<(), Error> {
+        {
+            let mut state = self.state.lock().unwrap();
+            let cpu = state
+                .cpus
+                .get_mut(slot as usize)
+                .filter(|cpu| cpu.enabled)
+                .ok_or(Error::InvalidSlot(slot))?;
+            if cpu.remove {
+                return Err(Error::UnplugPending(slot));
+            }
+            cpu.remove = true;
+        }
+        (self.notify_guest)();
+        Ok(())
+    }
+
+    fn take_ejected(&self) -> Vec<u32> {
+        std::mem::take(&mut self.state.lock().unwrap().ejected)
+    }
+}
+
+impl DevicePio for AcpiCpuHotplug {
+    fn pio_read(&self, _base: PioAddress, offset: PioOffset, data: &mut [u8]) {
+        self.read(u64::from(offset), data);
+    }
+
+    fn pio_write(&self, _base: PioAddress, offset: PioOffset, data: &[u8]) {
+        self.write(u64::from(offset), data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use crate::events::vm_event_channel;
+
+    fn read(hotplug: &AcpiCpuHotplug, offset: u64, len: usize) -> u32 {
+        let mut data = [0; 4];
+        hotplug.pio_read(
+            PioAddress(CPUHP_DEFAULT_PORT),
+            PioOffset(offset as PioAddressValue),
+            &mut data[..len],
+        );
+        u32::from_le_bytes(data)
+    }
+
+    fn write(hotplug: &AcpiCpuHotplug, offset: u64, data: &[u8]) {
+        hotplug.pio_write(
+            PioAddress(CPUHP_DEFAULT_PORT),
+            PioOffset(offset as PioAddressValue),
+            data,
+        );
+    }
+
+    #[test]
+    fn test_acpi_cpu_hotplug() {
+        let (tx, rx) = vm_event_channel();
+        let count = Arc::new(AtomicUsize::new(0));
+        let count_clone = count.clone();
+        let hotplug = AcpiCpuHotplug::new(
+            4,
+            2,
+            tx.for_device("cpuhp"),
+            Box::new(move || {
+                count_clone.fetch_add(1, Ordering::SeqCst);
+            }),
+        )
+        .with_arch_ids(&[0, 2, 4, 0x1_0000_0006]);
+        assert!(hotplug.is_enabled(1));
+        assert_eq!(hotplug.notify_add(1), Err(Error::InvalidSlot(1)));
+        assert_eq!(hotplug.notify_add(3), Err(Error::InvalidSlot(3)));
+
+        // The vCPU is created before the guest is told about it.
+        hotplug.plug(3).unwrap();
+        assert_eq!(hotplug.plug(3), Err(Error::InvalidSlot(3)));
+        assert_eq!(
+            rx.try_recv().unwrap().event,
+            VmEvent::AddCpu {
+                cpu: 3,
+                arch_id: 0x1_0000_0006
+            }
+        );
+        hotplug.notify_add(3).unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+
+        // The guest looks for the vCPU with the pending event, and reads its ID.
+        write(&hotplug, SELECTOR, &0u32.to_le_bytes());
+        write(&hotplug, COMMAND, &[CMD_NEXT_EVENT]);
+        assert_eq!(read(&hotplug, CMD_DATA, 4), 3);
+        assert_eq!(
+            read(&hotplug, STATUS, 1),
+            u32::from(STATUS_ENABLED | STATUS_INSERT)
+        );
+        write(&hotplug, COMMAND, &[CMD_GET_ARCH_ID]);
+        assert_eq!(read(&hotplug, CMD_DATA, 4), 6);
+        assert_eq!(read(&hotplug, CMD_DATA2, 4), 1);
+        write(&hotplug, CONTROL, &[STATUS_INSERT]);
+        assert_eq!(read(&hotplug, STATUS, 1), u32::from(STATUS_ENABLED));
+
+        hotplug.notify_remove(3).unwrap();
+        assert_eq!(hotplug.notify_remove(3), Err(Error::UnplugPending(3)));
+        assert_eq!(hotplug.notify_remove(2), Err(Error::InvalidSlot(2)));
+        write(&hotplug, CONTROL, &[CONTROL_EJECT]);
+        assert!(!hotplug.is_enabled(3));
+        assert_eq!(
+            rx.try_recv().unwrap().event,
+            VmEvent::RemoveCpu {
+                cpu: 3,
+                arch_id: 0x1_0000_0006
+            }
+        );
+        assert_eq!(hotplug.take_ejected(), vec![3]);
+        assert!(hotplug.take_ejected().is_empty());
+    }
+}
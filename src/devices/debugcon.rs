@@ -0,0 +1,143 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Guest debug console, compatible with the Bochs/QEMU `debugcon` port.
+//!
+//! Every byte written by the guest is forwarded to a `Write` sink. Reading the port returns
+//! `0xe9`, which guests use to detect the console. The device can be registered on the PIO
+//! bus (usually at [`DEBUGCON_PORT`](constant.DEBUGCON_PORT.html)), the MMIO bus, or both.
+
+use std::io::Write;
+use std::sync::Arc;
+
+use crate::bus::{MmioAddress, MmioOffset, PioAddress, PioAddressValue, PioOffset};
+use crate::time::Clock;
+use crate::{MutDeviceMmio, MutDevicePio};
+
+/// The conventional debug console port.
+pub const DEBUGCON_PORT: PioAddressValue = 0xe9;
+
+// Value returned by reads, which signals the presence of the console.
+const DEBUGCON_READBACK: u8 = 0xe9;
+
+const NS_PER_SEC: u64 = 1_000_000_000;
+
+struct RateLimit {
+    bytes_per_sec: u64,
+    clock: Arc<dyn Clock>,
+    window_start: u64,
+    window_bytes: u64,
+}
+
+/// Debug console which forwards the output of the guest to a sink.
+pub struct DebugCon<W: Write> {
+    sink: W,
+    rate_limit: Option<RateLimit>,
+    dropped: u64,
+}
+
+impl<W: Write> DebugCon<W> {
+    /// Create a new console which writes the output of the guest to `sink`.
+    pub fn new(sink: W) -> Self {
+        DebugCon {
+            sink,
+            rate_limit: None,
+            dropped: 0,
+        }
+    }
+
+    /// Only let the guest write `bytes_per_sec` bytes each second, as measured by `clock`.
+    /// The rest of the output is dropped, so a chatty guest can't flood the sink.
+    pub fn with_rate_limit(mut self, bytes_per_sec: u64, clock: Arc<dyn Clock>) -> Self {
+        self.rate_limit = Some(RateLimit {
+            bytes_per_sec,
+            window_start: clock.now(),
+            window_bytes: 0,
+            clock,
+        });
+        self
+    }
+
+    /// Return the number of bytes dropped because of the rate limit or sink errors.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Return a reference to the sink.
+    pub fn sink(&self) -> &W {
+        &self.sink
+    }
+
+    // Return how many bytes out of `len` can be written right now.
+    fn allowance(&mut self, len: usize) -> usize {
+        let limit = match self.rate_limit.as_mut() {
+            Some(limit) => limit,
+            None => return len,
+        };
+        let now = limit.clock.now();
+        if now.saturating_sub(limit.window_start) >= NS_PER_SEC {
+            limit.window_start = now;
+            limit.window_bytes = 0;
+        }
+        let left = limit.bytes_per_sec.saturating_sub(limit.window_bytes);
+        let allowed = (len as u64).min(left);
+        limit.window_bytes += allowed;
+        allowed as usize
+    }
+
+    fn output(&mut self, data: &[u8]) {
+        let allowed = self.allowance(data.len());
+        self.dropped += (data.len() - allowed) as u64;
+        if allowed > 0 && self.sink.write_all(&data[..allowed]).is_err() {
+            self.dropped += allowed as u64;
+        }
+    }
+}
+
+impl<W: Write> MutDevicePio for DebugCon<W> {
+    fn pio_read(&mut self, _base: PioAddress, _offset: PioOffset, data: &mut [u8]) {
+        data.iter_mut().for_each(|byte| *byte = DEBUGCON_READBACK);
+    }
+
+    fn pio_write(&mut self, _base: PioAddress, _offset: PioOffset, data: &[u8]) {
+        self.output(data);
+    }
+}
+
+impl<W: Write> MutDeviceMmio for DebugCon<W> {
+    fn mmio_read(&mut self, _base: MmioAddress, _offset: MmioOffset, data: &mut [u8]) {
+        data.iter_mut().for_each(|byte| *byte = DEBUGCON_READBACK);
+    }
+
+    fn mmio_write(&mut self, _base: MmioAddress, _offset: MmioOffset, data: &[u8]) {
+        self.output(data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::time::ManualClock;
+
+    #[test]
+    fn test_debugcon() {
+        let clock = Arc::new(ManualClock::new());
+        let mut con = DebugCon::new(Vec::new()).with_rate_limit(4, clock.clone());
+        let base = PioAddress(DEBUGCON_PORT);
+
+        let mut data = [0u8];
+        con.pio_read(base, PioOffset(0), &mut data);
+        assert_eq!(data, [DEBUGCON_READBACK]);
+
+        for byte in b"hello".iter() {
+            con.pio_write(base, PioOffset(0), &[*byte]);
+        }
+        assert_eq!(con.sink().as_slice(), b"hell");
+        assert_eq!(con.dropped(), 1);
+
+        clock.advance(NS_PER_SEC);
+        con.mmio_write(MmioAddress(0x1000), MmioOffset(0), b"o\n");
+        assert_eq!(con.sink().as_slice(), b"hello\n");
+    }
+}
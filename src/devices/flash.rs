@@ -0,0 +1,347 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! CFI compatible parallel flash, as used for the UEFI firmware code and variable store.
+//!
+//! The [`Flash`](struct.Flash.html) model implements the Intel/Sharp command set (CFI
+//! primary command set 0x0001) on an 8-bit wide device: read array, read identifier, CFI
+//! query, status register reads, single byte programming, and block erase. The contents are
+//! kept in memory, and can be written through to a file so that the variable store persists
+//! across runs.
+
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::Arc;
+
+use crate::bus::{MmioAddress, MmioOffset};
+use crate::dirty::{self, DirtyBitmap};
+use crate::MutDeviceMmio;
+
+/// Errors encountered while setting up a flash device.
+#[derive(Debug)]
+pub enum Error {
+    /// The size of the image is not a non-zero multiple of the sector size.
+    InvalidSize(u64, u64),
+    /// Failed to set up dirty tracking.
+    DirtyBitmap(dirty::Error),
+    /// Failed to access the backing file.
+    Io(io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::InvalidSize(size, sector_size) => write!(
+                f,
+                "flash size {:#x} is not a multiple of the sector size {:#x}",
+                size, sector_size
+            ),
+            Error::DirtyBitmap(_) => write!(f, "failed to set up flash dirty tracking"),
+            Error::Io(_) => write!(f, "flash backing file error"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::DirtyBitmap(e) => Some(e),
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+const CMD_READ_ARRAY: u8 = 0xff;
+const CMD_READ_ID: u8 = 0x90;
+const CMD_QUERY: u8 = 0x98;
+const CMD_READ_STATUS: u8 = 0x70;
+const CMD_CLEAR_STATUS: u8 = 0x50;
+const CMD_PROGRAM: u8 = 0x40;
+const CMD_PROGRAM_ALT: u8 = 0x10;
+const CMD_ERASE: u8 = 0x20;
+const CMD_CONFIRM: u8 = 0xd0;
+const CMD_LOCK_SETUP: u8 = 0x60;
+
+const STATUS_READY: u8 = 1 << 7;
+const STATUS_ERASE_ERROR: u8 = 1 << 5;
+const STATUS_PROGRAM_ERROR: u8 = 1 << 4;
+const STATUS_PROTECTED: u8 = 1 << 1;
+
+const MANUFACTURER_ID: u8 = 0x89;
+const DEVICE_ID: u8 = 0x18;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Mode {
+    ReadArray,
+    ReadId,
+    Query,
+    ReadStatus,
+    // The next write holds the data to program.
+    Program,
+    // The next write confirms the erase of the block containing the address.
+    EraseSetup,
+    // The next write selects the lock operation, which is ignored.
+    LockSetup,
+}
+
+/// Model of a CFI flash device.
+pub struct Flash {
+    data: Vec<u8>,
+    sector_size: u64,
+    read_only: bool,
+    file: Option<File>,
+    dirty: Option<Arc<DirtyBitmap>>,
+    mode: Mode,
+    status: u8,
+    query: Vec<u8>,
+}
+
+impl Flash {
+    /// Create a flash device with the contents of `data`, erased in blocks of `sector_size`
+    /// bytes.
+    pub fn new(data: Vec<u8>, sector_size: u64) -> Result<Self, Error> {
+        let size = data.len() as u64;
+        if sector_size == 0 || size == 0 || !size.is_multiple_of(sector_size) {
+            return Err(Error::InvalidSize(size, sector_size));
+        }
+        Ok(Flash {
+            query: Self::query_table(size, sector_size),
+            data,
+            sector_size,
+            read_only: false,
+            file: None,
+            dirty: None,
+            mode: Mode::ReadArray,
+            status: STATUS_READY,
+        })
+    }
+
+    /// Create a flash device backed by `file`. The contents are read when the device is
+    /// created, and every change made by the guest is written back to the file.
+    pub fn from_file(mut file: File, sector_size: u64) -> Result<Self, Error> {
+        let mut data = Vec::new();
+        file.seek(SeekFrom::Start(0)).map_err(Error::Io)?;
+        file.read_to_end(&mut data).map_err(Error::Io)?;
+        let mut flash = Self::new(data, sector_size)?;
+        flash.file = Some(file);
+        Ok(flash)
+    }
+
+    /// Reject programming and erase commands, as needed for the firmware code region.
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Track the pages of `page_size` bytes changed by programming and erase commands.
+    pub fn with_dirty_tracking(mut self, page_size: u64) -> Result<Self, Error> {
+        let bitmap = DirtyBitmap::new(self.size(), page_size).map_err(Error::DirtyBitmap)?;
+        self.dirty = Some(Arc::new(bitmap));
+        Ok(self)
+    }
+
+    /// Return the bitmap of the pages changed by the guest, if dirty tracking is enabled.
+    pub fn dirty_bitmap(&self) -> Option<Arc<DirtyBitmap>> {
+        self.dirty.clone()
+    }
+
+    /// Return the size of the device.
+    pub fn size(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    /// Return the current contents of the device.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    // Build the CFI query structure, which is mapped at offset 0x10.
+    fn query_table(size: u64, sector_size: u64) -> Vec<u8> {
+        let blocks = size / sector_size - 1;
+        let block_size = sector_size / 256;
+        let mut table = vec![0u8; 0x31];
+        table[0x10..0x13].copy_from_slice(b"QRY");
+        // Primary command set and extended table address.
+        table[0x13] = 0x01;
+        table[0x15] = 0x31;
+        // Vcc and Vpp voltages.
+        table[0x1b] = 0x45;
+        table[0x1c] = 0x55;
+        // Typical and maximum program and erase timeouts.
+        table[0x1f] = 0x01;
+        table[0x21] = 0x01;
+        table[0x23] = 0x01;
+        table[0x25] = 0x01;
+        table[0x27] = 63 - size.leading_zeros() as u8;
+        // 8-bit interface, no write buffer, and a single erase block region.
+        table[0x2c] = 0x01;
+        table[0x2d] = blocks as u8;
+        table[0x2e] = (blocks >> 8) as u8;
+        table[0x2f] = block_size as u8;
+        table[0x30] = (block_size >> 8) as u8;
+        table
+    }
+
+    fn persist(&mut self, offset: u64, len: u64) {
+        if let Some(bitmap) = self.dirty.as_ref() {
+            bitmap.mark(offset, len);
+        }
+        let data = &self.data[offset as usize..(offset + len) as usize];
+        if let Some(file) = self.file.as_mut() {
+            let res = file
+                .seek(SeekFrom::Start(offset))
+                .and_then(|_| file.write_all(data));
+            if res.is_err() {
+                self.status |= STATUS_PROGRAM_ERROR;
+            }
+        }
+    }
+
+    fn read_byte(&self, offset: u64) -> u8 {
+        match self.mode {
+            Mode::ReadArray => self.data.get(offset as usize).copied().unwrap_or(0xff),
+            Mode::ReadId => match offset & 0xff {
+                0 => MANUFACTURER_ID,
+                1 => DEVICE_ID,
+                _ => 0,
+            },
+            Mode::Query => self.query.get(offset as usize).copied().unwrap_or(0),
+            _ => self.status,
+        }
+    }
+
+    fn program(&mut self, offset: u64, value: u8) {
+        if self.read_only || offset >= self.size() {
+            self.status |= STATUS_PROGRAM_ERROR | STATUS_PROTECTED;
+            return;
+        }
+        // Programming can only clear bits; setting them back requires an erase.
+        self.data[offset as usize] &= value;
+        self.persist(offset, 1);
+    }
+
+    fn erase(&mut self, offset: u64) {
+        if self.read_only || offset >= self.size() {
+            self.status |= STATUS_ERASE_ERROR | STATUS_PROTECTED;
+            return;
+        }
+        let start = offset - offset % self.sector_size;
+        self.data[start as usize..(start + self.sector_size) as usize]
+            .iter_mut()
+            .for_each(|byte| *byte = 0xff);
+        self.persist(start, self.sector_size);
+    }
+
+    fn write_byte(&mut self, offset: u64, value: u8) {
+        self.mode = match self.mode {
+            Mode::EraseSetup if value == CMD_CONFIRM => {
+                self.erase(offset);
+                Mode::ReadStatus
+            }
+            Mode::EraseSetup => {
+                self.status |= STATUS_ERASE_ERROR | STATUS_PROGRAM_ERROR;
+                Mode::ReadStatus
+            }
+            Mode::LockSetup => Mode::ReadStatus,
+            _ => match value {
+                CMD_READ_ID => Mode::ReadId,
+                CMD_QUERY => Mode::Query,
+                CMD_READ_STATUS => Mode::ReadStatus,
+                CMD_CLEAR_STATUS => {
+                    self.status = STATUS_READY;
+                    self.mode
+                }
+                CMD_PROGRAM | CMD_PROGRAM_ALT => Mode::Program,
+                CMD_ERASE => Mode::EraseSetup,
+                CMD_LOCK_SETUP => Mode::LockSetup,
+                CMD_READ_ARRAY => Mode::ReadArray,
+                // Unknown commands bring the device back to read array mode.
+                _ => Mode::ReadArray,
+            },
+        };
+    }
+}
+
+impl MutDeviceMmio for Flash {
+    fn mmio_read(&mut self, _base: MmioAddress, offset: MmioOffset, data: &mut [u8]) {
+        for (idx, byte) in data.iter_mut().enumerate() {
+            *byte = self.read_byte(offset.raw() + idx as u64);
+        }
+    }
+
+    fn mmio_write(&mut self, _base: MmioAddress, offset: MmioOffset, data: &[u8]) {
+        if self.mode == Mode::Program {
+            // Wide writes program every byte they cover.
+            for (idx, byte) in data.iter().enumerate() {
+                self.program(offset.raw() + idx as u64, *byte);
+            }
+            self.mode = Mode::ReadStatus;
+        } else if let Some(byte) = data.first() {
+            // Commands are decoded from the low byte of the access.
+            self.write_byte(offset.raw(), *byte);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: MmioAddress = MmioAddress(0xffc0_0000);
+    const SECTOR_SIZE: u64 = 0x1000;
+
+    fn read(flash: &mut Flash, offset: u64) -> u8 {
+        let mut data = [0u8];
+        flash.mmio_read(BASE, MmioOffset(offset), &mut data);
+        data[0]
+    }
+
+    #[test]
+    fn test_flash() {
+        assert!(matches!(
+            Flash::new(vec![0; 0x1800], SECTOR_SIZE),
+            Err(Error::InvalidSize(0x1800, SECTOR_SIZE))
+        ));
+
+        let mut flash = Flash::new(vec![0xff; 0x4000], SECTOR_SIZE).unwrap();
+
+        flash.mmio_write(BASE, MmioOffset(0), &[CMD_QUERY]);
+        assert_eq!(read(&mut flash, 0x10), b'Q');
+        assert_eq!(read(&mut flash, 0x27), 14);
+        assert_eq!(read(&mut flash, 0x2d), 3);
+        assert_eq!(read(&mut flash, 0x2f), 0x10);
+        flash.mmio_write(BASE, MmioOffset(0), &[CMD_READ_ID]);
+        assert_eq!(read(&mut flash, 0), MANUFACTURER_ID);
+
+        flash.mmio_write(BASE, MmioOffset(0x1234), &[CMD_PROGRAM]);
+        flash.mmio_write(BASE, MmioOffset(0x1234), &[0x5a]);
+        assert_eq!(read(&mut flash, 0x1234), STATUS_READY);
+        flash.mmio_write(BASE, MmioOffset(0), &[CMD_READ_ARRAY]);
+        assert_eq!(read(&mut flash, 0x1234), 0x5a);
+
+        // Programming can't set bits back.
+        flash.mmio_write(BASE, MmioOffset(0x1234), &[CMD_PROGRAM_ALT]);
+        flash.mmio_write(BASE, MmioOffset(0x1234), &[0xa5]);
+        flash.mmio_write(BASE, MmioOffset(0), &[CMD_READ_ARRAY]);
+        assert_eq!(read(&mut flash, 0x1234), 0x00);
+
+        flash.mmio_write(BASE, MmioOffset(0x1000), &[CMD_ERASE]);
+        flash.mmio_write(BASE, MmioOffset(0x1000), &[CMD_CONFIRM]);
+        flash.mmio_write(BASE, MmioOffset(0), &[CMD_READ_ARRAY]);
+        assert_eq!(read(&mut flash, 0x1234), 0xff);
+
+        // A read-only device reports protection errors.
+        let mut flash = flash.with_read_only(true);
+        flash.mmio_write(BASE, MmioOffset(0), &[CMD_PROGRAM]);
+        flash.mmio_write(BASE, MmioOffset(0), &[0]);
+        assert_eq!(
+            read(&mut flash, 0),
+            STATUS_READY | STATUS_PROGRAM_ERROR | STATUS_PROTECTED
+        );
+        flash.mmio_write(BASE, MmioOffset(0), &[CMD_CLEAR_STATUS]);
+        assert_eq!(read(&mut flash, 0), STATUS_READY);
+        assert_eq!(flash.data()[0], 0xff);
+    }
+}
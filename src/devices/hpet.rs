@@ -0,0 +1,552 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! High Precision Event Timer.
+//!
+//! The [`Hpet`](struct.Hpet.html) model provides a 64-bit main counter running at 100 MHz and
+//! `HPET_NUM_TIMERS` comparators, which support one-shot and periodic operation, edge and
+//! level triggered interrupts routed to an I/O APIC input, the legacy replacement route,
+//! and FSB (MSI) delivery. Time is provided by a `Clock`; expirations are processed when the
+//! VMM calls [`check_timers`](struct.Hpet.html#method.check_timers), typically once
+//! [`next_deadline`](struct.Hpet.html#method.next_deadline) has passed.
+
+use std::sync::Arc;
+
+use crate::bus::{MmioAddress, MmioOffset};
+use crate::time::Clock;
+use crate::MutDeviceMmio;
+
+/// Default guest physical address of the HPET register block.
+pub const HPET_DEFAULT_BASE: u64 = 0xfed0_0000;
+
+/// Size of the HPET register block.
+pub const HPET_SIZE: u64 = 0x400;
+
+/// Number of comparators implemented by the model.
+pub const HPET_NUM_TIMERS: usize = 3;
+
+/// Bitmap of the I/O APIC inputs comparators can be routed to (GSIs 20 to 23).
+pub const HPET_INT_ROUTE_CAP: u32 = 0x00f0_0000;
+
+// Period of the main counter, in femtoseconds (100 MHz).
+const COUNTER_PERIOD_FS: u64 = 10_000_000;
+const NS_PER_TICK: u64 = COUNTER_PERIOD_FS / 1_000_000;
+
+const GCAP_ID: u64 = 0x000;
+const GEN_CONF: u64 = 0x010;
+const GINTR_STA: u64 = 0x020;
+const MAIN_COUNTER: u64 = 0x0f0;
+const TIMER_BASE: u64 = 0x100;
+const TIMER_SIZE: u64 = 0x20;
+const TIMER_CONF: u64 = 0x0;
+const TIMER_COMPARATOR: u64 = 0x8;
+const TIMER_FSB_ROUTE: u64 = 0x10;
+
+const GEN_CONF_ENABLE: u64 = 1 << 0;
+const GEN_CONF_LEGACY: u64 = 1 << 1;
+
+const TN_LEVEL: u64 = 1 << 1;
+const TN_INT_ENABLE: u64 = 1 << 2;
+const TN_PERIODIC: u64 = 1 << 3;
+const TN_PERIODIC_CAP: u64 = 1 << 4;
+const TN_SIZE_CAP: u64 = 1 << 5;
+const TN_VAL_SET: u64 = 1 << 6;
+const TN_FSB_ENABLE: u64 = 1 << 14;
+const TN_FSB_CAP: u64 = 1 << 15;
+const TN_ROUTE_SHIFT: u64 = 9;
+const TN_ROUTE_MASK: u64 = 0x1f << TN_ROUTE_SHIFT;
+// Interrupt type, enable, periodic, route, and FSB enable. The comparators always run in
+// 64-bit mode, so the 32-bit mode bit reads as zero.
+const TN_WRITABLE: u64 = TN_LEVEL | TN_INT_ENABLE | TN_PERIODIC | TN_ROUTE_MASK | TN_FSB_ENABLE;
+
+/// An interrupt generated by the HPET.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HpetInterrupt {
+    /// Change the level of an I/O APIC input. Edge triggered interrupts are reported as an
+    /// assertion immediately followed by a deassertion.
+    Gsi {
+        /// The I/O APIC input.
+        gsi: u32,
+        /// The new level of the input.
+        asserted: bool,
+    },
+    /// Send a message signaled interrupt.
+    Msi {
+        /// Message address.
+        address: u32,
+        /// Message data.
+        data: u32,
+    },
+}
+
+#[derive(Clone, Copy, Default)]
+struct Timer {
+    config: u64,
+    comparator: u64,
+    period: u64,
+    fsb_route: u64,
+    // A one-shot timer only fires once until its comparator or the main counter is written.
+    fired: bool,
+}
+
+/// Model of an HPET block.
+pub struct Hpet {
+    config: u64,
+    status: u64,
+    // Value of the main counter at `counter_start`, or while the counter is halted.
+    counter_offset: u64,
+    counter_start: u64,
+    timers: [Timer; HPET_NUM_TIMERS],
+    clock: Arc<dyn Clock>,
+    interrupt: Box<dyn Fn(HpetInterrupt) + Send + Sync>,
+}
+
+impl Hpet {
+    /// Create a new HPET driven by `clock`, which reports interrupts through `interrupt`.
+    pub fn new(clock: Arc<dyn Clock>, interrupt: Box<dyn Fn(HpetInterrupt) + Send + Sync>) -> Self {
+        Hpet {
+            config: 0,
+            status: 0,
+            counter_offset: 0,
+            counter_start: 0,
+            timers: [Timer {
+                comparator: u64::MAX,
+                ..Default::default()
+            }; HPET_NUM_TIMERS],
+            clock,
+            interrupt,
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.config & GEN_CONF_ENABLE != 0
+    }
+
+    fn counter(&self) -> u64 {
+        if !self.enabled() {
+            return self.counter_offset;
+        }
+        let elapsed = self.clock.now().saturating_sub(self.counter_start);
+        self.counter_offset.wrapping_add(elapsed / NS_PER_TICK)
+    }
+
+    fn capabilities() -> u64 {
+        (COUNTER_PERIOD_FS << 32)
+            | (0x8086 << 16)
+            | (1 << 15)
+            | (1 << 13)
+            | (((HPET_NUM_TIMERS - 1) as u64) << 8)
+            | 1
+    }
+
+    fn timer_capabilities() -> u64 {
+        (u64::from(HPET_INT_ROUTE_CAP) << 32) | TN_FSB_CAP | TN_SIZE_CAP | TN_PERIODIC_CAP
+    }
+
+    // Return the I/O APIC input timer `idx` is routed to.
+    fn timer_gsi(&self, idx: usize) -> u32 {
+        if self.config & GEN_CONF_LEGACY != 0 && idx < 2 {
+            return [2, 8][idx];
+        }
+        ((self.timers[idx].config & TN_ROUTE_MASK) >> TN_ROUTE_SHIFT) as u32
+    }
+
+    fn fire(&mut self, idx: usize) {
+        let timer = self.timers[idx];
+        let level = timer.config & TN_LEVEL != 0;
+        if level {
+            self.status |= 1 << idx;
+        }
+        if timer.config & TN_INT_ENABLE == 0 {
+            return;
+        }
+
+        if timer.config & TN_FSB_ENABLE != 0 {
+            (self.interrupt)(HpetInterrupt::Msi {
+                address: (timer.fsb_route >> 32) as u32,
+                data: timer.fsb_route as u32,
+            });
+            return;
+        }
+
+        let gsi = self.timer_gsi(idx);
+        (self.interrupt)(HpetInterrupt::Gsi {
+            gsi,
+            asserted: true,
+        });
+        if !level {
+            (self.interrupt)(HpetInterrupt::Gsi {
+                gsi,
+                asserted: false,
+            });
+        }
+    }
+
+    /// Return the time (as reported by the clock) at which the next comparator expires, if
+    /// the HPET is enabled.
+    pub fn next_deadline(&self) -> Option<u64> {
+        if !self.enabled() {
+            return None;
+        }
+        let counter = self.counter();
+        let now = self.clock.now();
+        self.timers
+            .iter()
+            .filter(|timer| !timer.fired && timer.config & TN_INT_ENABLE != 0)
+            .map(|timer| {
+                let ticks = timer.comparator.saturating_sub(counter);
+                now.saturating_add(ticks.saturating_mul(NS_PER_TICK))
+            })
+            .min()
+    }
+
+    /// Generate the interrupts of the comparators which have expired since the last call.
+    pub fn check_timers(&mut self) {
+        if !self.enabled() {
+            return;
+        }
+        let counter = self.counter();
+        for idx in 0..HPET_NUM_TIMERS {
+            let timer = &mut self.timers[idx];
+            if timer.fired || timer.comparator > counter {
+                continue;
+            }
+            if timer.config & TN_PERIODIC != 0 && timer.period != 0 {
+                // Skip the periods which have been missed entirely. The period is set by the
+                // guest, so a comparator which would go past the end of the counter stays
+                // there.
+                let missed = ((counter - timer.comparator) / timer.period).saturating_add(1);
+                timer.comparator = missed
+                    .checked_mul(timer.period)
+                    .and_then(|ticks| timer.comparator.checked_add(ticks))
+                    .unwrap_or(u64::MAX);
+            } else {
+                timer.fired = true;
+            }
+            self.fire(idx);
+        }
+    }
+
+    fn read_reg(&self, offset: u64) -> u64 {
+        match offset {
+            GCAP_ID => Self::capabilities(),
+            GEN_CONF => self.config,
+            GINTR_STA => self.status,
+            MAIN_COUNTER => self.counter(),
+            _ if offset >= TIMER_BASE => {
+                let idx = ((offset - TIMER_BASE) / TIMER_SIZE) as usize;
+                let timer = match self.timers.get(idx) {
+                    Some(timer) => timer,
+                    None => return 0,
+                };
+                match (offset - TIMER_BASE) % TIMER_SIZE {
+                    TIMER_CONF => timer.config | Self::timer_capabilities(),
+                    TIMER_COMPARATOR => timer.comparator,
+                    TIMER_FSB_ROUTE => timer.fsb_route,
+                    _ => 0,
+                }
+            }
+            _ => 0,
+        }
+    }
+
+    // Write the bits of `value` selected by `mask` to the register at `offset`.
+    fn write_reg(&mut self, offset: u64, value: u64, mask: u64) {
+        let merge = |old: u64| (old & !mask) | (value & mask);
+        match offset {
+            GEN_CONF => {
+                let was_enabled = self.enabled();
+                let counter = self.counter();
+                self.config = merge(self.config) & (GEN_CONF_ENABLE | GEN_CONF_LEGACY);
+                if !was_enabled && self.enabled() {
+                    self.counter_offset = counter;
+                    self.counter_start = self.clock.now();
+                } else if was_enabled && !self.enabled() {
+                    self.counter_offset = counter;
+                }
+            }
+            // Writing ones clears the status of level triggered interrupts.
+            GINTR_STA => {
+                let cleared = self.status & value & mask;
+                self.status &= !cleared;
+                for idx in 0..HPET_NUM_TIMERS {
+                    if cleared & (1 << idx) != 0 && self.timers[idx].config & TN_FSB_ENABLE == 0 {
+                        (self.interrupt)(HpetInterrupt::Gsi {
+                            gsi: self.timer_gsi(idx),
+                            asserted: false,
+                        });
+                    }
+                }
+            }
+            // The main counter can only be written while halted.
+            MAIN_COUNTER if !self.enabled() => {
+                self.counter_offset = merge(self.counter_offset);
+                self.timers.iter_mut().for_each(|timer| timer.fired = false);
+            }
+            _ if offset >= TIMER_BASE => {
+                let idx = ((offset - TIMER_BASE) / TIMER_SIZE) as usize;
+                if idx >= HPET_NUM_TIMERS {
+                    return;
+                }
+                let timer = &mut self.timers[idx];
+                match (offset - TIMER_BASE) % TIMER_SIZE {
+                    TIMER_CONF => {
+                        // The value set bit is write-only and allows the next comparator
+                        // write to only update the comparator value of a periodic timer.
+                        let old = timer.config;
+                        timer.config = merge(old) & (TN_WRITABLE | TN_VAL_SET);
+                        // Routes to the inputs missing from the capabilities are ignored.
+                        let route = (timer.config & TN_ROUTE_MASK) >> TN_ROUTE_SHIFT;
+                        if HPET_INT_ROUTE_CAP & (1 << route) == 0 {
+                            timer.config = (timer.config & !TN_ROUTE_MASK) | (old & TN_ROUTE_MASK);
+                        }
+                    }
+                    TIMER_COMPARATOR => {
+                        let periodic = timer.config & TN_PERIODIC != 0;
+                        if periodic && timer.config & TN_VAL_SET == 0 {
+                            timer.period = merge(timer.period);
+                        }
+                        timer.comparator = merge(timer.comparator);
+                        timer.config &= !TN_VAL_SET;
+                        timer.fired = false;
+                    }
+                    TIMER_FSB_ROUTE => timer.fsb_route = merge(timer.fsb_route),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl MutDeviceMmio for Hpet {
+    fn mmio_read(&mut self, _base: MmioAddress, offset: MmioOffset, data: &mut [u8]) {
+        let value = self.read_reg(offset.raw() & !0x7).to_le_bytes();
+        let start = (offset.raw() & 0x7) as usize;
+        for (idx, byte) in data.iter_mut().enumerate() {
+            *byte = value.get(start + idx).copied().unwrap_or(0);
+        }
+    }
+
+    fn mmio_write(&mut self, _base: MmioAddress, offset: MmioOffset, data: &[u8]) {
+        let start = (offset.raw() & 0x7) as usize;
+        if start + data.len() > 8 {
+            return;
+        }
+        let mut value = [0u8; 8];
+        let mut mask = [0u8; 8];
+        value[start..start + data.len()].copy_from_slice(data);
+        mask[start..start + data.len()]
+            .iter_mut()
+            .for_each(|b| *b = 0xff);
+        self.write_reg(
+            offset.raw() & !0x7,
+            u64::from_le_bytes(value),
+            u64::from_le_bytes(mask),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use crate::time::ManualClock;
+
+    const BASE: MmioAddress = MmioAddress(HPET_DEFAULT_BASE);
+
+    fn read(hpet: &mut Hpet, offset: u64) -> u64 {
+        let mut data = [0u8; 8];
+        hpet.mmio_read(BASE, MmioOffset(offset), &mut data);
+        u64::from_le_bytes(data)
+    }
+
+    fn write(hpet: &mut Hpet, offset: u64, value: u64) {
+        hpet.mmio_write(BASE, MmioOffset(offset), &value.to_le_bytes());
+    }
+
+    #[test]
+    fn test_hpet() {
+        let clock = Arc::new(ManualClock::new());
+        let irqs = Arc::new(Mutex::new(Vec::new()));
+        let sent = irqs.clone();
+        let mut hpet = Hpet::new(
+            clock.clone(),
+            Box::new(move |irq| sent.lock().unwrap().push(irq)),
+        );
+
+        let caps = read(&mut hpet, GCAP_ID);
+        assert_eq!(caps >> 32, COUNTER_PERIOD_FS);
+        assert_eq!((caps >> 8) & 0x1f, HPET_NUM_TIMERS as u64 - 1);
+        let mut low = [0u8; 4];
+        hpet.mmio_read(BASE, MmioOffset(GCAP_ID + 4), &mut low);
+        assert_eq!(u64::from(u32::from_le_bytes(low)), COUNTER_PERIOD_FS);
+
+        // The counter doesn't run while the HPET is disabled.
+        clock.advance(1000);
+        assert_eq!(read(&mut hpet, MAIN_COUNTER), 0);
+        assert_eq!(hpet.next_deadline(), None);
+
+        // Timer 0: periodic, edge triggered, routed to GSI 20, every 10 ticks.
+        let timer0 = TIMER_BASE;
+        write(
+            &mut hpet,
+            timer0 + TIMER_CONF,
+            TN_INT_ENABLE | TN_PERIODIC | (20 << TN_ROUTE_SHIFT),
+        );
+        write(&mut hpet, timer0 + TIMER_COMPARATOR, 10);
+        // Timer 1: one-shot, level triggered, routed to GSI 21, at tick 25.
+        let timer1 = TIMER_BASE + TIMER_SIZE;
+        write(
+            &mut hpet,
+            timer1 + TIMER_CONF,
+            TN_INT_ENABLE | TN_LEVEL | (21 << TN_ROUTE_SHIFT),
+        );
+        write(&mut hpet, timer1 + TIMER_COMPARATOR, 25);
+
+        write(&mut hpet, GEN_CONF, GEN_CONF_ENABLE);
+        assert_eq!(hpet.next_deadline(), Some(1000 + 10 * NS_PER_TICK));
+
+        clock.advance(10 * NS_PER_TICK);
+        assert_eq!(read(&mut hpet, MAIN_COUNTER), 10);
+        hpet.check_timers();
+        assert_eq!(
+            *irqs.lock().unwrap(),
+            vec![
+                HpetInterrupt::Gsi {
+                    gsi: 20,
+                    asserted: true
+                },
+                HpetInterrupt::Gsi {
+                    gsi: 20,
+                    asserted: false
+                }
+            ]
+        );
+        irqs.lock().unwrap().clear();
+        assert_eq!(read(&mut hpet, timer0 + TIMER_COMPARATOR), 20);
+
+        // Missed periods are coalesced.
+        clock.advance(16 * NS_PER_TICK);
+        hpet.check_timers();
+        assert_eq!(irqs.lock().unwrap().len(), 3);
+        assert_eq!(read(&mut hpet, timer0 + TIMER_COMPARATOR), 30);
+        assert_eq!(read(&mut hpet, GINTR_STA), 0b10);
+        irqs.lock().unwrap().clear();
+
+        // The one-shot timer doesn't fire again, and its level is deasserted when the status
+        // bit is cleared.
+        clock.advance(4 * NS_PER_TICK);
+        hpet.check_timers();
+        write(&mut hpet, GINTR_STA, 0b10);
+        assert_eq!(read(&mut hpet, GINTR_STA), 0);
+        assert_eq!(
+            irqs.lock().unwrap().last(),
+            Some(&HpetInterrupt::Gsi {
+                gsi: 21,
+                asserted: false
+            })
+        );
+
+        // Legacy replacement routes timer 0 to GSI 2, and FSB delivery sends an MSI.
+        irqs.lock().unwrap().clear();
+        write(&mut hpet, GEN_CONF, GEN_CONF_ENABLE | GEN_CONF_LEGACY);
+        write(
+            &mut hpet,
+            timer1 + TIMER_FSB_ROUTE,
+            (0xfee0_0000 << 32) | 0x41,
+        );
+        write(
+            &mut hpet,
+            timer1 + TIMER_CONF,
+            TN_INT_ENABLE | TN_FSB_ENABLE,
+        );
+        write(&mut hpet, timer1 + TIMER_COMPARATOR, 40);
+        clock.advance(10 * NS_PER_TICK);
+        hpet.check_timers();
+        assert_eq!(
+            *irqs.lock().unwrap(),
+            vec![
+                HpetInterrupt::Gsi {
+                    gsi: 2,
+                    asserted: true
+                },
+                HpetInterrupt::Gsi {
+                    gsi: 2,
+                    asserted: false
+                },
+                HpetInterrupt::Msi {
+                    address: 0xfee0_0000,
+                    data: 0x41
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_timer_config() {
+        let clock = Arc::new(ManualClock::new());
+        let mut hpet = Hpet::new(clock, Box::new(|_| {}));
+        let timer0 = TIMER_BASE;
+
+        // The 32-bit mode isn't implemented, and routes missing from the capabilities are
+        // ignored.
+        write(&mut hpet, timer0 + TIMER_CONF, TN_INT_ENABLE | (1 << 8));
+        assert_eq!(
+            read(&mut hpet, timer0 + TIMER_CONF) & 0xffff,
+            TN_INT_ENABLE | TN_FSB_CAP | TN_SIZE_CAP | TN_PERIODIC_CAP
+        );
+        write(&mut hpet, timer0 + TIMER_CONF, 22 << TN_ROUTE_SHIFT);
+        write(&mut hpet, timer0 + TIMER_CONF, 3 << TN_ROUTE_SHIFT);
+        assert_eq!(hpet.timer_gsi(0), 22);
+    }
+
+    #[test]
+    fn test_periodic_overflow() {
+        let clock = Arc::new(ManualClock::new());
+        let irqs = Arc::new(Mutex::new(0));
+        let sent = irqs.clone();
+        let mut hpet = Hpet::new(clock, Box::new(move |_| *sent.lock().unwrap() += 1));
+
+        // A period which takes the comparator past the end of the counter.
+        let timer0 = TIMER_BASE;
+        write(
+            &mut hpet,
+            timer0 + TIMER_CONF,
+            TN_INT_ENABLE | TN_PERIODIC | (20 << TN_ROUTE_SHIFT),
+        );
+        write(&mut hpet, timer0 + TIMER_COMPARATOR, 1 << 63);
+        write(
+            &mut hpet,
+            timer0 + TIMER_CONF,
+            TN_INT_ENABLE | TN_PERIODIC | TN_VAL_SET | (20 << TN_ROUTE_SHIFT),
+        );
+        write(&mut hpet, timer0 + TIMER_COMPARATOR, u64::MAX - 10);
+        write(&mut hpet, MAIN_COUNTER, u64::MAX - 5);
+        write(&mut hpet, GEN_CONF, GEN_CONF_ENABLE);
+        hpet.check_timers();
+        assert_eq!(read(&mut hpet, timer0 + TIMER_COMPARATOR), u64::MAX);
+        assert_eq!(*irqs.lock().unwrap(), 2);
+
+        // A period of one tick, with the whole counter missed.
+        write(&mut hpet, GEN_CONF, 0);
+        write(
+            &mut hpet,
+            timer0 + TIMER_CONF,
+            TN_INT_ENABLE | TN_PERIODIC | (20 << TN_ROUTE_SHIFT),
+        );
+        write(&mut hpet, timer0 + TIMER_COMPARATOR, 1);
+        write(
+            &mut hpet,
+            timer0 + TIMER_CONF,
+            TN_INT_ENABLE | TN_PERIODIC | TN_VAL_SET | (20 << TN_ROUTE_SHIFT),
+        );
+        write(&mut hpet, timer0 + TIMER_COMPARATOR, 0);
+        write(&mut hpet, MAIN_COUNTER, u64::MAX);
+        write(&mut hpet, GEN_CONF, GEN_CONF_ENABLE);
+        hpet.check_timers();
+        assert_eq!(read(&mut hpet, timer0 + TIMER_COMPARATOR), u64::MAX);
+    }
+}
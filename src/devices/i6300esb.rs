@@ -0,0 +1,441 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Intel 6300ESB watchdog timer, as supported by the `i6300esb` Linux driver.
+//!
+//! The watchdog is a PCI function with a 16-byte register BAR. Once enabled through its
+//! configuration space, it counts down in two stages: the expiration of the first one
+//! raises the interrupt of the function, and the expiration of the second one resets the
+//! VM by sending `VmEvent::Reset` through the `VmEventSender` of the device. The guest
+//! restarts the first stage by pinging the watchdog, after unlocking the registers with a
+//! magic sequence. The stages are timed with deadline timers of a `VirtualClock`, so they
+//! follow the guest view of time; the VMM runs the expired ones with `run_expired`.
+
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Mutex};
+
+use crate::bus::{MmioAddress, MmioOffset, MmioRange};
+use crate::device_manager::{self, IoManager, MmioManager};
+use crate::events::{VmEvent, VmEventSender};
+use crate::interrupt::LineInterrupt;
+use crate::pci::{
+    self, BarReprogrammingParams, PciBarConfiguration, PciBarRegionType, PciClassCode,
+    PciConfiguration, PciDevice,
+};
+use crate::time::{TimerId, VirtualClock};
+use crate::DeviceMmio;
+
+/// PCI vendor ID of the watchdog.
+pub const I6300ESB_VENDOR_ID: u16 = 0x8086;
+/// PCI device ID of the watchdog.
+pub const I6300ESB_DEVICE_ID: u16 = 0x25ab;
+/// Size of the register BAR.
+pub const I6300ESB_REGS_SIZE: u64 = 0x10;
+
+// Configuration space registers.
+const CONFIG_REG: usize = 0x60 / 4;
+const LOCK_REG: usize = 0x68 / 4;
+
+const CONFIG_CLOCK_1MHZ: u16 = 1 << 2;
+const CONFIG_NO_REBOOT: u16 = 1 << 5;
+const LOCK_LOCKED: u8 = 1 << 0;
+const LOCK_ENABLE: u8 = 1 << 1;
+
+const REGS_BAR: usize = 0;
+
+// BAR registers.
+const TIMER1_REG: u64 = 0x00;
+const TIMER2_REG: u64 = 0x04;
+const GINTSR_REG: u64 = 0x08;
+const RELOAD_REG: u64 = 0x0c;
+
+const RELOAD_PING: u16 = 1 << 8;
+const RELOAD_TIMEOUT: u16 = 1 << 9;
+const UNLOCK1: u16 = 0x80;
+const UNLOCK2: u16 = 0x86;
+const PRELOAD_MASK: u32 = 0xf_ffff;
+
+/// Errors encountered while setting up the watchdog.
+#[derive(Debug)]
+pub enum Error {
+    /// No address was provided for the BAR.
+    BarAddress(usize),
+    /// Failed to set up the configuration space.
+    Pci(pci::Error),
+    /// Failed to register the BAR.
+    Register(device_manager::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::BarAddress(idx) => write!(f, "no address for BAR {}", idx),
+            Error::Pci(_) => write!(f, "invalid PCI configuration"),
+            Error::Register(_) => write!(f, "failed to register the BAR"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Pci(e) => Some(e),
+            Error::Register(e) => Some(e),
+            Error::BarAddress(_) => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Stage {
+    Disabled,
+    First,
+    Second,
+}
+
+struct State {
+    config: u16,
+    lock: u8,
+    timer1: u32,
+    timer2: u32,
+    unlock: u8,
+    stage: Stage,
+    timer: Option<TimerId>,
+    int_status: bool,
+    timed_out: bool,
+}
+
+struct Inner {
+    clock: Arc<dyn VirtualClock>,
+    events: VmEventSender,
+    irq: Option<LineInterrupt>,
+    state: Mutex<State>,
+}
+
+impl Inner {
+    // Length of a stage with the preload value `preload`: the counter is decremented every
+    // 2^15 cycles of the 33 MHz clock, scaled down to 1 kHz unless the 1 MHz clock is
+    // selected.
+    fn stage_ns(state: &State, preload: u32) -> u64 {
+        let scale = if state.config & CONFIG_CLOCK_1MHZ != 0 {
+            1_000
+        } else {
+            1_000_000
+        };
+        (u64::from(preload) << 15) * scale / 33
+    }
+
+    // Start `stage`, replacing the pending timer if any.
+    fn arm(self: &Arc<Self>, state: &mut State, stage: Stage) {
+        if let Some(id) = state.timer.take() {
+            self.clock.cancel_timer(id);
+        }
+        state.stage = stage;
+        let preload = match stage {
+            Stage::Disabled => return,
+            Stage::First => state.timer1,
+            Stage::Second => state.timer2,
+        };
+        let deadline = self.clock.now() + Self::stage_ns(state, preload);
+        let inner = self.clone();
+        state.timer = Some(
+            self.clock
+                .add_timer(deadline, Box::new(move || inner.expired(stage))),
+        );
+    }
+
+    fn expired(self: &Arc<Self>, stage: Stage) {
+        let mut state = self.state.lock().unwrap();
+        // The timer may have been replaced while its callback was pending.
+        if state.stage != stage {
+            return;
+        }
+        state.timer = None;
+        match stage {
+            Stage::First => {
+                state.int_status = true;
+                if let Some(irq) = self.irq.as_ref() {
+                    irq.assert();
+                }
+                self.arm(&mut state, Stage::Second);
+            }
+            Stage::Second => {
+                state.timed_out = true;
+                state.stage = Stage::Disabled;
+                if state.config & CONFIG_NO_REBOOT == 0 {
+                    // The VMM is gone if the channel is disconnected.
+                    let _ = self.events.send(VmEvent::Reset);
+                }
+            }
+            Stage::Disabled => {}
+        }
+    }
+
+    fn ping(self: &Arc<Self>, state: &mut State) {
+        if state.stage != Stage::Disabled {
+            self.arm(state, Stage::First);
+        }
+    }
+}
+
+/// The register BAR of the watchdog.
+pub struct I6300EsbRegs(Arc<Inner>);
+
+impl DeviceMmio for I6300EsbRegs {
+    fn mmio_read(&self, _base: MmioAddress, offset: MmioOffset, data: &mut [u8]) {
+        let state = self.0.state.lock().unwrap();
+        let value = match offset.raw() & !0x3 {
+            TIMER1_REG => state.timer1,
+            TIMER2_REG => state.timer2,
+            GINTSR_REG => u32::from(state.int_status),
+            RELOAD_REG if state.timed_out => u32::from(RELOAD_TIMEOUT),
+            _ => 0,
+        };
+        let bytes = value.to_le_bytes();
+        let start = (offset.raw() & 0x3) as usize;
+        for (idx, byte) in data.iter_mut().enumerate() {
+            *byte = bytes.get(start + idx).copied().unwrap_or(0);
+        }
+    }
+
+    fn mmio_write(&self, _base: MmioAddress, offset: MmioOffset, data: &[u8]) {
+        let mut bytes = [0; 4];
+        let len = data.len().min(4);
+        bytes[..len].copy_from_slice(&data[..len]);
+        let value = u32::from_le_bytes(bytes);
+        let mut state = self.0.state.lock().unwrap();
+        match offset.raw() {
+            // The interrupt status is cleared by writing 1.
+            GINTSR_REG if value & 1 != 0 => {
+                state.int_status = false;
+                if let Some(irq) = self.0.irq.as_ref() {
+                    irq.deassert();
+                }
+            }
+            RELOAD_REG => match (state.unlock, value as u16) {
+                (_, UNLOCK1) => state.unlock = 1,
+                (1, UNLOCK2) => state.unlock = 2,
+                (2, value) => {
+                    state.unlock = 0;
+                    if value & RELOAD_TIMEOUT != 0 {
+                        state.timed_out = false;
+                    }
+                    if value & RELOAD_PING != 0 {
+                        self.0.ping(&mut state);
+                    }
+                }
+                _ => state.unlock = 0,
+            },
+            // The preload values can only be written right after the unlock sequence.
+            TIMER1_REG | TIMER2_REG if state.unlock == 2 => {
+                state.unlock = 0;
+                if offset == TIMER1_REG {
+                    state.timer1 = value & PRELOAD_MASK;
+                } else {
+                    state.timer2 = value & PRELOAD_MASK;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The watchdog PCI function, to be plugged into a `PciBus`.
+pub struct I6300Esb {
+    config: PciConfiguration,
+    inner: Arc<Inner>,
+}
+
+impl I6300Esb {
+    /// Create the watchdog, whose stages are timed with `clock` and which requests the VM
+    /// reset through `events`. The register BAR is placed at the address returned by
+    /// `bar_address`.
+    pub fn new<F>(
+        clock: Arc<dyn VirtualClock>,
+        events: VmEventSender,
+        mut bar_address: F,
+    ) -> Result<Self, Error>
+    where
+        F: FnMut(&PciBarConfiguration) -> Option<u64>,
+    {
+        let mut config = PciConfiguration::new(
+            I6300ESB_VENDOR_ID,
+            I6300ESB_DEVICE_ID,
+            PciClassCode {
+                class: 0x08,
+                subclass: 0x80,
+                prog_if: 0x00,
+            },
+        );
+        let bar = PciBarConfiguration::new(
+            REGS_BAR,
+            I6300ESB_REGS_SIZE,
+            PciBarRegionType::Memory32BitRegion,
+            false,
+        );
+        let addr = bar_address(&bar).ok_or(Error::BarAddress(REGS_BAR))?;
+        config
+            .add_bar(&bar.with_address(addr))
+            .map_err(Error::Pci)?;
+
+        Ok(I6300Esb {
+            config,
+            inner: Arc::new(Inner {
+                clock,
+                events,
+                irq: None,
+                state: Mutex::new(State {
+                    config: 0,
+                    lock: 0,
+                    timer1: PRELOAD_MASK,
+                    timer2: PRELOAD_MASK,
+                    unlock: 0,
+                    stage: Stage::Disabled,
+                    timer: None,
+                    int_status: false,
+                    timed_out: false,
+                }),
+            }),
+        })
+    }
+
+    /// Raise `intx` (the INTA# line of the function) when the first stage expires. Must be
+    /// called before handing out the register BAR with `regs`.
+    pub fn with_intx(mut self, intx: LineInterrupt) -> Self {
+        self.config.set_irq(intx.irq() as u8, 1);
+        if let Some(inner) = Arc::get_mut(&mut self.inner) {
+            inner.irq = Some(intx);
+        }
+        self
+    }
+
+    /// Return the device handling the register BAR.
+    pub fn regs(&self) -> Arc<I6300EsbRegs> {
+        Arc::new(I6300EsbRegs(self.inner.clone()))
+    }
+
+    /// Register the BAR on the MMIO bus of `io_mgr`, at its current address.
+    pub fn register_bars(&self, io_mgr: &mut IoManager) -> Result<(), Error> {
+        let register = |e| Error::Register(device_manager::Error::Bus(e));
+        let bar = self
+            .config
+            .bar(REGS_BAR)
+            .ok_or(Error::BarAddress(REGS_BAR))?;
+        let range = MmioRange::new(MmioAddress(bar.address()), bar.size()).map_err(register)?;
+        io_mgr.register_mmio(range, self.regs()).map_err(register)
+    }
+
+    /// Return whether the watchdog is counting down.
+    pub fn is_armed(&self) -> bool {
+        self.inner.state.lock().unwrap().stage != Stage::Disabled
+    }
+}
+
+impl PciDevice for I6300Esb {
+    fn config(&self) -> &PciConfiguration {
+        &self.config
+    }
+
+    fn config_mut(&mut self) -> &mut PciConfiguration {
+        &mut self.config
+    }
+
+    fn read_config_register(&self, reg_idx: usize) -> u32 {
+        let state = self.inner.state.lock().unwrap();
+        match reg_idx {
+            CONFIG_REG => u32::from(state.config),
+            LOCK_REG => u32::from(state.lock),
+            _ => self.config.read_reg(reg_idx),
+        }
+    }
+
+    fn write_config_register(
+        &mut self,
+        reg_idx: usize,
+        offset: u64,
+        data: &[u8],
+    ) -> Option<BarReprogrammingParams> {
+        let mut state = self.inner.state.lock().unwrap();
+        match (reg_idx, offset) {
+            // The configuration can't change once locked.
+            (CONFIG_REG, 0) if state.lock & LOCK_LOCKED == 0 => {
+                state.config = u16::from(data[0]) | data.get(1).map_or(0, |b| u16::from(*b) << 8);
+                None
+            }
+            (LOCK_REG, 0) if state.lock & LOCK_LOCKED == 0 => {
+                state.lock = data[0] & (LOCK_LOCKED | LOCK_ENABLE);
+                let stage = if state.lock & LOCK_ENABLE != 0 {
+                    Stage::First
+                } else {
+                    Stage::Disabled
+                };
+                self.inner.arm(&mut state, stage);
+                None
+            }
+            (CONFIG_REG, _) | (LOCK_REG, _) => None,
+            _ => {
+                drop(state);
+                self.config.write_reg(reg_idx, offset, data)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::events::vm_event_channel;
+    use crate::time::{ManualClock, ScaledClock};
+
+    fn write16(regs: &I6300EsbRegs, offset: u64, value: u16) {
+        regs.mmio_write(MmioAddress(0), MmioOffset(offset), &value.to_le_bytes());
+    }
+
+    #[test]
+    fn test_i6300esb() {
+        let source = Arc::new(ManualClock::new());
+        let clock = Arc::new(ScaledClock::new(source.clone()));
+        let (sender, receiver) = vm_event_channel();
+        let mut wdt = I6300Esb::new(clock.clone(), sender.for_device("watchdog"), |_| {
+            Some(0xfebf_0000)
+        })
+        .unwrap();
+        let regs = wdt.regs();
+
+        // Program both stages to 10 ticks of the 1 MHz clock (~9.9 ms).
+        for reg in [TIMER1_REG, TIMER2_REG].iter() {
+            write16(&regs, RELOAD_REG, UNLOCK1);
+            write16(&regs, RELOAD_REG, UNLOCK2);
+            regs.mmio_write(MmioAddress(0), MmioOffset(*reg), &10u32.to_le_bytes());
+        }
+        let stage = 10 * (1 << 15) * 1_000 / 33;
+        wdt.write_config_register(CONFIG_REG, 0, &[CONFIG_CLOCK_1MHZ as u8, 0]);
+        wdt.write_config_register(LOCK_REG, 0, &[LOCK_ENABLE]);
+        assert!(wdt.is_armed());
+        assert_eq!(clock.next_deadline(), Some(stage));
+
+        // Pinging restarts the first stage.
+        source.advance(stage - 1);
+        write16(&regs, RELOAD_REG, UNLOCK1);
+        write16(&regs, RELOAD_REG, UNLOCK2);
+        write16(&regs, RELOAD_REG, RELOAD_PING);
+        assert_eq!(clock.run_expired(), 0);
+        source.advance(stage);
+        assert_eq!(clock.run_expired(), 1);
+        let mut data = [0; 4];
+        regs.mmio_read(MmioAddress(0), MmioOffset(GINTSR_REG), &mut data);
+        assert_eq!(data[0], 1);
+        assert!(receiver.try_recv().is_none());
+
+        // The expiration of the second stage resets the VM.
+        source.advance(stage);
+        assert_eq!(clock.run_expired(), 1);
+        let message = receiver.try_recv().unwrap();
+        assert_eq!(&*message.source, "watchdog");
+        assert_eq!(message.event, VmEvent::Reset);
+        regs.mmio_read(MmioAddress(0), MmioOffset(RELOAD_REG), &mut data);
+        assert_eq!(u16::from_le_bytes([data[0], data[1]]), RELOAD_TIMEOUT);
+        assert!(!wdt.is_armed());
+    }
+}
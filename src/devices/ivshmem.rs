@@ -0,0 +1,479 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Inter-VM shared memory PCI device, compatible with the `ivshmem-doorbell` device of QEMU.
+//!
+//! The device exposes three BARs: the registers (BAR 0), the MSI-X table and pending bit
+//! array (BAR 1), and the shared memory (BAR 2). The shared memory is backed by a file
+//! (usually a memfd handed out by an ivshmem server) which is mapped into the guest, so only
+//! the accesses performed before the VMM maps it trap. A guest rings the doorbell of a peer
+//! by writing the peer ID and the vector to the doorbell register, which is forwarded to a
+//! [`DoorbellSink`](trait.DoorbellSink.html) (e.g. writing to the eventfd the server handed
+//! out for that peer vector). The VMM signals the vectors rung by the peers with
+//! `IvshmemRegs::notify`.
+
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+use crate::bus::{MmioAddress, MmioOffset, MmioRange};
+use crate::device_manager::{self, IoManager, MmioManager};
+use crate::interrupt::LineInterrupt;
+use crate::mappable::Mappable;
+use crate::msi::MsiSender;
+use crate::pci::{
+    self, BarReprogrammingParams, MsixCap, MsixTable, PciBarConfiguration, PciBarRegionType,
+    PciClassCode, PciConfiguration, PciDevice,
+};
+use crate::DeviceMmio;
+
+/// PCI vendor ID of the device.
+pub const IVSHMEM_VENDOR_ID: u16 = 0x1af4;
+/// PCI device ID of the device.
+pub const IVSHMEM_DEVICE_ID: u16 = 0x1110;
+
+/// Size of the register BAR.
+pub const IVSHMEM_REGS_SIZE: u64 = 0x100;
+/// Size of the MSI-X BAR.
+pub const IVSHMEM_MSIX_BAR_SIZE: u64 = 0x1000;
+
+const REGS_BAR: usize = 0;
+const MSIX_BAR: usize = 1;
+const MEMORY_BAR: usize = 2;
+const MSIX_PBA_OFFSET: u32 = 0x800;
+
+const INTR_MASK: u64 = 0x0;
+const INTR_STATUS: u64 = 0x4;
+const IV_POSITION: u64 = 0x8;
+const DOORBELL: u64 = 0xc;
+
+/// Errors encountered while setting up the device.
+#[derive(Debug)]
+pub enum Error {
+    /// No address was provided for the BAR.
+    BarAddress(usize),
+    /// Failed to query the shared memory file.
+    Io(io::Error),
+    /// The size of the shared memory is not a power of two.
+    MemorySize(u64),
+    /// Failed to set up the configuration space.
+    Pci(pci::Error),
+    /// Failed to register the BARs.
+    Register(device_manager::Error),
+    /// The number of vectors is not between 1 and the MSI-X table capacity.
+    Vectors(u16),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::BarAddress(idx) => write!(f, "no address for BAR {}", idx),
+            Error::Io(_) => write!(f, "failed to query the shared memory"),
+            Error::MemorySize(size) => write!(f, "invalid shared memory size {:#x}", size),
+            Error::Pci(_) => write!(f, "invalid PCI configuration"),
+            Error::Register(_) => write!(f, "failed to register the BARs"),
+            Error::Vectors(vectors) => write!(f, "invalid number of vectors {}", vectors),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Pci(e) => Some(e),
+            Error::Register(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Receives the doorbells rung by the guest.
+pub trait DoorbellSink: Send + Sync {
+    /// Signal the vector `vector` of the peer `peer`.
+    fn ring(&self, peer: u16, vector: u16) -> io::Result<()>;
+}
+
+impl<F: Fn(u16, u16) -> io::Result<()> + Send + Sync> DoorbellSink for F {
+    fn ring(&self, peer: u16, vector: u16) -> io::Result<()> {
+        self(peer, vector)
+    }
+}
+
+/// The registers of the device (BAR 0), which also deliver the interrupts of the vectors
+/// rung by the peers.
+pub struct IvshmemRegs {
+    peer_id: u16,
+    intr_mask: AtomicU32,
+    intr_status: AtomicU32,
+    doorbell: Arc<dyn DoorbellSink>,
+    msix: Arc<MsixTable>,
+    intx: Option<LineInterrupt>,
+}
+
+impl IvshmemRegs {
+    /// Return the ID of this VM among the peers.
+    pub fn peer_id(&self) -> u16 {
+        self.peer_id
+    }
+
+    /// Signal the vector `vector`, rung by a peer. The vector is fired through MSI-X when
+    /// enabled by the guest, and raises the legacy interrupt otherwise.
+    pub fn notify(&self, vector: u16) -> io::Result<()> {
+        if self.msix.enabled() {
+            return self.msix.trigger(usize::from(vector));
+        }
+        self.intr_status.fetch_or(1, Ordering::SeqCst);
+        self.update_intx();
+        Ok(())
+    }
+
+    fn update_intx(&self) {
+        if let Some(line) = self.intx.as_ref() {
+            let pending = self.intr_status.load(Ordering::SeqCst);
+            if pending & self.intr_mask.load(Ordering::SeqCst) != 0 {
+                line.assert();
+            } else {
+                line.deassert();
+            }
+        }
+    }
+}
+
+impl DeviceMmio for IvshmemRegs {
+    fn mmio_read(&self, _base: MmioAddress, offset: MmioOffset, data: &mut [u8]) {
+        let value = match offset.raw() & !0x3 {
+            INTR_MASK => self.intr_mask.load(Ordering::SeqCst),
+            // Reading the status acknowledges the interrupt.
+            INTR_STATUS => {
+                let status = self.intr_status.swap(0, Ordering::SeqCst);
+                self.update_intx();
+                status
+            }
+            IV_POSITION => u32::from(self.peer_id),
+            _ => 0,
+        };
+        let bytes = value.to_le_bytes();
+        let start = (offset.raw() & 0x3) as usize;
+        for (idx, byte) in data.iter_mut().enumerate() {
+            *byte = bytes.get(start + idx).copied().unwrap_or(0);
+        }
+    }
+
+    fn mmio_write(&self, _base: MmioAddress, offset: MmioOffset, data: &[u8]) {
+        // The registers are only written with full dword accesses.
+        if data.len() != 4 || offset.raw() & 0x3 != 0 {
+            return;
+        }
+        let value = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        match offset.raw() {
+            INTR_MASK => {
+                self.intr_mask.store(value, Ordering::SeqCst);
+                self.update_intx();
+            }
+            INTR_STATUS => {
+                self.intr_status.store(value, Ordering::SeqCst);
+                self.update_intx();
+            }
+            DOORBELL => {
+                // There is no way to report the failure to the guest.
+                let _ = self.doorbell.ring((value >> 16) as u16, value as u16);
+            }
+            _ => {}
+        }
+    }
+}
+
+// The MSI-X BAR, holding the table at its start and the PBA in its second half.
+struct MsixBar(Arc<MsixTable>);
+
+impl DeviceMmio for MsixBar {
+    fn mmio_read(&self, base: MmioAddress, offset: MmioOffset, data: &mut [u8]) {
+        let pba = u64::from(MSIX_PBA_OFFSET);
+        if offset < pba {
+            self.0.mmio_read(base, offset, data)
+        } else {
+            self.0.read_pba(offset.raw() - pba, data)
+        }
+    }
+
+    fn mmio_write(&self, base: MmioAddress, offset: MmioOffset, data: &[u8]) {
+        if offset < u64::from(MSIX_PBA_OFFSET) {
+            self.0.mmio_write(base, offset, data)
+        }
+    }
+}
+
+// Handles the accesses to the shared memory performed before the VMM maps it.
+struct SharedMemory(Arc<File>);
+
+impl DeviceMmio for SharedMemory {
+    fn mmio_read(&self, _base: MmioAddress, offset: MmioOffset, data: &mut [u8]) {
+        if self.0.read_exact_at(data, offset.raw()).is_err() {
+            data.iter_mut().for_each(|byte| *byte = 0xff);
+        }
+    }
+
+    fn mmio_write(&self, _base: MmioAddress, offset: MmioOffset, data: &[u8]) {
+        let _ = self.0.write_all_at(data, offset.raw());
+    }
+}
+
+/// The ivshmem PCI function, to be plugged into a `PciBus`.
+pub struct Ivshmem {
+    config: PciConfiguration,
+    msix_cap_offset: usize,
+    regs: Arc<IvshmemRegs>,
+    memory: Arc<File>,
+}
+
+impl Ivshmem {
+    /// Create the device of the peer `peer_id`, sharing `memory`, whose size must be a power
+    /// of two. Its `vectors` vectors fire through `senders` (see `MsixTable::new`), and the
+    /// doorbells rung by the guest go to `doorbell`. The BARs are placed at the addresses
+    /// returned by `bar_address`.
+    pub fn new<F>(
+        peer_id: u16,
+        memory: Arc<File>,
+        vectors: u16,
+        senders: Vec<Arc<dyn MsiSender>>,
+        doorbell: Arc<dyn DoorbellSink>,
+        mut bar_address: F,
+    ) -> Result<Self, Error>
+    where
+        F: FnMut(&PciBarConfiguration) -> Option<u64>,
+    {
+        let size = memory.metadata().map_err(Error::Io)?.len();
+        if !size.is_power_of_two() {
+            return Err(Error::MemorySize(size));
+        }
+        if vectors == 0 || u64::from(vectors) * 16 > u64::from(MSIX_PBA_OFFSET) {
+            return Err(Error::Vectors(vectors));
+        }
+
+        let mut config = PciConfiguration::new(
+            IVSHMEM_VENDOR_ID,
+            IVSHMEM_DEVICE_ID,
+            PciClassCode {
+                class: 0x05,
+                subclass: 0x00,
+                prog_if: 0x00,
+            },
+        );
+        config.set_revision_id(1);
+        for bar in [
+            PciBarConfiguration::new(
+                REGS_BAR,
+                IVSHMEM_REGS_SIZE,
+                PciBarRegionType::Memory32BitRegion,
+                false,
+            ),
+            PciBarConfiguration::new(
+                MSIX_BAR,
+                IVSHMEM_MSIX_BAR_SIZE,
+                PciBarRegionType::Memory32BitRegion,
+                false,
+            ),
+            PciBarConfiguration::new(MEMORY_BAR, size, PciBarRegionType::Memory64BitRegion, true),
+        ] {
+            let addr = bar_address(&bar).ok_or(Error::BarAddress(bar.idx()))?;
+            config
+                .add_bar(&bar.with_address(addr))
+                .map_err(Error::Pci)?;
+        }
+        let cap = MsixCap::new(vectors, MSIX_BAR as u8, 0, MSIX_BAR as u8, MSIX_PBA_OFFSET);
+        let msix_cap_offset = config.add_capability(&cap).map_err(Error::Pci)?;
+
+        Ok(Ivshmem {
+            config,
+            msix_cap_offset,
+            regs: Arc::new(IvshmemRegs {
+                peer_id,
+                intr_mask: AtomicU32::new(0),
+                intr_status: AtomicU32::new(0),
+                doorbell,
+                msix: Arc::new(MsixTable::new(vectors, senders).map_err(Error::Pci)?),
+                intx: None,
+            }),
+            memory,
+        })
+    }
+
+    /// Raise `line` for the vectors rung while MSI-X is disabled. Must be called before the
+    /// registers are shared through `regs` or `register_bars`.
+    pub fn with_intx(mut self, line: LineInterrupt) -> Self {
+        // The device uses INTA#.
+        self.config.set_irq(line.irq() as u8, 1);
+        if let Some(regs) = Arc::get_mut(&mut self.regs) {
+            regs.intx = Some(line);
+        }
+        self
+    }
+
+    /// Return the offset of the MSI-X capability within the configuration space.
+    pub fn msix_cap_offset(&self) -> usize {
+        self.msix_cap_offset
+    }
+
+    /// Return the registers of the device, used to notify it of the vectors rung by peers.
+    pub fn regs(&self) -> Arc<IvshmemRegs> {
+        self.regs.clone()
+    }
+
+    /// Register the BARs on the MMIO bus of `io_mgr`, at their current addresses. The
+    /// shared memory is marked as mappable.
+    pub fn register_bars(&self, io_mgr: &mut IoManager) -> Result<(), Error> {
+        let range = |idx| {
+            let bar = self.config.bar(idx).ok_or(Error::BarAddress(idx))?;
+            MmioRange::new(MmioAddress(bar.address()), bar.size())
+                .map_err(|e| Error::Register(device_manager::Error::Bus(e)))
+        };
+        let register = |e| Error::Register(device_manager::Error::Bus(e));
+        io_mgr
+            .register_mmio(range(REGS_BAR)?, self.regs.clone())
+            .map_err(register)?;
+        io_mgr
+            .register_mmio(range(MSIX_BAR)?, Arc::new(MsixBar(self.regs.msix.clone())))
+            .map_err(register)?;
+        io_mgr
+            .register_mmio_mappable(
+                range(MEMORY_BAR)?,
+                Arc::new(SharedMemory(self.memory.clone())),
+                Mappable::new(self.memory.clone(), 0),
+            )
+            .map_err(Error::Register)
+    }
+}
+
+impl PciDevice for Ivshmem {
+    fn config(&self) -> &PciConfiguration {
+        &self.config
+    }
+
+    fn config_mut(&mut self) -> &mut PciConfiguration {
+        &mut self.config
+    }
+
+    fn write_config_register(
+        &mut self,
+        reg_idx: usize,
+        offset: u64,
+        data: &[u8],
+    ) -> Option<BarReprogrammingParams> {
+        let params = self.config.write_reg(reg_idx, offset, data);
+        if reg_idx == self.msix_cap_offset / 4 {
+            let msg_ctl = self.config.read_reg(reg_idx) >> 16;
+            let _ = self.regs.msix.set_message_control(msg_ctl as u16);
+        }
+        params
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::OpenOptions;
+    use std::sync::Mutex;
+
+    use crate::msi::MsiMessage;
+
+    fn shared_memory(size: u64) -> Arc<File> {
+        let path = std::env::temp_dir().join(format!("ivshmem-{}", std::process::id()));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        file.set_len(size).unwrap();
+        Arc::new(file)
+    }
+
+    #[test]
+    fn test_ivshmem() {
+        let rung = Arc::new(Mutex::new(Vec::new()));
+        let doorbell_rung = rung.clone();
+        let doorbell = move |peer, vector| {
+            doorbell_rung.lock().unwrap().push((peer, vector));
+            Ok(())
+        };
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let sender_sent = sent.clone();
+        let sender = move |msg| {
+            sender_sent.lock().unwrap().push(msg);
+            Ok(())
+        };
+        let mut addrs = vec![0x1_0000_0000, 0xc000_1000, 0xc000_0000].into_iter();
+        assert!(matches!(
+            Ivshmem::new(
+                0,
+                shared_memory(0x3000),
+                2,
+                vec![],
+                Arc::new(doorbell.clone()),
+                |_| None
+            ),
+            Err(Error::MemorySize(0x3000))
+        ));
+        let mut ivshmem = Ivshmem::new(
+            3,
+            shared_memory(0x10_0000),
+            2,
+            vec![Arc::new(sender)],
+            Arc::new(doorbell),
+            |_| addrs.next_back(),
+        )
+        .unwrap();
+        assert_eq!(ivshmem.read_config_register(0), 0x1110_1af4);
+
+        let mut io_mgr = IoManager::new();
+        ivshmem.register_bars(&mut io_mgr).unwrap();
+        assert_eq!(
+            io_mgr.mappable_regions()[0].0.base(),
+            MmioAddress(0x1_0000_0000)
+        );
+
+        // The shared memory can be accessed before it's mapped.
+        io_mgr
+            .mmio_write(MmioAddress(0x1_0000_0010), &[1, 2, 3, 4])
+            .unwrap();
+        let mut data = [0; 4];
+        io_mgr
+            .mmio_read(MmioAddress(0x1_0000_0010), &mut data)
+            .unwrap();
+        assert_eq!(data, [1, 2, 3, 4]);
+
+        io_mgr
+            .mmio_read(MmioAddress(0xc000_0008), &mut data)
+            .unwrap();
+        assert_eq!(u32::from_le_bytes(data), 3);
+        io_mgr
+            .mmio_write(MmioAddress(0xc000_000c), &0x0005_0001u32.to_le_bytes())
+            .unwrap();
+        assert_eq!(*rung.lock().unwrap(), vec![(5, 1)]);
+
+        // Program and unmask vector 1, then enable MSI-X.
+        io_mgr
+            .mmio_write(MmioAddress(0xc000_1010), &0xfee0_0000u32.to_le_bytes())
+            .unwrap();
+        io_mgr
+            .mmio_write(MmioAddress(0xc000_1018), &0x22u32.to_le_bytes())
+            .unwrap();
+        io_mgr
+            .mmio_write(MmioAddress(0xc000_101c), &0u32.to_le_bytes())
+            .unwrap();
+        let cap_reg = ivshmem.msix_cap_offset() / 4;
+        ivshmem.write_config_register(cap_reg, 3, &[0x80]);
+        ivshmem.regs().notify(1).unwrap();
+        assert_eq!(
+            *sent.lock().unwrap(),
+            vec![MsiMessage::new(0xfee0_0000, 0x22)]
+        );
+    }
+}
@@ -0,0 +1,692 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Register model of the x86 local APIC, for VMMs which emulate the interrupt controller in
+//! userspace.
+//!
+//! Each vCPU has its own [`Lapic`](struct.Lapic.html). The model handles the xAPIC MMIO
+//! interface (through `MutDeviceMmio`), the x2APIC MSR interface (through
+//! [`x2apic_read`](struct.Lapic.html#method.x2apic_read) and
+//! [`x2apic_write`](struct.Lapic.html#method.x2apic_write)), interrupt prioritization, and
+//! the local timer. The x2APIC MSRs can also be routed to the model by registering it on
+//! the MSR bus, through `MutDeviceMsr`. Interprocessor interrupts which target other vCPUs
+//! are handed to a callback, which is responsible for routing them. Likewise, the ends of
+//! level-triggered interrupts are broadcast through the callback set with
+//! [`with_eoi_handler`](struct.Lapic.html#method.with_eoi_handler), so the IOAPIC model can
+//! deliver the interrupts of the lines which are still asserted.
+
+use std::sync::Arc;
+
+use crate::bus::{MmioAddress, MmioOffset, MsrAddress, MsrAddressValue};
+use crate::snapshot::{self, StateReader, StateWriter, Version, Versioned};
+use crate::time::Clock;
+use crate::{MutDeviceMmio, MutDeviceMsr};
+
+/// Default guest physical address of the xAPIC MMIO interface.
+pub const LAPIC_DEFAULT_BASE: u64 = 0xfee0_0000;
+
+/// Size of the xAPIC MMIO interface.
+pub const LAPIC_SIZE: u64 = 0x1000;
+
+/// First MSR of the x2APIC interface.
+pub const X2APIC_MSR_BASE: u32 = 0x800;
+
+/// Number of MSRs of the x2APIC interface.
+pub const X2APIC_MSR_COUNT: u32 = 0x40;
+
+const NUM_REGS: usize = 0x40;
+
+// Register indices (MMIO offset / 16, or MSR - X2APIC_MSR_BASE).
+const REG_ID: usize = 0x2;
+const REG_VERSION: usize = 0x3;
+const REG_TPR: usize = 0x8;
+const REG_PPR: usize = 0xa;
+const REG_EOI: usize = 0xb;
+const REG_LDR: usize = 0xd;
+const REG_DFR: usize = 0xe;
+const REG_SVR: usize = 0xf;
+const REG_ISR: usize = 0x10;
+const REG_TMR: usize = 0x18;
+const REG_IRR: usize = 0x20;
+const REG_ESR: usize = 0x28;
+const REG_ICR_LOW: usize = 0x30;
+const REG_ICR_HIGH: usize = 0x31;
+const REG_LVT_TIMER: usize = 0x32;
+const REG_LVT_THERMAL: usize = 0x33;
+const REG_LVT_PERF: usize = 0x34;
+const REG_LVT_LINT0: usize = 0x35;
+const REG_LVT_LINT1: usize = 0x36;
+const REG_LVT_ERROR: usize = 0x37;
+const REG_TIMER_INITIAL: usize = 0x38;
+const REG_TIMER_CURRENT: usize = 0x39;
+const REG_TIMER_DIVIDE: usize = 0x3e;
+const REG_SELF_IPI: usize = 0x3f;
+
+// Version 0x14, with 6 LVT entries.
+const APIC_VERSION: u32 = 0x0005_0014;
+
+const SVR_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+const ICR_LEVEL_TRIGGER: u32 = 1 << 15;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_LOGICAL: u32 = 1 << 11;
+
+const LVT_REGS: [usize; 6] = [
+    REG_LVT_TIMER,
+    REG_LVT_THERMAL,
+    REG_LVT_PERF,
+    REG_LVT_LINT0,
+    REG_LVT_LINT1,
+    REG_LVT_ERROR,
+];
+
+// Return the mask of the bits which can be written by the guest.
+fn writable_bits(reg: usize) -> u32 {
+    match reg {
+        REG_TPR => 0xff,
+        REG_LDR => 0xff00_0000,
+        REG_DFR => 0xf000_0000,
+        REG_SVR => 0x13ff,
+        REG_ICR_LOW => 0x000c_cfff,
+        REG_ICR_HIGH => 0xff00_0000,
+        REG_LVT_TIMER => 0x0007_00ff,
+        REG_LVT_THERMAL | REG_LVT_PERF => 0x0001_07ff,
+        REG_LVT_LINT0 | REG_LVT_LINT1 => 0x0001_a7ff,
+        REG_LVT_ERROR => 0x0001_00ff,
+        REG_TIMER_INITIAL => 0xffff_ffff,
+        REG_TIMER_DIVIDE => 0xb,
+        _ => 0,
+    }
+}
+
+/// Destination shorthand of an interprocessor interrupt.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum IpiShorthand {
+    /// The destination is specified by the `destination` field.
+    None,
+    /// The interrupt targets the sender.
+    SelfOnly,
+    /// The interrupt targets every local APIC, including the sender.
+    AllIncludingSelf,
+    /// The interrupt targets every local APIC, except the sender.
+    AllExcludingSelf,
+}
+
+/// An interprocessor interrupt sent by writing the interrupt command register.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Ipi {
+    /// ID of the sending local APIC.
+    pub source: u32,
+    /// Interrupt vector.
+    pub vector: u8,
+    /// Delivery mode (fixed, lowest priority, SMI, NMI, INIT, or SIPI).
+    pub delivery_mode: u8,
+    /// The destination is a logical (rather than physical) APIC ID.
+    pub logical: bool,
+    /// The interrupt is level triggered.
+    pub level_triggered: bool,
+    /// The level is asserted.
+    pub assert: bool,
+    /// Destination shorthand.
+    pub shorthand: IpiShorthand,
+    /// Destination APIC ID (8 bits wide in xAPIC mode, 32 bits wide in x2APIC mode).
+    pub destination: u32,
+}
+
+/// Saved state of a `Lapic`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LapicState {
+    /// Register values, indexed by MMIO offset divided by 16.
+    pub regs: Vec<u32>,
+    /// The x2APIC interface is enabled.
+    pub x2apic: bool,
+    /// Current count of the timer when the state was saved.
+    pub timer_current: u32,
+}
+
+impl Versioned for LapicState {
+    const VERSION: Version = Version::new(1, 0, 0);
+
+    fn save(&self, writer: &mut StateWriter) {
+        writer.put_u32(self.regs.len() as u32);
+        for reg in self.regs.iter() {
+            writer.put_u32(*reg);
+        }
+        writer.put_bool(self.x2apic);
+        writer.put_u32(self.timer_current);
+    }
+
+    fn restore(reader: &mut StateReader<'_>, _version: Version) -> Result<Self, snapshot::Error> {
+        let count = reader.u32()?;
+        let regs = (0..count).map(|_| reader.u32()).collect::<Result<_, _>>()?;
+        Ok(LapicState {
+            regs,
+            x2apic: reader.bool()?,
+            timer_current: reader.u32()?,
+        })
+    }
+}
+
+/// Register model of a local APIC.
+pub struct Lapic {
+    id: u32,
+    regs: [u32; NUM_REGS],
+    x2apic: bool,
+    // Time at which the timer (re)started counting down from the initial count.
+    timer_start: u64,
+    // Whether an expiration of the timer is yet to be reported.
+    timer_armed: bool,
+    clock: Arc<dyn Clock>,
+    send_ipi: Box<dyn Fn(Ipi) + Send + Sync>,
+    eoi_handler: Option<Box<dyn Fn(u8) + Send + Sync>>,
+}
+
+impl Lapic {
+    /// Create the local APIC with ID `id`. The timer is driven by `clock`, and interrupts
+    /// which target other local APICs are passed to `send_ipi`.
+    pub fn new(id: u32, clock: Arc<dyn Clock>, send_ipi: Box<dyn Fn(Ipi) + Send + Sync>) -> Self {
+        let mut lapic = Lapic {
+            id,
+            regs: [0; NUM_REGS],
+            x2apic: false,
+            timer_start: 0,
+            timer_armed: false,
+            clock,
+            send_ipi,
+            eoi_handler: None,
+        };
+        lapic.reset();
+        lapic
+    }
+
+    /// Invoke `handler` with the vector of each level-triggered interrupt the guest signals
+    /// the end of, as the EOI broadcast of the hardware.
+    pub fn with_eoi_handler(mut self, handler: Box<dyn Fn(u8) + Send + Sync>) -> Self {
+        self.eoi_handler = Some(handler);
+        self
+    }
+
+    /// Bring the registers back to their power-on values.
+    pub fn reset(&mut self) {
+        self.regs = [0; NUM_REGS];
+        self.regs[REG_ID] = self.id << 24;
+        self.regs[REG_VERSION] = APIC_VERSION;
+        self.regs[REG_DFR] = 0xffff_ffff;
+        self.regs[REG_SVR] = 0xff;
+        for reg in LVT_REGS.iter() {
+            self.regs[*reg] = LVT_MASKED;
+        }
+        self.x2apic = false;
+        self.timer_armed = false;
+    }
+
+    /// Return the ID of the local APIC.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Enable or disable the x2APIC interface. This is meant to be called by the handler of
+    /// the `IA32_APIC_BASE` MSR.
+    pub fn set_x2apic(&mut self, enabled: bool) {
+        self.x2apic = enabled;
+    }
+
+    fn enabled(&self) -> bool {
+        self.regs[REG_SVR] & SVR_ENABLE != 0
+    }
+
+    // Return the highest vector set in the 256-bit register starting at index `base`.
+    fn highest_vector(&self, base: usize) -> Option<u8> {
+        (0..8).rev().find_map(|idx| {
+            let value = self.regs[base + idx];
+            if value == 0 {
+                None
+            } else {
+                Some((idx * 32 + 31 - value.leading_zeros() as usize) as u8)
+            }
+        })
+    }
+
+    fn set_vector(&mut self, base: usize, vector: u8, set: bool) {
+        let reg = &mut self.regs[base + usize::from(vector >> 5)];
+        let bit = 1 << (vector & 0x1f);
+        if set {
+            *reg |= bit;
+        } else {
+            *reg &= !bit;
+        }
+    }
+
+    fn ppr(&self) -> u32 {
+        let tpr = self.regs[REG_TPR];
+        let isrv = self.highest_vector(REG_ISR).map_or(0, u32::from);
+        if tpr & 0xf0 >= isrv & 0xf0 {
+            tpr
+        } else {
+            isrv & 0xf0
+        }
+    }
+
+    /// Mark `vector` as requested, as a result of an interrupt sent to the local APIC.
+    pub fn accept_interrupt(&mut self, vector: u8, level_triggered: bool) {
+        self.set_vector(REG_IRR, vector, true);
+        self.set_vector(REG_TMR, vector, level_triggered);
+    }
+
+    /// Return the vector of the highest priority interrupt which can be delivered to the
+    /// vCPU, if any.
+    pub fn pending_interrupt(&self) -> Option<u8> {
+        if !self.enabled() {
+            return None;
+        }
+        self.highest_vector(REG_IRR)
+            .filter(|vector| u32::from(*vector) & 0xf0 > self.ppr() & 0xf0)
+    }
+
+    /// Move `vector` from the requested to the in-service state, once it has been injected
+    /// into the vCPU.
+    pub fn acknowledge_interrupt(&mut self, vector: u8) {
+        self.set_vector(REG_IRR, vector, false);
+        self.set_vector(REG_ISR, vector, true);
+    }
+
+    fn eoi(&mut self) {
+        if let Some(vector) = self.highest_vector(REG_ISR) {
+            self.set_vector(REG_ISR, vector, false);
+            if self.regs[REG_TMR + usize::from(vector >> 5)] & (1 << (vector & 0x1f)) != 0 {
+                if let Some(handler) = self.eoi_handler.as_ref() {
+                    handler(vector);
+                }
+            }
+        }
+    }
+
+    // Return the duration of a timer tick in nanoseconds. The timer counts at 1 GHz before
+    // going through the divider, so a tick lasts `divisor` nanoseconds.
+    fn timer_tick(&self) -> u64 {
+        let value = self.regs[REG_TIMER_DIVIDE];
+        let value = ((value & 0x8) >> 1) | (value & 0x3);
+        if value == 7 {
+            1
+        } else {
+            2 << value
+        }
+    }
+
+    fn timer_periodic(&self) -> bool {
+        self.regs[REG_LVT_TIMER] & LVT_TIMER_PERIODIC != 0
+    }
+
+    fn timer_current(&self) -> u32 {
+        let initial = u64::from(self.regs[REG_TIMER_INITIAL]);
+        if initial == 0 {
+            return 0;
+        }
+        let ticks = self.clock.now().saturating_sub(self.timer_start) / self.timer_tick();
+        if self.timer_periodic() {
+            (initial - ticks % initial) as u32
+        } else {
+            initial.saturating_sub(ticks) as u32
+        }
+    }
+
+    /// Return the time (as reported by the clock) at which the timer expires next, if it is
+    /// running.
+    pub fn timer_deadline(&self) -> Option<u64> {
+        if !self.timer_armed {
+            return None;
+        }
+        Some(self.timer_start + u64::from(self.regs[REG_TIMER_INITIAL]) * self.timer_tick())
+    }
+
+    /// Check whether the timer has expired, and request the interrupt configured in the
+    /// timer LVT entry if it did. Returns `true` when an interrupt has been requested.
+    pub fn check_timer(&mut self) -> bool {
+        let now = self.clock.now();
+        let deadline = match self.timer_deadline() {
+            Some(deadline) if deadline <= now => deadline,
+            _ => return false,
+        };
+
+        if self.timer_periodic() {
+            // Skip the periods which elapsed before the check as well, so a late check
+            // requests a single interrupt and the timer keeps its phase.
+            let period = u64::from(self.regs[REG_TIMER_INITIAL]) * self.timer_tick();
+            let missed = (now - deadline) / period.max(1);
+            self.timer_start = missed
+                .checked_mul(period)
+                .and_then(|elapsed| deadline.checked_add(elapsed))
+                .unwrap_or(u64::MAX);
+        } else {
+            self.timer_armed = false;
+        }
+
+        let lvt = self.regs[REG_LVT_TIMER];
+        if lvt & LVT_MASKED != 0 {
+            return false;
+        }
+        self.accept_interrupt(lvt as u8, false);
+        true
+    }
+
+    fn send_ipi(&mut self, icr_low: u32, destination: u32) {
+        let shorthand = match (icr_low >> 18) & 0x3 {
+            0 => IpiShorthand::None,
+            1 => IpiShorthand::SelfOnly,
+            2 => IpiShorthand::AllIncludingSelf,
+            _ => IpiShorthand::AllExcludingSelf,
+        };
+        let ipi = Ipi {
+            source: self.id,
+            vector: icr_low as u8,
+            delivery_mode: ((icr_low >> 8) & 0x7) as u8,
+            logical: icr_low & ICR_LOGICAL != 0,
+            level_triggered: icr_low & ICR_LEVEL_TRIGGER != 0,
+            assert: icr_low & ICR_LEVEL_ASSERT != 0,
+            shorthand,
+            destination,
+        };
+
+        // Fixed interrupts targeting the sender are handled locally.
+        if ipi.shorthand == IpiShorthand::SelfOnly && ipi.delivery_mode == 0 {
+            self.accept_interrupt(ipi.vector, ipi.level_triggered);
+        } else {
+            (self.send_ipi)(ipi);
+        }
+    }
+
+    fn read_reg(&self, reg: usize) -> u32 {
+        match reg {
+            REG_ID if self.x2apic => self.id,
+            REG_LDR if self.x2apic => ((self.id >> 4) << 16) | (1 << (self.id & 0xf)),
+            REG_PPR => self.ppr(),
+            REG_TIMER_CURRENT => self.timer_current(),
+            REG_EOI | REG_SELF_IPI => 0,
+            _ => self.regs.get(reg).copied().unwrap_or(0),
+        }
+    }
+
+    fn write_reg(&mut self, reg: usize, value: u32) {
+        match reg {
+            REG_EOI => self.eoi(),
+            REG_ESR => self.regs[REG_ESR] = 0,
+            REG_ICR_LOW => {
+                self.regs[REG_ICR_LOW] = value & writable_bits(REG_ICR_LOW);
+                let destination = self.regs[REG_ICR_HIGH] >> 24;
+                self.send_ipi(value, destination);
+            }
+            REG_TIMER_INITIAL => {
+                self.regs[REG_TIMER_INITIAL] = value;
+                self.timer_start = self.clock.now();
+                self.timer_armed = value != 0;
+            }
+            _ => {
+                let mask = writable_bits(reg);
+                let mut value = (self.regs[reg] & !mask) | (value & mask);
+                if LVT_REGS.contains(&reg) && !self.enabled() {
+                    value |= LVT_MASKED;
+                }
+                self.regs[reg] = value;
+
+                // Clearing the software enable bit masks every LVT entry.
+                if reg == REG_SVR && !self.enabled() {
+                    for reg in LVT_REGS.iter() {
+                        self.regs[*reg] |= LVT_MASKED;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Handle a read of the x2APIC MSR `msr`. Returns `None` when the MSR is not part of
+    /// the x2APIC interface, or the interface is disabled.
+    pub fn x2apic_read(&self, msr: u32) -> Option<u64> {
+        let reg = self.x2apic_reg(msr)?;
+        match reg {
+            REG_ICR_LOW => {
+                Some(u64::from(self.regs[REG_ICR_LOW]) | (u64::from(self.regs[REG_ICR_HIGH]) << 32))
+            }
+            REG_DFR | REG_ICR_HIGH | REG_EOI | REG_SELF_IPI => None,
+            _ => Some(u64::from(self.read_reg(reg))),
+        }
+    }
+
+    /// Handle a write of `value` to the x2APIC MSR `msr`. Returns `false` when the MSR is
+    /// not part of the x2APIC interface, or the interface is disabled.
+    pub fn x2apic_write(&mut self, msr: u32, value: u64) -> bool {
+        let reg = match self.x2apic_reg(msr) {
+            Some(reg) => reg,
+            None => return false,
+        };
+        match reg {
+            // The whole 64-bit ICR is written at once, with a 32-bit destination.
+            REG_ICR_LOW => {
+                self.regs[REG_ICR_LOW] = value as u32 & writable_bits(REG_ICR_LOW);
+                self.regs[REG_ICR_HIGH] = (value >> 32) as u32;
+                self.send_ipi(value as u32, (value >> 32) as u32);
+            }
+            REG_SELF_IPI => self.accept_interrupt(value as u8, false),
+            REG_ID | REG_LDR | REG_DFR | REG_ICR_HIGH => return false,
+            _ => self.write_reg(reg, value as u32),
+        }
+        true
+    }
+
+    fn x2apic_reg(&self, msr: u32) -> Option<usize> {
+        if !self.x2apic || !(X2APIC_MSR_BASE..X2APIC_MSR_BASE + X2APIC_MSR_COUNT).contains(&msr) {
+            return None;
+        }
+        Some((msr - X2APIC_MSR_BASE) as usize)
+    }
+
+    /// Save the state of the local APIC.
+    pub fn save_state(&self) -> LapicState {
+        LapicState {
+            regs: self.regs.to_vec(),
+            x2apic: self.x2apic,
+            timer_current: self.timer_current(),
+        }
+    }
+
+    /// Restore a state previously returned by `save_state`. The timer resumes counting from
+    /// the saved current count, regardless of the time elapsed since the state was saved.
+    pub fn restore_state(&mut self, state: &LapicState) {
+        for (reg, value) in self.regs.iter_mut().zip(state.regs.iter()) {
+            *reg = *value;
+        }
+        self.x2apic = state.x2apic;
+
+        let initial = u64::from(self.regs[REG_TIMER_INITIAL]);
+        let elapsed = initial.saturating_sub(u64::from(state.timer_current)) * self.timer_tick();
+        self.timer_start = self.clock.now().wrapping_sub(elapsed);
+        self.timer_armed = state.timer_current != 0;
+    }
+}
+
+impl MutDeviceMmio for Lapic {
+    fn mmio_read(&mut self, _base: MmioAddress, offset: MmioOffset, data: &mut [u8]) {
+        // The MMIO interface is disabled in x2APIC mode.
+        let value = if self.x2apic {
+            0
+        } else {
+            self.read_reg((offset.raw() >> 4) as usize)
+        };
+
+        let bytes = value.to_le_bytes();
+        let start = (offset.raw() & 0xf) as usize;
+        for (idx, byte) in data.iter_mut().enumerate() {
+            *byte = bytes.get(start + idx).copied().unwrap_or(0);
+        }
+    }
+
+    fn mmio_write(&mut self, _base: MmioAddress, offset: MmioOffset, data: &[u8]) {
+        // Registers can only be written with aligned dword accesses.
+        if self.x2apic || offset.raw() & 0xf != 0 || data.len() != 4 {
+            return;
+        }
+        let reg = (offset.raw() >> 4) as usize;
+        if reg != REG_SELF_IPI && reg < NUM_REGS {
+            self.write_reg(
+                reg,
+                u32::from_le_bytes([data[0], data[1], data[2], data[3]]),
+            );
+        }
+    }
+}
+
+// Registered with the `X2APIC_MSR_BASE` - `X2APIC_MSR_COUNT` range. MSRs which are not
+// accessible read as zero, and writes to them are ignored.
+impl MutDeviceMsr for Lapic {
+    fn msr_read(&mut self, base: MsrAddress, offset: MsrAddressValue) -> u64 {
+        self.x2apic_read(base.0 + offset).unwrap_or(0)
+    }
+
+    fn msr_write(&mut self, base: MsrAddress, offset: MsrAddressValue, value: u64) {
+        self.x2apic_write(base.0 + offset, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use crate::snapshot::VersionedState;
+    use crate::time::ManualClock;
+
+    const BASE: MmioAddress = MmioAddress(LAPIC_DEFAULT_BASE);
+
+    fn read(lapic: &mut Lapic, reg: usize) -> u32 {
+        let mut data = [0u8; 4];
+        lapic.mmio_read(BASE, MmioOffset((reg << 4) as u64), &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    fn write(lapic: &mut Lapic, reg: usize, value: u32) {
+        lapic.mmio_write(BASE, MmioOffset((reg << 4) as u64), &value.to_le_bytes());
+    }
+
+    fn test_lapic(clock: Arc<ManualClock>) -> (Lapic, Arc<Mutex<Vec<Ipi>>>) {
+        let ipis = Arc::new(Mutex::new(Vec::new()));
+        let sent = ipis.clone();
+        let lapic = Lapic::new(
+            1,
+            clock,
+            Box::new(move |ipi| sent.lock().unwrap().push(ipi)),
+        );
+        (lapic, ipis)
+    }
+
+    #[test]
+    fn test_interrupts() {
+        let (mut lapic, ipis) = test_lapic(Arc::new(ManualClock::new()));
+        assert_eq!(read(&mut lapic, REG_ID), 1 << 24);
+        assert_eq!(read(&mut lapic, REG_VERSION), APIC_VERSION);
+
+        // Interrupts are not delivered while the APIC is software disabled.
+        lapic.accept_interrupt(0x30, false);
+        assert_eq!(lapic.pending_interrupt(), None);
+        write(&mut lapic, REG_SVR, SVR_ENABLE | 0xff);
+        assert_eq!(lapic.pending_interrupt(), Some(0x30));
+
+        // The task priority blocks interrupts of the same or lower priority class.
+        write(&mut lapic, REG_TPR, 0x30);
+        assert_eq!(lapic.pending_interrupt(), None);
+        write(&mut lapic, REG_TPR, 0);
+
+        lapic.accept_interrupt(0x50, false);
+        assert_eq!(lapic.pending_interrupt(), Some(0x50));
+        lapic.acknowledge_interrupt(0x50);
+        assert_eq!(read(&mut lapic, REG_PPR), 0x50);
+        assert_eq!(lapic.pending_interrupt(), None);
+        write(&mut lapic, REG_EOI, 0);
+        assert_eq!(lapic.pending_interrupt(), Some(0x30));
+
+        // Only the ends of level-triggered interrupts are broadcast.
+        let eois = Arc::new(Mutex::new(Vec::new()));
+        let eois_clone = eois.clone();
+        let mut lapic = lapic.with_eoi_handler(Box::new(move |vector| {
+            eois_clone.lock().unwrap().push(vector)
+        }));
+        lapic.accept_interrupt(0x90, true);
+        lapic.accept_interrupt(0x80, false);
+        lapic.acknowledge_interrupt(0x90);
+        lapic.acknowledge_interrupt(0x80);
+        write(&mut lapic, REG_EOI, 0);
+        write(&mut lapic, REG_EOI, 0);
+        write(&mut lapic, REG_EOI, 0);
+        assert_eq!(*eois.lock().unwrap(), [0x90]);
+
+        // Self IPIs are handled locally, while the others are routed through the callback.
+        write(&mut lapic, REG_ICR_LOW, (1 << 18) | 0x60);
+        assert_eq!(lapic.pending_interrupt(), Some(0x60));
+        write(&mut lapic, REG_ICR_HIGH, 2 << 24);
+        write(&mut lapic, REG_ICR_LOW, 0x4000 | 0x70);
+        let ipi = ipis.lock().unwrap()[0];
+        assert_eq!(ipi.source, 1);
+        assert_eq!(ipi.destination, 2);
+        assert_eq!(ipi.vector, 0x70);
+        assert!(ipi.assert);
+
+        // x2APIC interface.
+        assert_eq!(lapic.x2apic_read(X2APIC_MSR_BASE + REG_ID as u32), None);
+        lapic.set_x2apic(true);
+        assert_eq!(lapic.x2apic_read(X2APIC_MSR_BASE + REG_ID as u32), Some(1));
+        assert_eq!(
+            lapic.x2apic_read(X2APIC_MSR_BASE + REG_LDR as u32),
+            Some(1 << 1)
+        );
+        assert!(lapic.x2apic_write(X2APIC_MSR_BASE + REG_ICR_LOW as u32, (0x100 << 32) | 0x80));
+        assert_eq!(ipis.lock().unwrap()[1].destination, 0x100);
+        assert!(lapic.x2apic_write(X2APIC_MSR_BASE + REG_SELF_IPI as u32, 0xe0));
+        assert_eq!(lapic.pending_interrupt(), Some(0xe0));
+        // The MMIO interface is disabled in x2APIC mode.
+        assert_eq!(read(&mut lapic, REG_VERSION), 0);
+    }
+
+    #[test]
+    fn test_timer() {
+        let clock = Arc::new(ManualClock::new());
+        let (mut lapic, _) = test_lapic(clock.clone());
+        write(&mut lapic, REG_SVR, SVR_ENABLE | 0xff);
+        // Divide by 2, one-shot.
+        write(&mut lapic, REG_TIMER_DIVIDE, 0);
+        write(&mut lapic, REG_LVT_TIMER, 0x40);
+        write(&mut lapic, REG_TIMER_INITIAL, 100);
+        assert_eq!(lapic.timer_deadline(), Some(200));
+
+        clock.advance(50);
+        assert_eq!(read(&mut lapic, REG_TIMER_CURRENT), 75);
+        assert!(!lapic.check_timer());
+
+        // Saving and restoring the state preserves the current count.
+        let state = VersionedState::save("lapic0", &lapic.save_state());
+        clock.advance(1000);
+        lapic.restore_state(&state.restore("lapic0").unwrap());
+        assert_eq!(read(&mut lapic, REG_TIMER_CURRENT), 75);
+        assert_eq!(lapic.timer_deadline(), Some(1200));
+
+        clock.advance(150);
+        assert!(lapic.check_timer());
+        assert_eq!(lapic.pending_interrupt(), Some(0x40));
+        assert_eq!(read(&mut lapic, REG_TIMER_CURRENT), 0);
+        assert_eq!(lapic.timer_deadline(), None);
+
+        // Periodic mode reloads the initial count.
+        write(&mut lapic, REG_LVT_TIMER, LVT_TIMER_PERIODIC | 0x41);
+        write(&mut lapic, REG_TIMER_INITIAL, 10);
+        clock.advance(25);
+        assert!(lapic.check_timer());
+        assert_eq!(lapic.timer_deadline(), Some(1240));
+        assert_eq!(read(&mut lapic, REG_TIMER_CURRENT), 8);
+
+        // A late check skips the missed periods, without changing the phase of the timer.
+        clock.advance(47);
+        assert!(lapic.check_timer());
+        assert_eq!(lapic.timer_deadline(), Some(1280));
+        assert_eq!(read(&mut lapic, REG_TIMER_CURRENT), 4);
+        assert!(!lapic.check_timer());
+    }
+}
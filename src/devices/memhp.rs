@@ -0,0 +1,397 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! ACPI memory hotplug controller, compatible with the register block described by the
+//! `MHPD` device of the usual DSDT memory hotplug methods.
+//!
+//! Each slot of the [`AcpiMemoryHotplug`](struct.AcpiMemoryHotplug.html) controller holds a
+//! DIMM. Adding memory takes three steps:
+//!
+//! 1. The VMM allocates the guest physical range and the KVM memory slot of the DIMM,
+//!    using the constraints returned by
+//!    [`constraints`](struct.AcpiMemoryHotplug.html#method.constraints).
+//! 2. [`plug`](struct.AcpiMemoryHotplug.html#method.plug) assigns the resources to a free
+//!    slot, and sends a `VmEvent::AddMemory` event asking the VMM to map the memory.
+//! 3. Once the memory is mapped, `HotplugNotifier::notify_add` lets the guest know about
+//!    the DIMM.
+//!
+//! Removal is requested with `HotplugNotifier::notify_remove`. When the guest ejects the
+//! DIMM, the controller sends a `VmEvent::RemoveMemory` event, and the VMM gets the
+//! resources of the slot back from
+//! [`release`](struct.AcpiMemoryHotplug.html#method.release).
+
+use std::sync::Mutex;
+
+use crate::bus::{PioAddress, PioAddressValue, PioOffset};
+use crate::events::{VmEvent, VmEventSender};
+use crate::hotplug::{Error, HotplugNotifier};
+use crate::resources::{DeviceResources, ResourceConstraint};
+use crate::DevicePio;
+
+/// I/O port of the register block on the QEMU `pc` and `q35` machines.
+pub const MEMHP_DEFAULT_PORT: PioAddressValue = 0x0a00;
+
+/// Size of the register block.
+pub const MEMHP_SIZE: PioAddressValue = 0x18;
+
+/// Alignment of the DIMMs, which matches the size of the Linux memory blocks on x86.
+pub const MEMHP_ALIGNMENT: u64 = 128 << 20;
+
+// Registers of the selected slot. Writes to the first three go to the selector and to the
+// `_OST` event and status registers instead.
+const BASE_LO: u64 = 0x00;
+const BASE_HI: u64 = 0x04;
+const SIZE_LO: u64 = 0x08;
+const SIZE_HI: u64 = 0x0c;
+const PROXIMITY: u64 = 0x10;
+const FLAGS: u64 = 0x14;
+const SELECTOR: u64 = 0x00;
+
+const FLAG_ENABLED: u8 = 1 << 0;
+const FLAG_INSERT: u8 = 1 << 1;
+const FLAG_REMOVE: u8 = 1 << 2;
+const FLAG_EJECT: u8 = 1 << 3;
+
+struct Dimm {
+    resources: DeviceResources,
+    base: u64,
+    size: u64,
+    memslot: u32,
+    node: u32,
+    enabled: bool,
+    insert: bool,
+    remove: bool,
+}
+
+struct State {
+    selector: u32,
+    slots: Vec<Option<Dimm>>,
+    ejected: Vec<u32>,
+}
+
+impl State {
+    fn selected(&mut self) -> Option<&mut Dimm> {
+        self.slots
+            .get_mut(self.selector as usize)
+            .and_then(Option::as_mut)
+    }
+}
+
+/// ACPI memory hotplug register block.
+pub struct AcpiMemoryHotplug {
+    window: Option<(u64, u64)>,
+    events: VmEventSender,
+    notify_guest: Box<dyn Fn() + Send + Sync>,
+    state: Mutex<State>,
+}
+
+impl AcpiMemoryHotplug {
+    /// Create a new controller with `slots` DIMM slots. The memory events are sent through
+    /// `events`, and `notify_guest` is invoked to raise the notification interrupt (usually
+    /// the ACPI SCI) whenever a new guest event becomes pending.
+    pub fn new(
+        slots: u32,
+        events: VmEventSender,
+        notify_guest: Box<dyn Fn() + Send + Sync>,
+    ) -> Self {
+        AcpiMemoryHotplug {
+            window: None,
+            events,
+            notify_guest,
+            state: Mutex::new(State {
+                selector: 0,
+                slots: (0..slots).map(|_| None).collect(),
+                ejected: Vec::new(),
+            }),
+        }
+    }
+
+    /// Place the hotplugged memory within [`start`, `end`].
+    pub fn with_window(mut self, start: u64, end: u64) -> Self {
+        self.window = Some((start, end));
+        self
+    }
+
+    /// Return the resources to allocate for a DIMM of `size` bytes.
+    pub fn constraints(&self, size: u64) -> Vec<ResourceConstraint> {
+        vec![
+            ResourceConstraint::mmio_with_constraints(size, self.window, MEMHP_ALIGNMENT),
+            ResourceConstraint::new_kvm_mem_slot(1, None),
+        ]
+    }
+
+    /// Assign the memory range and KVM memory slot of `resources` to a free slot, and ask
+    /// the VMM to map the memory. The DIMM belongs to the NUMA node `node`. Returns the slot,
+    /// which isn't visible to the guest until `notify_add` is called for it.
+    pub fn plug(&self, resources: DeviceResources, node: u32) -> Result<u32, Error> {
+        let (base, size) = resources
+            .get_mmio_address_ranges()
+            .first()
+            .copied()
+            .ok_or(Error::MissingResource("MMIO address range"))?;
+        let memslot = resources
+            .get_kvm_mem_slots()
+            .first()
+            .copied()
+            .ok_or(Error::MissingResource("KVM memory slot"))?;
+
+        let mut state = self.state.lock().unwrap();
+        let (slot, entry) = state
+            .slots
+            .iter_mut()
+            .enumerate()
+            .find(|(_, dimm)| dimm.is_none())
+            .ok_or(Error::NoFreeSlot)?;
+        *entry = Some(Dimm {
+            resources,
+            base,
+            size,
+            memslot,
+            node,
+            enabled: false,
+            insert: false,
+            remove: false,
+        });
+        let _ = self.events.send(VmEvent::AddMemory {
+            memslot,
+            base,
+            size,
+        });
+        Ok(slot as u32)
+    }
+
+    /// Free `slot`, and return its resources so they can go back to the allocator. Slots
+    /// in use by the guest can't be released before they are ejected.
+    pub fn release(&self, slot: u32) -> Option<DeviceResources> {
+        let mut state = self.state.lock().unwrap();
+        let entry = state.slots.get_mut(slot as usize)?;
+        if entry.as_ref()?.enabled {
+            return None;
+        }
+        entry.take().map(|dimm| dimm.resources)
+    }
+
+    fn read(&self, offset: u64, data: &mut [u8]) {
+        let mut state = self.state.lock().unwrap();
+        let value = match state.selected() {
+            Some(dimm) => match offset & !0x3 {
+                BASE_LO => dimm.base as u32,
+                BASE_HI => (dimm.base >> 32) as u32,
+                SIZE_LO => dimm.size as u32,
+                SIZE_HI => (dimm.size >> 32) as u32,
+                PROXIMITY => dimm.node,
+                FLAGS => {
+                    let mut flags = 0;
+                    if dimm.enabled {
+                        flags |= FLAG_ENABLED;
+                    }
+                    if dimm.insert {
+                        flags |= FLAG_INSERT;
+                    }
+                    if dimm.remove {
+                        flags |= FLAG_REMOVE;
+                    }
+                    u32::from(flags)
+                }
+                _ => 0,
+            },
+            None => 0,
+        };
+        let bytes = value.to_le_bytes();
+        let start = (offset & 0x3) as usize;
+        for (idx, byte) in data.iter_mut().enumerate() {
+            *byte = bytes.get(start + idx).copied().unwrap_or(0);
+        }
+    }
+
+    fn write(&self, offset: u64, data: &[u8]) {
+        let mut bytes = [0; 4];
+        let len = data.len().min(4);
+        bytes[..len].copy_from_slice(&data[..len]);
+        let value = u32::from_le_bytes(bytes);
+
+        let mut state = self.state.lock().unwrap();
+        match offset {
+            SELECTOR => state.selector = value,
+            FLAGS => {
+                let selector = state.selector;
+                let dimm = match state.selected() {
+                    Some(dimm) => dimm,
+                    None => return,
+                };
+                let flags = value as u8;
+                if flags & FLAG_INSERT != 0 {
+                    dimm.insert = false;
+                }
+                if flags & FLAG_REMOVE != 0 {
+                    dimm.remove = false;
+                }
+                if flags & FLAG_EJECT != 0 && dimm.enabled {
+                    dimm.enabled = false;
+                    dimm.remove = false;
+                    let event = VmEvent::RemoveMemory {
+                        memslot: dimm.memslot,
+                        base: dimm.base,
+                        size: dimm.size,
+                    };
+                    state.ejected.push(selector);
+                    let _ = self.events.send(event);
+                }
+            }
+            // The `_OST` status reports are only informative.
+            _ => {}
+        }
+    }
+}
+
+impl HotplugNotifier for AcpiMemoryHotplug {
+    fn notify_add(&self, slot: u32) -> Result<(), Error> {
+        {
+            let mut state = self.state.lock().unwrap();
+            let dimm = state
+                .slots
+                .get_mut(slot as usize)
+                .and_then(Option::as_mut)
+                .ok_or(Error::InvalidSlot(slot))?;
+            dimm.enabled = true;
+            dimm.insert = true;
+        }
+        (self.notify_guest)();
+        Ok(())
+    }
+
+    fn notify_remove(&self, slot: u32) -> Result<(), Error> {
+        {
+            let mut state = self.state.lock().unwrap();
+            let dimm = state
+                .slots
+                .get_mut(slot as usize)
+                .and_then(Option::as_mut)
+                .filter(|dimm| dimm.enabled)
+                .ok_or(Error::InvalidSlot(slot))?;
+            if dimm.remove {
+                return Err(Error::UnplugPending(slot));
+            }
+            dimm.remove = true;
+        }
+        (self.notify_guest)();
+        Ok(())
+    }
+
+    fn take_ejected(&self) -> Vec<u32> {
+        std::mem::take(&mut self.state.lock().unwrap().ejected)
+    }
+}
+
+impl DevicePio for AcpiMemoryHotplug {
+    fn pio_read(&self, _base: PioAddress, offset: PioOffset, data: &mut [u8]) {
+        self.read(u64::from(offset), data);
+    }
+
+    fn pio_write(&self, _base: PioAddress, offset: PioOffset, data: &[u8]) {
+        self.write(u64::from(offset), data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use crate::events::vm_event_channel;
+    use crate::resources::Resource;
+
+    fn read(hotplug: &AcpiMemoryHotplug, offset: u64) -> u32 {
+        let mut data = [0; 4];
+        hotplug.pio_read(
+            PioAddress(MEMHP_DEFAULT_PORT),
+            PioOffset(offset as PioAddressValue),
+            &mut data,
+        );
+        u32::from_le_bytes(data)
+    }
+
+    fn write(hotplug: &AcpiMemoryHotplug, offset: u64, value: u32) {
+        hotplug.pio_write(
+            PioAddress(MEMHP_DEFAULT_PORT),
+            PioOffset(offset as PioAddressValue),
+            &value.to_le_bytes(),
+        );
+    }
+
+    #[test]
+    fn test_acpi_memory_hotplug() {
+        let (tx, rx) = vm_event_channel();
+        let count = Arc::new(AtomicUsize::new(0));
+        let count_clone = count.clone();
+        let hotplug = AcpiMemoryHotplug::new(
+            1,
+            tx.for_device("memhp"),
+            Box::new(move || {
+                count_clone.fetch_add(1, Ordering::SeqCst);
+            }),
+        )
+        .with_window(1 << 32, (2 << 32) - 1);
+
+        match hotplug.constraints(MEMHP_ALIGNMENT)[0] {
+            ResourceConstraint::MmioAddress { range, align, size } => {
+                assert_eq!(range, Some((1 << 32, (2 << 32) - 1)));
+                assert_eq!((align, size), (MEMHP_ALIGNMENT, MEMHP_ALIGNMENT));
+            }
+            _ => panic!("unexpected constraint"),
+        }
+        let mut resources = DeviceResources::new();
+        resources.append(Resource::MmioAddressRange {
+            base: 0x1_2000_0000,
+            size: MEMHP_ALIGNMENT,
+        });
+        assert_eq!(
+            hotplug.plug(resources.clone(), 0),
+            Err(Error::MissingResource("KVM memory slot"))
+        );
+        resources.append(Resource::KvmMemSlot(5));
+
+        // The memory is mapped before the guest is told about it.
+        assert_eq!(hotplug.plug(resources.clone(), 1), Ok(0));
+        assert_eq!(hotplug.plug(resources, 1), Err(Error::NoFreeSlot));
+        let add = VmEvent::AddMemory {
+            memslot: 5,
+            base: 0x1_2000_0000,
+            size: MEMHP_ALIGNMENT,
+        };
+        assert_eq!(rx.try_recv().unwrap().event, add);
+        write(&hotplug, SELECTOR, 0);
+        assert_eq!(read(&hotplug, FLAGS), 0);
+        hotplug.notify_add(0).unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        assert_eq!(read(&hotplug, FLAGS), u32::from(FLAG_ENABLED | FLAG_INSERT));
+        assert_eq!(read(&hotplug, BASE_LO), 0x2000_0000);
+        assert_eq!(read(&hotplug, BASE_HI), 1);
+        assert_eq!(read(&hotplug, SIZE_LO), MEMHP_ALIGNMENT as u32);
+        assert_eq!(read(&hotplug, PROXIMITY), 1);
+        write(&hotplug, FLAGS, u32::from(FLAG_INSERT));
+        assert_eq!(read(&hotplug, FLAGS), u32::from(FLAG_ENABLED));
+
+        // Enabled DIMMs are only released after the guest ejects them.
+        assert!(hotplug.release(0).is_none());
+        hotplug.notify_remove(0).unwrap();
+        assert_eq!(hotplug.notify_remove(0), Err(Error::UnplugPending(0)));
+        assert_eq!(read(&hotplug, FLAGS), u32::from(FLAG_ENABLED | FLAG_REMOVE));
+        write(&hotplug, FLAGS, u32::from(FLAG_EJECT));
+        assert_eq!(read(&hotplug, FLAGS), 0);
+        assert_eq!(
+            rx.try_recv().unwrap().event,
+            VmEvent::RemoveMemory {
+                memslot: 5,
+                base: 0x1_2000_0000,
+                size: MEMHP_ALIGNMENT,
+            }
+        );
+        assert_eq!(hotplug.take_ejected(), vec![0]);
+        let released = hotplug.release(0).unwrap();
+        assert_eq!(released.get_kvm_mem_slots(), vec![5]);
+        assert_eq!(hotplug.notify_add(0), Err(Error::InvalidSlot(0)));
+    }
+}
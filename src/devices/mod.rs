@@ -0,0 +1,23 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Models of common platform devices, which can be registered with the `IoManager`.
+
+pub mod acpi_gpe;
+pub mod acpi_pm;
+pub mod cpuhp;
+pub mod debugcon;
+pub mod flash;
+pub mod hpet;
+pub mod i6300esb;
+pub mod ivshmem;
+pub mod lapic;
+pub mod memhp;
+pub mod pit;
+pub mod pl031;
+pub mod pl061;
+pub mod port92;
+pub mod post;
+pub mod ram;
+pub mod tpm_tis;
+pub mod vtd;
@@ -0,0 +1,414 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Intel 8254 programmable interval timer.
+//!
+//! The [`Pit`](struct.Pit.html) model handles the counter ports (`0x40` - `0x43`) and the
+//! speaker/NMI status port (`0x61`), which gates channel 2 and reports its output. The same
+//! object is meant to be registered on both ranges; accesses are told apart using the base
+//! address of the range. Channel 0 drives IRQ 0: expirations are processed when the VMM calls
+//! [`check_timer`](struct.Pit.html#method.check_timer). Counters are derived from a `Clock`,
+//! like the other timer devices.
+
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+use crate::bus::{PioAddress, PioAddressValue, PioOffset};
+use crate::time::Clock;
+use crate::MutDevicePio;
+
+/// Base of the PIT counter ports.
+pub const PIT_PORT: PioAddressValue = 0x40;
+
+/// Number of PIT counter ports.
+pub const PIT_PORT_SIZE: PioAddressValue = 0x4;
+
+/// The speaker (or NMI status and control) port, which exposes channel 2.
+pub const PIT_SPEAKER_PORT: PioAddressValue = 0x61;
+
+/// Input frequency of the counters.
+pub const PIT_FREQ_HZ: u64 = 1_193_182;
+
+const NUM_CHANNELS: usize = 3;
+const CONTROL_PORT: PioAddressValue = 3;
+
+// Access modes of the control word.
+const ACCESS_LATCH: u8 = 0;
+const ACCESS_LSB: u8 = 1;
+const ACCESS_MSB: u8 = 2;
+const ACCESS_WORD: u8 = 3;
+
+const SPEAKER_GATE: u8 = 1 << 0;
+const SPEAKER_DATA: u8 = 1 << 1;
+const SPEAKER_REFRESH: u8 = 1 << 4;
+const SPEAKER_OUTPUT: u8 = 1 << 5;
+
+const NS_PER_SEC: u128 = 1_000_000_000;
+
+// The conversions are done on 128 bits, since the counters run for as long as the VM does.
+fn ticks_to_ns(ticks: u64) -> u64 {
+    let ns = u128::from(ticks) * NS_PER_SEC / u128::from(PIT_FREQ_HZ);
+    u64::try_from(ns).unwrap_or(u64::MAX)
+}
+
+fn ns_to_ticks(ns: u64) -> u64 {
+    // The tick rate is lower than 1 GHz, so the result always fits.
+    (u128::from(ns) * u128::from(PIT_FREQ_HZ) / NS_PER_SEC) as u64
+}
+
+#[derive(Clone, Copy, Default)]
+struct Channel {
+    mode: u8,
+    access: u8,
+    // Initial count written by the guest; 0 stands for 0x10000.
+    reload: u16,
+    // Time at which the counter was last loaded.
+    start: u64,
+    armed: bool,
+    // The LSB of a word access has been written, and the MSB is expected next.
+    write_msb: bool,
+    // The LSB of a word access has been read, and the MSB is expected next.
+    read_msb: bool,
+    latch: Option<u16>,
+    // LSB held while waiting for the MSB of a word write.
+    write_lsb: u8,
+}
+
+impl Channel {
+    fn period(&self) -> u64 {
+        if self.reload == 0 {
+            0x10000
+        } else {
+            u64::from(self.reload)
+        }
+    }
+
+    fn periodic(&self) -> bool {
+        // Modes 6 and 7 are aliases of 2 and 3.
+        self.mode & 0x2 != 0
+    }
+
+    fn elapsed_ticks(&self, now: u64) -> u64 {
+        ns_to_ticks(now.saturating_sub(self.start))
+    }
+
+    // Current value of the counter. Mode 3 is reported as a rate generator, rather than with
+    // the halved count of the square wave generator.
+    fn count(&self, now: u64) -> u16 {
+        let ticks = self.elapsed_ticks(now);
+        let period = self.period();
+        let value = if self.periodic() {
+            period - ticks % period
+        } else {
+            period.wrapping_sub(ticks)
+        };
+        value as u16
+    }
+
+    fn output(&self, now: u64) -> bool {
+        let ticks = self.elapsed_ticks(now);
+        let period = self.period();
+        match self.mode & 0x3 {
+            // Rate generator: low for one tick at the end of each period.
+            2 => ticks % period != period - 1,
+            // Square wave: high during the first half of each period.
+            3 => ticks % period < period.div_ceil(2),
+            // One-shot modes: high once the count reaches zero.
+            _ => ticks >= period,
+        }
+    }
+
+    fn deadline(&self, now: u64) -> u64 {
+        let period = self.period();
+        let mut ticks = period;
+        if self.periodic() {
+            ticks = (self.elapsed_ticks(now) / period + 1) * period;
+        }
+        self.start.saturating_add(ticks_to_ns(ticks))
+    }
+}
+
+/// Model of the 8254 programmable interval timer.
+pub struct Pit {
+    channels: [Channel; NUM_CHANNELS],
+    speaker: u8,
+    // Deadline of the next channel 0 interrupt, if armed.
+    irq_deadline: Option<u64>,
+    clock: Arc<dyn Clock>,
+    trigger_irq: Box<dyn Fn() + Send + Sync>,
+}
+
+impl Pit {
+    /// Create a new PIT driven by `clock`, which triggers IRQ 0 by calling `trigger_irq`.
+    pub fn new(clock: Arc<dyn Clock>, trigger_irq: Box<dyn Fn() + Send + Sync>) -> Self {
+        Pit {
+            channels: [Channel::default(); NUM_CHANNELS],
+            speaker: 0,
+            irq_deadline: None,
+            clock,
+            trigger_irq,
+        }
+    }
+
+    /// Return the time (as reported by the clock) of the next channel 0 interrupt, if any.
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.irq_deadline
+    }
+
+    /// Trigger IRQ 0 if channel 0 expired since the last call. Returns `true` when the
+    /// interrupt has been triggered.
+    pub fn check_timer(&mut self) -> bool {
+        let now = self.clock.now();
+        match self.irq_deadline {
+            Some(deadline) if deadline <= now => {
+                let channel = &self.channels[0];
+                self.irq_deadline = if channel.periodic() {
+                    Some(channel.deadline(now))
+                } else {
+                    None
+                };
+                (self.trigger_irq)();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn load(&mut self, idx: usize) {
+        let now = self.clock.now();
+        let channel = &mut self.channels[idx];
+        channel.start = now;
+        channel.armed = true;
+        if idx == 0 {
+            self.irq_deadline = Some(channel.deadline(now));
+        }
+    }
+
+    fn write_control(&mut self, value: u8) {
+        let idx = usize::from(value >> 6);
+        if idx == NUM_CHANNELS {
+            // Read-back commands are not supported.
+            return;
+        }
+        let now = self.clock.now();
+        let channel = &mut self.channels[idx];
+        let access = (value >> 4) & 0x3;
+        if access == ACCESS_LATCH {
+            if channel.latch.is_none() {
+                channel.latch = Some(channel.count(now));
+            }
+            return;
+        }
+
+        channel.access = access;
+        channel.mode = (value >> 1) & 0x7;
+        channel.write_msb = false;
+        channel.read_msb = false;
+        channel.latch = None;
+        channel.armed = false;
+        if idx == 0 {
+            self.irq_deadline = None;
+        }
+    }
+
+    fn write_counter(&mut self, idx: usize, value: u8) {
+        let channel = &mut self.channels[idx];
+        match channel.access {
+            ACCESS_LSB => channel.reload = u16::from(value),
+            ACCESS_MSB => channel.reload = u16::from(value) << 8,
+            ACCESS_WORD if !channel.write_msb => {
+                channel.write_lsb = value;
+                channel.write_msb = true;
+                return;
+            }
+            ACCESS_WORD => {
+                channel.reload = u16::from_le_bytes([channel.write_lsb, value]);
+                channel.write_msb = false;
+            }
+            _ => return,
+        }
+        self.load(idx);
+    }
+
+    fn read_counter(&mut self, idx: usize) -> u8 {
+        let now = self.clock.now();
+        let channel = &mut self.channels[idx];
+        let value = channel.latch.unwrap_or_else(|| channel.count(now));
+        let byte = match channel.access {
+            ACCESS_MSB => (value >> 8) as u8,
+            ACCESS_WORD if channel.read_msb => (value >> 8) as u8,
+            _ => value as u8,
+        };
+
+        if channel.access == ACCESS_WORD {
+            channel.read_msb = !channel.read_msb;
+            if channel.read_msb {
+                return byte;
+            }
+        }
+        // The latch is released once it has been read completely.
+        channel.latch = None;
+        byte
+    }
+
+    fn read_speaker(&mut self) -> u8 {
+        // The refresh bit toggles on every read, which is enough for the delay loops that
+        // poll it.
+        self.speaker ^= SPEAKER_REFRESH;
+        let channel = &self.channels[2];
+        let mut value = self.speaker & (SPEAKER_GATE | SPEAKER_DATA | SPEAKER_REFRESH);
+        if channel.armed && channel.output(self.clock.now()) {
+            value |= SPEAKER_OUTPUT;
+        }
+        value
+    }
+
+    fn write_speaker(&mut self, value: u8) {
+        let rising_gate = self.speaker & SPEAKER_GATE == 0 && value & SPEAKER_GATE != 0;
+        self.speaker = (self.speaker & SPEAKER_REFRESH) | (value & (SPEAKER_GATE | SPEAKER_DATA));
+        // A rising edge on the gate restarts the count of channel 2.
+        if rising_gate && self.channels[2].armed {
+            self.load(2);
+        }
+    }
+}
+
+impl MutDevicePio for Pit {
+    fn pio_read(&mut self, base: PioAddress, offset: PioOffset, data: &mut [u8]) {
+        if data.len() != 1 {
+            return;
+        }
+        data[0] = match (base.0, offset.raw()) {
+            (PIT_SPEAKER_PORT, 0) => self.read_speaker(),
+            (PIT_PORT, offset) if offset < CONTROL_PORT => self.read_counter(offset as usize),
+            _ => 0xff,
+        };
+    }
+
+    fn pio_write(&mut self, base: PioAddress, offset: PioOffset, data: &[u8]) {
+        if data.len() != 1 {
+            return;
+        }
+        match (base.0, offset.raw()) {
+            (PIT_SPEAKER_PORT, 0) => self.write_speaker(data[0]),
+            (PIT_PORT, CONTROL_PORT) => self.write_control(data[0]),
+            (PIT_PORT, offset) if offset < CONTROL_PORT => {
+                self.write_counter(offset as usize, data[0])
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::time::ManualClock;
+
+    const BASE: PioAddress = PioAddress(PIT_PORT);
+    const SPEAKER: PioAddress = PioAddress(PIT_SPEAKER_PORT);
+
+    fn read(pit: &mut Pit, base: PioAddress, offset: PioAddressValue) -> u8 {
+        let mut data = [0u8];
+        pit.pio_read(base, PioOffset(offset), &mut data);
+        data[0]
+    }
+
+    #[test]
+    fn test_pit() {
+        let clock = Arc::new(ManualClock::new());
+        let irqs = Arc::new(AtomicUsize::new(0));
+        let count = irqs.clone();
+        let mut pit = Pit::new(
+            clock.clone(),
+            Box::new(move || {
+                count.fetch_add(1, Ordering::SeqCst);
+            }),
+        );
+
+        // Channel 0, LSB then MSB, rate generator, with a period of 1000 ticks.
+        pit.pio_write(BASE, PioOffset(CONTROL_PORT), &[0x34]);
+        pit.pio_write(BASE, PioOffset(0), &[0xe8]);
+        assert_eq!(pit.next_deadline(), None);
+        pit.pio_write(BASE, PioOffset(0), &[0x03]);
+        let period = ticks_to_ns(1000);
+        assert_eq!(pit.next_deadline(), Some(period));
+
+        clock.advance(ticks_to_ns(100) + 1);
+        // Latch the count, and read it while the counter keeps running.
+        pit.pio_write(BASE, PioOffset(CONTROL_PORT), &[0x00]);
+        clock.advance(ticks_to_ns(100));
+        assert_eq!(read(&mut pit, BASE, 0), 0x84);
+        assert_eq!(read(&mut pit, BASE, 0), 0x03);
+        assert!(!pit.check_timer());
+
+        clock.advance(period);
+        assert!(pit.check_timer());
+        assert_eq!(irqs.load(Ordering::SeqCst), 1);
+        assert_eq!(pit.next_deadline(), Some(2 * period));
+    }
+
+    #[test]
+    fn test_long_uptime() {
+        let clock = Arc::new(ManualClock::new());
+        let irqs = Arc::new(AtomicUsize::new(0));
+        let count = irqs.clone();
+        let mut pit = Pit::new(
+            clock.clone(),
+            Box::new(move || {
+                count.fetch_add(1, Ordering::SeqCst);
+            }),
+        );
+
+        // Program a 1000 ticks rate generator, and let it run for 5 hours.
+        pit.pio_write(BASE, PioOffset(CONTROL_PORT), &[0x34]);
+        pit.pio_write(BASE, PioOffset(0), &[0xe8]);
+        pit.pio_write(BASE, PioOffset(0), &[0x03]);
+        let hours = 5 * 3600 * 1_000_000_000u64;
+        clock.advance(hours);
+        assert!(pit.check_timer());
+        let ticks = ns_to_ticks(hours);
+        assert_eq!(ticks, 5 * 3600 * PIT_FREQ_HZ);
+        let deadline = pit.next_deadline().unwrap();
+        assert!(deadline > hours && deadline <= hours + ticks_to_ns(1000));
+
+        // The counter still counts down from the right value.
+        pit.pio_write(BASE, PioOffset(CONTROL_PORT), &[0x00]);
+        let value = u16::from(read(&mut pit, BASE, 0)) | u16::from(read(&mut pit, BASE, 0)) << 8;
+        assert_eq!(u64::from(value), 1000 - ticks % 1000);
+
+        assert_eq!(ticks_to_ns(u64::MAX), u64::MAX);
+        assert_eq!(ns_to_ticks(u64::MAX), 22_010_322_987_356_910);
+    }
+
+    #[test]
+    fn test_speaker_port() {
+        let clock = Arc::new(ManualClock::new());
+        let mut pit = Pit::new(clock.clone(), Box::new(|| {}));
+
+        // The refresh bit toggles on every read.
+        let first = read(&mut pit, SPEAKER, 0);
+        assert_ne!(
+            first & SPEAKER_REFRESH,
+            read(&mut pit, SPEAKER, 0) & SPEAKER_REFRESH
+        );
+
+        // Channel 2, LSB only, interrupt on terminal count, as used for TSC calibration.
+        pit.pio_write(SPEAKER, PioOffset(0), &[SPEAKER_GATE]);
+        pit.pio_write(BASE, PioOffset(CONTROL_PORT), &[0x90]);
+        pit.pio_write(BASE, PioOffset(2), &[100]);
+        assert_eq!(read(&mut pit, SPEAKER, 0) & SPEAKER_OUTPUT, 0);
+        clock.advance(ticks_to_ns(101));
+        assert_ne!(read(&mut pit, SPEAKER, 0) & SPEAKER_OUTPUT, 0);
+
+        // A rising edge on the gate restarts the count.
+        pit.pio_write(SPEAKER, PioOffset(0), &[0]);
+        pit.pio_write(SPEAKER, PioOffset(0), &[SPEAKER_GATE]);
+        assert_eq!(read(&mut pit, SPEAKER, 0) & SPEAKER_OUTPUT, 0);
+        // Channel 2 doesn't trigger interrupts.
+        assert_eq!(pit.next_deadline(), None);
+    }
+}
@@ -0,0 +1,281 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! ARM PrimeCell PL031 real time clock, as found on the aarch64 `virt` platform.
+//!
+//! The RTC counts seconds in a 32-bit data register, which the guest sets through the load
+//! register. When the counter reaches the value of the match register, the interrupt of the
+//! device is raised, unless masked, and stays raised until the guest clears it. The counter
+//! follows a `VirtualClock`, so it stops while the VM is paused, and the match interrupt is
+//! timed with a deadline timer of the same clock, which the VMM runs with `run_expired`.
+
+use std::sync::{Arc, Mutex};
+
+use crate::bus::{MmioAddress, MmioOffset};
+use crate::interrupt::LineInterrupt;
+use crate::time::{TimerId, VirtualClock};
+use crate::DeviceMmio;
+
+/// Guest physical address of the RTC on the QEMU `virt` machine.
+pub const PL031_DEFAULT_BASE: u64 = 0x0901_0000;
+
+/// Size of the register block.
+pub const PL031_SIZE: u64 = 0x1000;
+
+const NS_PER_SEC: u64 = 1_000_000_000;
+
+const DR: u64 = 0x000;
+const MR: u64 = 0x004;
+const LR: u64 = 0x008;
+const CR: u64 = 0x00c;
+const IMSC: u64 = 0x010;
+const RIS: u64 = 0x014;
+const MIS: u64 = 0x018;
+const ICR: u64 = 0x01c;
+const ID_BASE: u64 = 0xfe0;
+
+// Peripheral and PrimeCell identification registers, one byte per word.
+const PL031_ID: [u8; 8] = [0x31, 0x10, 0x14, 0x00, 0x0d, 0xf0, 0x05, 0xb1];
+
+struct State {
+    // Value of the counter at `load_ns`.
+    load: u32,
+    load_ns: u64,
+    match_value: u32,
+    mask: bool,
+    raw_status: bool,
+    timer: Option<TimerId>,
+    // Incremented whenever the match timer is replaced, so stale callbacks are ignored.
+    generation: u64,
+}
+
+struct Inner {
+    clock: Arc<dyn VirtualClock>,
+    irq: Option<LineInterrupt>,
+    state: Mutex<State>,
+}
+
+impl Inner {
+    fn counter(&self, state: &State) -> u32 {
+        let elapsed = self.clock.now().saturating_sub(state.load_ns) / NS_PER_SEC;
+        state.load.wrapping_add(elapsed as u32)
+    }
+
+    // Drive the interrupt line with the masked status.
+    fn update_irq(&self, state: &State) {
+        if let Some(irq) = self.irq.as_ref() {
+            if state.raw_status && state.mask {
+                irq.assert();
+            } else {
+                irq.deassert();
+            }
+        }
+    }
+
+    // Cancel the pending match timer, which holds a reference to the device.
+    fn disarm(&self, state: &mut State) {
+        if let Some(id) = state.timer.take() {
+            self.clock.cancel_timer(id);
+        }
+        state.generation += 1;
+    }
+
+    // Schedule the match interrupt for the next time the counter reaches the match value,
+    // replacing the pending timer if any.
+    fn arm(self: &Arc<Self>, state: &mut State) {
+        self.disarm(state);
+        let now = self.clock.now();
+        let elapsed = now.saturating_sub(state.load_ns) / NS_PER_SEC;
+        let counter = state.load.wrapping_add(elapsed as u32);
+        // The counter only matches when it changes to the match value.
+        let seconds = match state.match_value.wrapping_sub(counter) {
+            0 => 1 << 32,
+            seconds => u64::from(seconds),
+        };
+        let deadline = state.load_ns + (elapsed + seconds) * NS_PER_SEC;
+        let inner = self.clone();
+        let generation = state.generation;
+        state.timer = Some(
+            self.clock
+                .add_timer(deadline, Box::new(move || inner.matched(generation))),
+        );
+    }
+
+    fn matched(self: &Arc<Self>, generation: u64) {
+        let mut state = self.state.lock().unwrap();
+        // The timer may have been replaced while its callback was pending.
+        if state.generation != generation {
+            return;
+        }
+        state.timer = None;
+        state.raw_status = true;
+        self.update_irq(&state);
+        self.arm(&mut state);
+    }
+}
+
+/// Model of a PL031 RTC.
+pub struct Pl031(Arc<Inner>);
+
+impl Pl031 {
+    /// Create a new RTC whose counter starts at `seconds` (usually the seconds elapsed since
+    /// the UNIX epoch according to the host), and runs with `clock`.
+    pub fn new(clock: Arc<dyn VirtualClock>, seconds: u32) -> Self {
+        let load_ns = clock.now();
+        let inner = Arc::new(Inner {
+            clock,
+            irq: None,
+            state: Mutex::new(State {
+                load: seconds,
+                load_ns,
+                match_value: 0,
+                mask: false,
+                raw_status: false,
+                timer: None,
+                generation: 0,
+            }),
+        });
+        inner.arm(&mut inner.state.lock().unwrap());
+        Pl031(inner)
+    }
+
+    /// Raise `irq` when the counter matches. Must be called before the device is shared.
+    pub fn with_irq(mut self, irq: LineInterrupt) -> Self {
+        self.0.disarm(&mut self.0.state.lock().unwrap());
+        if let Some(inner) = Arc::get_mut(&mut self.0) {
+            inner.irq = Some(irq);
+        }
+        self.0.arm(&mut self.0.state.lock().unwrap());
+        self
+    }
+
+    /// Return the current value of the counter.
+    pub fn seconds(&self) -> u32 {
+        self.0.counter(&self.0.state.lock().unwrap())
+    }
+}
+
+impl Drop for Pl031 {
+    fn drop(&mut self) {
+        self.0.disarm(&mut self.0.state.lock().unwrap());
+    }
+}
+
+impl DeviceMmio for Pl031 {
+    fn mmio_read(&self, _base: MmioAddress, offset: MmioOffset, data: &mut [u8]) {
+        let state = self.0.state.lock().unwrap();
+        let value = match offset.raw() & !0x3 {
+            DR => self.0.counter(&state),
+            MR => state.match_value,
+            LR => state.load,
+            // The counter can't be stopped, so it always reads as started.
+            CR => 1,
+            IMSC => u32::from(state.mask),
+            RIS => u32::from(state.raw_status),
+            MIS => u32::from(state.raw_status && state.mask),
+            reg if (ID_BASE..PL031_SIZE).contains(&reg) => {
+                u32::from(PL031_ID[((reg - ID_BASE) / 4) as usize])
+            }
+            _ => 0,
+        };
+        let bytes = value.to_le_bytes();
+        let start = (offset.raw() & 0x3) as usize;
+        for (idx, byte) in data.iter_mut().enumerate() {
+            *byte = bytes.get(start + idx).copied().unwrap_or(0);
+        }
+    }
+
+    fn mmio_write(&self, _base: MmioAddress, offset: MmioOffset, data: &[u8]) {
+        let mut bytes = [0; 4];
+        let len = data.len().min(4);
+        bytes[..len].copy_from_slice(&data[..len]);
+        let value = u32::from_le_bytes(bytes);
+        let mut state = self.0.state.lock().unwrap();
+        match offset.raw() {
+            MR => {
+                state.match_value = value;
+                self.0.arm(&mut state);
+            }
+            LR => {
+                state.load = value;
+                state.load_ns = self.0.clock.now();
+                self.0.arm(&mut state);
+            }
+            IMSC => {
+                state.mask = value & 1 != 0;
+                self.0.update_irq(&state);
+            }
+            ICR if value & 1 != 0 => {
+                state.raw_status = false;
+                self.0.update_irq(&state);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::interrupt::{IrqRouter, TriggerMode};
+    use crate::time::{Clock, ManualClock, ScaledClock};
+
+    fn read(rtc: &Pl031, offset: u64) -> u32 {
+        let mut data = [0; 4];
+        rtc.mmio_read(
+            MmioAddress(PL031_DEFAULT_BASE),
+            MmioOffset(offset),
+            &mut data,
+        );
+        u32::from_le_bytes(data)
+    }
+
+    fn write(rtc: &Pl031, offset: u64, value: u32) {
+        rtc.mmio_write(
+            MmioAddress(PL031_DEFAULT_BASE),
+            MmioOffset(offset),
+            &value.to_le_bytes(),
+        );
+    }
+
+    #[test]
+    fn test_pl031() {
+        let source = Arc::new(ManualClock::new());
+        let clock = Arc::new(ScaledClock::new(source.clone()));
+        let router = IrqRouter::new(Arc::new(|_, _| {}));
+        let rtc =
+            Pl031::new(clock.clone(), 1000).with_irq(router.line(34, TriggerMode::Level).unwrap());
+
+        assert_eq!(read(&rtc, ID_BASE), 0x31);
+        assert_eq!(read(&rtc, ID_BASE + 0x1c), 0xb1);
+        source.advance(2 * NS_PER_SEC + 1);
+        assert_eq!(read(&rtc, DR), 1002);
+
+        // The counter stops with the clock, and can be loaded by the guest.
+        clock.pause();
+        source.advance(5 * NS_PER_SEC);
+        assert_eq!(rtc.seconds(), 1002);
+        clock.resume();
+        write(&rtc, LR, 50);
+        assert_eq!(read(&rtc, DR), 50);
+
+        // The match interrupt is only raised once unmasked, and until cleared.
+        write(&rtc, MR, 53);
+        source.advance(3 * NS_PER_SEC - 1);
+        assert_eq!(clock.run_expired(), 0);
+        source.advance(1);
+        assert_eq!(clock.run_expired(), 1);
+        assert_eq!((read(&rtc, RIS), read(&rtc, MIS)), (1, 0));
+        assert!(!router.level(34));
+        write(&rtc, IMSC, 1);
+        assert!(router.level(34));
+        write(&rtc, ICR, 1);
+        assert_eq!(read(&rtc, RIS), 0);
+        assert!(!router.level(34));
+
+        // Reloading the counter reschedules the match.
+        write(&rtc, LR, 52);
+        assert_eq!(clock.next_deadline(), Some(clock.now() + NS_PER_SEC));
+    }
+}
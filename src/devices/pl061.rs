@@ -0,0 +1,275 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! ARM PrimeCell PL061 GPIO controller, as found on the aarch64 `virt` platform.
+//!
+//! The controller has eight lines, each configured by the guest as an input or an output.
+//! The VMM drives the inputs with [`set_input`](struct.Pl061.html#method.set_input), and
+//! changes of their level raise the interrupt of the controller according to the edge or
+//! level sensitivity selected by the guest. Guests booted without ACPI use one of the lines
+//! as their power button (described in the device tree as a `gpio-keys` node), which the
+//! VMM presses through a [`PowerButton`](struct.PowerButton.html) to request a graceful
+//! shutdown.
+
+use std::sync::{Arc, Mutex};
+
+use crate::bus::{MmioAddress, MmioOffset};
+use crate::interrupt::LineInterrupt;
+use crate::DeviceMmio;
+
+/// Guest physical address of the GPIO controller on the QEMU `virt` machine.
+pub const PL061_DEFAULT_BASE: u64 = 0x0903_0000;
+
+/// Size of the register block.
+pub const PL061_SIZE: u64 = 0x1000;
+
+/// Number of lines of the controller.
+pub const PL061_NUM_LINES: u8 = 8;
+
+/// Line used as the power button on the QEMU `virt` machine.
+pub const PL061_POWER_BUTTON_LINE: u8 = 3;
+
+// The data register is mirrored over the first 1 KiB, with address bits 9:2 masking the
+// lines which are accessed.
+const DATA_END: u64 = 0x400;
+const DIR: u64 = 0x400;
+const IS: u64 = 0x404;
+const IBE: u64 = 0x408;
+const IEV: u64 = 0x40c;
+const IE: u64 = 0x410;
+const RIS: u64 = 0x414;
+const MIS: u64 = 0x418;
+const IC: u64 = 0x41c;
+const AFSEL: u64 = 0x420;
+const ID_BASE: u64 = 0xfe0;
+
+// Peripheral and PrimeCell identification registers, one byte per word.
+const PL061_ID: [u8; 8] = [0x61, 0x10, 0x04, 0x00, 0x0d, 0xf0, 0x05, 0xb1];
+
+#[derive(Default)]
+struct State {
+    // Values written to the lines configured as outputs.
+    data: u8,
+    // Levels driven by the VMM on the lines configured as inputs.
+    inputs: u8,
+    dir: u8,
+    is: u8,
+    ibe: u8,
+    iev: u8,
+    ie: u8,
+    ris: u8,
+    afsel: u8,
+}
+
+impl State {
+    // Return the level of each line.
+    fn levels(&self) -> u8 {
+        (self.data & self.dir) | (self.inputs & !self.dir)
+    }
+
+    // Latch the interrupts caused by the lines changing from the `old` levels.
+    fn detect(&mut self, old: u8) {
+        let new = self.levels();
+        let changed = old ^ new;
+        let edge = changed & (self.ibe | !(new ^ self.iev));
+        let level = !(new ^ self.iev);
+        self.ris |= (edge & !self.is) | (level & self.is);
+    }
+}
+
+/// Model of a PL061 GPIO controller.
+pub struct Pl061 {
+    irq: Option<LineInterrupt>,
+    state: Mutex<State>,
+}
+
+impl Pl061 {
+    /// Create a new controller, with all the lines configured as low inputs.
+    pub fn new() -> Self {
+        Pl061 {
+            irq: None,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Raise `irq` when an unmasked interrupt is pending.
+    pub fn with_irq(mut self, irq: LineInterrupt) -> Self {
+        self.irq = Some(irq);
+        self
+    }
+
+    /// Drive the input `line` to `level`. Levels driven on lines configured as outputs only
+    /// take effect once the guest configures them as inputs.
+    pub fn set_input(&self, line: u8, level: bool) {
+        if line >= PL061_NUM_LINES {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        let old = state.levels();
+        if level {
+            state.inputs |= 1 << line;
+        } else {
+            state.inputs &= !(1 << line);
+        }
+        state.detect(old);
+        self.update_irq(&state);
+    }
+
+    /// Return the level of `line`, whether driven by the guest or by the VMM.
+    pub fn level(&self, line: u8) -> bool {
+        line < PL061_NUM_LINES && self.state.lock().unwrap().levels() & (1 << line) != 0
+    }
+
+    /// Return a handle pressing the power button wired to `line`.
+    pub fn power_button(self: &Arc<Self>, line: u8) -> PowerButton {
+        PowerButton {
+            gpio: self.clone(),
+            line,
+        }
+    }
+
+    // Drive the interrupt line with the masked status.
+    fn update_irq(&self, state: &State) {
+        if let Some(irq) = self.irq.as_ref() {
+            if state.ris & state.ie != 0 {
+                irq.assert();
+            } else {
+                irq.deassert();
+            }
+        }
+    }
+}
+
+impl Default for Pl061 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DeviceMmio for Pl061 {
+    fn mmio_read(&self, _base: MmioAddress, offset: MmioOffset, data: &mut [u8]) {
+        let state = self.state.lock().unwrap();
+        let value = match offset.raw() & !0x3 {
+            reg if reg < DATA_END => state.levels() & (reg >> 2) as u8,
+            DIR => state.dir,
+            IS => state.is,
+            IBE => state.ibe,
+            IEV => state.iev,
+            IE => state.ie,
+            RIS => state.ris,
+            MIS => state.ris & state.ie,
+            AFSEL => state.afsel,
+            reg if (ID_BASE..PL061_SIZE).contains(&reg) => PL061_ID[((reg - ID_BASE) / 4) as usize],
+            _ => 0,
+        };
+        let bytes = u32::from(value).to_le_bytes();
+        let start = (offset.raw() & 0x3) as usize;
+        for (idx, byte) in data.iter_mut().enumerate() {
+            *byte = bytes.get(start + idx).copied().unwrap_or(0);
+        }
+    }
+
+    fn mmio_write(&self, _base: MmioAddress, offset: MmioOffset, data: &[u8]) {
+        let value = match data.first() {
+            Some(value) => *value,
+            None => return,
+        };
+        let mut state = self.state.lock().unwrap();
+        let old = state.levels();
+        match offset.raw() {
+            reg if reg < DATA_END => {
+                let mask = (reg >> 2) as u8;
+                state.data = (state.data & !mask) | (value & mask);
+            }
+            DIR => state.dir = value,
+            IS => state.is = value,
+            IBE => state.ibe = value,
+            IEV => state.iev = value,
+            IE => state.ie = value,
+            IC => state.ris &= !value,
+            AFSEL => state.afsel = value,
+            _ => return,
+        }
+        state.detect(old);
+        self.update_irq(&state);
+    }
+}
+
+/// Presses the power button wired to a line of a `Pl061`.
+#[derive(Clone)]
+pub struct PowerButton {
+    gpio: Arc<Pl061>,
+    line: u8,
+}
+
+impl PowerButton {
+    /// Press and release the button, which the guest handles as a shutdown request.
+    pub fn press(&self) {
+        self.gpio.set_input(self.line, true);
+        self.gpio.set_input(self.line, false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::interrupt::{IrqRouter, TriggerMode};
+
+    fn read(gpio: &Pl061, offset: u64) -> u8 {
+        let mut data = [0; 4];
+        gpio.mmio_read(
+            MmioAddress(PL061_DEFAULT_BASE),
+            MmioOffset(offset),
+            &mut data,
+        );
+        data[0]
+    }
+
+    fn write(gpio: &Pl061, offset: u64, value: u8) {
+        gpio.mmio_write(
+            MmioAddress(PL061_DEFAULT_BASE),
+            MmioOffset(offset),
+            &[value],
+        );
+    }
+
+    #[test]
+    fn test_pl061() {
+        let router = IrqRouter::new(Arc::new(|_, _| {}));
+        let gpio = Arc::new(Pl061::new().with_irq(router.line(39, TriggerMode::Level).unwrap()));
+        assert_eq!(read(&gpio, ID_BASE), 0x61);
+
+        // Outputs are written through the address mask.
+        write(&gpio, DIR, 0x03);
+        write(&gpio, 0x3fc, 0xff);
+        write(&gpio, 0x4, 0x00);
+        assert_eq!(read(&gpio, 0x3fc), 0x02);
+        assert_eq!(read(&gpio, 0x8), 0x02);
+        // Falling edges of the outputs latch interrupts as well, but they are masked.
+        assert_eq!(read(&gpio, RIS), 0x01);
+        assert!(!router.level(39));
+        write(&gpio, IC, 0xff);
+
+        // The guest sets up the power button as a rising edge interrupt.
+        let line = 1 << PL061_POWER_BUTTON_LINE;
+        write(&gpio, IEV, line);
+        write(&gpio, IE, line);
+        gpio.power_button(PL061_POWER_BUTTON_LINE).press();
+        assert!(!gpio.level(PL061_POWER_BUTTON_LINE));
+        assert_eq!(read(&gpio, MIS), line);
+        assert!(router.level(39));
+        write(&gpio, IC, line);
+        assert_eq!(read(&gpio, RIS), 0);
+        assert!(!router.level(39));
+
+        // Level sensitive interrupts stay pending while the line is at the active level.
+        write(&gpio, IS, line);
+        gpio.set_input(PL061_POWER_BUTTON_LINE, true);
+        write(&gpio, IC, line);
+        assert!(router.level(39));
+        gpio.set_input(PL061_POWER_BUTTON_LINE, false);
+        write(&gpio, IC, line);
+        assert!(!router.level(39));
+    }
+}
@@ -0,0 +1,174 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! System control port A at port 0x92, which provides the fast A20 gate and fast init of
+//! PC chipsets.
+//!
+//! Setting bit 0 asks for a reset of the VM, which the
+//! [`SystemControl`](struct.SystemControl.html) device forwards to the VMM as a
+//! `VmEvent::Reset` event. Bit 1 drives the A20 gate; the VMM gets its changes through the
+//! callback set with [`with_a20_handler`](struct.SystemControl.html#method.with_a20_handler),
+//! since masking the address line is up to the memory model of the VMM.
+
+use std::sync::Mutex;
+
+use crate::bus::{PioAddress, PioAddressValue, PioOffset};
+use crate::events::{VmEvent, VmEventSender};
+use crate::lifecycle::Lifecycle;
+use crate::DevicePio;
+
+/// The system control port A.
+pub const PORT92_PORT: PioAddressValue = 0x92;
+
+/// Size of the system control port A.
+pub const PORT92_PORT_SIZE: PioAddressValue = 0x1;
+
+const FAST_INIT: u8 = 1 << 0;
+const A20_ENABLE: u8 = 1 << 1;
+
+/// Model of the system control port A.
+pub struct SystemControl {
+    events: VmEventSender,
+    a20_handler: Option<Box<dyn Fn(bool) + Send + Sync>>,
+    value: Mutex<u8>,
+}
+
+impl SystemControl {
+    /// Create a new port which sends the reset requests of the guest through `events`.
+    pub fn new(events: VmEventSender) -> Self {
+        SystemControl {
+            events,
+            a20_handler: None,
+            value: Mutex::new(0),
+        }
+    }
+
+    /// Invoke `handler` with the new state of the A20 gate whenever the guest changes it.
+    /// The handler is called with the state of the port locked, so it must not access the
+    /// port.
+    pub fn with_a20_handler(mut self, handler: Box<dyn Fn(bool) + Send + Sync>) -> Self {
+        self.a20_handler = Some(handler);
+        self
+    }
+
+    /// Return whether the guest enabled the A20 gate.
+    pub fn a20_enabled(&self) -> bool {
+        *self.value.lock().unwrap() & A20_ENABLE != 0
+    }
+}
+
+impl Lifecycle for SystemControl {
+    fn reset(&self) {
+        // The A20 gate keeps its state, as on the chipsets implementing the port.
+        *self.value.lock().unwrap() &= !FAST_INIT;
+    }
+}
+
+impl DevicePio for SystemControl {
+    fn pio_read(&self, _base: PioAddress, _offset: PioOffset, data: &mut [u8]) {
+        let value = *self.value.lock().unwrap();
+        for byte in data.iter_mut() {
+            *byte = value;
+        }
+    }
+
+    fn pio_write(&self, _base: PioAddress, _offset: PioOffset, data: &[u8]) {
+        let value = match data.first() {
+            Some(value) => *value,
+            None => return,
+        };
+        let mut current = self.value.lock().unwrap();
+        let old = std::mem::replace(&mut *current, value);
+        // Notify the VMM with the lock held, so concurrent writes report the changes of the
+        // gate in the order they were stored.
+        if (old ^ value) & A20_ENABLE != 0 {
+            if let Some(handler) = self.a20_handler.as_ref() {
+                handler(value & A20_ENABLE != 0);
+            }
+        }
+        drop(current);
+        if value & FAST_INIT != 0 && old & FAST_INIT == 0 {
+            let _ = self.events.send(VmEvent::Reset);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::events::vm_event_channel;
+
+    fn write(port: &SystemControl, value: u8) {
+        port.pio_write(PioAddress(PORT92_PORT), PioOffset(0), &[value]);
+    }
+
+    fn read(port: &SystemControl) -> u8 {
+        let mut data = [0];
+        port.pio_read(PioAddress(PORT92_PORT), PioOffset(0), &mut data);
+        data[0]
+    }
+
+    #[test]
+    fn test_system_control() {
+        let (tx, rx) = vm_event_channel();
+        let gate = Arc::new(Mutex::new(Vec::new()));
+        let gate_clone = gate.clone();
+        let port = SystemControl::new(tx.for_device("port92")).with_a20_handler(Box::new(
+            move |enabled| gate_clone.lock().unwrap().push(enabled),
+        ));
+
+        write(&port, A20_ENABLE);
+        write(&port, A20_ENABLE);
+        assert!(port.a20_enabled());
+        assert_eq!(read(&port), A20_ENABLE);
+        assert!(rx.try_recv().is_none());
+
+        // Only setting the fast init bit requests a reset.
+        write(&port, A20_ENABLE | FAST_INIT);
+        write(&port, A20_ENABLE | FAST_INIT);
+        let message = rx.try_recv().unwrap();
+        assert_eq!(message.event, VmEvent::Reset);
+        assert_eq!(&*message.source, "port92");
+        assert!(rx.try_recv().is_none());
+
+        port.reset();
+        assert_eq!(read(&port), A20_ENABLE);
+        write(&port, 0);
+        assert!(!port.a20_enabled());
+        assert_eq!(*gate.lock().unwrap(), [true, false]);
+    }
+
+    #[test]
+    fn test_a20_notification_order() {
+        let gate = Arc::new(Mutex::new(Vec::new()));
+        let gate_clone = gate.clone();
+        let (tx, _rx) = vm_event_channel();
+        let port = Arc::new(
+            SystemControl::new(tx.for_device("port92")).with_a20_handler(Box::new(
+                move |enabled| gate_clone.lock().unwrap().push(enabled),
+            )),
+        );
+
+        let threads: Vec<_> = (0..4)
+            .map(|idx| {
+                let port = port.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..200 {
+                        write(&port, if idx % 2 == 0 { A20_ENABLE } else { 0 });
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        // The notifications alternate, and the last one matches the state of the port.
+        let gate = gate.lock().unwrap();
+        assert!(gate.windows(2).all(|pair| pair[0] != pair[1]));
+        assert_eq!(gate.last().copied().unwrap_or(false), port.a20_enabled());
+    }
+}
@@ -0,0 +1,130 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Capture of the POST codes written by the firmware to port 0x80.
+//!
+//! Firmware reports its progress through the boot by writing a code to the POST port
+//! before each step, so the last codes tell where a hung boot stopped. The
+//! [`PostCodes`](struct.PostCodes.html) device keeps the most recent codes in a ring buffer
+//! which the VMM can inspect at any time. The codes are kept across resets of the VM, since
+//! the ones written before a reset are usually the interesting ones.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use crate::bus::{PioAddress, PioAddressValue, PioOffset};
+use crate::DevicePio;
+
+/// The POST code port.
+pub const POST_PORT: PioAddressValue = 0x80;
+
+/// Size of the POST code port.
+pub const POST_PORT_SIZE: PioAddressValue = 0x1;
+
+/// Number of codes kept by default.
+pub const POST_DEFAULT_CAPACITY: usize = 256;
+
+struct State {
+    codes: VecDeque<u8>,
+    total: u64,
+}
+
+/// Ring buffer of the POST codes written by the guest.
+pub struct PostCodes {
+    capacity: usize,
+    state: Mutex<State>,
+}
+
+impl PostCodes {
+    /// Create a device keeping the last `capacity` codes.
+    pub fn new(capacity: usize) -> Self {
+        PostCodes {
+            capacity,
+            state: Mutex::new(State {
+                codes: VecDeque::with_capacity(capacity),
+                total: 0,
+            }),
+        }
+    }
+
+    /// Return the codes in the buffer, oldest first.
+    pub fn codes(&self) -> Vec<u8> {
+        self.state.lock().unwrap().codes.iter().copied().collect()
+    }
+
+    /// Return the codes in the buffer, oldest first, and empty it.
+    pub fn take(&self) -> Vec<u8> {
+        self.state.lock().unwrap().codes.drain(..).collect()
+    }
+
+    /// Return the last code written by the guest.
+    pub fn last(&self) -> Option<u8> {
+        self.state.lock().unwrap().codes.back().copied()
+    }
+
+    /// Return the number of codes written by the guest, including the ones which were
+    /// overwritten or taken.
+    pub fn total(&self) -> u64 {
+        self.state.lock().unwrap().total
+    }
+}
+
+impl Default for PostCodes {
+    fn default() -> Self {
+        Self::new(POST_DEFAULT_CAPACITY)
+    }
+}
+
+impl DevicePio for PostCodes {
+    fn pio_read(&self, _base: PioAddress, _offset: PioOffset, data: &mut [u8]) {
+        // Nothing drives the bus, as on most chipsets.
+        for byte in data.iter_mut() {
+            *byte = 0xff;
+        }
+    }
+
+    fn pio_write(&self, _base: PioAddress, _offset: PioOffset, data: &[u8]) {
+        // Wider writes only report their low byte, like on the diagnostic cards.
+        let code = match data.first() {
+            Some(code) => *code,
+            None => return,
+        };
+        let mut state = self.state.lock().unwrap();
+        state.total += 1;
+        if self.capacity == 0 {
+            return;
+        }
+        if state.codes.len() == self.capacity {
+            state.codes.pop_front();
+        }
+        state.codes.push_back(code);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_post_codes() {
+        let post = PostCodes::new(3);
+        let port = PioAddress(POST_PORT);
+        assert_eq!(post.last(), None);
+
+        for code in 1..=4u8 {
+            post.pio_write(port, PioOffset(0), &[code]);
+        }
+        post.pio_write(port, PioOffset(0), &[0x55, 0xaa]);
+        assert_eq!(post.codes(), [3, 4, 0x55]);
+        assert_eq!(post.last(), Some(0x55));
+        assert_eq!(post.total(), 5);
+
+        let mut data = [0; 1];
+        post.pio_read(port, PioOffset(0), &mut data);
+        assert_eq!(data, [0xff]);
+
+        assert_eq!(post.take(), [3, 4, 0x55]);
+        assert!(post.codes().is_empty());
+        assert_eq!(post.total(), 5);
+    }
+}
@@ -0,0 +1,257 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Memory regions accessed through MMIO exits, such as option ROMs, NVRAM, or small device
+//! memories which are not mapped into the guest address space.
+//!
+//! [`RamDevice`](struct.RamDevice.html) holds writable contents, optionally backed by a file
+//! which receives every change, and can be write protected at runtime or track the pages
+//! written by the guest. [`RomDevice`](struct.RomDevice.html) holds immutable contents, so
+//! it's accessed without locking and lends them directly to zero-copy reads.
+
+use std::convert::TryFrom;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::Arc;
+
+use crate::bus::{MmioAddress, MmioOffset};
+use crate::dirty::{self, DirtyBitmap};
+use crate::{DeviceMmio, DeviceMmioZeroCopy, MutDeviceMmio};
+
+/// Errors encountered while setting up a memory region.
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to set up dirty tracking.
+    DirtyBitmap(dirty::Error),
+    /// Failed to access the backing file.
+    Io(io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::DirtyBitmap(_) => write!(f, "failed to set up dirty tracking"),
+            Error::Io(_) => write!(f, "memory region backing file error"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::DirtyBitmap(e) => Some(e),
+            Error::Io(e) => Some(e),
+        }
+    }
+}
+
+// Copy the bytes at `offset` into `data`; the ones past the end of `src` read as all ones,
+// like unbacked memory.
+fn read_bytes(src: &[u8], offset: u64, data: &mut [u8]) {
+    for (idx, byte) in data.iter_mut().enumerate() {
+        *byte = (offset as usize)
+            .checked_add(idx)
+            .and_then(|pos| src.get(pos))
+            .copied()
+            .unwrap_or(0xff);
+    }
+}
+
+// Read the whole contents of `file`.
+fn read_file(file: &mut File) -> Result<Vec<u8>, Error> {
+    let mut data = Vec::new();
+    file.seek(SeekFrom::Start(0)).map_err(Error::Io)?;
+    file.read_to_end(&mut data).map_err(Error::Io)?;
+    Ok(data)
+}
+
+/// Writable memory region.
+pub struct RamDevice {
+    data: Vec<u8>,
+    file: Option<File>,
+    write_protected: bool,
+    dirty: Option<Arc<DirtyBitmap>>,
+}
+
+impl RamDevice {
+    /// Create a zeroed region of `size` bytes.
+    pub fn new(size: usize) -> Self {
+        Self::from_vec(vec![0; size])
+    }
+
+    /// Create a region with the contents of `data`.
+    pub fn from_vec(data: Vec<u8>) -> Self {
+        RamDevice {
+            data,
+            file: None,
+            write_protected: false,
+            dirty: None,
+        }
+    }
+
+    /// Create a region backed by `file`, as needed for NVRAM. The contents are read when
+    /// the device is created, and every write is also applied to the file.
+    pub fn from_file(mut file: File) -> Result<Self, Error> {
+        let mut ram = Self::from_vec(read_file(&mut file)?);
+        ram.file = Some(file);
+        Ok(ram)
+    }
+
+    /// Track the pages of `page_size` bytes written by the guest.
+    pub fn with_dirty_tracking(mut self, page_size: u64) -> Result<Self, Error> {
+        let bitmap =
+            DirtyBitmap::new(self.data.len() as u64, page_size).map_err(Error::DirtyBitmap)?;
+        self.dirty = Some(Arc::new(bitmap));
+        Ok(self)
+    }
+
+    /// Return the bitmap of the pages written by the guest, if dirty tracking is enabled.
+    pub fn dirty_bitmap(&self) -> Option<Arc<DirtyBitmap>> {
+        self.dirty.clone()
+    }
+
+    /// Drop the guest writes while `write_protected` is set (e.g. for a flash region which
+    /// is locked by the chipset).
+    pub fn set_write_protected(&mut self, write_protected: bool) {
+        self.write_protected = write_protected;
+    }
+
+    /// Return whether guest writes are dropped.
+    pub fn is_write_protected(&self) -> bool {
+        self.write_protected
+    }
+
+    /// Return the current contents of the region.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Return the indices of the pages written since the last call, in ascending order, and
+    /// mark them clean. Returns an empty list when dirty tracking is not enabled.
+    pub fn take_dirty_pages(&mut self) -> Vec<u64> {
+        self.dirty
+            .as_ref()
+            .map(|bitmap| bitmap.take_dirty())
+            .unwrap_or_default()
+    }
+}
+
+impl MutDeviceMmio for RamDevice {
+    fn mmio_read(&mut self, _base: MmioAddress, offset: MmioOffset, data: &mut [u8]) {
+        read_bytes(&self.data, offset.raw(), data);
+    }
+
+    fn mmio_write(&mut self, _base: MmioAddress, offset: MmioOffset, data: &[u8]) {
+        let offset = offset.raw();
+        let size = self.data.len() as u64;
+        if self.write_protected || offset >= size {
+            return;
+        }
+        // Bytes past the end of the region are dropped.
+        let len = (data.len() as u64).min(size - offset);
+        let range = offset as usize..(offset + len) as usize;
+        self.data[range.clone()].copy_from_slice(&data[..len as usize]);
+
+        if let Some(bitmap) = self.dirty.as_ref() {
+            bitmap.mark(offset, len);
+        }
+        if let Some(file) = self.file.as_mut() {
+            // Device models have no way to report errors to the guest, so a failure only
+            // leaves the file out of date.
+            let written = &self.data[range];
+            let _ = file
+                .seek(SeekFrom::Start(offset))
+                .and_then(|_| file.write_all(written));
+        }
+    }
+}
+
+/// Read-only memory region. Guest writes are ignored.
+pub struct RomDevice {
+    data: Vec<u8>,
+}
+
+impl RomDevice {
+    /// Create a region with the contents of `data`.
+    pub fn new(data: Vec<u8>) -> Self {
+        RomDevice { data }
+    }
+
+    /// Create a region with the contents of `file`, such as an option ROM image.
+    pub fn from_file(mut file: File) -> Result<Self, Error> {
+        read_file(&mut file).map(Self::new)
+    }
+
+    /// Return the contents of the region.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl DeviceMmio for RomDevice {
+    fn mmio_read(&self, _base: MmioAddress, offset: MmioOffset, data: &mut [u8]) {
+        read_bytes(&self.data, offset.raw(), data);
+    }
+
+    fn mmio_write(&self, _base: MmioAddress, _offset: MmioOffset, _data: &[u8]) {}
+
+    fn as_zero_copy(&self) -> Option<&dyn DeviceMmioZeroCopy> {
+        Some(self)
+    }
+}
+
+impl DeviceMmioZeroCopy for RomDevice {
+    fn mmio_slice(&self, _base: MmioAddress, offset: MmioOffset, len: usize) -> Option<&[u8]> {
+        let start = usize::try_from(offset.raw()).ok()?;
+        self.data.get(start..start.checked_add(len)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: MmioAddress = MmioAddress(0xc_0000);
+
+    #[test]
+    fn test_ram_device() {
+        assert!(matches!(
+            RamDevice::new(0x100).with_dirty_tracking(0x30),
+            Err(Error::DirtyBitmap(dirty::Error::InvalidPageSize(0x30)))
+        ));
+        let mut ram = RamDevice::new(0x100).with_dirty_tracking(0x40).unwrap();
+
+        ram.mmio_write(BASE, MmioOffset(0x3e), &[1, 2, 3, 4]);
+        // Writes past the end of the region are truncated.
+        ram.mmio_write(BASE, MmioOffset(0xfe), &[5, 6, 7, 8]);
+        let mut data = [0u8; 4];
+        ram.mmio_read(BASE, MmioOffset(0x3e), &mut data);
+        assert_eq!(data, [1, 2, 3, 4]);
+        ram.mmio_read(BASE, MmioOffset(0xfe), &mut data);
+        assert_eq!(data, [5, 6, 0xff, 0xff]);
+        assert_eq!(ram.take_dirty_pages(), vec![0, 1, 3]);
+        assert!(ram.take_dirty_pages().is_empty());
+
+        ram.set_write_protected(true);
+        ram.mmio_write(BASE, MmioOffset(0), &[0xaa]);
+        assert_eq!(ram.data()[0], 0);
+        assert!(ram.take_dirty_pages().is_empty());
+    }
+
+    #[test]
+    fn test_rom_device() {
+        let rom = RomDevice::new(vec![0x55, 0xaa, 0x10, 0x00]);
+        rom.mmio_write(BASE, MmioOffset(0), &[0, 0]);
+        let mut data = [0u8; 2];
+        rom.mmio_read(BASE, MmioOffset(0), &mut data);
+        assert_eq!(data, [0x55, 0xaa]);
+
+        let zero_copy = rom.as_zero_copy().unwrap();
+        assert_eq!(
+            zero_copy.mmio_slice(BASE, MmioOffset(2), 2),
+            Some(&[0x10, 0x00][..])
+        );
+        assert_eq!(zero_copy.mmio_slice(BASE, MmioOffset(3), 2), None);
+    }
+}
@@ -0,0 +1,440 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! TPM Interface Specification (TIS) FIFO interface of a TPM 2.0.
+//!
+//! The [`TpmTis`](struct.TpmTis.html) model implements the register interface guests use to
+//! talk to a TPM: locality arbitration through the access registers, and the command and
+//! response exchange through the status and data FIFO registers. The commands themselves
+//! are executed by a [`TpmBackend`](trait.TpmBackend.html), such as a
+//! [`StreamBackend`](struct.StreamBackend.html) connected to the data channel of `swtpm`,
+//! or the [`NullBackend`](struct.NullBackend.html) which fails every command. Commands are
+//! executed synchronously, from the vCPU which starts them. Interrupts aren't supported, so
+//! guests poll the status register.
+
+use std::io::{self, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+use crate::bus::{MmioAddress, MmioOffset};
+use crate::MutDeviceMmio;
+
+/// Guest physical address of the TPM registers.
+pub const TPM_TIS_BASE: u64 = 0xfed4_0000;
+
+/// Size of the registers of the five localities.
+pub const TPM_TIS_SIZE: u64 = 0x5000;
+
+/// Largest command or response exchanged with the backend.
+pub const TPM_TIS_BUFFER_SIZE: usize = 4096;
+
+const NUM_LOCALITIES: u8 = 5;
+const LOCALITY_SHIFT: u64 = 12;
+
+// Registers of each locality.
+const ACCESS: u64 = 0x00;
+const INT_ENABLE: u64 = 0x08;
+const INTF_CAPABILITY: u64 = 0x14;
+const STS: u64 = 0x18;
+const DATA_FIFO: u64 = 0x24;
+const INTERFACE_ID: u64 = 0x30;
+const XDATA_FIFO: u64 = 0x80;
+const DID_VID: u64 = 0xf00;
+const RID: u64 = 0xf04;
+
+const ACCESS_ESTABLISHMENT: u8 = 1 << 0;
+const ACCESS_REQUEST_USE: u8 = 1 << 1;
+const ACCESS_PENDING_REQUEST: u8 = 1 << 2;
+const ACCESS_ACTIVE_LOCALITY: u8 = 1 << 5;
+const ACCESS_VALID: u8 = 1 << 7;
+
+const STS_RESPONSE_RETRY: u32 = 1 << 1;
+const STS_EXPECT: u32 = 1 << 3;
+const STS_DATA_AVAIL: u32 = 1 << 4;
+const STS_GO: u32 = 1 << 5;
+const STS_COMMAND_READY: u32 = 1 << 6;
+const STS_VALID: u32 = 1 << 7;
+const STS_BURST_SHIFT: u32 = 8;
+const STS_FAMILY_TPM2: u32 = 1 << 26;
+
+// Static burst count, 64-byte transfers, and TIS 1.3 for TPM 2.0, without interrupts.
+const CAPABILITIES: u32 = (1 << 8) | (3 << 9) | (3 << 28);
+// FIFO interface of the TIS.
+const INTERFACE_ID_TIS: u32 = 0xffff_ffff;
+const DID_VID_VALUE: u32 = 0x0001_1014;
+const RID_VALUE: u32 = 0x01;
+
+// Size of the header of commands and responses: tag, size, and code.
+const HEADER_SIZE: usize = 10;
+const TPM_ST_NO_SESSIONS: u16 = 0x8001;
+const TPM_RC_FAILURE: u32 = 0x101;
+
+/// Executes the commands received by a `TpmTis`.
+pub trait TpmBackend {
+    /// Execute `command`, sent from `locality`, and return the response.
+    fn execute(&mut self, locality: u8, command: &[u8]) -> io::Result<Vec<u8>>;
+}
+
+/// Backend without a TPM, which fails every command.
+pub struct NullBackend;
+
+impl TpmBackend for NullBackend {
+    fn execute(&mut self, _locality: u8, _command: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(failure_response())
+    }
+}
+
+/// Backend forwarding the commands over a stream, such as the data channel of `swtpm`
+/// (`swtpm socket --server type=unixio,path=...`).
+pub struct StreamBackend<S: Read + Write> {
+    stream: S,
+}
+
+impl<S: Read + Write> StreamBackend<S> {
+    /// Create a backend exchanging commands and responses over `stream`.
+    pub fn new(stream: S) -> Self {
+        StreamBackend { stream }
+    }
+}
+
+impl StreamBackend<UnixStream> {
+    /// Connect to the Unix socket at `path`.
+    pub fn connect<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        UnixStream::connect(path).map(Self::new)
+    }
+}
+
+impl<S: Read + Write> TpmBackend for StreamBackend<S> {
+    fn execute(&mut self, _locality: u8, command: &[u8]) -> io::Result<Vec<u8>> {
+        self.stream.write_all(command)?;
+        let mut response = vec![0; HEADER_SIZE];
+        self.stream.read_exact(&mut response)?;
+        let size = header_size(&response);
+        if !(HEADER_SIZE..=TPM_TIS_BUFFER_SIZE).contains(&size) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid TPM response size",
+            ));
+        }
+        response.resize(size, 0);
+        self.stream.read_exact(&mut response[HEADER_SIZE..])?;
+        Ok(response)
+    }
+}
+
+// Return the size field of a command or response header.
+fn header_size(header: &[u8]) -> usize {
+    u32::from_be_bytes([header[2], header[3], header[4], header[5]]) as usize
+}
+
+fn failure_response() -> Vec<u8> {
+    let mut response = TPM_ST_NO_SESSIONS.to_be_bytes().to_vec();
+    response.extend_from_slice(&(HEADER_SIZE as u32).to_be_bytes());
+    response.extend_from_slice(&TPM_RC_FAILURE.to_be_bytes());
+    response
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Idle,
+    Ready,
+    Reception,
+    Completion,
+}
+
+/// Model of the TIS interface of a TPM.
+pub struct TpmTis {
+    backend: Box<dyn TpmBackend + Send>,
+    active: Option<u8>,
+    // Bitmap of the localities waiting to become active.
+    pending: u8,
+    int_enable: u32,
+    state: State,
+    command: Vec<u8>,
+    response: Vec<u8>,
+    // Position of the next byte of the response read by the guest.
+    read_pos: usize,
+}
+
+impl TpmTis {
+    /// Create a new interface forwarding the commands to `backend`.
+    pub fn new(backend: Box<dyn TpmBackend + Send>) -> Self {
+        TpmTis {
+            backend,
+            active: None,
+            pending: 0,
+            int_enable: 0,
+            state: State::Idle,
+            command: Vec::new(),
+            response: Vec::new(),
+            read_pos: 0,
+        }
+    }
+
+    /// Return the active locality.
+    pub fn active_locality(&self) -> Option<u8> {
+        self.active
+    }
+
+    // Return `true` while the command being received is shorter than its header says.
+    fn expects_data(&self) -> bool {
+        self.command.len() < HEADER_SIZE || self.command.len() < header_size(&self.command)
+    }
+
+    fn abort(&mut self) {
+        self.state = State::Idle;
+        self.command.clear();
+        self.response.clear();
+        self.read_pos = 0;
+    }
+
+    fn sts(&self) -> u32 {
+        let (flags, burst) = match self.state {
+            State::Idle => (0, 0),
+            State::Ready => (STS_VALID | STS_COMMAND_READY, TPM_TIS_BUFFER_SIZE),
+            State::Reception => {
+                let expect = if self.expects_data() { STS_EXPECT } else { 0 };
+                (STS_VALID | expect, TPM_TIS_BUFFER_SIZE - self.command.len())
+            }
+            State::Completion => {
+                let left = self.response.len() - self.read_pos;
+                let avail = if left > 0 { STS_DATA_AVAIL } else { 0 };
+                (STS_VALID | avail, left)
+            }
+        };
+        STS_FAMILY_TPM2 | flags | ((burst.min(0xffff) as u32) << STS_BURST_SHIFT)
+    }
+
+    fn access(&self, locality: u8) -> u8 {
+        let mut value = ACCESS_VALID | ACCESS_ESTABLISHMENT;
+        if self.active == Some(locality) {
+            value |= ACCESS_ACTIVE_LOCALITY;
+            if self.pending & !(1 << locality) != 0 {
+                value |= ACCESS_PENDING_REQUEST;
+            }
+        } else if self.pending & (1 << locality) != 0 {
+            value |= ACCESS_REQUEST_USE;
+        }
+        value
+    }
+
+    fn write_access(&mut self, locality: u8, value: u8) {
+        if value & ACCESS_REQUEST_USE != 0 && self.active != Some(locality) {
+            if self.active.is_none() {
+                self.active = Some(locality);
+            } else {
+                self.pending |= 1 << locality;
+            }
+        }
+        if value & ACCESS_ACTIVE_LOCALITY != 0 {
+            self.pending &= !(1 << locality);
+            if self.active == Some(locality) {
+                // The highest pending locality becomes active.
+                self.active = (0..NUM_LOCALITIES)
+                    .rev()
+                    .find(|idx| self.pending & (1 << idx) != 0);
+                if let Some(next) = self.active {
+                    self.pending &= !(1 << next);
+                }
+                self.abort();
+            }
+        }
+    }
+
+    fn write_sts(&mut self, locality: u8, value: u32) {
+        if value & STS_COMMAND_READY != 0 {
+            self.abort();
+            self.state = State::Ready;
+        } else if value & STS_GO != 0 {
+            if self.state == State::Reception && !self.expects_data() {
+                self.response = self
+                    .backend
+                    .execute(locality, &self.command)
+                    .unwrap_or_else(|_| failure_response());
+                self.command.clear();
+                self.read_pos = 0;
+                self.state = State::Completion;
+            }
+        } else if value & STS_RESPONSE_RETRY != 0 && self.state == State::Completion {
+            self.read_pos = 0;
+        }
+    }
+
+    fn write_fifo(&mut self, data: &[u8]) {
+        if self.state == State::Ready {
+            self.state = State::Reception;
+        }
+        if self.state == State::Reception {
+            let room = TPM_TIS_BUFFER_SIZE - self.command.len();
+            self.command
+                .extend_from_slice(&data[..data.len().min(room)]);
+        }
+    }
+
+    fn read_fifo(&mut self, data: &mut [u8]) {
+        for byte in data.iter_mut() {
+            *byte = match self.response.get(self.read_pos) {
+                Some(value) if self.state == State::Completion => {
+                    self.read_pos += 1;
+                    *value
+                }
+                _ => 0xff,
+            };
+        }
+    }
+}
+
+// Split `offset` into the locality it targets, and the offset of the register.
+fn decode(offset: MmioOffset) -> (u8, u64) {
+    let locality = (offset.raw() >> LOCALITY_SHIFT) as u8;
+    (locality, offset.raw() & ((1 << LOCALITY_SHIFT) - 1))
+}
+
+fn is_fifo(reg: u64) -> bool {
+    (DATA_FIFO..DATA_FIFO + 4).contains(&reg) || (XDATA_FIFO..XDATA_FIFO + 4).contains(&reg)
+}
+
+impl MutDeviceMmio for TpmTis {
+    fn mmio_read(&mut self, _base: MmioAddress, offset: MmioOffset, data: &mut [u8]) {
+        let (locality, reg) = decode(offset);
+        let active = self.active == Some(locality);
+        if is_fifo(reg) {
+            if active {
+                self.read_fifo(data);
+            } else {
+                data.iter_mut().for_each(|byte| *byte = 0xff);
+            }
+            return;
+        }
+        let value = match reg & !0x3 {
+            ACCESS => u32::from(self.access(locality)),
+            INT_ENABLE => self.int_enable,
+            INTF_CAPABILITY => CAPABILITIES,
+            STS if active => self.sts(),
+            STS => u32::MAX,
+            INTERFACE_ID => INTERFACE_ID_TIS,
+            DID_VID => DID_VID_VALUE,
+            RID => RID_VALUE,
+            _ => 0,
+        };
+        let bytes = value.to_le_bytes();
+        let start = (reg & 0x3) as usize;
+        for (idx, byte) in data.iter_mut().enumerate() {
+            *byte = bytes.get(start + idx).copied().unwrap_or(0);
+        }
+    }
+
+    fn mmio_write(&mut self, _base: MmioAddress, offset: MmioOffset, data: &[u8]) {
+        let (locality, reg) = decode(offset);
+        if locality >= NUM_LOCALITIES || data.is_empty() {
+            return;
+        }
+        if reg == ACCESS {
+            return self.write_access(locality, data[0]);
+        }
+        // Only the active locality can exchange commands.
+        if self.active != Some(locality) {
+            return;
+        }
+        if is_fifo(reg) {
+            return self.write_fifo(data);
+        }
+        let mut bytes = [0; 4];
+        let start = (reg & 0x3) as usize;
+        let len = data.len().min(4 - start);
+        bytes[start..start + len].copy_from_slice(&data[..len]);
+        let value = u32::from_le_bytes(bytes);
+        match reg & !0x3 {
+            INT_ENABLE => self.int_enable = value,
+            STS => self.write_sts(locality, value),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    // Runs a TPM2_Startup(SU_CLEAR) command through the interface, from locality 0.
+    fn startup(tpm: &mut TpmTis) -> Vec<u8> {
+        let base = MmioAddress(TPM_TIS_BASE);
+        let command = [0x80, 0x01, 0, 0, 0, 12, 0, 0, 0x01, 0x44, 0, 0];
+        let mut sts = [0; 4];
+
+        tpm.mmio_write(base, MmioOffset(STS), &[STS_COMMAND_READY as u8]);
+        tpm.mmio_read(base, MmioOffset(STS), &mut sts);
+        assert_eq!(sts[0] as u32, STS_VALID | STS_COMMAND_READY);
+        for (idx, byte) in command.iter().enumerate() {
+            tpm.mmio_write(base, MmioOffset(DATA_FIFO), &[*byte]);
+            tpm.mmio_read(base, MmioOffset(STS), &mut sts);
+            let expect = sts[0] as u32 & STS_EXPECT != 0;
+            assert_eq!(expect, idx < command.len() - 1);
+        }
+        tpm.mmio_write(base, MmioOffset(STS), &[STS_GO as u8]);
+
+        let mut response = Vec::new();
+        loop {
+            tpm.mmio_read(base, MmioOffset(STS), &mut sts);
+            if sts[0] as u32 & STS_DATA_AVAIL == 0 {
+                break;
+            }
+            let mut byte = [0];
+            tpm.mmio_read(base, MmioOffset(DATA_FIFO), &mut byte);
+            response.push(byte[0]);
+        }
+        response
+    }
+
+    #[test]
+    fn test_tpm_tis() {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let swtpm = thread::spawn(move || {
+            let mut stream = theirs;
+            let mut command = [0; 12];
+            stream.read_exact(&mut command).unwrap();
+            assert_eq!(&command[6..10], &[0, 0, 0x01, 0x44]);
+            stream
+                .write_all(&[0x80, 0x01, 0, 0, 0, 10, 0, 0, 0, 0])
+                .unwrap();
+        });
+
+        let mut tpm = TpmTis::new(Box::new(StreamBackend::new(ours)));
+        let base = MmioAddress(TPM_TIS_BASE);
+        let locality1 = MmioOffset(1 << LOCALITY_SHIFT);
+        let mut access = [0];
+
+        // Locality 0 becomes active, and locality 1 waits for it.
+        tpm.mmio_write(base, MmioOffset(ACCESS), &[ACCESS_REQUEST_USE]);
+        tpm.mmio_write(base, locality1, &[ACCESS_REQUEST_USE]);
+        tpm.mmio_read(base, MmioOffset(ACCESS), &mut access);
+        assert_eq!(
+            access[0],
+            ACCESS_VALID | ACCESS_ESTABLISHMENT | ACCESS_ACTIVE_LOCALITY | ACCESS_PENDING_REQUEST
+        );
+        assert_eq!(tpm.active_locality(), Some(0));
+
+        assert_eq!(startup(&mut tpm), vec![0x80, 0x01, 0, 0, 0, 10, 0, 0, 0, 0]);
+        swtpm.join().unwrap();
+
+        // Responses can be read again, and the backend failure is reported to the guest.
+        tpm.mmio_write(base, MmioOffset(STS), &[STS_RESPONSE_RETRY as u8]);
+        let mut data = [0; 4];
+        tpm.mmio_read(base, MmioOffset(DATA_FIFO), &mut data);
+        assert_eq!(data, [0x80, 0x01, 0, 0]);
+        assert_eq!(startup(&mut tpm), failure_response());
+
+        // Relinquishing locality 0 hands the TPM over to locality 1.
+        tpm.mmio_write(base, MmioOffset(ACCESS), &[ACCESS_ACTIVE_LOCALITY]);
+        assert_eq!(tpm.active_locality(), Some(1));
+        let mut sts = [0; 4];
+        tpm.mmio_read(base, MmioOffset(STS), &mut sts);
+        assert_eq!(sts, [0xff; 4]);
+
+        let mut null = TpmTis::new(Box::new(NullBackend));
+        null.mmio_write(base, MmioOffset(ACCESS), &[ACCESS_REQUEST_USE]);
+        assert_eq!(startup(&mut null), failure_response());
+    }
+}
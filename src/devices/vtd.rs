@@ -0,0 +1,416 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Intel VT-d DMA remapping registers.
+//!
+//! The [`Vtd`](struct.Vtd.html) model implements the register interface of a DMA remapping
+//! hardware unit, as driven by the `intel-iommu` Linux driver with register based
+//! invalidation: the root table pointer, translation enable, the context cache and IOTLB
+//! invalidation registers, and a single fault recording register with its fault event
+//! interrupt. The requests of the guest are forwarded to an `IoTranslator`, which reports
+//! the failed translations back with [`record_fault`](struct.Vtd.html#method.record_fault).
+//! Queued invalidation and interrupt remapping aren't supported.
+
+use std::sync::{Arc, Mutex};
+
+use crate::bus::{MmioAddress, MmioOffset};
+use crate::iommu::{Fault, FaultReason, Invalidation, IoTranslator};
+use crate::msi::{MsiMessage, MsiSender};
+use crate::DeviceMmio;
+
+/// Guest physical address of the remapping registers, as used by QEMU on q35.
+pub const VTD_DEFAULT_BASE: u64 = 0xfed9_0000;
+
+/// Size of the remapping registers.
+pub const VTD_SIZE: u64 = 0x1000;
+
+const VER: u64 = 0x00;
+const CAP: u64 = 0x08;
+const ECAP: u64 = 0x10;
+const GCMD: u64 = 0x18;
+const GSTS: u64 = 0x1c;
+const RTADDR: u64 = 0x20;
+const CCMD: u64 = 0x28;
+const FSTS: u64 = 0x34;
+const FECTL: u64 = 0x38;
+const FEDATA: u64 = 0x3c;
+const FEADDR: u64 = 0x40;
+const FEUADDR: u64 = 0x44;
+// The IOTLB and fault recording registers are placed by the offsets advertised in the
+// capabilities.
+const IVA: u64 = 0x200;
+const IOTLB: u64 = 0x208;
+const FRCD: u64 = 0x220;
+
+const VERSION_1_0: u32 = 0x10;
+// 256 domains, 48-bit 4-level tables, 48-bit guest addresses, and one fault record.
+const CAP_VALUE: u64 = 2 | (1 << 10) | (47 << 16) | ((FRCD >> 4) << 24);
+// Coherent page walks.
+const ECAP_VALUE: u64 = 1 | ((IVA >> 4) << 8);
+
+const GCMD_SRTP: u32 = 1 << 30;
+const GCMD_TE: u32 = 1 << 31;
+const GSTS_RTPS: u32 = 1 << 30;
+const GSTS_TES: u32 = 1 << 31;
+
+// Upper halves of the 64-bit command registers.
+const CCMD_ICC: u32 = 1 << 31;
+const CCMD_CIRG_SHIFT: u32 = 29;
+const CCMD_CAIG_SHIFT: u32 = 27;
+const IOTLB_IVT: u32 = 1 << 31;
+const IOTLB_IIRG_SHIFT: u32 = 28;
+const IOTLB_IAIG_SHIFT: u32 = 25;
+const GRANULARITY_MASK: u32 = 0x3;
+const GRANULARITY_GLOBAL: u32 = 1;
+const GRANULARITY_DOMAIN: u32 = 2;
+const GRANULARITY_DEVICE: u32 = 3;
+
+const FSTS_PFO: u32 = 1 << 0;
+const FSTS_PPF: u32 = 1 << 1;
+const FECTL_IP: u32 = 1 << 30;
+const FECTL_IM: u32 = 1 << 31;
+
+// Upper half of the upper quadword of the fault record.
+const FRCD_F: u32 = 1 << 31;
+const FRCD_T_READ: u32 = 1 << 30;
+
+const PAGE_SIZE: u64 = 0x1000;
+
+struct State {
+    gsts: u32,
+    rtaddr: u64,
+    ccmd: u64,
+    iva: u64,
+    iotlb: u64,
+    fsts: u32,
+    fectl: u32,
+    fedata: u32,
+    feaddr: u64,
+    frcd: [u64; 2],
+}
+
+/// Model of the registers of a VT-d remapping hardware unit.
+pub struct Vtd {
+    translator: Arc<dyn IoTranslator>,
+    msi: Arc<dyn MsiSender>,
+    state: Mutex<State>,
+}
+
+// Replace the `half` (0 for the low one) 32-bit half of `reg` with `value`.
+fn set_half(reg: &mut u64, half: u64, value: u32) {
+    let shift = half * 32;
+    *reg = (*reg & !(0xffff_ffff << shift)) | (u64::from(value) << shift);
+}
+
+fn half(reg: u64, half: u64) -> u32 {
+    (reg >> (half * 32)) as u32
+}
+
+impl Vtd {
+    /// Create the registers of a unit forwarding the requests of the guest to `translator`,
+    /// and sending the fault events through `msi`.
+    pub fn new(translator: Arc<dyn IoTranslator>, msi: Arc<dyn MsiSender>) -> Self {
+        Vtd {
+            translator,
+            msi,
+            state: Mutex::new(State {
+                gsts: 0,
+                rtaddr: 0,
+                ccmd: 0,
+                iva: 0,
+                iotlb: 0,
+                fsts: 0,
+                fectl: FECTL_IM,
+                fedata: 0,
+                feaddr: 0,
+                frcd: [0; 2],
+            }),
+        }
+    }
+
+    /// Return `true` if the guest enabled translation.
+    pub fn is_enabled(&self) -> bool {
+        self.state.lock().unwrap().gsts & GSTS_TES != 0
+    }
+
+    /// Record the translation `fault` in the fault recording register, and signal the fault
+    /// event. The fault is dropped, and an overflow reported, while the guest hasn't cleared
+    /// the previous one.
+    pub fn record_fault(&self, fault: &Fault) {
+        let mut state = self.state.lock().unwrap();
+        if half(state.frcd[1], 1) & FRCD_F != 0 {
+            state.fsts |= FSTS_PFO;
+            return;
+        }
+        let reason: u64 = match fault.reason {
+            FaultReason::RootNotPresent => 0x1,
+            FaultReason::ContextNotPresent => 0x2,
+            FaultReason::AddressTooWide => 0x4,
+            FaultReason::WriteDenied => 0x5,
+            FaultReason::ReadDenied => 0x6,
+        };
+        let kind = if fault.write { 0 } else { FRCD_T_READ };
+        state.frcd[0] = fault.iova & !(PAGE_SIZE - 1);
+        state.frcd[1] =
+            u64::from(fault.requester) | (reason << 32) | (u64::from(FRCD_F | kind) << 32);
+        state.fsts |= FSTS_PPF;
+        self.fault_event(&mut state);
+    }
+
+    fn fault_event(&self, state: &mut State) {
+        if state.fectl & FECTL_IM != 0 {
+            state.fectl |= FECTL_IP;
+        } else {
+            state.fectl &= !FECTL_IP;
+            // The guest finds the fault when it next checks the registers.
+            let _ = self.msi.send(MsiMessage::new(state.feaddr, state.fedata));
+        }
+    }
+
+    fn read_dword(&self, state: &State, reg: u64) -> u32 {
+        match reg {
+            VER => VERSION_1_0,
+            CAP | 0x0c => half(CAP_VALUE, (reg - CAP) / 4),
+            ECAP | 0x14 => half(ECAP_VALUE, (reg - ECAP) / 4),
+            GSTS => state.gsts,
+            RTADDR | 0x24 => half(state.rtaddr, (reg - RTADDR) / 4),
+            CCMD | 0x2c => half(state.ccmd, (reg - CCMD) / 4),
+            FSTS => state.fsts,
+            FECTL => state.fectl,
+            FEDATA => state.fedata,
+            FEADDR | FEUADDR => half(state.feaddr, (reg - FEADDR) / 4),
+            IVA | 0x204 => half(state.iva, (reg - IVA) / 4),
+            IOTLB | 0x20c => half(state.iotlb, (reg - IOTLB) / 4),
+            FRCD..=0x22c => {
+                let idx = (reg - FRCD) / 8;
+                half(state.frcd[idx as usize], (reg - FRCD) / 4 % 2)
+            }
+            _ => 0,
+        }
+    }
+
+    fn write_gcmd(&self, state: &mut State, value: u32) {
+        if value & GCMD_SRTP != 0 {
+            self.translator.set_root(state.rtaddr);
+            state.gsts |= GSTS_RTPS;
+        }
+        let enabled = value & GCMD_TE != 0;
+        if enabled != (state.gsts & GSTS_TES != 0) {
+            self.translator.set_enabled(enabled);
+            state.gsts ^= GSTS_TES;
+        }
+    }
+
+    // Invalidate the context cache, as requested by the upper half of `CCMD`.
+    fn write_ccmd(&self, state: &mut State, value: u32) {
+        set_half(&mut state.ccmd, 1, value);
+        if value & CCMD_ICC == 0 {
+            return;
+        }
+        let granularity = (value >> CCMD_CIRG_SHIFT) & GRANULARITY_MASK;
+        let scope = match granularity {
+            GRANULARITY_DOMAIN => Invalidation::Domain(state.ccmd as u16),
+            GRANULARITY_DEVICE => Invalidation::Device((state.ccmd >> 16) as u16),
+            _ => Invalidation::All,
+        };
+        self.translator.invalidate(scope);
+        let value = (value & !(CCMD_ICC | (GRANULARITY_MASK << CCMD_CAIG_SHIFT)))
+            | (granularity.max(GRANULARITY_GLOBAL) << CCMD_CAIG_SHIFT);
+        set_half(&mut state.ccmd, 1, value);
+    }
+
+    // Invalidate the IOTLB, as requested by the upper half of `IOTLB`.
+    fn write_iotlb(&self, state: &mut State, value: u32) {
+        set_half(&mut state.iotlb, 1, value);
+        if value & IOTLB_IVT == 0 {
+            return;
+        }
+        let granularity = (value >> IOTLB_IIRG_SHIFT) & GRANULARITY_MASK;
+        let domain = value as u16;
+        let scope = match granularity {
+            GRANULARITY_DOMAIN => Invalidation::Domain(domain),
+            // Page selective invalidations cover 2^AM pages.
+            GRANULARITY_DEVICE => Invalidation::Range {
+                domain,
+                iova: state.iva & !(PAGE_SIZE - 1),
+                size: PAGE_SIZE << (state.iva & 0x3f).min(52),
+            },
+            _ => Invalidation::All,
+        };
+        self.translator.invalidate(scope);
+        let value = (value & !(IOTLB_IVT | (GRANULARITY_MASK << IOTLB_IAIG_SHIFT)))
+            | (granularity.max(GRANULARITY_GLOBAL) << IOTLB_IAIG_SHIFT);
+        set_half(&mut state.iotlb, 1, value);
+    }
+
+    fn write_dword(&self, state: &mut State, reg: u64, value: u32) {
+        match reg {
+            GCMD => self.write_gcmd(state, value),
+            RTADDR | 0x24 => set_half(&mut state.rtaddr, (reg - RTADDR) / 4, value),
+            CCMD => set_half(&mut state.ccmd, 0, value),
+            0x2c => self.write_ccmd(state, value),
+            // The overflow is cleared by writing 1, the pending fault through the record.
+            FSTS => state.fsts &= !(value & FSTS_PFO),
+            FECTL => {
+                let pending = state.fectl & FECTL_IP != 0;
+                state.fectl = (value & FECTL_IM) | (state.fectl & FECTL_IP);
+                if pending && value & FECTL_IM == 0 {
+                    self.fault_event(state);
+                }
+            }
+            FEDATA => state.fedata = value,
+            FEADDR | FEUADDR => set_half(&mut state.feaddr, (reg - FEADDR) / 4, value),
+            IVA | 0x204 => set_half(&mut state.iva, (reg - IVA) / 4, value),
+            IOTLB => set_half(&mut state.iotlb, 0, value),
+            0x20c => self.write_iotlb(state, value),
+            0x22c if value & FRCD_F != 0 => {
+                state.frcd = [0; 2];
+                state.fsts &= !FSTS_PPF;
+                state.fectl &= !FECTL_IP;
+            }
+            _ => {}
+        }
+    }
+}
+
+impl DeviceMmio for Vtd {
+    fn mmio_read(&self, _base: MmioAddress, offset: MmioOffset, data: &mut [u8]) {
+        let state = self.state.lock().unwrap();
+        let reg = offset.raw() & !0x3;
+        let value = u64::from(self.read_dword(&state, reg))
+            | (u64::from(self.read_dword(&state, reg + 4)) << 32);
+        let bytes = value.to_le_bytes();
+        let start = (offset.raw() & 0x3) as usize;
+        for (idx, byte) in data.iter_mut().enumerate() {
+            *byte = bytes.get(start + idx).copied().unwrap_or(0);
+        }
+    }
+
+    fn mmio_write(&self, _base: MmioAddress, offset: MmioOffset, data: &[u8]) {
+        let reg = offset.raw();
+        if reg & 0x3 != 0 {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        // 64-bit registers are written low half first, so the commands in their upper half
+        // see the complete value.
+        for (idx, chunk) in data.chunks_exact(4).enumerate() {
+            let value = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+            self.write_dword(&mut state, reg + idx as u64 * 4, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Records the requests forwarded by the registers.
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<String>>);
+
+    impl IoTranslator for Recorder {
+        fn set_enabled(&self, enabled: bool) {
+            self.0.lock().unwrap().push(format!("enabled {}", enabled));
+        }
+
+        fn set_root(&self, root: u64) {
+            self.0.lock().unwrap().push(format!("root {:#x}", root));
+        }
+
+        fn invalidate(&self, scope: Invalidation) {
+            self.0.lock().unwrap().push(format!("{:?}", scope));
+        }
+
+        fn translate(&self, requester: u16, iova: u64, write: bool) -> Result<u64, Fault> {
+            Err(Fault {
+                requester,
+                iova,
+                write,
+                reason: FaultReason::ContextNotPresent,
+            })
+        }
+    }
+
+    fn read64(vtd: &Vtd, reg: u64) -> u64 {
+        let mut data = [0; 8];
+        vtd.mmio_read(MmioAddress(VTD_DEFAULT_BASE), MmioOffset(reg), &mut data);
+        u64::from_le_bytes(data)
+    }
+
+    fn write64(vtd: &Vtd, reg: u64, value: u64) {
+        vtd.mmio_write(
+            MmioAddress(VTD_DEFAULT_BASE),
+            MmioOffset(reg),
+            &value.to_le_bytes(),
+        );
+    }
+
+    fn write32(vtd: &Vtd, reg: u64, value: u32) {
+        vtd.mmio_write(
+            MmioAddress(VTD_DEFAULT_BASE),
+            MmioOffset(reg),
+            &value.to_le_bytes(),
+        );
+    }
+
+    #[test]
+    fn test_vtd() {
+        let translator = Arc::new(Recorder::default());
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let msi = sent.clone();
+        let vtd = Vtd::new(
+            translator.clone(),
+            Arc::new(move |msg: MsiMessage| {
+                msi.lock().unwrap().push(msg);
+                Ok(())
+            }),
+        );
+
+        assert_eq!((read64(&vtd, CAP) >> 16) & 0x3f, 47);
+        assert_eq!((read64(&vtd, ECAP) >> 8) & 0x3ff, IVA >> 4);
+
+        // The Linux driver sets the root table, flushes the caches, then enables
+        // translation.
+        write64(&vtd, RTADDR, 0x1000_0000);
+        write32(&vtd, GCMD, GCMD_SRTP);
+        assert_eq!(read64(&vtd, GCMD) >> 32, u64::from(GSTS_RTPS));
+        write64(&vtd, CCMD, (1 << 63) | (1 << 61));
+        assert_eq!((read64(&vtd, CCMD) >> 59) & 0x3, 0x1);
+        write64(&vtd, IVA, 0x2000 | 1);
+        write32(&vtd, IOTLB, 0);
+        write32(&vtd, IOTLB + 4, IOTLB_IVT | (3 << IOTLB_IIRG_SHIFT) | 7);
+        assert_eq!((read64(&vtd, IOTLB) >> 57) & 0x3, 0x3);
+        write32(&vtd, GCMD, GCMD_TE);
+        assert!(vtd.is_enabled());
+        assert_eq!(
+            *translator.0.lock().unwrap(),
+            vec![
+                "root 0x10000000",
+                "All",
+                "Range { domain: 7, iova: 8192, size: 8192 }",
+                "enabled true"
+            ]
+        );
+
+        // Faults are signaled once unmasked, and stay recorded until cleared.
+        write32(&vtd, FEDATA, 0x31);
+        write32(&vtd, FEADDR, 0xfee0_0000);
+        let fault = translator.translate(0x0010, 0x3456, true).unwrap_err();
+        vtd.record_fault(&fault);
+        assert!(sent.lock().unwrap().is_empty());
+        assert_eq!(read64(&vtd, FECTL) as u32, FECTL_IM | FECTL_IP);
+        write32(&vtd, FECTL, 0);
+        assert_eq!(
+            *sent.lock().unwrap(),
+            vec![MsiMessage::new(0xfee0_0000, 0x31)]
+        );
+        assert_eq!(read64(&vtd, FRCD), 0x3000);
+        assert_eq!(read64(&vtd, FRCD + 8), (1 << 63) | (0x2 << 32) | 0x10);
+        vtd.record_fault(&fault);
+        assert_eq!(read64(&vtd, FSTS - 4) >> 32, u64::from(FSTS_PPF | FSTS_PFO));
+        write32(&vtd, FRCD + 12, FRCD_F);
+        write32(&vtd, FSTS, FSTS_PFO);
+        assert_eq!(read64(&vtd, FSTS - 4) >> 32, 0);
+    }
+}
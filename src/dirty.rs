@@ -0,0 +1,114 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Tracking of the pages written in device-backed memory, such as RAM, flash, or
+//! framebuffer regions, so that migration only has to resend what changed.
+//!
+//! Devices mark the pages they change in a shared [`DirtyBitmap`](struct.DirtyBitmap.html),
+//! which is also handed to the `IoManager` together with the range of the region. The
+//! migration code then collects the dirty pages of every region with
+//! `IoManager::take_dirty`.
+
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Errors encountered while setting up a dirty bitmap.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The page size is not a non-zero power of two.
+    InvalidPageSize(u64),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::InvalidPageSize(size) => write!(f, "invalid dirty page size {:#x}", size),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Bitmap holding the written state of each page of a memory region. Pages can be marked
+/// concurrently with the bitmap being collected.
+#[derive(Debug)]
+pub struct DirtyBitmap {
+    page_size: u64,
+    size: u64,
+    bits: Vec<AtomicU64>,
+}
+
+impl DirtyBitmap {
+    /// Create a clean bitmap for a region of `size` bytes, split in pages of `page_size`
+    /// bytes.
+    pub fn new(size: u64, page_size: u64) -> Result<Self, Error> {
+        if !page_size.is_power_of_two() {
+            return Err(Error::InvalidPageSize(page_size));
+        }
+        let pages = size.div_ceil(page_size);
+        Ok(DirtyBitmap {
+            page_size,
+            size,
+            bits: (0..pages.div_ceil(64)).map(|_| AtomicU64::new(0)).collect(),
+        })
+    }
+
+    /// Return the page size.
+    pub fn page_size(&self) -> u64 {
+        self.page_size
+    }
+
+    /// Mark the pages touched by the `len` bytes at `offset` as dirty. Bytes past the end of
+    /// the region are ignored.
+    pub fn mark(&self, offset: u64, len: u64) {
+        if len == 0 || offset >= self.size {
+            return;
+        }
+        let last = offset.saturating_add(len - 1).min(self.size - 1);
+        for page in offset / self.page_size..=last / self.page_size {
+            self.bits[(page / 64) as usize].fetch_or(1 << (page % 64), Ordering::SeqCst);
+        }
+    }
+
+    /// Return whether `page` was written since the bitmap was last collected.
+    pub fn is_dirty(&self, page: u64) -> bool {
+        self.bits
+            .get((page / 64) as usize)
+            .is_some_and(|word| word.load(Ordering::SeqCst) & (1 << (page % 64)) != 0)
+    }
+
+    /// Return the indices of the dirty pages in ascending order, and mark them clean.
+    pub fn take_dirty(&self) -> Vec<u64> {
+        let mut pages = Vec::new();
+        for (idx, word) in self.bits.iter().enumerate() {
+            let mut bits = word.swap(0, Ordering::SeqCst);
+            while bits != 0 {
+                pages.push(idx as u64 * 64 + u64::from(bits.trailing_zeros()));
+                bits &= bits - 1;
+            }
+        }
+        pages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dirty_bitmap() {
+        assert_eq!(
+            DirtyBitmap::new(0x1000, 0).unwrap_err(),
+            Error::InvalidPageSize(0)
+        );
+        let bitmap = DirtyBitmap::new(0x10_0000, 0x1000).unwrap();
+        bitmap.mark(0xfff, 2);
+        bitmap.mark(0x4_0000, 1);
+        bitmap.mark(0xf_ffff, 0x100);
+        bitmap.mark(0x10_0000, 1);
+        assert!(bitmap.is_dirty(0x40));
+        assert!(!bitmap.is_dirty(0x41));
+        assert_eq!(bitmap.take_dirty(), vec![0, 1, 0x40, 0xff]);
+        assert!(bitmap.take_dirty().is_empty());
+    }
+}
@@ -0,0 +1,178 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Control plane events sent by devices to the VMM.
+//!
+//! Devices such as the reset control register, a pvpanic device, or a hotplug controller
+//! need to ask the VMM to act on the whole VM. Instead of each of them defining its own
+//! callback type, they get a [`VmEventSender`](struct.VmEventSender.html) labelled with their
+//! name when they are created (see `IoManager::event_sender`), and the VMM handles the
+//! events coming out of the matching [`VmEventReceiver`](struct.VmEventReceiver.html).
+
+use std::fmt::{Display, Formatter};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::exit::AddressSpace;
+use crate::lifecycle::SleepState;
+
+/// Errors encountered while sending events.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The receiving end of the channel has been dropped.
+    Disconnected,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Disconnected => write!(f, "VM event receiver disconnected"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Control plane events sent by devices.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum VmEvent {
+    /// The guest requested a power off.
+    Shutdown,
+    /// The guest requested a reset.
+    Reset,
+    /// The guest requested entering the sleep state.
+    Sleep(SleepState),
+    /// The guest reported a panic, with a device specific code.
+    Panic(u64),
+    /// The guest requested adding (`add` is set) or removing a device from `slot`.
+    HotplugRequest { slot: u32, add: bool },
+    /// Message which should be logged by the VMM.
+    Log(String),
+    /// The range at `base` was quarantined, since its device kept failing accesses.
+    DeviceQuarantined { space: AddressSpace, base: u64 },
+    /// The device of the range at `base` panicked while handling an access. The panic was
+    /// caught by the manager, which quarantined the range.
+    DevicePanicked { space: AddressSpace, base: u64 },
+    /// The `size` bytes of guest memory at `base` must be mapped in the KVM memory slot
+    /// `memslot` before the guest is told about them.
+    AddMemory { memslot: u32, base: u64, size: u64 },
+    /// The guest released the `size` bytes of memory at `base`, which can be unmapped from
+    /// the KVM memory slot `memslot`.
+    RemoveMemory { memslot: u32, base: u64, size: u64 },
+    /// The vCPU `cpu`, with the architectural ID `arch_id` (the APIC ID on x86), must be
+    /// created before the guest is told about it.
+    AddCpu { cpu: u32, arch_id: u64 },
+    /// The guest ejected the vCPU `cpu`, which can be destroyed.
+    RemoveCpu { cpu: u32, arch_id: u64 },
+}
+
+/// Event together with the name of the device which sent it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VmEventMessage {
+    /// Name of the sending device.
+    pub source: Arc<str>,
+    /// The event itself.
+    pub event: VmEvent,
+}
+
+/// Sending end of a VM event channel, labelled with the name of the device owning it.
+#[derive(Clone, Debug)]
+pub struct VmEventSender {
+    source: Arc<str>,
+    tx: Sender<VmEventMessage>,
+}
+
+impl VmEventSender {
+    /// Return a sender for the same channel, labelled with `source`.
+    pub fn for_device(&self, source: &str) -> Self {
+        VmEventSender {
+            source: Arc::from(source),
+            tx: self.tx.clone(),
+        }
+    }
+
+    /// Return the name of the device owning the sender.
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Send `event` to the VMM.
+    pub fn send(&self, event: VmEvent) -> Result<(), Error> {
+        self.tx
+            .send(VmEventMessage {
+                source: self.source.clone(),
+                event,
+            })
+            .map_err(|_| Error::Disconnected)
+    }
+}
+
+/// Receiving end of a VM event channel.
+#[derive(Debug)]
+pub struct VmEventReceiver {
+    rx: Receiver<VmEventMessage>,
+}
+
+impl VmEventReceiver {
+    /// Return the next pending event, without blocking.
+    pub fn try_recv(&self) -> Option<VmEventMessage> {
+        self.rx.try_recv().ok()
+    }
+
+    /// Wait up to `timeout` for the next event. Returns `None` when the timeout expires, or
+    /// when all the senders have been dropped.
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<VmEventMessage> {
+        match self.rx.recv_timeout(timeout) {
+            Ok(message) => Some(message),
+            Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => None,
+        }
+    }
+
+    /// Return all the pending events, oldest first.
+    pub fn drain(&self) -> Vec<VmEventMessage> {
+        self.rx.try_iter().collect()
+    }
+}
+
+/// Create a VM event channel. The sender is labelled `vmm`; use `VmEventSender::for_device`
+/// to get the ones handed to devices.
+pub fn vm_event_channel() -> (VmEventSender, VmEventReceiver) {
+    let (tx, rx) = mpsc::channel();
+    (
+        VmEventSender {
+            source: Arc::from("vmm"),
+            tx,
+        },
+        VmEventReceiver { rx },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    #[test]
+    fn test_vm_event_channel() {
+        let (sender, receiver) = vm_event_channel();
+        assert!(receiver.try_recv().is_none());
+
+        let pvpanic = sender.for_device("pvpanic");
+        assert_eq!(pvpanic.source(), "pvpanic");
+        thread::spawn(move || pvpanic.send(VmEvent::Panic(1)).unwrap())
+            .join()
+            .unwrap();
+        sender.for_device("cf9").send(VmEvent::Reset).unwrap();
+
+        let message = receiver.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(&*message.source, "pvpanic");
+        assert_eq!(message.event, VmEvent::Panic(1));
+        let events: Vec<_> = receiver.drain().into_iter().map(|m| m.event).collect();
+        assert_eq!(events, vec![VmEvent::Reset]);
+
+        drop(receiver);
+        assert_eq!(sender.send(VmEvent::Shutdown), Err(Error::Disconnected));
+    }
+}
@@ -0,0 +1,313 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Hypervisor agnostic description of the I/O exits of vCPUs.
+//!
+//! Every hypervisor reports the accesses its vCPUs perform to emulated devices in its own
+//! format. They are converted to an [`IoExit`](enum.IoExit.html), which is then dispatched
+//! to the devices registered with an `IoManager` by the same glue, whatever the hypervisor.
+//! Adapters are provided for the exits of KVM (with the `kvm` feature), and for the I/O
+//! port intercepts of Microsoft Hypervisor (mshv). The memory intercepts of mshv only carry
+//! the faulting instruction, so the VMM decodes them with an instruction emulator first,
+//! which then reports plain `IoExit::Read` and `IoExit::Write` MMIO accesses.
+
+use crate::bus::{self, MmioAddress, PioAddress};
+use crate::device_manager::{MmioManager, PioManager};
+use crate::IoAccess;
+
+/// Address space targeted by an access.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AddressSpace {
+    /// Port I/O.
+    Pio,
+    /// Memory mapped I/O.
+    Mmio,
+}
+
+/// Direction of an access.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum IoDirection {
+    /// The vCPU reads from the device.
+    Read,
+    /// The vCPU writes to the device.
+    Write,
+}
+
+/// An access performed by a vCPU, which has to be handled by an emulated device.
+#[derive(Debug, Eq, PartialEq)]
+pub enum IoExit<'a> {
+    /// Read of `data.len()` bytes at `addr`, to be filled in `data`.
+    Read {
+        /// Address space of the access.
+        space: AddressSpace,
+        /// Address of the access.
+        addr: u64,
+        /// Buffer receiving the read value.
+        data: &'a mut [u8],
+    },
+    /// Write of `data` at `addr`.
+    Write {
+        /// Address space of the access.
+        space: AddressSpace,
+        /// Address of the access.
+        addr: u64,
+        /// The written value.
+        data: &'a [u8],
+    },
+}
+
+impl IoExit<'_> {
+    /// Return the direction of the access.
+    pub fn direction(&self) -> IoDirection {
+        match self {
+            IoExit::Read { .. } => IoDirection::Read,
+            IoExit::Write { .. } => IoDirection::Write,
+        }
+    }
+
+    /// Return the address space of the access.
+    pub fn space(&self) -> AddressSpace {
+        match self {
+            IoExit::Read { space, .. } | IoExit::Write { space, .. } => *space,
+        }
+    }
+
+    /// Return the address of the access.
+    pub fn addr(&self) -> u64 {
+        match self {
+            IoExit::Read { addr, .. } | IoExit::Write { addr, .. } => *addr,
+        }
+    }
+
+    /// Return the length of the access.
+    pub fn len(&self) -> usize {
+        match self {
+            IoExit::Read { data, .. } => data.len(),
+            IoExit::Write { data, .. } => data.len(),
+        }
+    }
+
+    /// Return `true` if the access has no data.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Dispatch the access to the device registered at its address with `io_mgr`, described
+    /// by the `access` context. PIO accesses beyond the port range don't reach any device.
+    pub fn dispatch<T>(self, io_mgr: &T, access: IoAccess) -> Result<(), bus::Error>
+    where
+        T: PioManager + MmioManager<MmioAddress>,
+    {
+        let access = IoAccess {
+            width: self.len(),
+            ..access
+        };
+        let port = |addr: u64| PioAddress::new(addr).ok_or(bus::Error::DeviceNotFound);
+        match self {
+            IoExit::Read {
+                space: AddressSpace::Pio,
+                addr,
+                data,
+            } => io_mgr.pio_read_with(port(addr)?, access, data),
+            IoExit::Write {
+                space: AddressSpace::Pio,
+                addr,
+                data,
+            } => io_mgr.pio_write_with(port(addr)?, access, data),
+            IoExit::Read {
+                space: AddressSpace::Mmio,
+                addr,
+                data,
+            } => io_mgr.mmio_read_with(MmioAddress(addr), access, data),
+            IoExit::Write {
+                space: AddressSpace::Mmio,
+                addr,
+                data,
+            } => io_mgr.mmio_write_with(MmioAddress(addr), access, data),
+        }
+    }
+}
+
+#[cfg(feature = "kvm")]
+mod kvm {
+    use std::convert::TryFrom;
+
+    use kvm_ioctls::VcpuExit;
+
+    use super::{AddressSpace, IoExit};
+
+    /// Converts the I/O exits of KVM, and hands back the other ones.
+    impl<'a> TryFrom<VcpuExit<'a>> for IoExit<'a> {
+        type Error = VcpuExit<'a>;
+
+        fn try_from(exit: VcpuExit<'a>) -> Result<Self, Self::Error> {
+            match exit {
+                VcpuExit::IoIn(port, data) => Ok(IoExit::Read {
+                    space: AddressSpace::Pio,
+                    addr: u64::from(port),
+                    data,
+                }),
+                VcpuExit::IoOut(port, data) => Ok(IoExit::Write {
+                    space: AddressSpace::Pio,
+                    addr: u64::from(port),
+                    data,
+                }),
+                VcpuExit::MmioRead(addr, data) => Ok(IoExit::Read {
+                    space: AddressSpace::Mmio,
+                    addr,
+                    data,
+                }),
+                VcpuExit::MmioWrite(addr, data) => Ok(IoExit::Write {
+                    space: AddressSpace::Mmio,
+                    addr,
+                    data,
+                }),
+                exit => Err(exit),
+            }
+        }
+    }
+}
+
+pub use self::mshv::MshvIoPortIntercept;
+
+mod mshv {
+    use crate::bus::{self, MmioAddress};
+    use crate::device_manager::{MmioManager, PioManager};
+    use crate::IoAccess;
+
+    use super::{AddressSpace, IoExit};
+
+    // Values of the `intercept_access_type` field of the intercept message header.
+    const HV_INTERCEPT_ACCESS_WRITE: u8 = 1;
+    // Mask of the access size within the `access_info` field of the message.
+    const HV_ACCESS_SIZE_MASK: u8 = 0x7;
+
+    /// The fields of an mshv I/O port intercept message
+    /// (`hv_x64_io_port_intercept_message`) needed to emulate the access.
+    #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+    pub struct MshvIoPortIntercept {
+        /// The accessed port.
+        pub port: u16,
+        /// Length of the access, in bytes.
+        pub access_size: u8,
+        /// The vCPU writes to the port.
+        pub is_write: bool,
+        /// Value of RAX, which holds the written value, and receives the read one.
+        pub rax: u64,
+    }
+
+    impl MshvIoPortIntercept {
+        /// Build the intercept from the raw `port_number`, `access_info` and `rax` fields of
+        /// the message, and the `intercept_access_type` field of its header.
+        pub fn new(port_number: u16, access_info: u8, intercept_access_type: u8, rax: u64) -> Self {
+            MshvIoPortIntercept {
+                port: port_number,
+                access_size: access_info & HV_ACCESS_SIZE_MASK,
+                is_write: intercept_access_type == HV_INTERCEPT_ACCESS_WRITE,
+                rax,
+            }
+        }
+
+        /// Dispatch the access to `io_mgr`, and return the value RAX must hold when the
+        /// vCPU resumes: the read value merged in its low bytes, or its current value for
+        /// writes.
+        pub fn handle<T>(&self, io_mgr: &T, access: IoAccess) -> Result<u64, bus::Error>
+        where
+            T: PioManager + MmioManager<MmioAddress>,
+        {
+            let len = usize::from(self.access_size);
+            if len == 0 || len > 4 {
+                return Err(bus::Error::InvalidAccessLength(len));
+            }
+            let mut data = self.rax.to_le_bytes();
+            if self.is_write {
+                IoExit::Write {
+                    space: AddressSpace::Pio,
+                    addr: u64::from(self.port),
+                    data: &data[..len],
+                }
+                .dispatch(io_mgr, access)?;
+            } else {
+                IoExit::Read {
+                    space: AddressSpace::Pio,
+                    addr: u64::from(self.port),
+                    data: &mut data[..len],
+                }
+                .dispatch(io_mgr, access)?;
+            }
+            Ok(u64::from_le_bytes(data))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::bus::{MmioRange, PioRange};
+    use crate::device_manager::IoManager;
+    use crate::testing::EchoDevice;
+
+    #[test]
+    fn test_io_exit() {
+        let mut io_mgr = IoManager::new();
+        let device = Arc::new(EchoDevice::new());
+        io_mgr
+            .register_pio(
+                PioRange::new(PioAddress(0x60), 0x10).unwrap(),
+                device.clone(),
+            )
+            .unwrap();
+        io_mgr
+            .register_mmio(
+                MmioRange::new(MmioAddress(0x1000), 0x10).unwrap(),
+                device.clone(),
+            )
+            .unwrap();
+
+        let exit = IoExit::Write {
+            space: AddressSpace::Mmio,
+            addr: 0x1004,
+            data: &[1, 2],
+        };
+        assert_eq!(exit.direction(), IoDirection::Write);
+        assert_eq!(exit.len(), 2);
+        exit.dispatch(&io_mgr, IoAccess::new(0, 0)).unwrap();
+        let mut data = [0; 2];
+        IoExit::Read {
+            space: AddressSpace::Pio,
+            addr: 0x64,
+            data: &mut data,
+        }
+        .dispatch(&io_mgr, IoAccess::default())
+        .unwrap();
+        assert_eq!(data, [1, 2]);
+        assert_eq!(device.accesses().len(), 2);
+
+        let mut data = [0; 1];
+        let exit = IoExit::Read {
+            space: AddressSpace::Pio,
+            addr: 0x1_0000,
+            data: &mut data,
+        };
+        assert_eq!(
+            exit.dispatch(&io_mgr, IoAccess::default()),
+            Err(bus::Error::DeviceNotFound)
+        );
+
+        // The read value of an mshv port intercept ends up in the low bytes of RAX.
+        let write = MshvIoPortIntercept::new(0x60, 4, 1, 0xdead_beef);
+        assert_eq!(
+            write.handle(&io_mgr, IoAccess::default()).unwrap(),
+            0xdead_beef
+        );
+        let read = MshvIoPortIntercept::new(0x60, 2, 0, 0x1111_2222_3333_4444);
+        assert!(!read.is_write);
+        assert_eq!(
+            read.handle(&io_mgr, IoAccess::default()).unwrap(),
+            0x1111_2222_3333_beef
+        );
+    }
+}
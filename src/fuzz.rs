@@ -0,0 +1,359 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Driver which replays arbitrary sequences of bus operations, for use by fuzz targets.
+//!
+//! The operations are decoded from raw bytes, applied both to a `Bus` and to the MMIO bus of
+//! an `IoManager`, and mirrored on a simple reference model. The driver panics as soon as
+//! the outcome of an operation, or the resulting device map, differs from what the model
+//! expects. The `fuzz` directory holds the `cargo fuzz` targets built on top of it.
+
+use std::sync::Arc;
+
+use crate::bus::{self, Bus, MmioAddress, MmioOffset, MmioRange};
+use crate::device_manager::{Error, IoManager, MmioManager};
+use crate::DeviceMmio;
+
+/// Operation performed on the buses under test.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BusOp {
+    /// Register a new device with the range starting at `base`.
+    Register { base: u64, size: u64 },
+    /// Register a new device with the shadow range starting at `base`.
+    RegisterShadow { base: u64, size: u64 },
+    /// Deregister the device found at `addr`.
+    Deregister { addr: u64 },
+    /// Read `len` bytes at `addr`.
+    Access { addr: u64, len: usize },
+}
+
+impl BusOp {
+    /// Decode the next operation from the beginning of `data`, and advance it past the
+    /// consumed bytes. Return `None` when `data` doesn't hold enough bytes.
+    ///
+    /// Addresses and sizes are kept small to make overlaps likely; the top bit of the first
+    /// byte moves the addresses to the top end of the address space instead, so that
+    /// overflow conditions get exercised as well.
+    pub fn decode(data: &mut &[u8]) -> Option<Self> {
+        let (&tag, rest) = data.split_first()?;
+        *data = rest;
+        let mut next = |n: usize| -> Option<u64> {
+            if data.len() < n {
+                return None;
+            }
+            let value = data[..n]
+                .iter()
+                .fold(0u64, |acc, byte| (acc << 8) | u64::from(*byte));
+            *data = &data[n..];
+            Some(value)
+        };
+        let addr = |value: u64| {
+            if tag & 0x80 != 0 {
+                u64::MAX - value
+            } else {
+                value
+            }
+        };
+
+        let op = match tag & 0x3 {
+            0 => BusOp::Register {
+                base: addr(next(2)?),
+                size: next(2)?,
+            },
+            1 => BusOp::RegisterShadow {
+                base: addr(next(2)?),
+                size: next(2)?,
+            },
+            2 => BusOp::Deregister {
+                addr: addr(next(2)?),
+            },
+            _ => BusOp::Access {
+                addr: addr(next(2)?),
+                len: next(1)? as usize % 9,
+            },
+        };
+        Some(op)
+    }
+}
+
+// Device which reports its identifier on reads.
+struct IdDevice(u32);
+
+impl DeviceMmio for IdDevice {
+    fn mmio_read(&self, _base: MmioAddress, _offset: MmioOffset, data: &mut [u8]) {
+        for (idx, byte) in data.iter_mut().enumerate() {
+            *byte = self.0.to_le_bytes().get(idx).copied().unwrap_or(0);
+        }
+    }
+
+    fn mmio_write(&self, _base: MmioAddress, _offset: MmioOffset, _data: &[u8]) {}
+}
+
+// Entry of the reference model.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct ModelEntry {
+    base: u64,
+    last: u64,
+    id: u32,
+    shadow: bool,
+}
+
+impl ModelEntry {
+    fn contains(&self, addr: u64) -> bool {
+        self.base <= addr && addr <= self.last
+    }
+
+    fn overlaps(&self, base: u64, last: u64) -> bool {
+        !(self.base > last || self.last < base)
+    }
+}
+
+/// Applies operations to the buses under test, and checks them against a reference model.
+#[derive(Default)]
+pub struct FuzzDriver {
+    bus: Bus<MmioAddress, u32>,
+    io_mgr: IoManager,
+    model: Vec<ModelEntry>,
+    next_id: u32,
+}
+
+impl FuzzDriver {
+    /// Create a driver with empty buses.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Return the entry handling `addr`, with shadows taking priority.
+    fn lookup(&self, addr: u64) -> Option<ModelEntry> {
+        let find = |shadow| {
+            self.model
+                .iter()
+                .find(|e| e.shadow == shadow && e.contains(addr))
+                .copied()
+        };
+        find(true).or_else(|| find(false))
+    }
+
+    // Return the expected outcome of registering `[base, base + size)`.
+    fn expect_register(&self, base: u64, size: u64, shadow: bool) -> Result<u64, bus::Error> {
+        if size == 0 {
+            return Err(bus::Error::InvalidRange);
+        }
+        let last = base.checked_add(size - 1).ok_or(bus::Error::InvalidRange)?;
+        if shadow {
+            let parent = self
+                .model
+                .iter()
+                .any(|e| !e.shadow && e.contains(base) && e.last >= last);
+            if !parent {
+                return Err(bus::Error::DeviceNotFound);
+            }
+        }
+        if self
+            .model
+            .iter()
+            .any(|e| e.shadow == shadow && e.overlaps(base, last))
+        {
+            return Err(bus::Error::DeviceOverlap);
+        }
+        Ok(last)
+    }
+
+    // Return the expected outcome of an access of `len` bytes at `addr`.
+    fn expect_access(&self, addr: u64, len: usize) -> Result<u32, bus::Error> {
+        if len == 0 {
+            return Err(bus::Error::InvalidRange);
+        }
+        let last = addr
+            .checked_add(len as u64 - 1)
+            .ok_or(bus::Error::InvalidRange)?;
+        self.lookup(addr)
+            .filter(|e| e.last >= last)
+            .filter(|e| {
+                e.shadow
+                    || !self
+                        .model
+                        .iter()
+                        .any(|s| s.shadow && s.overlaps(addr, last))
+            })
+            .map(|e| e.id)
+            .ok_or(bus::Error::DeviceNotFound)
+    }
+
+    fn register(&mut self, base: u64, size: u64, shadow: bool) {
+        let expected = self.expect_register(base, size, shadow);
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let range = match MmioRange::new(MmioAddress(base), size) {
+            Ok(range) => range,
+            Err(e) => {
+                assert_eq!(Err(e), expected);
+                return;
+            }
+        };
+        let device = Arc::new(IdDevice(id));
+        let (bus_res, mgr_res) = if shadow {
+            (
+                self.bus.register_shadow(range, id),
+                self.io_mgr
+                    .register_mmio_shadow(range, device)
+                    .map_err(|e| match e {
+                        Error::Bus(e) => e,
+                        e => panic!("unexpected error: {}", e),
+                    }),
+            )
+        } else {
+            (
+                self.bus.register(range, id),
+                self.io_mgr.register_mmio(range, device),
+            )
+        };
+        assert_eq!(bus_res, expected.map(|_| ()));
+        assert_eq!(mgr_res, expected.map(|_| ()));
+        if let Ok(last) = expected {
+            self.model.push(ModelEntry {
+                base,
+                last,
+                id,
+                shadow,
+            });
+        }
+    }
+
+    fn deregister(&mut self, addr: u64) {
+        let expected = self.lookup(addr);
+        let bus_res = self.bus.deregister(MmioAddress(addr));
+        let mgr_res = self.io_mgr.deregister_mmio(MmioAddress(addr));
+        assert_eq!(
+            bus_res.map(|(range, id)| (range.base().0, id)),
+            expected.map(|e| (e.base, e.id))
+        );
+        assert_eq!(
+            mgr_res.map(|(range, _)| range.base().0),
+            expected.map(|e| e.base)
+        );
+        if let Some(entry) = expected {
+            // The shadows go away together with the regular range below them.
+            self.model.retain(|e| {
+                *e != entry && (entry.shadow || !e.shadow || !entry.overlaps(e.base, e.last))
+            });
+        }
+    }
+
+    fn access(&self, addr: u64, len: usize) {
+        let expected = self.expect_access(addr, len);
+        let bus_res = self.bus.access(MmioAddress(addr), len).map(|a| *a);
+        assert_eq!(bus_res, expected);
+
+        let mut data = [0u8; 8];
+        let mgr_res = self
+            .io_mgr
+            .mmio_read(MmioAddress(addr), &mut data[..len])
+            .map(|_| {
+                let mut id = [0u8; 4];
+                id.copy_from_slice(&data[..4]);
+                u32::from_le_bytes(id)
+            });
+        match expected {
+            // Narrow accesses only return part of the identifier.
+            Ok(id) if len < 4 => assert!(mgr_res.is_ok(), "{:?} != Ok({})", mgr_res, id),
+            _ => assert_eq!(mgr_res, expected),
+        }
+    }
+
+    /// Apply `op`, and check its outcome together with the resulting device map.
+    pub fn apply(&mut self, op: BusOp) {
+        match op {
+            BusOp::Register { base, size } => self.register(base, size, false),
+            BusOp::RegisterShadow { base, size } => self.register(base, size, true),
+            BusOp::Deregister { addr } => self.deregister(addr),
+            BusOp::Access { addr, len } => self.access(addr, len),
+        }
+        self.check();
+    }
+
+    /// Check that the buses under test hold the same ranges as the model, and that lookups
+    /// at the edges of every range agree with it.
+    pub fn check(&self) {
+        let mut expected: Vec<_> = self.model.iter().filter(|e| !e.shadow).collect();
+        expected.extend(self.model.iter().filter(|e| e.shadow));
+        expected.sort_by_key(|e| e.base);
+
+        let layout = self.io_mgr.layout();
+        let actual: Vec<_> = layout
+            .mmio
+            .iter()
+            .map(|e| (e.range.base().0, e.range.last().0, e.shadow))
+            .collect();
+        let model: Vec<_> = expected
+            .iter()
+            .map(|e| (e.base, e.last, e.shadow))
+            .collect();
+        assert_eq!(actual, model);
+        assert_eq!(
+            self.bus.len(),
+            self.model.iter().filter(|e| !e.shadow).count()
+        );
+
+        for entry in self.model.iter() {
+            let probes = [
+                entry.base.checked_sub(1),
+                Some(entry.base),
+                Some(entry.last),
+                entry.last.checked_add(1),
+            ];
+            for addr in probes.iter().flatten() {
+                let expected = self.lookup(*addr).map(|e| e.id);
+                let actual = self.bus.device(MmioAddress(*addr)).map(|(_, id)| *id);
+                assert_eq!(actual, expected, "lookup at {:#x}", addr);
+            }
+        }
+    }
+}
+
+/// Decode operations from `data` and apply them until the input is exhausted. This is the
+/// entry point used by the fuzz targets.
+pub fn run(mut data: &[u8]) {
+    let mut driver = FuzzDriver::new();
+    while let Some(op) = BusOp::decode(&mut data) {
+        driver.apply(op);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fuzz_driver() {
+        let mut data = &[0x00, 0x00, 0x10, 0x00, 0x10, 0x83, 0x00, 0x01, 0x04][..];
+        assert_eq!(
+            BusOp::decode(&mut data),
+            Some(BusOp::Register {
+                base: 0x10,
+                size: 0x10
+            })
+        );
+        assert_eq!(
+            BusOp::decode(&mut data),
+            Some(BusOp::Access {
+                addr: u64::MAX - 1,
+                len: 4
+            })
+        );
+        assert_eq!(BusOp::decode(&mut data), None);
+
+        // Replay pseudo-random inputs.
+        let mut state = 0x1234_5678u32;
+        for _ in 0..64 {
+            let input: Vec<u8> = (0..256)
+                .map(|_| {
+                    state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                    (state >> 16) as u8 & 0x87
+                })
+                .collect();
+            run(&input);
+        }
+    }
+}
@@ -0,0 +1,403 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Sharing an `IoManager` between vCPU threads without locking the dispatch path.
+//!
+//! Accesses are dispatched through `&IoManager`, but changing the device map requires
+//! `&mut IoManager`, so a manager shared by the vCPUs and by the thread handling hotplug
+//! usually ends up behind a `RwLock`, which every access has to take. An
+//! [`IoManagerOwner`](struct.IoManagerOwner.html) instead keeps two copies of the manager:
+//! the vCPUs dispatch through an [`IoManagerHandle`](struct.IoManagerHandle.html) to the
+//! active copy, while the owner applies each change to a copy of it, makes that copy the
+//! active one, waits for the accesses still running on the previous copy, and replaces it
+//! with another copy. Unlike `IoManager::fork`, the copies share the state of their ranges,
+//! so the state changed through `&IoManager` (e.g. the decoding of a range, its quarantine and its statistics) is
+//! the same on both copies. Dispatching an access only costs two atomic increments and a
+//! load.
+//!
+//! Handles don't change the device map themselves: they queue changes, which the owner
+//! applies with `apply_pending`.
+
+use std::cell::{Cell, UnsafeCell};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::bus::{self, BusRange, MmioAddress, MmioBusAddress, PioAddress, PioRange};
+use crate::device_manager::{Error, IoManager, MmioManager, PioManager};
+use crate::{DeviceMmio, DevicePio};
+
+/// A change of the device map, applied by the owner to a copy of the active one.
+pub type Mutation<M> = Box<dyn Fn(&mut IoManager<M>) -> Result<(), Error> + Send>;
+
+// A change queued by a handle, with the channel receiving its result.
+type Queued<M> = (Mutation<M>, Sender<Result<(), Error>>);
+
+struct Shared<M: MmioBusAddress> {
+    copies: [UnsafeCell<IoManager<M>>; 2],
+    active: AtomicUsize,
+    // One counter per handle, which is odd while the handle dispatches an access.
+    readers: Mutex<Vec<Arc<AtomicU64>>>,
+}
+
+// The copies are only written by the owner, while no reader can access them (see
+// `IoManagerOwner::apply`), so they can be shared like an `IoManager`.
+unsafe impl<M: MmioBusAddress> Sync for Shared<M> where IoManager<M>: Send + Sync {}
+
+impl<M: MmioBusAddress> Shared<M> {
+    fn reader(self: &Arc<Self>) -> Arc<AtomicU64> {
+        let epoch = Arc::new(AtomicU64::new(0));
+        self.readers.lock().unwrap().push(epoch.clone());
+        epoch
+    }
+}
+
+/// A change queued by a handle, whose result is available once the owner applied it.
+pub struct PendingMutation(Receiver<Result<(), Error>>);
+
+impl PendingMutation {
+    /// Wait for the owner to apply the change, and return its result. Returns `None` if the
+    /// owner was dropped before applying it.
+    pub fn wait(self) -> Option<Result<(), Error>> {
+        self.0.recv().ok()
+    }
+}
+
+/// Dispatches the accesses of a vCPU to the manager shared through an `IoManagerOwner`.
+///
+/// Each vCPU thread uses its own handle, obtained with `clone`.
+pub struct IoManagerHandle<M: MmioBusAddress = MmioAddress> {
+    shared: Arc<Shared<M>>,
+    epoch: Arc<AtomicU64>,
+    // Nesting level of the accesses in progress, e.g. when a device dispatches an access
+    // while handling one. Also makes the handle `!Sync`, since the epoch is per thread.
+    depth: Cell<usize>,
+    mutations: Sender<Queued<M>>,
+}
+
+impl<M: MmioBusAddress> IoManagerHandle<M> {
+    /// Run `f` with the active copy of the manager.
+    pub fn with<R, F: FnOnce(&IoManager<M>) -> R>(&self, f: F) -> R {
+        let depth = self.depth.get();
+        if depth == 0 {
+            self.epoch.fetch_add(1, Ordering::SeqCst);
+        }
+        self.depth.set(depth + 1);
+        let active = self.shared.active.load(Ordering::SeqCst);
+        // The owner doesn't modify the active copy, nor the previous one until the epoch
+        // of this handle changes.
+        let result = f(unsafe { &*self.shared.copies[active].get() });
+        self.depth.set(depth);
+        if depth == 0 {
+            self.epoch.fetch_add(1, Ordering::SeqCst);
+        }
+        result
+    }
+
+    /// Dispatch a PIO read to the device registered at `addr`.
+    pub fn pio_read(&self, addr: PioAddress, data: &mut [u8]) -> Result<(), bus::Error> {
+        self.with(|io_mgr| io_mgr.pio_read(addr, data))
+    }
+
+    /// Dispatch a PIO write to the device registered at `addr`.
+    pub fn pio_write(&self, addr: PioAddress, data: &[u8]) -> Result<(), bus::Error> {
+        self.with(|io_mgr| io_mgr.pio_write(addr, data))
+    }
+
+    /// Dispatch a MMIO read to the device registered at `addr`.
+    pub fn mmio_read(&self, addr: M, data: &mut [u8]) -> Result<(), bus::Error> {
+        self.with(|io_mgr| io_mgr.mmio_read(addr, data))
+    }
+
+    /// Dispatch a MMIO write to the device registered at `addr`.
+    pub fn mmio_write(&self, addr: M, data: &[u8]) -> Result<(), bus::Error> {
+        self.with(|io_mgr| io_mgr.mmio_write(addr, data))
+    }
+
+    /// Queue `mutation`, to be applied by the owner.
+    pub fn mutate(&self, mutation: Mutation<M>) -> PendingMutation {
+        let (sender, receiver) = channel();
+        // The result of a change queued after the owner was dropped is never available,
+        // which `PendingMutation::wait` reports.
+        let _ = self.mutations.send((mutation, sender));
+        PendingMutation(receiver)
+    }
+
+    /// Queue the registration of a PIO device.
+    pub fn register_pio(
+        &self,
+        range: PioRange,
+        device: Arc<dyn DevicePio + Send + Sync>,
+    ) -> PendingMutation {
+        self.mutate(Box::new(move |io_mgr| {
+            io_mgr
+                .register_pio(range, device.clone())
+                .map_err(Error::Bus)
+        }))
+    }
+
+    /// Queue the deregistration of the PIO device registered at `addr`.
+    pub fn deregister_pio(&self, addr: PioAddress) -> PendingMutation {
+        self.mutate(Box::new(move |io_mgr| {
+            io_mgr
+                .deregister_pio(addr)
+                .map(|_| ())
+                .ok_or(Error::Bus(bus::Error::DeviceNotFound))
+        }))
+    }
+
+    /// Queue the registration of a MMIO device.
+    pub fn register_mmio(
+        &self,
+        range: BusRange<M>,
+        device: Arc<dyn DeviceMmio + Send + Sync>,
+    ) -> PendingMutation
+    where
+        M: Send + 'static,
+        BusRange<M>: Send,
+    {
+        self.mutate(Box::new(move |io_mgr| {
+            io_mgr
+                .register_mmio(range, device.clone())
+                .map_err(Error::Bus)
+        }))
+    }
+
+    /// Queue the deregistration of the MMIO device registered at `addr`.
+    pub fn deregister_mmio(&self, addr: M) -> PendingMutation
+    where
+        M: Send + 'static,
+    {
+        self.mutate(Box::new(move |io_mgr| {
+            io_mgr
+                .deregister_mmio(addr)
+                .map(|_| ())
+                .ok_or(Error::Bus(bus::Error::DeviceNotFound))
+        }))
+    }
+}
+
+impl<M: MmioBusAddress> Clone for IoManagerHandle<M> {
+    fn clone(&self) -> Self {
+        IoManagerHandle {
+            shared: self.shared.clone(),
+            epoch: self.shared.reader(),
+            depth: Cell::new(0),
+            mutations: self.mutations.clone(),
+        }
+    }
+}
+
+impl<M: MmioBusAddress> Drop for IoManagerHandle<M> {
+    fn drop(&mut self) {
+        self.shared
+            .readers
+            .lock()
+            .unwrap()
+            .retain(|epoch| !Arc::ptr_eq(epoch, &self.epoch));
+    }
+}
+
+/// Applies the changes of the device map shared with `IoManagerHandle`s.
+pub struct IoManagerOwner<M: MmioBusAddress = MmioAddress> {
+    shared: Arc<Shared<M>>,
+    sender: Sender<Queued<M>>,
+    receiver: Receiver<Queued<M>>,
+}
+
+impl<M: MmioBusAddress> IoManagerOwner<M> {
+    /// Create an owner of an empty device map.
+    pub fn new() -> Self {
+        let (sender, receiver) = channel();
+        IoManagerOwner {
+            shared: Arc::new(Shared {
+                copies: [
+                    UnsafeCell::new(IoManager::default()),
+                    UnsafeCell::new(IoManager::default()),
+                ],
+                active: AtomicUsize::new(0),
+                readers: Mutex::new(Vec::new()),
+            }),
+            sender,
+            receiver,
+        }
+    }
+
+    /// Return a new handle dispatching accesses to the shared manager.
+    pub fn handle(&self) -> IoManagerHandle<M> {
+        IoManagerHandle {
+            shared: self.shared.clone(),
+            epoch: self.shared.reader(),
+            depth: Cell::new(0),
+            mutations: self.sender.clone(),
+        }
+    }
+
+    /// Return the active copy of the manager.
+    pub fn manager(&self) -> &IoManager<M> {
+        // Only the owner changes the copies, which it can't do while this borrow is alive.
+        unsafe { &*self.shared.copies[self.shared.active.load(Ordering::SeqCst)].get() }
+    }
+
+    /// Apply `mutation` to the shared manager. When it fails, the device map is left
+    /// unchanged.
+    pub fn apply(&mut self, mutation: &Mutation<M>) -> Result<(), Error> {
+        let active = self.shared.active.load(Ordering::SeqCst);
+        let inactive = 1 - active;
+        // No handle accesses the inactive copy: the ones which used it when it was last
+        // active were waited for when it was swapped out.
+        let current = unsafe { &*self.shared.copies[active].get() };
+        let next = unsafe { &mut *self.shared.copies[inactive].get() };
+        *next = current.share();
+        mutation(next)?;
+        self.shared.active.store(inactive, Ordering::SeqCst);
+        self.wait_for_readers();
+        // Replace the previous copy, so it doesn't keep the devices which were removed.
+        let previous = unsafe { &mut *self.shared.copies[active].get() };
+        *previous = next.share();
+        Ok(())
+    }
+
+    /// Apply the changes queued by the handles, in order, and return their number.
+    pub fn apply_pending(&mut self) -> usize {
+        let pending: Vec<_> = self.receiver.try_iter().collect();
+        for (mutation, result) in pending.iter() {
+            let _ = result.send(self.apply(mutation));
+        }
+        pending.len()
+    }
+
+    // Wait until every handle which may have been using the previously active copy is done
+    // with it.
+    fn wait_for_readers(&self) {
+        let readers = self.shared.readers.lock().unwrap().clone();
+        for epoch in readers.iter() {
+            let start = epoch.load(Ordering::SeqCst);
+            if start % 2 == 0 {
+                continue;
+            }
+            while epoch.load(Ordering::SeqCst) == start {
+                thread::yield_now();
+            }
+        }
+    }
+}
+
+impl<M: MmioBusAddress> Default for IoManagerOwner<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::AtomicBool;
+
+    use crate::bus::MmioRange;
+    use crate::testing::{EchoDevice, FailingDevice};
+
+    #[test]
+    fn test_io_manager_handle() {
+        let mut owner = IoManagerOwner::<MmioAddress>::new();
+        let handle = owner.handle();
+        let device = Arc::new(EchoDevice::new());
+        let range = MmioRange::new(MmioAddress(0x1000), 0x100).unwrap();
+
+        // Changes only take effect once the owner applies them.
+        let pending = handle.register_mmio(range, device.clone());
+        assert!(handle.mmio_write(MmioAddress(0x1000), &[1]).is_err());
+        assert_eq!(owner.apply_pending(), 1);
+        assert!(pending.wait().unwrap().is_ok());
+        assert!(owner.manager().mmio_device(MmioAddress(0x1000)).is_some());
+
+        // Both copies are updated.
+        let failed = handle.register_mmio(range, device.clone());
+        owner.apply_pending();
+        assert!(failed.wait().unwrap().is_err());
+
+        // vCPU threads keep dispatching while the device map changes.
+        let stop = Arc::new(AtomicBool::new(false));
+        let vcpus: Vec<_> = (0..2)
+            .map(|_| {
+                let handle = handle.clone();
+                let stop = stop.clone();
+                thread::spawn(move || {
+                    let mut data = [0; 1];
+                    while !stop.load(Ordering::Relaxed) {
+                        handle.mmio_read(MmioAddress(0x1000), &mut data).unwrap();
+                    }
+                })
+            })
+            .collect();
+        let pio = PioRange::new(PioAddress(0x60), 1).unwrap();
+        for _ in 0..100 {
+            handle.register_pio(pio, device.clone());
+            handle.deregister_pio(PioAddress(0x60));
+            assert_eq!(owner.apply_pending(), 2);
+        }
+        stop.store(true, Ordering::Relaxed);
+        for vcpu in vcpus {
+            vcpu.join().unwrap();
+        }
+
+        handle.deregister_mmio(MmioAddress(0x1000));
+        owner.apply_pending();
+        assert!(handle.mmio_read(MmioAddress(0x1000), &mut [0]).is_err());
+        drop(owner);
+        assert!(handle.deregister_mmio(MmioAddress(0x1000)).wait().is_none());
+    }
+
+    #[test]
+    fn test_range_state_across_changes() {
+        let mut owner = IoManagerOwner::<MmioAddress>::new();
+        let handle = owner.handle();
+        let echo = MmioRange::new(MmioAddress(0x1000), 0x100).unwrap();
+        let failing = PioRange::new(PioAddress(0x60), 1).unwrap();
+        owner
+            .apply(
+                &(Box::new(move |io_mgr: &mut IoManager| {
+                    io_mgr.set_catch_panics(true);
+                    io_mgr
+                        .register_mmio(echo, Arc::new(EchoDevice::new()))
+                        .map_err(Error::Bus)?;
+                    io_mgr
+                        .register_pio(failing, Arc::new(FailingDevice::new("failing")))
+                        .map_err(Error::Bus)
+                }) as Mutation<MmioAddress>),
+            )
+            .unwrap();
+
+        // Disable a range, and get the other one quarantined.
+        handle.with(|io_mgr| io_mgr.set_range_enabled(echo.base(), false).unwrap());
+        assert!(handle.pio_read(failing.base(), &mut [0]).is_ok());
+        assert!(owner.manager().is_quarantined(failing.base()));
+
+        // The state of the ranges is kept by the following changes, whichever copy is active.
+        for _ in 0..2 {
+            let range = PioRange::new(PioAddress(0x70), 1).unwrap();
+            handle.register_pio(range, Arc::new(EchoDevice::new()));
+            handle.deregister_pio(range.base());
+            assert_eq!(owner.apply_pending(), 2);
+            assert!(handle.mmio_read(echo.base(), &mut [0]).is_err());
+            assert!(handle.pio_read(failing.base(), &mut [0]).is_err());
+            assert!(owner.manager().is_quarantined(failing.base()));
+            let stats = owner.manager().range_stats::<PioAddress>();
+            assert_eq!(stats.len(), 1);
+            assert_eq!(stats[0].1.accesses, 1);
+        }
+
+        // Changes of the state after a swap reach both copies as well.
+        owner.manager().release_quarantine(failing.base()).unwrap();
+        handle.with(|io_mgr| io_mgr.set_range_enabled(echo.base(), true).unwrap());
+        handle.register_pio(
+            PioRange::new(PioAddress(0x70), 1).unwrap(),
+            Arc::new(EchoDevice::new()),
+        );
+        assert_eq!(owner.apply_pending(), 1);
+        assert!(!owner.manager().is_quarantined(failing.base()));
+        assert!(handle.mmio_read(echo.base(), &mut [0]).is_ok());
+    }
+}
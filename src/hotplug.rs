@@ -0,0 +1,223 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Abstractions for notifying the guest about hotplugged and hot-unplugged devices.
+//!
+//! The device manager registers the resources of a hotplugged device and then asks a
+//! [`HotplugNotifier`](trait.HotplugNotifier.html) to let the guest know about it. Unplug is
+//! a two step process: the guest is first asked to release the device, and the resources are
+//! only unregistered after the guest acknowledges the request by ejecting the slot.
+
+use std::fmt::{Display, Formatter};
+use std::sync::Mutex;
+
+use crate::bus::{MmioAddress, MmioOffset, PioAddress, PioOffset};
+use crate::{DeviceMmio, DevicePio};
+
+/// Errors encountered during hotplug operations.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The slot number is not supported by the notifier.
+    InvalidSlot(u32),
+    /// An unplug request is already pending for the slot.
+    UnplugPending(u32),
+    /// All the slots of the notifier are in use.
+    NoFreeSlot,
+    /// The resources of a hotplugged device lack a required entry.
+    MissingResource(&'static str),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::InvalidSlot(slot) => write!(f, "invalid hotplug slot {}", slot),
+            Error::UnplugPending(slot) => write!(f, "unplug already pending for slot {}", slot),
+            Error::NoFreeSlot => write!(f, "no free hotplug slot"),
+            Error::MissingResource(kind) => write!(f, "missing {} resource", kind),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Represents the guest visible mechanism (e.g. ACPI PCI hotplug, or a virtio-mem style
+/// device) used to report hotplug events.
+pub trait HotplugNotifier {
+    /// Notify the guest that a device has been added to `slot`.
+    fn notify_add(&self, slot: u32) -> Result<(), Error>;
+
+    /// Ask the guest to release the device plugged into `slot`.
+    fn notify_remove(&self, slot: u32) -> Result<(), Error>;
+
+    /// Return (and forget about) the slots ejected by the guest since the last call.
+    fn take_ejected(&self) -> Vec<u32>;
+}
+
+/// Number of slots handled by an `AcpiPciHotplug` register block.
+pub const ACPI_PCI_HOTPLUG_SLOTS: u32 = 32;
+
+/// Size of the `AcpiPciHotplug` register block.
+pub const ACPI_PCI_HOTPLUG_SIZE: u64 = 0xc;
+
+// Offsets of the registers within the block. Each register is a bitmap of slots.
+const PCIU_OFFSET: u64 = 0x0;
+const PCID_OFFSET: u64 = 0x4;
+const B0EJ_OFFSET: u64 = 0x8;
+
+#[derive(Default)]
+struct AcpiPciHotplugState {
+    // Slots with a pending device check (added devices).
+    up: u32,
+    // Slots with a pending eject request (removed devices).
+    down: u32,
+    // Slots ejected by the guest.
+    ejected: u32,
+}
+
+/// ACPI PCI hotplug register block, compatible with the `PCIU`, `PCID`, and `B0EJ` registers
+/// used by the usual DSDT hotplug methods.
+///
+/// Reading `PCIU` or `PCID` returns and clears the bitmap of slots with pending device check
+/// or eject requests. Writing a bitmap to `B0EJ` acknowledges the ejection of the
+/// corresponding slots. The block can be registered on either the PIO or the MMIO bus.
+pub struct AcpiPciHotplug {
+    state: Mutex<AcpiPciHotplugState>,
+    notify_guest: Box<dyn Fn() + Send + Sync>,
+}
+
+impl AcpiPciHotplug {
+    /// Create a new register block. `notify_guest` is invoked to raise the notification
+    /// interrupt (usually the ACPI SCI) whenever a new event becomes pending.
+    pub fn new(notify_guest: Box<dyn Fn() + Send + Sync>) -> Self {
+        AcpiPciHotplug {
+            state: Mutex::new(AcpiPciHotplugState::default()),
+            notify_guest,
+        }
+    }
+
+    fn slot_bit(slot: u32) -> Result<u32, Error> {
+        if slot >= ACPI_PCI_HOTPLUG_SLOTS {
+            return Err(Error::InvalidSlot(slot));
+        }
+        Ok(1 << slot)
+    }
+
+    fn read(&self, offset: u64, data: &mut [u8]) {
+        let mut state = self.state.lock().unwrap();
+        let value = match offset {
+            PCIU_OFFSET => std::mem::take(&mut state.up),
+            PCID_OFFSET => std::mem::take(&mut state.down),
+            _ => 0,
+        };
+        for (idx, byte) in data.iter_mut().enumerate() {
+            *byte = value.to_le_bytes().get(idx).copied().unwrap_or(0);
+        }
+    }
+
+    fn write(&self, offset: u64, data: &[u8]) {
+        if offset != B0EJ_OFFSET {
+            return;
+        }
+        let mut bytes = [0u8; 4];
+        let len = data.len().min(4);
+        bytes[..len].copy_from_slice(&data[..len]);
+        self.state.lock().unwrap().ejected |= u32::from_le_bytes(bytes);
+    }
+}
+
+impl HotplugNotifier for AcpiPciHotplug {
+    fn notify_add(&self, slot: u32) -> Result<(), Error> {
+        let bit = Self::slot_bit(slot)?;
+        {
+            let mut state = self.state.lock().unwrap();
+            state.up |= bit;
+            state.ejected &= !bit;
+        }
+        (self.notify_guest)();
+        Ok(())
+    }
+
+    fn notify_remove(&self, slot: u32) -> Result<(), Error> {
+        let bit = Self::slot_bit(slot)?;
+        self.state.lock().unwrap().down |= bit;
+        (self.notify_guest)();
+        Ok(())
+    }
+
+    fn take_ejected(&self) -> Vec<u32> {
+        let ejected = std::mem::take(&mut self.state.lock().unwrap().ejected);
+        (0..ACPI_PCI_HOTPLUG_SLOTS)
+            .filter(|slot| ejected & (1 << slot) != 0)
+            .collect()
+    }
+}
+
+impl DevicePio for AcpiPciHotplug {
+    fn pio_read(&self, _base: PioAddress, offset: PioOffset, data: &mut [u8]) {
+        self.read(u64::from(offset), data);
+    }
+
+    fn pio_write(&self, _base: PioAddress, offset: PioOffset, data: &[u8]) {
+        self.write(u64::from(offset), data);
+    }
+}
+
+impl DeviceMmio for AcpiPciHotplug {
+    fn mmio_read(&self, _base: MmioAddress, offset: MmioOffset, data: &mut [u8]) {
+        self.read(offset.raw(), data);
+    }
+
+    fn mmio_write(&self, _base: MmioAddress, offset: MmioOffset, data: &[u8]) {
+        self.write(offset.raw(), data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use crate::bus::PioAddressValue;
+
+    #[test]
+    fn test_acpi_pci_hotplug() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let count_clone = count.clone();
+        let hotplug = AcpiPciHotplug::new(Box::new(move || {
+            count_clone.fetch_add(1, Ordering::SeqCst);
+        }));
+        let base = PioAddress(0xae00);
+
+        assert_eq!(
+            hotplug.notify_add(ACPI_PCI_HOTPLUG_SLOTS),
+            Err(Error::InvalidSlot(ACPI_PCI_HOTPLUG_SLOTS))
+        );
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+
+        hotplug.notify_add(3).unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+
+        let mut data = [0u8; 4];
+        hotplug.pio_read(base, PioOffset(PCIU_OFFSET as PioAddressValue), &mut data);
+        assert_eq!(u32::from_le_bytes(data), 1 << 3);
+        // The bitmap is cleared by the read.
+        hotplug.pio_read(base, PioOffset(PCIU_OFFSET as PioAddressValue), &mut data);
+        assert_eq!(u32::from_le_bytes(data), 0);
+
+        hotplug.notify_remove(3).unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        hotplug.mmio_read(MmioAddress(0), MmioOffset(PCID_OFFSET), &mut data);
+        assert_eq!(u32::from_le_bytes(data), 1 << 3);
+
+        assert!(hotplug.take_ejected().is_empty());
+        hotplug.pio_write(
+            base,
+            PioOffset(B0EJ_OFFSET as PioAddressValue),
+            &(1u32 << 3).to_le_bytes(),
+        );
+        assert_eq!(hotplug.take_ejected(), vec![3]);
+        assert!(hotplug.take_ejected().is_empty());
+    }
+}
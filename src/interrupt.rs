@@ -0,0 +1,564 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Legacy interrupt lines shared between devices.
+//!
+//! Devices drive their `Resource::LegacyIrq` through a
+//! [`LineInterrupt`](struct.LineInterrupt.html) handle, which they get from the
+//! [`IrqRouter`](struct.IrqRouter.html) of the platform (see `IoManager::line_interrupt`).
+//! The router keeps the routing table of the lines: level triggered lines can be shared,
+//! and are asserted for as long as any of their devices asserts them (wire-OR), while edge
+//! triggered lines belong to a single device. Level changes of the lines are forwarded to an
+//! [`IrqLineSink`](trait.IrqLineSink.html), such as the interrupt controller model or the
+//! hypervisor.
+//!
+//! The router also keeps statistics for each line (see `IrqRouter::stats`): how many times
+//! it was asserted, when, and for how long. A line asserted more often than allowed by the
+//! [`StormPolicy`](struct.StormPolicy.html) of the router is flagged as storming, which
+//! usually points to a guest driver which doesn't acknowledge the interrupts of its device.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::time::{Clock, HostClock};
+
+/// Errors encountered while setting up interrupt lines.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The line is edge triggered, and already used by another device.
+    EdgeShared(u32),
+    /// No interrupt router was set.
+    NoRouter,
+    /// The device has no legacy IRQ.
+    NoLegacyIrq,
+    /// The line is already used with a different trigger mode.
+    TriggerMismatch(u32),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::EdgeShared(irq) => write!(f, "edge triggered IRQ {} can't be shared", irq),
+            Error::NoRouter => write!(f, "no interrupt router"),
+            Error::NoLegacyIrq => write!(f, "no legacy IRQ in the device resources"),
+            Error::TriggerMismatch(irq) => {
+                write!(f, "IRQ {} is used with another trigger mode", irq)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Trigger mode of an interrupt line.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TriggerMode {
+    /// The interrupt is signalled by a rising edge of the line.
+    Edge,
+    /// The interrupt is signalled while the line is asserted.
+    Level,
+}
+
+/// Receives the level changes of the interrupt lines.
+///
+/// The changes are forwarded in the order they happen, without holding the locks of the
+/// router, so the sink can change the level of the lines itself.
+pub trait IrqLineSink: Send + Sync {
+    /// Set the level of the line `irq`.
+    fn set_level(&self, irq: u32, level: bool);
+}
+
+impl<F: Fn(u32, bool) + Send + Sync> IrqLineSink for F {
+    fn set_level(&self, irq: u32, level: bool) {
+        self(irq, level)
+    }
+}
+
+/// Number of assertions within a time window above which a line is considered storming.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct StormPolicy {
+    /// Length of the window, in nanoseconds.
+    pub window_ns: u64,
+    /// Maximum number of assertions of a line within a window.
+    pub max_assertions: u64,
+}
+
+impl Default for StormPolicy {
+    fn default() -> Self {
+        StormPolicy {
+            window_ns: 1_000_000_000,
+            max_assertions: 10_000,
+        }
+    }
+}
+
+/// Statistics of an interrupt line since the router was created.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct IrqStats {
+    /// Number of times the line went from deasserted to asserted.
+    pub assertions: u64,
+    /// Time of the last assertion, in nanoseconds.
+    pub last_assertion_ns: Option<u64>,
+    /// Total time the line was asserted, in nanoseconds. It doesn't include the current
+    /// assertion of the line, if any.
+    pub asserted_ns: u64,
+    /// Number of times the line started storming.
+    pub storms: u64,
+    /// Whether the line exceeded the storm policy in the current or the last window.
+    pub storming: bool,
+}
+
+// Statistics of a line, along with the state needed to update them.
+#[derive(Default)]
+struct LineMonitor {
+    stats: IrqStats,
+    asserted_since: Option<u64>,
+    window_start: u64,
+    window_assertions: u64,
+    // Whether the window before the current one exceeded the policy.
+    previous_storm: bool,
+}
+
+impl LineMonitor {
+    fn storming(&self, now: u64, policy: &StormPolicy) -> bool {
+        let elapsed = now.saturating_sub(self.window_start);
+        let storm = self.window_assertions > policy.max_assertions;
+        if elapsed < policy.window_ns {
+            storm || self.previous_storm
+        } else {
+            // The current window is over, so it's the last one until the next assertion.
+            storm && elapsed < policy.window_ns.saturating_mul(2)
+        }
+    }
+
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    fn assert(&mut self, irq: u32, now: u64, policy: &StormPolicy) {
+        let elapsed = now.saturating_sub(self.window_start);
+        if elapsed >= policy.window_ns {
+            self.previous_storm = self.storming(now, policy);
+            self.window_start = now;
+            self.window_assertions = 0;
+        }
+        let was_storming = self.storming(now, policy);
+        self.window_assertions += 1;
+        if !was_storming && self.storming(now, policy) {
+            self.stats.storms += 1;
+            #[cfg(feature = "tracing")]
+            tracing::warn!(irq, "interrupt storm");
+        }
+        self.stats.assertions += 1;
+        self.stats.last_assertion_ns = Some(now);
+        self.asserted_since = Some(now);
+    }
+
+    fn deassert(&mut self, now: u64) {
+        if let Some(since) = self.asserted_since.take() {
+            self.stats.asserted_ns += now.saturating_sub(since);
+        }
+    }
+}
+
+struct Monitor {
+    clock: Arc<dyn Clock>,
+    policy: StormPolicy,
+    lines: BTreeMap<u32, LineMonitor>,
+}
+
+struct LineState {
+    trigger: TriggerMode,
+    // Number of handles for the line.
+    users: usize,
+    // Number of handles which assert the line.
+    asserted: usize,
+}
+
+// Level changes of the lines waiting to be forwarded to the sink.
+#[derive(Default)]
+struct PendingChanges {
+    changes: VecDeque<(u32, bool)>,
+    // Set while a thread forwards the changes.
+    forwarding: bool,
+}
+
+struct RouterInner {
+    sink: Arc<dyn IrqLineSink>,
+    lines: Mutex<BTreeMap<u32, LineState>>,
+    // Locked after `lines` when both are needed.
+    monitor: Mutex<Monitor>,
+    // Locked after `lines` when both are needed.
+    pending: Mutex<PendingChanges>,
+}
+
+impl RouterInner {
+    // Set the level of the `handle` of `irq`, account for the change, and forward the change
+    // of the line level to the sink.
+    fn update(&self, irq: u32, handle: &AtomicBool, level: bool) {
+        let mut lines = self.lines.lock().unwrap();
+        // The handle changes with the lines locked, so concurrent changes of the same handle
+        // are accounted in the order they are made.
+        if handle.swap(level, Ordering::SeqCst) == level {
+            return;
+        }
+        if let Some(line) = lines.get_mut(&irq) {
+            let was_asserted = line.asserted > 0;
+            if level {
+                line.asserted += 1;
+            } else {
+                line.asserted -= 1;
+            }
+            if was_asserted != (line.asserted > 0) {
+                let mut monitor = self.monitor.lock().unwrap();
+                let now = monitor.clock.now();
+                let policy = monitor.policy;
+                let line = monitor.lines.entry(irq).or_default();
+                if level {
+                    line.assert(irq, now, &policy);
+                } else {
+                    line.deassert(now);
+                }
+                drop(monitor);
+                self.pending.lock().unwrap().changes.push_back((irq, level));
+            }
+        }
+        drop(lines);
+        self.forward();
+    }
+
+    // Forward the queued changes to the sink, unless another call already does it, e.g. a
+    // concurrent one, or one further up the stack when the sink changes a line itself.
+    fn forward(&self) {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.forwarding {
+            return;
+        }
+        pending.forwarding = true;
+        while let Some((irq, level)) = pending.changes.pop_front() {
+            drop(pending);
+            self.sink.set_level(irq, level);
+            pending = self.pending.lock().unwrap();
+        }
+        pending.forwarding = false;
+    }
+}
+
+/// Routing table of the legacy interrupt lines.
+#[derive(Clone)]
+pub struct IrqRouter {
+    inner: Arc<RouterInner>,
+}
+
+impl IrqRouter {
+    /// Create a router which forwards the line level changes to `sink`.
+    pub fn new(sink: Arc<dyn IrqLineSink>) -> Self {
+        Self::with_clock(sink, Arc::new(HostClock::new()))
+    }
+
+    /// Create a router which forwards the line level changes to `sink`, and times the
+    /// assertions of the lines with `clock`.
+    pub fn with_clock(sink: Arc<dyn IrqLineSink>, clock: Arc<dyn Clock>) -> Self {
+        IrqRouter {
+            inner: Arc::new(RouterInner {
+                sink,
+                lines: Mutex::new(BTreeMap::new()),
+                monitor: Mutex::new(Monitor {
+                    clock,
+                    policy: StormPolicy::default(),
+                    lines: BTreeMap::new(),
+                }),
+                pending: Mutex::new(PendingChanges::default()),
+            }),
+        }
+    }
+
+    /// Set the policy used to flag storming lines.
+    pub fn set_storm_policy(&self, policy: StormPolicy) {
+        self.inner.monitor.lock().unwrap().policy = policy;
+    }
+
+    /// Return the statistics of the line `irq`, if it was ever asserted.
+    pub fn stats(&self, irq: u32) -> Option<IrqStats> {
+        let monitor = self.inner.monitor.lock().unwrap();
+        let now = monitor.clock.now();
+        monitor
+            .lines
+            .get(&irq)
+            .map(|line| Self::line_stats(line, now, &monitor.policy))
+    }
+
+    /// Return the statistics of all lines which were ever asserted, sorted by line.
+    pub fn all_stats(&self) -> Vec<(u32, IrqStats)> {
+        let monitor = self.inner.monitor.lock().unwrap();
+        let now = monitor.clock.now();
+        monitor
+            .lines
+            .iter()
+            .map(|(irq, line)| (*irq, Self::line_stats(line, now, &monitor.policy)))
+            .collect()
+    }
+
+    /// Return the lines which are currently storming.
+    pub fn storming_lines(&self) -> Vec<u32> {
+        self.all_stats()
+            .into_iter()
+            .filter(|(_, stats)| stats.storming)
+            .map(|(irq, _)| irq)
+            .collect()
+    }
+
+    fn line_stats(line: &LineMonitor, now: u64, policy: &StormPolicy) -> IrqStats {
+        IrqStats {
+            storming: line.storming(now, policy),
+            ..line.stats
+        }
+    }
+
+    /// Return a new handle for the line `irq`. Level triggered lines can have any number of
+    /// handles, while edge triggered ones can only have one at a time.
+    pub fn line(&self, irq: u32, trigger: TriggerMode) -> Result<LineInterrupt, Error> {
+        let mut lines = self.inner.lines.lock().unwrap();
+        let line = lines.entry(irq).or_insert(LineState {
+            trigger,
+            users: 0,
+            asserted: 0,
+        });
+        if line.trigger != trigger {
+            return Err(Error::TriggerMismatch(irq));
+        }
+        if trigger == TriggerMode::Edge && line.users > 0 {
+            return Err(Error::EdgeShared(irq));
+        }
+        line.users += 1;
+        Ok(LineInterrupt {
+            irq,
+            trigger,
+            asserted: AtomicBool::new(false),
+            router: self.inner.clone(),
+        })
+    }
+
+    /// Return whether the line `irq` is asserted by any of its handles.
+    pub fn level(&self, irq: u32) -> bool {
+        let lines = self.inner.lines.lock().unwrap();
+        lines.get(&irq).is_some_and(|line| line.asserted > 0)
+    }
+
+    /// Return the number of handles of the line `irq`.
+    pub fn users(&self, irq: u32) -> usize {
+        let lines = self.inner.lines.lock().unwrap();
+        lines.get(&irq).map_or(0, |line| line.users)
+    }
+}
+
+/// Handle used by a device to drive an interrupt line. Dropping the handle deasserts it.
+pub struct LineInterrupt {
+    irq: u32,
+    trigger: TriggerMode,
+    asserted: AtomicBool,
+    router: Arc<RouterInner>,
+}
+
+impl LineInterrupt {
+    /// Return the number of the line.
+    pub fn irq(&self) -> u32 {
+        self.irq
+    }
+
+    /// Return the trigger mode of the line.
+    pub fn trigger(&self) -> TriggerMode {
+        self.trigger
+    }
+
+    /// Assert the line. The line stays asserted until this handle deasserts it, or while any
+    /// other device sharing it asserts it.
+    pub fn assert(&self) {
+        if !self.asserted.load(Ordering::SeqCst) {
+            self.router.update(self.irq, &self.asserted, true);
+        }
+    }
+
+    /// Stop asserting the line.
+    pub fn deassert(&self) {
+        if self.asserted.load(Ordering::SeqCst) {
+            self.router.update(self.irq, &self.asserted, false);
+        }
+    }
+
+    /// Assert and immediately deassert the line, as done for edge triggered interrupts.
+    pub fn pulse(&self) {
+        self.assert();
+        self.deassert();
+    }
+
+    /// Return whether this handle asserts the line.
+    pub fn is_asserted(&self) -> bool {
+        self.asserted.load(Ordering::SeqCst)
+    }
+
+    /// Return the current level of the line, which also accounts for the other devices
+    /// sharing it.
+    pub fn level(&self) -> bool {
+        let lines = self.router.lines.lock().unwrap();
+        lines.get(&self.irq).is_some_and(|line| line.asserted > 0)
+    }
+}
+
+impl Drop for LineInterrupt {
+    fn drop(&mut self) {
+        self.deassert();
+        let mut lines = self.router.lines.lock().unwrap();
+        if let Some(line) = lines.get_mut(&self.irq) {
+            line.users -= 1;
+            if line.users == 0 {
+                lines.remove(&self.irq);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::time::ManualClock;
+
+    #[test]
+    fn test_shared_level_line() {
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let sink_changes = changes.clone();
+        let router = IrqRouter::new(Arc::new(move |irq, level| {
+            sink_changes.lock().unwrap().push((irq, level))
+        }));
+
+        let first = router.line(10, TriggerMode::Level).unwrap();
+        let second = router.line(10, TriggerMode::Level).unwrap();
+        assert_eq!(router.users(10), 2);
+        assert_eq!(
+            router.line(10, TriggerMode::Edge).err(),
+            Some(Error::TriggerMismatch(10))
+        );
+
+        first.assert();
+        second.assert();
+        first.deassert();
+        // The line stays asserted while the second device asserts it.
+        assert!(first.level());
+        assert!(!first.is_asserted());
+        drop(second);
+        assert!(!router.level(10));
+        assert_eq!(*changes.lock().unwrap(), vec![(10, true), (10, false)]);
+
+        first.pulse();
+        assert_eq!(changes.lock().unwrap().len(), 4);
+        drop(first);
+        assert_eq!(router.users(10), 0);
+    }
+
+    #[test]
+    fn test_concurrent_changes() {
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let sink_changes = changes.clone();
+        let router_slot: Arc<Mutex<Option<IrqRouter>>> = Arc::new(Mutex::new(None));
+        let sink_router = router_slot.clone();
+        let router = IrqRouter::new(Arc::new(move |irq, level| {
+            // The sink can use the router, e.g. to mirror the line on another one.
+            let router = sink_router.lock().unwrap().clone().unwrap();
+            let _ = router.level(irq);
+            sink_changes.lock().unwrap().push((irq, level));
+        }));
+        *router_slot.lock().unwrap() = Some(router.clone());
+
+        let line = Arc::new(router.line(5, TriggerMode::Level).unwrap());
+        let threads: Vec<_> = (0..4)
+            .map(|idx| {
+                let line = line.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..500 {
+                        if idx % 2 == 0 {
+                            line.assert();
+                        } else {
+                            line.deassert();
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        line.deassert();
+        assert!(!router.level(5));
+
+        // The sink got the changes in order, so the line is left deasserted.
+        let changes = changes.lock().unwrap();
+        assert!(changes.windows(2).all(|pair| pair[0].1 != pair[1].1));
+        assert_eq!(changes.last().map(|change| change.1), Some(false));
+        *router_slot.lock().unwrap() = None;
+    }
+
+    #[test]
+    fn test_edge_line() {
+        let router = IrqRouter::new(Arc::new(|_, _| {}));
+        let line = router.line(4, TriggerMode::Edge).unwrap();
+        assert_eq!(
+            router.line(4, TriggerMode::Edge).err(),
+            Some(Error::EdgeShared(4))
+        );
+        drop(line);
+        assert!(router.line(4, TriggerMode::Edge).is_ok());
+    }
+
+    #[test]
+    fn test_irq_stats() {
+        let clock = Arc::new(ManualClock::new());
+        let router = IrqRouter::with_clock(Arc::new(|_, _| {}), clock.clone());
+        router.set_storm_policy(StormPolicy {
+            window_ns: 1000,
+            max_assertions: 3,
+        });
+        let first = router.line(9, TriggerMode::Level).unwrap();
+        let second = router.line(9, TriggerMode::Level).unwrap();
+        assert_eq!(router.stats(9), None);
+
+        clock.advance(10);
+        first.assert();
+        clock.advance(20);
+        // Asserting a line which is already asserted isn't accounted.
+        second.assert();
+        first.deassert();
+        clock.advance(30);
+        second.deassert();
+        assert_eq!(
+            router.stats(9).unwrap(),
+            IrqStats {
+                assertions: 1,
+                last_assertion_ns: Some(10),
+                asserted_ns: 50,
+                storms: 0,
+                storming: false,
+            }
+        );
+
+        for _ in 0..2 {
+            first.pulse();
+        }
+        assert!(router.storming_lines().is_empty());
+        first.pulse();
+        let stats = router.stats(9).unwrap();
+        assert_eq!((stats.assertions, stats.storms), (4, 1));
+        assert_eq!(router.storming_lines(), [9]);
+
+        // The line stays flagged during the window following the storm.
+        clock.advance(1000);
+        first.pulse();
+        assert!(router.stats(9).unwrap().storming);
+        clock.advance(1000);
+        assert!(!router.stats(9).unwrap().storming);
+
+        // The statistics outlive the handles of the line.
+        drop(first);
+        drop(second);
+        assert_eq!(router.all_stats()[0].1.assertions, 5);
+    }
+}
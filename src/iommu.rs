@@ -0,0 +1,95 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! DMA address translation for emulated IOMMUs.
+//!
+//! A vIOMMU is split in two parts. The register front-end (e.g. the
+//! [`Vtd`](../devices/vtd/struct.Vtd.html) remapping registers) is the device the guest
+//! programs: it enables translation, points the IOMMU at the translation structures in
+//! guest memory, and requests invalidations. The front-end forwards these requests to an
+//! [`IoTranslator`](trait.IoTranslator.html), implemented by the VMM, which walks the
+//! structures in guest memory, caches the translations, and translates the DMA addresses of
+//! the devices placed behind the IOMMU. Failed translations are reported back to the
+//! front-end, which records them in its fault registers and notifies the guest.
+
+use std::fmt::{Display, Formatter};
+
+/// Cached translations dropped by an invalidation.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Invalidation {
+    /// All the translations.
+    All,
+    /// The translations of the devices attached to the domain.
+    Domain(u16),
+    /// The translations of the device with the requester ID.
+    Device(u16),
+    /// The translations of the `size` bytes at `iova` in the domain.
+    Range {
+        /// The domain of the translations.
+        domain: u16,
+        /// The first address of the range.
+        iova: u64,
+        /// The size of the range.
+        size: u64,
+    },
+}
+
+/// Why a DMA address couldn't be translated.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FaultReason {
+    /// The root entry of the bus of the device isn't present.
+    RootNotPresent,
+    /// The context entry of the device isn't present.
+    ContextNotPresent,
+    /// The address is wider than the address width of the domain.
+    AddressTooWide,
+    /// The page isn't mapped for reads.
+    ReadDenied,
+    /// The page isn't mapped for writes.
+    WriteDenied,
+}
+
+/// A failed translation.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Fault {
+    /// Requester ID of the device performing the DMA.
+    pub requester: u16,
+    /// The address accessed by the device.
+    pub iova: u64,
+    /// Whether the access is a write.
+    pub write: bool,
+    /// Why the translation failed.
+    pub reason: FaultReason,
+}
+
+impl Display for Fault {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "DMA {} of {:#x} by {:04x} failed: {:?}",
+            if self.write { "write" } else { "read" },
+            self.iova,
+            self.requester,
+            self.reason
+        )
+    }
+}
+
+impl std::error::Error for Fault {}
+
+/// Translates the DMA addresses of the devices behind an emulated IOMMU.
+pub trait IoTranslator: Send + Sync {
+    /// Enable or disable translation. While disabled, DMA addresses aren't translated.
+    fn set_enabled(&self, enabled: bool);
+
+    /// Use the translation structures at the guest physical address `root` (e.g. the root
+    /// table of VT-d).
+    fn set_root(&self, root: u64);
+
+    /// Drop the cached translations in `scope`.
+    fn invalidate(&self, scope: Invalidation);
+
+    /// Return the guest physical address accessed by the DMA of the device `requester` to
+    /// `iova`.
+    fn translate(&self, requester: u16, iova: u64, write: bool) -> Result<u64, Fault>;
+}
@@ -0,0 +1,329 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Recommended address maps for common machine types.
+//!
+//! Guests expect the legacy devices of a platform at well known addresses, and the
+//! remaining space is split in windows from which the VMM allocates the resources of the
+//! other devices. [`x86_legacy`](fn.x86_legacy.html) and
+//! [`aarch64_virt`](fn.aarch64_virt.html) return those maps, and the constants they are
+//! built from are available in the [`x86`](x86/index.html) and
+//! [`aarch64`](aarch64/index.html) modules.
+
+use crate::builder::IoManagerBuilder;
+use crate::bus::PioAddressValue;
+use crate::resources::Resource;
+
+/// Constants of the x86 legacy I/O map.
+pub mod x86 {
+    use crate::bus::PioAddressValue;
+
+    pub use crate::devices::debugcon::DEBUGCON_PORT;
+    pub use crate::devices::hpet::{HPET_DEFAULT_BASE, HPET_SIZE};
+    pub use crate::devices::lapic::{LAPIC_DEFAULT_BASE, LAPIC_SIZE};
+    pub use crate::devices::pit::{PIT_PORT, PIT_PORT_SIZE};
+    pub use crate::devices::port92::{PORT92_PORT, PORT92_PORT_SIZE};
+    pub use crate::devices::post::{POST_PORT, POST_PORT_SIZE};
+
+    /// Master 8259 PIC ports.
+    pub const PIC_MASTER_PORT: PioAddressValue = 0x20;
+    /// Slave 8259 PIC ports.
+    pub const PIC_SLAVE_PORT: PioAddressValue = 0xa0;
+    /// Size of the ports of each PIC.
+    pub const PIC_PORT_SIZE: PioAddressValue = 0x2;
+    /// i8042 data port; the command port is at offset 4.
+    pub const I8042_PORT: PioAddressValue = 0x60;
+    /// Size of the i8042 ports.
+    pub const I8042_PORT_SIZE: PioAddressValue = 0x5;
+    /// CMOS/RTC index and data ports.
+    pub const CMOS_PORT: PioAddressValue = 0x70;
+    /// Size of the CMOS ports.
+    pub const CMOS_PORT_SIZE: PioAddressValue = 0x2;
+    /// Ports of the four legacy serial ports, COM1 first.
+    pub const COM_PORTS: [PioAddressValue; 4] = [0x3f8, 0x2f8, 0x3e8, 0x2e8];
+    /// Size of the ports of each serial port.
+    pub const COM_PORT_SIZE: PioAddressValue = 0x8;
+    /// IRQs of the four legacy serial ports, COM1 first.
+    pub const COM_IRQS: [u32; 4] = [4, 3, 4, 3];
+    /// PCI configuration mechanism #1 ports.
+    pub const PCI_CONFIG_PORT: PioAddressValue = 0xcf8;
+    /// Size of the PCI configuration ports.
+    pub const PCI_CONFIG_PORT_SIZE: PioAddressValue = 0x8;
+
+    /// IRQ of the PIT.
+    pub const PIT_IRQ: u32 = 0;
+    /// IRQ of the i8042 keyboard.
+    pub const KEYBOARD_IRQ: u32 = 1;
+    /// IRQ used to cascade the slave PIC.
+    pub const CASCADE_IRQ: u32 = 2;
+    /// IRQ of the RTC.
+    pub const RTC_IRQ: u32 = 8;
+
+    /// First port of the window for the I/O BARs of PCI devices.
+    pub const PCI_PIO_BASE: PioAddressValue = 0x1000;
+    /// Size of the window for the I/O BARs of PCI devices.
+    pub const PCI_PIO_SIZE: PioAddressValue = 0xf000;
+    /// Base of the MMIO hole below 4 GiB.
+    pub const MMIO_32_BASE: u64 = 0xc000_0000;
+    /// Size of the MMIO hole below 4 GiB.
+    pub const MMIO_32_SIZE: u64 = 0x4000_0000;
+    /// Base of the PCIe ECAM region.
+    pub const ECAM_BASE: u64 = 0xe000_0000;
+    /// Size of the PCIe ECAM region, for 256 buses.
+    pub const ECAM_SIZE: u64 = 0x1000_0000;
+    /// Base of the I/O APIC registers.
+    pub const IOAPIC_BASE: u64 = 0xfec0_0000;
+    /// Size of the I/O APIC registers.
+    pub const IOAPIC_SIZE: u64 = 0x1000;
+    /// Base of the identity map page KVM needs on Intel hosts.
+    pub const KVM_IDENTITY_MAP_BASE: u64 = 0xfffb_c000;
+    /// Size of the KVM identity map.
+    pub const KVM_IDENTITY_MAP_SIZE: u64 = 0x1000;
+    /// Base of the pages KVM uses for the TSS on Intel hosts.
+    pub const KVM_TSS_BASE: u64 = 0xfffb_d000;
+    /// Size of the KVM TSS pages.
+    pub const KVM_TSS_SIZE: u64 = 0x3000;
+}
+
+/// Constants of the aarch64 `virt` machine map.
+pub mod aarch64 {
+    /// Base of the GIC distributor.
+    pub const GIC_DIST_BASE: u64 = 0x0800_0000;
+    /// Size of the GIC distributor.
+    pub const GIC_DIST_SIZE: u64 = 0x1_0000;
+    /// Base of the GICv3 redistributors.
+    pub const GIC_REDIST_BASE: u64 = 0x080a_0000;
+    /// Size of the GICv3 redistributors, enough for 123 vCPUs.
+    pub const GIC_REDIST_SIZE: u64 = 0xf6_0000;
+    /// Base of the PL011 UART.
+    pub const UART_BASE: u64 = 0x0900_0000;
+    /// Base of the PL031 RTC.
+    pub const RTC_BASE: u64 = 0x0901_0000;
+    /// Base of the PL061 GPIO controller.
+    pub const GPIO_BASE: u64 = 0x0903_0000;
+    /// Size of the registers of each PrimeCell device.
+    pub const PRIMECELL_SIZE: u64 = 0x1000;
+    /// Base of the window for virtio-mmio transports.
+    pub const VIRTIO_MMIO_BASE: u64 = 0x0a00_0000;
+    /// Size of the window for virtio-mmio transports, for 32 devices.
+    pub const VIRTIO_MMIO_SIZE: u64 = 0x4000;
+    /// Base of the window for the memory BARs of PCI devices.
+    pub const PCI_MMIO_BASE: u64 = 0x1000_0000;
+    /// Size of the window for the memory BARs of PCI devices.
+    pub const PCI_MMIO_SIZE: u64 = 0x2eff_0000;
+    /// Base of the PCIe ECAM region.
+    pub const ECAM_BASE: u64 = 0x3f00_0000;
+    /// Size of the PCIe ECAM region, for 16 buses.
+    pub const ECAM_SIZE: u64 = 0x100_0000;
+    /// Start of the guest RAM.
+    pub const RAM_BASE: u64 = 0x4000_0000;
+
+    /// GIC SPI of the UART.
+    pub const UART_IRQ: u32 = 1;
+    /// GIC SPI of the RTC.
+    pub const RTC_IRQ: u32 = 2;
+    /// GIC SPI of the GPIO controller.
+    pub const GPIO_IRQ: u32 = 7;
+}
+
+/// Range or IRQ used by a platform device at a fixed location.
+#[derive(Clone)]
+pub struct FixedResource {
+    /// Name of the device.
+    pub name: &'static str,
+    /// The resource used by the device.
+    pub resource: Resource,
+}
+
+/// Address map of a machine type.
+#[derive(Clone, Default)]
+pub struct MachineLayout {
+    /// PIO windows, as `(base, size)` pairs.
+    pub pio_windows: Vec<(PioAddressValue, PioAddressValue)>,
+    /// MMIO windows below the guest RAM, as `(base, size)` pairs. Windows above the RAM
+    /// depend on its size, so they are left to the VMM.
+    pub mmio_windows: Vec<(u64, u64)>,
+    /// Resources of the platform devices at fixed locations.
+    pub fixed: Vec<FixedResource>,
+    /// Ranges used by the platform without any device, where no device can be placed.
+    pub reservations: Vec<FixedResource>,
+}
+
+impl MachineLayout {
+    /// Return the resource of the device called `name`.
+    pub fn find(&self, name: &str) -> Option<&Resource> {
+        self.fixed
+            .iter()
+            .find(|fixed| fixed.name == name)
+            .map(|fixed| &fixed.resource)
+    }
+
+    /// Return the resources which the allocator must not hand out to other devices.
+    pub fn reserved(&self) -> Vec<Resource> {
+        self.fixed
+            .iter()
+            .chain(self.reservations.iter())
+            .map(|fixed| fixed.resource.clone())
+            .collect()
+    }
+
+    /// Return a builder which only accepts ranges within the windows of the layout, and
+    /// outside of its reservations.
+    pub fn builder(&self) -> IoManagerBuilder {
+        let builder = self
+            .pio_windows
+            .iter()
+            .fold(IoManagerBuilder::new(), |b, &(base, size)| {
+                b.pio_window(base, size)
+            });
+        let builder = self
+            .mmio_windows
+            .iter()
+            .fold(builder, |b, &(base, size)| b.mmio_window(base, size));
+        self.reservations
+            .iter()
+            .fold(builder, |b, reserved| match reserved.resource {
+                Resource::PioAddressRange { base, size } => {
+                    b.reserve_pio(reserved.name, base, size)
+                }
+                Resource::MmioAddressRange { base, size } => {
+                    b.reserve_mmio(reserved.name, base, size)
+                }
+                _ => b,
+            })
+    }
+
+    fn add(&mut self, name: &'static str, resource: Resource) {
+        self.fixed.push(FixedResource { name, resource });
+    }
+
+    fn add_pio(&mut self, name: &'static str, base: PioAddressValue, size: PioAddressValue) {
+        self.add(name, Resource::PioAddressRange { base, size });
+    }
+
+    fn add_mmio(&mut self, name: &'static str, base: u64, size: u64) {
+        self.add(name, Resource::MmioAddressRange { base, size });
+    }
+
+    fn reserve_mmio(&mut self, name: &'static str, base: u64, size: u64) {
+        self.reservations.push(FixedResource {
+            name,
+            resource: Resource::MmioAddressRange { base, size },
+        });
+    }
+}
+
+/// Return the legacy I/O map of a PC, with a PCIe host bridge.
+pub fn x86_legacy() -> MachineLayout {
+    use self::x86::*;
+
+    let mut layout = MachineLayout {
+        pio_windows: vec![(0, PCI_PIO_BASE), (PCI_PIO_BASE, PCI_PIO_SIZE)],
+        mmio_windows: vec![(MMIO_32_BASE, MMIO_32_SIZE)],
+        fixed: Vec::new(),
+        reservations: Vec::new(),
+    };
+    layout.add_pio("pic-master", PIC_MASTER_PORT, PIC_PORT_SIZE);
+    layout.add_pio("pit", PIT_PORT, PIT_PORT_SIZE);
+    layout.add_pio("i8042", I8042_PORT, I8042_PORT_SIZE);
+    layout.add_pio("cmos", CMOS_PORT, CMOS_PORT_SIZE);
+    layout.add_pio("post", POST_PORT, POST_PORT_SIZE);
+    layout.add_pio("port92", PORT92_PORT, PORT92_PORT_SIZE);
+    layout.add_pio("pic-slave", PIC_SLAVE_PORT, PIC_PORT_SIZE);
+    layout.add_pio("debugcon", DEBUGCON_PORT, 1);
+    layout.add_pio("com1", COM_PORTS[0], COM_PORT_SIZE);
+    layout.add_pio("com2", COM_PORTS[1], COM_PORT_SIZE);
+    layout.add_pio("com3", COM_PORTS[2], COM_PORT_SIZE);
+    layout.add_pio("com4", COM_PORTS[3], COM_PORT_SIZE);
+    layout.add_pio("pci-config", PCI_CONFIG_PORT, PCI_CONFIG_PORT_SIZE);
+    layout.add_mmio("ecam", ECAM_BASE, ECAM_SIZE);
+    layout.add_mmio("ioapic", IOAPIC_BASE, IOAPIC_SIZE);
+    layout.add_mmio("hpet", HPET_DEFAULT_BASE, HPET_SIZE);
+    layout.add_mmio("lapic", LAPIC_DEFAULT_BASE, LAPIC_SIZE);
+    layout.reserve_mmio(
+        "kvm-identity-map",
+        KVM_IDENTITY_MAP_BASE,
+        KVM_IDENTITY_MAP_SIZE,
+    );
+    layout.reserve_mmio("kvm-tss", KVM_TSS_BASE, KVM_TSS_SIZE);
+    layout.add("pit-irq", Resource::LegacyIrq(PIT_IRQ));
+    layout.add("keyboard-irq", Resource::LegacyIrq(KEYBOARD_IRQ));
+    layout.add("cascade-irq", Resource::LegacyIrq(CASCADE_IRQ));
+    layout.add("com1-irq", Resource::LegacyIrq(COM_IRQS[0]));
+    layout.add("com2-irq", Resource::LegacyIrq(COM_IRQS[1]));
+    layout.add("rtc-irq", Resource::LegacyIrq(RTC_IRQ));
+    layout
+}
+
+/// Return the map of the aarch64 `virt` machine. IRQs are GIC SPI numbers. The virtio-mmio
+/// and PCI windows are left for allocation, so VMMs should pick their ranges from
+/// `VIRTIO_MMIO_BASE` and `PCI_MMIO_BASE`.
+pub fn aarch64_virt() -> MachineLayout {
+    use self::aarch64::*;
+
+    let mut layout = MachineLayout {
+        pio_windows: Vec::new(),
+        mmio_windows: vec![(GIC_DIST_BASE, RAM_BASE - GIC_DIST_BASE)],
+        fixed: Vec::new(),
+        reservations: Vec::new(),
+    };
+    layout.add_mmio("gic-dist", GIC_DIST_BASE, GIC_DIST_SIZE);
+    layout.add_mmio("gic-redist", GIC_REDIST_BASE, GIC_REDIST_SIZE);
+    layout.add_mmio("uart", UART_BASE, PRIMECELL_SIZE);
+    layout.add_mmio("rtc", RTC_BASE, PRIMECELL_SIZE);
+    layout.add_mmio("gpio", GPIO_BASE, PRIMECELL_SIZE);
+    layout.add_mmio("ecam", ECAM_BASE, ECAM_SIZE);
+    layout.add("uart-irq", Resource::LegacyIrq(UART_IRQ));
+    layout.add("rtc-irq", Resource::LegacyIrq(RTC_IRQ));
+    layout.add("gpio-irq", Resource::LegacyIrq(GPIO_IRQ));
+    layout
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::testing::EchoDevice;
+
+    #[test]
+    fn test_layouts_are_consistent() {
+        for layout in [x86_legacy(), aarch64_virt()].iter() {
+            // All the fixed ranges are disjoint and within the windows of the layout, and no
+            // IRQ is used twice.
+            let dev = Arc::new(EchoDevice::new());
+            let builder = layout.fixed.iter().fold(layout.builder(), |b, fixed| {
+                b.device(
+                    fixed.name,
+                    dev.clone(),
+                    std::slice::from_ref(&fixed.resource),
+                )
+            });
+            builder.validate().unwrap();
+        }
+
+        let layout = x86_legacy();
+        assert!(matches!(
+            layout.find("com1"),
+            Some(Resource::PioAddressRange {
+                base: 0x3f8,
+                size: 8
+            })
+        ));
+        assert!(matches!(
+            layout.find("post"),
+            Some(Resource::PioAddressRange {
+                base: x86::POST_PORT,
+                size: x86::POST_PORT_SIZE
+            })
+        ));
+        assert!(layout.find("uart").is_none());
+        assert_eq!(
+            layout.reserved().len(),
+            layout.fixed.len() + layout.reservations.len()
+        );
+        let io_mgr = layout.builder().build().unwrap();
+        assert_eq!(io_mgr.layout().mmio_reserved.len(), 2);
+    }
+}
@@ -3,23 +3,280 @@
 
 //! rust-vmm device model.
 
+// Lets the code generated by the derive macros refer to this crate as `vm_device`.
+#[cfg(feature = "derive")]
+extern crate self as vm_device;
+
+pub mod builder;
 pub mod bus;
+pub mod cache;
+pub mod composite;
+pub mod console;
+pub mod coverage;
+pub mod cpuid;
+pub mod dependency;
 pub mod device_manager;
+pub mod devices;
+pub mod dirty;
+pub mod events;
+pub mod exit;
+pub mod fuzz;
+pub mod handle;
+pub mod hotplug;
+pub mod interrupt;
+pub mod iommu;
+pub mod layout;
+pub mod lifecycle;
+pub mod machine;
+pub mod mappable;
+pub mod metrics;
+pub mod migration;
+pub mod msi;
+pub mod nested;
+pub mod pci;
+pub mod per_cpu;
+pub mod poison;
+pub mod polled;
+pub mod quiesce;
+pub mod rate_limit;
+pub mod registers;
+pub mod remote;
+pub mod replay;
 pub mod resources;
+#[cfg(feature = "vm-memory")]
+pub mod ring;
+pub mod smbios;
+pub mod snapshot;
+#[cfg(feature = "event-manager")]
+pub mod subscriber;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod time;
+pub mod transaction;
+pub mod typed;
+#[cfg(feature = "vfio")]
+pub mod vfio;
 
+use std::any::Any;
 use std::ops::Deref;
 use std::sync::{Arc, Mutex};
 
-use bus::{MmioAddress, PioAddress, PioAddressValue};
+#[cfg(feature = "derive")]
+pub use vm_device_derive::{DeviceEnum, MmioDevice, PioDevice};
+
+use poison::{DeviceError, PoisonPolicy};
+
+use bus::{
+    MmioAddress, MmioOffset, MsrAddress, MsrAddressValue, PioAddress, PioOffset, SysRegAddress,
+    SysRegAddressValue,
+};
+
+/// Describes the context of an access: who performed it, and how.
+///
+/// Devices which behave differently depending on the initiator (such as the local APIC or
+/// the GIC redistributors, which are banked per vCPU) can implement the `*_with` variants of
+/// the device trait methods to receive it. The other devices can ignore it, as the default
+/// implementations of those methods forward to the regular ones.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct IoAccess {
+    /// Index of the vCPU which performed the access, if known.
+    pub vcpu_id: Option<u32>,
+    /// Architecture specific privilege level of the access (CPL on x86, EL on aarch64).
+    pub privilege: u8,
+    /// Width of the access requested by the guest, in bytes.
+    pub width: usize,
+    /// The access is performed by the secure world.
+    pub secure: bool,
+}
+
+impl IoAccess {
+    /// Create the context of a `width` bytes access performed by the vCPU with index
+    /// `vcpu_id`.
+    pub fn new(vcpu_id: u32, width: usize) -> Self {
+        IoAccess {
+            vcpu_id: Some(vcpu_id),
+            width,
+            ..Default::default()
+        }
+    }
+}
 
 pub trait DevicePio {
-    fn pio_read(&self, base: PioAddress, offset: PioAddressValue, data: &mut [u8]);
-    fn pio_write(&self, base: PioAddress, offset: PioAddressValue, data: &[u8]);
+    fn pio_read(&self, base: PioAddress, offset: PioOffset, data: &mut [u8]);
+    fn pio_write(&self, base: PioAddress, offset: PioOffset, data: &[u8]);
+
+    /// Handle a read which carries its access context.
+    fn pio_read_with(
+        &self,
+        _access: IoAccess,
+        base: PioAddress,
+        offset: PioOffset,
+        data: &mut [u8],
+    ) {
+        self.pio_read(base, offset, data)
+    }
+
+    /// Handle a write which carries its access context.
+    fn pio_write_with(&self, _access: IoAccess, base: PioAddress, offset: PioOffset, data: &[u8]) {
+        self.pio_write(base, offset, data)
+    }
+
+    /// Handle a read, reporting whether the device could serve it. Devices which can't
+    /// fail don't have to implement it.
+    fn try_pio_read(
+        &self,
+        access: IoAccess,
+        base: PioAddress,
+        offset: PioOffset,
+        data: &mut [u8],
+    ) -> Result<(), DeviceError> {
+        self.pio_read_with(access, base, offset, data);
+        Ok(())
+    }
+
+    /// Handle a write, reporting whether the device could serve it.
+    fn try_pio_write(
+        &self,
+        access: IoAccess,
+        base: PioAddress,
+        offset: PioOffset,
+        data: &[u8],
+    ) -> Result<(), DeviceError> {
+        self.pio_write_with(access, base, offset, data);
+        Ok(())
+    }
+
+    /// Return the device as `Any`, so the VMM can recover its concrete type once registered
+    /// (e.g. to inject input into a serial port found by address). Devices opt in by
+    /// returning `Some(self)`. Devices behind a `Mutex` can't, and are retrieved with
+    /// `IoManager::device_as` instead.
+    fn as_any(&self) -> Option<&dyn Any> {
+        None
+    }
 }
 
 pub trait DeviceMmio {
-    fn mmio_read(&self, base: MmioAddress, offset: u64, data: &mut [u8]);
-    fn mmio_write(&self, base: MmioAddress, offset: u64, data: &[u8]);
+    fn mmio_read(&self, base: MmioAddress, offset: MmioOffset, data: &mut [u8]);
+    fn mmio_write(&self, base: MmioAddress, offset: MmioOffset, data: &[u8]);
+
+    /// Handle a read which carries its access context.
+    fn mmio_read_with(
+        &self,
+        _access: IoAccess,
+        base: MmioAddress,
+        offset: MmioOffset,
+        data: &mut [u8],
+    ) {
+        self.mmio_read(base, offset, data)
+    }
+
+    /// Handle a write which carries its access context.
+    fn mmio_write_with(
+        &self,
+        _access: IoAccess,
+        base: MmioAddress,
+        offset: MmioOffset,
+        data: &[u8],
+    ) {
+        self.mmio_write(base, offset, data)
+    }
+
+    /// Handle a read, reporting whether the device could serve it. Devices which can't
+    /// fail don't have to implement it.
+    fn try_mmio_read(
+        &self,
+        access: IoAccess,
+        base: MmioAddress,
+        offset: MmioOffset,
+        data: &mut [u8],
+    ) -> Result<(), DeviceError> {
+        self.mmio_read_with(access, base, offset, data);
+        Ok(())
+    }
+
+    /// Handle a write, reporting whether the device could serve it.
+    fn try_mmio_write(
+        &self,
+        access: IoAccess,
+        base: MmioAddress,
+        offset: MmioOffset,
+        data: &[u8],
+    ) -> Result<(), DeviceError> {
+        self.mmio_write_with(access, base, offset, data);
+        Ok(())
+    }
+
+    /// Return the zero-copy interface of the device, if it has one. Devices behind a `Mutex`
+    /// don't, since the window they'd expose would outlive the lock.
+    fn as_zero_copy(&self) -> Option<&dyn DeviceMmioZeroCopy> {
+        None
+    }
+
+    /// Return the device as `Any`, so the VMM can recover its concrete type once
+    /// registered. Devices opt in by returning `Some(self)`. Devices behind a `Mutex` can't,
+    /// and are retrieved with `IoManager::device_as` instead.
+    fn as_any(&self) -> Option<&dyn Any> {
+        None
+    }
+}
+
+/// Devices which can expose their contents directly, so that large reads (e.g. of firmware
+/// blobs) don't have to be copied through an intermediate buffer. Such devices also return
+/// `Some(self)` from `DeviceMmio::as_zero_copy`.
+pub trait DeviceMmioZeroCopy {
+    /// Return the `len` bytes at `offset`, or `None` if the window can't be borrowed (in
+    /// which case the access goes through `DeviceMmio::mmio_read`).
+    fn mmio_slice(&self, base: MmioAddress, offset: MmioOffset, len: usize) -> Option<&[u8]>;
+}
+
+/// Devices handling trapped system register (aarch64) or CSR (RISC-V) accesses. Registers are
+/// always accessed as a whole, so values are passed around as `u64`s.
+pub trait DeviceSysReg {
+    fn sysreg_read(&self, base: SysRegAddress, offset: SysRegAddressValue) -> u64;
+    fn sysreg_write(&self, base: SysRegAddress, offset: SysRegAddressValue, value: u64);
+
+    /// Handle a read which carries its access context.
+    fn sysreg_read_with(
+        &self,
+        _access: IoAccess,
+        base: SysRegAddress,
+        offset: SysRegAddressValue,
+    ) -> u64 {
+        self.sysreg_read(base, offset)
+    }
+
+    /// Handle a write which carries its access context.
+    fn sysreg_write_with(
+        &self,
+        _access: IoAccess,
+        base: SysRegAddress,
+        offset: SysRegAddressValue,
+        value: u64,
+    ) {
+        self.sysreg_write(base, offset, value)
+    }
+}
+
+/// Devices handling x86 model specific register accesses (`RDMSR`/`WRMSR` exits).
+pub trait DeviceMsr {
+    fn msr_read(&self, base: MsrAddress, offset: MsrAddressValue) -> u64;
+    fn msr_write(&self, base: MsrAddress, offset: MsrAddressValue, value: u64);
+
+    /// Handle a read which carries its access context.
+    fn msr_read_with(&self, _access: IoAccess, base: MsrAddress, offset: MsrAddressValue) -> u64 {
+        self.msr_read(base, offset)
+    }
+
+    /// Handle a write which carries its access context.
+    fn msr_write_with(
+        &self,
+        _access: IoAccess,
+        base: MsrAddress,
+        offset: MsrAddressValue,
+        value: u64,
+    ) {
+        self.msr_write(base, offset, value)
+    }
 }
 
 // TODO: turn into actual doc comments.
@@ -28,55 +285,434 @@ pub trait DeviceMmio {
 // mutability properties).
 
 pub trait MutDevicePio {
-    fn pio_read(&mut self, base: PioAddress, offset: PioAddressValue, data: &mut [u8]);
-    fn pio_write(&mut self, base: PioAddress, offset: PioAddressValue, data: &[u8]);
+    fn pio_read(&mut self, base: PioAddress, offset: PioOffset, data: &mut [u8]);
+    fn pio_write(&mut self, base: PioAddress, offset: PioOffset, data: &[u8]);
+
+    fn pio_read_with(
+        &mut self,
+        _access: IoAccess,
+        base: PioAddress,
+        offset: PioOffset,
+        data: &mut [u8],
+    ) {
+        self.pio_read(base, offset, data)
+    }
+
+    fn pio_write_with(
+        &mut self,
+        _access: IoAccess,
+        base: PioAddress,
+        offset: PioOffset,
+        data: &[u8],
+    ) {
+        self.pio_write(base, offset, data)
+    }
 }
 
 pub trait MutDeviceMmio {
-    fn mmio_read(&mut self, base: MmioAddress, offset: u64, data: &mut [u8]);
-    fn mmio_write(&mut self, base: MmioAddress, offset: u64, data: &[u8]);
+    fn mmio_read(&mut self, base: MmioAddress, offset: MmioOffset, data: &mut [u8]);
+    fn mmio_write(&mut self, base: MmioAddress, offset: MmioOffset, data: &[u8]);
+
+    fn mmio_read_with(
+        &mut self,
+        _access: IoAccess,
+        base: MmioAddress,
+        offset: MmioOffset,
+        data: &mut [u8],
+    ) {
+        self.mmio_read(base, offset, data)
+    }
+
+    fn mmio_write_with(
+        &mut self,
+        _access: IoAccess,
+        base: MmioAddress,
+        offset: MmioOffset,
+        data: &[u8],
+    ) {
+        self.mmio_write(base, offset, data)
+    }
+}
+
+pub trait MutDeviceSysReg {
+    fn sysreg_read(&mut self, base: SysRegAddress, offset: SysRegAddressValue) -> u64;
+    fn sysreg_write(&mut self, base: SysRegAddress, offset: SysRegAddressValue, value: u64);
+
+    fn sysreg_read_with(
+        &mut self,
+        _access: IoAccess,
+        base: SysRegAddress,
+        offset: SysRegAddressValue,
+    ) -> u64 {
+        self.sysreg_read(base, offset)
+    }
+
+    fn sysreg_write_with(
+        &mut self,
+        _access: IoAccess,
+        base: SysRegAddress,
+        offset: SysRegAddressValue,
+        value: u64,
+    ) {
+        self.sysreg_write(base, offset, value)
+    }
+}
+
+pub trait MutDeviceMsr {
+    fn msr_read(&mut self, base: MsrAddress, offset: MsrAddressValue) -> u64;
+    fn msr_write(&mut self, base: MsrAddress, offset: MsrAddressValue, value: u64);
+
+    fn msr_read_with(
+        &mut self,
+        _access: IoAccess,
+        base: MsrAddress,
+        offset: MsrAddressValue,
+    ) -> u64 {
+        self.msr_read(base, offset)
+    }
+
+    fn msr_write_with(
+        &mut self,
+        _access: IoAccess,
+        base: MsrAddress,
+        offset: MsrAddressValue,
+        value: u64,
+    ) {
+        self.msr_write(base, offset, value)
+    }
 }
 
 // Blanket implementations for Arc<T>.
 
 impl<T: DeviceMmio + ?Sized> DeviceMmio for Arc<T> {
-    fn mmio_read(&self, base: MmioAddress, offset: u64, data: &mut [u8]) {
+    fn mmio_read(&self, base: MmioAddress, offset: MmioOffset, data: &mut [u8]) {
         self.deref().mmio_read(base, offset, data);
     }
 
-    fn mmio_write(&self, base: MmioAddress, offset: u64, data: &[u8]) {
+    fn mmio_write(&self, base: MmioAddress, offset: MmioOffset, data: &[u8]) {
         self.deref().mmio_write(base, offset, data);
     }
+
+    fn mmio_read_with(
+        &self,
+        access: IoAccess,
+        base: MmioAddress,
+        offset: MmioOffset,
+        data: &mut [u8],
+    ) {
+        self.deref().mmio_read_with(access, base, offset, data)
+    }
+
+    fn mmio_write_with(
+        &self,
+        access: IoAccess,
+        base: MmioAddress,
+        offset: MmioOffset,
+        data: &[u8],
+    ) {
+        self.deref().mmio_write_with(access, base, offset, data)
+    }
+
+    fn try_mmio_read(
+        &self,
+        access: IoAccess,
+        base: MmioAddress,
+        offset: MmioOffset,
+        data: &mut [u8],
+    ) -> Result<(), DeviceError> {
+        self.deref().try_mmio_read(access, base, offset, data)
+    }
+
+    fn try_mmio_write(
+        &self,
+        access: IoAccess,
+        base: MmioAddress,
+        offset: MmioOffset,
+        data: &[u8],
+    ) -> Result<(), DeviceError> {
+        self.deref().try_mmio_write(access, base, offset, data)
+    }
+
+    fn as_zero_copy(&self) -> Option<&dyn DeviceMmioZeroCopy> {
+        self.deref().as_zero_copy()
+    }
+
+    fn as_any(&self) -> Option<&dyn Any> {
+        self.deref().as_any()
+    }
 }
 
 impl<T: DevicePio + ?Sized> DevicePio for Arc<T> {
-    fn pio_read(&self, base: PioAddress, offset: PioAddressValue, data: &mut [u8]) {
+    fn pio_read(&self, base: PioAddress, offset: PioOffset, data: &mut [u8]) {
         self.deref().pio_read(base, offset, data);
     }
 
-    fn pio_write(&self, base: PioAddress, offset: PioAddressValue, data: &[u8]) {
+    fn pio_write(&self, base: PioAddress, offset: PioOffset, data: &[u8]) {
         self.deref().pio_write(base, offset, data);
     }
+
+    fn pio_read_with(
+        &self,
+        access: IoAccess,
+        base: PioAddress,
+        offset: PioOffset,
+        data: &mut [u8],
+    ) {
+        self.deref().pio_read_with(access, base, offset, data)
+    }
+
+    fn pio_write_with(&self, access: IoAccess, base: PioAddress, offset: PioOffset, data: &[u8]) {
+        self.deref().pio_write_with(access, base, offset, data)
+    }
+
+    fn try_pio_read(
+        &self,
+        access: IoAccess,
+        base: PioAddress,
+        offset: PioOffset,
+        data: &mut [u8],
+    ) -> Result<(), DeviceError> {
+        self.deref().try_pio_read(access, base, offset, data)
+    }
+
+    fn try_pio_write(
+        &self,
+        access: IoAccess,
+        base: PioAddress,
+        offset: PioOffset,
+        data: &[u8],
+    ) -> Result<(), DeviceError> {
+        self.deref().try_pio_write(access, base, offset, data)
+    }
+
+    fn as_any(&self) -> Option<&dyn Any> {
+        self.deref().as_any()
+    }
+}
+
+impl<T: DeviceSysReg + ?Sized> DeviceSysReg for Arc<T> {
+    fn sysreg_read(&self, base: SysRegAddress, offset: SysRegAddressValue) -> u64 {
+        self.deref().sysreg_read(base, offset)
+    }
+
+    fn sysreg_write(&self, base: SysRegAddress, offset: SysRegAddressValue, value: u64) {
+        self.deref().sysreg_write(base, offset, value);
+    }
+
+    fn sysreg_read_with(
+        &self,
+        access: IoAccess,
+        base: SysRegAddress,
+        offset: SysRegAddressValue,
+    ) -> u64 {
+        self.deref().sysreg_read_with(access, base, offset)
+    }
+
+    fn sysreg_write_with(
+        &self,
+        access: IoAccess,
+        base: SysRegAddress,
+        offset: SysRegAddressValue,
+        value: u64,
+    ) {
+        self.deref().sysreg_write_with(access, base, offset, value)
+    }
+}
+
+impl<T: DeviceMsr + ?Sized> DeviceMsr for Arc<T> {
+    fn msr_read(&self, base: MsrAddress, offset: MsrAddressValue) -> u64 {
+        self.deref().msr_read(base, offset)
+    }
+
+    fn msr_write(&self, base: MsrAddress, offset: MsrAddressValue, value: u64) {
+        self.deref().msr_write(base, offset, value);
+    }
+
+    fn msr_read_with(&self, access: IoAccess, base: MsrAddress, offset: MsrAddressValue) -> u64 {
+        self.deref().msr_read_with(access, base, offset)
+    }
+
+    fn msr_write_with(
+        &self,
+        access: IoAccess,
+        base: MsrAddress,
+        offset: MsrAddressValue,
+        value: u64,
+    ) {
+        self.deref().msr_write_with(access, base, offset, value)
+    }
 }
 
-// Blanket implementations for Mutex<T>.
+// Blanket implementations for Mutex<T>. A poisoned lock panics; devices which need another
+// `poison::PoisonPolicy` are wrapped in a `poison::SupervisedMutex` instead. `as_any` and
+// `as_zero_copy` keep returning `None`, because the references they return would outlive
+// the lock of the device.
 
 impl<T: MutDeviceMmio + ?Sized> DeviceMmio for Mutex<T> {
-    fn mmio_read(&self, base: MmioAddress, offset: u64, data: &mut [u8]) {
-        self.lock().unwrap().mmio_read(base, offset, data)
+    fn mmio_read(&self, base: MmioAddress, offset: MmioOffset, data: &mut [u8]) {
+        self.mmio_read_with(IoAccess::default(), base, offset, data)
     }
 
-    fn mmio_write(&self, base: MmioAddress, offset: u64, data: &[u8]) {
-        self.lock().unwrap().mmio_write(base, offset, data)
+    fn mmio_write(&self, base: MmioAddress, offset: MmioOffset, data: &[u8]) {
+        self.mmio_write_with(IoAccess::default(), base, offset, data)
+    }
+
+    fn mmio_read_with(
+        &self,
+        access: IoAccess,
+        base: MmioAddress,
+        offset: MmioOffset,
+        data: &mut [u8],
+    ) {
+        if self.try_mmio_read(access, base, offset, data).is_err() {
+            poison::fail_read(data);
+        }
+    }
+
+    fn mmio_write_with(
+        &self,
+        access: IoAccess,
+        base: MmioAddress,
+        offset: MmioOffset,
+        data: &[u8],
+    ) {
+        let _ = self.try_mmio_write(access, base, offset, data);
+    }
+
+    fn try_mmio_read(
+        &self,
+        access: IoAccess,
+        base: MmioAddress,
+        offset: MmioOffset,
+        data: &mut [u8],
+    ) -> Result<(), DeviceError> {
+        poison::with_lock(self, PoisonPolicy::Panic, None, |device| {
+            device.mmio_read_with(access, base, offset, data)
+        })
+    }
+
+    fn try_mmio_write(
+        &self,
+        access: IoAccess,
+        base: MmioAddress,
+        offset: MmioOffset,
+        data: &[u8],
+    ) -> Result<(), DeviceError> {
+        poison::with_lock(self, PoisonPolicy::Panic, None, |device| {
+            device.mmio_write_with(access, base, offset, data)
+        })
     }
 }
 
 impl<T: MutDevicePio + ?Sized> DevicePio for Mutex<T> {
-    fn pio_read(&self, base: PioAddress, offset: PioAddressValue, data: &mut [u8]) {
-        self.lock().unwrap().pio_read(base, offset, data)
+    fn pio_read(&self, base: PioAddress, offset: PioOffset, data: &mut [u8]) {
+        self.pio_read_with(IoAccess::default(), base, offset, data)
+    }
+
+    fn pio_write(&self, base: PioAddress, offset: PioOffset, data: &[u8]) {
+        self.pio_write_with(IoAccess::default(), base, offset, data)
+    }
+
+    fn pio_read_with(
+        &self,
+        access: IoAccess,
+        base: PioAddress,
+        offset: PioOffset,
+        data: &mut [u8],
+    ) {
+        if self.try_pio_read(access, base, offset, data).is_err() {
+            poison::fail_read(data);
+        }
+    }
+
+    fn pio_write_with(&self, access: IoAccess, base: PioAddress, offset: PioOffset, data: &[u8]) {
+        let _ = self.try_pio_write(access, base, offset, data);
+    }
+
+    fn try_pio_read(
+        &self,
+        access: IoAccess,
+        base: PioAddress,
+        offset: PioOffset,
+        data: &mut [u8],
+    ) -> Result<(), DeviceError> {
+        poison::with_lock(self, PoisonPolicy::Panic, None, |device| {
+            device.pio_read_with(access, base, offset, data)
+        })
+    }
+
+    fn try_pio_write(
+        &self,
+        access: IoAccess,
+        base: PioAddress,
+        offset: PioOffset,
+        data: &[u8],
+    ) -> Result<(), DeviceError> {
+        poison::with_lock(self, PoisonPolicy::Panic, None, |device| {
+            device.pio_write_with(access, base, offset, data)
+        })
+    }
+}
+
+impl<T: MutDeviceSysReg + ?Sized> DeviceSysReg for Mutex<T> {
+    fn sysreg_read(&self, base: SysRegAddress, offset: SysRegAddressValue) -> u64 {
+        self.sysreg_read_with(IoAccess::default(), base, offset)
+    }
+
+    fn sysreg_write(&self, base: SysRegAddress, offset: SysRegAddressValue, value: u64) {
+        self.sysreg_write_with(IoAccess::default(), base, offset, value)
+    }
+
+    fn sysreg_read_with(
+        &self,
+        access: IoAccess,
+        base: SysRegAddress,
+        offset: SysRegAddressValue,
+    ) -> u64 {
+        poison::with_lock(self, PoisonPolicy::Panic, None, |device| {
+            device.sysreg_read_with(access, base, offset)
+        })
+        .unwrap_or(u64::MAX)
+    }
+
+    fn sysreg_write_with(
+        &self,
+        access: IoAccess,
+        base: SysRegAddress,
+        offset: SysRegAddressValue,
+        value: u64,
+    ) {
+        let _ = poison::with_lock(self, PoisonPolicy::Panic, None, |device| {
+            device.sysreg_write_with(access, base, offset, value)
+        });
+    }
+}
+
+impl<T: MutDeviceMsr + ?Sized> DeviceMsr for Mutex<T> {
+    fn msr_read(&self, base: MsrAddress, offset: MsrAddressValue) -> u64 {
+        self.msr_read_with(IoAccess::default(), base, offset)
+    }
+
+    fn msr_write(&self, base: MsrAddress, offset: MsrAddressValue, value: u64) {
+        self.msr_write_with(IoAccess::default(), base, offset, value)
+    }
+
+    fn msr_read_with(&self, access: IoAccess, base: MsrAddress, offset: MsrAddressValue) -> u64 {
+        poison::with_lock(self, PoisonPolicy::Panic, None, |device| {
+            device.msr_read_with(access, base, offset)
+        })
+        .unwrap_or(u64::MAX)
     }
 
-    fn pio_write(&self, base: PioAddress, offset: PioAddressValue, data: &[u8]) {
-        self.lock().unwrap().pio_write(base, offset, data)
+    fn msr_write_with(
+        &self,
+        access: IoAccess,
+        base: MsrAddress,
+        offset: MsrAddressValue,
+        value: u64,
+    ) {
+        let _ = poison::with_lock(self, PoisonPolicy::Panic, None, |device| {
+            device.msr_write_with(access, base, offset, value)
+        });
     }
 }
@@ -0,0 +1,331 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Power state transitions of the devices.
+//!
+//! Devices which need to act when the VM is reset, goes to sleep, or wakes up implement
+//! [`Lifecycle`](trait.Lifecycle.html) and are registered with
+//! `IoManager::register_lifecycle`. The VMM drives the transitions, usually after a device
+//! such as the [`AcpiPm`](../devices/acpi_pm/struct.AcpiPm.html) register block reports a
+//! guest request through a `VmEvent`.
+//!
+//! Some devices must be reset after others, e.g. the interrupt controller after the devices
+//! wired to it, so it doesn't latch the lines they lower while they return to their
+//! power-on state. These constraints are declared with `IoManager::reset_after`, and
+//! `IoManager::reset_platform` resets the devices in an order honoring all of them, checks
+//! that each reset completed, and reports the devices which failed.
+
+use std::fmt::{Display, Formatter};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::Arc;
+
+use crate::bus::MmioBusAddress;
+use crate::dependency::topological_order;
+use crate::device_manager::IoManager;
+
+/// ACPI sleep states the guest can enter, besides the soft off (S5) state.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SleepState {
+    /// Suspend to RAM (S3). The memory is preserved, while the device contexts are lost.
+    S3,
+    /// Suspend to disk (S4). The guest saved its memory, so the VM can be powered off.
+    S4,
+}
+
+/// Errors encountered while resetting the platform.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// A reset ordering constraint refers to a device which isn't registered.
+    UnknownDevice(String),
+    /// The reset ordering constraints of these devices form a cycle.
+    Cycle(Vec<String>),
+    /// The reset of these devices failed; the others were reset.
+    Reset(Vec<(String, ResetFailure)>),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::UnknownDevice(id) => write!(f, "unknown device {} in reset order", id),
+            Error::Cycle(ids) => write!(f, "cyclic reset order between {}", ids.join(", ")),
+            Error::Reset(failures) => {
+                write!(f, "failed to reset devices")?;
+                for (idx, (id, failure)) in failures.iter().enumerate() {
+                    let sep = if idx == 0 { ": " } else { ", " };
+                    write!(f, "{}{} ({})", sep, id, failure)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Reason why the reset of a device failed.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ResetFailure {
+    /// The device panicked while resetting.
+    Panicked,
+    /// The device didn't return to its power-on state.
+    Incomplete,
+    /// The device wasn't reset, since the reset of the device it depends on failed.
+    Dependency(String),
+}
+
+impl Display for ResetFailure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResetFailure::Panicked => write!(f, "panicked"),
+            ResetFailure::Incomplete => write!(f, "incomplete"),
+            ResetFailure::Dependency(id) => write!(f, "dependency {} failed", id),
+        }
+    }
+}
+
+/// Devices taking part in the power state transitions of the VM. All the methods do nothing
+/// by default.
+pub trait Lifecycle {
+    /// Return to the power-on state.
+    fn reset(&self) {}
+
+    /// Return whether the last reset brought the device back to its power-on state.
+    fn reset_complete(&self) -> bool {
+        true
+    }
+
+    /// Prepare for the VM entering `state`, e.g. by stopping the timers.
+    fn suspend(&self, _state: SleepState) {}
+
+    /// Re-initialize the device after the VM woke up from `state`.
+    fn resume(&self, _state: SleepState) {}
+}
+
+impl<M: MmioBusAddress> IoManager<M> {
+    /// Register the device `id`, to take part in the power state transitions of the VM.
+    pub fn register_lifecycle(&mut self, id: &str, device: Arc<dyn Lifecycle + Send + Sync>) {
+        self.lifecycle_devices.push((id.to_string(), device));
+    }
+
+    /// Reset the registered devices, in the order of their registration.
+    pub fn reset_devices(&self) {
+        for (_, device) in self.lifecycle_devices.iter() {
+            device.reset();
+        }
+    }
+
+    /// Reset the device `id` after the device `dependency` when the platform is reset. Both
+    /// devices can be registered later.
+    pub fn reset_after(&mut self, id: &str, dependency: &str) {
+        self.reset_order
+            .push((id.to_string(), dependency.to_string()));
+    }
+
+    // Return the indices of the registered devices in the order they are reset in, and the
+    // indices of the dependencies of each device. Devices without constraints between them
+    // keep the order of their registration.
+    fn reset_sequence(&self) -> Result<(Vec<usize>, Vec<Vec<usize>>), Error> {
+        let count = self.lifecycle_devices.len();
+        let index = |id: &str| {
+            self.lifecycle_devices
+                .iter()
+                .position(|(dev_id, _)| dev_id == id)
+                .ok_or_else(|| Error::UnknownDevice(id.to_string()))
+        };
+        let mut deps = vec![Vec::new(); count];
+        for (id, dependency) in self.reset_order.iter() {
+            deps[index(id)?].push(index(dependency)?);
+        }
+
+        let order = topological_order(&deps).map_err(|cycle| {
+            Error::Cycle(
+                cycle
+                    .into_iter()
+                    .map(|idx| self.lifecycle_devices[idx].0.clone())
+                    .collect(),
+            )
+        })?;
+        Ok((order, deps))
+    }
+
+    /// Reset the registered devices in an order honoring the constraints declared with
+    /// `reset_after`, and check that each of them completed its reset. A device which
+    /// panics or doesn't complete its reset fails, and so do the devices depending on it,
+    /// which aren't reset; the other devices are reset regardless.
+    pub fn reset_platform(&self) -> Result<(), Error> {
+        let (order, deps) = self.reset_sequence()?;
+        let mut failed = vec![false; order.len()];
+        let mut failures = Vec::new();
+        for idx in order {
+            let (id, device) = &self.lifecycle_devices[idx];
+            let failure = match deps[idx].iter().find(|dep| failed[**dep]) {
+                Some(dep) => Some(ResetFailure::Dependency(
+                    self.lifecycle_devices[*dep].0.clone(),
+                )),
+                None => match catch_unwind(AssertUnwindSafe(|| device.reset())) {
+                    Err(_) => Some(ResetFailure::Panicked),
+                    Ok(()) if !device.reset_complete() => Some(ResetFailure::Incomplete),
+                    Ok(()) => None,
+                },
+            };
+            if let Some(failure) = failure {
+                failed[idx] = true;
+                failures.push((id.clone(), failure));
+            }
+        }
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Reset(failures))
+        }
+    }
+
+    /// Prepare the registered devices for the VM entering `state`, in the order of their
+    /// registration.
+    pub fn suspend_devices(&self, state: SleepState) {
+        for (_, device) in self.lifecycle_devices.iter() {
+            device.suspend(state);
+        }
+    }
+
+    /// Re-initialize the registered devices after the VM woke up from `state`, in the
+    /// reverse order of their registration.
+    pub fn resume_devices(&self, state: SleepState) {
+        for (_, device) in self.lifecycle_devices.iter().rev() {
+            device.resume(state);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    struct Device {
+        name: &'static str,
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Lifecycle for Device {
+        fn reset(&self) {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("{} reset", self.name));
+            if self.name == "broken" {
+                panic!("reset failed");
+            }
+        }
+
+        fn reset_complete(&self) -> bool {
+            self.name != "stuck"
+        }
+
+        fn suspend(&self, state: SleepState) {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("{} suspend {:?}", self.name, state));
+        }
+
+        fn resume(&self, state: SleepState) {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("{} resume {:?}", self.name, state));
+        }
+    }
+
+    #[test]
+    fn test_lifecycle() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut io_mgr = IoManager::new();
+        for name in ["pic", "uart"].iter() {
+            let device = Device {
+                name,
+                log: log.clone(),
+            };
+            io_mgr.register_lifecycle(name, Arc::new(device));
+        }
+
+        io_mgr.reset_devices();
+        io_mgr.suspend_devices(SleepState::S3);
+        io_mgr.resume_devices(SleepState::S3);
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![
+                "pic reset",
+                "uart reset",
+                "pic suspend S3",
+                "uart suspend S3",
+                "uart resume S3",
+                "pic resume S3"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reset_platform() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut io_mgr = IoManager::new();
+        for name in ["pic", "uart", "stuck", "rtc", "broken"].iter() {
+            let device = Device {
+                name,
+                log: log.clone(),
+            };
+            io_mgr.register_lifecycle(name, Arc::new(device));
+        }
+        io_mgr.reset_after("pic", "uart");
+        io_mgr.reset_after("pic", "rtc");
+        io_mgr.reset_after("rtc", "stuck");
+        io_mgr.reset_after("uart", "broken");
+
+        // The devices depending on a failed one aren't reset.
+        assert_eq!(
+            io_mgr.reset_platform().unwrap_err(),
+            Error::Reset(vec![
+                ("stuck".to_string(), ResetFailure::Incomplete),
+                (
+                    "rtc".to_string(),
+                    ResetFailure::Dependency("stuck".to_string())
+                ),
+                ("broken".to_string(), ResetFailure::Panicked),
+                (
+                    "uart".to_string(),
+                    ResetFailure::Dependency("broken".to_string())
+                ),
+                (
+                    "pic".to_string(),
+                    ResetFailure::Dependency("uart".to_string())
+                ),
+            ])
+        );
+        assert_eq!(*log.lock().unwrap(), vec!["stuck reset", "broken reset"]);
+
+        io_mgr.reset_after("stuck", "pic");
+        assert!(matches!(io_mgr.reset_platform(), Err(Error::Cycle(ref ids)) if ids.len() == 3));
+        io_mgr.reset_after("serial", "pic");
+        assert_eq!(
+            io_mgr.reset_platform(),
+            Err(Error::UnknownDevice("serial".to_string()))
+        );
+
+        let mut io_mgr = IoManager::new();
+        log.lock().unwrap().clear();
+        for name in ["pic", "uart", "rtc"].iter() {
+            let device = Device {
+                name,
+                log: log.clone(),
+            };
+            io_mgr.register_lifecycle(name, Arc::new(device));
+        }
+        io_mgr.reset_after("pic", "rtc");
+        io_mgr.reset_platform().unwrap();
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec!["uart reset", "rtc reset", "pic reset"]
+        );
+    }
+}
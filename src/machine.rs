@@ -0,0 +1,417 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Declarative description of the devices of a machine.
+//!
+//! A [`MachineDescription`](struct.MachineDescription.html) lists the address windows and
+//! reserved ranges of the platform, together with the devices provided by this crate which
+//! don't need any runtime context (such as a clock or an interrupt line) to be created,
+//! their configuration and their resources. The description can then instantiate the
+//! devices, and either build an `IoManager` directly, or return an
+//! [`IoManagerBuilder`](../builder/struct.IoManagerBuilder.html) the VMM completes with its
+//! own devices.
+//!
+//! With the `serde` feature, the description can be deserialized, e.g. from a TOML file:
+//!
+//! ```toml
+//! mmio_windows = [{ base = 0xd0000000, size = 0x10000000 }]
+//!
+//! [[devices]]
+//! name = "flash0"
+//! kind = "flash"
+//! path = "/var/lib/vm/OVMF_CODE.fd"
+//! sector_size = 0x1000
+//! read_only = true
+//! resources = [{ mmio_address_range = { base = 0xffc00000, size = 0x400000 } }]
+//!
+//! [[devices]]
+//! name = "debugcon"
+//! kind = "debug_con"
+//! resources = [{ pio_address_range = { base = 0xe9, size = 1 } }]
+//! ```
+
+use std::fmt::{Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::builder::{self, IoManagerBuilder};
+use crate::bus::PioAddressValue;
+use crate::device_manager::IoManager;
+use crate::devices::debugcon::DebugCon;
+use crate::devices::flash::{self, Flash};
+use crate::devices::pl061::Pl061;
+use crate::devices::ram::{self, RamDevice, RomDevice};
+use crate::resources::Resource;
+
+/// Errors encountered while instantiating the devices of a machine.
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to open the file used by the device.
+    Open(String, io::Error),
+    /// Failed to create the memory region.
+    Ram(String, ram::Error),
+    /// Failed to create the flash device.
+    Flash(String, flash::Error),
+    /// The layout of the machine is invalid.
+    Layout(builder::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Open(dev, _) => write!(f, "failed to open the file of device {}", dev),
+            Error::Ram(dev, _) => write!(f, "failed to create memory region {}", dev),
+            Error::Flash(dev, _) => write!(f, "failed to create flash device {}", dev),
+            Error::Layout(_) => write!(f, "invalid machine layout"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Open(_, e) => Some(e),
+            Error::Ram(_, e) => Some(e),
+            Error::Flash(_, e) => Some(e),
+            Error::Layout(e) => Some(e),
+        }
+    }
+}
+
+/// Range of the address space, starting at `base`.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct Window<T> {
+    /// First address of the range.
+    pub base: T,
+    /// Size of the range.
+    pub size: T,
+}
+
+/// Ranges no device can be placed in.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct ReservedDescription {
+    /// Use of the ranges (e.g. "kvm-tss").
+    pub name: String,
+    /// The MMIO and PIO ranges which are reserved.
+    pub resources: Vec<Resource>,
+}
+
+/// Device model provided by this crate, with its configuration.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(tag = "kind", rename_all = "snake_case"))]
+pub enum DeviceKind {
+    /// Writable memory region of `size` bytes, or backed by the file at `path`.
+    Ram {
+        /// Size of the region, ignored when a backing file is used.
+        #[cfg_attr(feature = "serde", serde(default))]
+        size: usize,
+        /// File holding the contents of the region, as needed for NVRAM.
+        #[cfg_attr(feature = "serde", serde(default))]
+        path: Option<PathBuf>,
+    },
+    /// Read-only memory region with the contents of the file at `path`.
+    Rom {
+        /// File holding the contents of the region.
+        path: PathBuf,
+    },
+    /// CFI flash device backed by the file at `path`.
+    Flash {
+        /// File holding the contents of the flash device.
+        path: PathBuf,
+        /// Size of the erase blocks.
+        sector_size: u64,
+        /// Reject programming and erase commands (e.g. for the firmware code).
+        #[cfg_attr(feature = "serde", serde(default))]
+        read_only: bool,
+    },
+    /// Debug console writing the output of the guest to the file at `path`, or to the
+    /// standard output of the VMM.
+    DebugCon {
+        /// File the output of the guest is appended to.
+        #[cfg_attr(feature = "serde", serde(default))]
+        path: Option<PathBuf>,
+    },
+    /// PL061 GPIO controller.
+    Pl061,
+}
+
+/// Device of the machine.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct DeviceDescription {
+    /// Name of the device, used to report errors.
+    pub name: String,
+    /// Model and configuration of the device.
+    #[cfg_attr(feature = "serde", serde(flatten))]
+    pub kind: DeviceKind,
+    /// Resources of the device; its ranges are registered with the bus matching the model.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub resources: Vec<Resource>,
+    /// Names of the devices this device depends on.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub depends_on: Vec<String>,
+}
+
+/// Description of the address windows, reserved ranges, and devices of a machine.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct MachineDescription {
+    /// Windows the MMIO ranges must lie in. All addresses are allowed when empty.
+    pub mmio_windows: Vec<Window<u64>>,
+    /// Windows the PIO ranges must lie in. All ports are allowed when empty.
+    pub pio_windows: Vec<Window<PioAddressValue>>,
+    /// Ranges reserved for other uses.
+    pub reserved: Vec<ReservedDescription>,
+    /// IRQs which can be used by several devices.
+    pub shared_irqs: Vec<u32>,
+    /// Devices of the machine.
+    pub devices: Vec<DeviceDescription>,
+}
+
+// Open the file at `path` used by device `name`.
+fn open(name: &str, path: &Path, writable: bool) -> Result<File, Error> {
+    OpenOptions::new()
+        .read(true)
+        .write(writable)
+        .open(path)
+        .map_err(|e| Error::Open(name.to_string(), e))
+}
+
+impl DeviceDescription {
+    // Instantiate the device, and add it to `builder`.
+    fn add_to(&self, builder: IoManagerBuilder) -> Result<IoManagerBuilder, Error> {
+        let name = self.name.as_str();
+        let resources = self.resources.as_slice();
+        let builder = self.depends_on.iter().fold(builder, |builder, dependency| {
+            builder.depends_on(name, dependency)
+        });
+        let builder = match &self.kind {
+            DeviceKind::Ram { size, path } => {
+                let ram = match path {
+                    Some(path) => RamDevice::from_file(open(name, path, true)?)
+                        .map_err(|e| Error::Ram(name.to_string(), e))?,
+                    None => RamDevice::new(*size),
+                };
+                builder.mmio_device(name, Arc::new(Mutex::new(ram)), resources)
+            }
+            DeviceKind::Rom { path } => {
+                let rom = RomDevice::from_file(open(name, path, false)?)
+                    .map_err(|e| Error::Ram(name.to_string(), e))?;
+                builder.mmio_device(name, Arc::new(rom), resources)
+            }
+            DeviceKind::Flash {
+                path,
+                sector_size,
+                read_only,
+            } => {
+                let flash = Flash::from_file(open(name, path, !read_only)?, *sector_size)
+                    .map_err(|e| Error::Flash(name.to_string(), e))?
+                    .with_read_only(*read_only);
+                builder.mmio_device(name, Arc::new(Mutex::new(flash)), resources)
+            }
+            DeviceKind::DebugCon { path } => {
+                let sink: Box<dyn Write + Send> = match path {
+                    Some(path) => Box::new(
+                        OpenOptions::new()
+                            .create(true)
+                            .append(true)
+                            .open(path)
+                            .map_err(|e| Error::Open(name.to_string(), e))?,
+                    ),
+                    None => Box::new(io::stdout()),
+                };
+                builder.device(name, Arc::new(Mutex::new(DebugCon::new(sink))), resources)
+            }
+            DeviceKind::Pl061 => builder.mmio_device(name, Arc::new(Pl061::new()), resources),
+        };
+        Ok(builder)
+    }
+}
+
+impl MachineDescription {
+    /// Instantiate the devices, and return a builder holding them together with the
+    /// windows and reserved ranges of the machine, which can be completed with the devices
+    /// of the VMM.
+    pub fn builder(&self) -> Result<IoManagerBuilder, Error> {
+        let mut builder = IoManagerBuilder::new();
+        for window in self.mmio_windows.iter() {
+            builder = builder.mmio_window(window.base, window.size);
+        }
+        for window in self.pio_windows.iter() {
+            builder = builder.pio_window(window.base, window.size);
+        }
+        for reserved in self.reserved.iter() {
+            for res in reserved.resources.iter() {
+                builder = match *res {
+                    Resource::MmioAddressRange { base, size } => {
+                        builder.reserve_mmio(&reserved.name, base, size)
+                    }
+                    Resource::PioAddressRange { base, size } => {
+                        builder.reserve_pio(&reserved.name, base, size)
+                    }
+                    _ => builder,
+                };
+            }
+        }
+        for irq in self.shared_irqs.iter() {
+            builder = builder.shared_irq(*irq);
+        }
+        self.devices
+            .iter()
+            .try_fold(builder, |builder, dev| dev.add_to(builder))
+    }
+
+    /// Instantiate the devices, and build a manager with all of them registered.
+    pub fn build(&self) -> Result<IoManager, Error> {
+        self.builder()?.build().map_err(Error::Layout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::bus::{MmioAddress, PioAddress};
+    use crate::device_manager::{MmioManager, PioManager};
+
+    fn device(name: &str, kind: DeviceKind, resources: Vec<Resource>) -> DeviceDescription {
+        DeviceDescription {
+            name: name.to_string(),
+            kind,
+            resources,
+            depends_on: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_machine_description() {
+        let mut machine = MachineDescription {
+            mmio_windows: vec![Window {
+                base: 0xd000_0000,
+                size: 0x1000_0000,
+            }],
+            reserved: vec![ReservedDescription {
+                name: "kvm-tss".to_string(),
+                resources: vec![Resource::MmioAddressRange {
+                    base: 0xfffb_d000,
+                    size: 0x3000,
+                }],
+            }],
+            devices: vec![
+                device(
+                    "ram",
+                    DeviceKind::Ram {
+                        size: 0x1000,
+                        path: None,
+                    },
+                    vec![Resource::MmioAddressRange {
+                        base: 0xd000_0000,
+                        size: 0x1000,
+                    }],
+                ),
+                device(
+                    "gpio",
+                    DeviceKind::Pl061,
+                    vec![Resource::MmioAddressRange {
+                        base: 0xd000_1000,
+                        size: 0x1000,
+                    }],
+                ),
+                device(
+                    "debugcon",
+                    DeviceKind::DebugCon { path: None },
+                    vec![Resource::PioAddressRange {
+                        base: 0xe9,
+                        size: 1,
+                    }],
+                ),
+            ],
+            ..Default::default()
+        };
+
+        machine.devices[2].depends_on.push("gpio".to_string());
+        let io_mgr = machine.build().unwrap();
+        assert_eq!(io_mgr.device_order().unwrap(), ["ram", "gpio", "debugcon"]);
+        io_mgr
+            .mmio_write(MmioAddress(0xd000_0010), &[1, 2])
+            .unwrap();
+        let mut data = [0; 2];
+        io_mgr
+            .mmio_read(MmioAddress(0xd000_0010), &mut data)
+            .unwrap();
+        assert_eq!(data, [1, 2]);
+        assert!(io_mgr.mmio_device(MmioAddress(0xd000_1000)).is_some());
+        io_mgr.pio_read(PioAddress(0xe9), &mut data[..1]).unwrap();
+        assert_eq!(data[0], 0xe9);
+
+        machine.devices.push(device(
+            "rom",
+            DeviceKind::Rom {
+                path: PathBuf::from("/nonexistent/rom.bin"),
+            },
+            Vec::new(),
+        ));
+        assert!(matches!(machine.build(), Err(Error::Open(ref dev, _)) if dev == "rom"));
+        machine.devices.pop();
+        machine.devices.push(device(
+            "ram2",
+            DeviceKind::Ram {
+                size: 0x1000,
+                path: None,
+            },
+            vec![Resource::MmioAddressRange {
+                base: 0xd000_0800,
+                size: 0x1000,
+            }],
+        ));
+        assert!(matches!(
+            machine.build(),
+            Err(Error::Layout(builder::Error::Overlap(_, _)))
+        ));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_deserialize() {
+        let json = r#"{
+            "mmio_windows": [{ "base": 3489660928, "size": 268435456 }],
+            "devices": [
+                {
+                    "name": "gpio",
+                    "kind": "pl061",
+                    "resources": [{ "mmio_address_range": { "base": 3489660928, "size": 4096 } }]
+                },
+                { "name": "debugcon", "kind": "debug_con", "depends_on": ["gpio"] }
+            ]
+        }"#;
+        let toml = r#"
+            mmio_windows = [{ base = 0xd0000000, size = 0x10000000 }]
+
+            [[devices]]
+            name = "gpio"
+            kind = "pl061"
+            resources = [{ mmio_address_range = { base = 0xd0000000, size = 0x1000 } }]
+
+            [[devices]]
+            name = "debugcon"
+            kind = "debug_con"
+            depends_on = ["gpio"]
+        "#;
+        let from_json: MachineDescription = serde_json::from_str(json).unwrap();
+        let from_toml: MachineDescription = toml::from_str(toml).unwrap();
+        assert_eq!(from_json, from_toml);
+        assert_eq!(from_json.devices[0].kind, DeviceKind::Pl061);
+        assert_eq!(
+            from_json.devices[1].kind,
+            DeviceKind::DebugCon { path: None }
+        );
+        assert!(from_json.devices[1].resources.is_empty());
+        assert!(from_toml.build().is_ok());
+    }
+}
@@ -0,0 +1,190 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Device regions mapped directly into the guest address space.
+//!
+//! Some regions, such as the BARs of assigned devices or shared memory, are backed by a file
+//! descriptor which the VMM can map into the guest, so accesses don't trap. Such a region is
+//! registered like any other MMIO range, and also marked with a
+//! [`Mappable`](struct.Mappable.html) describing its backing. The VMM creates a memory slot
+//! for each entry returned by `IoManager::mappable_regions`, while the registered device
+//! still handles the accesses which trap, e.g. before the slot is set up or for the parts of
+//! the device range which are not mappable.
+
+use std::fmt::{Debug, Formatter};
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+
+use crate::bus::{self, BusRange, MmioBusAddress};
+use crate::device_manager::{Error, IoManager};
+use crate::DeviceMmio;
+
+/// Backing of a region which can be mapped into the guest: the region starts at `offset`
+/// within the file behind `fd`.
+#[derive(Clone)]
+pub struct Mappable {
+    /// File backing the region.
+    pub fd: Arc<dyn AsRawFd + Send + Sync>,
+    /// Offset of the region within the file.
+    pub offset: u64,
+    /// Whether the guest can only read the region.
+    pub read_only: bool,
+}
+
+impl Mappable {
+    /// Create a writable mapping of the file behind `fd`, starting at `offset`.
+    pub fn new(fd: Arc<dyn AsRawFd + Send + Sync>, offset: u64) -> Self {
+        Mappable {
+            fd,
+            offset,
+            read_only: false,
+        }
+    }
+
+    /// Make the mapping read-only, so guest writes still trap.
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+}
+
+impl Debug for Mappable {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Mappable")
+            .field("fd", &self.fd.as_raw_fd())
+            .field("offset", &self.offset)
+            .field("read_only", &self.read_only)
+            .finish()
+    }
+}
+
+impl<M: MmioBusAddress> IoManager<M> {
+    /// Register a MMIO device whose whole range can be mapped into the guest as described
+    /// by `mappable`.
+    pub fn register_mmio_mappable(
+        &mut self,
+        range: BusRange<M>,
+        device: Arc<dyn DeviceMmio + Send + Sync>,
+        mappable: Mappable,
+    ) -> Result<(), Error> {
+        self.mmio_bus.register(range, device).map_err(Error::Bus)?;
+        self.mappable.register(range, mappable).map_err(|e| {
+            self.mmio_bus.deregister(range.base());
+            Error::Bus(e)
+        })
+    }
+
+    /// Mark `range`, which must be part of the range of a registered MMIO device, as
+    /// mappable into the guest as described by `mappable`.
+    pub fn mark_mappable(&mut self, range: BusRange<M>, mappable: Mappable) -> Result<(), Error> {
+        match self.mmio_bus.device(range.base()) {
+            Some((device_range, _)) if device_range.last() >= range.last() => {}
+            _ => return Err(Error::Bus(bus::Error::DeviceNotFound)),
+        }
+        self.mappable.register(range, mappable).map_err(Error::Bus)
+    }
+
+    /// Stop reporting the mappable region which contains `addr`, e.g. before deregistering
+    /// its device.
+    pub fn unmark_mappable(&mut self, addr: M) -> Option<(BusRange<M>, Mappable)> {
+        self.mappable.deregister(addr)
+    }
+
+    /// Return the regions which can be mapped into the guest. Regions whose device is no
+    /// longer registered are left out.
+    pub fn mappable_regions(&self) -> Vec<(BusRange<M>, Mappable)> {
+        self.mappable
+            .iter()
+            .filter(|(range, _)| self.mmio_bus.device(range.base()).is_some())
+            .map(|(range, mappable)| (*range, mappable.clone()))
+            .collect()
+    }
+
+    // Move the mappable regions within the old range of each move along with their device,
+    // which now covers the new one. All the regions are taken out before any is put back, so
+    // a device can move to where another one was.
+    pub(crate) fn move_mappable(&mut self, moves: &[(BusRange<M>, BusRange<M>)]) {
+        let mut entries = Vec::new();
+        for (old, new) in moves.iter() {
+            let moved: Vec<BusRange<M>> = self
+                .mappable
+                .ranges()
+                .filter(|range| range.base() >= old.base() && range.last() <= old.last())
+                .copied()
+                .collect();
+            for range in moved {
+                if let Some(entry) = self.mappable.deregister(range.base()) {
+                    entries.push((*old, *new, entry));
+                }
+            }
+        }
+        for (old, new, (range, mappable)) in entries {
+            let offset = range.base() - old.base();
+            if let Ok(range) = BusRange::new(new.base() + offset, range.size()) {
+                // The moved regions keep their relative layout, so they can't overlap.
+                let _ = self.mappable.register(range, mappable);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::File;
+
+    use crate::bus::{MmioAddress, MmioRange};
+    use crate::device_manager::MmioManager;
+    use crate::pci::{BarReprogrammingParams, PciBarRegionType};
+    use crate::testing::EchoDevice;
+
+    #[test]
+    fn test_mappable_regions() {
+        let mut io_mgr = IoManager::new();
+        let fd: Arc<dyn AsRawFd + Send + Sync> = Arc::new(File::open("/dev/null").unwrap());
+        let bar = MmioRange::new(MmioAddress(0x1000_0000), 0x4000).unwrap();
+        let window = MmioRange::new(MmioAddress(0x1000_2000), 0x2000).unwrap();
+        let device = Arc::new(EchoDevice::new());
+
+        // Only ranges of registered devices can be marked.
+        assert!(io_mgr
+            .mark_mappable(window, Mappable::new(fd.clone(), 0))
+            .is_err());
+        io_mgr.register_mmio(bar, device.clone()).unwrap();
+        io_mgr
+            .mark_mappable(window, Mappable::new(fd.clone(), 0x2000))
+            .unwrap();
+        let regions = io_mgr.mappable_regions();
+        assert_eq!(regions.len(), 1);
+        assert_eq!(regions[0].0, window);
+        assert_eq!(regions[0].1.offset, 0x2000);
+
+        // The part which is not mapped still traps.
+        io_mgr.mmio_write(MmioAddress(0x1000_0000), &[1]).unwrap();
+        assert_eq!(device.accesses().len(), 1);
+
+        // The mappable regions follow the BAR when the guest moves it.
+        io_mgr
+            .relocate_bars(&[BarReprogrammingParams {
+                old_base: 0x1000_0000,
+                new_base: 0x2000_0000,
+                len: 0x4000,
+                region_type: PciBarRegionType::Memory32BitRegion,
+            }])
+            .unwrap();
+        assert_eq!(
+            io_mgr.mappable_regions()[0].0,
+            MmioRange::new(MmioAddress(0x2000_2000), 0x2000).unwrap()
+        );
+
+        io_mgr.deregister_mmio(MmioAddress(0x2000_0000)).unwrap();
+        assert!(io_mgr.mappable_regions().is_empty());
+        assert!(io_mgr.unmark_mappable(MmioAddress(0x2000_2000)).is_some());
+
+        io_mgr
+            .register_mmio_mappable(bar, device, Mappable::new(fd, 0).read_only())
+            .unwrap();
+        assert!(io_mgr.mappable_regions()[0].1.read_only);
+    }
+}
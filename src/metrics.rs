@@ -0,0 +1,314 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Export of the device statistics in the Prometheus text format.
+//!
+//! The buses keep statistics for each registered range (see `IoManager::range_stats`). A
+//! [`PrometheusExporter`](struct.PrometheusExporter.html) renders them as Prometheus metrics,
+//! with the address space, base, and size of the range as labels, so the VMM can serve them
+//! from its metrics endpoint, or write them to a file collected by the node exporter. With
+//! the `latency` feature, the latency histograms of the ranges are exported as well, in
+//! seconds.
+//!
+//! When the `IoManager` has an interrupt router, the statistics of the interrupt lines (see
+//! `IrqRouter::stats`) are exported too, labeled with the number of the line.
+
+use std::io::{self, Write};
+
+use crate::bus::{
+    BusAddress, BusRange, MmioBusAddress, PioAddress, RangeStats, LATENCY_BUCKETS_NS,
+};
+use crate::device_manager::IoManager;
+use crate::interrupt::IrqStats;
+
+/// Renders the statistics of an `IoManager` in the Prometheus text format.
+pub struct PrometheusExporter {
+    prefix: String,
+}
+
+impl Default for PrometheusExporter {
+    fn default() -> Self {
+        PrometheusExporter {
+            prefix: "vm_device".to_string(),
+        }
+    }
+}
+
+// Metric families exported for each range: their name suffix, type, help text, and value.
+type Family = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&RangeStats) -> u64,
+);
+
+const FAMILIES: [Family; 3] = [
+    (
+        "accesses_total",
+        "counter",
+        "Accesses dispatched to the device of the range.",
+        |stats| stats.accesses,
+    ),
+    (
+        "failures_total",
+        "counter",
+        "Accesses the device of the range failed to handle.",
+        |stats| stats.failures,
+    ),
+    (
+        "quarantined",
+        "gauge",
+        "Whether the range is quarantined.",
+        |stats| u64::from(stats.quarantined),
+    ),
+];
+
+// Metric families exported for each interrupt line.
+type IrqFamily = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&IrqStats) -> f64,
+);
+
+const IRQ_FAMILIES: [IrqFamily; 4] = [
+    (
+        "irq_assertions_total",
+        "counter",
+        "Assertions of the interrupt line.",
+        |stats| stats.assertions as f64,
+    ),
+    (
+        "irq_asserted_seconds_total",
+        "counter",
+        "Time the interrupt line was asserted.",
+        |stats| stats.asserted_ns as f64 / 1e9,
+    ),
+    (
+        "irq_storms_total",
+        "counter",
+        "Interrupt storms detected on the line.",
+        |stats| stats.storms as f64,
+    ),
+    (
+        "irq_storming",
+        "gauge",
+        "Whether the interrupt line is storming.",
+        |stats| u64::from(stats.storming) as f64,
+    ),
+];
+
+impl PrometheusExporter {
+    /// Create an exporter naming the metrics `vm_device_*`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Name the metrics `<prefix>_*`, e.g. to group them with the other metrics of the VMM.
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.to_string();
+        self
+    }
+
+    /// Write the metrics of the PIO and MMIO ranges of `io_mgr` to `writer`.
+    pub fn write_to<M: MmioBusAddress, W: Write>(
+        &self,
+        io_mgr: &IoManager<M>,
+        writer: &mut W,
+    ) -> io::Result<()> {
+        let pio = io_mgr.range_stats::<PioAddress>();
+        let mmio = io_mgr.range_stats::<M>();
+        for (suffix, kind, help, value) in FAMILIES.iter() {
+            let name = format!("{}_{}", self.prefix, suffix);
+            writeln!(writer, "# HELP {} {}", name, help)?;
+            writeln!(writer, "# TYPE {} {}", name, kind)?;
+            write_family(writer, &name, "pio", &pio, *value)?;
+            write_family(writer, &name, "mmio", &mmio, *value)?;
+        }
+        if cfg!(feature = "latency") {
+            let name = format!("{}_latency_seconds", self.prefix);
+            writeln!(
+                writer,
+                "# HELP {} Time the device of the range took to handle the accesses.",
+                name
+            )?;
+            writeln!(writer, "# TYPE {} histogram", name)?;
+            write_histograms(writer, &name, "pio", &pio)?;
+            write_histograms(writer, &name, "mmio", &mmio)?;
+        }
+        if let Some(router) = io_mgr.irq_router() {
+            let lines = router.all_stats();
+            for (suffix, kind, help, value) in IRQ_FAMILIES.iter() {
+                let name = format!("{}_{}", self.prefix, suffix);
+                writeln!(writer, "# HELP {} {}", name, help)?;
+                writeln!(writer, "# TYPE {} {}", name, kind)?;
+                for (irq, stats) in lines.iter() {
+                    writeln!(writer, "{}{{irq=\"{}\"}} {}", name, irq, value(stats))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Return the metrics of `io_mgr` as a string.
+    pub fn render<M: MmioBusAddress>(&self, io_mgr: &IoManager<M>) -> String {
+        let mut buf = Vec::new();
+        // Writing to a vector doesn't fail, and the metrics are ASCII.
+        let _ = self.write_to(io_mgr, &mut buf);
+        String::from_utf8_lossy(&buf).into_owned()
+    }
+}
+
+// Return the labels identifying `range`.
+fn labels<A: BusAddress>(space: &str, range: &BusRange<A>) -> String {
+    format!(
+        "space=\"{}\",base=\"{:#x}\",size=\"{:#x}\"",
+        space,
+        range.base().value().into(),
+        range.size().into()
+    )
+}
+
+fn write_family<A: BusAddress, W: Write>(
+    writer: &mut W,
+    name: &str,
+    space: &str,
+    ranges: &[(BusRange<A>, RangeStats)],
+    value: fn(&RangeStats) -> u64,
+) -> io::Result<()> {
+    for (range, stats) in ranges.iter() {
+        writeln!(
+            writer,
+            "{}{{{}}} {}",
+            name,
+            labels(space, range),
+            value(stats)
+        )?;
+    }
+    Ok(())
+}
+
+fn write_histograms<A: BusAddress, W: Write>(
+    writer: &mut W,
+    name: &str,
+    space: &str,
+    ranges: &[(BusRange<A>, RangeStats)],
+) -> io::Result<()> {
+    for (range, stats) in ranges.iter() {
+        let labels = labels(space, range);
+        let histogram = &stats.latency;
+        // Prometheus buckets are cumulative.
+        let mut count = 0;
+        for (bound, bucket) in LATENCY_BUCKETS_NS.iter().zip(histogram.buckets.iter()) {
+            count += bucket;
+            let le = *bound as f64 / 1e9;
+            writeln!(
+                writer,
+                "{}_bucket{{{},le=\"{}\"}} {}",
+                name, labels, le, count
+            )?;
+        }
+        let count = histogram.count();
+        writeln!(
+            writer,
+            "{}_bucket{{{},le=\"+Inf\"}} {}",
+            name, labels, count
+        )?;
+        let sum = histogram.sum_ns as f64 / 1e9;
+        writeln!(writer, "{}_sum{{{}}} {}", name, labels, sum)?;
+        writeln!(writer, "{}_count{{{}}} {}", name, labels, count)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::bus::{MmioAddress, MmioRange, PioRange};
+    use crate::device_manager::{MmioManager, PioManager};
+    use crate::interrupt::{IrqRouter, TriggerMode};
+    use crate::testing::EchoDevice;
+    use crate::time::ManualClock;
+    use crate::IoAccess;
+
+    #[test]
+    fn test_prometheus_exporter() {
+        let mut io_mgr = IoManager::new();
+        let dev = Arc::new(EchoDevice::new());
+        io_mgr
+            .register_pio(PioRange::new(PioAddress(0x3f8), 8).unwrap(), dev.clone())
+            .unwrap();
+        io_mgr
+            .register_mmio(
+                MmioRange::new(MmioAddress(0xd000_0000), 0x1000).unwrap(),
+                dev,
+            )
+            .unwrap();
+
+        let mut data = [0; 4];
+        io_mgr.pio_write(PioAddress(0x3f8), &[0x41]).unwrap();
+        io_mgr
+            .mmio_read(MmioAddress(0xd000_0000), &mut data)
+            .unwrap();
+        io_mgr
+            .mmio_read_with(MmioAddress(0xd000_0004), IoAccess::new(0, 0), &mut data)
+            .unwrap();
+        // Accesses which don't reach any range aren't accounted to any device.
+        assert!(io_mgr.pio_read(PioAddress(0x80), &mut data[..1]).is_err());
+
+        let stats = io_mgr.range_stats::<MmioAddress>();
+        assert_eq!(stats[0].1.accesses, 2);
+        assert_eq!(stats[0].1.failures, 0);
+
+        let text = PrometheusExporter::new().with_prefix("vmm").render(&io_mgr);
+        let lines: Vec<&str> = text.lines().take(12).collect();
+        assert_eq!(
+            &lines[..4],
+            &[
+                "# HELP vmm_accesses_total Accesses dispatched to the device of the range.",
+                "# TYPE vmm_accesses_total counter",
+                "vmm_accesses_total{space=\"pio\",base=\"0x3f8\",size=\"0x8\"} 1",
+                "vmm_accesses_total{space=\"mmio\",base=\"0xd0000000\",size=\"0x1000\"} 2",
+            ]
+        );
+        assert!(lines.contains(&"vmm_quarantined{space=\"pio\",base=\"0x3f8\",size=\"0x8\"} 0"));
+
+        assert!(!text.contains("vmm_irq_"));
+
+        // Every access dispatched to the device is measured.
+        #[cfg(feature = "latency")]
+        {
+            assert_eq!(stats[0].1.latency.count(), 2);
+            assert!(text.contains(
+                "vmm_latency_seconds_bucket{space=\"mmio\",base=\"0xd0000000\",size=\"0x1000\",le=\"0.00000025\"}"
+            ));
+            assert!(text.contains(
+                "vmm_latency_seconds_count{space=\"mmio\",base=\"0xd0000000\",size=\"0x1000\"} 2"
+            ));
+        }
+    }
+
+    #[test]
+    fn test_irq_metrics() {
+        let clock = Arc::new(ManualClock::new());
+        let mut io_mgr = IoManager::<MmioAddress>::new();
+        io_mgr.set_irq_router(IrqRouter::with_clock(Arc::new(|_, _| {}), clock.clone()));
+        let line = io_mgr
+            .irq_router()
+            .unwrap()
+            .line(4, TriggerMode::Level)
+            .unwrap();
+        line.assert();
+        clock.advance(1_500_000_000);
+        line.deassert();
+
+        let text = PrometheusExporter::new().render(&io_mgr);
+        assert!(text.contains("# TYPE vm_device_irq_assertions_total counter"));
+        assert!(text.contains("vm_device_irq_assertions_total{irq=\"4\"} 1"));
+        assert!(text.contains("vm_device_irq_asserted_seconds_total{irq=\"4\"} 1.5"));
+        assert!(text.contains("vm_device_irq_storming{irq=\"4\"} 0"));
+    }
+}
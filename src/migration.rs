@@ -0,0 +1,678 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Streaming format for the device model state, used by snapshots and live migration.
+//!
+//! A [`MigrationWriter`](struct.MigrationWriter.html) serializes the bus layout of an
+//! `IoManager`, the resources of the devices, and their versioned states over any
+//! `io::Write`, and a [`MigrationReader`](struct.MigrationReader.html) reads them back from
+//! any `io::Read`. The stream starts with a header holding a magic value and the format
+//! version, followed by sections. Each section is framed by its kind and length, and
+//! followed by the CRC-32 of its payload, so a corrupted stream is detected before any of its
+//! contents is used. The stream ends with an end section.
+//!
+//! The reader accepts the streams written with any format version since
+//! `MIGRATION_MIN_FORMAT_VERSION`, and upgrades their contents to the current format: the
+//! fields added by later versions get the value the older ones implied.
+
+use std::convert::TryFrom;
+use std::fmt::{Display, Formatter};
+use std::io::{self, Read, Write};
+
+use crate::bus::{BusAddress, BusRange, MmioAddress, MsrAddress, PioAddress, SysRegAddress};
+use crate::device_manager::{AccessAttrs, IoLayout, LayoutEntry, MmioSegment, Reservation};
+use crate::resources::{MsiIrqType, Resource};
+use crate::snapshot::{self, StateReader, StateWriter, Version, VersionedState};
+
+/// Magic value starting the migration streams.
+pub const MIGRATION_MAGIC: [u8; 8] = *b"VMDEVMIG";
+
+/// Version of the stream format written by this crate.
+pub const MIGRATION_FORMAT_VERSION: u32 = 5;
+
+/// Oldest version of the stream format which can still be read.
+pub const MIGRATION_MIN_FORMAT_VERSION: u32 = 1;
+
+// Format versions which changed the contents of the sections: PIO resources with 32-bit
+// ports, then the reserved ranges in the layout, the decode enable of the layout entries,
+// and the MMIO segments.
+const FORMAT_PIO_32: u32 = 2;
+const FORMAT_RESERVATIONS: u32 = 3;
+const FORMAT_DECODE_ENABLE: u32 = 4;
+const FORMAT_MMIO_SEGMENTS: u32 = 5;
+
+const SECTION_END: u8 = 0;
+const SECTION_LAYOUT: u8 = 1;
+const SECTION_RESOURCES: u8 = 2;
+const SECTION_DEVICE: u8 = 3;
+
+/// Errors encountered while reading migration streams.
+#[derive(Debug)]
+pub enum Error {
+    /// The stream doesn't start with `MIGRATION_MAGIC`.
+    BadMagic,
+    /// The payload of a section doesn't match its CRC.
+    Crc(u8),
+    /// Failed to decode a section.
+    Decode(snapshot::Error),
+    /// Failed to read from or write to the stream.
+    Io(io::Error),
+    /// The stream ended without an end section.
+    MissingEnd,
+    /// The stream format version is not supported.
+    UnsupportedFormat(u32),
+    /// The section kind is unknown.
+    UnknownSection(u8),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::BadMagic => write!(f, "not a migration stream"),
+            Error::Crc(kind) => write!(f, "CRC mismatch in section of kind {}", kind),
+            Error::Decode(_) => write!(f, "failed to decode section"),
+            Error::Io(_) => write!(f, "migration stream IO error"),
+            Error::MissingEnd => write!(f, "migration stream ended unexpectedly"),
+            Error::UnsupportedFormat(version) => {
+                write!(f, "unsupported migration format version {}", version)
+            }
+            Error::UnknownSection(kind) => write!(f, "unknown section kind {}", kind),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Decode(e) => Some(e),
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Compute the CRC-32 (IEEE 802.3) of `data`.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data.iter() {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (!(crc & 1)).wrapping_add(1));
+        }
+    }
+    !crc
+}
+
+/// A section of a migration stream.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Section {
+    /// Ranges registered on the buses.
+    Layout(IoLayout),
+    /// Resources allocated to a device, keyed by device ID.
+    Resources(String, Vec<Resource>),
+    /// State of a device.
+    Device(VersionedState),
+}
+
+fn put_entries<A: BusAddress>(writer: &mut StateWriter, entries: &[LayoutEntry<A>]) {
+    writer.put_u32(entries.len() as u32);
+    for entry in entries.iter() {
+        writer.put_u64(entry.range.base().value().into());
+        writer.put_u64(entry.range.size().into());
+        writer.put_u64(entry.device_id as u64);
+        writer.put_bool(entry.draining);
+        writer.put_bool(entry.shadow);
+        writer.put_bool(entry.enabled);
+    }
+}
+
+fn get_entries<A: BusAddress>(
+    reader: &mut StateReader<'_>,
+    format: u32,
+    address: fn(u64) -> Option<A>,
+) -> Result<Vec<LayoutEntry<A>>, snapshot::Error> {
+    let invalid = || snapshot::Error::InvalidData("invalid layout range".to_string());
+    (0..reader.u32()?)
+        .map(|_| {
+            let base = address(reader.u64()?).ok_or_else(invalid)?;
+            let size = usize::try_from(reader.u64()?)
+                .ok()
+                .and_then(|size| A::V::try_from(size).ok())
+                .ok_or_else(invalid)?;
+            Ok(LayoutEntry {
+                range: BusRange::new(base, size).map_err(|_| invalid())?,
+                device_id: reader.u64()? as usize,
+                draining: reader.bool()?,
+                shadow: reader.bool()?,
+                // All the ranges decoded their accesses before they could be disabled.
+                enabled: format < FORMAT_DECODE_ENABLE || reader.bool()?,
+            })
+        })
+        .collect()
+}
+
+fn put_reservations<A: BusAddress>(writer: &mut StateWriter, reservations: &[Reservation<A>]) {
+    writer.put_u32(reservations.len() as u32);
+    for reservation in reservations.iter() {
+        writer.put_u64(reservation.range.base().value().into());
+        writer.put_u64(reservation.range.size().into());
+        writer.put_bytes(reservation.name.as_bytes());
+    }
+}
+
+fn get_reservations<A: BusAddress>(
+    reader: &mut StateReader<'_>,
+    address: fn(u64) -> Option<A>,
+) -> Result<Vec<Reservation<A>>, snapshot::Error> {
+    let invalid = || snapshot::Error::InvalidData("invalid reserved range".to_string());
+    (0..reader.u32()?)
+        .map(|_| {
+            let base = address(reader.u64()?).ok_or_else(invalid)?;
+            let size = usize::try_from(reader.u64()?)
+                .ok()
+                .and_then(|size| A::V::try_from(size).ok())
+                .ok_or_else(invalid)?;
+            Ok(Reservation {
+                range: BusRange::new(base, size).map_err(|_| invalid())?,
+                name: String::from_utf8(reader.bytes()?.to_vec()).map_err(|_| invalid())?,
+            })
+        })
+        .collect()
+}
+
+fn put_resource(writer: &mut StateWriter, resource: &Resource) {
+    match resource {
+        Resource::PioAddressRange { base, size } => {
+            writer.put_u8(0);
+            writer.put_u32(*base);
+            writer.put_u32(*size);
+        }
+        Resource::MmioAddressRange { base, size } => {
+            writer.put_u8(1);
+            writer.put_u64(*base);
+            writer.put_u64(*size);
+        }
+        Resource::LegacyIrq(irq) => {
+            writer.put_u8(2);
+            writer.put_u32(*irq);
+        }
+        Resource::MsiIrq { ty, base, size } => {
+            writer.put_u8(3);
+            writer.put_u8(match ty {
+                MsiIrqType::PciMsi => 0,
+                MsiIrqType::PciMsix => 1,
+                MsiIrqType::GenericMsi => 2,
+            });
+            writer.put_u32(*base);
+            writer.put_u32(*size);
+        }
+        Resource::MacAddresss(mac) => {
+            writer.put_u8(4);
+            writer.put_bytes(mac.as_bytes());
+        }
+        Resource::KvmMemSlot(slot) => {
+            writer.put_u8(5);
+            writer.put_u32(*slot);
+        }
+    }
+}
+
+fn get_resource(reader: &mut StateReader<'_>, format: u32) -> Result<Resource, snapshot::Error> {
+    let invalid = |what: &str| snapshot::Error::InvalidData(format!("invalid {}", what));
+    let port = |reader: &mut StateReader<'_>| {
+        if format < FORMAT_PIO_32 {
+            reader.u16().map(u32::from)
+        } else {
+            reader.u32()
+        }
+    };
+    Ok(match reader.u8()? {
+        0 => Resource::PioAddressRange {
+            base: port(reader)?,
+            size: port(reader)?,
+        },
+        1 => Resource::MmioAddressRange {
+            base: reader.u64()?,
+            size: reader.u64()?,
+        },
+        2 => Resource::LegacyIrq(reader.u32()?),
+        3 => Resource::MsiIrq {
+            ty: match reader.u8()? {
+                0 => MsiIrqType::PciMsi,
+                1 => MsiIrqType::PciMsix,
+                2 => MsiIrqType::GenericMsi,
+                _ => return Err(invalid("MSI type")),
+            },
+            base: reader.u32()?,
+            size: reader.u32()?,
+        },
+        4 => Resource::MacAddresss(
+            String::from_utf8(reader.bytes()?.to_vec()).map_err(|_| invalid("MAC address"))?,
+        ),
+        5 => Resource::KvmMemSlot(reader.u32()?),
+        _ => return Err(invalid("resource type")),
+    })
+}
+
+/// Writes the device model state to a migration stream.
+pub struct MigrationWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> MigrationWriter<W> {
+    /// Start a migration stream over `inner`, by writing its header.
+    pub fn new(mut inner: W) -> io::Result<Self> {
+        inner.write_all(&MIGRATION_MAGIC)?;
+        inner.write_all(&MIGRATION_FORMAT_VERSION.to_le_bytes())?;
+        Ok(MigrationWriter { inner })
+    }
+
+    fn write_section(&mut self, kind: u8, payload: &[u8]) -> io::Result<()> {
+        self.inner.write_all(&[kind])?;
+        self.inner
+            .write_all(&(payload.len() as u32).to_le_bytes())?;
+        self.inner.write_all(payload)?;
+        self.inner.write_all(&crc32(payload).to_le_bytes())
+    }
+
+    /// Write the ranges registered on the buses.
+    pub fn write_layout(&mut self, layout: &IoLayout) -> io::Result<()> {
+        let mut writer = StateWriter::new();
+        put_entries(&mut writer, &layout.pio);
+        put_entries(&mut writer, &layout.mmio);
+        writer.put_u32(layout.mmio_overlays.len() as u32);
+        for (attrs, entries) in layout.mmio_overlays.iter() {
+            writer.put_bool(attrs.smm);
+            writer.put_bool(attrs.secure);
+            put_entries(&mut writer, entries);
+        }
+        writer.put_u32(layout.mmio_segments.len() as u32);
+        for (segment, entries) in layout.mmio_segments.iter() {
+            writer.put_u16(segment.0);
+            put_entries(&mut writer, entries);
+        }
+        put_entries(&mut writer, &layout.sysreg);
+        put_entries(&mut writer, &layout.msr);
+        put_reservations(&mut writer, &layout.pio_reserved);
+        put_reservations(&mut writer, &layout.mmio_reserved);
+        self.write_section(SECTION_LAYOUT, &writer.into_inner())
+    }
+
+    /// Write the resources allocated to the device `id`.
+    pub fn write_resources(&mut self, id: &str, resources: &[Resource]) -> io::Result<()> {
+        let mut writer = StateWriter::new();
+        writer.put_bytes(id.as_bytes());
+        writer.put_u32(resources.len() as u32);
+        for resource in resources.iter() {
+            put_resource(&mut writer, resource);
+        }
+        self.write_section(SECTION_RESOURCES, &writer.into_inner())
+    }
+
+    /// Write the state of a device.
+    pub fn write_device(&mut self, state: &VersionedState) -> io::Result<()> {
+        let mut writer = StateWriter::new();
+        writer.put_bytes(state.id.as_bytes());
+        writer.put_u16(state.version.major);
+        writer.put_u16(state.version.minor);
+        writer.put_u16(state.version.patch);
+        writer.put_bytes(&state.data);
+        self.write_section(SECTION_DEVICE, &writer.into_inner())
+    }
+
+    /// Write the end section, and return the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_section(SECTION_END, &[])?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+/// The device model state read from a migration stream.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MigrationState {
+    /// Ranges registered on the buses, if the stream holds them.
+    pub layout: Option<IoLayout>,
+    /// Resources allocated to the devices, keyed by device ID.
+    pub resources: Vec<(String, Vec<Resource>)>,
+    /// States of the devices.
+    pub devices: Vec<VersionedState>,
+}
+
+/// Reads the device model state from a migration stream.
+pub struct MigrationReader<R: Read> {
+    inner: R,
+    format: u32,
+    done: bool,
+}
+
+impl<R: Read> MigrationReader<R> {
+    /// Start reading the migration stream from `inner`, by checking its header.
+    pub fn new(mut inner: R) -> Result<Self, Error> {
+        let mut magic = [0; 8];
+        inner.read_exact(&mut magic).map_err(Error::Io)?;
+        if magic != MIGRATION_MAGIC {
+            return Err(Error::BadMagic);
+        }
+        let mut version = [0; 4];
+        inner.read_exact(&mut version).map_err(Error::Io)?;
+        let version = u32::from_le_bytes(version);
+        if !(MIGRATION_MIN_FORMAT_VERSION..=MIGRATION_FORMAT_VERSION).contains(&version) {
+            return Err(Error::UnsupportedFormat(version));
+        }
+        Ok(MigrationReader {
+            inner,
+            format: version,
+            done: false,
+        })
+    }
+
+    /// Return the format version of the stream.
+    pub fn format_version(&self) -> u32 {
+        self.format
+    }
+
+    fn read_u32(&mut self) -> Result<u32, Error> {
+        let mut value = [0; 4];
+        self.inner
+            .read_exact(&mut value)
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::UnexpectedEof => Error::MissingEnd,
+                _ => Error::Io(e),
+            })?;
+        Ok(u32::from_le_bytes(value))
+    }
+
+    /// Read the next section, or return `None` once the end section was read.
+    pub fn next_section(&mut self) -> Result<Option<Section>, Error> {
+        if self.done {
+            return Ok(None);
+        }
+        let mut kind = [0];
+        self.inner
+            .read_exact(&mut kind)
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::UnexpectedEof => Error::MissingEnd,
+                _ => Error::Io(e),
+            })?;
+        let kind = kind[0];
+        if kind > SECTION_DEVICE {
+            return Err(Error::UnknownSection(kind));
+        }
+        let len = self.read_u32()? as usize;
+        let mut payload = Vec::new();
+        (&mut self.inner)
+            .take(len as u64)
+            .read_to_end(&mut payload)
+            .map_err(Error::Io)?;
+        if payload.len() != len {
+            return Err(Error::MissingEnd);
+        }
+        if self.read_u32()? != crc32(&payload) {
+            return Err(Error::Crc(kind));
+        }
+
+        let mut reader = StateReader::new(&payload);
+        let section = match kind {
+            SECTION_END => {
+                self.done = true;
+                return Ok(None);
+            }
+            SECTION_LAYOUT => Section::Layout(Self::decode_layout(&mut reader, self.format)?),
+            SECTION_RESOURCES => {
+                let id = Self::decode_string(&mut reader)?;
+                let resources = (0..reader.u32().map_err(Error::Decode)?)
+                    .map(|_| get_resource(&mut reader, self.format))
+                    .collect::<Result<_, _>>()
+                    .map_err(Error::Decode)?;
+                Section::Resources(id, resources)
+            }
+            _ => {
+                let id = Self::decode_string(&mut reader)?;
+                let mut version = || reader.u16().map_err(Error::Decode);
+                let version = Version::new(version()?, version()?, version()?);
+                let data = reader.bytes().map_err(Error::Decode)?.to_vec();
+                Section::Device(VersionedState { id, version, data })
+            }
+        };
+        Ok(Some(section))
+    }
+
+    fn decode_string(reader: &mut StateReader<'_>) -> Result<String, Error> {
+        let bytes = reader.bytes().map_err(Error::Decode)?;
+        String::from_utf8(bytes.to_vec())
+            .map_err(|_| Error::Decode(snapshot::Error::InvalidData("invalid ID".to_string())))
+    }
+
+    fn decode_layout(reader: &mut StateReader<'_>, format: u32) -> Result<IoLayout, Error> {
+        let decode = |reader: &mut StateReader<'_>| -> Result<IoLayout, snapshot::Error> {
+            let pio = get_entries(reader, format, PioAddress::new)?;
+            let mmio = get_entries(reader, format, |v| Some(MmioAddress(v)))?;
+            let mmio_overlays = (0..reader.u32()?)
+                .map(|_| {
+                    let attrs = AccessAttrs {
+                        smm: reader.bool()?,
+                        secure: reader.bool()?,
+                    };
+                    Ok((
+                        attrs,
+                        get_entries(reader, format, |v| Some(MmioAddress(v)))?,
+                    ))
+                })
+                .collect::<Result<_, snapshot::Error>>()?;
+            let segments = if format < FORMAT_MMIO_SEGMENTS {
+                0
+            } else {
+                reader.u32()?
+            };
+            let mmio_segments = (0..segments)
+                .map(|_| {
+                    let segment = MmioSegment(reader.u16()?);
+                    Ok((
+                        segment,
+                        get_entries(reader, format, |v| Some(MmioAddress(v)))?,
+                    ))
+                })
+                .collect::<Result<_, snapshot::Error>>()?;
+            let sysreg = get_entries(reader, format, |v| u32::try_from(v).ok().map(SysRegAddress))?;
+            let msr = get_entries(reader, format, |v| u32::try_from(v).ok().map(MsrAddress))?;
+            let (pio_reserved, mmio_reserved) = if format < FORMAT_RESERVATIONS {
+                (Vec::new(), Vec::new())
+            } else {
+                (
+                    get_reservations(reader, PioAddress::new)?,
+                    get_reservations(reader, |v| Some(MmioAddress(v)))?,
+                )
+            };
+            Ok(IoLayout {
+                pio,
+                mmio,
+                mmio_overlays,
+                mmio_segments,
+                sysreg,
+                msr,
+                pio_reserved,
+                mmio_reserved,
+            })
+        };
+        decode(reader).map_err(Error::Decode)
+    }
+
+    /// Read all the remaining sections, up to the end section.
+    pub fn read_all(mut self) -> Result<MigrationState, Error> {
+        let mut state = MigrationState::default();
+        while let Some(section) = self.next_section()? {
+            match section {
+                Section::Layout(layout) => state.layout = Some(layout),
+                Section::Resources(id, resources) => state.resources.push((id, resources)),
+                Section::Device(device) => state.devices.push(device),
+            }
+        }
+        Ok(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::bus::{MmioRange, PioRange};
+    use crate::device_manager::{IoManager, PioManager};
+    use crate::testing::EchoDevice;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn test_migration_stream() {
+        let mut io_mgr = IoManager::new();
+        let resources = vec![
+            Resource::PioAddressRange {
+                base: 0x3f8,
+                size: 8,
+            },
+            Resource::LegacyIrq(4),
+            Resource::MacAddresss("52:54:00:12:34:56".to_string()),
+        ];
+        io_mgr
+            .register_pio(
+                PioRange::new(PioAddress(0x3f8), 8).unwrap(),
+                Arc::new(EchoDevice::new()),
+            )
+            .unwrap();
+        io_mgr
+            .reserve_mmio(
+                MmioRange::new(MmioAddress(0xfffb_c000), 0x4000).unwrap(),
+                "kvm-tss",
+            )
+            .unwrap();
+        let device = VersionedState {
+            id: "serial0".to_string(),
+            version: Version::new(1, 2, 0),
+            data: vec![1, 2, 3],
+        };
+
+        let mut writer = MigrationWriter::new(Vec::new()).unwrap();
+        writer.write_layout(&io_mgr.layout()).unwrap();
+        writer.write_resources("serial0", &resources).unwrap();
+        writer.write_device(&device).unwrap();
+        let stream = writer.finish().unwrap();
+
+        let state = MigrationReader::new(stream.as_slice())
+            .unwrap()
+            .read_all()
+            .unwrap();
+        assert_eq!(state.layout, Some(io_mgr.layout()));
+        assert_eq!(state.resources, vec![("serial0".to_string(), resources)]);
+        assert_eq!(state.devices, vec![device]);
+
+        // Corrupted and truncated streams are rejected.
+        let mut corrupted = stream.clone();
+        corrupted[20] ^= 1;
+        assert!(matches!(
+            MigrationReader::new(corrupted.as_slice())
+                .unwrap()
+                .read_all(),
+            Err(Error::Crc(SECTION_LAYOUT))
+        ));
+        assert!(matches!(
+            MigrationReader::new(&stream[..stream.len() - 9])
+                .unwrap()
+                .read_all(),
+            Err(Error::MissingEnd)
+        ));
+        assert!(matches!(
+            MigrationReader::new(&stream[1..]),
+            Err(Error::BadMagic)
+        ));
+    }
+
+    // Return a stream with the format `version` and the `sections`, as `(kind, payload)`.
+    fn raw_stream(version: u32, sections: &[(u8, Vec<u8>)]) -> Vec<u8> {
+        let mut stream = MIGRATION_MAGIC.to_vec();
+        stream.extend_from_slice(&version.to_le_bytes());
+        for (kind, payload) in sections.iter() {
+            stream.push(*kind);
+            stream.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+            stream.extend_from_slice(payload);
+            stream.extend_from_slice(&crc32(payload).to_le_bytes());
+        }
+        stream.extend_from_slice(&[SECTION_END, 0, 0, 0, 0]);
+        stream.extend_from_slice(&crc32(&[]).to_le_bytes());
+        stream
+    }
+
+    #[test]
+    fn test_upgrade_format() {
+        // Layout of the first format: no decode enable, MMIO segments, or reservations.
+        let mut layout = StateWriter::new();
+        layout.put_u32(1);
+        layout.put_u64(0x3f8);
+        layout.put_u64(8);
+        layout.put_u64(0x1000);
+        layout.put_bool(false);
+        layout.put_bool(false);
+        for _ in 0..4 {
+            // The MMIO entries, overlays, system registers, and MSRs are empty.
+            layout.put_u32(0);
+        }
+        // The PIO resources had 16-bit ports.
+        let mut resources = StateWriter::new();
+        resources.put_bytes(b"serial0");
+        resources.put_u32(2);
+        resources.put_u8(0);
+        resources.put_u16(0x3f8);
+        resources.put_u16(8);
+        resources.put_u8(2);
+        resources.put_u32(4);
+        let stream = raw_stream(
+            1,
+            &[
+                (SECTION_LAYOUT, layout.into_inner()),
+                (SECTION_RESOURCES, resources.into_inner()),
+            ],
+        );
+
+        let reader = MigrationReader::new(stream.as_slice()).unwrap();
+        assert_eq!(reader.format_version(), 1);
+        let state = reader.read_all().unwrap();
+        let layout = state.layout.unwrap();
+        assert_eq!(
+            layout.pio,
+            [LayoutEntry {
+                range: PioRange::new(PioAddress(0x3f8), 8).unwrap(),
+                device_id: 0x1000,
+                draining: false,
+                shadow: false,
+                enabled: true,
+            }]
+        );
+        assert!(layout.mmio_segments.is_empty());
+        assert!(layout.pio_reserved.is_empty() && layout.mmio_reserved.is_empty());
+        assert_eq!(
+            state.resources,
+            [(
+                "serial0".to_string(),
+                vec![
+                    Resource::PioAddressRange {
+                        base: 0x3f8,
+                        size: 8
+                    },
+                    Resource::LegacyIrq(4)
+                ]
+            )]
+        );
+
+        for version in [0, MIGRATION_FORMAT_VERSION + 1].iter() {
+            assert!(matches!(
+                MigrationReader::new(raw_stream(*version, &[]).as_slice()),
+                Err(Error::UnsupportedFormat(v)) if v == *version
+            ));
+        }
+    }
+}
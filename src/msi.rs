@@ -0,0 +1,284 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Message Signaled Interrupts.
+//!
+//! Devices compose an [`MsiMessage`](struct.MsiMessage.html) (usually from the values the
+//! guest programmed in their MSI capability or MSI-X table) and fire it through an
+//! [`MsiSender`](trait.MsiSender.html). The message layout depends on the interrupt
+//! controller, so helpers are provided for the x86 local APICs and for the aarch64 GICv3
+//! ITS. With the `kvm` feature, [`KvmMsiRouting`](struct.KvmMsiRouting.html) provides
+//! senders which inject the messages through KVM irqfds.
+
+use std::io;
+
+/// Base of the x86 MSI address range.
+pub const X86_MSI_ADDRESS_BASE: u64 = 0xfee0_0000;
+
+/// Offset of the `GITS_TRANSLATER` register from the base of the GICv3 ITS.
+pub const GITS_TRANSLATER_OFFSET: u64 = 0x1_0040;
+
+/// Delivery mode of x86 MSIs.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum X86DeliveryMode {
+    /// Deliver the vector to the destination.
+    Fixed = 0,
+    /// Deliver the vector to the lowest priority destination.
+    LowestPriority = 1,
+    /// System Management Interrupt.
+    Smi = 2,
+    /// Non-Maskable Interrupt.
+    Nmi = 4,
+    /// INIT signal.
+    Init = 5,
+    /// External interrupt, from an 8259 compatible controller.
+    ExtInt = 7,
+}
+
+/// Message written by a device to signal an interrupt.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct MsiMessage {
+    /// Address of the write.
+    pub addr: u64,
+    /// Data of the write.
+    pub data: u32,
+    /// Requester ID of the device, which the GICv3 ITS needs to translate the message.
+    pub devid: Option<u32>,
+}
+
+impl MsiMessage {
+    /// Create a message from the address and data programmed by the guest.
+    pub fn new(addr: u64, data: u32) -> Self {
+        MsiMessage {
+            addr,
+            data,
+            devid: None,
+        }
+    }
+
+    /// Compose an x86 message delivering `vector` to the local APIC `dest_id`, in logical
+    /// destination mode if `logical` is set. IDs above 255 use the extended destination ID
+    /// bits of the address, as understood by KVM guests which support them.
+    pub fn x86(dest_id: u32, vector: u8, mode: X86DeliveryMode, logical: bool) -> Self {
+        let addr = X86_MSI_ADDRESS_BASE
+            | u64::from(dest_id & 0xff) << 12
+            | u64::from((dest_id >> 8) & 0x7f) << 5
+            | u64::from(logical) << 2;
+        let data = u32::from(vector) | (mode as u32) << 8;
+        Self::new(addr, data)
+    }
+
+    /// Compose a message which signals the event `event_id` of the device `devid` to the
+    /// GICv3 ITS at `its_base`.
+    pub fn its(its_base: u64, devid: u32, event_id: u32) -> Self {
+        MsiMessage {
+            addr: its_base + GITS_TRANSLATER_OFFSET,
+            data: event_id,
+            devid: Some(devid),
+        }
+    }
+
+    /// Return the destination local APIC ID of an x86 message.
+    pub fn x86_dest_id(&self) -> u32 {
+        ((self.addr >> 12) & 0xff) as u32 | (((self.addr >> 5) & 0x7f) as u32) << 8
+    }
+
+    /// Return the vector of an x86 message.
+    pub fn x86_vector(&self) -> u8 {
+        self.data as u8
+    }
+
+    /// Return the delivery mode of an x86 message, if valid.
+    pub fn x86_delivery_mode(&self) -> Option<X86DeliveryMode> {
+        match (self.data >> 8) & 0x7 {
+            0 => Some(X86DeliveryMode::Fixed),
+            1 => Some(X86DeliveryMode::LowestPriority),
+            2 => Some(X86DeliveryMode::Smi),
+            4 => Some(X86DeliveryMode::Nmi),
+            5 => Some(X86DeliveryMode::Init),
+            7 => Some(X86DeliveryMode::ExtInt),
+            _ => None,
+        }
+    }
+}
+
+/// Fires the MSIs of a device.
+pub trait MsiSender: Send + Sync {
+    /// Signal the interrupt described by `msg`.
+    fn send(&self, msg: MsiMessage) -> io::Result<()>;
+}
+
+impl<F: Fn(MsiMessage) -> io::Result<()> + Send + Sync> MsiSender for F {
+    fn send(&self, msg: MsiMessage) -> io::Result<()> {
+        self(msg)
+    }
+}
+
+#[cfg(feature = "kvm")]
+pub use self::kvm::{KvmMsiRouting, KvmMsiSender};
+
+#[cfg(feature = "kvm")]
+mod kvm {
+    use std::collections::BTreeMap;
+    use std::io;
+    use std::mem::size_of;
+    use std::sync::{Arc, Mutex};
+
+    use kvm_bindings::{
+        kvm_irq_routing, kvm_irq_routing_entry, KVM_IRQ_ROUTING_MSI, KVM_MSI_VALID_DEVID,
+    };
+    use kvm_ioctls::VmFd;
+    use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+
+    use super::{MsiMessage, MsiSender};
+
+    struct RoutingInner {
+        vm: Arc<VmFd>,
+        // The GSI routing table replaces the one set up by KVM, so it includes the routes
+        // which are not managed here (e.g. the ones of the legacy interrupt controllers).
+        base: Vec<kvm_irq_routing_entry>,
+        msi: BTreeMap<u32, kvm_irq_routing_entry>,
+    }
+
+    impl RoutingInner {
+        fn apply(&self) -> io::Result<()> {
+            let entries: Vec<_> = self.base.iter().chain(self.msi.values()).copied().collect();
+            // `kvm_irq_routing` ends with a flexible array of entries, so allocate enough
+            // headers to hold all of them.
+            let count = 1
+                + (entries.len() * size_of::<kvm_irq_routing_entry>())
+                    .div_ceil(size_of::<kvm_irq_routing>());
+            let mut routing: Vec<kvm_irq_routing> =
+                (0..count).map(|_| kvm_irq_routing::default()).collect();
+            routing[0].nr = entries.len() as u32;
+            // SAFETY: `routing` has room for `entries.len()` entries after the header.
+            unsafe {
+                routing[0]
+                    .entries
+                    .as_mut_slice(entries.len())
+                    .copy_from_slice(&entries);
+            }
+            self.vm
+                .set_gsi_routing(&routing[0])
+                .map_err(io::Error::from)
+        }
+    }
+
+    /// GSI routing table of a KVM VM, which hands out MSI senders backed by irqfds.
+    #[derive(Clone)]
+    pub struct KvmMsiRouting {
+        inner: Arc<Mutex<RoutingInner>>,
+    }
+
+    impl KvmMsiRouting {
+        /// Create the routing table of `vm`. `base` holds the routes which are not MSIs,
+        /// and are kept in the table.
+        pub fn new(vm: Arc<VmFd>, base: Vec<kvm_irq_routing_entry>) -> Self {
+            KvmMsiRouting {
+                inner: Arc::new(Mutex::new(RoutingInner {
+                    vm,
+                    base,
+                    msi: BTreeMap::new(),
+                })),
+            }
+        }
+
+        /// Return a sender which injects messages through `gsi`. Each MSI vector of a device
+        /// needs its own sender, since the route is updated when the message changes.
+        pub fn sender(&self, gsi: u32) -> io::Result<KvmMsiSender> {
+            let eventfd = EventFd::new(EFD_NONBLOCK)?;
+            self.inner
+                .lock()
+                .unwrap()
+                .vm
+                .register_irqfd(&eventfd, gsi)
+                .map_err(io::Error::from)?;
+            Ok(KvmMsiSender {
+                routing: self.clone(),
+                gsi,
+                eventfd,
+                route: Mutex::new(None),
+            })
+        }
+    }
+
+    /// Injects MSIs through a KVM irqfd.
+    pub struct KvmMsiSender {
+        routing: KvmMsiRouting,
+        gsi: u32,
+        eventfd: EventFd,
+        route: Mutex<Option<MsiMessage>>,
+    }
+
+    impl KvmMsiSender {
+        /// Return the GSI used by the sender.
+        pub fn gsi(&self) -> u32 {
+            self.gsi
+        }
+    }
+
+    impl MsiSender for KvmMsiSender {
+        fn send(&self, msg: MsiMessage) -> io::Result<()> {
+            let mut route = self.route.lock().unwrap();
+            if *route != Some(msg) {
+                let mut entry = kvm_irq_routing_entry {
+                    gsi: self.gsi,
+                    type_: KVM_IRQ_ROUTING_MSI,
+                    ..Default::default()
+                };
+                entry.u.msi.address_lo = msg.addr as u32;
+                entry.u.msi.address_hi = (msg.addr >> 32) as u32;
+                entry.u.msi.data = msg.data;
+                if let Some(devid) = msg.devid {
+                    entry.flags = KVM_MSI_VALID_DEVID;
+                    entry.u.msi.__bindgen_anon_1.devid = devid;
+                }
+                let mut routing = self.routing.inner.lock().unwrap();
+                routing.msi.insert(self.gsi, entry);
+                routing.apply()?;
+                *route = Some(msg);
+            }
+            self.eventfd.write(1)
+        }
+    }
+
+    impl Drop for KvmMsiSender {
+        fn drop(&mut self) {
+            let mut routing = self.routing.inner.lock().unwrap();
+            let _ = routing.vm.unregister_irqfd(&self.eventfd, self.gsi);
+            // The stale route is dropped from the table the next time it's applied.
+            routing.msi.remove(&self.gsi);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_msi_message() {
+        let msg = MsiMessage::x86(0x1ab, 0x30, X86DeliveryMode::Fixed, false);
+        assert_eq!(msg.addr, 0xfeea_b020);
+        assert_eq!(msg.data, 0x30);
+        assert_eq!(msg.x86_dest_id(), 0x1ab);
+        assert_eq!(msg.x86_vector(), 0x30);
+        assert_eq!(msg.x86_delivery_mode(), Some(X86DeliveryMode::Fixed));
+        let nmi = MsiMessage::x86(1, 0, X86DeliveryMode::Nmi, true);
+        assert_eq!((nmi.addr, nmi.data), (0xfee0_1004, 0x400));
+
+        let msg = MsiMessage::its(0x0808_0000, 0x10, 3);
+        assert_eq!(msg.addr, 0x0809_0040);
+        assert_eq!((msg.data, msg.devid), (3, Some(0x10)));
+
+        let sent = Mutex::new(Vec::new());
+        let sender = |msg| {
+            sent.lock().unwrap().push(msg);
+            Ok(())
+        };
+        sender.send(msg).unwrap();
+        assert_eq!(*sent.lock().unwrap(), vec![msg]);
+    }
+}
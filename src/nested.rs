@@ -0,0 +1,236 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Mounting a whole `IoManager` under a window of another one.
+//!
+//! Some platforms are easier to describe as a machine within the machine: a sub-platform
+//! emulated for the firmware, or the chassis of a multi-chassis model, each with its own
+//! devices laid out at their usual addresses. A [`NestedManager`](struct.NestedManager.html)
+//! wraps the `IoManager` of such a platform and implements `DeviceMmio` and `DevicePio`, so
+//! it can be registered with a range of the parent manager. Accesses to the range are
+//! dispatched to the nested manager, at the offset within the range added to the base of the
+//! nested address space. Accesses which don't reach any device of the nested manager read as
+//! all ones, and writes to them are dropped.
+//!
+//! `IoManager` can't implement the device traits itself, since their methods would clash
+//! with the ones of `PioManager` and `MmioManager`.
+
+use crate::bus::{MmioAddress, MmioOffset, PioAddress, PioAddressValue, PioOffset};
+use crate::device_manager::{IoManager, MmioManager, PioManager};
+use crate::poison;
+use crate::{DeviceMmio, DevicePio, IoAccess};
+
+/// Device dispatching its accesses to a nested `IoManager`.
+pub struct NestedManager {
+    manager: IoManager,
+    mmio_base: u64,
+    pio_base: PioAddressValue,
+}
+
+impl NestedManager {
+    /// Wrap `manager`. The start of the parent ranges maps to address zero of the nested
+    /// address spaces.
+    pub fn new(manager: IoManager) -> Self {
+        NestedManager {
+            manager,
+            mmio_base: 0,
+            pio_base: 0,
+        }
+    }
+
+    /// Map the start of the parent MMIO range to `base` in the nested manager.
+    pub fn with_mmio_base(mut self, base: u64) -> Self {
+        self.mmio_base = base;
+        self
+    }
+
+    /// Map the start of the parent PIO range to `base` in the nested manager.
+    pub fn with_pio_base(mut self, base: PioAddressValue) -> Self {
+        self.pio_base = base;
+        self
+    }
+
+    /// Return the nested manager.
+    pub fn manager(&self) -> &IoManager {
+        &self.manager
+    }
+
+    fn mmio_address(&self, offset: MmioOffset) -> MmioAddress {
+        MmioAddress(self.mmio_base.wrapping_add(offset.raw()))
+    }
+
+    fn pio_address(&self, offset: PioOffset) -> PioAddress {
+        PioAddress(self.pio_base.wrapping_add(offset.raw()))
+    }
+}
+
+impl DeviceMmio for NestedManager {
+    fn mmio_read(&self, base: MmioAddress, offset: MmioOffset, data: &mut [u8]) {
+        self.mmio_read_with(IoAccess::default(), base, offset, data)
+    }
+
+    fn mmio_write(&self, base: MmioAddress, offset: MmioOffset, data: &[u8]) {
+        self.mmio_write_with(IoAccess::default(), base, offset, data)
+    }
+
+    fn mmio_read_with(
+        &self,
+        access: IoAccess,
+        _base: MmioAddress,
+        offset: MmioOffset,
+        data: &mut [u8],
+    ) {
+        let addr = self.mmio_address(offset);
+        if MmioManager::mmio_read_with(&self.manager, addr, access, data).is_err() {
+            poison::fail_read(data);
+        }
+    }
+
+    fn mmio_write_with(
+        &self,
+        access: IoAccess,
+        _base: MmioAddress,
+        offset: MmioOffset,
+        data: &[u8],
+    ) {
+        let addr = self.mmio_address(offset);
+        let _ = MmioManager::mmio_write_with(&self.manager, addr, access, data);
+    }
+}
+
+impl DevicePio for NestedManager {
+    fn pio_read(&self, base: PioAddress, offset: PioOffset, data: &mut [u8]) {
+        self.pio_read_with(IoAccess::default(), base, offset, data)
+    }
+
+    fn pio_write(&self, base: PioAddress, offset: PioOffset, data: &[u8]) {
+        self.pio_write_with(IoAccess::default(), base, offset, data)
+    }
+
+    fn pio_read_with(
+        &self,
+        access: IoAccess,
+        _base: PioAddress,
+        offset: PioOffset,
+        data: &mut [u8],
+    ) {
+        let addr = self.pio_address(offset);
+        if PioManager::pio_read_with(&self.manager, addr, access, data).is_err() {
+            poison::fail_read(data);
+        }
+    }
+
+    fn pio_write_with(&self, access: IoAccess, _base: PioAddress, offset: PioOffset, data: &[u8]) {
+        let addr = self.pio_address(offset);
+        let _ = PioManager::pio_write_with(&self.manager, addr, access, data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    use crate::bus::{MmioRange, PioRange};
+
+    // Records the base and offset of the accesses, and the vCPU performing them.
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<(u64, u64, Option<u32>)>>);
+
+    impl DeviceMmio for Recorder {
+        fn mmio_read(&self, base: MmioAddress, offset: MmioOffset, data: &mut [u8]) {
+            self.mmio_read_with(IoAccess::default(), base, offset, data)
+        }
+
+        fn mmio_write(&self, _base: MmioAddress, _offset: MmioOffset, _data: &[u8]) {}
+
+        fn mmio_read_with(
+            &self,
+            access: IoAccess,
+            base: MmioAddress,
+            offset: MmioOffset,
+            data: &mut [u8],
+        ) {
+            data.iter_mut().for_each(|byte| *byte = 0x5a);
+            self.0
+                .lock()
+                .unwrap()
+                .push((base.0, offset.raw(), access.vcpu_id));
+        }
+    }
+
+    impl DevicePio for Recorder {
+        fn pio_read(&self, base: PioAddress, offset: PioOffset, data: &mut [u8]) {
+            data.iter_mut().for_each(|byte| *byte = 0xa5);
+            self.0
+                .lock()
+                .unwrap()
+                .push((u64::from(base.0), u64::from(offset), None));
+        }
+
+        fn pio_write(&self, _base: PioAddress, _offset: PioOffset, _data: &[u8]) {}
+    }
+
+    #[test]
+    fn test_nested_manager() {
+        let recorder = Arc::new(Recorder::default());
+        let mut child = IoManager::new();
+        child
+            .register_mmio(
+                MmioRange::new(MmioAddress(0x8000_1000), 0x100).unwrap(),
+                recorder.clone(),
+            )
+            .unwrap();
+        child
+            .register_pio(
+                PioRange::new(PioAddress(0x60), 0x4).unwrap(),
+                recorder.clone(),
+            )
+            .unwrap();
+
+        let nested = Arc::new(
+            NestedManager::new(child)
+                .with_mmio_base(0x8000_0000)
+                .with_pio_base(0x40),
+        );
+        assert!(nested
+            .manager()
+            .mmio_device(MmioAddress(0x8000_1000))
+            .is_some());
+        let mut parent = IoManager::new();
+        parent
+            .register_mmio(
+                MmioRange::new(MmioAddress(0x1_0000_0000), 0x1_0000).unwrap(),
+                nested.clone(),
+            )
+            .unwrap();
+        parent
+            .register_pio(PioRange::new(PioAddress(0x1000), 0x100).unwrap(), nested)
+            .unwrap();
+
+        // The access context reaches the nested device, at the translated address.
+        let mut data = [0; 4];
+        MmioManager::mmio_read_with(
+            &parent,
+            MmioAddress(0x1_0000_1008),
+            IoAccess::new(3, 4),
+            &mut data,
+        )
+        .unwrap();
+        assert_eq!(data, [0x5a; 4]);
+        MmioManager::mmio_read(&parent, MmioAddress(0x1_0000_2000), &mut data).unwrap();
+        assert_eq!(data, [0xff; 4]);
+
+        let mut port = [0; 1];
+        PioManager::pio_read(&parent, PioAddress(0x1022), &mut port).unwrap();
+        assert_eq!(port, [0xa5]);
+        PioManager::pio_read(&parent, PioAddress(0x1000), &mut port).unwrap();
+        assert_eq!(port, [0xff]);
+
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            vec![(0x8000_1000, 8, Some(3)), (0x60, 2, None)]
+        );
+    }
+}
@@ -0,0 +1,610 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::bus::{PioAddress, PioAddressValue, PioOffset};
+use crate::handle::IoManagerHandle;
+use crate::pci::{BarReprogrammingParams, Error, PciDevice};
+use crate::MutDevicePio;
+
+/// Number of device slots on a PCI bus.
+pub const NUM_DEVICE_SLOTS: u8 = 32;
+
+/// Number of functions per PCI device.
+pub const NUM_FUNCTIONS: u8 = 8;
+
+// The header type register, and its multifunction bit.
+const HEADER_TYPE_REG: usize = 3;
+const HEADER_TYPE_MULTIFUNCTION: u32 = 0x0080_0000;
+
+/// Base of the PIO range used by configuration access mechanism #1.
+pub const PCI_CONFIG_IO_PORT: PioAddressValue = 0xcf8;
+
+/// Size of the PIO range used by configuration access mechanism #1.
+pub const PCI_CONFIG_IO_PORT_SIZE: PioAddressValue = 0x8;
+
+/// Identifies a PCI function by its bus, device and function numbers.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct PciBdf {
+    bus: u8,
+    device: u8,
+    function: u8,
+}
+
+impl PciBdf {
+    /// Create a new identifier, checking that the device and function numbers are valid.
+    pub fn new(bus: u8, device: u8, function: u8) -> Result<Self, Error> {
+        if device >= NUM_DEVICE_SLOTS || function >= NUM_FUNCTIONS {
+            return Err(Error::InvalidBdf(bus, device, function));
+        }
+        Ok(PciBdf {
+            bus,
+            device,
+            function,
+        })
+    }
+
+    /// Return the bus number.
+    pub fn bus(&self) -> u8 {
+        self.bus
+    }
+
+    /// Return the device number.
+    pub fn device(&self) -> u8 {
+        self.device
+    }
+
+    /// Return the function number.
+    pub fn function(&self) -> u8 {
+        self.function
+    }
+}
+
+/// A PCI root bus, which routes configuration space accesses to the functions plugged into
+/// its slots.
+///
+/// Each function is a separate `PciDevice`, so a multifunction device is built by adding
+/// several functions to the same slot, and functions can be added and removed at runtime.
+/// The multifunction bit of the header type register is reported on behalf of the slots
+/// with more than one function. When ARI (Alternative Routing-ID Interpretation) is enabled,
+/// the bus holds a single device, whose function numbers span the 8 bits otherwise split
+/// between the device and function numbers. The functions expose the ARI capability
+/// themselves.
+///
+/// Guest writes that move a BAR relocate the range of the BAR automatically once the bus is
+/// connected to the manager dispatching the accesses, with
+/// [`set_io_manager`](struct.PciBus.html#method.set_io_manager). Otherwise, the moves are
+/// recorded, and can be retrieved with
+/// [`take_bar_reprogramming`](struct.PciBus.html#method.take_bar_reprogramming) and passed
+/// to [`IoManager::relocate_bars`](../device_manager/struct.IoManager.html#method.relocate_bars)
+/// once the VM exit which triggered them has been handled.
+#[derive(Default)]
+pub struct PciBus {
+    number: u8,
+    ari: bool,
+    // Keyed by the routing ID of the function within the bus (`device << 3 | function`, or
+    // the ARI function number).
+    functions: BTreeMap<u8, Arc<Mutex<dyn PciDevice>>>,
+    bar_reprogramming: Vec<BarReprogrammingParams>,
+    io_manager: Option<IoManagerHandle>,
+    // Number of BAR moves the manager couldn't apply.
+    failed_relocations: Arc<AtomicU64>,
+}
+
+impl PciBus {
+    /// Create an empty root bus with number 0.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Return the number of the bus.
+    pub fn number(&self) -> u8 {
+        self.number
+    }
+
+    /// Enable or disable ARI. This can only be done while the bus is empty.
+    pub fn set_ari(&mut self, enabled: bool) -> Result<(), Error> {
+        if let Some(devfn) = self.functions.keys().next() {
+            return Err(Error::SlotInUse(devfn >> 3));
+        }
+        self.ari = enabled;
+        Ok(())
+    }
+
+    /// Relocate the BARs moved by the guest on the buses of the manager shared through
+    /// `handle`. Each move is queued as a change of the manager, applied together with the
+    /// other pending changes by `IoManagerOwner::apply_pending`. A move which fails (e.g.
+    /// because the new range overlaps another device) leaves the range at its previous
+    /// address, and the BAR register is written back to match it. The failures are counted
+    /// by [`failed_bar_relocations`](struct.PciBus.html#method.failed_bar_relocations), and
+    /// logged when the `tracing` feature is enabled.
+    pub fn set_io_manager(&mut self, handle: IoManagerHandle) {
+        self.io_manager = Some(handle);
+    }
+
+    /// Return the number of BAR moves which the manager connected with `set_io_manager`
+    /// failed to apply.
+    pub fn failed_bar_relocations(&self) -> u64 {
+        self.failed_relocations.load(Ordering::Relaxed)
+    }
+
+    /// Return whether ARI is enabled.
+    pub fn ari(&self) -> bool {
+        self.ari
+    }
+
+    fn devfn(&self, device_num: u8, function: u8) -> Result<u8, Error> {
+        if self.ari {
+            if device_num != 0 {
+                return Err(Error::InvalidBdf(self.number, device_num, function));
+            }
+            return Ok(function);
+        }
+        if device_num >= NUM_DEVICE_SLOTS || function >= NUM_FUNCTIONS {
+            return Err(Error::InvalidBdf(self.number, device_num, function));
+        }
+        Ok(device_num << 3 | function)
+    }
+
+    // Return the routing IDs of the functions sharing a slot with the function `devfn`.
+    fn slot_functions(&self, devfn: u8) -> impl Iterator<Item = &u8> {
+        let slot = if self.ari {
+            0..=255
+        } else {
+            devfn & !7..=devfn | 7
+        };
+        self.functions.range(slot).map(|(devfn, _)| devfn)
+    }
+
+    /// Plug `device` into the slot with number `device_num`, as its function 0.
+    pub fn add_device(
+        &mut self,
+        device_num: u8,
+        device: Arc<Mutex<dyn PciDevice>>,
+    ) -> Result<(), Error> {
+        let devfn = self.devfn(device_num, 0)?;
+        if self.slot_functions(devfn).next().is_some() {
+            return Err(Error::SlotInUse(device_num));
+        }
+        self.functions.insert(devfn, device);
+        Ok(())
+    }
+
+    /// Remove the device plugged into the slot with number `device_num`, with all its
+    /// functions. Returns its function 0.
+    pub fn remove_device(&mut self, device_num: u8) -> Option<Arc<Mutex<dyn PciDevice>>> {
+        let devfn = self.devfn(device_num, 0).ok()?;
+        let slot: Vec<u8> = self.slot_functions(devfn).copied().collect();
+        let mut device = None;
+        for function in slot {
+            let removed = self.functions.remove(&function);
+            if function == devfn {
+                device = removed;
+            }
+        }
+        device
+    }
+
+    /// Return the function 0 of the device plugged into the slot with number `device_num`.
+    pub fn device(&self, device_num: u8) -> Option<&Arc<Mutex<dyn PciDevice>>> {
+        self.function_device(device_num, 0)
+    }
+
+    /// Add `device` as the function `function` of the slot with number `device_num`. With
+    /// ARI enabled, `device_num` must be 0 and `function` can be any 8-bit value.
+    pub fn add_function(
+        &mut self,
+        device_num: u8,
+        function: u8,
+        device: Arc<Mutex<dyn PciDevice>>,
+    ) -> Result<(), Error> {
+        let devfn = self.devfn(device_num, function)?;
+        if self.functions.contains_key(&devfn) {
+            return Err(Error::FunctionInUse(device_num, function));
+        }
+        self.functions.insert(devfn, device);
+        Ok(())
+    }
+
+    /// Remove the function `function` of the slot with number `device_num`.
+    pub fn remove_function(
+        &mut self,
+        device_num: u8,
+        function: u8,
+    ) -> Option<Arc<Mutex<dyn PciDevice>>> {
+        let devfn = self.devfn(device_num, function).ok()?;
+        self.functions.remove(&devfn)
+    }
+
+    /// Return the function `function` of the slot with number `device_num`.
+    pub fn function_device(
+        &self,
+        device_num: u8,
+        function: u8,
+    ) -> Option<&Arc<Mutex<dyn PciDevice>>> {
+        let devfn = self.devfn(device_num, function).ok()?;
+        self.functions.get(&devfn)
+    }
+
+    /// Return the numbers of the functions present in the slot with number `device_num`.
+    pub fn functions(&self, device_num: u8) -> Vec<u8> {
+        let devfn = match self.devfn(device_num, 0) {
+            Ok(devfn) => devfn,
+            Err(_) => return Vec::new(),
+        };
+        let mask = if self.ari { 0xff } else { 0x7 };
+        self.slot_functions(devfn)
+            .map(|devfn| devfn & mask)
+            .collect()
+    }
+
+    // In ARI mode, the device and function numbers decoded by the configuration access
+    // mechanisms form the 8-bit function number, which is also the routing ID.
+    fn function(&self, bdf: PciBdf) -> Option<(u8, &Arc<Mutex<dyn PciDevice>>)> {
+        if bdf.bus != self.number {
+            return None;
+        }
+        let devfn = bdf.device << 3 | bdf.function;
+        self.functions.get(&devfn).map(|device| (devfn, device))
+    }
+
+    /// Read the configuration register with index `reg_idx` of the function identified by
+    /// `bdf`. Accesses to functions which are not present read as all ones.
+    pub fn config_read(&self, bdf: PciBdf, reg_idx: usize) -> u32 {
+        let (devfn, device) = match self.function(bdf) {
+            Some(function) => function,
+            None => return 0xffff_ffff,
+        };
+        let mut value = device.lock().unwrap().read_config_register(reg_idx);
+        if reg_idx == HEADER_TYPE_REG && self.slot_functions(devfn).nth(1).is_some() {
+            value |= HEADER_TYPE_MULTIFUNCTION;
+        }
+        value
+    }
+
+    /// Write `data` at `offset` within the configuration register with index `reg_idx` of the
+    /// function identified by `bdf`.
+    pub fn config_write(&mut self, bdf: PciBdf, reg_idx: usize, offset: u64, data: &[u8]) {
+        let (device, params) = match self.function(bdf) {
+            Some((_, device)) => (
+                device.clone(),
+                device
+                    .lock()
+                    .unwrap()
+                    .write_config_register(reg_idx, offset, data),
+            ),
+            None => return,
+        };
+
+        if let Some(params) = params {
+            match self.io_manager.as_ref() {
+                Some(handle) => {
+                    let failed_relocations = self.failed_relocations.clone();
+                    // The guest isn't told about failed moves, so nobody waits for them.
+                    let _ = handle.mutate(Box::new(move |io_mgr| {
+                        let result = io_mgr.relocate_bars(&[params]);
+                        if let Err(_e) = result.as_ref() {
+                            #[cfg(feature = "tracing")]
+                            tracing::warn!(
+                                old_base = params.old_base,
+                                new_base = params.new_base,
+                                error = %_e,
+                                "failed to relocate BAR"
+                            );
+                            failed_relocations.fetch_add(1, Ordering::Relaxed);
+                            device
+                                .lock()
+                                .unwrap()
+                                .config_mut()
+                                .revert_bar_reprogramming(&params);
+                        }
+                        result
+                    }));
+                }
+                None => self.bar_reprogramming.push(params),
+            }
+        }
+    }
+
+    /// Return (and forget about) the BAR moves performed by the guest so far, while the bus
+    /// isn't connected to a manager.
+    pub fn take_bar_reprogramming(&mut self) -> Vec<BarReprogrammingParams> {
+        std::mem::take(&mut self.bar_reprogramming)
+    }
+}
+
+const CONFIG_ADDRESS_ENABLE: u32 = 0x8000_0000;
+
+/// Emulates the PCI configuration access mechanism #1 (the `0xcf8` address port and `0xcfc`
+/// data port), forwarding accesses to a `PciBus`.
+pub struct PciConfigIo {
+    config_address: u32,
+    pci_bus: Arc<Mutex<PciBus>>,
+}
+
+impl PciConfigIo {
+    /// Create a new object which forwards configuration accesses to `pci_bus`.
+    pub fn new(pci_bus: Arc<Mutex<PciBus>>) -> Self {
+        PciConfigIo {
+            config_address: 0,
+            pci_bus,
+        }
+    }
+
+    // Decode the function and register targeted by the current value of the address port.
+    fn target(&self) -> Option<(PciBdf, usize)> {
+        if self.config_address & CONFIG_ADDRESS_ENABLE == 0 {
+            return None;
+        }
+        let bus = (self.config_address >> 16) as u8;
+        let device = ((self.config_address >> 11) & 0x1f) as u8;
+        let function = ((self.config_address >> 8) & 0x7) as u8;
+        let reg_idx = ((self.config_address >> 2) & 0x3f) as usize;
+        PciBdf::new(bus, device, function)
+            .ok()
+            .map(|bdf| (bdf, reg_idx))
+    }
+}
+
+impl MutDevicePio for PciConfigIo {
+    fn pio_read(&mut self, _base: PioAddress, offset: PioOffset, data: &mut [u8]) {
+        let offset = offset.as_usize();
+        let value = match offset {
+            0..=3 => self.config_address,
+            _ => self
+                .target()
+                .map(|(bdf, reg_idx)| self.pci_bus.lock().unwrap().config_read(bdf, reg_idx))
+                .unwrap_or(0xffff_ffff),
+        };
+
+        let bytes = value.to_le_bytes();
+        let start = offset % 4;
+        for (idx, byte) in data.iter_mut().enumerate() {
+            *byte = bytes.get(start + idx).copied().unwrap_or(0xff);
+        }
+    }
+
+    fn pio_write(&mut self, _base: PioAddress, offset: PioOffset, data: &[u8]) {
+        match offset.raw() {
+            // Only full dword writes update the address port.
+            0 if data.len() == 4 => {
+                self.config_address = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+            }
+            4..=7 => {
+                if let Some((bdf, reg_idx)) = self.target() {
+                    self.pci_bus.lock().unwrap().config_write(
+                        bdf,
+                        reg_idx,
+                        u64::from(offset - 4),
+                        data,
+                    );
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::pci::{PciBarConfiguration, PciBarRegionType, PciClassCode, PciConfiguration};
+
+    struct TestDevice {
+        config: PciConfiguration,
+    }
+
+    impl PciDevice for TestDevice {
+        fn config(&self) -> &PciConfiguration {
+            &self.config
+        }
+
+        fn config_mut(&mut self) -> &mut PciConfiguration {
+            &mut self.config
+        }
+    }
+
+    fn test_device() -> Arc<Mutex<TestDevice>> {
+        let mut config = PciConfiguration::new(
+            0x1af4,
+            0x1000,
+            PciClassCode {
+                class: 0x02,
+                subclass: 0x00,
+                prog_if: 0x00,
+            },
+        );
+        config
+            .add_bar(
+                &PciBarConfiguration::new(0, 0x1000, PciBarRegionType::Memory32BitRegion, false)
+                    .with_address(0xc000_0000),
+            )
+            .unwrap();
+        Arc::new(Mutex::new(TestDevice { config }))
+    }
+
+    #[test]
+    fn test_pci_bus() {
+        let mut bus = PciBus::new();
+        let bdf = PciBdf::new(0, 3, 0).unwrap();
+
+        assert_eq!(PciBdf::new(0, 32, 0), Err(Error::InvalidBdf(0, 32, 0)));
+        assert_eq!(PciBdf::new(0, 0, 8), Err(Error::InvalidBdf(0, 0, 8)));
+        assert_eq!(bus.config_read(bdf, 0), 0xffff_ffff);
+
+        bus.add_device(3, test_device()).unwrap();
+        assert_eq!(
+            bus.add_device(3, test_device()).unwrap_err(),
+            Error::SlotInUse(3)
+        );
+        assert_eq!(
+            bus.add_device(NUM_DEVICE_SLOTS, test_device()).unwrap_err(),
+            Error::InvalidBdf(0, NUM_DEVICE_SLOTS, 0)
+        );
+
+        assert_eq!(bus.config_read(bdf, 0), 0x1000_1af4);
+        // Other functions of the device and other buses are not present.
+        assert_eq!(
+            bus.config_read(PciBdf::new(0, 3, 1).unwrap(), 0),
+            0xffff_ffff
+        );
+        assert_eq!(
+            bus.config_read(PciBdf::new(1, 3, 0).unwrap(), 0),
+            0xffff_ffff
+        );
+
+        bus.config_write(bdf, 4, 0, &0xd000_0000u32.to_le_bytes());
+        let moves = bus.take_bar_reprogramming();
+        assert_eq!(moves.len(), 1);
+        assert_eq!(moves[0].old_base, 0xc000_0000);
+        assert_eq!(moves[0].new_base, 0xd000_0000);
+        assert!(bus.take_bar_reprogramming().is_empty());
+
+        assert!(bus.remove_device(3).is_some());
+        assert!(bus.device(3).is_none());
+    }
+
+    #[test]
+    fn test_bar_relocation() {
+        use crate::bus::{MmioAddress, MmioRange};
+        use crate::device_manager::MmioManager;
+        use crate::handle::IoManagerOwner;
+        use crate::testing::EchoDevice;
+
+        let mut owner = IoManagerOwner::<MmioAddress>::new();
+        let bar = MmioRange::new(MmioAddress(0xc000_0000), 0x1000).unwrap();
+        let pending = owner
+            .handle()
+            .register_mmio(bar, Arc::new(EchoDevice::new()));
+        owner.apply_pending();
+        pending.wait().unwrap().unwrap();
+
+        let mut bus = PciBus::new();
+        let bdf = PciBdf::new(0, 3, 0).unwrap();
+        bus.add_device(3, test_device()).unwrap();
+        bus.set_io_manager(owner.handle());
+
+        // The move is applied with the other changes of the manager.
+        bus.config_write(bdf, 4, 0, &0xd000_0000u32.to_le_bytes());
+        assert!(bus.take_bar_reprogramming().is_empty());
+        assert!(owner.manager().mmio_device(bar.base()).is_some());
+        assert_eq!(owner.apply_pending(), 1);
+        assert!(owner.manager().mmio_device(bar.base()).is_none());
+        assert!(owner
+            .manager()
+            .mmio_device(MmioAddress(0xd000_0000))
+            .is_some());
+        assert_eq!(bus.failed_bar_relocations(), 0);
+
+        // A move onto another device fails, and the BAR reports the range still mapped.
+        let other = MmioRange::new(MmioAddress(0xe000_0000), 0x1000).unwrap();
+        let pending = owner
+            .handle()
+            .register_mmio(other, Arc::new(EchoDevice::new()));
+        owner.apply_pending();
+        pending.wait().unwrap().unwrap();
+        bus.config_write(bdf, 4, 0, &0xe000_0000u32.to_le_bytes());
+        assert_eq!(bus.config_read(bdf, 4) & !0xf, 0xe000_0000);
+        assert_eq!(owner.apply_pending(), 1);
+        assert_eq!(bus.failed_bar_relocations(), 1);
+        assert_eq!(bus.config_read(bdf, 4) & !0xf, 0xd000_0000);
+        assert!(owner
+            .manager()
+            .mmio_device(MmioAddress(0xd000_0000))
+            .is_some());
+
+        // The guest can still move the BAR afterwards.
+        bus.config_write(bdf, 4, 0, &0xc000_0000u32.to_le_bytes());
+        assert_eq!(owner.apply_pending(), 1);
+        assert!(owner.manager().mmio_device(bar.base()).is_some());
+    }
+
+    #[test]
+    fn test_multifunction() {
+        let mut bus = PciBus::new();
+        let bdf = PciBdf::new(0, 3, 0).unwrap();
+        bus.add_device(3, test_device()).unwrap();
+        assert_eq!(
+            bus.config_read(bdf, HEADER_TYPE_REG) & HEADER_TYPE_MULTIFUNCTION,
+            0
+        );
+
+        bus.add_function(3, 2, test_device()).unwrap();
+        assert_eq!(
+            bus.add_function(3, 2, test_device()).unwrap_err(),
+            Error::FunctionInUse(3, 2)
+        );
+        assert_eq!(
+            bus.add_function(3, 8, test_device()).unwrap_err(),
+            Error::InvalidBdf(0, 3, 8)
+        );
+        assert_eq!(bus.functions(3), vec![0, 2]);
+        assert_ne!(
+            bus.config_read(bdf, HEADER_TYPE_REG) & HEADER_TYPE_MULTIFUNCTION,
+            0
+        );
+        assert_eq!(
+            bus.config_read(PciBdf::new(0, 3, 2).unwrap(), 0),
+            0x1000_1af4
+        );
+        assert!(bus.remove_function(3, 2).is_some());
+        assert_eq!(
+            bus.config_read(bdf, HEADER_TYPE_REG) & HEADER_TYPE_MULTIFUNCTION,
+            0
+        );
+        assert_eq!(bus.set_ari(true).unwrap_err(), Error::SlotInUse(3));
+
+        // With ARI, function 9 of device 0 is reached as device 1, function 1.
+        let mut bus = PciBus::new();
+        bus.set_ari(true).unwrap();
+        bus.add_device(0, test_device()).unwrap();
+        bus.add_function(0, 9, test_device()).unwrap();
+        assert_eq!(
+            bus.add_function(1, 0, test_device()).unwrap_err(),
+            Error::InvalidBdf(0, 1, 0)
+        );
+        assert_eq!(bus.functions(0), vec![0, 9]);
+        assert_eq!(
+            bus.config_read(PciBdf::new(0, 1, 1).unwrap(), 0),
+            0x1000_1af4
+        );
+        assert!(bus.remove_device(0).is_some());
+        assert!(bus.functions(0).is_empty());
+    }
+
+    #[test]
+    fn test_config_io() {
+        let bus = Arc::new(Mutex::new(PciBus::new()));
+        bus.lock().unwrap().add_device(1, test_device()).unwrap();
+        let mut config_io = PciConfigIo::new(bus.clone());
+        let base = PioAddress(PCI_CONFIG_IO_PORT);
+
+        let mut data = [0u8; 4];
+        // Accesses are ignored while the enable bit is not set.
+        config_io.pio_write(base, PioOffset(0), &0x0000_0800u32.to_le_bytes());
+        config_io.pio_read(base, PioOffset(4), &mut data);
+        assert_eq!(data, [0xff; 4]);
+
+        // Device 1, function 0, register 0.
+        config_io.pio_write(base, PioOffset(0), &0x8000_0800u32.to_le_bytes());
+        config_io.pio_read(base, PioOffset(0), &mut data);
+        assert_eq!(u32::from_le_bytes(data), 0x8000_0800);
+        config_io.pio_read(base, PioOffset(4), &mut data);
+        assert_eq!(u32::from_le_bytes(data), 0x1000_1af4);
+
+        let mut word = [0u8; 2];
+        config_io.pio_read(base, PioOffset(6), &mut word);
+        assert_eq!(u16::from_le_bytes(word), 0x1000);
+
+        // Program BAR0 through the data port.
+        config_io.pio_write(base, PioOffset(0), &0x8000_0810u32.to_le_bytes());
+        config_io.pio_write(base, PioOffset(4), &0xe000_0000u32.to_le_bytes());
+        config_io.pio_read(base, PioOffset(4), &mut data);
+        assert_eq!(u32::from_le_bytes(data), 0xe000_0000);
+        assert_eq!(bus.lock().unwrap().take_bar_reprogramming().len(), 1);
+    }
+}
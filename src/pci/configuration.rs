@@ -0,0 +1,781 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+use crate::bus::PioAddressValue;
+use crate::pci::Error;
+use crate::resources::Resource;
+
+/// Number of 32-bit registers in the configuration space of a conventional PCI function.
+pub const NUM_CONFIGURATION_REGISTERS: usize = 64;
+
+/// Number of BAR registers in a type 0 header.
+pub const NUM_BAR_REGS: usize = 6;
+
+const COMMAND_REG: usize = 1;
+const STATUS_REG_CAPABILITIES_USED_MASK: u32 = 0x0010_0000;
+const CLASS_REG: usize = 2;
+const HEADER_TYPE_REG: usize = 3;
+const BAR0_REG: usize = 4;
+const SUBSYSTEM_REG: usize = 11;
+const CAPABILITY_LIST_HEAD_REG: usize = 13;
+const INTERRUPT_LINE_PIN_REG: usize = 15;
+
+const FIRST_CAPABILITY_OFFSET: usize = 0x40;
+const CAPABILITY_MAX_OFFSET: usize = NUM_CONFIGURATION_REGISTERS * 4;
+
+const BAR_IO_ADDR_MASK: u32 = 0xffff_fffc;
+const BAR_IO_MIN_SIZE: u64 = 4;
+const BAR_MEM_ADDR_MASK: u32 = 0xffff_fff0;
+const BAR_MEM_MIN_SIZE: u64 = 16;
+const BAR_IO_BIT: u32 = 0x0000_0001;
+const BAR_MEM_64BIT: u32 = 0x0000_0004;
+
+/// Value written by the guest to a BAR register when probing the size of the region.
+const BAR_SIZING_VALUE: u32 = 0xffff_ffff;
+
+/// Identifies the class of a PCI function (base class, sub-class, and programming interface).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PciClassCode {
+    /// Base class code.
+    pub class: u8,
+    /// Sub-class code.
+    pub subclass: u8,
+    /// Register-level programming interface.
+    pub prog_if: u8,
+}
+
+/// Type of the address region described by a BAR.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PciBarRegionType {
+    /// Memory region located below 4 GiB.
+    Memory32BitRegion,
+    /// Memory region that can be located anywhere in the 64-bit address space.
+    Memory64BitRegion,
+    /// Port I/O region.
+    IoRegion,
+}
+
+/// Describes a BAR which is added to a `PciConfiguration`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PciBarConfiguration {
+    idx: usize,
+    addr: u64,
+    size: u64,
+    region_type: PciBarRegionType,
+    prefetchable: bool,
+}
+
+impl PciBarConfiguration {
+    /// Create a new BAR description for the BAR register with index `idx`.
+    pub fn new(idx: usize, size: u64, region_type: PciBarRegionType, prefetchable: bool) -> Self {
+        PciBarConfiguration {
+            idx,
+            addr: 0,
+            size,
+            region_type,
+            prefetchable,
+        }
+    }
+
+    /// Return a copy of the BAR description that uses `addr` as the region address.
+    pub fn with_address(mut self, addr: u64) -> Self {
+        self.addr = addr;
+        self
+    }
+
+    /// Return the index of the (first) register used by this BAR.
+    pub fn idx(&self) -> usize {
+        self.idx
+    }
+
+    /// Return the address currently programmed into the BAR.
+    pub fn address(&self) -> u64 {
+        self.addr
+    }
+
+    /// Return the size of the region described by the BAR.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Return the type of the region described by the BAR.
+    pub fn region_type(&self) -> PciBarRegionType {
+        self.region_type
+    }
+
+    /// Return whether the memory region is prefetchable.
+    pub fn prefetchable(&self) -> bool {
+        self.prefetchable
+    }
+
+    fn addr_mask(&self) -> u32 {
+        match self.region_type {
+            PciBarRegionType::IoRegion => BAR_IO_ADDR_MASK,
+            _ => BAR_MEM_ADDR_MASK,
+        }
+    }
+
+    fn flags(&self) -> u32 {
+        match self.region_type {
+            PciBarRegionType::IoRegion => BAR_IO_BIT,
+            PciBarRegionType::Memory32BitRegion => u32::from(self.prefetchable) << 3,
+            PciBarRegionType::Memory64BitRegion => {
+                BAR_MEM_64BIT | u32::from(self.prefetchable) << 3
+            }
+        }
+    }
+
+    fn is_64bit(&self) -> bool {
+        self.region_type == PciBarRegionType::Memory64BitRegion
+    }
+}
+
+/// Describes a BAR that has been moved by the guest to a different address.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BarReprogrammingParams {
+    /// Address of the region before the guest wrote to the BAR.
+    pub old_base: u64,
+    /// Address of the region after the guest wrote to the BAR.
+    pub new_base: u64,
+    /// Size of the region.
+    pub len: u64,
+    /// Type of the region.
+    pub region_type: PciBarRegionType,
+}
+
+/// Standard PCI capability IDs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum PciCapabilityId {
+    /// PCI power management interface.
+    PowerManagement = 0x01,
+    /// Message Signaled Interrupts.
+    Msi = 0x05,
+    /// Vendor specific capability.
+    VendorSpecific = 0x09,
+    /// PCI Express capability.
+    PciExpress = 0x10,
+    /// Extended Message Signaled Interrupts.
+    MsiX = 0x11,
+}
+
+/// A capability that can be added to the capability list of a `PciConfiguration`.
+pub trait PciCapability {
+    /// Return the ID of the capability.
+    fn id(&self) -> PciCapabilityId;
+
+    /// Return the contents of the capability, excluding the ID and next pointer bytes.
+    fn bytes(&self) -> Vec<u8>;
+
+    /// Return the guest writable bits for each register covered by the capability, starting
+    /// with the register that holds the ID and next pointer bytes. Missing entries are treated
+    /// as read-only.
+    fn writable_bits(&self) -> Vec<u32> {
+        Vec::new()
+    }
+}
+
+const MSI_CTL_ENABLE: u16 = 0x0001;
+const MSI_CTL_MULTI_MSG_CAPABLE_SHIFT: u16 = 1;
+const MSI_CTL_64_BITS: u16 = 0x0080;
+const MSI_CTL_PER_VECTOR_MASK: u16 = 0x0100;
+
+/// The MSI capability structure.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MsiCap {
+    msg_ctl: u16,
+}
+
+impl MsiCap {
+    /// Create an MSI capability advertising `2 ^ vectors_log2` vectors.
+    pub fn new(vectors_log2: u8, is_64bit: bool, per_vector_mask: bool) -> Self {
+        let mut msg_ctl = (u16::from(vectors_log2.min(5))) << MSI_CTL_MULTI_MSG_CAPABLE_SHIFT;
+        if is_64bit {
+            msg_ctl |= MSI_CTL_64_BITS;
+        }
+        if per_vector_mask {
+            msg_ctl |= MSI_CTL_PER_VECTOR_MASK;
+        }
+        MsiCap { msg_ctl }
+    }
+
+    /// Return whether the message address is 64 bits wide.
+    pub fn is_64bit(&self) -> bool {
+        self.msg_ctl & MSI_CTL_64_BITS != 0
+    }
+
+    /// Return whether per vector masking is supported.
+    pub fn per_vector_mask(&self) -> bool {
+        self.msg_ctl & MSI_CTL_PER_VECTOR_MASK != 0
+    }
+
+    // Size of the structure in bytes, including the ID and next pointer.
+    fn size(&self) -> usize {
+        let mut size = 10;
+        if self.is_64bit() {
+            size += 4;
+        }
+        if self.per_vector_mask() {
+            size += 10;
+        }
+        size
+    }
+}
+
+impl PciCapability for MsiCap {
+    fn id(&self) -> PciCapabilityId {
+        PciCapabilityId::Msi
+    }
+
+    fn bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0; self.size() - 2];
+        bytes[..2].copy_from_slice(&self.msg_ctl.to_le_bytes());
+        bytes
+    }
+
+    fn writable_bits(&self) -> Vec<u32> {
+        // Message control: the enable bit and the multiple message enable field.
+        let mut bits = vec![(u32::from(MSI_CTL_ENABLE) | 0x70) << 16, 0xffff_fffc];
+        if self.is_64bit() {
+            bits.push(0xffff_ffff);
+        }
+        bits.push(0x0000_ffff);
+        if self.per_vector_mask() {
+            bits.push(0xffff_ffff);
+        }
+        bits
+    }
+}
+
+const MSIX_CTL_TABLE_SIZE_MASK: u16 = 0x07ff;
+const MSIX_CTL_FUNCTION_MASK: u16 = 0x4000;
+const MSIX_CTL_ENABLE: u16 = 0x8000;
+const MSIX_BIR_MASK: u32 = 0x7;
+
+/// The MSI-X capability structure.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MsixCap {
+    msg_ctl: u16,
+    table: u32,
+    pba: u32,
+}
+
+impl MsixCap {
+    /// Create an MSI-X capability for a table with `table_size` entries. The table and the
+    /// pending bit array are located at the specified offsets within the BARs with indices
+    /// `table_bir` and `pba_bir`.
+    pub fn new(table_size: u16, table_bir: u8, table_off: u32, pba_bir: u8, pba_off: u32) -> Self {
+        MsixCap {
+            msg_ctl: table_size.saturating_sub(1) & MSIX_CTL_TABLE_SIZE_MASK,
+            table: (table_off & !MSIX_BIR_MASK) | (u32::from(table_bir) & MSIX_BIR_MASK),
+            pba: (pba_off & !MSIX_BIR_MASK) | (u32::from(pba_bir) & MSIX_BIR_MASK),
+        }
+    }
+
+    /// Return the number of entries in the MSI-X table.
+    pub fn table_size(&self) -> u16 {
+        (self.msg_ctl & MSIX_CTL_TABLE_SIZE_MASK) + 1
+    }
+
+    /// Return the index of the BAR holding the MSI-X table.
+    pub fn table_bir(&self) -> u8 {
+        (self.table & MSIX_BIR_MASK) as u8
+    }
+
+    /// Return the offset of the MSI-X table within its BAR.
+    pub fn table_offset(&self) -> u32 {
+        self.table & !MSIX_BIR_MASK
+    }
+
+    /// Return the index of the BAR holding the pending bit array.
+    pub fn pba_bir(&self) -> u8 {
+        (self.pba & MSIX_BIR_MASK) as u8
+    }
+
+    /// Return the offset of the pending bit array within its BAR.
+    pub fn pba_offset(&self) -> u32 {
+        self.pba & !MSIX_BIR_MASK
+    }
+}
+
+impl PciCapability for MsixCap {
+    fn id(&self) -> PciCapabilityId {
+        PciCapabilityId::MsiX
+    }
+
+    fn bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(10);
+        bytes.extend_from_slice(&self.msg_ctl.to_le_bytes());
+        bytes.extend_from_slice(&self.table.to_le_bytes());
+        bytes.extend_from_slice(&self.pba.to_le_bytes());
+        bytes
+    }
+
+    fn writable_bits(&self) -> Vec<u32> {
+        vec![u32::from(MSIX_CTL_ENABLE | MSIX_CTL_FUNCTION_MASK) << 16]
+    }
+}
+
+/// Emulates the configuration space of a PCI function with a type 0 header.
+#[derive(Clone)]
+pub struct PciConfiguration {
+    registers: [u32; NUM_CONFIGURATION_REGISTERS],
+    writable_bits: [u32; NUM_CONFIGURATION_REGISTERS],
+    bars: Vec<PciBarConfiguration>,
+    // Offset of the last capability added to the list (0 if the list is empty).
+    last_capability: usize,
+    // Offset where the next capability can be placed.
+    next_capability: usize,
+}
+
+impl PciConfiguration {
+    /// Create the configuration space of a function with the specified identity.
+    pub fn new(vendor_id: u16, device_id: u16, class_code: PciClassCode) -> Self {
+        let mut registers = [0u32; NUM_CONFIGURATION_REGISTERS];
+        let mut writable_bits = [0u32; NUM_CONFIGURATION_REGISTERS];
+
+        registers[0] = u32::from(device_id) << 16 | u32::from(vendor_id);
+        // The command register is writable, while the status register is read-only.
+        writable_bits[COMMAND_REG] = 0x0000_ffff;
+        registers[CLASS_REG] = u32::from(class_code.class) << 24
+            | u32::from(class_code.subclass) << 16
+            | u32::from(class_code.prog_if) << 8;
+        // The cache line size register is writable, and the header type is 0.
+        writable_bits[HEADER_TYPE_REG] = 0x0000_00ff;
+        // The interrupt line register is writable.
+        writable_bits[INTERRUPT_LINE_PIN_REG] = 0x0000_00ff;
+
+        PciConfiguration {
+            registers,
+            writable_bits,
+            bars: Vec::new(),
+            last_capability: 0,
+            next_capability: FIRST_CAPABILITY_OFFSET,
+        }
+    }
+
+    /// Set the revision ID of the function.
+    pub fn set_revision_id(&mut self, revision_id: u8) {
+        self.registers[CLASS_REG] = (self.registers[CLASS_REG] & !0xff) | u32::from(revision_id);
+    }
+
+    /// Set the subsystem vendor and subsystem IDs of the function.
+    pub fn set_subsystem(&mut self, subsystem_vendor_id: u16, subsystem_id: u16) {
+        self.registers[SUBSYSTEM_REG] =
+            u32::from(subsystem_id) << 16 | u32::from(subsystem_vendor_id);
+    }
+
+    /// Set the interrupt line and interrupt pin (1 = INTA# .. 4 = INTD#) used by the function.
+    pub fn set_irq(&mut self, line: u8, pin: u8) {
+        self.registers[INTERRUPT_LINE_PIN_REG] = (self.registers[INTERRUPT_LINE_PIN_REG]
+            & 0xffff_0000)
+            | u32::from(pin) << 8
+            | u32::from(line);
+    }
+
+    /// Return the value of the command register.
+    pub fn command(&self) -> u16 {
+        self.registers[COMMAND_REG] as u16
+    }
+
+    /// Return the value of the register with index `reg_idx`. Registers outside the
+    /// configuration space read as all ones.
+    pub fn read_reg(&self, reg_idx: usize) -> u32 {
+        self.registers.get(reg_idx).copied().unwrap_or(0xffff_ffff)
+    }
+
+    /// Write `data` at `offset` within the register with index `reg_idx`, honouring the
+    /// writable bits of the register. Returns the description of the move when the write
+    /// relocates a BAR.
+    pub fn write_reg(
+        &mut self,
+        reg_idx: usize,
+        offset: u64,
+        data: &[u8],
+    ) -> Option<BarReprogrammingParams> {
+        if reg_idx >= NUM_CONFIGURATION_REGISTERS {
+            return None;
+        }
+
+        let (value, width_mask) = match (offset, data.len()) {
+            (0..=3, 1) => (u32::from(data[0]), 0xff),
+            (0 | 2, 2) => (u32::from(u16::from_le_bytes([data[0], data[1]])), 0xffff),
+            (0, 4) => (
+                u32::from_le_bytes([data[0], data[1], data[2], data[3]]),
+                0xffff_ffff,
+            ),
+            _ => return None,
+        };
+
+        let shift = offset * 8;
+        let mask = self.writable_bits[reg_idx] & (width_mask << shift);
+        self.registers[reg_idx] = (self.registers[reg_idx] & !mask) | ((value << shift) & mask);
+
+        // BAR relocation is only detected for full register writes, which is what guests
+        // use when programming BARs.
+        if data.len() == 4 && value != BAR_SIZING_VALUE {
+            return self.detect_bar_reprogramming(reg_idx);
+        }
+
+        None
+    }
+
+    fn detect_bar_reprogramming(&mut self, reg_idx: usize) -> Option<BarReprogrammingParams> {
+        let bar_reg = reg_idx.checked_sub(BAR0_REG)?;
+        let registers = &self.registers;
+        let bar = self
+            .bars
+            .iter_mut()
+            .find(|bar| bar.idx == bar_reg || (bar.is_64bit() && bar.idx + 1 == bar_reg))?;
+
+        if bar.is_64bit() {
+            // The guest may size both halves of a 64-bit BAR before restoring either of them,
+            // so hold off while the other half still contains the sizing mask.
+            let other = if bar.idx == bar_reg {
+                BAR0_REG + bar_reg + 1
+            } else {
+                BAR0_REG + bar.idx
+            };
+            let writable = self.writable_bits[other];
+            if writable != 0 && registers[other] & writable == writable {
+                return None;
+            }
+        }
+
+        let mut new_base = u64::from(registers[BAR0_REG + bar.idx] & bar.addr_mask());
+        if bar.is_64bit() {
+            new_base |= u64::from(registers[BAR0_REG + bar.idx + 1]) << 32;
+        }
+
+        if new_base == bar.addr {
+            return None;
+        }
+
+        let params = BarReprogrammingParams {
+            old_base: bar.addr,
+            new_base,
+            len: bar.size,
+            region_type: bar.region_type,
+        };
+        bar.addr = new_base;
+        Some(params)
+    }
+
+    /// Undo the BAR move described by `params`, e.g. when its range couldn't be relocated,
+    /// so the BAR reports the address its range is still decoded at. Returns `false` if the
+    /// BAR has moved again since, in which case it is left alone.
+    pub fn revert_bar_reprogramming(&mut self, params: &BarReprogrammingParams) -> bool {
+        let bar = match self
+            .bars
+            .iter_mut()
+            .find(|bar| bar.addr == params.new_base && bar.region_type == params.region_type)
+        {
+            Some(bar) => bar,
+            None => return false,
+        };
+        let reg_idx = BAR0_REG + bar.idx;
+        self.registers[reg_idx] = (params.old_base as u32 & bar.addr_mask()) | bar.flags();
+        if bar.is_64bit() {
+            self.registers[reg_idx + 1] = (params.old_base >> 32) as u32;
+        }
+        bar.addr = params.old_base;
+        true
+    }
+
+    /// Add a BAR to the configuration space. Returns the index of the BAR on success.
+    pub fn add_bar(&mut self, config: &PciBarConfiguration) -> Result<usize, Error> {
+        let num_regs = if config.is_64bit() { 2 } else { 1 };
+        if config.idx + num_regs > NUM_BAR_REGS {
+            return Err(Error::BarInvalid(config.idx));
+        }
+
+        let in_use = |idx: usize| {
+            self.bars
+                .iter()
+                .any(|bar| bar.idx == idx || (bar.is_64bit() && bar.idx + 1 == idx))
+        };
+        if (config.idx..config.idx + num_regs).any(in_use) {
+            return Err(Error::BarInUse(config.idx));
+        }
+
+        let min_size = match config.region_type {
+            PciBarRegionType::IoRegion => BAR_IO_MIN_SIZE,
+            _ => BAR_MEM_MIN_SIZE,
+        };
+        if config.size < min_size || !config.size.is_power_of_two() {
+            return Err(Error::BarSizeInvalid(config.size));
+        }
+
+        let max_addr = match config.region_type {
+            PciBarRegionType::Memory64BitRegion => u64::MAX,
+            _ => u64::from(u32::MAX),
+        };
+        if !config.addr.is_multiple_of(config.size) || config.addr > max_addr - (config.size - 1) {
+            return Err(Error::BarAddressInvalid(config.addr, config.size));
+        }
+
+        let size_mask = !(config.size - 1);
+        let reg_idx = BAR0_REG + config.idx;
+        self.registers[reg_idx] = (config.addr as u32 & config.addr_mask()) | config.flags();
+        self.writable_bits[reg_idx] = size_mask as u32 & config.addr_mask();
+        if config.is_64bit() {
+            self.registers[reg_idx + 1] = (config.addr >> 32) as u32;
+            self.writable_bits[reg_idx + 1] = (size_mask >> 32) as u32;
+        }
+
+        self.bars.push(*config);
+        Ok(config.idx)
+    }
+
+    /// Return the current description of the BAR with index `idx`, if present.
+    pub fn bar(&self, idx: usize) -> Option<PciBarConfiguration> {
+        self.bars.iter().find(|bar| bar.idx == idx).copied()
+    }
+
+    /// Return the I/O resources currently described by the BARs, so they can be registered
+    /// with an `IoManager`.
+    pub fn bar_resources(&self) -> Vec<Resource> {
+        self.bars
+            .iter()
+            .map(|bar| match bar.region_type {
+                PciBarRegionType::IoRegion => Resource::PioAddressRange {
+                    base: bar.addr as PioAddressValue,
+                    size: bar.size as PioAddressValue,
+                },
+                _ => Resource::MmioAddressRange {
+                    base: bar.addr,
+                    size: bar.size,
+                },
+            })
+            .collect()
+    }
+
+    /// Add a capability to the capability list. Returns the offset of the capability within
+    /// the configuration space.
+    pub fn add_capability(&mut self, cap: &dyn PciCapability) -> Result<usize, Error> {
+        let body = cap.bytes();
+        let total_len = body.len() + 2;
+        let offset = self.next_capability;
+        let end = offset + total_len;
+        if end > CAPABILITY_MAX_OFFSET {
+            return Err(Error::CapabilitySpaceFull(total_len));
+        }
+
+        self.write_byte_internal(offset, cap.id() as u8);
+        self.write_byte_internal(offset + 1, 0);
+        for (idx, byte) in body.iter().enumerate() {
+            self.write_byte_internal(offset + 2 + idx, *byte);
+        }
+
+        for (idx, bits) in cap.writable_bits().into_iter().enumerate() {
+            let reg_idx = offset / 4 + idx;
+            if reg_idx < NUM_CONFIGURATION_REGISTERS {
+                self.writable_bits[reg_idx] = bits;
+            }
+        }
+
+        if self.last_capability == 0 {
+            self.registers[CAPABILITY_LIST_HEAD_REG] = offset as u32;
+            self.registers[COMMAND_REG] |= STATUS_REG_CAPABILITIES_USED_MASK;
+        } else {
+            self.write_byte_internal(self.last_capability + 1, offset as u8);
+        }
+
+        self.last_capability = offset;
+        // Capabilities are always placed at dword aligned offsets.
+        self.next_capability = (end + 3) & !3;
+        Ok(offset)
+    }
+
+    /// Return the offset of the first capability with the specified ID, if present.
+    pub fn capability_offset(&self, id: PciCapabilityId) -> Option<usize> {
+        let mut offset = self.registers[CAPABILITY_LIST_HEAD_REG] as usize & 0xfc;
+        while offset != 0 {
+            if self.read_byte(offset) == id as u8 {
+                return Some(offset);
+            }
+            offset = self.read_byte(offset + 1) as usize;
+        }
+        None
+    }
+
+    /// Return the byte located at `offset` within the configuration space.
+    pub fn read_byte(&self, offset: usize) -> u8 {
+        (self.read_reg(offset / 4) >> ((offset % 4) * 8)) as u8
+    }
+
+    fn write_byte_internal(&mut self, offset: usize, value: u8) {
+        let shift = (offset % 4) * 8;
+        let reg = &mut self.registers[offset / 4];
+        *reg = (*reg & !(0xff << shift)) | u32::from(value) << shift;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_config() -> PciConfiguration {
+        PciConfiguration::new(
+            0x1234,
+            0x5678,
+            PciClassCode {
+                class: 0x02,
+                subclass: 0x00,
+                prog_if: 0x00,
+            },
+        )
+    }
+
+    #[test]
+    fn test_header() {
+        let mut config = test_config();
+        config.set_revision_id(0x3);
+        config.set_subsystem(0xabcd, 0xef01);
+        config.set_irq(5, 1);
+
+        assert_eq!(config.read_reg(0), 0x5678_1234);
+        assert_eq!(config.read_reg(CLASS_REG), 0x0200_0003);
+        assert_eq!(config.read_reg(SUBSYSTEM_REG), 0xef01_abcd);
+        assert_eq!(config.read_reg(INTERRUPT_LINE_PIN_REG), 0x0000_0105);
+        assert_eq!(config.read_reg(NUM_CONFIGURATION_REGISTERS), 0xffff_ffff);
+
+        // Vendor and device IDs are read-only.
+        config.write_reg(0, 0, &[0xff; 4]);
+        assert_eq!(config.read_reg(0), 0x5678_1234);
+
+        // The command register is writable, but the status register isn't.
+        config.write_reg(COMMAND_REG, 0, &[0xff; 4]);
+        assert_eq!(config.read_reg(COMMAND_REG), 0x0000_ffff);
+        config.write_reg(COMMAND_REG, 0, &[0x06, 0x00]);
+        assert_eq!(config.command(), 0x0006);
+
+        // Invalid write shapes are ignored.
+        config.write_reg(INTERRUPT_LINE_PIN_REG, 1, &[0, 0]);
+        config.write_reg(INTERRUPT_LINE_PIN_REG, 0, &[0x0a]);
+        assert_eq!(config.read_reg(INTERRUPT_LINE_PIN_REG), 0x0000_010a);
+    }
+
+    #[test]
+    fn test_bars() {
+        let mut config = test_config();
+
+        let mmio_bar =
+            PciBarConfiguration::new(0, 0x1000, PciBarRegionType::Memory64BitRegion, true)
+                .with_address(0x1_0000_0000);
+        let io_bar = PciBarConfiguration::new(2, 0x20, PciBarRegionType::IoRegion, false)
+            .with_address(0x1000);
+
+        assert_eq!(config.add_bar(&mmio_bar), Ok(0));
+        assert_eq!(config.add_bar(&io_bar), Ok(2));
+
+        // The upper half of a 64-bit BAR is in use as well.
+        let bar = PciBarConfiguration::new(1, 0x1000, PciBarRegionType::Memory32BitRegion, false);
+        assert_eq!(config.add_bar(&bar), Err(Error::BarInUse(1)));
+        let bar = PciBarConfiguration::new(5, 0x1000, PciBarRegionType::Memory64BitRegion, false);
+        assert_eq!(config.add_bar(&bar), Err(Error::BarInvalid(5)));
+        let bar = PciBarConfiguration::new(3, 0x1001, PciBarRegionType::Memory32BitRegion, false);
+        assert_eq!(config.add_bar(&bar), Err(Error::BarSizeInvalid(0x1001)));
+        let bar = PciBarConfiguration::new(3, 0x1000, PciBarRegionType::Memory32BitRegion, false)
+            .with_address(0x800);
+        assert_eq!(
+            config.add_bar(&bar),
+            Err(Error::BarAddressInvalid(0x800, 0x1000))
+        );
+
+        assert_eq!(config.read_reg(BAR0_REG), 0x0000_000c);
+        assert_eq!(config.read_reg(BAR0_REG + 1), 0x0000_0001);
+        assert_eq!(config.read_reg(BAR0_REG + 2), 0x0000_1001);
+        assert_eq!(config.bar_resources().len(), 2);
+
+        // BAR sizing protocol.
+        assert_eq!(config.write_reg(BAR0_REG, 0, &[0xff; 4]), None);
+        assert_eq!(config.read_reg(BAR0_REG), 0xffff_f00c);
+        assert_eq!(config.write_reg(BAR0_REG + 1, 0, &[0xff; 4]), None);
+        assert_eq!(config.read_reg(BAR0_REG + 1), 0xffff_ffff);
+        assert_eq!(config.write_reg(BAR0_REG + 2, 0, &[0xff; 4]), None);
+        assert_eq!(config.read_reg(BAR0_REG + 2), 0xffff_ffe1);
+
+        // Restoring the original values does not move the BARs.
+        assert_eq!(config.write_reg(BAR0_REG, 0, &0u32.to_le_bytes()), None);
+        assert_eq!(config.write_reg(BAR0_REG + 1, 0, &1u32.to_le_bytes()), None);
+        assert_eq!(
+            config.write_reg(BAR0_REG + 2, 0, &0x1000u32.to_le_bytes()),
+            None
+        );
+
+        // Moving the BARs is reported.
+        assert_eq!(
+            config.write_reg(BAR0_REG + 1, 0, &2u32.to_le_bytes()),
+            Some(BarReprogrammingParams {
+                old_base: 0x1_0000_0000,
+                new_base: 0x2_0000_0000,
+                len: 0x1000,
+                region_type: PciBarRegionType::Memory64BitRegion,
+            })
+        );
+        assert_eq!(
+            config.write_reg(BAR0_REG + 2, 0, &0x2000u32.to_le_bytes()),
+            Some(BarReprogrammingParams {
+                old_base: 0x1000,
+                new_base: 0x2000,
+                len: 0x20,
+                region_type: PciBarRegionType::IoRegion,
+            })
+        );
+        assert_eq!(config.bar(0).unwrap().address(), 0x2_0000_0000);
+        assert_eq!(config.bar(2).unwrap().address(), 0x2000);
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let mut config = test_config();
+        assert_eq!(config.capability_offset(PciCapabilityId::Msi), None);
+
+        let msi = MsiCap::new(2, true, true);
+        let msix = MsixCap::new(16, 1, 0x1000, 1, 0x2000);
+        assert_eq!(msix.table_size(), 16);
+        assert_eq!(msix.table_bir(), 1);
+        assert_eq!(msix.table_offset(), 0x1000);
+        assert_eq!(msix.pba_bir(), 1);
+        assert_eq!(msix.pba_offset(), 0x2000);
+
+        assert_eq!(config.add_capability(&msi), Ok(FIRST_CAPABILITY_OFFSET));
+        // The MSI capability is 24 bytes long.
+        assert_eq!(
+            config.add_capability(&msix),
+            Ok(FIRST_CAPABILITY_OFFSET + 24)
+        );
+
+        assert_ne!(
+            config.read_reg(COMMAND_REG) & STATUS_REG_CAPABILITIES_USED_MASK,
+            0
+        );
+        assert_eq!(
+            config.capability_offset(PciCapabilityId::Msi),
+            Some(FIRST_CAPABILITY_OFFSET)
+        );
+        assert_eq!(
+            config.capability_offset(PciCapabilityId::MsiX),
+            Some(FIRST_CAPABILITY_OFFSET + 24)
+        );
+
+        // Only the MSI-X enable and function mask bits are writable in the first register.
+        let msix_reg = (FIRST_CAPABILITY_OFFSET + 24) / 4;
+        let before = config.read_reg(msix_reg);
+        config.write_reg(msix_reg, 0, &[0xff; 4]);
+        assert_eq!(config.read_reg(msix_reg), before | 0xc000_0000);
+
+        // The MSI enable bit is writable.
+        let msi_reg = FIRST_CAPABILITY_OFFSET / 4;
+        config.write_reg(msi_reg, 2, &[0x01]);
+        assert_eq!(config.read_reg(msi_reg) & 0x0001_0000, 0x0001_0000);
+
+        // Fill up the remaining space.
+        let mut result = Ok(0);
+        while result.is_ok() {
+            result = config.add_capability(&msi);
+        }
+        assert_eq!(result, Err(Error::CapabilitySpaceFull(24)));
+    }
+}
@@ -0,0 +1,186 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+use std::sync::{Arc, Mutex};
+
+use crate::bus::{MmioAddress, MmioOffset};
+use crate::pci::{PciBdf, PciBus};
+use crate::DeviceMmio;
+
+/// Size of the ECAM window covering a single bus (32 devices * 8 functions * 4 KiB).
+pub const ECAM_BUS_SIZE: u64 = 1 << 20;
+
+/// Size of the configuration space of a function, as exposed through ECAM.
+pub const ECAM_FUNCTION_SIZE: u64 = 1 << 12;
+
+/// Maps the PCI Express Enhanced Configuration Access Mechanism (MMCONFIG) window of a PCI
+/// segment onto a `PciBus`.
+///
+/// The window starts with the configuration space of bus `start_bus` and covers every bus
+/// up to and including `end_bus`. Each function has a 4 KiB configuration space, located at
+/// offset `(bus - start_bus) << 20 | device << 15 | function << 12` within the window.
+pub struct PciEcam {
+    segment: u16,
+    start_bus: u8,
+    end_bus: u8,
+    pci_bus: Arc<Mutex<PciBus>>,
+}
+
+impl PciEcam {
+    /// Create the ECAM window of segment `segment`, covering buses `start_bus` to `end_bus`,
+    /// which forwards accesses to `pci_bus`.
+    pub fn new(segment: u16, start_bus: u8, end_bus: u8, pci_bus: Arc<Mutex<PciBus>>) -> Self {
+        PciEcam {
+            segment,
+            start_bus,
+            end_bus: end_bus.max(start_bus),
+            pci_bus,
+        }
+    }
+
+    /// Return the number of the segment served by the window.
+    pub fn segment(&self) -> u16 {
+        self.segment
+    }
+
+    /// Return the size of the MMIO range which should be registered for the window.
+    pub fn size(&self) -> u64 {
+        (u64::from(self.end_bus - self.start_bus) + 1) * ECAM_BUS_SIZE
+    }
+
+    // Decode the function, register index, and offset within the register targeted by an
+    // access at `offset` within the window.
+    fn decode(&self, offset: u64, len: usize) -> Option<(PciBdf, usize, u64)> {
+        let reg_offset = offset & 0x3;
+        // Accesses must not cross a register boundary.
+        if len == 0 || reg_offset + len as u64 > 4 || offset >= self.size() {
+            return None;
+        }
+
+        let bus = self.start_bus + (offset / ECAM_BUS_SIZE) as u8;
+        let device = ((offset >> 15) & 0x1f) as u8;
+        let function = ((offset >> 12) & 0x7) as u8;
+        let reg_idx = ((offset & (ECAM_FUNCTION_SIZE - 1)) >> 2) as usize;
+        PciBdf::new(bus, device, function)
+            .ok()
+            .map(|bdf| (bdf, reg_idx, reg_offset))
+    }
+}
+
+impl DeviceMmio for PciEcam {
+    fn mmio_read(&self, _base: MmioAddress, offset: MmioOffset, data: &mut [u8]) {
+        let value = match self.decode(offset.raw(), data.len()) {
+            Some((bdf, reg_idx, _)) => self.pci_bus.lock().unwrap().config_read(bdf, reg_idx),
+            None => 0xffff_ffff,
+        };
+
+        let bytes = value.to_le_bytes();
+        let start = (offset.raw() & 0x3) as usize;
+        for (idx, byte) in data.iter_mut().enumerate() {
+            *byte = bytes.get(start + idx).copied().unwrap_or(0xff);
+        }
+    }
+
+    fn mmio_write(&self, _base: MmioAddress, offset: MmioOffset, data: &[u8]) {
+        if let Some((bdf, reg_idx, reg_offset)) = self.decode(offset.raw(), data.len()) {
+            self.pci_bus
+                .lock()
+                .unwrap()
+                .config_write(bdf, reg_idx, reg_offset, data);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::pci::{
+        PciBarConfiguration, PciBarRegionType, PciClassCode, PciConfiguration, PciDevice,
+    };
+
+    struct TestDevice {
+        config: PciConfiguration,
+    }
+
+    impl PciDevice for TestDevice {
+        fn config(&self) -> &PciConfiguration {
+            &self.config
+        }
+
+        fn config_mut(&mut self) -> &mut PciConfiguration {
+            &mut self.config
+        }
+    }
+
+    #[test]
+    fn test_ecam() {
+        let mut config = PciConfiguration::new(
+            0x1af4,
+            0x1041,
+            PciClassCode {
+                class: 0x02,
+                subclass: 0x00,
+                prog_if: 0x00,
+            },
+        );
+        config
+            .add_bar(&PciBarConfiguration::new(
+                0,
+                0x1000,
+                PciBarRegionType::Memory32BitRegion,
+                false,
+            ))
+            .unwrap();
+
+        let pci_bus = Arc::new(Mutex::new(PciBus::new()));
+        pci_bus
+            .lock()
+            .unwrap()
+            .add_device(2, Arc::new(Mutex::new(TestDevice { config })))
+            .unwrap();
+
+        let ecam = PciEcam::new(0, 0, 3, pci_bus.clone());
+        let base = MmioAddress(0xe000_0000);
+        assert_eq!(ecam.segment(), 0);
+        assert_eq!(ecam.size(), 4 * ECAM_BUS_SIZE);
+
+        let dev_offset = 2 << 15;
+        let mut data = [0u8; 4];
+        ecam.mmio_read(base, MmioOffset(dev_offset), &mut data);
+        assert_eq!(u32::from_le_bytes(data), 0x1041_1af4);
+
+        let mut word = [0u8; 2];
+        ecam.mmio_read(base, MmioOffset(dev_offset + 2), &mut word);
+        assert_eq!(u16::from_le_bytes(word), 0x1041);
+
+        // Accesses crossing a register boundary are rejected.
+        ecam.mmio_read(base, MmioOffset(dev_offset + 3), &mut word);
+        assert_eq!(word, [0xff; 2]);
+
+        // Missing functions, devices, and buses read as all ones.
+        for offset in [dev_offset + (1 << 12), 3 << 15, ECAM_BUS_SIZE + dev_offset].iter() {
+            ecam.mmio_read(base, MmioOffset(*offset), &mut data);
+            assert_eq!(data, [0xff; 4]);
+        }
+
+        // Extended configuration space is not backed by the conventional header.
+        ecam.mmio_read(base, MmioOffset(dev_offset + 0x100), &mut data);
+        assert_eq!(data, [0xff; 4]);
+
+        // Program BAR0 through the window.
+        ecam.mmio_write(
+            base,
+            MmioOffset(dev_offset + 0x10),
+            &0xc000_0000u32.to_le_bytes(),
+        );
+        ecam.mmio_read(base, MmioOffset(dev_offset + 0x10), &mut data);
+        assert_eq!(u32::from_le_bytes(data), 0xc000_0000);
+        assert_eq!(pci_bus.lock().unwrap().take_bar_reprogramming().len(), 1);
+
+        // A window which starts at bus 1 does not reach the root bus.
+        let ecam = PciEcam::new(1, 1, 1, pci_bus);
+        ecam.mmio_read(base, MmioOffset(dev_offset), &mut data);
+        assert_eq!(data, [0xff; 4]);
+    }
+}
@@ -0,0 +1,263 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+use super::{PciClassCode, PciConfiguration, PciDevice};
+use crate::pci::BarReprogrammingParams;
+
+/// Offset of the PIRQ route control registers in the configuration space of the PIIX3 ISA
+/// bridge.
+pub const PIRQ_ROUTE_OFFSET: usize = 0x60;
+
+/// Number of PIRQ links (LNKA to LNKD).
+pub const NUM_PIRQ_LINKS: usize = 4;
+
+// A PIRQ route control register with this bit set doesn't route the link.
+const PIRQ_DISABLED: u8 = 0x80;
+const PIRQ_IRQ_MASK: u8 = 0x0f;
+// IRQs 0, 1, 2, 8 and 13 are reserved, and can't be used by PCI devices.
+const PIRQ_RESERVED_IRQS: [u8; 5] = [0, 1, 2, 8, 13];
+
+/// Legacy interrupt pin of a PCI function.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum PciInterruptPin {
+    /// INTA#.
+    IntA,
+    /// INTB#.
+    IntB,
+    /// INTC#.
+    IntC,
+    /// INTD#.
+    IntD,
+}
+
+impl PciInterruptPin {
+    const PINS: [PciInterruptPin; 4] = [
+        PciInterruptPin::IntA,
+        PciInterruptPin::IntB,
+        PciInterruptPin::IntC,
+        PciInterruptPin::IntD,
+    ];
+
+    /// Return the pin described by the value of the interrupt pin register (1 = INTA# ..
+    /// 4 = INTD#), if the function uses one.
+    pub fn from_config(value: u8) -> Option<Self> {
+        Self::PINS.get(usize::from(value).checked_sub(1)?).copied()
+    }
+
+    /// Return the value of the interrupt pin register for this pin.
+    pub fn to_config(self) -> u8 {
+        self as u8 + 1
+    }
+
+    /// Return the pin seen on the primary side of a PCI-to-PCI bridge, for a function of
+    /// the device `device` on the secondary bus using this pin.
+    pub fn swizzle(self, device: u8) -> Self {
+        Self::PINS[(self as usize + usize::from(device)) % 4]
+    }
+}
+
+/// Return the slot and pin through which a function raises its interrupts on the root bus.
+/// `devices` holds the device number of the function on its bus, followed by the device
+/// numbers of the bridges on the path to the root bus, the last one being on the root bus.
+pub fn route_to_root(devices: &[u8], pin: PciInterruptPin) -> Option<(u8, PciInterruptPin)> {
+    let (root_slot, bridges) = devices.split_last()?;
+    let pin = bridges.iter().fold(pin, |pin, device| pin.swizzle(*device));
+    Some((*root_slot, pin))
+}
+
+/// Fixed routing of the root bus INTx pins to GSIs, as described to the guest by the ACPI
+/// `_PRT` or the device tree `interrupt-map`. Pin `p` of slot `s` is routed to the GSI
+/// `(s + p) % n` of the `n` GSIs of the table, which spreads the interrupts of the devices
+/// using INTA# over all GSIs.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IntxRouting {
+    gsis: Vec<u32>,
+}
+
+impl IntxRouting {
+    /// Create a routing table spreading the pins over `gsis`, which must not be empty.
+    pub fn new(gsis: Vec<u32>) -> Self {
+        assert!(!gsis.is_empty(), "no GSIs for the INTx routing");
+        IntxRouting { gsis }
+    }
+
+    /// Return the GSI of the pin `pin` of the root bus slot `slot`.
+    pub fn gsi(&self, slot: u8, pin: PciInterruptPin) -> u32 {
+        self.gsis[(usize::from(slot) + pin as usize) % self.gsis.len()]
+    }
+
+    /// Return the GSI of a function behind bridges, as for `route_to_root`.
+    pub fn gsi_behind_bridges(&self, devices: &[u8], pin: PciInterruptPin) -> Option<u32> {
+        route_to_root(devices, pin).map(|(slot, pin)| self.gsi(slot, pin))
+    }
+
+    /// Return the routing entries of all the pins of `slots`, to generate the firmware
+    /// tables from.
+    pub fn entries<I: IntoIterator<Item = u8>>(&self, slots: I) -> Vec<(u8, PciInterruptPin, u32)> {
+        slots
+            .into_iter()
+            .flat_map(|slot| {
+                PciInterruptPin::PINS
+                    .iter()
+                    .map(move |pin| (slot, *pin, self.gsi(slot, *pin)))
+            })
+            .collect()
+    }
+}
+
+/// The PIRQ router of the PIIX3 ISA bridge, whose LNKA to LNKD links are programmed by the
+/// guest (or its firmware) to route the root bus INTx pins to ISA IRQs. Pin `p` of slot `s`
+/// is wired to the link `(s + p) % 4`. The router is plugged into the root bus as function 0
+/// of the ISA bridge, and the VMM polls the link changes with `take_link_changes` to rewire
+/// the interrupt lines.
+pub struct PirqRouter {
+    config: PciConfiguration,
+    links: [u8; NUM_PIRQ_LINKS],
+    changes: Vec<(usize, Option<u8>)>,
+}
+
+impl Default for PirqRouter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PirqRouter {
+    /// Create the router, with all links disabled.
+    pub fn new() -> Self {
+        let class = PciClassCode {
+            class: 0x06,
+            subclass: 0x01,
+            prog_if: 0x00,
+        };
+        PirqRouter {
+            // Intel 82371SB PIIX3 ISA bridge.
+            config: PciConfiguration::new(0x8086, 0x7000, class),
+            links: [PIRQ_DISABLED; NUM_PIRQ_LINKS],
+            changes: Vec::new(),
+        }
+    }
+
+    /// Return the link the pin `pin` of the root bus slot `slot` is wired to.
+    pub fn link(slot: u8, pin: PciInterruptPin) -> usize {
+        (usize::from(slot) + pin as usize) % NUM_PIRQ_LINKS
+    }
+
+    /// Return the ISA IRQ the link `link` is routed to, if enabled.
+    pub fn link_irq(&self, link: usize) -> Option<u8> {
+        let value = *self.links.get(link)?;
+        let irq = value & PIRQ_IRQ_MASK;
+        if value & PIRQ_DISABLED != 0 || PIRQ_RESERVED_IRQS.contains(&irq) {
+            return None;
+        }
+        Some(irq)
+    }
+
+    /// Route the link `link` to the ISA IRQ `irq`, or disable it, e.g. to preset the routing
+    /// for guests whose firmware doesn't program the links.
+    pub fn set_link_irq(&mut self, link: usize, irq: Option<u8>) {
+        self.set_link(link, irq.map_or(PIRQ_DISABLED, |irq| irq & PIRQ_IRQ_MASK));
+    }
+
+    /// Return the ISA IRQ the pin `pin` of the root bus slot `slot` is routed to, if any.
+    pub fn irq(&self, slot: u8, pin: PciInterruptPin) -> Option<u8> {
+        self.link_irq(Self::link(slot, pin))
+    }
+
+    /// Return the links whose routing changed since the last call, with their new IRQ.
+    pub fn take_link_changes(&mut self) -> Vec<(usize, Option<u8>)> {
+        std::mem::take(&mut self.changes)
+    }
+
+    fn set_link(&mut self, link: usize, value: u8) {
+        let value = value & (PIRQ_DISABLED | PIRQ_IRQ_MASK);
+        if link < NUM_PIRQ_LINKS && self.links[link] != value {
+            self.links[link] = value;
+            let irq = self.link_irq(link);
+            self.changes.push((link, irq));
+        }
+    }
+}
+
+impl PciDevice for PirqRouter {
+    fn config(&self) -> &PciConfiguration {
+        &self.config
+    }
+
+    fn config_mut(&mut self) -> &mut PciConfiguration {
+        &mut self.config
+    }
+
+    fn read_config_register(&self, reg_idx: usize) -> u32 {
+        if reg_idx == PIRQ_ROUTE_OFFSET / 4 {
+            return u32::from_le_bytes(self.links);
+        }
+        self.config.read_reg(reg_idx)
+    }
+
+    fn write_config_register(
+        &mut self,
+        reg_idx: usize,
+        offset: u64,
+        data: &[u8],
+    ) -> Option<BarReprogrammingParams> {
+        if reg_idx == PIRQ_ROUTE_OFFSET / 4 {
+            for (i, value) in data.iter().enumerate() {
+                self.set_link(offset as usize + i, *value);
+            }
+            return None;
+        }
+        self.config.write_reg(reg_idx, offset, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intx_swizzling() {
+        use PciInterruptPin::*;
+
+        assert_eq!(PciInterruptPin::from_config(2), Some(IntB));
+        assert_eq!(PciInterruptPin::from_config(0), None);
+        assert_eq!(IntD.to_config(), 4);
+        assert_eq!(IntA.swizzle(5), IntB);
+        // A function using INTB# in slot 2 behind a bridge in slot 3 of a bridge in slot 1
+        // of the root bus.
+        assert_eq!(route_to_root(&[2, 3, 1], IntB), Some((1, IntC)));
+        assert_eq!(route_to_root(&[], IntA), None);
+
+        let routing = IntxRouting::new(vec![16, 17, 18, 19]);
+        assert_eq!(routing.gsi(0, IntA), 16);
+        assert_eq!(routing.gsi(3, IntC), 17);
+        assert_eq!(routing.gsi_behind_bridges(&[2, 3, 1], IntB), Some(19));
+        let entries = routing.entries(0..2);
+        assert_eq!(entries.len(), 8);
+        assert_eq!(entries[5], (1, IntB, 18));
+    }
+
+    #[test]
+    fn test_pirq_router() {
+        let mut router = PirqRouter::new();
+        assert_eq!(router.irq(0, PciInterruptPin::IntA), None);
+
+        // The guest routes LNKA to IRQ 10 and LNKB to IRQ 11, and disables LNKC.
+        router.write_config_register(PIRQ_ROUTE_OFFSET / 4, 0, &[10, 11]);
+        router.write_config_register(PIRQ_ROUTE_OFFSET / 4, 2, &[0x80]);
+        assert_eq!(
+            router.read_config_register(PIRQ_ROUTE_OFFSET / 4),
+            0x8080_0b0a
+        );
+        assert_eq!(
+            router.take_link_changes(),
+            vec![(0, Some(10)), (1, Some(11))]
+        );
+        assert_eq!(router.irq(1, PciInterruptPin::IntD), Some(10));
+        assert_eq!(router.irq(2, PciInterruptPin::IntA), None);
+
+        router.set_link_irq(3, Some(13));
+        assert_eq!(router.link_irq(3), None);
+        assert_eq!(router.read_config_register(0), 0x7000_8086);
+    }
+}
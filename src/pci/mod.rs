@@ -0,0 +1,117 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Provides building blocks for emulating PCI devices: configuration space emulation with
+//! BAR sizing and capability lists, a root bus routing configuration accesses to devices,
+//! the configuration access mechanisms (port I/O based, and ECAM) used by guests to reach
+//! it, the MSI-X table and pending bit array, and the routing of the legacy INTx
+//! interrupts.
+
+mod bus;
+mod configuration;
+mod ecam;
+mod intx;
+mod msix;
+
+use std::fmt::{Display, Formatter};
+
+pub use bus::{
+    PciBdf, PciBus, PciConfigIo, NUM_DEVICE_SLOTS, NUM_FUNCTIONS, PCI_CONFIG_IO_PORT,
+    PCI_CONFIG_IO_PORT_SIZE,
+};
+pub use configuration::{
+    BarReprogrammingParams, MsiCap, MsixCap, PciBarConfiguration, PciBarRegionType, PciCapability,
+    PciCapabilityId, PciClassCode, PciConfiguration, NUM_BAR_REGS, NUM_CONFIGURATION_REGISTERS,
+};
+pub use ecam::{PciEcam, ECAM_BUS_SIZE, ECAM_FUNCTION_SIZE};
+pub use intx::{
+    route_to_root, IntxRouting, PciInterruptPin, PirqRouter, NUM_PIRQ_LINKS, PIRQ_ROUTE_OFFSET,
+};
+pub use msix::{MsixPba, MsixTable, MSIX_TABLE_ENTRY_SIZE};
+
+/// Errors encountered while setting up PCI devices.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The BAR address is not aligned to its size, or does not fit the region type.
+    BarAddressInvalid(u64, u64),
+    /// The BAR index is out of range.
+    BarInvalid(usize),
+    /// The BAR register is already in use.
+    BarInUse(usize),
+    /// The BAR size is not a power of two, or is too small for the region type.
+    BarSizeInvalid(u64),
+    /// There is not enough room left for a capability of the specified length.
+    CapabilitySpaceFull(usize),
+    /// The function of the device slot is already in use.
+    FunctionInUse(u8, u8),
+    /// Invalid bus, device, and function number combination.
+    InvalidBdf(u8, u8, u8),
+    /// The MSI-X structure at the offset (second) of the BAR at the address (first) doesn't
+    /// fit in the address space.
+    MsixRangeInvalid(u64, u32),
+    /// No MSI sender was provided for the MSI-X table.
+    MsixNoSenders,
+    /// The device slot is already in use.
+    SlotInUse(u8),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::BarAddressInvalid(addr, size) => {
+                write!(f, "invalid BAR address {:#x} for size {:#x}", addr, size)
+            }
+            Error::BarInvalid(idx) => write!(f, "invalid BAR index {}", idx),
+            Error::BarInUse(idx) => write!(f, "BAR {} already in use", idx),
+            Error::BarSizeInvalid(size) => write!(f, "invalid BAR size {:#x}", size),
+            Error::CapabilitySpaceFull(len) => {
+                write!(f, "no room for a capability of length {}", len)
+            }
+            Error::FunctionInUse(device, function) => {
+                write!(f, "PCI function {:02x}.{} already in use", device, function)
+            }
+            Error::InvalidBdf(bus, device, function) => write!(
+                f,
+                "invalid PCI function {:02x}:{:02x}.{}",
+                bus, device, function
+            ),
+            Error::MsixRangeInvalid(bar_addr, offset) => write!(
+                f,
+                "MSI-X structure at offset {:#x} of the BAR at {:#x} overflows the address space",
+                offset, bar_addr
+            ),
+            Error::MsixNoSenders => write!(f, "no MSI senders for the MSI-X table"),
+            Error::SlotInUse(device) => write!(f, "PCI slot {} already in use", device),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Represents a PCI function that can be plugged into a `PciBus`.
+///
+/// Only access to the configuration space is required, because accesses to the regions
+/// described by the BARs are dispatched through the regular MMIO and PIO buses.
+pub trait PciDevice: Send {
+    /// Return a reference to the configuration space of the function.
+    fn config(&self) -> &PciConfiguration;
+
+    /// Return a mutable reference to the configuration space of the function.
+    fn config_mut(&mut self) -> &mut PciConfiguration;
+
+    /// Read the configuration register with index `reg_idx`.
+    fn read_config_register(&self, reg_idx: usize) -> u32 {
+        self.config().read_reg(reg_idx)
+    }
+
+    /// Write `data` at `offset` within the configuration register with index `reg_idx`.
+    /// Returns the description of the move when the write relocates a BAR.
+    fn write_config_register(
+        &mut self,
+        reg_idx: usize,
+        offset: u64,
+        data: &[u8],
+    ) -> Option<BarReprogrammingParams> {
+        self.config_mut().write_reg(reg_idx, offset, data)
+    }
+}
@@ -0,0 +1,385 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! MSI-X table and pending bit array of PCI functions.
+//!
+//! The MSI-X capability in the configuration space tells the guest which BARs hold the
+//! table of vectors and the pending bit array, and at which offsets. The guest programs the
+//! message of each vector in the table, and masks or unmasks it, while the function signals
+//! its vectors through the [`MsixTable`](struct.MsixTable.html), which sends the messages of
+//! the unmasked vectors, and keeps the others pending until they are unmasked.
+
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use super::{Error, MsixCap};
+use crate::bus::{MmioAddress, MmioOffset, MmioRange};
+use crate::msi::{MsiMessage, MsiSender};
+use crate::DeviceMmio;
+
+/// Size of an MSI-X table entry.
+pub const MSIX_TABLE_ENTRY_SIZE: u64 = 16;
+
+const MSIX_ENTRY_MASKED: u32 = 1;
+// The other bits of the vector control word are reserved, and read as zero.
+const MSIX_ENTRY_CONTROL_MASK: u32 = MSIX_ENTRY_MASKED;
+const MSIX_CTL_FUNCTION_MASK: u16 = 0x4000;
+const MSIX_CTL_ENABLE: u16 = 0x8000;
+
+#[derive(Clone, Copy)]
+struct MsixEntry {
+    addr_lo: u32,
+    addr_hi: u32,
+    data: u32,
+    control: u32,
+}
+
+impl Default for MsixEntry {
+    fn default() -> Self {
+        // The vectors are masked after reset.
+        MsixEntry {
+            addr_lo: 0,
+            addr_hi: 0,
+            data: 0,
+            control: MSIX_ENTRY_MASKED,
+        }
+    }
+}
+
+impl MsixEntry {
+    fn to_bytes(self) -> [u8; 16] {
+        let mut bytes = [0; 16];
+        for (i, field) in [self.addr_lo, self.addr_hi, self.data, self.control]
+            .iter()
+            .enumerate()
+        {
+            bytes[i * 4..i * 4 + 4].copy_from_slice(&field.to_le_bytes());
+        }
+        bytes
+    }
+
+    fn from_bytes(bytes: [u8; 16]) -> Self {
+        let field = |i: usize| {
+            let mut value = [0; 4];
+            value.copy_from_slice(&bytes[i * 4..i * 4 + 4]);
+            u32::from_le_bytes(value)
+        };
+        MsixEntry {
+            addr_lo: field(0),
+            addr_hi: field(1),
+            data: field(2),
+            control: field(3),
+        }
+    }
+
+    fn masked(&self) -> bool {
+        self.control & MSIX_ENTRY_MASKED != 0
+    }
+}
+
+struct MsixState {
+    entries: Vec<MsixEntry>,
+    pba: Vec<u64>,
+    enabled: bool,
+    function_masked: bool,
+}
+
+impl MsixState {
+    fn pending(&self, vector: usize) -> bool {
+        self.pba[vector / 64] & (1 << (vector % 64)) != 0
+    }
+
+    fn set_pending(&mut self, vector: usize, pending: bool) {
+        if pending {
+            self.pba[vector / 64] |= 1 << (vector % 64);
+        } else {
+            self.pba[vector / 64] &= !(1 << (vector % 64));
+        }
+    }
+}
+
+/// MSI-X table and pending bit array of a PCI function.
+///
+/// The function embeds the table, registers it (and its `MsixPba`) on the MMIO bus at the
+/// BAR sub-ranges described by its MSI-X capability, forwards the writes of the capability
+/// message control to `set_message_control`, and calls `trigger` to signal its vectors. The
+/// messages of masked vectors are held as pending, and sent when the vectors are unmasked.
+pub struct MsixTable {
+    state: Mutex<MsixState>,
+    senders: Vec<Arc<dyn MsiSender>>,
+    devid: Option<u32>,
+}
+
+impl MsixTable {
+    /// Create a table with `num_vectors` entries. Vector `i` sends its messages through
+    /// `senders[i % senders.len()]`, so a single sender can serve all the vectors, but there
+    /// has to be at least one.
+    pub fn new(num_vectors: u16, senders: Vec<Arc<dyn MsiSender>>) -> Result<Self, Error> {
+        if senders.is_empty() {
+            return Err(Error::MsixNoSenders);
+        }
+        let num_vectors = usize::from(num_vectors.max(1));
+        Ok(MsixTable {
+            state: Mutex::new(MsixState {
+                entries: vec![MsixEntry::default(); num_vectors],
+                pba: vec![0; num_vectors.div_ceil(64)],
+                enabled: false,
+                function_masked: false,
+            }),
+            senders,
+            devid: None,
+        })
+    }
+
+    /// Tag the messages with the requester ID of the function, as needed by the GICv3 ITS.
+    pub fn with_devid(mut self, devid: u32) -> Self {
+        self.devid = Some(devid);
+        self
+    }
+
+    /// Return the number of vectors of the table.
+    pub fn num_vectors(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    // Return the range of `size` bytes at `offset` in the BAR at `bar_addr`.
+    fn bar_range(bar_addr: u64, offset: u32, size: u64) -> Result<MmioRange, Error> {
+        bar_addr
+            .checked_add(u64::from(offset))
+            .and_then(|base| MmioRange::new(MmioAddress(base), size).ok())
+            .ok_or(Error::MsixRangeInvalid(bar_addr, offset))
+    }
+
+    /// Return the range of the table, for a capability `cap` of a function whose table BAR
+    /// is at `bar_addr`.
+    pub fn table_range(cap: &MsixCap, bar_addr: u64) -> Result<MmioRange, Error> {
+        let size = u64::from(cap.table_size()) * MSIX_TABLE_ENTRY_SIZE;
+        Self::bar_range(bar_addr, cap.table_offset(), size)
+    }
+
+    /// Return the range of the pending bit array, for a capability `cap` of a function whose
+    /// PBA BAR is at `bar_addr`.
+    pub fn pba_range(cap: &MsixCap, bar_addr: u64) -> Result<MmioRange, Error> {
+        let size = u64::from(cap.table_size()).div_ceil(64) * 8;
+        Self::bar_range(bar_addr, cap.pba_offset(), size)
+    }
+
+    /// Update the MSI-X enable and function mask bits from the value written by the guest
+    /// to the message control register of the capability.
+    pub fn set_message_control(&self, msg_ctl: u16) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.enabled = msg_ctl & MSIX_CTL_ENABLE != 0;
+        state.function_masked = msg_ctl & MSIX_CTL_FUNCTION_MASK != 0;
+        self.send_pending(&mut state)
+    }
+
+    /// Return whether MSI-X is enabled by the guest. The function signals its interrupts
+    /// through INTx otherwise.
+    pub fn enabled(&self) -> bool {
+        self.state.lock().unwrap().enabled
+    }
+
+    /// Return whether `vector` is masked, either by its own mask bit or by the function mask.
+    pub fn masked(&self, vector: usize) -> bool {
+        let state = self.state.lock().unwrap();
+        state.function_masked || state.entries.get(vector).is_none_or(MsixEntry::masked)
+    }
+
+    /// Return whether a message of `vector` is pending.
+    pub fn pending(&self, vector: usize) -> bool {
+        let state = self.state.lock().unwrap();
+        vector < state.entries.len() && state.pending(vector)
+    }
+
+    /// Signal `vector`. The message is held as pending while the vector is masked, and
+    /// dropped while MSI-X is disabled.
+    pub fn trigger(&self, vector: usize) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let entry = match state.entries.get(vector) {
+            Some(entry) if state.enabled => *entry,
+            _ => return Ok(()),
+        };
+        if state.function_masked || entry.masked() {
+            state.set_pending(vector, true);
+            return Ok(());
+        }
+        self.send(vector, &entry)
+    }
+
+    fn send(&self, vector: usize, entry: &MsixEntry) -> io::Result<()> {
+        let msg = MsiMessage {
+            addr: u64::from(entry.addr_hi) << 32 | u64::from(entry.addr_lo),
+            data: entry.data,
+            devid: self.devid,
+        };
+        self.senders[vector % self.senders.len()].send(msg)
+    }
+
+    // Send the pending messages of the vectors which are no longer masked.
+    fn send_pending(&self, state: &mut MsixState) -> io::Result<()> {
+        if !state.enabled || state.function_masked {
+            return Ok(());
+        }
+        for vector in 0..state.entries.len() {
+            let entry = state.entries[vector];
+            if state.pending(vector) && !entry.masked() {
+                state.set_pending(vector, false);
+                self.send(vector, &entry)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle a read of the table at `offset`.
+    pub fn read_table(&self, offset: u64, data: &mut [u8]) {
+        let state = self.state.lock().unwrap();
+        for (i, byte) in data.iter_mut().enumerate() {
+            let offset = offset + i as u64;
+            let entry = (offset / MSIX_TABLE_ENTRY_SIZE) as usize;
+            *byte = state.entries.get(entry).map_or(0, |entry| {
+                entry.to_bytes()[(offset % MSIX_TABLE_ENTRY_SIZE) as usize]
+            });
+        }
+    }
+
+    /// Handle a write of the table at `offset`. Unmasking a vector sends its pending
+    /// message.
+    pub fn write_table(&self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        for (i, byte) in data.iter().enumerate() {
+            let offset = offset + i as u64;
+            let index = (offset / MSIX_TABLE_ENTRY_SIZE) as usize;
+            if let Some(entry) = state.entries.get_mut(index) {
+                let mut bytes = entry.to_bytes();
+                bytes[(offset % MSIX_TABLE_ENTRY_SIZE) as usize] = *byte;
+                *entry = MsixEntry::from_bytes(bytes);
+                entry.control &= MSIX_ENTRY_CONTROL_MASK;
+            }
+        }
+        self.send_pending(&mut state)
+    }
+
+    /// Handle a read of the pending bit array at `offset`.
+    pub fn read_pba(&self, offset: u64, data: &mut [u8]) {
+        let state = self.state.lock().unwrap();
+        for (i, byte) in data.iter_mut().enumerate() {
+            let offset = (offset + i as u64) as usize;
+            *byte = state
+                .pba
+                .get(offset / 8)
+                .map_or(0, |word| (word >> (8 * (offset % 8))) as u8);
+        }
+    }
+}
+
+impl DeviceMmio for MsixTable {
+    fn mmio_read(&self, _base: MmioAddress, offset: MmioOffset, data: &mut [u8]) {
+        self.read_table(offset.raw(), data)
+    }
+
+    fn mmio_write(&self, _base: MmioAddress, offset: MmioOffset, data: &[u8]) {
+        // Failing to send a pending message is not something the guest can be told about.
+        let _ = self.write_table(offset.raw(), data);
+    }
+}
+
+/// MMIO view of the pending bit array of a `MsixTable`, which is read-only for the guest.
+pub struct MsixPba(pub Arc<MsixTable>);
+
+impl DeviceMmio for MsixPba {
+    fn mmio_read(&self, _base: MmioAddress, offset: MmioOffset, data: &mut [u8]) {
+        self.0.read_pba(offset.raw(), data)
+    }
+
+    fn mmio_write(&self, _base: MmioAddress, _offset: MmioOffset, _data: &[u8]) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::device_manager::{IoManager, MmioManager};
+
+    #[test]
+    fn test_msix_table() {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let sender_sent = sent.clone();
+        let sender = move |msg| {
+            sender_sent.lock().unwrap().push(msg);
+            Ok(())
+        };
+        assert_eq!(
+            MsixTable::new(2, Vec::new()).err(),
+            Some(Error::MsixNoSenders)
+        );
+        let table = Arc::new(MsixTable::new(2, vec![Arc::new(sender)]).unwrap());
+        let cap = MsixCap::new(2, 0, 0, 0, 0x800);
+        assert_eq!(
+            MsixTable::pba_range(&cap, u64::MAX - 0x7ff).err(),
+            Some(Error::MsixRangeInvalid(u64::MAX - 0x7ff, 0x800))
+        );
+        let mut io_mgr = IoManager::new();
+        io_mgr
+            .register_mmio(
+                MsixTable::table_range(&cap, 0x1000_0000).unwrap(),
+                table.clone(),
+            )
+            .unwrap();
+        io_mgr
+            .register_mmio(
+                MsixTable::pba_range(&cap, 0x1000_0000).unwrap(),
+                Arc::new(MsixPba(table.clone())),
+            )
+            .unwrap();
+
+        // Program vector 1 while it's masked.
+        io_mgr
+            .mmio_write(MmioAddress(0x1000_0010), &0xfee0_0000u32.to_le_bytes())
+            .unwrap();
+        io_mgr
+            .mmio_write(MmioAddress(0x1000_0018), &0x41u32.to_le_bytes())
+            .unwrap();
+        table.set_message_control(MSIX_CTL_ENABLE).unwrap();
+        assert!(table.masked(1));
+        table.trigger(1).unwrap();
+        assert!(table.pending(1));
+        let mut pba = [0; 8];
+        io_mgr
+            .mmio_read(MmioAddress(0x1000_0800), &mut pba)
+            .unwrap();
+        assert_eq!(pba[0], 0b10);
+
+        // Unmasking the vector sends the pending message. The reserved bits of the vector
+        // control word are ignored.
+        io_mgr
+            .mmio_write(MmioAddress(0x1000_001c), &0xffff_fffeu32.to_le_bytes())
+            .unwrap();
+        let mut control = [0; 4];
+        io_mgr
+            .mmio_read(MmioAddress(0x1000_001c), &mut control)
+            .unwrap();
+        assert_eq!(control, [0; 4]);
+        assert!(!table.pending(1));
+        assert_eq!(
+            *sent.lock().unwrap(),
+            vec![MsiMessage::new(0xfee0_0000, 0x41)]
+        );
+        table.trigger(1).unwrap();
+        assert_eq!(sent.lock().unwrap().len(), 2);
+
+        // The function mask holds the messages of all vectors.
+        table
+            .set_message_control(MSIX_CTL_ENABLE | MSIX_CTL_FUNCTION_MASK)
+            .unwrap();
+        table.trigger(1).unwrap();
+        assert!(table.pending(1));
+        table.set_message_control(MSIX_CTL_ENABLE).unwrap();
+        assert_eq!(sent.lock().unwrap().len(), 3);
+
+        let mut data = [0; 4];
+        io_mgr
+            .mmio_read(MmioAddress(0x1000_0018), &mut data)
+            .unwrap();
+        assert_eq!(u32::from_le_bytes(data), 0x41);
+    }
+}
@@ -0,0 +1,215 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Dispatch of accesses to devices which are banked per vCPU.
+//!
+//! Some devices (such as the local APIC, or the GIC CPU interface) are mapped at the same
+//! address for every vCPU, but each vCPU sees its own instance. A
+//! [`PerCpuDevice`](struct.PerCpuDevice.html) holds one instance per vCPU, and forwards each
+//! access to the instance of the vCPU which performed it, based on the `vcpu_id` of the
+//! `IoAccess` context. Accesses which don't carry a vCPU index, or carry an index without
+//! a matching instance, are ignored: reads return zeros, and writes are dropped.
+
+use crate::bus::{
+    MmioAddress, MmioOffset, MsrAddress, MsrAddressValue, PioAddress, PioOffset, SysRegAddress,
+    SysRegAddressValue,
+};
+use crate::{DeviceMmio, DeviceMsr, DevicePio, DeviceSysReg, IoAccess};
+
+/// Holds a device instance for each vCPU.
+pub struct PerCpuDevice<T> {
+    devices: Vec<T>,
+}
+
+impl<T> PerCpuDevice<T> {
+    /// Create a new object, where `devices[i]` handles the accesses of vCPU `i`.
+    pub fn new(devices: Vec<T>) -> Self {
+        PerCpuDevice { devices }
+    }
+
+    /// Return the instance of the vCPU with index `vcpu_id`.
+    pub fn device(&self, vcpu_id: u32) -> Option<&T> {
+        self.devices.get(vcpu_id as usize)
+    }
+
+    /// Return the number of instances.
+    pub fn len(&self) -> usize {
+        self.devices.len()
+    }
+
+    /// Return `true` if there are no instances.
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+
+    fn target(&self, access: &IoAccess) -> Option<&T> {
+        access.vcpu_id.and_then(|id| self.device(id))
+    }
+}
+
+impl<T: DevicePio> DevicePio for PerCpuDevice<T> {
+    fn pio_read(&self, _base: PioAddress, _offset: PioOffset, data: &mut [u8]) {
+        data.iter_mut().for_each(|byte| *byte = 0);
+    }
+
+    fn pio_write(&self, _base: PioAddress, _offset: PioOffset, _data: &[u8]) {}
+
+    fn pio_read_with(
+        &self,
+        access: IoAccess,
+        base: PioAddress,
+        offset: PioOffset,
+        data: &mut [u8],
+    ) {
+        match self.target(&access) {
+            Some(device) => device.pio_read_with(access, base, offset, data),
+            None => self.pio_read(base, offset, data),
+        }
+    }
+
+    fn pio_write_with(&self, access: IoAccess, base: PioAddress, offset: PioOffset, data: &[u8]) {
+        if let Some(device) = self.target(&access) {
+            device.pio_write_with(access, base, offset, data);
+        }
+    }
+}
+
+impl<T: DeviceMmio> DeviceMmio for PerCpuDevice<T> {
+    fn mmio_read(&self, _base: MmioAddress, _offset: MmioOffset, data: &mut [u8]) {
+        data.iter_mut().for_each(|byte| *byte = 0);
+    }
+
+    fn mmio_write(&self, _base: MmioAddress, _offset: MmioOffset, _data: &[u8]) {}
+
+    fn mmio_read_with(
+        &self,
+        access: IoAccess,
+        base: MmioAddress,
+        offset: MmioOffset,
+        data: &mut [u8],
+    ) {
+        match self.target(&access) {
+            Some(device) => device.mmio_read_with(access, base, offset, data),
+            None => self.mmio_read(base, offset, data),
+        }
+    }
+
+    fn mmio_write_with(
+        &self,
+        access: IoAccess,
+        base: MmioAddress,
+        offset: MmioOffset,
+        data: &[u8],
+    ) {
+        if let Some(device) = self.target(&access) {
+            device.mmio_write_with(access, base, offset, data);
+        }
+    }
+}
+
+impl<T: DeviceSysReg> DeviceSysReg for PerCpuDevice<T> {
+    fn sysreg_read(&self, _base: SysRegAddress, _offset: SysRegAddressValue) -> u64 {
+        0
+    }
+
+    fn sysreg_write(&self, _base: SysRegAddress, _offset: SysRegAddressValue, _value: u64) {}
+
+    fn sysreg_read_with(
+        &self,
+        access: IoAccess,
+        base: SysRegAddress,
+        offset: SysRegAddressValue,
+    ) -> u64 {
+        self.target(&access)
+            .map_or(0, |device| device.sysreg_read_with(access, base, offset))
+    }
+
+    fn sysreg_write_with(
+        &self,
+        access: IoAccess,
+        base: SysRegAddress,
+        offset: SysRegAddressValue,
+        value: u64,
+    ) {
+        if let Some(device) = self.target(&access) {
+            device.sysreg_write_with(access, base, offset, value);
+        }
+    }
+}
+
+impl<T: DeviceMsr> DeviceMsr for PerCpuDevice<T> {
+    fn msr_read(&self, _base: MsrAddress, _offset: MsrAddressValue) -> u64 {
+        0
+    }
+
+    fn msr_write(&self, _base: MsrAddress, _offset: MsrAddressValue, _value: u64) {}
+
+    fn msr_read_with(&self, access: IoAccess, base: MsrAddress, offset: MsrAddressValue) -> u64 {
+        self.target(&access)
+            .map_or(0, |device| device.msr_read_with(access, base, offset))
+    }
+
+    fn msr_write_with(
+        &self,
+        access: IoAccess,
+        base: MsrAddress,
+        offset: MsrAddressValue,
+        value: u64,
+    ) {
+        if let Some(device) = self.target(&access) {
+            device.msr_write_with(access, base, offset, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::EchoDevice;
+
+    #[test]
+    fn test_per_cpu_device() {
+        let device = PerCpuDevice::new(vec![EchoDevice::new(), EchoDevice::new()]);
+        assert_eq!(device.len(), 2);
+        assert!(!device.is_empty());
+        let (base, offset) = (MmioAddress(0xfee0_0000), MmioOffset(0x20));
+        let mut data = [0; 2];
+
+        // Each vCPU only reaches its own instance.
+        device.mmio_write_with(IoAccess::new(0, 2), base, offset, &[1, 2]);
+        device.mmio_read_with(IoAccess::new(1, 2), base, offset, &mut data);
+        assert_eq!(data, [0, 0]);
+        device.mmio_write_with(IoAccess::new(1, 2), base, offset, &[3, 4]);
+        device.mmio_read_with(IoAccess::new(0, 2), base, offset, &mut data);
+        assert_eq!(data, [1, 2]);
+        device.mmio_read_with(IoAccess::new(1, 2), base, offset, &mut data);
+        assert_eq!(data, [3, 4]);
+        assert_eq!(device.device(0).unwrap().accesses().len(), 2);
+        assert_eq!(device.device(1).unwrap().accesses().len(), 3);
+
+        // The PIO accesses are routed the same way.
+        device.pio_write_with(IoAccess::new(1, 1), PioAddress(0x60), PioOffset(0), &[5]);
+        let mut data = [0; 1];
+        device.pio_read_with(
+            IoAccess::new(0, 1),
+            PioAddress(0x60),
+            PioOffset(0),
+            &mut data,
+        );
+        assert_eq!(data, [1]);
+
+        // Accesses without a matching instance don't reach any of them.
+        assert!(device.device(2).is_none());
+        let mut data = [0xff; 2];
+        device.mmio_write_with(IoAccess::new(2, 2), base, offset, &[6, 7]);
+        device.mmio_read_with(IoAccess::new(2, 2), base, offset, &mut data);
+        assert_eq!(data, [0, 0]);
+        data = [0xff; 2];
+        device.mmio_read(base, offset, &mut data);
+        assert_eq!(data, [0, 0]);
+        device.mmio_write(base, offset, &[8, 9]);
+        assert_eq!(device.device(0).unwrap().accesses().len(), 3);
+        assert_eq!(device.device(1).unwrap().accesses().len(), 4);
+    }
+}
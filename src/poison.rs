@@ -0,0 +1,556 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Containment of device models which panic.
+//!
+//! The blanket implementations of the device traits for `Mutex<T>` lock the device for
+//! every access. When a device model panics while handling an access, the lock is poisoned,
+//! and every later access to the device panics as well, taking down each vCPU touching it.
+//! Wrapping the device in a [`SupervisedMutex`](struct.SupervisedMutex.html) instead lets
+//! a [`PoisonPolicy`](enum.PoisonPolicy.html) decide what happens, and notifies a
+//! [`Supervisor`](type.Supervisor.html) of each access finding the lock poisoned. Both are
+//! set per device, so devices registered with different managers don't affect each other.
+//!
+//! Devices can also be isolated individually by wrapping them in a
+//! [`CatchUnwindDevice`](struct.CatchUnwindDevice.html), which stops the panics before they
+//! reach the vCPU, and fails the accesses to the device from then on. The whole device map
+//! can be isolated with `IoManager::set_catch_panics`, which quarantines the range of the
+//! devices which panic.
+
+use std::any::type_name;
+use std::fmt::{Display, Formatter};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::bus::{MmioAddress, MmioOffset, PioAddress, PioOffset};
+use crate::bus::{MsrAddress, MsrAddressValue, SysRegAddress, SysRegAddressValue};
+use crate::{
+    DeviceMmio, DeviceMsr, DevicePio, DeviceSysReg, IoAccess, MutDeviceMmio, MutDeviceMsr,
+    MutDevicePio, MutDeviceSysReg,
+};
+
+/// Errors reported by the fallible accesses to devices.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DeviceError {
+    /// The lock of the device was poisoned by a panic during a previous access.
+    Poisoned,
+    /// The device panicked while handling the access, or during a previous one.
+    Panicked,
+}
+
+impl Display for DeviceError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeviceError::Poisoned => write!(f, "device lock poisoned by a panic"),
+            DeviceError::Panicked => write!(f, "device panicked while handling an access"),
+        }
+    }
+}
+
+impl std::error::Error for DeviceError {}
+
+/// What a [`SupervisedMutex`](struct.SupervisedMutex.html) does when it finds the lock of
+/// the device poisoned.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum PoisonPolicy {
+    /// Propagate the panic to the caller (the default).
+    #[default]
+    Panic,
+    /// Clear the poison and keep accessing the device, whatever state it was left in.
+    Recover,
+    /// Fail the access without reaching the device: reads return all ones and writes are
+    /// dropped, while the `try_*` methods of the device traits return
+    /// `DeviceError::Poisoned`.
+    Fail,
+}
+
+/// Callback notified with the type name of each device found with a poisoned lock.
+pub type Supervisor = Arc<dyn Fn(&'static str) + Send + Sync>;
+
+// Run `f` with the device locked, applying `policy` if the lock is poisoned.
+pub(crate) fn with_lock<T: ?Sized, R, F: FnOnce(&mut T) -> R>(
+    mutex: &Mutex<T>,
+    policy: PoisonPolicy,
+    supervisor: Option<&Supervisor>,
+    f: F,
+) -> Result<R, DeviceError> {
+    let mut guard = match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => {
+            if let Some(supervisor) = supervisor {
+                supervisor(type_name::<T>());
+            }
+            match policy {
+                PoisonPolicy::Panic => panic!("device lock poisoned: {}", type_name::<T>()),
+                PoisonPolicy::Recover => {
+                    mutex.clear_poison();
+                    poisoned.into_inner()
+                }
+                PoisonPolicy::Fail => return Err(DeviceError::Poisoned),
+            }
+        }
+    };
+    Ok(f(&mut guard))
+}
+
+// Fill the buffer of a failed read the way unbacked addresses read.
+pub(crate) fn fail_read(data: &mut [u8]) {
+    data.iter_mut().for_each(|byte| *byte = 0xff);
+}
+
+/// Applies a [`PoisonPolicy`](enum.PoisonPolicy.html) to the lock of the wrapped device.
+///
+/// It implements the device traits the same way the blanket `Mutex<T>` implementations do,
+/// except that a poisoned lock is handled according to the policy of the wrapper, and
+/// reported to its supervisor, instead of panicking.
+pub struct SupervisedMutex<T> {
+    device: Mutex<T>,
+    policy: PoisonPolicy,
+    supervisor: Option<Supervisor>,
+}
+
+impl<T> SupervisedMutex<T> {
+    /// Wrap `device`, with the `Panic` policy and no supervisor.
+    pub fn new(device: T) -> Self {
+        SupervisedMutex {
+            device: Mutex::new(device),
+            policy: PoisonPolicy::default(),
+            supervisor: None,
+        }
+    }
+
+    /// Apply `policy` when the lock of the device is found poisoned.
+    pub fn with_policy(mut self, policy: PoisonPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Notify `supervisor` with the type name of the device each time its lock is found
+    /// poisoned. It is called before the policy is applied, from the thread performing the
+    /// access.
+    pub fn with_supervisor(mut self, supervisor: Supervisor) -> Self {
+        self.supervisor = Some(supervisor);
+        self
+    }
+
+    /// Return the policy applied to the lock of the device.
+    pub fn policy(&self) -> PoisonPolicy {
+        self.policy
+    }
+
+    /// Return the lock of the device.
+    pub fn inner(&self) -> &Mutex<T> {
+        &self.device
+    }
+
+    fn with_lock<R, F: FnOnce(&mut T) -> R>(&self, f: F) -> Result<R, DeviceError> {
+        with_lock(&self.device, self.policy, self.supervisor.as_ref(), f)
+    }
+}
+
+impl<T: MutDevicePio> DevicePio for SupervisedMutex<T> {
+    fn pio_read(&self, base: PioAddress, offset: PioOffset, data: &mut [u8]) {
+        self.pio_read_with(IoAccess::default(), base, offset, data)
+    }
+
+    fn pio_write(&self, base: PioAddress, offset: PioOffset, data: &[u8]) {
+        self.pio_write_with(IoAccess::default(), base, offset, data)
+    }
+
+    fn pio_read_with(
+        &self,
+        access: IoAccess,
+        base: PioAddress,
+        offset: PioOffset,
+        data: &mut [u8],
+    ) {
+        if self.try_pio_read(access, base, offset, data).is_err() {
+            fail_read(data);
+        }
+    }
+
+    fn pio_write_with(&self, access: IoAccess, base: PioAddress, offset: PioOffset, data: &[u8]) {
+        let _ = self.try_pio_write(access, base, offset, data);
+    }
+
+    fn try_pio_read(
+        &self,
+        access: IoAccess,
+        base: PioAddress,
+        offset: PioOffset,
+        data: &mut [u8],
+    ) -> Result<(), DeviceError> {
+        self.with_lock(|device| device.pio_read_with(access, base, offset, data))
+    }
+
+    fn try_pio_write(
+        &self,
+        access: IoAccess,
+        base: PioAddress,
+        offset: PioOffset,
+        data: &[u8],
+    ) -> Result<(), DeviceError> {
+        self.with_lock(|device| device.pio_write_with(access, base, offset, data))
+    }
+}
+
+impl<T: MutDeviceMmio> DeviceMmio for SupervisedMutex<T> {
+    fn mmio_read(&self, base: MmioAddress, offset: MmioOffset, data: &mut [u8]) {
+        self.mmio_read_with(IoAccess::default(), base, offset, data)
+    }
+
+    fn mmio_write(&self, base: MmioAddress, offset: MmioOffset, data: &[u8]) {
+        self.mmio_write_with(IoAccess::default(), base, offset, data)
+    }
+
+    fn mmio_read_with(
+        &self,
+        access: IoAccess,
+        base: MmioAddress,
+        offset: MmioOffset,
+        data: &mut [u8],
+    ) {
+        if self.try_mmio_read(access, base, offset, data).is_err() {
+            fail_read(data);
+        }
+    }
+
+    fn mmio_write_with(
+        &self,
+        access: IoAccess,
+        base: MmioAddress,
+        offset: MmioOffset,
+        data: &[u8],
+    ) {
+        let _ = self.try_mmio_write(access, base, offset, data);
+    }
+
+    fn try_mmio_read(
+        &self,
+        access: IoAccess,
+        base: MmioAddress,
+        offset: MmioOffset,
+        data: &mut [u8],
+    ) -> Result<(), DeviceError> {
+        self.with_lock(|device| device.mmio_read_with(access, base, offset, data))
+    }
+
+    fn try_mmio_write(
+        &self,
+        access: IoAccess,
+        base: MmioAddress,
+        offset: MmioOffset,
+        data: &[u8],
+    ) -> Result<(), DeviceError> {
+        self.with_lock(|device| device.mmio_write_with(access, base, offset, data))
+    }
+}
+
+impl<T: MutDeviceSysReg> DeviceSysReg for SupervisedMutex<T> {
+    fn sysreg_read(&self, base: SysRegAddress, offset: SysRegAddressValue) -> u64 {
+        self.sysreg_read_with(IoAccess::default(), base, offset)
+    }
+
+    fn sysreg_write(&self, base: SysRegAddress, offset: SysRegAddressValue, value: u64) {
+        self.sysreg_write_with(IoAccess::default(), base, offset, value)
+    }
+
+    fn sysreg_read_with(
+        &self,
+        access: IoAccess,
+        base: SysRegAddress,
+        offset: SysRegAddressValue,
+    ) -> u64 {
+        self.with_lock(|device| device.sysreg_read_with(access, base, offset))
+            .unwrap_or(u64::MAX)
+    }
+
+    fn sysreg_write_with(
+        &self,
+        access: IoAccess,
+        base: SysRegAddress,
+        offset: SysRegAddressValue,
+        value: u64,
+    ) {
+        let _ = self.with_lock(|device| device.sysreg_write_with(access, base, offset, value));
+    }
+}
+
+impl<T: MutDeviceMsr> DeviceMsr for SupervisedMutex<T> {
+    fn msr_read(&self, base: MsrAddress, offset: MsrAddressValue) -> u64 {
+        self.msr_read_with(IoAccess::default(), base, offset)
+    }
+
+    fn msr_write(&self, base: MsrAddress, offset: MsrAddressValue, value: u64) {
+        self.msr_write_with(IoAccess::default(), base, offset, value)
+    }
+
+    fn msr_read_with(&self, access: IoAccess, base: MsrAddress, offset: MsrAddressValue) -> u64 {
+        self.with_lock(|device| device.msr_read_with(access, base, offset))
+            .unwrap_or(u64::MAX)
+    }
+
+    fn msr_write_with(
+        &self,
+        access: IoAccess,
+        base: MsrAddress,
+        offset: MsrAddressValue,
+        value: u64,
+    ) {
+        let _ = self.with_lock(|device| device.msr_write_with(access, base, offset, value));
+    }
+}
+
+/// Stops the panics of the wrapped device before they reach the vCPU.
+///
+/// Once the device has panicked its state can't be trusted anymore, so every following
+/// access fails without reaching it, until `reset` is called.
+pub struct CatchUnwindDevice<D> {
+    device: D,
+    failed: AtomicBool,
+    supervisor: Option<Supervisor>,
+}
+
+impl<D> CatchUnwindDevice<D> {
+    /// Wrap `device`.
+    pub fn new(device: D) -> Self {
+        CatchUnwindDevice {
+            device,
+            failed: AtomicBool::new(false),
+            supervisor: None,
+        }
+    }
+
+    /// Notify `supervisor` with the type name of the device when it panics.
+    pub fn with_supervisor(mut self, supervisor: Supervisor) -> Self {
+        self.supervisor = Some(supervisor);
+        self
+    }
+
+    /// Return `true` if the device has panicked since it was wrapped or last reset.
+    pub fn has_failed(&self) -> bool {
+        self.failed.load(Ordering::Acquire)
+    }
+
+    /// Let the accesses reach the device again, e.g. after it was restored from a snapshot.
+    pub fn reset(&self) {
+        self.failed.store(false, Ordering::Release);
+    }
+
+    /// Return the wrapped device.
+    pub fn inner(&self) -> &D {
+        &self.device
+    }
+
+    fn guard<F>(&self, access: F) -> Result<(), DeviceError>
+    where
+        F: FnOnce() -> Result<(), DeviceError>,
+    {
+        if self.has_failed() {
+            return Err(DeviceError::Panicked);
+        }
+        catch_unwind(AssertUnwindSafe(access)).unwrap_or_else(|_| {
+            self.failed.store(true, Ordering::Release);
+            if let Some(supervisor) = self.supervisor.as_ref() {
+                supervisor(type_name::<D>());
+            }
+            Err(DeviceError::Panicked)
+        })
+    }
+}
+
+impl<D: DevicePio> DevicePio for CatchUnwindDevice<D> {
+    fn pio_read(&self, base: PioAddress, offset: PioOffset, data: &mut [u8]) {
+        self.pio_read_with(IoAccess::default(), base, offset, data);
+    }
+
+    fn pio_write(&self, base: PioAddress, offset: PioOffset, data: &[u8]) {
+        self.pio_write_with(IoAccess::default(), base, offset, data);
+    }
+
+    fn pio_read_with(
+        &self,
+        access: IoAccess,
+        base: PioAddress,
+        offset: PioOffset,
+        data: &mut [u8],
+    ) {
+        if self.try_pio_read(access, base, offset, data).is_err() {
+            fail_read(data);
+        }
+    }
+
+    fn pio_write_with(&self, access: IoAccess, base: PioAddress, offset: PioOffset, data: &[u8]) {
+        let _ = self.try_pio_write(access, base, offset, data);
+    }
+
+    fn try_pio_read(
+        &self,
+        access: IoAccess,
+        base: PioAddress,
+        offset: PioOffset,
+        data: &mut [u8],
+    ) -> Result<(), DeviceError> {
+        self.guard(|| self.device.try_pio_read(access, base, offset, data))
+    }
+
+    fn try_pio_write(
+        &self,
+        access: IoAccess,
+        base: PioAddress,
+        offset: PioOffset,
+        data: &[u8],
+    ) -> Result<(), DeviceError> {
+        self.guard(|| self.device.try_pio_write(access, base, offset, data))
+    }
+}
+
+impl<D: DeviceMmio> DeviceMmio for CatchUnwindDevice<D> {
+    fn mmio_read(&self, base: MmioAddress, offset: MmioOffset, data: &mut [u8]) {
+        self.mmio_read_with(IoAccess::default(), base, offset, data);
+    }
+
+    fn mmio_write(&self, base: MmioAddress, offset: MmioOffset, data: &[u8]) {
+        self.mmio_write_with(IoAccess::default(), base, offset, data);
+    }
+
+    fn mmio_read_with(
+        &self,
+        access: IoAccess,
+        base: MmioAddress,
+        offset: MmioOffset,
+        data: &mut [u8],
+    ) {
+        if self.try_mmio_read(access, base, offset, data).is_err() {
+            fail_read(data);
+        }
+    }
+
+    fn mmio_write_with(
+        &self,
+        access: IoAccess,
+        base: MmioAddress,
+        offset: MmioOffset,
+        data: &[u8],
+    ) {
+        let _ = self.try_mmio_write(access, base, offset, data);
+    }
+
+    fn try_mmio_read(
+        &self,
+        access: IoAccess,
+        base: MmioAddress,
+        offset: MmioOffset,
+        data: &mut [u8],
+    ) -> Result<(), DeviceError> {
+        self.guard(|| self.device.try_mmio_read(access, base, offset, data))
+    }
+
+    fn try_mmio_write(
+        &self,
+        access: IoAccess,
+        base: MmioAddress,
+        offset: MmioOffset,
+        data: &[u8],
+    ) -> Result<(), DeviceError> {
+        self.guard(|| self.device.try_mmio_write(access, base, offset, data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::AtomicUsize;
+
+    use crate::MutDeviceMmio;
+
+    // Panics on writes of zero.
+    struct Fragile(u8);
+
+    impl MutDeviceMmio for Fragile {
+        fn mmio_read(&mut self, _base: MmioAddress, _offset: MmioOffset, data: &mut [u8]) {
+            data[0] = self.0;
+        }
+
+        fn mmio_write(&mut self, _base: MmioAddress, _offset: MmioOffset, data: &[u8]) {
+            assert_ne!(data[0], 0);
+            self.0 = data[0];
+        }
+    }
+
+    #[test]
+    fn test_poisoned_device() {
+        let base = MmioAddress(0);
+        let offset = MmioOffset(0);
+        let notified = Arc::new(AtomicUsize::new(0));
+        let counter = notified.clone();
+        let supervisor: Supervisor = Arc::new(move |name| {
+            assert!(name.contains("Fragile"));
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+
+        // The panic is caught by the wrapper, which fails the accesses from then on.
+        let device = CatchUnwindDevice::new(
+            SupervisedMutex::new(Fragile(1))
+                .with_policy(PoisonPolicy::Fail)
+                .with_supervisor(supervisor.clone()),
+        )
+        .with_supervisor(supervisor);
+        device.mmio_write(base, offset, &[2]);
+        assert_eq!(
+            device.try_mmio_write(IoAccess::default(), base, offset, &[0]),
+            Err(DeviceError::Panicked)
+        );
+        assert!(device.has_failed());
+        assert_eq!(notified.load(Ordering::SeqCst), 1);
+        let mut data = [0];
+        device.mmio_read(base, offset, &mut data);
+        assert_eq!(data, [0xff]);
+
+        // The lock of the inner device is now poisoned.
+        device.reset();
+        assert_eq!(
+            device.try_mmio_read(IoAccess::default(), base, offset, &mut data),
+            Err(DeviceError::Poisoned)
+        );
+        assert_eq!(data, [0xff]);
+        assert_eq!(notified.load(Ordering::SeqCst), 2);
+        assert!(device.inner().inner().is_poisoned());
+    }
+
+    #[test]
+    fn test_poison_policy() {
+        let base = MmioAddress(0);
+        let offset = MmioOffset(0);
+        let poison = |device: &SupervisedMutex<Fragile>| {
+            device.mmio_write(base, offset, &[3]);
+            let _ = catch_unwind(AssertUnwindSafe(|| device.mmio_write(base, offset, &[0])));
+            assert!(device.inner().is_poisoned());
+        };
+        let mut data = [0];
+
+        // Two devices with different policies don't affect each other.
+        let recovering = SupervisedMutex::new(Fragile(1)).with_policy(PoisonPolicy::Recover);
+        let failing = SupervisedMutex::new(Fragile(1)).with_policy(PoisonPolicy::Fail);
+        poison(&recovering);
+        poison(&failing);
+
+        recovering.mmio_read(base, offset, &mut data);
+        assert_eq!(data, [3]);
+        assert!(!recovering.inner().is_poisoned());
+        failing.mmio_read(base, offset, &mut data);
+        assert_eq!(data, [0xff]);
+        assert!(failing.inner().is_poisoned());
+
+        // The default policy keeps panicking, like the blanket `Mutex<T>` implementations.
+        let panicking = SupervisedMutex::new(Fragile(1));
+        assert_eq!(panicking.policy(), PoisonPolicy::Panic);
+        poison(&panicking);
+        assert!(catch_unwind(AssertUnwindSafe(|| {
+            panicking.mmio_read(base, offset, &mut data)
+        }))
+        .is_err());
+    }
+}
@@ -0,0 +1,224 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Registers which the guest reads from shared memory instead of trapping.
+//!
+//! Guests keep polling some status registers (completion counters, link state), and each
+//! read costs an exit. Similarly to the steal time or pvclock pages, a device implementing
+//! [`PolledDevice`](trait.PolledDevice.html) can instead publish the value of such registers
+//! in a page of shared memory, which the VMM maps read-only into the guest over the part of
+//! the device range holding them. The guest reads the page without exiting, while its writes
+//! still trap to the device.
+//!
+//! The VMM provides the memory through the [`SharedPage`](trait.SharedPage.html) trait, and
+//! registers it with `IoManager::register_polled_page`, which marks the range as mappable and
+//! hands the page to the device as a [`PolledPage`](struct.PolledPage.html). The device then
+//! updates the page every time one of the registers changes.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use crate::bus::{self, BusRange, MmioBusAddress};
+use crate::device_manager::{Error, IoManager};
+use crate::mappable::Mappable;
+
+/// Memory shared with the guest, e.g. a mapping of the file which is also mapped into the
+/// guest address space.
+pub trait SharedPage: Send + Sync {
+    /// Return the size of the page.
+    fn size(&self) -> u64;
+
+    /// Copy `data` to `offset` within the page.
+    fn write(&self, offset: u64, data: &[u8]);
+}
+
+/// Page of shared memory attached to a device, through which it publishes its polled
+/// registers.
+#[derive(Clone)]
+pub struct PolledPage {
+    page: Arc<dyn SharedPage>,
+}
+
+impl PolledPage {
+    /// Write `data` at `offset` within the page. Writes past the end of the page are
+    /// truncated.
+    pub fn write(&self, offset: u64, data: &[u8]) {
+        let len = self
+            .page
+            .size()
+            .saturating_sub(offset)
+            .min(data.len() as u64);
+        if len > 0 {
+            self.page.write(offset, &data[..len as usize]);
+        }
+    }
+
+    /// Write the little endian `value` at `offset` within the page.
+    pub fn write_u32(&self, offset: u64, value: u32) {
+        self.write(offset, &value.to_le_bytes());
+    }
+
+    /// Write the little endian `value` at `offset` within the page.
+    pub fn write_u64(&self, offset: u64, value: u64) {
+        self.write(offset, &value.to_le_bytes());
+    }
+}
+
+/// Devices publishing some of their registers through a shared page.
+pub trait PolledDevice {
+    /// Start publishing the polled registers through `page`. The device writes the current
+    /// value of the registers right away, and then whenever they change.
+    fn attach_page(&self, page: PolledPage);
+
+    /// Stop publishing the polled registers, e.g. before the page is unmapped. The guest
+    /// reads them through trapped accesses again.
+    fn detach_page(&self);
+}
+
+/// Polled devices registered with an `IoManager`, keyed by the range of their page.
+pub(crate) type PolledDevices<M> = BTreeMap<BusRange<M>, Arc<dyn PolledDevice + Send + Sync>>;
+
+impl<M: MmioBusAddress> IoManager<M> {
+    /// Publish the registers of `device` within `range` through `page`. The range, which
+    /// must be part of the range of the registered MMIO device, is marked as mappable
+    /// read-only into the guest as described by `mappable`, and the page is attached to
+    /// the device.
+    pub fn register_polled_page(
+        &mut self,
+        range: BusRange<M>,
+        device: Arc<dyn PolledDevice + Send + Sync>,
+        page: Arc<dyn SharedPage>,
+        mappable: Mappable,
+    ) -> Result<(), Error> {
+        if range.size().into() > page.size() {
+            return Err(Error::Bus(bus::Error::InvalidRange));
+        }
+        self.mark_mappable(range, mappable.read_only())?;
+        device.attach_page(PolledPage { page });
+        self.polled_devices.insert(range, device);
+        Ok(())
+    }
+
+    /// Detach the page registered at `addr` from its device, and stop reporting it as
+    /// mappable. Return the range of the page.
+    pub fn deregister_polled_page(&mut self, addr: M) -> Option<BusRange<M>> {
+        let range = *self
+            .polled_devices
+            .keys()
+            .find(|range| range.base() <= addr && range.last() >= addr)?;
+        if let Some(device) = self.polled_devices.remove(&range) {
+            device.detach_page();
+        }
+        self.unmark_mappable(range.base());
+        Some(range)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::File;
+    use std::os::unix::io::AsRawFd;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Mutex;
+
+    use crate::bus::{MmioAddress, MmioOffset, MmioRange};
+    use crate::device_manager::MmioManager;
+    use crate::DeviceMmio;
+
+    const STATUS: u64 = 0x1000;
+
+    struct MemoryPage(Mutex<Vec<u8>>);
+
+    impl SharedPage for MemoryPage {
+        fn size(&self) -> u64 {
+            self.0.lock().unwrap().len() as u64
+        }
+
+        fn write(&self, offset: u64, data: &[u8]) {
+            let offset = offset as usize;
+            self.0.lock().unwrap()[offset..offset + data.len()].copy_from_slice(data);
+        }
+    }
+
+    // Completion counter at `STATUS`, published through the page once attached.
+    #[derive(Default)]
+    struct Queue {
+        completed: AtomicU32,
+        page: Mutex<Option<PolledPage>>,
+    }
+
+    impl Queue {
+        fn complete(&self) {
+            let completed = self.completed.fetch_add(1, Ordering::SeqCst) + 1;
+            if let Some(page) = self.page.lock().unwrap().as_ref() {
+                page.write_u32(0, completed);
+            }
+        }
+    }
+
+    impl PolledDevice for Queue {
+        fn attach_page(&self, page: PolledPage) {
+            page.write_u32(0, self.completed.load(Ordering::SeqCst));
+            *self.page.lock().unwrap() = Some(page);
+        }
+
+        fn detach_page(&self) {
+            self.page.lock().unwrap().take();
+        }
+    }
+
+    impl DeviceMmio for Queue {
+        fn mmio_read(&self, _base: MmioAddress, offset: MmioOffset, data: &mut [u8]) {
+            if offset.raw() == STATUS {
+                let value = self.completed.load(Ordering::SeqCst).to_le_bytes();
+                data.copy_from_slice(&value[..data.len()]);
+            }
+        }
+
+        fn mmio_write(&self, _base: MmioAddress, _offset: MmioOffset, _data: &[u8]) {}
+    }
+
+    #[test]
+    fn test_polled_page() {
+        let mut io_mgr = IoManager::new();
+        let fd: Arc<dyn AsRawFd + Send + Sync> = Arc::new(File::open("/dev/null").unwrap());
+        let queue = Arc::new(Queue::default());
+        let regs = MmioRange::new(MmioAddress(0xc000_0000), 0x2000).unwrap();
+        let status = MmioRange::new(MmioAddress(0xc000_1000), 0x1000).unwrap();
+        let page = Arc::new(MemoryPage(Mutex::new(vec![0xff; 0x1000])));
+        io_mgr.register_mmio(regs, queue.clone()).unwrap();
+
+        // The page must cover the range.
+        let small = Arc::new(MemoryPage(Mutex::new(vec![0; 0x800])));
+        assert!(io_mgr
+            .register_polled_page(status, queue.clone(), small, Mappable::new(fd.clone(), 0))
+            .is_err());
+
+        queue.complete();
+        io_mgr
+            .register_polled_page(status, queue.clone(), page.clone(), Mappable::new(fd, 0))
+            .unwrap();
+        assert_eq!(&page.0.lock().unwrap()[..4], &[1, 0, 0, 0]);
+        let regions = io_mgr.mappable_regions();
+        assert_eq!(regions[0].0, status);
+        assert!(regions[0].1.read_only);
+
+        // The page follows the register, which can still be read through trapped accesses.
+        queue.complete();
+        assert_eq!(&page.0.lock().unwrap()[..4], &[2, 0, 0, 0]);
+        let mut data = [0; 4];
+        io_mgr.mmio_read(status.base(), &mut data).unwrap();
+        assert_eq!(data, [2, 0, 0, 0]);
+
+        assert_eq!(
+            io_mgr.deregister_polled_page(MmioAddress(0xc000_1004)),
+            Some(status)
+        );
+        assert!(io_mgr.mappable_regions().is_empty());
+        queue.complete();
+        assert_eq!(&page.0.lock().unwrap()[..4], &[2, 0, 0, 0]);
+        assert!(io_mgr.deregister_polled_page(status.base()).is_none());
+    }
+}
@@ -0,0 +1,183 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Quiescing devices, so snapshots capture a consistent state.
+//!
+//! Devices which perform DMA or have asynchronous operations in flight implement
+//! [`Quiesce`](trait.Quiesce.html) and are registered with `IoManager::register_quiesce`.
+//! `IoManager::quiesce` asks each of them to stop initiating DMA, and waits until all of them
+//! signal, through their [`QuiesceCompletion`](struct.QuiesceCompletion.html), that their
+//! in-flight operations completed. Devices can signal it right away, or later from another
+//! thread (e.g. once an IO backend drained its queue). `IoManager::resume` lets the devices
+//! start operating again.
+
+use std::collections::BTreeSet;
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use crate::bus::MmioBusAddress;
+use crate::device_manager::IoManager;
+
+/// Errors encountered while quiescing devices.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The devices with these IDs didn't complete their in-flight operations in time.
+    Timeout(Vec<String>),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Timeout(ids) => write!(f, "devices not quiesced in time: {}", ids.join(", ")),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Default)]
+struct Barrier {
+    pending: Mutex<BTreeSet<String>>,
+    done: Condvar,
+}
+
+/// Signals that a device completed its in-flight operations. Dropping the completion
+/// signals it as well.
+pub struct QuiesceCompletion {
+    id: String,
+    barrier: Option<Arc<Barrier>>,
+}
+
+impl QuiesceCompletion {
+    /// Signal that the device is quiescent.
+    pub fn complete(mut self) {
+        self.signal();
+    }
+
+    fn signal(&mut self) {
+        if let Some(barrier) = self.barrier.take() {
+            barrier.pending.lock().unwrap().remove(&self.id);
+            barrier.done.notify_all();
+        }
+    }
+}
+
+impl Drop for QuiesceCompletion {
+    fn drop(&mut self) {
+        self.signal();
+    }
+}
+
+/// Devices which can be quiesced.
+pub trait Quiesce {
+    /// Stop initiating DMA and complete the in-flight operations, then signal `completion`.
+    fn quiesce(&self, completion: QuiesceCompletion);
+
+    /// Start operating again after being quiesced.
+    fn resume(&self);
+}
+
+impl<M: MmioBusAddress> IoManager<M> {
+    /// Register the device `id`, to be quiesced by `quiesce`.
+    pub fn register_quiesce(&mut self, id: &str, device: Arc<dyn Quiesce + Send + Sync>) {
+        self.quiesce_devices.push((id.to_string(), device));
+    }
+
+    /// Quiesce the registered devices, and wait up to `timeout` for all of them to complete
+    /// their in-flight operations. The devices stay quiesced when this fails, so `resume`
+    /// has to be called in both cases.
+    pub fn quiesce(&self, timeout: Duration) -> Result<(), Error> {
+        let barrier = Arc::new(Barrier::default());
+        barrier
+            .pending
+            .lock()
+            .unwrap()
+            .extend(self.quiesce_devices.iter().map(|(id, _)| id.clone()));
+        for (id, device) in self.quiesce_devices.iter() {
+            device.quiesce(QuiesceCompletion {
+                id: id.clone(),
+                barrier: Some(barrier.clone()),
+            });
+        }
+        let (pending, _) = barrier
+            .done
+            .wait_timeout_while(barrier.pending.lock().unwrap(), timeout, |pending| {
+                !pending.is_empty()
+            })
+            .unwrap();
+        if pending.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Timeout(pending.iter().cloned().collect()))
+        }
+    }
+
+    /// Resume the registered devices, in the reverse order of their registration.
+    pub fn resume(&self) {
+        for (_, device) in self.quiesce_devices.iter().rev() {
+            device.resume();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    // Completes the in-flight operations from a worker thread.
+    #[derive(Default)]
+    struct DmaDevice {
+        stopped: AtomicBool,
+        stuck: bool,
+        worker: Mutex<Option<thread::JoinHandle<()>>>,
+    }
+
+    impl Quiesce for DmaDevice {
+        fn quiesce(&self, completion: QuiesceCompletion) {
+            self.stopped.store(true, Ordering::SeqCst);
+            let stuck = self.stuck;
+            *self.worker.lock().unwrap() = Some(thread::spawn(move || {
+                if stuck {
+                    thread::sleep(Duration::from_millis(200));
+                }
+                completion.complete();
+            }));
+        }
+
+        fn resume(&self) {
+            if let Some(worker) = self.worker.lock().unwrap().take() {
+                worker.join().unwrap();
+            }
+            self.stopped.store(false, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_quiesce() {
+        let disk = Arc::new(DmaDevice::default());
+        let net = Arc::new(DmaDevice::default());
+        let mut io_mgr = IoManager::new();
+        io_mgr.register_quiesce("disk", disk.clone());
+        io_mgr.register_quiesce("net", net.clone());
+
+        io_mgr.quiesce(Duration::from_secs(5)).unwrap();
+        assert!(disk.stopped.load(Ordering::SeqCst) && net.stopped.load(Ordering::SeqCst));
+        io_mgr.resume();
+        assert!(!disk.stopped.load(Ordering::SeqCst));
+
+        let stuck = Arc::new(DmaDevice {
+            stuck: true,
+            ..Default::default()
+        });
+        io_mgr.register_quiesce("stuck", stuck);
+        assert_eq!(
+            io_mgr.quiesce(Duration::from_millis(10)),
+            Err(Error::Timeout(vec!["stuck".to_string()]))
+        );
+        io_mgr.resume();
+    }
+}
@@ -0,0 +1,331 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Throttling of the accesses to a device.
+//!
+//! A [`RateLimitedDevice`](struct.RateLimitedDevice.html) wraps a device and only forwards
+//! the accesses allowed by its token buckets: one limiting the number of accesses, and one
+//! limiting the number of bytes transferred. The accesses over the limit are dropped (reads
+//! return zeros), which caps the host resources a misbehaving guest can consume through the
+//! device, e.g. by spamming a debug console. Since a wrapper is registered for a single
+//! range, the limits apply per range; wrapping the same device for several ranges gives each
+//! of them its own budget.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::bus::{
+    MmioAddress, MmioOffset, MsrAddress, MsrAddressValue, PioAddress, PioOffset, SysRegAddress,
+    SysRegAddressValue,
+};
+use crate::time::Clock;
+use crate::{DeviceMmio, DeviceMsr, DevicePio, DeviceSysReg, IoAccess};
+
+const NS_PER_SEC: u128 = 1_000_000_000;
+
+/// A bucket holding up to `capacity` tokens, refilled at `rate` tokens per second.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TokenBucket {
+    /// Maximum number of tokens, i.e. the largest burst allowed.
+    pub capacity: u64,
+    /// Number of tokens added per second.
+    pub rate: u64,
+}
+
+impl TokenBucket {
+    /// Create a bucket of `capacity` tokens, refilled at `rate` tokens per second.
+    pub fn new(capacity: u64, rate: u64) -> Self {
+        TokenBucket { capacity, rate }
+    }
+}
+
+struct BucketState {
+    config: TokenBucket,
+    tokens: u64,
+    // Time up to which the refill was accounted for.
+    last: u64,
+}
+
+impl BucketState {
+    fn new(config: TokenBucket, now: u64) -> Self {
+        BucketState {
+            config,
+            tokens: config.capacity,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, now: u64) {
+        if self.config.rate == 0 {
+            return;
+        }
+        let elapsed = u128::from(now.saturating_sub(self.last));
+        let rate = u128::from(self.config.rate);
+        let added = elapsed * rate / NS_PER_SEC;
+        if self.tokens.saturating_add(added as u64) >= self.config.capacity {
+            self.tokens = self.config.capacity;
+            self.last = now;
+        } else {
+            self.tokens += added as u64;
+            // Keep the time of the fractional token for the next refill.
+            self.last += (added * NS_PER_SEC / rate) as u64;
+        }
+    }
+
+    // Whether `cost` tokens are available. Costs larger than the capacity need a full
+    // bucket, so such accesses are throttled rather than never allowed.
+    fn available(&self, cost: u64) -> bool {
+        self.tokens >= cost.min(self.config.capacity)
+    }
+
+    fn consume(&mut self, cost: u64) {
+        self.tokens = self.tokens.saturating_sub(cost);
+    }
+}
+
+/// Forwards the accesses to a device while they stay within the configured limits.
+pub struct RateLimitedDevice<D> {
+    device: D,
+    clock: Arc<dyn Clock>,
+    ops: Option<Mutex<BucketState>>,
+    bytes: Option<Mutex<BucketState>>,
+    throttled: AtomicU64,
+}
+
+impl<D> RateLimitedDevice<D> {
+    /// Wrap `device`, without any limits until they are set with `with_ops_limit` or
+    /// `with_bandwidth_limit`. Tokens are refilled following `clock`.
+    pub fn new(device: D, clock: Arc<dyn Clock>) -> Self {
+        RateLimitedDevice {
+            device,
+            clock,
+            ops: None,
+            bytes: None,
+            throttled: AtomicU64::new(0),
+        }
+    }
+
+    /// Limit the number of accesses, each of them costing one token.
+    pub fn with_ops_limit(mut self, bucket: TokenBucket) -> Self {
+        self.ops = Some(Mutex::new(BucketState::new(bucket, self.clock.now())));
+        self
+    }
+
+    /// Limit the number of bytes transferred, each access costing one token per byte.
+    pub fn with_bandwidth_limit(mut self, bucket: TokenBucket) -> Self {
+        self.bytes = Some(Mutex::new(BucketState::new(bucket, self.clock.now())));
+        self
+    }
+
+    /// Return the wrapped device.
+    pub fn inner(&self) -> &D {
+        &self.device
+    }
+
+    /// Return the number of accesses dropped so far.
+    pub fn throttled(&self) -> u64 {
+        self.throttled.load(Ordering::Relaxed)
+    }
+
+    // Consume the tokens of an access of `len` bytes, or return `false` if either bucket
+    // doesn't hold enough of them.
+    fn admit(&self, len: usize) -> bool {
+        let now = self.clock.now();
+        let mut ops = self.ops.as_ref().map(|bucket| bucket.lock().unwrap());
+        let mut bytes = self.bytes.as_ref().map(|bucket| bucket.lock().unwrap());
+        let mut checks = [(ops.as_deref_mut(), 1), (bytes.as_deref_mut(), len as u64)];
+        let mut allowed = true;
+        for (bucket, cost) in checks.iter_mut() {
+            if let Some(bucket) = bucket {
+                bucket.refill(now);
+                allowed &= bucket.available(*cost);
+            }
+        }
+        if !allowed {
+            self.throttled.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        for (bucket, cost) in checks.iter_mut() {
+            if let Some(bucket) = bucket {
+                bucket.consume(*cost);
+            }
+        }
+        true
+    }
+}
+
+impl<D: DevicePio> DevicePio for RateLimitedDevice<D> {
+    fn pio_read(&self, base: PioAddress, offset: PioOffset, data: &mut [u8]) {
+        self.pio_read_with(IoAccess::default(), base, offset, data);
+    }
+
+    fn pio_write(&self, base: PioAddress, offset: PioOffset, data: &[u8]) {
+        self.pio_write_with(IoAccess::default(), base, offset, data);
+    }
+
+    fn pio_read_with(
+        &self,
+        access: IoAccess,
+        base: PioAddress,
+        offset: PioOffset,
+        data: &mut [u8],
+    ) {
+        if self.admit(data.len()) {
+            self.device.pio_read_with(access, base, offset, data);
+        } else {
+            data.iter_mut().for_each(|byte| *byte = 0);
+        }
+    }
+
+    fn pio_write_with(&self, access: IoAccess, base: PioAddress, offset: PioOffset, data: &[u8]) {
+        if self.admit(data.len()) {
+            self.device.pio_write_with(access, base, offset, data);
+        }
+    }
+}
+
+impl<D: DeviceMmio> DeviceMmio for RateLimitedDevice<D> {
+    fn mmio_read(&self, base: MmioAddress, offset: MmioOffset, data: &mut [u8]) {
+        self.mmio_read_with(IoAccess::default(), base, offset, data);
+    }
+
+    fn mmio_write(&self, base: MmioAddress, offset: MmioOffset, data: &[u8]) {
+        self.mmio_write_with(IoAccess::default(), base, offset, data);
+    }
+
+    fn mmio_read_with(
+        &self,
+        access: IoAccess,
+        base: MmioAddress,
+        offset: MmioOffset,
+        data: &mut [u8],
+    ) {
+        if self.admit(data.len()) {
+            self.device.mmio_read_with(access, base, offset, data);
+        } else {
+            data.iter_mut().for_each(|byte| *byte = 0);
+        }
+    }
+
+    fn mmio_write_with(
+        &self,
+        access: IoAccess,
+        base: MmioAddress,
+        offset: MmioOffset,
+        data: &[u8],
+    ) {
+        if self.admit(data.len()) {
+            self.device.mmio_write_with(access, base, offset, data);
+        }
+    }
+}
+
+impl<D: DeviceSysReg> DeviceSysReg for RateLimitedDevice<D> {
+    fn sysreg_read(&self, base: SysRegAddress, offset: SysRegAddressValue) -> u64 {
+        self.sysreg_read_with(IoAccess::default(), base, offset)
+    }
+
+    fn sysreg_write(&self, base: SysRegAddress, offset: SysRegAddressValue, value: u64) {
+        self.sysreg_write_with(IoAccess::default(), base, offset, value);
+    }
+
+    fn sysreg_read_with(
+        &self,
+        access: IoAccess,
+        base: SysRegAddress,
+        offset: SysRegAddressValue,
+    ) -> u64 {
+        if self.admit(8) {
+            self.device.sysreg_read_with(access, base, offset)
+        } else {
+            0
+        }
+    }
+
+    fn sysreg_write_with(
+        &self,
+        access: IoAccess,
+        base: SysRegAddress,
+        offset: SysRegAddressValue,
+        value: u64,
+    ) {
+        if self.admit(8) {
+            self.device.sysreg_write_with(access, base, offset, value);
+        }
+    }
+}
+
+impl<D: DeviceMsr> DeviceMsr for RateLimitedDevice<D> {
+    fn msr_read(&self, base: MsrAddress, offset: MsrAddressValue) -> u64 {
+        self.msr_read_with(IoAccess::default(), base, offset)
+    }
+
+    fn msr_write(&self, base: MsrAddress, offset: MsrAddressValue, value: u64) {
+        self.msr_write_with(IoAccess::default(), base, offset, value);
+    }
+
+    fn msr_read_with(&self, access: IoAccess, base: MsrAddress, offset: MsrAddressValue) -> u64 {
+        if self.admit(8) {
+            self.device.msr_read_with(access, base, offset)
+        } else {
+            0
+        }
+    }
+
+    fn msr_write_with(
+        &self,
+        access: IoAccess,
+        base: MsrAddress,
+        offset: MsrAddressValue,
+        value: u64,
+    ) {
+        if self.admit(8) {
+            self.device.msr_write_with(access, base, offset, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::EchoDevice;
+    use crate::time::ManualClock;
+
+    #[test]
+    fn test_rate_limited_device() {
+        let clock = Arc::new(ManualClock::new());
+        let device = RateLimitedDevice::new(EchoDevice::new(), clock.clone())
+            .with_ops_limit(TokenBucket::new(2, 1))
+            .with_bandwidth_limit(TokenBucket::new(8, 1000));
+        let base = PioAddress(0x3f8);
+
+        // The burst allowed by the ops bucket goes through.
+        device.pio_write(base, PioOffset(0), &[1]);
+        device.pio_write(base, PioOffset(0), &[2]);
+        device.pio_write(base, PioOffset(0), &[3]);
+        assert_eq!(device.inner().accesses().len(), 2);
+        assert_eq!(device.throttled(), 1);
+        let mut data = [0xff];
+        device.pio_read(base, PioOffset(0), &mut data);
+        assert_eq!(data, [0]);
+
+        // One token is back after a second.
+        clock.advance(1_000_000_000);
+        device.pio_read(base, PioOffset(0), &mut data);
+        assert_eq!(data, [2]);
+        device.pio_read(base, PioOffset(0), &mut data);
+        assert_eq!(device.throttled(), 3);
+
+        // The bandwidth bucket is exhausted by the large accesses, and the ones larger
+        // than its capacity need it to be full.
+        clock.advance(2_000_000_000);
+        device.mmio_write(MmioAddress(0), MmioOffset(0), &[0; 16]);
+        assert_eq!(device.inner().accesses().len(), 4);
+        device.mmio_write(MmioAddress(0), MmioOffset(0), &[0; 4]);
+        assert_eq!(device.throttled(), 4);
+        clock.advance(4_000_000);
+        device.mmio_write(MmioAddress(0), MmioOffset(0), &[0; 4]);
+        assert_eq!(device.inner().accesses().len(), 5);
+    }
+}
@@ -0,0 +1,514 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Helpers for emulating MMIO register blocks.
+//!
+//! Devices keep their registers in cells which implement the access semantics of the
+//! hardware ([`ReadWrite`](struct.ReadWrite.html), [`ReadOnly`](struct.ReadOnly.html),
+//! [`WriteClear`](struct.WriteClear.html), [`W1C`](struct.W1C.html)), and map them to their
+//! offsets with a [`RegisterBlock`](struct.RegisterBlock.html). The block implements
+//! `DeviceMmio`, converting the access data according to the endianness of the device, and
+//! handles accesses to part of a register, or to several registers at once. Offsets without a
+//! register read as zero and ignore writes.
+
+use std::collections::BTreeMap;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::bus::{MmioAddress, MmioOffset};
+use crate::DeviceMmio;
+
+/// Value types of the registers.
+pub trait RegisterValue: Copy + Send + Sync {
+    /// Size of the register, in bytes.
+    const SIZE: usize;
+
+    /// Convert the value to a `u64`.
+    fn to_u64(self) -> u64;
+
+    /// Convert a `u64` to a value, discarding the bits which don't fit.
+    fn from_u64(value: u64) -> Self;
+}
+
+macro_rules! register_value {
+    ($($t:ty),*) => {
+        $(
+            impl RegisterValue for $t {
+                const SIZE: usize = std::mem::size_of::<$t>();
+
+                fn to_u64(self) -> u64 {
+                    u64::from(self)
+                }
+
+                fn from_u64(value: u64) -> Self {
+                    value as $t
+                }
+            }
+        )*
+    };
+}
+
+register_value!(u8, u16, u32, u64);
+
+/// A register cell, as seen by a `RegisterBlock`.
+pub trait Register: Send + Sync {
+    /// Return the size of the register, in bytes.
+    fn size(&self) -> usize;
+
+    /// Return the value read by the guest.
+    fn read(&self) -> u64;
+
+    /// Handle a guest write of `value`. Only the bits set in `mask` are written, which lets
+    /// cells tell partial writes apart from writes of zero bits.
+    fn write(&self, value: u64, mask: u64);
+
+    /// Set the register to `value`, regardless of the guest access semantics.
+    fn reset(&self, value: u64);
+}
+
+/// Register cells whose size is known at compile time, which lets the register map derives
+/// check the sizes declared by the `#[register(..)]` attributes.
+pub trait RegisterCell: Register {
+    /// Size of the register, in bytes.
+    const SIZE: usize;
+}
+
+/// Register which the guest can read and write. Writes only change the writable bits.
+pub struct ReadWrite<T> {
+    value: AtomicU64,
+    writable: u64,
+    _type: PhantomData<T>,
+}
+
+impl<T: RegisterValue> ReadWrite<T> {
+    /// Create a register holding `value`, with all bits writable.
+    pub fn new(value: T) -> Self {
+        ReadWrite {
+            value: AtomicU64::new(value.to_u64()),
+            writable: T::from_u64(u64::MAX).to_u64(),
+            _type: PhantomData,
+        }
+    }
+
+    /// Only let the guest change the bits set in `writable`.
+    pub fn with_mask(mut self, writable: T) -> Self {
+        self.writable = writable.to_u64();
+        self
+    }
+
+    /// Return the value of the register.
+    pub fn get(&self) -> T {
+        T::from_u64(self.value.load(Ordering::SeqCst))
+    }
+
+    /// Set the value of the register, including the bits the guest can't write.
+    pub fn set(&self, value: T) {
+        self.value.store(value.to_u64(), Ordering::SeqCst);
+    }
+}
+
+impl<T: RegisterValue> RegisterCell for ReadWrite<T> {
+    const SIZE: usize = T::SIZE;
+}
+
+impl<T: RegisterValue> Register for ReadWrite<T> {
+    fn size(&self) -> usize {
+        T::SIZE
+    }
+
+    fn read(&self) -> u64 {
+        self.value.load(Ordering::SeqCst)
+    }
+
+    fn write(&self, value: u64, mask: u64) {
+        let mask = mask & self.writable;
+        let _ = self
+            .value
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |old| {
+                Some((old & !mask) | (value & mask))
+            });
+    }
+
+    fn reset(&self, value: u64) {
+        self.value.store(value, Ordering::SeqCst);
+    }
+}
+
+/// Register which the guest can only read, such as an ID or a status register. The device
+/// can still update it.
+pub struct ReadOnly<T> {
+    value: AtomicU64,
+    _type: PhantomData<T>,
+}
+
+impl<T: RegisterValue> ReadOnly<T> {
+    /// Create a register holding `value`.
+    pub fn new(value: T) -> Self {
+        ReadOnly {
+            value: AtomicU64::new(value.to_u64()),
+            _type: PhantomData,
+        }
+    }
+
+    /// Return the value of the register.
+    pub fn get(&self) -> T {
+        T::from_u64(self.value.load(Ordering::SeqCst))
+    }
+
+    /// Set the value of the register.
+    pub fn set(&self, value: T) {
+        self.value.store(value.to_u64(), Ordering::SeqCst);
+    }
+}
+
+impl<T: RegisterValue> RegisterCell for ReadOnly<T> {
+    const SIZE: usize = T::SIZE;
+}
+
+impl<T: RegisterValue> Register for ReadOnly<T> {
+    fn size(&self) -> usize {
+        T::SIZE
+    }
+
+    fn read(&self) -> u64 {
+        self.value.load(Ordering::SeqCst)
+    }
+
+    fn write(&self, _value: u64, _mask: u64) {}
+
+    fn reset(&self, value: u64) {
+        self.value.store(value, Ordering::SeqCst);
+    }
+}
+
+/// Register which is cleared by any guest write, regardless of the written value.
+pub struct WriteClear<T> {
+    value: AtomicU64,
+    _type: PhantomData<T>,
+}
+
+impl<T: RegisterValue> WriteClear<T> {
+    /// Create a register holding `value`.
+    pub fn new(value: T) -> Self {
+        WriteClear {
+            value: AtomicU64::new(value.to_u64()),
+            _type: PhantomData,
+        }
+    }
+
+    /// Return the value of the register.
+    pub fn get(&self) -> T {
+        T::from_u64(self.value.load(Ordering::SeqCst))
+    }
+
+    /// Set the value of the register.
+    pub fn set(&self, value: T) {
+        self.value.store(value.to_u64(), Ordering::SeqCst);
+    }
+}
+
+impl<T: RegisterValue> RegisterCell for WriteClear<T> {
+    const SIZE: usize = T::SIZE;
+}
+
+impl<T: RegisterValue> Register for WriteClear<T> {
+    fn size(&self) -> usize {
+        T::SIZE
+    }
+
+    fn read(&self) -> u64 {
+        self.value.load(Ordering::SeqCst)
+    }
+
+    fn write(&self, _value: u64, mask: u64) {
+        self.value.fetch_and(!mask, Ordering::SeqCst);
+    }
+
+    fn reset(&self, value: u64) {
+        self.value.store(value, Ordering::SeqCst);
+    }
+}
+
+/// Register whose bits are set by the device and cleared by the guest writing 1 to them, as
+/// usual for interrupt status registers.
+#[allow(clippy::upper_case_acronyms)]
+pub struct W1C<T> {
+    value: AtomicU64,
+    _type: PhantomData<T>,
+}
+
+impl<T: RegisterValue> W1C<T> {
+    /// Create a register holding `value`.
+    pub fn new(value: T) -> Self {
+        W1C {
+            value: AtomicU64::new(value.to_u64()),
+            _type: PhantomData,
+        }
+    }
+
+    /// Return the value of the register.
+    pub fn get(&self) -> T {
+        T::from_u64(self.value.load(Ordering::SeqCst))
+    }
+
+    /// Set the bits of `bits` in the register.
+    pub fn set_bits(&self, bits: T) {
+        self.value.fetch_or(bits.to_u64(), Ordering::SeqCst);
+    }
+}
+
+impl<T: RegisterValue> RegisterCell for W1C<T> {
+    const SIZE: usize = T::SIZE;
+}
+
+impl<T: RegisterValue> Register for W1C<T> {
+    fn size(&self) -> usize {
+        T::SIZE
+    }
+
+    fn read(&self) -> u64 {
+        self.value.load(Ordering::SeqCst)
+    }
+
+    fn write(&self, value: u64, mask: u64) {
+        self.value.fetch_and(!(value & mask), Ordering::SeqCst);
+    }
+
+    fn reset(&self, value: u64) {
+        self.value.store(value, Ordering::SeqCst);
+    }
+}
+
+/// Byte order of the registers of a device.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Endianness {
+    /// Least significant byte first.
+    Little,
+    /// Most significant byte first.
+    Big,
+}
+
+// Return the bit position of the byte at `index` in a register of `size` bytes.
+fn shift(endianness: Endianness, index: usize, size: usize) -> u64 {
+    match endianness {
+        Endianness::Little => 8 * index as u64,
+        Endianness::Big => 8 * (size - 1 - index) as u64,
+    }
+}
+
+/// Handle a read of `data.len()` bytes at `offset` from a set of registers. `lookup` returns
+/// the offset of the register containing an offset, and the register.
+pub fn read_registers<'a, F>(endianness: Endianness, lookup: F, offset: u64, data: &mut [u8])
+where
+    F: Fn(u64) -> Option<(u64, &'a dyn Register)>,
+{
+    for (pos, byte) in data.iter_mut().enumerate() {
+        let offset = offset + pos as u64;
+        *byte = match lookup(offset) {
+            Some((base, register)) => {
+                let shift = shift(endianness, (offset - base) as usize, register.size());
+                (register.read() >> shift) as u8
+            }
+            None => 0,
+        };
+    }
+}
+
+/// Handle a write of `data` at `offset` to a set of registers, and call `written` with the
+/// offset of each register after writing it. `lookup` works as for `read_registers`.
+pub fn write_registers<'a, F, W>(
+    endianness: Endianness,
+    lookup: F,
+    offset: u64,
+    data: &[u8],
+    mut written: W,
+) where
+    F: Fn(u64) -> Option<(u64, &'a dyn Register)>,
+    W: FnMut(u64),
+{
+    let mut pos = 0;
+    while pos < data.len() {
+        let addr = offset + pos as u64;
+        let (base, register) = match lookup(addr) {
+            Some(found) => found,
+            None => {
+                pos += 1;
+                continue;
+            }
+        };
+        let size = register.size();
+        let (mut value, mut mask) = (0, 0);
+        let mut index = (addr - base) as usize;
+        while index < size && pos < data.len() {
+            let shift = shift(endianness, index, size);
+            value |= u64::from(data[pos]) << shift;
+            mask |= 0xff << shift;
+            index += 1;
+            pos += 1;
+        }
+        register.write(value, mask);
+        written(base);
+    }
+}
+
+/// Devices whose registers are fields of the device object, usually implemented with
+/// `#[derive(MmioDevice)]` or `#[derive(PioDevice)]` (with the `derive` feature).
+pub trait RegisterMap {
+    /// Byte order of the registers.
+    const ENDIANNESS: Endianness;
+
+    /// Return the offset of the register containing `offset`, and the register.
+    fn register_at(&self, offset: u64) -> Option<(u64, &dyn Register)>;
+
+    /// Reset all the registers to their default values.
+    fn reset_registers(&self);
+}
+
+type WriteHook = Box<dyn Fn(u64) + Send + Sync>;
+
+struct Slot {
+    register: Arc<dyn Register>,
+    on_write: Option<WriteHook>,
+}
+
+/// Maps offsets to the registers of a device, and handles the MMIO accesses to them.
+pub struct RegisterBlock {
+    endianness: Endianness,
+    slots: BTreeMap<u64, Slot>,
+}
+
+impl RegisterBlock {
+    /// Create an empty block for registers of the provided endianness.
+    pub fn new(endianness: Endianness) -> Self {
+        RegisterBlock {
+            endianness,
+            slots: BTreeMap::new(),
+        }
+    }
+
+    /// Map `register` at `offset`, replacing any register previously mapped there. The
+    /// device keeps its own clone of `register` to access the value.
+    pub fn register(mut self, offset: u64, register: Arc<dyn Register>) -> Self {
+        self.slots.insert(
+            offset,
+            Slot {
+                register,
+                on_write: None,
+            },
+        );
+        self
+    }
+
+    /// Call `hook` with the new value of the register mapped at `offset` after each guest
+    /// write to it, so the device can act on the change. Does nothing when no register is
+    /// mapped at `offset`.
+    pub fn on_write<F: Fn(u64) + Send + Sync + 'static>(mut self, offset: u64, hook: F) -> Self {
+        if let Some(slot) = self.slots.get_mut(&offset) {
+            slot.on_write = Some(Box::new(hook));
+        }
+        self
+    }
+
+    // Return the offset of the register containing `offset`, and the register.
+    fn lookup(&self, offset: u64) -> Option<(u64, &dyn Register)> {
+        self.slots
+            .range(..=offset)
+            .next_back()
+            .filter(|(base, slot)| offset - **base < slot.register.size() as u64)
+            .map(|(base, slot)| (*base, slot.register.as_ref()))
+    }
+
+    /// Handle a read of `data.len()` bytes at `offset`.
+    pub fn read(&self, offset: u64, data: &mut [u8]) {
+        read_registers(self.endianness, |offset| self.lookup(offset), offset, data)
+    }
+
+    /// Handle a write of `data` at `offset`. The bytes falling on the same register are
+    /// written together.
+    pub fn write(&self, offset: u64, data: &[u8]) {
+        write_registers(
+            self.endianness,
+            |offset| self.lookup(offset),
+            offset,
+            data,
+            |base| {
+                let slot = &self.slots[&base];
+                if let Some(hook) = slot.on_write.as_ref() {
+                    hook(slot.register.read());
+                }
+            },
+        )
+    }
+}
+
+impl DeviceMmio for RegisterBlock {
+    fn mmio_read(&self, _base: MmioAddress, offset: MmioOffset, data: &mut [u8]) {
+        self.read(offset.raw(), data)
+    }
+
+    fn mmio_write(&self, _base: MmioAddress, offset: MmioOffset, data: &[u8]) {
+        self.write(offset.raw(), data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_register_cells() {
+        let ctrl = Arc::new(ReadWrite::new(0u32).with_mask(0xff));
+        let id = Arc::new(ReadOnly::new(0x1234_5678u32));
+        let status = Arc::new(W1C::new(0u32));
+        let events = Arc::new(WriteClear::new(0u16));
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let hook_written = written.clone();
+        let block = RegisterBlock::new(Endianness::Little)
+            .register(0x0, ctrl.clone())
+            .register(0x4, id)
+            .register(0x8, status.clone())
+            .register(0xc, events.clone())
+            .on_write(0x0, move |value| hook_written.lock().unwrap().push(value));
+
+        block.write(0x0, &[0xaa, 0xbb, 0xcc, 0xdd]);
+        assert_eq!(ctrl.get(), 0xaa);
+        assert_eq!(*written.lock().unwrap(), vec![0xaa]);
+
+        let mut data = [0; 4];
+        block.read(0x4, &mut data);
+        assert_eq!(data, [0x78, 0x56, 0x34, 0x12]);
+        block.write(0x4, &[0; 4]);
+        // Partial and unmapped accesses.
+        let mut data = [0; 2];
+        block.read(0x6, &mut data);
+        assert_eq!(data, [0x34, 0x12]);
+        block.read(0x10, &mut data);
+        assert_eq!(data, [0, 0]);
+
+        status.set_bits(0b1011);
+        block.write(0x8, &[0b0011]);
+        assert_eq!(status.get(), 0b1000);
+        // Writing the upper bytes leaves the lower bits alone.
+        block.write(0x9, &[0xff]);
+        assert_eq!(status.get(), 0b1000);
+
+        events.set(0x101);
+        block.write(0xc, &[0, 0]);
+        assert_eq!(events.get(), 0);
+    }
+
+    #[test]
+    fn test_big_endian_block() {
+        let reg = Arc::new(ReadWrite::new(0x0102_0304u32));
+        let block = RegisterBlock::new(Endianness::Big).register(0x10, reg.clone());
+        let mut data = [0; 4];
+        block.mmio_read(MmioAddress(0), MmioOffset(0x10), &mut data);
+        assert_eq!(data, [1, 2, 3, 4]);
+
+        block.mmio_write(MmioAddress(0), MmioOffset(0x12), &[0xaa]);
+        assert_eq!(reg.get(), 0x0102_aa04);
+    }
+}
@@ -0,0 +1,417 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Devices running in another process, reached over a Unix socket.
+//!
+//! Running a device model out of process lets it be sandboxed independently of the VMM. The
+//! VMM registers a [`RemoteDevice`](struct.RemoteDevice.html) with its `IoManager` like any
+//! other MMIO device; the accesses it receives are forwarded over the socket to a
+//! [`RemoteServer`](struct.RemoteServer.html), which runs them against the actual device
+//! and sends back the read values. The device process raises and lowers its interrupt lines
+//! through a [`RemoteIrq`](struct.RemoteIrq.html), whose messages are handed to a callback
+//! on the VMM side.
+//!
+//! Every message of the protocol is a fixed size [`Message`](struct.Message.html). The
+//! accesses carry at most 8 bytes of data, so larger ones are split by the client. When the
+//! connection is lost, the remote device behaves as if it was unplugged: reads return all
+//! ones, and writes are dropped.
+
+use std::fmt::{Display, Formatter};
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::bus::{MmioAddress, MmioOffset};
+use crate::DeviceMmio;
+
+/// Largest access carried by a single message.
+pub const MAX_ACCESS_LEN: usize = 8;
+
+/// Errors encountered while exchanging messages.
+#[derive(Debug)]
+pub enum Error {
+    /// The peer closed the connection.
+    Disconnected,
+    /// The message kind is unknown.
+    InvalidKind(u8),
+    /// The access length is larger than `MAX_ACCESS_LEN`.
+    InvalidLength(u8),
+    /// Failed to access the socket.
+    Io(io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Disconnected => write!(f, "the remote peer disconnected"),
+            Error::InvalidKind(kind) => write!(f, "invalid message kind {}", kind),
+            Error::InvalidLength(len) => write!(f, "invalid access length {}", len),
+            Error::Io(_) => write!(f, "failed to access the socket"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Kind of a protocol message.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MessageKind {
+    /// Read request, sent by the client.
+    Read = 1,
+    /// Write request, sent by the client.
+    Write = 2,
+    /// Completion of a request, carrying the read data. Sent by the server.
+    Reply = 3,
+    /// Interrupt line change, sent by the server. The line is in `offset`, and the level
+    /// in the first data byte.
+    Irq = 4,
+}
+
+/// A message of the remote device protocol.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Message {
+    /// Kind of the message.
+    pub kind: MessageKind,
+    /// Length of the access.
+    pub len: u8,
+    /// Base address of the device range.
+    pub base: u64,
+    /// Offset of the access within the device range.
+    pub offset: u64,
+    /// Data of the access; only the first `len` bytes are meaningful.
+    pub data: [u8; MAX_ACCESS_LEN],
+}
+
+impl Message {
+    /// Size of an encoded message.
+    pub const SIZE: usize = 32;
+
+    fn new(kind: MessageKind, base: u64, offset: u64, data: &[u8]) -> Self {
+        let mut message = Message {
+            kind,
+            len: data.len() as u8,
+            base,
+            offset,
+            data: [0; MAX_ACCESS_LEN],
+        };
+        message.data[..data.len()].copy_from_slice(data);
+        message
+    }
+
+    /// Encode the message, in little endian.
+    pub fn encode(&self) -> [u8; Self::SIZE] {
+        let mut buf = [0; Self::SIZE];
+        buf[0] = self.kind as u8;
+        buf[1] = self.len;
+        buf[8..16].copy_from_slice(&self.base.to_le_bytes());
+        buf[16..24].copy_from_slice(&self.offset.to_le_bytes());
+        buf[24..].copy_from_slice(&self.data);
+        buf
+    }
+
+    /// Decode a message encoded with `encode`.
+    pub fn decode(buf: &[u8; Self::SIZE]) -> Result<Self, Error> {
+        let kind = match buf[0] {
+            1 => MessageKind::Read,
+            2 => MessageKind::Write,
+            3 => MessageKind::Reply,
+            4 => MessageKind::Irq,
+            kind => return Err(Error::InvalidKind(kind)),
+        };
+        if buf[1] as usize > MAX_ACCESS_LEN {
+            return Err(Error::InvalidLength(buf[1]));
+        }
+        let u64_at = |idx: usize| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&buf[idx..idx + 8]);
+            u64::from_le_bytes(bytes)
+        };
+        let mut data = [0; MAX_ACCESS_LEN];
+        data.copy_from_slice(&buf[24..]);
+        Ok(Message {
+            kind,
+            len: buf[1],
+            base: u64_at(8),
+            offset: u64_at(16),
+            data,
+        })
+    }
+
+    /// Read the next message from `reader`.
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Self, Error> {
+        let mut buf = [0; Self::SIZE];
+        reader.read_exact(&mut buf).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => Error::Disconnected,
+            _ => Error::Io(e),
+        })?;
+        Self::decode(&buf)
+    }
+
+    /// Write the message to `writer`.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
+        writer.write_all(&self.encode()).map_err(Error::Io)
+    }
+}
+
+struct Connection {
+    stream: UnixStream,
+    replies: Receiver<Message>,
+}
+
+/// Client side of a remote device, forwarding the MMIO accesses it receives.
+pub struct RemoteDevice {
+    // The lock is held for the whole round trip, so the replies match the requests.
+    connection: Mutex<Connection>,
+    connected: Arc<AtomicBool>,
+    timeout: Option<Duration>,
+    reader: Option<JoinHandle<()>>,
+}
+
+impl RemoteDevice {
+    /// Create the client of the device served on the other end of `stream`. The
+    /// interrupt line changes requested by the device are passed to `irq_handler`, with
+    /// the line number and the new level, from a thread reading the socket.
+    pub fn new(
+        stream: UnixStream,
+        irq_handler: Arc<dyn Fn(u32, bool) + Send + Sync>,
+    ) -> io::Result<Self> {
+        let mut reader_stream = stream.try_clone()?;
+        let connected = Arc::new(AtomicBool::new(true));
+        let (sender, replies) = channel();
+        let reader_connected = connected.clone();
+        let reader = thread::Builder::new()
+            .name("remote-device".to_string())
+            .spawn(move || {
+                while let Ok(message) = Message::read_from(&mut reader_stream) {
+                    match message.kind {
+                        MessageKind::Reply => {
+                            if sender.send(message).is_err() {
+                                break;
+                            }
+                        }
+                        MessageKind::Irq => {
+                            irq_handler(message.offset as u32, message.data[0] != 0)
+                        }
+                        // Requests are never sent to the client.
+                        MessageKind::Read | MessageKind::Write => break,
+                    }
+                }
+                reader_connected.store(false, Ordering::Release);
+                // Stop the round trip in progress, if any.
+                let _ = reader_stream.shutdown(Shutdown::Both);
+            })?;
+
+        Ok(RemoteDevice {
+            connection: Mutex::new(Connection { stream, replies }),
+            connected,
+            timeout: None,
+            reader: Some(reader),
+        })
+    }
+
+    /// Consider the device lost when it doesn't complete an access within `timeout`, so a
+    /// hung device process can't block the vCPUs forever.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Return `false` once the connection to the device has been lost.
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Acquire)
+    }
+
+    // Send `request` and wait for its completion.
+    fn round_trip(&self, request: Message) -> Result<Message, Error> {
+        if !self.is_connected() {
+            return Err(Error::Disconnected);
+        }
+        let mut connection = self.connection.lock().unwrap();
+        let result = request
+            .write_to(&mut connection.stream)
+            .and_then(|_| match self.timeout {
+                Some(timeout) => connection
+                    .replies
+                    .recv_timeout(timeout)
+                    .map_err(|_| Error::Disconnected),
+                None => connection.replies.recv().map_err(|_| Error::Disconnected),
+            });
+        if result.is_err() {
+            // A late reply would be taken for the one of the next request.
+            self.connected.store(false, Ordering::Release);
+            let _ = connection.stream.shutdown(Shutdown::Both);
+        }
+        result
+    }
+}
+
+impl DeviceMmio for RemoteDevice {
+    fn mmio_read(&self, base: MmioAddress, offset: MmioOffset, data: &mut [u8]) {
+        for (idx, chunk) in data.chunks_mut(MAX_ACCESS_LEN).enumerate() {
+            let chunk_offset = offset + (idx * MAX_ACCESS_LEN) as u64;
+            let mut request = Message::new(MessageKind::Read, base.0, chunk_offset.raw(), &[]);
+            request.len = chunk.len() as u8;
+            match self.round_trip(request) {
+                Ok(reply) => chunk.copy_from_slice(&reply.data[..chunk.len()]),
+                Err(_) => chunk.iter_mut().for_each(|byte| *byte = 0xff),
+            }
+        }
+    }
+
+    fn mmio_write(&self, base: MmioAddress, offset: MmioOffset, data: &[u8]) {
+        for (idx, chunk) in data.chunks(MAX_ACCESS_LEN).enumerate() {
+            let chunk_offset = offset + (idx * MAX_ACCESS_LEN) as u64;
+            let request = Message::new(MessageKind::Write, base.0, chunk_offset.raw(), chunk);
+            if self.round_trip(request).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+impl Drop for RemoteDevice {
+    fn drop(&mut self) {
+        let connection = self.connection.lock().unwrap();
+        let _ = connection.stream.shutdown(Shutdown::Both);
+        drop(connection);
+        if let Some(reader) = self.reader.take() {
+            let _ = reader.join();
+        }
+    }
+}
+
+/// Raises and lowers the interrupt lines of a device served by a `RemoteServer`.
+#[derive(Clone)]
+pub struct RemoteIrq(Arc<Mutex<UnixStream>>);
+
+impl RemoteIrq {
+    /// Set the level of the interrupt line `irq`.
+    pub fn set_level(&self, irq: u32, level: bool) -> Result<(), Error> {
+        Message::new(MessageKind::Irq, 0, u64::from(irq), &[u8::from(level)])
+            .write_to(&mut *self.0.lock().unwrap())
+    }
+}
+
+/// Serves the accesses sent by a `RemoteDevice` with a device model.
+pub struct RemoteServer<D> {
+    device: D,
+    reader: UnixStream,
+    writer: Arc<Mutex<UnixStream>>,
+}
+
+impl<D: DeviceMmio> RemoteServer<D> {
+    /// Serve `device` to the client on the other end of `stream`.
+    pub fn new(stream: UnixStream, device: D) -> io::Result<Self> {
+        Ok(RemoteServer {
+            device,
+            reader: stream.try_clone()?,
+            writer: Arc::new(Mutex::new(stream)),
+        })
+    }
+
+    /// Return the handle the device uses to change the level of its interrupt lines.
+    pub fn irq(&self) -> RemoteIrq {
+        RemoteIrq(self.writer.clone())
+    }
+
+    /// Handle the requests of the client until it disconnects.
+    pub fn run(&mut self) -> Result<(), Error> {
+        loop {
+            let request = match Message::read_from(&mut self.reader) {
+                Ok(request) => request,
+                Err(Error::Disconnected) => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            let base = MmioAddress(request.base);
+            let len = request.len as usize;
+            let mut reply = Message::new(MessageKind::Reply, request.base, request.offset, &[]);
+            match request.kind {
+                MessageKind::Read => {
+                    self.device
+                        .mmio_read(base, MmioOffset(request.offset), &mut reply.data[..len]);
+                    reply.len = request.len;
+                }
+                MessageKind::Write => {
+                    self.device
+                        .mmio_write(base, MmioOffset(request.offset), &request.data[..len]);
+                }
+                kind => return Err(Error::InvalidKind(kind as u8)),
+            }
+            reply.write_to(&mut *self.writer.lock().unwrap())?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::bus::MmioRange;
+    use crate::device_manager::{IoManager, MmioManager};
+    use crate::testing::EchoDevice;
+
+    #[test]
+    fn test_remote_device() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let device = Arc::new(EchoDevice::new());
+        let mut server = RemoteServer::new(server, device.clone()).unwrap();
+        let irq = server.irq();
+        let server = thread::spawn(move || server.run());
+
+        let (irq_sender, irq_receiver) = channel();
+        let irq_sender = Mutex::new(irq_sender);
+        let handler = Arc::new(move |line, level| {
+            irq_sender.lock().unwrap().send((line, level)).unwrap();
+        });
+        let remote = Arc::new(RemoteDevice::new(client, handler).unwrap());
+        let mut io_mgr = IoManager::new();
+        let range = MmioRange::new(MmioAddress(0x1000), 0x100).unwrap();
+        io_mgr.register_mmio(range, remote.clone()).unwrap();
+
+        io_mgr
+            .mmio_write(MmioAddress(0x1010), &[1, 2, 3, 4])
+            .unwrap();
+        let mut data = [0; 4];
+        io_mgr.mmio_read(MmioAddress(0x1010), &mut data).unwrap();
+        assert_eq!(data, [1, 2, 3, 4]);
+        assert_eq!(device.accesses().len(), 2);
+
+        irq.set_level(5, true).unwrap();
+        assert_eq!(
+            irq_receiver.recv_timeout(Duration::from_secs(5)).unwrap(),
+            (5, true)
+        );
+
+        // Accesses larger than a message are split.
+        let mut data = [0; 12];
+        remote.mmio_read(MmioAddress(0x1000), MmioOffset(0), &mut data);
+        assert_eq!(device.accesses().len(), 4);
+
+        // The device behaves as unplugged once the connection is lost.
+        io_mgr.deregister_mmio(MmioAddress(0x1000)).unwrap();
+        drop(irq);
+        let remote = Arc::try_unwrap(remote).ok().unwrap();
+        drop(remote);
+        assert!(server.join().unwrap().is_ok());
+
+        let (client, server) = UnixStream::pair().unwrap();
+        let remote = RemoteDevice::new(client, Arc::new(|_, _| {})).unwrap();
+        drop(server);
+        let mut data = [0; 2];
+        remote.mmio_read(MmioAddress(0), MmioOffset(0), &mut data);
+        assert_eq!(data, [0xff, 0xff]);
+        assert!(!remote.is_connected());
+    }
+}
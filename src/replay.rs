@@ -0,0 +1,299 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Record and replay of the device accesses performed by a guest.
+//!
+//! A [`Recorder`](struct.Recorder.html) dispatches accesses to an `IoManager` like the VMM
+//! exit handlers do, and appends each of them to an [`IoLog`](struct.IoLog.html) together
+//! with its data and result. A [`Replayer`](struct.Replayer.html) later serves the same
+//! sequence of accesses from the log, without any device, and reports the point where the
+//! guest diverges from the recording. Accesses are replayed in the order they were recorded,
+//! so VMMs with multiple vCPUs should keep one log per vCPU.
+
+use std::fmt::{Display, Formatter};
+use std::sync::Mutex;
+
+use crate::bus::{self, MmioBusAddress, PioAddress};
+use crate::device_manager::{IoManager, MmioManager, PioManager};
+
+/// Errors encountered while replaying accesses.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The access was recorded as failing with this error.
+    Bus(bus::Error),
+    /// All the recorded accesses have been replayed.
+    Exhausted,
+    /// The access doesn't match the record with this index.
+    Diverged(usize),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Bus(_) => write!(f, "recorded bus error"),
+            Error::Exhausted => write!(f, "no recorded access left to replay"),
+            Error::Diverged(index) => write!(f, "access diverges from record {}", index),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Bus(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Address space of a recorded access.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum IoSpace {
+    /// Port I/O.
+    Pio,
+    /// Memory mapped I/O.
+    Mmio,
+}
+
+/// Recorded access.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IoRecord {
+    /// Address space of the access.
+    pub space: IoSpace,
+    /// Address of the access.
+    pub addr: u64,
+    /// Whether the access is a write.
+    pub write: bool,
+    /// Data written, or returned by the read.
+    pub data: Vec<u8>,
+    /// Result of the access.
+    pub result: Result<(), bus::Error>,
+}
+
+/// Log of recorded accesses, which can be shared by the threads performing them.
+#[derive(Debug, Default)]
+pub struct IoLog {
+    records: Mutex<Vec<IoRecord>>,
+}
+
+impl IoLog {
+    /// Create an empty log.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append `record` to the log.
+    pub fn push(&self, record: IoRecord) {
+        self.records.lock().unwrap().push(record);
+    }
+
+    /// Return the number of records.
+    pub fn len(&self) -> usize {
+        self.records.lock().unwrap().len()
+    }
+
+    /// Return `true` if nothing has been recorded.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Return a copy of the records, oldest first.
+    pub fn records(&self) -> Vec<IoRecord> {
+        self.records.lock().unwrap().clone()
+    }
+
+    /// Consume the log and return its records.
+    pub fn into_records(self) -> Vec<IoRecord> {
+        self.records.into_inner().unwrap()
+    }
+}
+
+/// Dispatches accesses to an `IoManager`, and records them.
+pub struct Recorder<'a, M: MmioBusAddress> {
+    io_mgr: &'a IoManager<M>,
+    log: &'a IoLog,
+}
+
+impl<'a, M: MmioBusAddress> Recorder<'a, M> {
+    /// Create a recorder which dispatches to `io_mgr`, and appends to `log`.
+    pub fn new(io_mgr: &'a IoManager<M>, log: &'a IoLog) -> Self {
+        Recorder { io_mgr, log }
+    }
+
+    fn record(
+        &self,
+        space: IoSpace,
+        addr: u64,
+        write: bool,
+        data: &[u8],
+        result: Result<(), bus::Error>,
+    ) -> Result<(), bus::Error> {
+        self.log.push(IoRecord {
+            space,
+            addr,
+            write,
+            data: data.to_vec(),
+            result,
+        });
+        result
+    }
+
+    /// Dispatch and record a PIO read.
+    pub fn pio_read(&self, addr: PioAddress, data: &mut [u8]) -> Result<(), bus::Error> {
+        let result = self.io_mgr.pio_read(addr, data);
+        self.record(IoSpace::Pio, u64::from(addr.0), false, data, result)
+    }
+
+    /// Dispatch and record a PIO write.
+    pub fn pio_write(&self, addr: PioAddress, data: &[u8]) -> Result<(), bus::Error> {
+        let result = self.io_mgr.pio_write(addr, data);
+        self.record(IoSpace::Pio, u64::from(addr.0), true, data, result)
+    }
+
+    /// Dispatch and record a MMIO read.
+    pub fn mmio_read(&self, addr: M, data: &mut [u8]) -> Result<(), bus::Error> {
+        let result = self.io_mgr.mmio_read(addr, data);
+        self.record(IoSpace::Mmio, addr.to_mmio_address().0, false, data, result)
+    }
+
+    /// Dispatch and record a MMIO write.
+    pub fn mmio_write(&self, addr: M, data: &[u8]) -> Result<(), bus::Error> {
+        let result = self.io_mgr.mmio_write(addr, data);
+        self.record(IoSpace::Mmio, addr.to_mmio_address().0, true, data, result)
+    }
+}
+
+/// Serves accesses from recorded ones.
+#[derive(Debug)]
+pub struct Replayer {
+    records: Vec<IoRecord>,
+    next: Mutex<usize>,
+}
+
+impl Replayer {
+    /// Create a replayer which serves the accesses of `records`, in order.
+    pub fn new(records: Vec<IoRecord>) -> Self {
+        Replayer {
+            records,
+            next: Mutex::new(0),
+        }
+    }
+
+    /// Return the number of accesses left to replay.
+    pub fn remaining(&self) -> usize {
+        self.records.len() - *self.next.lock().unwrap()
+    }
+
+    // Consume the next record, if it matches the access. Writes must also match the
+    // recorded data, while reads only have to match its length.
+    fn next(
+        &self,
+        space: IoSpace,
+        addr: u64,
+        write: bool,
+        data: &[u8],
+    ) -> Result<&IoRecord, Error> {
+        let mut next = self.next.lock().unwrap();
+        let index = *next;
+        let record = self.records.get(index).ok_or(Error::Exhausted)?;
+        if record.space != space
+            || record.addr != addr
+            || record.write != write
+            || record.data.len() != data.len()
+            || (write && record.data != data)
+        {
+            return Err(Error::Diverged(index));
+        }
+        *next += 1;
+        Ok(record)
+    }
+
+    fn read(&self, space: IoSpace, addr: u64, data: &mut [u8]) -> Result<(), Error> {
+        let record = self.next(space, addr, false, data)?;
+        data.copy_from_slice(&record.data);
+        record.result.map_err(Error::Bus)
+    }
+
+    fn write(&self, space: IoSpace, addr: u64, data: &[u8]) -> Result<(), Error> {
+        self.next(space, addr, true, data)?
+            .result
+            .map_err(Error::Bus)
+    }
+
+    /// Replay a PIO read.
+    pub fn pio_read(&self, addr: PioAddress, data: &mut [u8]) -> Result<(), Error> {
+        self.read(IoSpace::Pio, u64::from(addr.0), data)
+    }
+
+    /// Replay a PIO write.
+    pub fn pio_write(&self, addr: PioAddress, data: &[u8]) -> Result<(), Error> {
+        self.write(IoSpace::Pio, u64::from(addr.0), data)
+    }
+
+    /// Replay a MMIO read.
+    pub fn mmio_read<M: MmioBusAddress>(&self, addr: M, data: &mut [u8]) -> Result<(), Error> {
+        self.read(IoSpace::Mmio, addr.to_mmio_address().0, data)
+    }
+
+    /// Replay a MMIO write.
+    pub fn mmio_write<M: MmioBusAddress>(&self, addr: M, data: &[u8]) -> Result<(), Error> {
+        self.write(IoSpace::Mmio, addr.to_mmio_address().0, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::bus::{MmioAddress, MmioRange, PioRange};
+    use crate::testing::RegisterFileDevice;
+
+    #[test]
+    fn test_record_replay() {
+        let mut io_mgr = IoManager::new();
+        let dev = Arc::new(RegisterFileDevice::new(0x10));
+        io_mgr
+            .register_mmio(
+                MmioRange::new(MmioAddress(0x1000), 0x10).unwrap(),
+                dev.clone(),
+            )
+            .unwrap();
+        io_mgr
+            .register_pio(PioRange::new(PioAddress(0x60), 0x10).unwrap(), dev)
+            .unwrap();
+
+        let log = IoLog::new();
+        let recorder = Recorder::new(&io_mgr, &log);
+        let mut data = [0; 2];
+        recorder.mmio_write(MmioAddress(0x1004), &[1, 2]).unwrap();
+        recorder.pio_read(PioAddress(0x64), &mut data).unwrap();
+        assert_eq!(data, [1, 2]);
+        assert_eq!(
+            recorder.mmio_read(MmioAddress(0x2000), &mut data),
+            Err(bus::Error::DeviceNotFound)
+        );
+        assert_eq!(log.len(), 3);
+
+        // Replaying needs no device at all.
+        let replayer = Replayer::new(log.into_records());
+        replayer.mmio_write(MmioAddress(0x1004), &[1, 2]).unwrap();
+        let mut data = [0; 2];
+        assert_eq!(
+            replayer.pio_read(PioAddress(0x65), &mut data),
+            Err(Error::Diverged(1))
+        );
+        replayer.pio_read(PioAddress(0x64), &mut data).unwrap();
+        assert_eq!(data, [1, 2]);
+        assert_eq!(
+            replayer.mmio_read(MmioAddress(0x2000), &mut data),
+            Err(Error::Bus(bus::Error::DeviceNotFound))
+        );
+        assert_eq!(replayer.remaining(), 0);
+        assert_eq!(
+            replayer.mmio_write(MmioAddress(0x1004), &[1, 2]),
+            Err(Error::Exhausted)
+        );
+    }
+}
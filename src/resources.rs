@@ -11,19 +11,26 @@
 //! 4) the VMM passes the allocated resources to the device object.
 //! 5) the VMM registers the new device onto corresponding device managers according the allocated
 //!    resources.
+//!
+//! Devices can also be registered together with a `DeviceConfig`, which describes how the VMM
+//! configured them (e.g. the path of the backend of a serial port), so management layers can
+//! enumerate the devices with their configuration from the device manager alone.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
 
-use std::{u16, u32, u64};
+use crate::bus::PioAddressValue;
 
 /// Enumeration describing a device's resource constraints.
 pub enum ResourceConstraint {
     /// Constraint for an IO Port address range.
     PioAddress {
         /// Allocating resource within the range [`min`, `max`] if specified.
-        range: Option<(u16, u16)>,
+        range: Option<(PioAddressValue, PioAddressValue)>,
         /// Alignment for the allocated address.
-        align: u16,
+        align: PioAddressValue,
         /// Size for the allocated address range.
-        size: u16,
+        size: PioAddressValue,
     },
     /// Constraint for a Memory Mapped IO address range.
     MmioAddress {
@@ -65,7 +72,7 @@ pub enum ResourceConstraint {
 
 impl ResourceConstraint {
     /// Create a new PIO address constraint object with default configuration.
-    pub fn new_pio(size: u16) -> Self {
+    pub fn new_pio(size: PioAddressValue) -> Self {
         ResourceConstraint::PioAddress {
             range: None,
             align: 0x1,
@@ -74,7 +81,11 @@ impl ResourceConstraint {
     }
 
     /// Create a new PIO address constraint object.
-    pub fn pio_with_constraints(size: u16, range: Option<(u16, u16)>, align: u16) -> Self {
+    pub fn pio_with_constraints(
+        size: PioAddressValue,
+        range: Option<(PioAddressValue, PioAddressValue)>,
+        align: PioAddressValue,
+    ) -> Self {
         ResourceConstraint::PioAddress { range, align, size }
     }
 
@@ -108,7 +119,9 @@ impl ResourceConstraint {
 }
 
 /// Type of Message Singaled Interrupt
-#[derive(Copy, Clone, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum MsiIrqType {
     /// PCI MSI IRQ numbers.
     PciMsi,
@@ -120,10 +133,15 @@ pub enum MsiIrqType {
 
 /// Enumeration for device resources.
 #[allow(missing_docs)]
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum Resource {
     /// IO Port address range.
-    PioAddressRange { base: u16, size: u16 },
+    PioAddressRange {
+        base: PioAddressValue,
+        size: PioAddressValue,
+    },
     /// Memory Mapped IO address range.
     MmioAddressRange { base: u64, size: u64 },
     /// Legacy IRQ number.
@@ -156,7 +174,7 @@ impl DeviceResources {
     }
 
     /// Get the IO port address resources.
-    pub fn get_pio_address_ranges(&self) -> Vec<(u16, u16)> {
+    pub fn get_pio_address_ranges(&self) -> Vec<(PioAddressValue, PioAddressValue)> {
         let mut vec = Vec::new();
         for entry in self.0.iter().as_ref() {
             if let Resource::PioAddressRange { base, size } = entry {
@@ -245,12 +263,108 @@ impl DeviceResources {
     }
 }
 
+/// Typed value of a device configuration entry.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigValue {
+    /// Boolean flag.
+    Bool(bool),
+    /// Signed integer.
+    Int(i64),
+    /// Unsigned integer, e.g. a size or a queue count.
+    UInt(u64),
+    /// Free-form string.
+    Str(String),
+    /// Path on the host, e.g. a disk image or a socket.
+    Path(PathBuf),
+}
+
+impl From<bool> for ConfigValue {
+    fn from(value: bool) -> Self {
+        ConfigValue::Bool(value)
+    }
+}
+
+impl From<i64> for ConfigValue {
+    fn from(value: i64) -> Self {
+        ConfigValue::Int(value)
+    }
+}
+
+impl From<u64> for ConfigValue {
+    fn from(value: u64) -> Self {
+        ConfigValue::UInt(value)
+    }
+}
+
+impl From<&str> for ConfigValue {
+    fn from(value: &str) -> Self {
+        ConfigValue::Str(value.to_string())
+    }
+}
+
+impl From<String> for ConfigValue {
+    fn from(value: String) -> Self {
+        ConfigValue::Str(value)
+    }
+}
+
+impl From<PathBuf> for ConfigValue {
+    fn from(value: PathBuf) -> Self {
+        ConfigValue::Path(value)
+    }
+}
+
+/// Configuration of a device, as a set of typed key/value entries.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DeviceConfig {
+    kind: String,
+    entries: BTreeMap<String, ConfigValue>,
+}
+
+impl DeviceConfig {
+    /// Create an empty configuration for a device of `kind` (e.g. `serial`).
+    pub fn new(kind: &str) -> Self {
+        DeviceConfig {
+            kind: kind.to_string(),
+            entries: BTreeMap::new(),
+        }
+    }
+
+    /// Set the entry `key` to `value`.
+    pub fn with_entry<V: Into<ConfigValue>>(mut self, key: &str, value: V) -> Self {
+        self.set(key, value);
+        self
+    }
+
+    /// Set the entry `key` to `value`, and return the previous value, if any.
+    pub fn set<V: Into<ConfigValue>>(&mut self, key: &str, value: V) -> Option<ConfigValue> {
+        self.entries.insert(key.to_string(), value.into())
+    }
+
+    /// Return the kind of the device.
+    pub fn kind(&self) -> &str {
+        &self.kind
+    }
+
+    /// Return the value of the entry `key`.
+    pub fn get(&self, key: &str) -> Option<&ConfigValue> {
+        self.entries.get(key)
+    }
+
+    /// Return the entries, ordered by key.
+    pub fn entries(&self) -> impl Iterator<Item = (&str, &ConfigValue)> {
+        self.entries
+            .iter()
+            .map(|(key, value)| (key.as_str(), value))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    const PIO_ADDRESS_SIZE: u16 = 5;
-    const PIO_ADDRESS_BASE: u16 = 0;
+    const PIO_ADDRESS_SIZE: PioAddressValue = 5;
+    const PIO_ADDRESS_BASE: PioAddressValue = 0;
     const MMIO_ADDRESS_SIZE: u64 = 0x8765_4321;
     const MMIO_ADDRESS_BASE: u64 = 0x1234_5678;
     const LEGACY_IRQ: u32 = 0x168;
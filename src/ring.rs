@@ -0,0 +1,435 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Descriptor rings shared with the guest, for devices exchanging buffers with their driver
+//! through DMA.
+//!
+//! A [`Ring`](struct.Ring.html) follows the layout of the virtio split virtqueue, which is
+//! also a good fit for custom queue-based devices: a table of descriptors, a driver ring
+//! where the guest publishes the heads of the descriptor chains it makes available, and a
+//! device ring where the device returns them once used. The ring indices are free running
+//! 16-bit counters which wrap around, and the guest visible fields are accessed atomically
+//! through `vm_memory`, with the barriers the protocol requires. The device side can tell
+//! the driver when it doesn't need to be notified, and check whether the driver wants an
+//! interrupt after returning buffers, either through the ring flags or through the event
+//! indices.
+
+use std::fmt::{Display, Formatter};
+use std::num::Wrapping;
+use std::sync::atomic::{fence, Ordering};
+
+use vm_memory::{Address, Bytes, GuestAddress, GuestMemory, GuestMemoryError};
+
+/// The descriptor continues in the one at index `next`.
+pub const DESC_F_NEXT: u16 = 0x1;
+/// The buffer of the descriptor is written by the device.
+pub const DESC_F_WRITE: u16 = 0x2;
+
+/// Set by the device in the device ring when it doesn't need to be notified.
+pub const USED_F_NO_NOTIFY: u16 = 0x1;
+/// Set by the driver in the driver ring when it doesn't need to be interrupted.
+pub const AVAIL_F_NO_INTERRUPT: u16 = 0x1;
+
+/// Largest number of entries of a ring.
+pub const MAX_RING_SIZE: u16 = 0x8000;
+
+const DESCRIPTOR_SIZE: u64 = 16;
+const USED_ELEM_SIZE: u64 = 8;
+// Offset of the entries of both rings, after the flags and index fields.
+const RING_OFFSET: u64 = 4;
+
+/// Errors encountered while accessing a ring.
+#[derive(Debug)]
+pub enum Error {
+    /// The driver made more entries available than the ring holds.
+    AvailIndex(u16),
+    /// A descriptor chain is longer than the ring, so it probably loops.
+    ChainTooLong(u16),
+    /// The descriptor index is out of the table.
+    InvalidIndex(u16),
+    /// The ring size is not a power of two between 1 and `MAX_RING_SIZE`.
+    InvalidSize(u16),
+    /// Failed to access the guest memory.
+    Memory(GuestMemoryError),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::AvailIndex(idx) => write!(f, "invalid available ring index {}", idx),
+            Error::ChainTooLong(head) => write!(f, "descriptor chain {} is too long", head),
+            Error::InvalidIndex(idx) => write!(f, "invalid descriptor index {}", idx),
+            Error::InvalidSize(size) => write!(f, "invalid ring size {}", size),
+            Error::Memory(_) => write!(f, "failed to access the ring"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Memory(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// A buffer described by the driver.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Descriptor {
+    /// Guest address of the buffer.
+    pub addr: GuestAddress,
+    /// Length of the buffer.
+    pub len: u32,
+    /// `DESC_F_*` flags.
+    pub flags: u16,
+    /// Index of the next descriptor of the chain, if `DESC_F_NEXT` is set.
+    pub next: u16,
+}
+
+impl Descriptor {
+    /// Return `true` if the device writes the buffer, and `false` if it reads it.
+    pub fn is_write_only(&self) -> bool {
+        self.flags & DESC_F_WRITE != 0
+    }
+
+    /// Return `true` if the chain continues after this descriptor.
+    pub fn has_next(&self) -> bool {
+        self.flags & DESC_F_NEXT != 0
+    }
+}
+
+/// The descriptors of a chain made available by the driver, in order.
+pub struct DescriptorChain<'a, M> {
+    mem: &'a M,
+    desc_table: GuestAddress,
+    size: u16,
+    head: u16,
+    next: Option<u16>,
+    count: u16,
+}
+
+impl<M: GuestMemory> DescriptorChain<'_, M> {
+    /// Return the index of the first descriptor, which identifies the chain when it is
+    /// returned with `Ring::add_used`.
+    pub fn head(&self) -> u16 {
+        self.head
+    }
+
+    fn read(&self, idx: u16) -> Result<Descriptor, Error> {
+        if idx >= self.size {
+            return Err(Error::InvalidIndex(idx));
+        }
+        let addr = self
+            .desc_table
+            .unchecked_add(u64::from(idx) * DESCRIPTOR_SIZE);
+        let read = |offset| self.mem.read_obj::<u64>(addr.unchecked_add(offset));
+        let buf = read(0).map_err(Error::Memory)?;
+        let rest = read(8).map_err(Error::Memory)?;
+        Ok(Descriptor {
+            addr: GuestAddress(u64::from_le(buf)),
+            len: u64::from_le(rest) as u32,
+            flags: (u64::from_le(rest) >> 32) as u16,
+            next: (u64::from_le(rest) >> 48) as u16,
+        })
+    }
+}
+
+impl<M: GuestMemory> Iterator for DescriptorChain<'_, M> {
+    type Item = Result<Descriptor, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let idx = self.next.take()?;
+        if self.count == self.size {
+            return Some(Err(Error::ChainTooLong(self.head)));
+        }
+        self.count += 1;
+        let desc = self.read(idx);
+        if let Ok(desc) = desc.as_ref() {
+            if desc.has_next() {
+                self.next = Some(desc.next);
+            }
+        }
+        Some(desc)
+    }
+}
+
+/// Device side of a descriptor ring.
+#[derive(Clone, Debug)]
+pub struct Ring {
+    size: u16,
+    desc_table: GuestAddress,
+    avail: GuestAddress,
+    used: GuestAddress,
+    next_avail: Wrapping<u16>,
+    next_used: Wrapping<u16>,
+    event_idx: bool,
+    // Device ring index at the time of the last interrupt.
+    signalled_used: Option<Wrapping<u16>>,
+}
+
+impl Ring {
+    /// Create a ring of `size` entries, with the descriptor table, the driver ring and the
+    /// device ring at the given guest addresses.
+    pub fn new(
+        size: u16,
+        desc_table: GuestAddress,
+        avail: GuestAddress,
+        used: GuestAddress,
+    ) -> Result<Self, Error> {
+        if !size.is_power_of_two() || size > MAX_RING_SIZE {
+            return Err(Error::InvalidSize(size));
+        }
+        Ok(Ring {
+            size,
+            desc_table,
+            avail,
+            used,
+            next_avail: Wrapping(0),
+            next_used: Wrapping(0),
+            event_idx: false,
+            signalled_used: None,
+        })
+    }
+
+    /// Suppress the notifications through the event indices at the end of both rings,
+    /// rather than through the ring flags.
+    pub fn with_event_idx(mut self) -> Self {
+        self.event_idx = true;
+        self
+    }
+
+    /// Return the number of entries of the ring.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Return the index of the next entry of the driver ring to consume.
+    pub fn next_avail(&self) -> u16 {
+        self.next_avail.0
+    }
+
+    /// Return the index of the next entry of the device ring to fill.
+    pub fn next_used(&self) -> u16 {
+        self.next_used.0
+    }
+
+    /// Set both ring indices, e.g. when restoring the device.
+    pub fn set_indices(&mut self, next_avail: u16, next_used: u16) {
+        self.next_avail = Wrapping(next_avail);
+        self.next_used = Wrapping(next_used);
+        self.signalled_used = None;
+    }
+
+    fn avail_entry(&self, idx: Wrapping<u16>) -> GuestAddress {
+        let slot = u64::from(idx.0 % self.size);
+        self.avail.unchecked_add(RING_OFFSET + slot * 2)
+    }
+
+    fn used_entry(&self, idx: Wrapping<u16>) -> GuestAddress {
+        let slot = u64::from(idx.0 % self.size);
+        self.used.unchecked_add(RING_OFFSET + slot * USED_ELEM_SIZE)
+    }
+
+    // The device writes the index of the next driver ring entry it wants to be notified
+    // about after the device ring entries.
+    fn avail_event(&self) -> GuestAddress {
+        self.used
+            .unchecked_add(RING_OFFSET + u64::from(self.size) * USED_ELEM_SIZE)
+    }
+
+    // The driver writes the index of the next device ring entry it wants to be
+    // interrupted about after the driver ring entries.
+    fn used_event(&self) -> GuestAddress {
+        self.avail
+            .unchecked_add(RING_OFFSET + u64::from(self.size) * 2)
+    }
+
+    fn load_u16<M: GuestMemory>(mem: &M, addr: GuestAddress) -> Result<u16, Error> {
+        mem.load::<u16>(addr, Ordering::Acquire)
+            .map(u16::from_le)
+            .map_err(Error::Memory)
+    }
+
+    fn store_u16<M: GuestMemory>(mem: &M, value: u16, addr: GuestAddress) -> Result<(), Error> {
+        mem.store(value.to_le(), addr, Ordering::Release)
+            .map_err(Error::Memory)
+    }
+
+    /// Return the number of chains made available by the driver and not consumed yet.
+    pub fn pending<M: GuestMemory>(&self, mem: &M) -> Result<u16, Error> {
+        let avail_idx = Wrapping(Self::load_u16(mem, self.avail.unchecked_add(2))?);
+        let pending = (avail_idx - self.next_avail).0;
+        if pending > self.size {
+            return Err(Error::AvailIndex(avail_idx.0));
+        }
+        Ok(pending)
+    }
+
+    /// Consume the next chain made available by the driver, if any.
+    pub fn pop<'a, M: GuestMemory>(
+        &mut self,
+        mem: &'a M,
+    ) -> Result<Option<DescriptorChain<'a, M>>, Error> {
+        if self.pending(mem)? == 0 {
+            return Ok(None);
+        }
+        let head = Self::load_u16(mem, self.avail_entry(self.next_avail))?;
+        self.next_avail += Wrapping(1);
+        Ok(Some(DescriptorChain {
+            mem,
+            desc_table: self.desc_table,
+            size: self.size,
+            head,
+            next: Some(head),
+            count: 0,
+        }))
+    }
+
+    /// Return the chain starting at `head` to the driver, after the device wrote `len`
+    /// bytes to its buffers.
+    pub fn add_used<M: GuestMemory>(&mut self, mem: &M, head: u16, len: u32) -> Result<(), Error> {
+        if head >= self.size {
+            return Err(Error::InvalidIndex(head));
+        }
+        let elem = u64::from(head) | u64::from(len) << 32;
+        mem.write_obj(elem.to_le(), self.used_entry(self.next_used))
+            .map_err(Error::Memory)?;
+        self.next_used += Wrapping(1);
+        // The entry must be visible before the index which publishes it.
+        Self::store_u16(mem, self.next_used.0, self.used.unchecked_add(2))
+    }
+
+    /// Ask the driver to notify the device when it makes chains available. Returns `true`
+    /// if chains were made available in the meantime, in which case the device should
+    /// process them since the driver may not have notified it.
+    pub fn enable_notification<M: GuestMemory>(&mut self, mem: &M) -> Result<bool, Error> {
+        if self.event_idx {
+            Self::store_u16(mem, self.next_avail.0, self.avail_event())?;
+        } else {
+            let flags = Self::load_u16(mem, self.used)?;
+            Self::store_u16(mem, flags & !USED_F_NO_NOTIFY, self.used)?;
+        }
+        // The write must be visible before checking the driver ring index.
+        fence(Ordering::SeqCst);
+        Ok(self.pending(mem)? != 0)
+    }
+
+    /// Tell the driver the device doesn't need to be notified, e.g. while it is processing
+    /// the chains already available.
+    pub fn disable_notification<M: GuestMemory>(&mut self, mem: &M) -> Result<(), Error> {
+        // With event indices, the driver stops notifying once it goes past `avail_event`.
+        if self.event_idx {
+            return Ok(());
+        }
+        let flags = Self::load_u16(mem, self.used)?;
+        Self::store_u16(mem, flags | USED_F_NO_NOTIFY, self.used)
+    }
+
+    /// Return `true` if the driver has to be interrupted about the chains returned since
+    /// the last call.
+    pub fn needs_notification<M: GuestMemory>(&mut self, mem: &M) -> Result<bool, Error> {
+        // The device ring index must be visible before checking what the driver asked.
+        fence(Ordering::SeqCst);
+        if !self.event_idx {
+            let flags = Self::load_u16(mem, self.avail)?;
+            return Ok(flags & AVAIL_F_NO_INTERRUPT == 0);
+        }
+        let used_event = Wrapping(Self::load_u16(mem, self.used_event())?);
+        let new = self.next_used;
+        let notify = match self.signalled_used.replace(new) {
+            // Interrupt if `used_event` is within the entries returned since the last call.
+            Some(old) => (new - used_event - Wrapping(1)) < (new - old),
+            None => true,
+        };
+        Ok(notify)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vm_memory::GuestMemoryMmap;
+
+    const SIZE: u16 = 4;
+    const DESC_TABLE: GuestAddress = GuestAddress(0x1000);
+    const AVAIL: GuestAddress = GuestAddress(0x2000);
+    const USED: GuestAddress = GuestAddress(0x3000);
+
+    fn write_desc(mem: &GuestMemoryMmap, idx: u16, addr: u64, len: u32, flags: u16, next: u16) {
+        let desc = DESC_TABLE.unchecked_add(u64::from(idx) * DESCRIPTOR_SIZE);
+        mem.write_obj(addr, desc).unwrap();
+        mem.write_obj(len, desc.unchecked_add(8)).unwrap();
+        mem.write_obj(flags, desc.unchecked_add(12)).unwrap();
+        mem.write_obj(next, desc.unchecked_add(14)).unwrap();
+    }
+
+    // Make the chain starting at `head` available, as the driver would.
+    fn make_available(mem: &GuestMemoryMmap, head: u16) {
+        let idx: u16 = mem.read_obj(AVAIL.unchecked_add(2)).unwrap();
+        let slot = u64::from(idx % SIZE);
+        mem.write_obj(head, AVAIL.unchecked_add(RING_OFFSET + slot * 2))
+            .unwrap();
+        mem.write_obj(idx.wrapping_add(1), AVAIL.unchecked_add(2))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_ring() {
+        let mem = GuestMemoryMmap::<()>::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        assert!(Ring::new(3, DESC_TABLE, AVAIL, USED).is_err());
+        let mut ring = Ring::new(SIZE, DESC_TABLE, AVAIL, USED).unwrap();
+        assert!(ring.pop(&mem).unwrap().is_none());
+
+        write_desc(&mem, 2, 0x8000, 0x100, DESC_F_NEXT, 0);
+        write_desc(&mem, 0, 0x9000, 0x200, DESC_F_WRITE, 0);
+        make_available(&mem, 2);
+        let chain = ring.pop(&mem).unwrap().unwrap();
+        assert_eq!(chain.head(), 2);
+        let descs: Vec<Descriptor> = chain.map(Result::unwrap).collect();
+        assert_eq!(descs.len(), 2);
+        assert_eq!(descs[0].addr, GuestAddress(0x8000));
+        assert!(!descs[0].is_write_only());
+        assert_eq!(descs[1].len, 0x200);
+        assert!(descs[1].is_write_only());
+
+        ring.add_used(&mem, 2, 0x80).unwrap();
+        let used_idx: u16 = mem.read_obj(USED.unchecked_add(2)).unwrap();
+        assert_eq!(used_idx, 1);
+        let elem: u64 = mem.read_obj(USED.unchecked_add(RING_OFFSET)).unwrap();
+        assert_eq!(elem, 0x80_0000_0002);
+        assert!(ring.needs_notification(&mem).unwrap());
+
+        // A chain looping on itself is cut.
+        write_desc(&mem, 1, 0x8000, 0x100, DESC_F_NEXT, 1);
+        make_available(&mem, 1);
+        let chain = ring.pop(&mem).unwrap().unwrap();
+        assert!(matches!(chain.last(), Some(Err(Error::ChainTooLong(1)))));
+
+        // The indices wrap around.
+        let mut ring = Ring::new(SIZE, DESC_TABLE, AVAIL, USED)
+            .unwrap()
+            .with_event_idx();
+        mem.write_obj(u16::MAX, AVAIL.unchecked_add(2)).unwrap();
+        mem.write_obj(u16::MAX, USED.unchecked_add(2)).unwrap();
+        ring.set_indices(u16::MAX, u16::MAX);
+        make_available(&mem, 0);
+        make_available(&mem, 1);
+        assert_eq!(ring.pending(&mem).unwrap(), 2);
+        assert!(ring.enable_notification(&mem).unwrap());
+        let head = ring.pop(&mem).unwrap().unwrap().head();
+        assert_eq!(head, 0);
+        let avail_event: u16 = mem.read_obj(ring.avail_event()).unwrap();
+        assert_eq!(avail_event, u16::MAX);
+
+        // The driver asks to be interrupted once the entry at index 0 is used.
+        mem.write_obj(0u16, ring.used_event()).unwrap();
+        ring.add_used(&mem, 0, 0).unwrap();
+        assert!(ring.needs_notification(&mem).unwrap());
+        ring.add_used(&mem, 1, 0).unwrap();
+        assert!(ring.needs_notification(&mem).unwrap());
+        ring.add_used(&mem, 1, 0).unwrap();
+        assert!(!ring.needs_notification(&mem).unwrap());
+    }
+}
@@ -0,0 +1,405 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! SMBIOS (DMI) tables describing the platform to the guest.
+//!
+//! Guests read the vendor, product and serial strings of the machine (e.g. with `dmidecode`,
+//! or through `/sys/class/dmi`) from the SMBIOS tables. The
+//! [`SmbiosBuilder`](struct.SmbiosBuilder.html) collects these strings and generates the
+//! BIOS, system, chassis and OEM strings structures, along with a 64-bit (SMBIOS 3.0) entry
+//! point. The resulting [`SmbiosTables`](struct.SmbiosTables.html) are exposed to the guest
+//! as a read-only memory region registered on the MMIO bus, at an address the firmware or
+//! the guest kernel is told about (e.g. through the `smbios3` UEFI configuration table, or
+//! the `0xf0000` legacy BIOS area scanned by x86 guests).
+
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+
+use crate::bus::{self, MmioAddress, MmioRange};
+use crate::device_manager::{IoManager, MmioManager};
+use crate::devices::ram::RomDevice;
+
+/// Size of the SMBIOS 3.0 entry point structure.
+pub const SMBIOS_ENTRY_POINT_SIZE: usize = 0x18;
+
+// The structure table starts right after the entry point, on a 16-byte boundary.
+const TABLE_OFFSET: usize = 0x20;
+
+const BIOS_INFORMATION: u8 = 0;
+const SYSTEM_INFORMATION: u8 = 1;
+const CHASSIS_INFORMATION: u8 = 3;
+const OEM_STRINGS: u8 = 11;
+const END_OF_TABLE: u8 = 127;
+
+// "BIOS characteristics are not supported".
+const BIOS_CHARACTERISTICS_UNSUPPORTED: u64 = 1 << 3;
+// "SMBIOS table describes a virtual machine", in the second extension byte.
+const BIOS_CHARACTERISTICS_EXT2_VM: u8 = 1 << 4;
+// Wake-up type of the system: power switch.
+const WAKE_UP_POWER_SWITCH: u8 = 0x06;
+// Chassis type: other.
+const CHASSIS_TYPE_OTHER: u8 = 0x01;
+// Boot-up, power supply and thermal state: safe.
+const CHASSIS_STATE_SAFE: u8 = 0x03;
+// Security status: unknown.
+const CHASSIS_SECURITY_UNKNOWN: u8 = 0x02;
+
+/// Errors encountered while building or exposing the SMBIOS tables.
+#[derive(Debug)]
+pub enum Error {
+    /// The string contains a NUL byte, or is an empty OEM string, which can't be encoded in
+    /// the tables.
+    InvalidString(String),
+    /// A structure holds more than the 255 strings it can reference.
+    TooManyStrings,
+    /// Failed to register the tables on the MMIO bus.
+    Bus(bus::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::InvalidString(s) => write!(f, "invalid SMBIOS string: {:?}", s),
+            Error::TooManyStrings => write!(f, "too many strings in an SMBIOS structure"),
+            Error::Bus(_) => write!(f, "failed to register the SMBIOS tables"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Bus(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// A structure being assembled: the formatted area, then the strings it references.
+struct Structure {
+    data: Vec<u8>,
+    strings: Vec<u8>,
+    count: u8,
+}
+
+impl Structure {
+    fn new(kind: u8, handle: u16) -> Self {
+        let mut data = vec![kind, 0];
+        data.extend_from_slice(&handle.to_le_bytes());
+        Structure {
+            data,
+            strings: Vec::new(),
+            count: 0,
+        }
+    }
+
+    fn byte(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    fn bytes(&mut self, value: &[u8]) {
+        self.data.extend_from_slice(value);
+    }
+
+    // Add `value` to the string set, and return its index.
+    fn add_string(&mut self, value: &str) -> Result<u8, Error> {
+        // An empty string would end the string set.
+        if value.is_empty() || value.contains('\0') {
+            return Err(Error::InvalidString(value.to_string()));
+        }
+        self.count = self.count.checked_add(1).ok_or(Error::TooManyStrings)?;
+        self.strings.extend_from_slice(value.as_bytes());
+        self.strings.push(0);
+        Ok(self.count)
+    }
+
+    // Append the index of `value` in the string set, or 0 when the string isn't set.
+    fn string(&mut self, value: &Option<String>) -> Result<(), Error> {
+        let index = match value.as_deref() {
+            None | Some("") => 0,
+            Some(value) => self.add_string(value)?,
+        };
+        self.byte(index);
+        Ok(())
+    }
+
+    fn finish(mut self, table: &mut Vec<u8>) {
+        self.data[1] = self.data.len() as u8;
+        table.extend_from_slice(&self.data);
+        // The string set ends with an additional NUL, and is never shorter than two NULs.
+        if self.strings.is_empty() {
+            self.strings.push(0);
+        }
+        table.extend_from_slice(&self.strings);
+        table.push(0);
+    }
+}
+
+/// Collects the strings describing the platform, and builds the SMBIOS tables.
+#[derive(Clone, Default)]
+pub struct SmbiosBuilder {
+    bios_vendor: Option<String>,
+    bios_version: Option<String>,
+    bios_release_date: Option<String>,
+    manufacturer: Option<String>,
+    product: Option<String>,
+    version: Option<String>,
+    serial: Option<String>,
+    uuid: [u8; 16],
+    sku: Option<String>,
+    family: Option<String>,
+    chassis_asset_tag: Option<String>,
+    oem_strings: Vec<String>,
+}
+
+impl SmbiosBuilder {
+    /// Create a builder with no strings set.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the vendor, version and release date (`mm/dd/yyyy`) of the firmware.
+    pub fn bios(mut self, vendor: &str, version: &str, release_date: &str) -> Self {
+        self.bios_vendor = Some(vendor.to_string());
+        self.bios_version = Some(version.to_string());
+        self.bios_release_date = Some(release_date.to_string());
+        self
+    }
+
+    /// Set the manufacturer of the system, which is also reported for the chassis.
+    pub fn manufacturer(mut self, manufacturer: &str) -> Self {
+        self.manufacturer = Some(manufacturer.to_string());
+        self
+    }
+
+    /// Set the product name of the system.
+    pub fn product(mut self, product: &str) -> Self {
+        self.product = Some(product.to_string());
+        self
+    }
+
+    /// Set the version of the system.
+    pub fn version(mut self, version: &str) -> Self {
+        self.version = Some(version.to_string());
+        self
+    }
+
+    /// Set the serial number of the system, which is also reported for the chassis.
+    pub fn serial(mut self, serial: &str) -> Self {
+        self.serial = Some(serial.to_string());
+        self
+    }
+
+    /// Set the UUID of the system, in the byte order of its textual representation.
+    pub fn uuid(mut self, uuid: [u8; 16]) -> Self {
+        self.uuid = uuid;
+        self
+    }
+
+    /// Set the SKU number of the system.
+    pub fn sku(mut self, sku: &str) -> Self {
+        self.sku = Some(sku.to_string());
+        self
+    }
+
+    /// Set the family of the system.
+    pub fn family(mut self, family: &str) -> Self {
+        self.family = Some(family.to_string());
+        self
+    }
+
+    /// Set the asset tag of the chassis.
+    pub fn asset_tag(mut self, asset_tag: &str) -> Self {
+        self.chassis_asset_tag = Some(asset_tag.to_string());
+        self
+    }
+
+    /// Add a free-form, non-empty OEM string, e.g. to pass configuration to software in the
+    /// guest.
+    pub fn oem_string(mut self, value: &str) -> Self {
+        self.oem_strings.push(value.to_string());
+        self
+    }
+
+    /// Build the tables, to be exposed to the guest at `address`.
+    pub fn build(&self, address: u64) -> Result<SmbiosTables, Error> {
+        let mut table = Vec::new();
+        let mut handle = 0;
+        let mut next_handle = || {
+            handle += 1;
+            handle - 1
+        };
+
+        let mut bios = Structure::new(BIOS_INFORMATION, next_handle());
+        bios.string(&self.bios_vendor)?;
+        bios.string(&self.bios_version)?;
+        // The starting segment and ROM size only apply to legacy BIOS images.
+        bios.bytes(&[0, 0]);
+        bios.string(&self.bios_release_date)?;
+        bios.byte(0);
+        bios.bytes(&BIOS_CHARACTERISTICS_UNSUPPORTED.to_le_bytes());
+        bios.bytes(&[0, BIOS_CHARACTERISTICS_EXT2_VM]);
+        // The firmware and embedded controller releases are unknown.
+        bios.bytes(&[0xff; 4]);
+        bios.finish(&mut table);
+
+        let mut system = Structure::new(SYSTEM_INFORMATION, next_handle());
+        system.string(&self.manufacturer)?;
+        system.string(&self.product)?;
+        system.string(&self.version)?;
+        system.string(&self.serial)?;
+        // The first three fields of the UUID are stored in little endian.
+        let mut uuid = self.uuid;
+        uuid[..4].reverse();
+        uuid[4..6].reverse();
+        uuid[6..8].reverse();
+        system.bytes(&uuid);
+        system.byte(WAKE_UP_POWER_SWITCH);
+        system.string(&self.sku)?;
+        system.string(&self.family)?;
+        system.finish(&mut table);
+
+        let mut chassis = Structure::new(CHASSIS_INFORMATION, next_handle());
+        chassis.string(&self.manufacturer)?;
+        chassis.byte(CHASSIS_TYPE_OTHER);
+        chassis.string(&None)?;
+        chassis.string(&self.serial)?;
+        chassis.string(&self.chassis_asset_tag)?;
+        chassis.bytes(&[CHASSIS_STATE_SAFE; 3]);
+        chassis.byte(CHASSIS_SECURITY_UNKNOWN);
+        // OEM-defined value, height, power cords, and contained elements.
+        chassis.bytes(&[0; 8]);
+        chassis.finish(&mut table);
+
+        if !self.oem_strings.is_empty() {
+            let mut oem = Structure::new(OEM_STRINGS, next_handle());
+            if self.oem_strings.len() > usize::from(u8::MAX) {
+                return Err(Error::TooManyStrings);
+            }
+            oem.byte(self.oem_strings.len() as u8);
+            for value in self.oem_strings.iter() {
+                oem.add_string(value)?;
+            }
+            oem.finish(&mut table);
+        }
+
+        Structure::new(END_OF_TABLE, next_handle()).finish(&mut table);
+
+        let table_address = address + TABLE_OFFSET as u64;
+        let mut entry_point = Vec::with_capacity(SMBIOS_ENTRY_POINT_SIZE);
+        entry_point.extend_from_slice(b"_SM3_");
+        // Checksum, length, SMBIOS 3.2, docrev, and entry point revision.
+        entry_point.extend_from_slice(&[0, SMBIOS_ENTRY_POINT_SIZE as u8, 3, 2, 0, 1, 0]);
+        entry_point.extend_from_slice(&(table.len() as u32).to_le_bytes());
+        entry_point.extend_from_slice(&table_address.to_le_bytes());
+        let sum = entry_point
+            .iter()
+            .fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        entry_point[5] = sum.wrapping_neg();
+
+        Ok(SmbiosTables {
+            address,
+            entry_point,
+            table,
+        })
+    }
+}
+
+/// SMBIOS entry point and structure table, laid out for a given guest address.
+pub struct SmbiosTables {
+    address: u64,
+    entry_point: Vec<u8>,
+    table: Vec<u8>,
+}
+
+impl SmbiosTables {
+    /// Return the guest address of the entry point.
+    pub fn address(&self) -> u64 {
+        self.address
+    }
+
+    /// Return the entry point structure.
+    pub fn entry_point(&self) -> &[u8] {
+        &self.entry_point
+    }
+
+    /// Return the structure table.
+    pub fn table(&self) -> &[u8] {
+        &self.table
+    }
+
+    /// Return the contents of the guest memory holding the tables, starting at `address`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.entry_point.clone();
+        bytes.resize(TABLE_OFFSET, 0);
+        bytes.extend_from_slice(&self.table);
+        bytes
+    }
+
+    /// Register the tables on the MMIO bus of `io_mgr`, as a read-only region at `address`.
+    pub fn register(&self, io_mgr: &mut IoManager) -> Result<(), Error> {
+        let bytes = self.to_bytes();
+        let range =
+            MmioRange::new(MmioAddress(self.address), bytes.len() as u64).map_err(Error::Bus)?;
+        io_mgr
+            .register_mmio(range, Arc::new(RomDevice::new(bytes)))
+            .map_err(Error::Bus)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_smbios() {
+        let uuid = [
+            0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd,
+            0xee, 0xff,
+        ];
+        let tables = SmbiosBuilder::new()
+            .bios("vm-device", "1.0", "01/01/2020")
+            .manufacturer("Acme")
+            .product("Virtual Machine")
+            .uuid(uuid)
+            .oem_string("role=worker")
+            .build(0xf0000)
+            .unwrap();
+
+        let entry_point = tables.entry_point();
+        assert_eq!(&entry_point[..5], b"_SM3_");
+        assert_eq!(entry_point.iter().fold(0u8, |s, b| s.wrapping_add(*b)), 0);
+        assert_eq!(&entry_point[0x10..], &0xf0020u64.to_le_bytes());
+        assert_eq!(
+            &entry_point[0xc..0x10],
+            &(tables.table().len() as u32).to_le_bytes()
+        );
+
+        // Walk the structures, skipping their string sets.
+        let table = tables.table();
+        let mut kinds = Vec::new();
+        let mut pos = 0;
+        while pos < table.len() {
+            kinds.push(table[pos]);
+            let strings = pos + table[pos + 1] as usize;
+            let end = (strings..table.len() - 1)
+                .find(|&idx| table[idx] == 0 && table[idx + 1] == 0)
+                .unwrap();
+            if table[pos] == SYSTEM_INFORMATION {
+                assert_eq!(table[pos + 4], 1);
+                assert_eq!(&table[pos + 8..pos + 12], &[0x33, 0x22, 0x11, 0x00]);
+                assert_eq!(&table[strings..end], b"Acme\0Virtual Machine");
+            }
+            pos = end + 2;
+        }
+        assert_eq!(kinds, vec![0, 1, 3, 11, 127]);
+
+        let mut io_mgr = IoManager::new();
+        tables.register(&mut io_mgr).unwrap();
+        let mut data = [0; 5];
+        io_mgr.mmio_read(MmioAddress(0xf0000), &mut data).unwrap();
+        assert_eq!(&data, b"_SM3_");
+
+        assert!(SmbiosBuilder::new().product("a\0b").build(0).is_err());
+    }
+}
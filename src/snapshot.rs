@@ -0,0 +1,496 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Versioned device state.
+//!
+//! Device states implement [`Versioned`](trait.Versioned.html), which ties them to a
+//! [`Version`](struct.Version.html) and describes how they are encoded with a
+//! [`StateWriter`](struct.StateWriter.html) and decoded with a
+//! [`StateReader`](struct.StateReader.html). Saved states carry their version, so a newer
+//! build of a device can restore the states saved by an older one:
+//!
+//! - states with the same major version and an older (or equal) minor version are decoded
+//!   directly, the device being told which version it reads so it can default the fields
+//!   added since;
+//! - states with an older major version are first converted by the
+//!   [`Migrations`](struct.Migrations.html) of the device, one major version at a time.
+//!
+//! States saved by a newer version of a device are rejected. Once all the devices are
+//! restored and registered again, `IoManager::complete_restore` runs their
+//! [`PostRestore`](trait.PostRestore.html) hooks.
+
+use std::fmt::{Display, Formatter};
+use std::io;
+
+use crate::resources::Resource;
+
+/// Errors encountered while restoring device states.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The state belongs to another device.
+    IdMismatch(String),
+    /// The state is malformed.
+    InvalidData(String),
+    /// The state ends before all the fields were read.
+    UnexpectedEnd,
+    /// The state version can't be restored by the current version of the device.
+    UnsupportedVersion(Version),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::IdMismatch(id) => write!(f, "state belongs to device {}", id),
+            Error::InvalidData(msg) => write!(f, "invalid state: {}", msg),
+            Error::UnexpectedEnd => write!(f, "unexpected end of state"),
+            Error::UnsupportedVersion(version) => {
+                write!(f, "unsupported state version {}", version)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Semantic version of a device state. Minor versions only add fields at the end of the
+/// state, major versions change its layout, and patch versions don't affect it.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Version {
+    /// Major version.
+    pub major: u16,
+    /// Minor version.
+    pub minor: u16,
+    /// Patch version.
+    pub patch: u16,
+}
+
+impl Version {
+    /// Create a version.
+    pub const fn new(major: u16, minor: u16, patch: u16) -> Self {
+        Version {
+            major,
+            minor,
+            patch,
+        }
+    }
+
+    /// Return whether a state saved at `self` can be decoded directly by a device at
+    /// `current`.
+    pub fn is_compatible_with(&self, current: Version) -> bool {
+        self.major == current.major && self.minor <= current.minor
+    }
+}
+
+impl Display for Version {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Encodes the fields of a state, in little endian.
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    /// Create an empty writer.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a `u8`.
+    pub fn put_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    /// Append a `bool`.
+    pub fn put_bool(&mut self, value: bool) {
+        self.put_u8(u8::from(value));
+    }
+
+    /// Append a `u16`.
+    pub fn put_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Append a `u32`.
+    pub fn put_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Append a `u64`.
+    pub fn put_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Append a byte string, preceded by its length.
+    pub fn put_bytes(&mut self, value: &[u8]) {
+        self.put_u32(value.len() as u32);
+        self.data.extend_from_slice(value);
+    }
+
+    /// Return the encoded state.
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// Decodes the fields of a state encoded by a `StateWriter`.
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    /// Create a reader for `data`.
+    pub fn new(data: &'a [u8]) -> Self {
+        StateReader { data }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.data.len() < len {
+            return Err(Error::UnexpectedEnd);
+        }
+        let (value, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(value)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        let mut value = [0; N];
+        value.copy_from_slice(self.take(N)?);
+        Ok(value)
+    }
+
+    /// Read a `u8`.
+    pub fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    /// Read a `bool`.
+    pub fn bool(&mut self) -> Result<bool, Error> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            value => Err(Error::InvalidData(format!("{} is not a bool", value))),
+        }
+    }
+
+    /// Read a `u16`.
+    pub fn u16(&mut self) -> Result<u16, Error> {
+        self.array().map(u16::from_le_bytes)
+    }
+
+    /// Read a `u32`.
+    pub fn u32(&mut self) -> Result<u32, Error> {
+        self.array().map(u32::from_le_bytes)
+    }
+
+    /// Read a `u64`.
+    pub fn u64(&mut self) -> Result<u64, Error> {
+        self.array().map(u64::from_le_bytes)
+    }
+
+    /// Read a byte string preceded by its length.
+    pub fn bytes(&mut self) -> Result<&'a [u8], Error> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    /// Return whether all the data was read.
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+/// Converts the encoded state of a major version to the next one.
+pub type MigrationFn = fn(&[u8]) -> Result<Vec<u8>, Error>;
+
+/// Conversions of the encoded states of a device between its major versions.
+#[derive(Clone, Default)]
+pub struct Migrations {
+    steps: Vec<(Version, Version, MigrationFn)>,
+}
+
+impl Migrations {
+    /// Create an empty set of migrations.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a conversion of the states saved at versions compatible with `from` to `to`.
+    pub fn add(mut self, from: Version, to: Version, migrate: MigrationFn) -> Self {
+        self.steps.push((from, to, migrate));
+        self
+    }
+
+    /// Return the versions a state saved at `version` goes through to be restored by a
+    /// device at `current`, starting with `version` itself.
+    pub fn path(&self, version: Version, current: Version) -> Result<Vec<Version>, Error> {
+        let mut path = vec![version];
+        let mut cur = version;
+        while !cur.is_compatible_with(current) {
+            cur = self.step(cur).ok_or(Error::UnsupportedVersion(version))?.1;
+            path.push(cur);
+        }
+        Ok(path)
+    }
+
+    // Return the migration of the states saved at `version` to a newer major version.
+    fn step(&self, version: Version) -> Option<&(Version, Version, MigrationFn)> {
+        self.steps
+            .iter()
+            .find(|(from, to, _)| version.is_compatible_with(*from) && *to > version)
+    }
+
+    // Convert `data`, saved at `version`, to a version compatible with `current`.
+    fn apply(
+        &self,
+        version: Version,
+        current: Version,
+        data: &[u8],
+    ) -> Result<(Version, Vec<u8>), Error> {
+        let mut cur = version;
+        let mut data = data.to_vec();
+        while !cur.is_compatible_with(current) {
+            let (_, to, migrate) = self.step(cur).ok_or(Error::UnsupportedVersion(version))?;
+            data = migrate(&data)?;
+            cur = *to;
+        }
+        Ok((cur, data))
+    }
+}
+
+/// Device states which can be saved and restored across versions of the device.
+pub trait Versioned: Sized {
+    /// Version of the states saved by this build of the device.
+    const VERSION: Version;
+
+    /// Encode the state.
+    fn save(&self, writer: &mut StateWriter);
+
+    /// Decode a state saved at `version`, which is compatible with `VERSION`.
+    fn restore(reader: &mut StateReader<'_>, version: Version) -> Result<Self, Error>;
+
+    /// Return the conversions of the states saved with older major versions.
+    fn migrations() -> Migrations {
+        Migrations::new()
+    }
+}
+
+/// A saved device state, tagged with the ID of the device and the state version.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VersionedState {
+    /// ID of the device.
+    pub id: String,
+    /// Version of the state.
+    pub version: Version,
+    /// Encoded state.
+    pub data: Vec<u8>,
+}
+
+impl VersionedState {
+    /// Save `state` for the device `id`.
+    pub fn save<T: Versioned>(id: &str, state: &T) -> Self {
+        let mut writer = StateWriter::new();
+        state.save(&mut writer);
+        VersionedState {
+            id: id.to_string(),
+            version: T::VERSION,
+            data: writer.into_inner(),
+        }
+    }
+
+    /// Restore the state of the device `id`, migrating it to the current version if needed.
+    pub fn restore<T: Versioned>(&self, id: &str) -> Result<T, Error> {
+        if self.id != id {
+            return Err(Error::IdMismatch(self.id.clone()));
+        }
+        let (version, data) = T::migrations().apply(self.version, T::VERSION, &self.data)?;
+        T::restore(&mut StateReader::new(&data), version)
+    }
+}
+
+/// What a device sees of a completed restore.
+pub struct RestoreContext<'a> {
+    id: &'a str,
+    resources: &'a [(String, Vec<Resource>)],
+}
+
+impl<'a> RestoreContext<'a> {
+    /// Create the context of the device `id`, given the restored resources of all the
+    /// devices, keyed by ID.
+    pub fn new(id: &'a str, resources: &'a [(String, Vec<Resource>)]) -> Self {
+        RestoreContext { id, resources }
+    }
+
+    /// Return the ID of the device.
+    pub fn id(&self) -> &str {
+        self.id
+    }
+
+    /// Return the restored resources of the device.
+    pub fn resources(&self) -> &[Resource] {
+        self.device_resources(self.id).unwrap_or(&[])
+    }
+
+    /// Return the restored resources of the device `id`, if any.
+    pub fn device_resources(&self, id: &str) -> Option<&[Resource]> {
+        self.resources
+            .iter()
+            .find(|(dev, _)| dev == id)
+            .map(|(_, res)| res.as_slice())
+    }
+}
+
+/// Fixups run by a device once all the devices are restored and the buses rebuilt, such as
+/// re-arming timers, registering ioeventfds again, or reconnecting to backends.
+pub trait PostRestore {
+    /// Complete the restore of the device.
+    fn post_restore(&self, ctx: &RestoreContext<'_>) -> io::Result<()>;
+}
+
+impl<F: Fn(&RestoreContext<'_>) -> io::Result<()>> PostRestore for F {
+    fn post_restore(&self, ctx: &RestoreContext<'_>) -> io::Result<()> {
+        self(ctx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Version 1 held the counter as a `u32`, version 2 as a `u64`, and version 2.1 added
+    // the enable flag.
+    #[derive(Debug, PartialEq)]
+    struct CounterState {
+        count: u64,
+        enabled: bool,
+    }
+
+    fn migrate_v1(data: &[u8]) -> Result<Vec<u8>, Error> {
+        let count = StateReader::new(data).u32()?;
+        let mut writer = StateWriter::new();
+        writer.put_u64(u64::from(count));
+        Ok(writer.into_inner())
+    }
+
+    impl Versioned for CounterState {
+        const VERSION: Version = Version::new(2, 1, 0);
+
+        fn save(&self, writer: &mut StateWriter) {
+            writer.put_u64(self.count);
+            writer.put_bool(self.enabled);
+        }
+
+        fn restore(reader: &mut StateReader<'_>, version: Version) -> Result<Self, Error> {
+            let count = reader.u64()?;
+            let enabled = if version.minor >= 1 {
+                reader.bool()?
+            } else {
+                true
+            };
+            Ok(CounterState { count, enabled })
+        }
+
+        fn migrations() -> Migrations {
+            Migrations::new().add(Version::new(1, 0, 0), Version::new(2, 0, 0), migrate_v1)
+        }
+    }
+
+    #[test]
+    fn test_versioned_state() {
+        let state = CounterState {
+            count: 7,
+            enabled: false,
+        };
+        let saved = VersionedState::save("counter", &state);
+        assert_eq!(saved.version, Version::new(2, 1, 0));
+        assert_eq!(saved.restore::<CounterState>("counter").unwrap(), state);
+        assert_eq!(
+            saved.restore::<CounterState>("other"),
+            Err(Error::IdMismatch("counter".to_string()))
+        );
+
+        // A state saved by version 1.
+        let old = VersionedState {
+            id: "counter".to_string(),
+            version: Version::new(1, 0, 3),
+            data: 5u32.to_le_bytes().to_vec(),
+        };
+        assert_eq!(
+            CounterState::migrations()
+                .path(old.version, CounterState::VERSION)
+                .unwrap(),
+            vec![Version::new(1, 0, 3), Version::new(2, 0, 0)]
+        );
+        assert_eq!(
+            old.restore::<CounterState>("counter").unwrap(),
+            CounterState {
+                count: 5,
+                enabled: true
+            }
+        );
+
+        // States from newer versions, or truncated ones, are rejected.
+        let newer = VersionedState {
+            version: Version::new(2, 2, 0),
+            ..saved.clone()
+        };
+        assert_eq!(
+            newer.restore::<CounterState>("counter"),
+            Err(Error::UnsupportedVersion(Version::new(2, 2, 0)))
+        );
+        let truncated = VersionedState {
+            data: saved.data[..4].to_vec(),
+            ..saved
+        };
+        assert_eq!(
+            truncated.restore::<CounterState>("counter"),
+            Err(Error::UnexpectedEnd)
+        );
+    }
+
+    #[test]
+    fn test_post_restore() {
+        use std::sync::{Arc, Mutex};
+
+        use crate::device_manager::{self, IoManager};
+
+        let resources = vec![
+            ("rtc".to_string(), vec![Resource::LegacyIrq(8)]),
+            ("serial0".to_string(), vec![Resource::LegacyIrq(4)]),
+        ];
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut io_mgr = IoManager::new();
+        for id in ["serial0", "rtc"].iter() {
+            let seen = seen.clone();
+            io_mgr.register_post_restore(
+                id,
+                Arc::new(move |ctx: &RestoreContext<'_>| {
+                    seen.lock()
+                        .unwrap()
+                        .push((ctx.id().to_string(), ctx.resources().to_vec()));
+                    Ok(())
+                }),
+            );
+        }
+        io_mgr.complete_restore(&resources).unwrap();
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                ("serial0".to_string(), vec![Resource::LegacyIrq(4)]),
+                ("rtc".to_string(), vec![Resource::LegacyIrq(8)]),
+            ]
+        );
+
+        io_mgr.register_post_restore(
+            "vsock",
+            Arc::new(|_: &RestoreContext<'_>| Err(io::Error::from(io::ErrorKind::NotConnected))),
+        );
+        assert!(matches!(
+            io_mgr.complete_restore(&resources),
+            Err(device_manager::Error::PostRestore(id, _)) if id == "vsock"
+        ));
+    }
+}
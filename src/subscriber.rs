@@ -0,0 +1,181 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Registration of devices which also handle events from an `event_manager::EventManager`,
+//! available with the `event-manager` feature.
+//!
+//! Backend driven devices (e.g. a serial port reading from stdin, or a virtio device with a
+//! tap backend) are accessed by the guest through the buses, and react to their backend
+//! file descriptors through the event manager. The helpers here register such a device with
+//! both at once, and undo both registrations together.
+
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+
+use event_manager::{EventSubscriber, SubscriberId, SubscriberOps};
+
+use crate::bus::{self, BusRange, MmioBusAddress, PioAddress, PioRange};
+use crate::device_manager::{IoManager, MmioManager, PioManager};
+use crate::{DeviceMmio, DevicePio};
+
+/// Subscriber type of the event managers which can be used with the helpers.
+pub type DeviceSubscriber = Arc<dyn EventSubscriber + Send + Sync>;
+
+/// Errors encountered while registering a device with both the buses and the event manager.
+#[derive(Debug)]
+pub enum Error {
+    /// Error during bus operation.
+    Bus(bus::Error),
+    /// Error returned by the event manager.
+    EventManager(event_manager::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Bus(_) => write!(f, "subscriber: bus error"),
+            Error::EventManager(_) => write!(f, "subscriber: event manager error"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Bus(e) => Some(e),
+            Error::EventManager(e) => Some(e),
+        }
+    }
+}
+
+impl<M: MmioBusAddress> IoManager<M> {
+    /// Register `device` with the MMIO `range`, and as a subscriber of `event_manager`,
+    /// which then calls its `init` method so it can add its event sources.
+    pub fn register_mmio_subscriber<T, E>(
+        &mut self,
+        range: BusRange<M>,
+        device: Arc<T>,
+        event_manager: &mut E,
+    ) -> Result<SubscriberId, Error>
+    where
+        T: DeviceMmio + EventSubscriber + Send + Sync + 'static,
+        E: SubscriberOps<Subscriber = DeviceSubscriber>,
+    {
+        self.register_mmio(range, device.clone())
+            .map_err(Error::Bus)?;
+        Ok(event_manager.add_subscriber(device))
+    }
+
+    /// Register `device` with the PIO `range`, and as a subscriber of `event_manager`.
+    pub fn register_pio_subscriber<T, E>(
+        &mut self,
+        range: PioRange,
+        device: Arc<T>,
+        event_manager: &mut E,
+    ) -> Result<SubscriberId, Error>
+    where
+        T: DevicePio + EventSubscriber + Send + Sync + 'static,
+        E: SubscriberOps<Subscriber = DeviceSubscriber>,
+    {
+        self.register_pio(range, device.clone())
+            .map_err(Error::Bus)?;
+        Ok(event_manager.add_subscriber(device))
+    }
+
+    /// Deregister the device registered at the MMIO address `addr`, and remove the
+    /// subscriber `id` from `event_manager`.
+    pub fn deregister_mmio_subscriber<E>(
+        &mut self,
+        addr: M,
+        id: SubscriberId,
+        event_manager: &mut E,
+    ) -> Result<(), Error>
+    where
+        E: SubscriberOps<Subscriber = DeviceSubscriber>,
+    {
+        self.deregister_mmio(addr)
+            .ok_or(Error::Bus(bus::Error::DeviceNotFound))?;
+        event_manager
+            .remove_subscriber(id)
+            .map(|_| ())
+            .map_err(Error::EventManager)
+    }
+
+    /// Deregister the device registered at the PIO address `addr`, and remove the
+    /// subscriber `id` from `event_manager`.
+    pub fn deregister_pio_subscriber<E>(
+        &mut self,
+        addr: PioAddress,
+        id: SubscriberId,
+        event_manager: &mut E,
+    ) -> Result<(), Error>
+    where
+        E: SubscriberOps<Subscriber = DeviceSubscriber>,
+    {
+        self.deregister_pio(addr)
+            .ok_or(Error::Bus(bus::Error::DeviceNotFound))?;
+        event_manager
+            .remove_subscriber(id)
+            .map(|_| ())
+            .map_err(Error::EventManager)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use event_manager::{EventManager, EventOps, Events};
+
+    use crate::bus::PioOffset;
+
+    #[derive(Default)]
+    struct Serial {
+        initialized: AtomicBool,
+    }
+
+    impl DevicePio for Serial {
+        fn pio_read(&self, _base: PioAddress, _offset: PioOffset, data: &mut [u8]) {
+            data[0] = self.initialized.load(Ordering::SeqCst) as u8;
+        }
+
+        fn pio_write(&self, _base: PioAddress, _offset: PioOffset, _data: &[u8]) {}
+    }
+
+    impl EventSubscriber for Serial {
+        fn process(&self, _events: Events, _ops: &mut EventOps) {}
+
+        fn init(&self, _ops: &mut EventOps) {
+            self.initialized.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_register_subscriber() {
+        let mut io_mgr = IoManager::new();
+        let mut event_manager = EventManager::<DeviceSubscriber>::new().unwrap();
+        let range = PioRange::new(PioAddress(0x3f8), 8).unwrap();
+
+        let id = io_mgr
+            .register_pio_subscriber(range, Arc::new(Serial::default()), &mut event_manager)
+            .unwrap();
+        let mut data = [0];
+        io_mgr.pio_read(PioAddress(0x3f8), &mut data).unwrap();
+        assert_eq!(data[0], 1);
+        assert!(matches!(
+            io_mgr.register_pio_subscriber(range, Arc::new(Serial::default()), &mut event_manager),
+            Err(Error::Bus(bus::Error::DeviceOverlap))
+        ));
+
+        io_mgr
+            .deregister_pio_subscriber(PioAddress(0x3f8), id, &mut event_manager)
+            .unwrap();
+        assert!(io_mgr.pio_read(PioAddress(0x3f8), &mut data).is_err());
+        assert!(matches!(
+            event_manager.remove_subscriber(id),
+            Err(event_manager::Error::InvalidId)
+        ));
+    }
+}
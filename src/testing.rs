@@ -0,0 +1,354 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Helpers for testing the device wiring of a VMM, available with the `testing` feature.
+//!
+//! The module provides mock devices which implement both `DevicePio` and `DeviceMmio`, a
+//! generator of pseudo-random disjoint ranges, and assertions which dispatch accesses
+//! through a manager and check their outcome.
+
+use std::sync::Mutex;
+
+use crate::bus::{
+    self, MmioAddress, MmioBusAddress, MmioOffset, MmioRange, PioAddress, PioAddressValue,
+    PioOffset, PioRange,
+};
+use crate::device_manager::{MmioManager, PioManager};
+use crate::{DeviceMmio, DevicePio};
+
+/// Access observed by a mock device.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum MockAccess {
+    /// Read of `len` bytes at `offset`.
+    Read { offset: u64, len: usize },
+    /// Write of `data` at `offset`.
+    Write { offset: u64, data: Vec<u8> },
+}
+
+/// Device which returns the data of the last write on reads, regardless of the offset, and
+/// logs every access it handles.
+#[derive(Default)]
+pub struct EchoDevice {
+    last: Mutex<Vec<u8>>,
+    log: Mutex<Vec<MockAccess>>,
+}
+
+impl EchoDevice {
+    /// Create a device which reads as zeros until it's written to.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Return the accesses handled so far, oldest first.
+    pub fn accesses(&self) -> Vec<MockAccess> {
+        self.log.lock().unwrap().clone()
+    }
+
+    fn read(&self, offset: u64, data: &mut [u8]) {
+        let last = self.last.lock().unwrap();
+        for (idx, byte) in data.iter_mut().enumerate() {
+            *byte = last.get(idx).copied().unwrap_or(0);
+        }
+        self.log.lock().unwrap().push(MockAccess::Read {
+            offset,
+            len: data.len(),
+        });
+    }
+
+    fn write(&self, offset: u64, data: &[u8]) {
+        *self.last.lock().unwrap() = data.to_vec();
+        self.log.lock().unwrap().push(MockAccess::Write {
+            offset,
+            data: data.to_vec(),
+        });
+    }
+}
+
+impl DevicePio for EchoDevice {
+    fn pio_read(&self, _base: PioAddress, offset: PioOffset, data: &mut [u8]) {
+        self.read(u64::from(offset), data)
+    }
+
+    fn pio_write(&self, _base: PioAddress, offset: PioOffset, data: &[u8]) {
+        self.write(u64::from(offset), data)
+    }
+}
+
+impl DeviceMmio for EchoDevice {
+    fn mmio_read(&self, _base: MmioAddress, offset: MmioOffset, data: &mut [u8]) {
+        self.read(offset.raw(), data)
+    }
+
+    fn mmio_write(&self, _base: MmioAddress, offset: MmioOffset, data: &[u8]) {
+        self.write(offset.raw(), data)
+    }
+}
+
+/// Device backed by an array of bytes, which are read and written at the offset of each
+/// access. Bytes past the end of the array read as zeros, and writes to them are dropped.
+pub struct RegisterFileDevice {
+    regs: Mutex<Vec<u8>>,
+}
+
+impl RegisterFileDevice {
+    /// Create a device with `size` zeroed bytes.
+    pub fn new(size: usize) -> Self {
+        RegisterFileDevice {
+            regs: Mutex::new(vec![0; size]),
+        }
+    }
+
+    /// Return the current contents of the device.
+    pub fn contents(&self) -> Vec<u8> {
+        self.regs.lock().unwrap().clone()
+    }
+
+    fn read(&self, offset: u64, data: &mut [u8]) {
+        let regs = self.regs.lock().unwrap();
+        for (idx, byte) in data.iter_mut().enumerate() {
+            *byte = regs.get(offset as usize + idx).copied().unwrap_or(0);
+        }
+    }
+
+    fn write(&self, offset: u64, data: &[u8]) {
+        let mut regs = self.regs.lock().unwrap();
+        for (idx, byte) in data.iter().enumerate() {
+            if let Some(reg) = regs.get_mut(offset as usize + idx) {
+                *reg = *byte;
+            }
+        }
+    }
+}
+
+impl DevicePio for RegisterFileDevice {
+    fn pio_read(&self, _base: PioAddress, offset: PioOffset, data: &mut [u8]) {
+        self.read(u64::from(offset), data)
+    }
+
+    fn pio_write(&self, _base: PioAddress, offset: PioOffset, data: &[u8]) {
+        self.write(u64::from(offset), data)
+    }
+}
+
+impl DeviceMmio for RegisterFileDevice {
+    fn mmio_read(&self, _base: MmioAddress, offset: MmioOffset, data: &mut [u8]) {
+        self.read(offset.raw(), data)
+    }
+
+    fn mmio_write(&self, _base: MmioAddress, offset: MmioOffset, data: &[u8]) {
+        self.write(offset.raw(), data)
+    }
+}
+
+/// Device which panics when accessed, for ranges which must not be reached (e.g. because
+/// a shadow or an overlay is expected to handle the accesses instead).
+pub struct FailingDevice {
+    name: String,
+}
+
+impl FailingDevice {
+    /// Create a device which reports `name` when it panics.
+    pub fn new(name: &str) -> Self {
+        FailingDevice {
+            name: name.to_string(),
+        }
+    }
+}
+
+impl DevicePio for FailingDevice {
+    fn pio_read(&self, base: PioAddress, offset: PioOffset, _data: &mut [u8]) {
+        panic!(
+            "unexpected read of {} at {:?} + {:#x}",
+            self.name, base, offset
+        );
+    }
+
+    fn pio_write(&self, base: PioAddress, offset: PioOffset, _data: &[u8]) {
+        panic!(
+            "unexpected write of {} at {:?} + {:#x}",
+            self.name, base, offset
+        );
+    }
+}
+
+impl DeviceMmio for FailingDevice {
+    fn mmio_read(&self, base: MmioAddress, offset: MmioOffset, _data: &mut [u8]) {
+        panic!(
+            "unexpected read of {} at {:?} + {:#x}",
+            self.name, base, offset
+        );
+    }
+
+    fn mmio_write(&self, base: MmioAddress, offset: MmioOffset, _data: &[u8]) {
+        panic!(
+            "unexpected write of {} at {:?} + {:#x}",
+            self.name, base, offset
+        );
+    }
+}
+
+/// Deterministic generator of pseudo-random ranges, so failing tests can be replayed by
+/// reusing the same seed.
+pub struct RangeGenerator {
+    state: u64,
+}
+
+impl RangeGenerator {
+    /// Create a generator with the specified seed.
+    pub fn new(seed: u64) -> Self {
+        // The xorshift state must not be zero.
+        RangeGenerator { state: seed | 1 }
+    }
+
+    /// Return the next value in `[0, bound)`.
+    pub fn next_below(&mut self, bound: u64) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state % bound.max(1)
+    }
+
+    // Return `count` disjoint `(base, size)` pairs in ascending order, starting at `start`,
+    // with sizes in `[1, max_size]` and gaps of up to `max_size` between them.
+    fn disjoint(&mut self, start: u64, count: usize, max_size: u64) -> Vec<(u64, u64)> {
+        let mut base = start;
+        (0..count)
+            .map(|_| {
+                base += self.next_below(max_size + 1);
+                let size = self.next_below(max_size) + 1;
+                let range = (base, size);
+                base += size;
+                range
+            })
+            .collect()
+    }
+
+    /// Return `count` disjoint MMIO ranges at or above `start`, in ascending order.
+    pub fn mmio_ranges(&mut self, start: u64, count: usize, max_size: u64) -> Vec<MmioRange> {
+        self.disjoint(start, count, max_size)
+            .into_iter()
+            .map(|(base, size)| MmioRange::new(MmioAddress(base), size).unwrap())
+            .collect()
+    }
+
+    /// Return `count` disjoint PIO ranges at or above `start`, in ascending order. The ranges
+    /// must fit in the PIO address space.
+    pub fn pio_ranges(
+        &mut self,
+        start: PioAddressValue,
+        count: usize,
+        max_size: PioAddressValue,
+    ) -> Vec<PioRange> {
+        self.disjoint(u64::from(start), count, u64::from(max_size))
+            .into_iter()
+            .map(|(base, size)| {
+                PioRange::new(PioAddress(base as PioAddressValue), size as PioAddressValue).unwrap()
+            })
+            .collect()
+    }
+}
+
+/// Assert that reading `expected.len()` bytes at `addr` through `manager` succeeds and
+/// returns `expected`.
+pub fn assert_mmio_read<A: MmioBusAddress, M: MmioManager<A>>(
+    manager: &M,
+    addr: A,
+    expected: &[u8],
+) {
+    let mut data = vec![0; expected.len()];
+    manager
+        .mmio_read(addr, &mut data)
+        .unwrap_or_else(|e| panic!("MMIO read at {:?} failed: {}", addr, e));
+    assert_eq!(data, expected, "MMIO read at {:?}", addr);
+}
+
+/// Assert that reading `expected.len()` bytes at `addr` through `manager` succeeds and
+/// returns `expected`.
+pub fn assert_pio_read<M: PioManager>(manager: &M, addr: PioAddress, expected: &[u8]) {
+    let mut data = vec![0; expected.len()];
+    manager
+        .pio_read(addr, &mut data)
+        .unwrap_or_else(|e| panic!("PIO read at {:?} failed: {}", addr, e));
+    assert_eq!(data, expected, "PIO read at {:?}", addr);
+}
+
+/// Assert that an access of `len` bytes at `addr` doesn't reach any device of `manager`.
+pub fn assert_mmio_unmapped<A: MmioBusAddress, M: MmioManager<A>>(
+    manager: &M,
+    addr: A,
+    len: usize,
+) {
+    let mut data = vec![0; len];
+    assert_eq!(
+        manager.mmio_read(addr, &mut data),
+        Err(bus::Error::DeviceNotFound),
+        "MMIO access at {:?}",
+        addr
+    );
+}
+
+/// Assert that an access of `len` bytes at `addr` doesn't reach any device of `manager`.
+pub fn assert_pio_unmapped<M: PioManager>(manager: &M, addr: PioAddress, len: usize) {
+    let mut data = vec![0; len];
+    assert_eq!(
+        manager.pio_read(addr, &mut data),
+        Err(bus::Error::DeviceNotFound),
+        "PIO access at {:?}",
+        addr
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::panic::AssertUnwindSafe;
+    use std::sync::Arc;
+
+    use crate::device_manager::IoManager;
+
+    #[test]
+    fn test_mock_devices() {
+        let mut io_mgr = IoManager::new();
+        let mut gen = RangeGenerator::new(0x5eed);
+        let ranges = gen.mmio_ranges(0x1000, 3, 0x100);
+        assert!(ranges.windows(2).all(|w| w[0].last() < w[1].base()));
+
+        let echo = Arc::new(EchoDevice::new());
+        let regs = Arc::new(RegisterFileDevice::new(8));
+        io_mgr.register_mmio(ranges[0], echo.clone()).unwrap();
+        io_mgr.register_mmio(ranges[1], regs.clone()).unwrap();
+        io_mgr
+            .register_mmio(ranges[2], Arc::new(FailingDevice::new("failing")))
+            .unwrap();
+
+        io_mgr.mmio_write(ranges[0].base(), &[1, 2]).unwrap();
+        assert_mmio_read(&io_mgr, ranges[0].base(), &[1, 2, 0, 0]);
+        assert_eq!(
+            echo.accesses(),
+            vec![
+                MockAccess::Write {
+                    offset: 0,
+                    data: vec![1, 2]
+                },
+                MockAccess::Read { offset: 0, len: 4 }
+            ]
+        );
+
+        let addr = MmioAddress(ranges[1].base().0 + 6);
+        io_mgr.mmio_write(addr, &[0xaa, 0xbb, 0xcc]).unwrap();
+        assert_mmio_read(&io_mgr, addr, &[0xaa, 0xbb]);
+        assert_eq!(regs.contents()[6..], [0xaa, 0xbb]);
+
+        assert_mmio_unmapped(&io_mgr, MmioAddress(ranges[1].last().0 + 1), 1);
+        let res = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            assert_mmio_read(&io_mgr, ranges[2].base(), &[0])
+        }));
+        assert!(res.is_err());
+
+        let pio = gen.pio_ranges(0x100, 2, 0x10);
+        io_mgr.register_pio(pio[0], echo).unwrap();
+        assert_pio_read(&io_mgr, pio[0].base(), &[1]);
+        assert_pio_unmapped(&io_mgr, pio[1].base(), 1);
+    }
+}
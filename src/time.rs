@@ -0,0 +1,299 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Time sources consumed by the timer device models.
+//!
+//! Devices read the time through the [`Clock`](trait.Clock.html) trait. A
+//! [`VirtualClock`](trait.VirtualClock.html) additionally lets the VMM pause and resume
+//! the guest view of time (e.g. around a snapshot), scale it, move it after a restore, and
+//! schedule deadline timers. Since every `VirtualClock` is a `Clock`, devices driven by one
+//! follow these adjustments without any changes.
+
+use std::collections::BTreeMap;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// A monotonic source of time, expressed in nanoseconds.
+pub trait Clock: Send + Sync {
+    /// Return the current time in nanoseconds.
+    fn now(&self) -> u64;
+}
+
+impl<T: Clock + ?Sized> Clock for Arc<T> {
+    fn now(&self) -> u64 {
+        self.deref().now()
+    }
+}
+
+/// A `Clock` backed by the monotonic clock of the host, counting from its creation.
+pub struct HostClock {
+    start: Instant,
+}
+
+impl HostClock {
+    /// Create a new clock which starts counting from zero.
+    pub fn new() -> Self {
+        HostClock {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for HostClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for HostClock {
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_nanos() as u64
+    }
+}
+
+/// A `Clock` which only moves when explicitly advanced, useful for deterministic tests.
+#[derive(Default)]
+pub struct ManualClock {
+    now: AtomicU64,
+}
+
+impl ManualClock {
+    /// Create a new clock which starts at zero.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Move the clock forward by `ns` nanoseconds.
+    pub fn advance(&self, ns: u64) {
+        self.now.fetch_add(ns, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> u64 {
+        self.now.load(Ordering::SeqCst)
+    }
+}
+
+/// Identifies a deadline timer scheduled with a `VirtualClock`.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct TimerId(u64);
+
+/// Callback invoked when a deadline timer expires.
+pub type TimerCallback = Box<dyn FnOnce() + Send>;
+
+/// A guest view of time, which can be adjusted independently of the host clock.
+pub trait VirtualClock: Clock {
+    /// Stop the clock. `now` keeps returning the same value until `resume` is called.
+    fn pause(&self);
+
+    /// Let the clock run again, from the value it had when it was paused.
+    fn resume(&self);
+
+    /// Return `true` if the clock is paused.
+    fn is_paused(&self) -> bool;
+
+    /// Make the clock run at `num / den` times the speed of its time source.
+    fn set_scale(&self, num: u32, den: u32);
+
+    /// Set the current time to `now` nanoseconds, for example to account for the time the
+    /// guest spent saved before a restore. Pending deadlines are left untouched.
+    fn set_now(&self, now: u64);
+
+    /// Schedule `callback` to run once the clock reaches `deadline`.
+    fn add_timer(&self, deadline: u64, callback: TimerCallback) -> TimerId;
+
+    /// Cancel a timer. Returns `false` if the timer has already expired or was cancelled.
+    fn cancel_timer(&self, id: TimerId) -> bool;
+
+    /// Return the earliest deadline of the pending timers.
+    fn next_deadline(&self) -> Option<u64>;
+
+    /// Run the callbacks of the timers which expired, and return their number.
+    fn run_expired(&self) -> usize;
+}
+
+struct ScaledClockState {
+    // Value of the clock at `source_ref`.
+    offset: u64,
+    source_ref: u64,
+    num: u32,
+    den: u32,
+    paused: bool,
+}
+
+/// A `VirtualClock` derived from another `Clock`, which is the host clock by default.
+pub struct ScaledClock<C: Clock = HostClock> {
+    source: C,
+    state: Mutex<ScaledClockState>,
+    timers: Mutex<BTreeMap<(u64, TimerId), TimerCallback>>,
+    next_id: AtomicU64,
+}
+
+impl<C: Clock> ScaledClock<C> {
+    /// Create a running clock which starts at zero and follows `source` at the same speed.
+    pub fn new(source: C) -> Self {
+        let source_ref = source.now();
+        ScaledClock {
+            source,
+            state: Mutex::new(ScaledClockState {
+                offset: 0,
+                source_ref,
+                num: 1,
+                den: 1,
+                paused: false,
+            }),
+            timers: Mutex::new(BTreeMap::new()),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    fn now_locked(&self, state: &ScaledClockState) -> u64 {
+        if state.paused {
+            return state.offset;
+        }
+        let elapsed = u128::from(self.source.now().saturating_sub(state.source_ref));
+        let scaled = elapsed * u128::from(state.num) / u128::from(state.den);
+        state.offset.wrapping_add(scaled as u64)
+    }
+
+    // Fold the time elapsed so far into the offset, before changing how the clock runs.
+    fn rebase(&self, state: &mut ScaledClockState) {
+        state.offset = self.now_locked(state);
+        state.source_ref = self.source.now();
+    }
+}
+
+impl<C: Clock> Clock for ScaledClock<C> {
+    fn now(&self) -> u64 {
+        self.now_locked(&self.state.lock().unwrap())
+    }
+}
+
+impl<C: Clock> VirtualClock for ScaledClock<C> {
+    fn pause(&self) {
+        let mut state = self.state.lock().unwrap();
+        self.rebase(&mut state);
+        state.paused = true;
+    }
+
+    fn resume(&self) {
+        let mut state = self.state.lock().unwrap();
+        self.rebase(&mut state);
+        state.paused = false;
+    }
+
+    fn is_paused(&self) -> bool {
+        self.state.lock().unwrap().paused
+    }
+
+    fn set_scale(&self, num: u32, den: u32) {
+        let mut state = self.state.lock().unwrap();
+        self.rebase(&mut state);
+        state.num = num;
+        state.den = den.max(1);
+    }
+
+    fn set_now(&self, now: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.offset = now;
+        state.source_ref = self.source.now();
+    }
+
+    fn add_timer(&self, deadline: u64, callback: TimerCallback) -> TimerId {
+        let id = TimerId(self.next_id.fetch_add(1, Ordering::SeqCst));
+        self.timers.lock().unwrap().insert((deadline, id), callback);
+        id
+    }
+
+    fn cancel_timer(&self, id: TimerId) -> bool {
+        let mut timers = self.timers.lock().unwrap();
+        let key = timers.keys().find(|(_, timer)| *timer == id).copied();
+        key.and_then(|key| timers.remove(&key)).is_some()
+    }
+
+    fn next_deadline(&self) -> Option<u64> {
+        self.timers
+            .lock()
+            .unwrap()
+            .keys()
+            .next()
+            .map(|(deadline, _)| *deadline)
+    }
+
+    fn run_expired(&self) -> usize {
+        let now = self.now();
+        let expired = {
+            let mut timers = self.timers.lock().unwrap();
+            let pending = timers.split_off(&(now.saturating_add(1), TimerId(0)));
+            std::mem::replace(&mut *timers, pending)
+        };
+
+        // The callbacks run without holding the lock, so they can schedule new timers.
+        let count = expired.len();
+        expired.into_iter().for_each(|(_, callback)| callback());
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scaled_clock() {
+        let source = Arc::new(ManualClock::new());
+        let clock = ScaledClock::new(source.clone());
+
+        source.advance(100);
+        assert_eq!(clock.now(), 100);
+
+        clock.pause();
+        assert!(clock.is_paused());
+        source.advance(100);
+        assert_eq!(clock.now(), 100);
+        clock.resume();
+        source.advance(50);
+        assert_eq!(clock.now(), 150);
+
+        // Run at half speed.
+        clock.set_scale(1, 2);
+        source.advance(100);
+        assert_eq!(clock.now(), 200);
+
+        clock.set_now(1000);
+        assert_eq!(clock.now(), 1000);
+    }
+
+    #[test]
+    fn test_deadline_timers() {
+        let source = Arc::new(ManualClock::new());
+        let clock = Arc::new(ScaledClock::new(source.clone()));
+        let fired = Arc::new(Mutex::new(Vec::new()));
+
+        for deadline in [30, 10, 20].iter() {
+            let fired = fired.clone();
+            let deadline = *deadline;
+            clock.add_timer(
+                deadline,
+                Box::new(move || fired.lock().unwrap().push(deadline)),
+            );
+        }
+        let cancelled = clock.add_timer(15, Box::new(|| panic!("cancelled timer fired")));
+        assert!(clock.cancel_timer(cancelled));
+        assert!(!clock.cancel_timer(cancelled));
+        assert_eq!(clock.next_deadline(), Some(10));
+
+        source.advance(20);
+        assert_eq!(clock.run_expired(), 2);
+        assert_eq!(*fired.lock().unwrap(), vec![10, 20]);
+        assert_eq!(clock.next_deadline(), Some(30));
+
+        // Time doesn't move while the clock is paused.
+        clock.pause();
+        source.advance(20);
+        assert_eq!(clock.run_expired(), 0);
+    }
+}
@@ -0,0 +1,197 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Groups of device map changes which are applied atomically.
+//!
+//! Reconfigurations triggered by the guest, such as rebalancing the PCI BARs, may consist of
+//! several register, deregister, and relocate operations. Running them through
+//! [`IoManager::transaction`](../device_manager/struct.IoManager.html#method.transaction)
+//! ensures that either all of them take effect, or the device map is left unchanged when one
+//! of them fails. The transaction holds the manager mutably for its whole duration, so the
+//! intermediate states are never visible to the vCPUs dispatching accesses.
+
+use std::sync::Arc;
+
+use crate::bus::{self, BusRange, MmioBusAddress, PioAddress, PioRange};
+use crate::device_manager::{Error, IoManager, MmioManager, PioManager};
+use crate::{DeviceMmio, DevicePio};
+
+// Operation which reverts a change applied by a transaction.
+enum Undo<M: MmioBusAddress> {
+    RegisterPio(PioRange, Arc<dyn DevicePio + Send + Sync>),
+    DeregisterPio(PioAddress),
+    RegisterMmio(BusRange<M>, Arc<dyn DeviceMmio + Send + Sync>),
+    DeregisterMmio(M),
+}
+
+/// Changes to the device map of an `IoManager` which are in progress. The changes are
+/// applied as they are made, and reverted in reverse order if the transaction fails.
+pub struct IoTransaction<'a, M: MmioBusAddress> {
+    manager: &'a mut IoManager<M>,
+    undo: Vec<Undo<M>>,
+}
+
+impl<M: MmioBusAddress> IoTransaction<'_, M> {
+    /// Register a PIO device with the provided range.
+    pub fn register_pio(
+        &mut self,
+        range: PioRange,
+        device: Arc<dyn DevicePio + Send + Sync>,
+    ) -> Result<(), Error> {
+        self.manager
+            .register_pio(range, device)
+            .map_err(Error::Bus)?;
+        self.undo.push(Undo::DeregisterPio(range.base()));
+        Ok(())
+    }
+
+    /// Deregister the PIO device registered at `addr`, and return it with its range.
+    pub fn deregister_pio(
+        &mut self,
+        addr: PioAddress,
+    ) -> Result<(PioRange, Arc<dyn DevicePio + Send + Sync>), Error> {
+        let (range, device) = self
+            .manager
+            .deregister_pio(addr)
+            .ok_or(Error::Bus(bus::Error::DeviceNotFound))?;
+        self.undo.push(Undo::RegisterPio(range, device.clone()));
+        Ok((range, device))
+    }
+
+    /// Move the PIO device registered at `addr` to `range`.
+    pub fn relocate_pio(&mut self, addr: PioAddress, range: PioRange) -> Result<(), Error> {
+        let (_, device) = self.deregister_pio(addr)?;
+        self.register_pio(range, device)
+    }
+
+    /// Register a MMIO device with the provided range.
+    pub fn register_mmio(
+        &mut self,
+        range: BusRange<M>,
+        device: Arc<dyn DeviceMmio + Send + Sync>,
+    ) -> Result<(), Error> {
+        self.manager
+            .register_mmio(range, device)
+            .map_err(Error::Bus)?;
+        self.undo.push(Undo::DeregisterMmio(range.base()));
+        Ok(())
+    }
+
+    /// Deregister the MMIO device registered at `addr`, and return it with its range.
+    pub fn deregister_mmio(
+        &mut self,
+        addr: M,
+    ) -> Result<(BusRange<M>, Arc<dyn DeviceMmio + Send + Sync>), Error> {
+        let (range, device) = self
+            .manager
+            .deregister_mmio(addr)
+            .ok_or(Error::Bus(bus::Error::DeviceNotFound))?;
+        self.undo.push(Undo::RegisterMmio(range, device.clone()));
+        Ok((range, device))
+    }
+
+    /// Move the MMIO device registered at `addr` to `range`.
+    pub fn relocate_mmio(&mut self, addr: M, range: BusRange<M>) -> Result<(), Error> {
+        let (_, device) = self.deregister_mmio(addr)?;
+        self.register_mmio(range, device)
+    }
+
+    // Revert the changes applied so far, most recent first. Each step restores the state
+    // seen right before the matching change, so it cannot fail.
+    fn rollback(&mut self) {
+        while let Some(op) = self.undo.pop() {
+            match op {
+                Undo::RegisterPio(range, device) => {
+                    self.manager.register_pio(range, device).unwrap();
+                }
+                Undo::DeregisterPio(addr) => {
+                    self.manager.deregister_pio(addr).unwrap();
+                }
+                Undo::RegisterMmio(range, device) => {
+                    self.manager.register_mmio(range, device).unwrap();
+                }
+                Undo::DeregisterMmio(addr) => {
+                    self.manager.deregister_mmio(addr).unwrap();
+                }
+            }
+        }
+    }
+}
+
+impl<M: MmioBusAddress> IoManager<M> {
+    /// Run `f` as a transaction over the device map. The changes made through the
+    /// `IoTransaction` are kept when `f` succeeds, and rolled back when it returns an error.
+    pub fn transaction<F, R>(&mut self, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(&mut IoTransaction<'_, M>) -> Result<R, Error>,
+    {
+        let mut txn = IoTransaction {
+            manager: self,
+            undo: Vec::new(),
+        };
+        let result = f(&mut txn);
+        if result.is_err() {
+            txn.rollback();
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::bus::{MmioAddress, MmioOffset, MmioRange, PioOffset};
+    use crate::device_manager::IoLayout;
+
+    struct Dummy;
+
+    impl DevicePio for Dummy {
+        fn pio_read(&self, _base: PioAddress, _offset: PioOffset, _data: &mut [u8]) {}
+        fn pio_write(&self, _base: PioAddress, _offset: PioOffset, _data: &[u8]) {}
+    }
+
+    impl DeviceMmio for Dummy {
+        fn mmio_read(&self, _base: MmioAddress, _offset: MmioOffset, _data: &mut [u8]) {}
+        fn mmio_write(&self, _base: MmioAddress, _offset: MmioOffset, _data: &[u8]) {}
+    }
+
+    fn ranges(layout: &IoLayout) -> (Vec<PioRange>, Vec<MmioRange>) {
+        (
+            layout.pio.iter().map(|e| e.range).collect(),
+            layout.mmio.iter().map(|e| e.range).collect(),
+        )
+    }
+
+    #[test]
+    fn test_transaction() {
+        let mut io_mgr = IoManager::new();
+        let dev = Arc::new(Dummy);
+        let mmio_a = MmioRange::new(MmioAddress(0x1000), 0x1000).unwrap();
+        let mmio_b = MmioRange::new(MmioAddress(0x2000), 0x1000).unwrap();
+        let pio = PioRange::new(PioAddress(0x100), 0x10).unwrap();
+        io_mgr.register_mmio(mmio_a, dev.clone()).unwrap();
+        io_mgr.register_mmio(mmio_b, dev.clone()).unwrap();
+
+        // Swapping two BARs goes through a state where both would overlap if the moves were
+        // applied in isolation; the failure rolls everything back.
+        let before = ranges(&io_mgr.layout());
+        let res = io_mgr.transaction(|txn| {
+            txn.register_pio(pio, dev.clone())?;
+            txn.relocate_mmio(MmioAddress(0x1000), mmio_b)?;
+            Ok(())
+        });
+        assert!(matches!(res, Err(Error::Bus(bus::Error::DeviceOverlap))));
+        assert_eq!(ranges(&io_mgr.layout()), before);
+
+        io_mgr
+            .transaction(|txn| {
+                let (_, a) = txn.deregister_mmio(MmioAddress(0x1000))?;
+                txn.relocate_mmio(MmioAddress(0x2000), mmio_a)?;
+                txn.register_mmio(mmio_b, a)?;
+                txn.register_pio(pio, dev.clone())
+            })
+            .unwrap();
+        assert_eq!(ranges(&io_mgr.layout()), (vec![pio], vec![mmio_a, mmio_b]));
+    }
+}
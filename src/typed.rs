@@ -0,0 +1,257 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Device manager dispatching statically to a closed set of devices.
+//!
+//! The [`IoManager`](../device_manager/struct.IoManager.html) holds its devices as trait
+//! objects behind an `Arc`, so any device can be registered, at the cost of an indirect call
+//! for every access. VMMs emulating a fixed set of device models can use a
+//! [`TypedIoManager`](struct.TypedIoManager.html) instead, whose device type is an enum of
+//! those models: the buses store the enum inline, and the accesses are dispatched with a
+//! `match` the compiler can see through. With the `derive` feature, `#[derive(DeviceEnum)]`
+//! implements the device traits of the enum by forwarding them to its variants.
+//!
+//! The manager implements `PioManager` and `MmioManager` through `BusManager`, like the
+//! `IoManager`, so both can be used by the same code.
+
+use crate::bus::{BusManager, MmioAddress, MmioBus, PioAddress, PioBus};
+use crate::events::VmEventSender;
+
+/// Manages the PIO and MMIO devices of type `D`, which is usually an enum of the device
+/// models of the VMM (see the module documentation).
+pub struct TypedIoManager<D> {
+    pio_bus: PioBus<D>,
+    mmio_bus: MmioBus<D>,
+    events: Option<VmEventSender>,
+}
+
+impl<D> Default for TypedIoManager<D> {
+    fn default() -> Self {
+        TypedIoManager {
+            pio_bus: PioBus::new(),
+            mmio_bus: MmioBus::new(),
+            events: None,
+        }
+    }
+}
+
+impl<D> TypedIoManager<D> {
+    /// Create a manager without any device.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the channel used to notify the VMM when a range is quarantined because its
+    /// device keeps failing accesses.
+    pub fn set_event_sender(&mut self, sender: VmEventSender) {
+        self.events = Some(sender);
+    }
+}
+
+// Enables the automatic implementation of `PioManager` for `TypedIoManager`.
+impl<D> BusManager<PioAddress> for TypedIoManager<D> {
+    type D = D;
+
+    fn bus(&self) -> &PioBus<D> {
+        &self.pio_bus
+    }
+
+    fn bus_mut(&mut self) -> &mut PioBus<D> {
+        &mut self.pio_bus
+    }
+
+    fn events(&self) -> Option<&VmEventSender> {
+        self.events.as_ref()
+    }
+}
+
+// Enables the automatic implementation of `MmioManager` for `TypedIoManager`.
+impl<D> BusManager<MmioAddress> for TypedIoManager<D> {
+    type D = D;
+
+    fn bus(&self) -> &MmioBus<D> {
+        &self.mmio_bus
+    }
+
+    fn bus_mut(&mut self) -> &mut MmioBus<D> {
+        &mut self.mmio_bus
+    }
+
+    fn events(&self) -> Option<&VmEventSender> {
+        self.events.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::bus::{MmioOffset, MmioRange, PioOffset, PioRange};
+    use crate::device_manager::{MmioManager, PioManager};
+    use crate::testing::EchoDevice;
+    use crate::{DeviceMmio, DevicePio};
+
+    // Port which always reads as the same value.
+    #[derive(Clone, Copy)]
+    struct Constant(u8);
+
+    impl DevicePio for Constant {
+        fn pio_read(&self, _base: PioAddress, _offset: PioOffset, data: &mut [u8]) {
+            data.fill(self.0);
+        }
+
+        fn pio_write(&self, _base: PioAddress, _offset: PioOffset, _data: &[u8]) {}
+    }
+
+    // Device enum with hand written dispatch, as `#[derive(DeviceEnum)]` would generate it.
+    #[derive(Clone)]
+    enum Manual {
+        Constant(Constant),
+        Echo(Arc<EchoDevice>),
+    }
+
+    impl DevicePio for Manual {
+        fn pio_read(&self, base: PioAddress, offset: PioOffset, data: &mut [u8]) {
+            match self {
+                Manual::Constant(dev) => dev.pio_read(base, offset, data),
+                Manual::Echo(dev) => dev.pio_read(base, offset, data),
+            }
+        }
+
+        fn pio_write(&self, base: PioAddress, offset: PioOffset, data: &[u8]) {
+            match self {
+                Manual::Constant(dev) => dev.pio_write(base, offset, data),
+                Manual::Echo(dev) => dev.pio_write(base, offset, data),
+            }
+        }
+    }
+
+    impl DeviceMmio for Manual {
+        fn mmio_read(&self, base: MmioAddress, offset: MmioOffset, data: &mut [u8]) {
+            match self {
+                Manual::Constant(_) => data.fill(0xff),
+                Manual::Echo(dev) => dev.mmio_read(base, offset, data),
+            }
+        }
+
+        fn mmio_write(&self, base: MmioAddress, offset: MmioOffset, data: &[u8]) {
+            if let Manual::Echo(dev) = self {
+                dev.mmio_write(base, offset, data);
+            }
+        }
+    }
+
+    #[test]
+    fn test_typed_io_manager_manual() {
+        let mut io_mgr = TypedIoManager::new();
+        let echo = Arc::new(EchoDevice::new());
+        io_mgr
+            .register_pio(
+                PioRange::new(PioAddress(0x80), 1).unwrap(),
+                Manual::Constant(Constant(0x5a)),
+            )
+            .unwrap();
+        io_mgr
+            .register_mmio(
+                MmioRange::new(MmioAddress(0xd000_0000), 0x1000).unwrap(),
+                Manual::Echo(echo),
+            )
+            .unwrap();
+        assert!(io_mgr
+            .register_pio(
+                PioRange::new(PioAddress(0x80), 1).unwrap(),
+                Manual::Constant(Constant(0)),
+            )
+            .is_err());
+
+        let mut data = [0; 2];
+        io_mgr.pio_read(PioAddress(0x80), &mut data[..1]).unwrap();
+        assert_eq!(data[0], 0x5a);
+        io_mgr
+            .mmio_write(MmioAddress(0xd000_0010), &[3, 4])
+            .unwrap();
+        io_mgr
+            .mmio_read(MmioAddress(0xd000_0010), &mut data)
+            .unwrap();
+        assert_eq!(data, [3, 4]);
+        assert!(io_mgr.pio_read(PioAddress(0x81), &mut data).is_err());
+
+        // Both buses report the quarantined ranges through the same channel.
+        let (tx, _rx) = crate::events::vm_event_channel();
+        io_mgr.set_event_sender(tx.for_device("typed"));
+        assert!(BusManager::<PioAddress>::events(&io_mgr).is_some());
+        assert!(BusManager::<MmioAddress>::events(&io_mgr).is_some());
+        assert!(matches!(
+            io_mgr.deregister_mmio(MmioAddress(0xd000_0000)),
+            Some((_, Manual::Echo(_)))
+        ));
+    }
+
+    #[cfg(feature = "derive")]
+    #[derive(Clone, crate::DeviceEnum)]
+    enum Device {
+        #[device(pio)]
+        Constant(Constant),
+        #[device(pio, mmio)]
+        Echo(Arc<EchoDevice>),
+    }
+
+    #[cfg(feature = "derive")]
+    #[test]
+    fn test_typed_io_manager() {
+        let mut io_mgr = TypedIoManager::new();
+        let echo = Arc::new(EchoDevice::new());
+        io_mgr
+            .register_pio(
+                PioRange::new(PioAddress(0x80), 1).unwrap(),
+                Device::Constant(Constant(0x5a)),
+            )
+            .unwrap();
+        io_mgr
+            .register_pio(
+                PioRange::new(PioAddress(0x3f8), 8).unwrap(),
+                Device::Echo(echo.clone()),
+            )
+            .unwrap();
+        io_mgr
+            .register_mmio(
+                MmioRange::new(MmioAddress(0xd000_0000), 0x1000).unwrap(),
+                Device::Echo(echo),
+            )
+            .unwrap();
+
+        let mut data = [0; 2];
+        io_mgr.pio_read(PioAddress(0x80), &mut data[..1]).unwrap();
+        assert_eq!(data[0], 0x5a);
+        io_mgr.pio_write(PioAddress(0x3f8), &[1, 2]).unwrap();
+        io_mgr.pio_read(PioAddress(0x3f8), &mut data).unwrap();
+        assert_eq!(data, [1, 2]);
+        io_mgr
+            .mmio_write(MmioAddress(0xd000_0010), &[3, 4])
+            .unwrap();
+        io_mgr
+            .mmio_read(MmioAddress(0xd000_0010), &mut data)
+            .unwrap();
+        assert_eq!(data, [3, 4]);
+
+        // A variant which isn't a MMIO device reads as all ones if it ends up on the bus.
+        io_mgr
+            .register_mmio(
+                MmioRange::new(MmioAddress(0xe000_0000), 0x10).unwrap(),
+                Device::Constant(Constant(0)),
+            )
+            .unwrap();
+        io_mgr
+            .mmio_read(MmioAddress(0xe000_0000), &mut data)
+            .unwrap();
+        assert_eq!(data, [0xff, 0xff]);
+        let dev = io_mgr.mmio_device(MmioAddress(0xe000_0000)).unwrap().1;
+        dev.mmio_write(MmioAddress(0), MmioOffset(0), &[0]);
+        assert!(matches!(
+            io_mgr.deregister_pio(PioAddress(0x80)),
+            Some((_, Device::Constant(Constant(0x5a))))
+        ));
+    }
+}
@@ -0,0 +1,860 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Device assignment through VFIO.
+//!
+//! A [`VfioDevice`](struct.VfioDevice.html) wraps the file descriptor of a device, as
+//! returned by `VFIO_GROUP_GET_DEVICE_FD`. Setting up the container and the group, and
+//! mapping the guest memory in the IOMMU, is left to the VMM. The regions of the device are
+//! exposed to the guest through [`VfioRegion`](struct.VfioRegion.html), which forwards the
+//! accesses to the kernel.
+//!
+//! A [`VfioPciDevice`](struct.VfioPciDevice.html) plugs an assigned PCI function into a
+//! `PciBus`. Its BARs are discovered from the region info of the device, and are emulated
+//! so they can be placed anywhere in the guest address space, while the rest of the
+//! configuration space is forwarded to the kernel. The MSI-X table is emulated by a
+//! `MsixTable`, which fires the vectors through the `MsiSender`s of the VMM, and the legacy
+//! interrupt drives a `LineInterrupt`. The kernel signals the interrupts of the device through
+//! eventfds, which the VMM polls and passes to the `handle_*_event` methods.
+
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io;
+use std::mem::size_of;
+use std::os::raw::c_ulong;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Arc;
+
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+use vmm_sys_util::ioctl::{ioctl, ioctl_expr, ioctl_with_mut_ref, ioctl_with_ptr, _IOC_NONE};
+
+use crate::bus::{
+    MmioAddress, MmioOffset, MmioRange, PioAddress, PioAddressValue, PioOffset, PioRange,
+};
+use crate::device_manager::{self, IoManager, MmioManager, PioManager};
+use crate::interrupt::LineInterrupt;
+use crate::mappable::Mappable;
+use crate::msi::MsiSender;
+use crate::pci::{
+    self, BarReprogrammingParams, MsixCap, MsixPba, MsixTable, PciBarConfiguration,
+    PciBarRegionType, PciClassCode, PciConfiguration, PciDevice, NUM_BAR_REGS,
+};
+use crate::{DeviceMmio, DevicePio};
+
+/// Region index of the first BAR of a PCI device.
+pub const VFIO_PCI_BAR0_REGION_INDEX: u32 = 0;
+/// Region index of the configuration space of a PCI device.
+pub const VFIO_PCI_CONFIG_REGION_INDEX: u32 = 7;
+/// IRQ index of the legacy interrupt of a PCI device.
+pub const VFIO_PCI_INTX_IRQ_INDEX: u32 = 0;
+/// IRQ index of the MSIs of a PCI device.
+pub const VFIO_PCI_MSI_IRQ_INDEX: u32 = 1;
+/// IRQ index of the MSI-X vectors of a PCI device.
+pub const VFIO_PCI_MSIX_IRQ_INDEX: u32 = 2;
+
+const VFIO_TYPE: u32 = b';' as u32;
+const VFIO_BASE: u32 = 100;
+const VFIO_DEVICE_GET_INFO: u32 = VFIO_BASE + 7;
+const VFIO_DEVICE_GET_REGION_INFO: u32 = VFIO_BASE + 8;
+const VFIO_DEVICE_GET_IRQ_INFO: u32 = VFIO_BASE + 9;
+const VFIO_DEVICE_SET_IRQS: u32 = VFIO_BASE + 10;
+const VFIO_DEVICE_RESET: u32 = VFIO_BASE + 11;
+
+const VFIO_REGION_INFO_FLAG_READ: u32 = 1;
+const VFIO_REGION_INFO_FLAG_WRITE: u32 = 2;
+const VFIO_REGION_INFO_FLAG_MMAP: u32 = 4;
+
+const VFIO_IRQ_SET_DATA_NONE: u32 = 1;
+const VFIO_IRQ_SET_DATA_EVENTFD: u32 = 4;
+const VFIO_IRQ_SET_ACTION_UNMASK: u32 = 16;
+const VFIO_IRQ_SET_ACTION_TRIGGER: u32 = 32;
+
+const PCI_STATUS_CAP_LIST: u32 = 0x0010_0000;
+const PCI_CAPABILITY_LIST: u64 = 0x34;
+const PCI_CAP_ID_MSIX: u8 = 0x11;
+const BAR0_REG: usize = 4;
+const ROM_BAR_REG: usize = 12;
+
+// Layouts of the structures exchanged with the VFIO ioctls.
+#[repr(C)]
+#[derive(Default)]
+struct DeviceInfo {
+    argsz: u32,
+    flags: u32,
+    num_regions: u32,
+    num_irqs: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct RegionInfo {
+    argsz: u32,
+    flags: u32,
+    index: u32,
+    cap_offset: u32,
+    size: u64,
+    offset: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct IrqInfo {
+    argsz: u32,
+    flags: u32,
+    index: u32,
+    count: u32,
+}
+
+// Number of 32-bit fields of `vfio_irq_set`, before its data.
+const IRQ_SET_HEADER_LEN: usize = 5;
+
+fn vfio_ioctl(nr: u32) -> c_ulong {
+    ioctl_expr(_IOC_NONE, VFIO_TYPE, nr, 0)
+}
+
+fn ioctl_result(ret: i32) -> io::Result<()> {
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Errors encountered while setting up assigned devices.
+#[derive(Debug)]
+pub enum Error {
+    /// No address was provided for the BAR.
+    BarAddress(usize),
+    /// A VFIO ioctl, or an access to a region of the device, failed.
+    Io(io::Error),
+    /// The device has no configuration space region.
+    NoConfigRegion,
+    /// Failed to set up the emulated configuration space.
+    Pci(pci::Error),
+    /// Failed to register the BARs.
+    Register(device_manager::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::BarAddress(idx) => write!(f, "no address for BAR {}", idx),
+            Error::Io(_) => write!(f, "VFIO device access failed"),
+            Error::NoConfigRegion => write!(f, "no PCI configuration space region"),
+            Error::Pci(_) => write!(f, "invalid PCI configuration"),
+            Error::Register(_) => write!(f, "failed to register the BARs"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Pci(e) => Some(e),
+            Error::Register(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Description of a region of an assigned device.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct VfioRegionInfo {
+    /// Index of the region.
+    pub index: u32,
+    /// `VFIO_REGION_INFO_FLAG_*` flags of the region.
+    pub flags: u32,
+    /// Size of the region.
+    pub size: u64,
+    /// Offset of the region within the device file.
+    pub offset: u64,
+}
+
+impl VfioRegionInfo {
+    /// Return whether the region can be read.
+    pub fn readable(&self) -> bool {
+        self.flags & VFIO_REGION_INFO_FLAG_READ != 0
+    }
+
+    /// Return whether the region can be written.
+    pub fn writable(&self) -> bool {
+        self.flags & VFIO_REGION_INFO_FLAG_WRITE != 0
+    }
+
+    /// Return whether the region can be mapped in the guest address space.
+    pub fn mappable(&self) -> bool {
+        self.flags & VFIO_REGION_INFO_FLAG_MMAP != 0
+    }
+}
+
+/// An assigned device, opened through its VFIO group.
+pub struct VfioDevice {
+    file: File,
+    regions: Vec<VfioRegionInfo>,
+    irqs: Vec<u32>,
+}
+
+impl VfioDevice {
+    /// Wrap the device file `file`, and query the description of its regions and IRQs.
+    pub fn new(file: File) -> Result<Self, Error> {
+        let mut info = DeviceInfo {
+            argsz: size_of::<DeviceInfo>() as u32,
+            ..Default::default()
+        };
+        // SAFETY: The kernel writes at most `argsz` bytes to `info`.
+        ioctl_result(unsafe {
+            ioctl_with_mut_ref(&file, vfio_ioctl(VFIO_DEVICE_GET_INFO), &mut info)
+        })
+        .map_err(Error::Io)?;
+
+        let mut regions = Vec::new();
+        for index in 0..info.num_regions {
+            let mut region = RegionInfo {
+                argsz: size_of::<RegionInfo>() as u32,
+                index,
+                ..Default::default()
+            };
+            // SAFETY: The kernel writes at most `argsz` bytes to `region`.
+            ioctl_result(unsafe {
+                ioctl_with_mut_ref(&file, vfio_ioctl(VFIO_DEVICE_GET_REGION_INFO), &mut region)
+            })
+            .map_err(Error::Io)?;
+            regions.push(VfioRegionInfo {
+                index,
+                flags: region.flags,
+                size: region.size,
+                offset: region.offset,
+            });
+        }
+
+        let mut irqs = Vec::new();
+        for index in 0..info.num_irqs {
+            let mut irq = IrqInfo {
+                argsz: size_of::<IrqInfo>() as u32,
+                index,
+                ..Default::default()
+            };
+            // SAFETY: The kernel writes at most `argsz` bytes to `irq`.
+            ioctl_result(unsafe {
+                ioctl_with_mut_ref(&file, vfio_ioctl(VFIO_DEVICE_GET_IRQ_INFO), &mut irq)
+            })
+            .map_err(Error::Io)?;
+            irqs.push(irq.count);
+        }
+
+        Ok(VfioDevice {
+            file,
+            regions,
+            irqs,
+        })
+    }
+
+    /// Return the description of the regions of the device.
+    pub fn regions(&self) -> &[VfioRegionInfo] {
+        &self.regions
+    }
+
+    /// Return the description of the region `index`.
+    pub fn region(&self, index: u32) -> Option<&VfioRegionInfo> {
+        self.regions.get(index as usize)
+    }
+
+    /// Return the number of interrupts of the IRQ `index`.
+    pub fn irq_count(&self, index: u32) -> u32 {
+        self.irqs.get(index as usize).copied().unwrap_or(0)
+    }
+
+    fn region_offset(&self, index: u32, offset: u64, len: usize, write: bool) -> io::Result<u64> {
+        let region = self
+            .region(index)
+            .filter(|region| {
+                if write {
+                    region.writable()
+                } else {
+                    region.readable()
+                }
+            })
+            .filter(|region| offset.saturating_add(len as u64) <= region.size)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+        Ok(region.offset + offset)
+    }
+
+    /// Read `data` from `offset` within the region `index`.
+    pub fn read_region(&self, index: u32, offset: u64, data: &mut [u8]) -> io::Result<()> {
+        let offset = self.region_offset(index, offset, data.len(), false)?;
+        self.file.read_exact_at(data, offset)
+    }
+
+    /// Write `data` at `offset` within the region `index`.
+    pub fn write_region(&self, index: u32, offset: u64, data: &[u8]) -> io::Result<()> {
+        let offset = self.region_offset(index, offset, data.len(), true)?;
+        self.file.write_all_at(data, offset)
+    }
+
+    /// Reset the device.
+    pub fn reset(&self) -> io::Result<()> {
+        // SAFETY: The ioctl has no argument.
+        ioctl_result(unsafe { ioctl(&self.file, vfio_ioctl(VFIO_DEVICE_RESET)) })
+    }
+
+    fn set_irqs(&self, index: u32, flags: u32, data: &[u32]) -> io::Result<()> {
+        // The `vfio_irq_set` header is followed by the data of each interrupt.
+        let argsz = (IRQ_SET_HEADER_LEN + data.len()) * 4;
+        let mut buf = vec![argsz as u32, flags, index, 0, data.len() as u32];
+        buf.extend_from_slice(data);
+        // SAFETY: `buf` holds `argsz` bytes, which the kernel only reads.
+        ioctl_result(unsafe {
+            ioctl_with_ptr(&self.file, vfio_ioctl(VFIO_DEVICE_SET_IRQS), buf.as_ptr())
+        })
+    }
+
+    /// Have the kernel signal the interrupts `0..eventfds.len()` of the IRQ `index` through
+    /// `eventfds`.
+    pub fn enable_irq(&self, index: u32, eventfds: &[&EventFd]) -> io::Result<()> {
+        self.set_irqs(
+            index,
+            VFIO_IRQ_SET_DATA_EVENTFD | VFIO_IRQ_SET_ACTION_TRIGGER,
+            &eventfds
+                .iter()
+                .map(|eventfd| eventfd.as_raw_fd() as u32)
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    /// Stop signalling the interrupts of the IRQ `index`.
+    pub fn disable_irq(&self, index: u32) -> io::Result<()> {
+        self.set_irqs(
+            index,
+            VFIO_IRQ_SET_DATA_NONE | VFIO_IRQ_SET_ACTION_TRIGGER,
+            &[],
+        )
+    }
+
+    /// Unmask the IRQ `index`, which the kernel masks after signalling a level triggered
+    /// interrupt.
+    pub fn unmask_irq(&self, index: u32) -> io::Result<()> {
+        // A single interrupt, without data.
+        let argsz = IRQ_SET_HEADER_LEN as u32 * 4;
+        let buf = [
+            argsz,
+            VFIO_IRQ_SET_DATA_NONE | VFIO_IRQ_SET_ACTION_UNMASK,
+            index,
+            0,
+            1,
+        ];
+        // SAFETY: `buf` holds `argsz` bytes, which the kernel only reads.
+        ioctl_result(unsafe {
+            ioctl_with_ptr(&self.file, vfio_ioctl(VFIO_DEVICE_SET_IRQS), buf.as_ptr())
+        })
+    }
+}
+
+impl AsRawFd for VfioDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+/// A region of an assigned device, registered on the MMIO or PIO bus. The accesses are
+/// forwarded to the kernel, and failed reads return all ones.
+pub struct VfioRegion {
+    device: Arc<VfioDevice>,
+    index: u32,
+    msix: Option<(MsixCap, Arc<MsixTable>)>,
+}
+
+impl VfioRegion {
+    /// Expose the region `index` of `device`.
+    pub fn new(device: Arc<VfioDevice>, index: u32) -> Self {
+        VfioRegion {
+            device,
+            index,
+            msix: None,
+        }
+    }
+
+    // Return the emulated MSI-X structure covering `offset`, if any.
+    fn msix(&self, offset: u64) -> Option<(Arc<MsixTable>, u64, bool)> {
+        let (cap, table) = self.msix.as_ref()?;
+        let bar = self.index - VFIO_PCI_BAR0_REGION_INDEX;
+        for (bir, range, is_pba) in [
+            (cap.table_bir(), MsixTable::table_range(cap, 0).ok()?, false),
+            (cap.pba_bir(), MsixTable::pba_range(cap, 0).ok()?, true),
+        ] {
+            if u32::from(bir) == bar && range.base().0 <= offset && offset <= range.last().0 {
+                return Some((table.clone(), offset - range.base().0, is_pba));
+            }
+        }
+        None
+    }
+
+    fn read(&self, offset: u64, data: &mut [u8]) {
+        match self.msix(offset) {
+            Some((table, offset, false)) => table.read_table(offset, data),
+            Some((table, offset, true)) => {
+                MsixPba(table).mmio_read(MmioAddress(0), MmioOffset(offset), data)
+            }
+            None => {
+                if self.device.read_region(self.index, offset, data).is_err() {
+                    data.iter_mut().for_each(|byte| *byte = 0xff);
+                }
+            }
+        }
+    }
+
+    fn write(&self, offset: u64, data: &[u8]) {
+        match self.msix(offset) {
+            Some((table, offset, false)) => {
+                table.mmio_write(MmioAddress(0), MmioOffset(offset), data)
+            }
+            Some((_, _, true)) => {}
+            None => {
+                // There is no way to report the failure to the guest.
+                let _ = self.device.write_region(self.index, offset, data);
+            }
+        }
+    }
+}
+
+impl DeviceMmio for VfioRegion {
+    fn mmio_read(&self, _base: MmioAddress, offset: MmioOffset, data: &mut [u8]) {
+        self.read(offset.raw(), data)
+    }
+
+    fn mmio_write(&self, _base: MmioAddress, offset: MmioOffset, data: &[u8]) {
+        self.write(offset.raw(), data)
+    }
+}
+
+impl DevicePio for VfioRegion {
+    fn pio_read(&self, _base: PioAddress, offset: PioOffset, data: &mut [u8]) {
+        self.read(u64::from(offset), data)
+    }
+
+    fn pio_write(&self, _base: PioAddress, offset: PioOffset, data: &[u8]) {
+        self.write(u64::from(offset), data)
+    }
+}
+
+/// An assigned PCI function, to be plugged into a `PciBus`.
+pub struct VfioPciDevice {
+    device: Arc<VfioDevice>,
+    config: PciConfiguration,
+    msix: Option<(MsixCap, usize, Arc<MsixTable>)>,
+    msix_cap: Option<(MsixCap, usize)>,
+    msix_eventfds: Vec<EventFd>,
+    intx: Option<(EventFd, LineInterrupt)>,
+}
+
+impl VfioPciDevice {
+    /// Set up the function `device`. Its BARs are discovered from the region info of the
+    /// device, and placed at the addresses returned by `bar_address`.
+    pub fn new<F>(device: Arc<VfioDevice>, mut bar_address: F) -> Result<Self, Error>
+    where
+        F: FnMut(&PciBarConfiguration) -> Option<u64>,
+    {
+        if device.region(VFIO_PCI_CONFIG_REGION_INDEX).is_none() {
+            return Err(Error::NoConfigRegion);
+        }
+        let read_config = |offset: u64, data: &mut [u8]| {
+            device
+                .read_region(VFIO_PCI_CONFIG_REGION_INDEX, offset, data)
+                .map_err(Error::Io)
+        };
+        let mut dword = [0; 4];
+        read_config(0, &mut dword)?;
+        let id = u32::from_le_bytes(dword);
+        read_config(8, &mut dword)?;
+        let class = u32::from_le_bytes(dword);
+        let mut config = PciConfiguration::new(
+            id as u16,
+            (id >> 16) as u16,
+            PciClassCode {
+                class: (class >> 24) as u8,
+                subclass: (class >> 16) as u8,
+                prog_if: (class >> 8) as u8,
+            },
+        );
+
+        let mut idx = 0;
+        while idx < NUM_BAR_REGS {
+            let size = device
+                .region(VFIO_PCI_BAR0_REGION_INDEX + idx as u32)
+                .map_or(0, |region| region.size);
+            read_config((BAR0_REG + idx) as u64 * 4, &mut dword)?;
+            let bar = u32::from_le_bytes(dword);
+            let region_type = if bar & 1 != 0 {
+                PciBarRegionType::IoRegion
+            } else if bar & 0x6 == 0x4 {
+                PciBarRegionType::Memory64BitRegion
+            } else {
+                PciBarRegionType::Memory32BitRegion
+            };
+            if size != 0 {
+                let bar_config = PciBarConfiguration::new(idx, size, region_type, bar & 0x8 != 0);
+                let addr = bar_address(&bar_config).ok_or(Error::BarAddress(idx))?;
+                config
+                    .add_bar(&bar_config.with_address(addr))
+                    .map_err(Error::Pci)?;
+            }
+            idx += if region_type == PciBarRegionType::Memory64BitRegion {
+                2
+            } else {
+                1
+            };
+        }
+
+        let msix_cap = Self::find_msix(&device)?;
+        Ok(VfioPciDevice {
+            device,
+            config,
+            msix: None,
+            msix_cap,
+            msix_eventfds: Vec::new(),
+            intx: None,
+        })
+    }
+
+    // Walk the capability list to find the MSI-X capability.
+    fn find_msix(device: &VfioDevice) -> Result<Option<(MsixCap, usize)>, Error> {
+        let read = |offset: u64, data: &mut [u8]| {
+            device
+                .read_region(VFIO_PCI_CONFIG_REGION_INDEX, offset, data)
+                .map_err(Error::Io)
+        };
+        let mut dword = [0; 4];
+        read(4, &mut dword)?;
+        if u32::from_le_bytes(dword) & PCI_STATUS_CAP_LIST == 0 {
+            return Ok(None);
+        }
+        let mut ptr = [0];
+        read(PCI_CAPABILITY_LIST, &mut ptr)?;
+        // Bound the walk, in case the list loops.
+        for _ in 0..48 {
+            let offset = u64::from(ptr[0] & !0x3);
+            if offset == 0 {
+                break;
+            }
+            read(offset, &mut dword)?;
+            if dword[0] == PCI_CAP_ID_MSIX {
+                let msg_ctl = u16::from_le_bytes([dword[2], dword[3]]);
+                read(offset + 4, &mut dword)?;
+                let table = u32::from_le_bytes(dword);
+                read(offset + 8, &mut dword)?;
+                let pba = u32::from_le_bytes(dword);
+                let cap = MsixCap::new(
+                    (msg_ctl & 0x7ff) + 1,
+                    (table & 0x7) as u8,
+                    table & !0x7,
+                    (pba & 0x7) as u8,
+                    pba & !0x7,
+                );
+                return Ok(Some((cap, offset as usize)));
+            }
+            ptr[0] = dword[1];
+        }
+        Ok(None)
+    }
+
+    /// Emulate the MSI-X table of the function, firing its vectors through `senders` (see
+    /// `MsixTable::new`). Without it, the guest can't use MSI-X.
+    pub fn with_msix_senders(mut self, senders: Vec<Arc<dyn MsiSender>>) -> Result<Self, Error> {
+        if let Some((cap, offset)) = self.msix_cap {
+            self.msix_eventfds = (0..cap.table_size())
+                .map(|_| EventFd::new(EFD_NONBLOCK))
+                .collect::<io::Result<_>>()
+                .map_err(Error::Io)?;
+            let table = Arc::new(MsixTable::new(cap.table_size(), senders).map_err(Error::Pci)?);
+            self.msix = Some((cap, offset, table));
+        }
+        Ok(self)
+    }
+
+    /// Forward the legacy interrupt of the function to `line`.
+    pub fn with_intx(mut self, line: LineInterrupt) -> Result<Self, Error> {
+        if self.device.irq_count(VFIO_PCI_INTX_IRQ_INDEX) > 0 {
+            let eventfd = EventFd::new(EFD_NONBLOCK).map_err(Error::Io)?;
+            self.device
+                .enable_irq(VFIO_PCI_INTX_IRQ_INDEX, &[&eventfd])
+                .map_err(Error::Io)?;
+            self.intx = Some((eventfd, line));
+        }
+        Ok(self)
+    }
+
+    /// Return the underlying device.
+    pub fn device(&self) -> &Arc<VfioDevice> {
+        &self.device
+    }
+
+    /// Return the emulated MSI-X table, if any.
+    pub fn msix_table(&self) -> Option<&Arc<MsixTable>> {
+        self.msix.as_ref().map(|(_, _, table)| table)
+    }
+
+    /// Return the eventfds signalled by the kernel for the MSI-X vectors, by vector.
+    pub fn msix_eventfds(&self) -> &[EventFd] {
+        &self.msix_eventfds
+    }
+
+    /// Handle the signalling of the eventfd of the MSI-X vector `vector`.
+    pub fn handle_msix_event(&self, vector: usize) -> io::Result<()> {
+        if let (Some(eventfd), Some(table)) = (self.msix_eventfds.get(vector), self.msix_table()) {
+            eventfd.read()?;
+            table.trigger(vector)?;
+        }
+        Ok(())
+    }
+
+    /// Return the eventfd signalled by the kernel for the legacy interrupt, if forwarded.
+    pub fn intx_eventfd(&self) -> Option<&EventFd> {
+        self.intx.as_ref().map(|(eventfd, _)| eventfd)
+    }
+
+    /// Handle the signalling of the legacy interrupt eventfd, by asserting the line.
+    pub fn handle_intx_event(&self) -> io::Result<()> {
+        if let Some((eventfd, line)) = self.intx.as_ref() {
+            eventfd.read()?;
+            line.assert();
+        }
+        Ok(())
+    }
+
+    /// Handle the end of interrupt of the legacy interrupt, by deasserting the line and
+    /// letting the kernel signal it again.
+    pub fn intx_eoi(&self) -> io::Result<()> {
+        if let Some((_, line)) = self.intx.as_ref() {
+            line.deassert();
+            self.device.unmask_irq(VFIO_PCI_INTX_IRQ_INDEX)?;
+        }
+        Ok(())
+    }
+
+    /// Return the device exposing the BAR `idx` to the guest, with the MSI-X structures it
+    /// holds emulated.
+    pub fn bar_region(&self, idx: usize) -> VfioRegion {
+        let mut region =
+            VfioRegion::new(self.device.clone(), VFIO_PCI_BAR0_REGION_INDEX + idx as u32);
+        region.msix = self
+            .msix
+            .as_ref()
+            .map(|(cap, _, table)| (*cap, table.clone()));
+        region
+    }
+
+    /// Register the BARs of the function on the buses of `io_mgr`, at their current
+    /// addresses. The memory BARs which the kernel allows to map, and which don't hold the
+    /// MSI-X structures, are also marked as mappable.
+    pub fn register_bars(&self, io_mgr: &mut IoManager) -> Result<(), Error> {
+        let register = |e| Error::Register(device_manager::Error::Bus(e));
+        for idx in 0..NUM_BAR_REGS {
+            let bar = match self.config.bar(idx) {
+                Some(bar) => bar,
+                None => continue,
+            };
+            let device = Arc::new(self.bar_region(idx));
+            if bar.region_type() == PciBarRegionType::IoRegion {
+                let range = PioRange::new(
+                    PioAddress(bar.address() as PioAddressValue),
+                    bar.size() as PioAddressValue,
+                )
+                .map_err(register)?;
+                io_mgr.register_pio(range, device).map_err(register)?;
+            } else {
+                let range =
+                    MmioRange::new(MmioAddress(bar.address()), bar.size()).map_err(register)?;
+                let holds_msix = self.msix_cap.is_some_and(|(cap, _)| {
+                    usize::from(cap.table_bir()) == idx || usize::from(cap.pba_bir()) == idx
+                });
+                match self
+                    .device
+                    .region(VFIO_PCI_BAR0_REGION_INDEX + idx as u32)
+                    .filter(|region| region.mappable() && !holds_msix)
+                {
+                    Some(region) => {
+                        let mappable = Mappable::new(self.device.clone(), region.offset);
+                        io_mgr
+                            .register_mmio_mappable(range, device, mappable)
+                            .map_err(Error::Register)?;
+                    }
+                    None => io_mgr.register_mmio(range, device).map_err(register)?,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Track the MSI-X enable and function mask bits written by the guest.
+    fn update_msix(&self) -> io::Result<()> {
+        let (offset, table) = match self.msix.as_ref() {
+            Some((_, offset, table)) => (*offset, table),
+            None => return Ok(()),
+        };
+        let mut msg_ctl = [0; 2];
+        self.device.read_region(
+            VFIO_PCI_CONFIG_REGION_INDEX,
+            offset as u64 + 2,
+            &mut msg_ctl,
+        )?;
+        let was_enabled = table.enabled();
+        table.set_message_control(u16::from_le_bytes(msg_ctl))?;
+        match (was_enabled, table.enabled()) {
+            (false, true) => {
+                let eventfds: Vec<&EventFd> = self.msix_eventfds.iter().collect();
+                self.device.enable_irq(VFIO_PCI_MSIX_IRQ_INDEX, &eventfds)
+            }
+            (true, false) => self.device.disable_irq(VFIO_PCI_MSIX_IRQ_INDEX),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl PciDevice for VfioPciDevice {
+    fn config(&self) -> &PciConfiguration {
+        &self.config
+    }
+
+    fn config_mut(&mut self) -> &mut PciConfiguration {
+        &mut self.config
+    }
+
+    fn read_config_register(&self, reg_idx: usize) -> u32 {
+        // The BARs are emulated, and the option ROM is not exposed.
+        if (BAR0_REG..BAR0_REG + NUM_BAR_REGS).contains(&reg_idx) || reg_idx == ROM_BAR_REG {
+            return self.config.read_reg(reg_idx);
+        }
+        let mut data = [0xff; 4];
+        let _ =
+            self.device
+                .read_region(VFIO_PCI_CONFIG_REGION_INDEX, reg_idx as u64 * 4, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    fn write_config_register(
+        &mut self,
+        reg_idx: usize,
+        offset: u64,
+        data: &[u8],
+    ) -> Option<BarReprogrammingParams> {
+        if (BAR0_REG..BAR0_REG + NUM_BAR_REGS).contains(&reg_idx) || reg_idx == ROM_BAR_REG {
+            return self.config.write_reg(reg_idx, offset, data);
+        }
+        // There is no way to report the failures to the guest.
+        let _ = self.device.write_region(
+            VFIO_PCI_CONFIG_REGION_INDEX,
+            reg_idx as u64 * 4 + offset,
+            data,
+        );
+        if let Some((_, cap_offset, _)) = self.msix.as_ref() {
+            if reg_idx == (cap_offset + 2) / 4 {
+                let _ = self.update_msix();
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use vmm_sys_util::tempfile::TempFile;
+
+    use crate::msi::MsiMessage;
+
+    // A device backed by a regular file, with the configuration space in region 7 and a
+    // 64-bit memory BAR 0 holding the MSI-X table at 0x1000 and the PBA at 0x1800.
+    fn test_device() -> Arc<VfioDevice> {
+        let file = TempFile::new().unwrap().into_file();
+        let mut config = [0u8; 256];
+        config[0..4].copy_from_slice(&0x1234_8086u32.to_le_bytes());
+        config[4..8].copy_from_slice(&PCI_STATUS_CAP_LIST.to_le_bytes());
+        config[8..12].copy_from_slice(&0x0200_0001u32.to_le_bytes());
+        config[0x10..0x14].copy_from_slice(&0xcu32.to_le_bytes());
+        config[0x34] = 0x40;
+        config[0x40..0x44].copy_from_slice(&[0x05, 0x50, 0, 0]);
+        config[0x50..0x54].copy_from_slice(&[PCI_CAP_ID_MSIX, 0, 3, 0]);
+        config[0x54..0x58].copy_from_slice(&0x1000u32.to_le_bytes());
+        config[0x58..0x5c].copy_from_slice(&0x1800u32.to_le_bytes());
+        file.write_all_at(&config, 0x10_0000).unwrap();
+        file.write_all_at(&[0xab; 4], 0x10).unwrap();
+
+        let rw = VFIO_REGION_INFO_FLAG_READ | VFIO_REGION_INFO_FLAG_WRITE;
+        let mut regions = vec![VfioRegionInfo::default(); 8];
+        regions[0] = VfioRegionInfo {
+            index: 0,
+            flags: rw,
+            size: 0x4000,
+            offset: 0,
+        };
+        regions[7] = VfioRegionInfo {
+            index: 7,
+            flags: rw,
+            size: 256,
+            offset: 0x10_0000,
+        };
+        Arc::new(VfioDevice {
+            file,
+            regions,
+            irqs: vec![1, 1, 4],
+        })
+    }
+
+    #[test]
+    fn test_vfio_pci_device() {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let sender_sent = sent.clone();
+        let sender = move |msg| {
+            sender_sent.lock().unwrap().push(msg);
+            Ok(())
+        };
+        let mut pci_dev = VfioPciDevice::new(test_device(), |bar| {
+            assert_eq!(bar.region_type(), PciBarRegionType::Memory64BitRegion);
+            Some(0x1_0000_0000)
+        })
+        .unwrap()
+        .with_msix_senders(vec![Arc::new(sender)])
+        .unwrap();
+        assert_eq!(pci_dev.msix_eventfds().len(), 4);
+
+        // The identity comes from the kernel, and the BARs are emulated.
+        assert_eq!(pci_dev.read_config_register(0), 0x1234_8086);
+        assert_eq!(pci_dev.read_config_register(4), 0x0000_000c);
+        assert_eq!(pci_dev.read_config_register(5), 1);
+        assert!(pci_dev
+            .write_config_register(5, 0, &2u32.to_le_bytes())
+            .is_some());
+
+        let mut io_mgr = IoManager::new();
+        pci_dev.register_bars(&mut io_mgr).unwrap();
+        let mut data = [0; 4];
+        io_mgr
+            .mmio_read(MmioAddress(0x2_0000_0010), &mut data)
+            .unwrap();
+        assert_eq!(data, [0xab; 4]);
+
+        // Program and unmask vector 2 through the emulated table, then enable MSI-X.
+        io_mgr
+            .mmio_write(MmioAddress(0x2_0000_1020), &0xfee0_0000u32.to_le_bytes())
+            .unwrap();
+        io_mgr
+            .mmio_write(MmioAddress(0x2_0000_1028), &0x30u32.to_le_bytes())
+            .unwrap();
+        io_mgr
+            .mmio_write(MmioAddress(0x2_0000_102c), &0u32.to_le_bytes())
+            .unwrap();
+        // Enabling the vectors in the kernel fails on a regular file.
+        pci_dev.write_config_register(0x50 / 4, 3, &[0x80]);
+        assert!(pci_dev.msix_table().unwrap().enabled());
+
+        pci_dev.msix_eventfds()[2].write(1).unwrap();
+        pci_dev.handle_msix_event(2).unwrap();
+        assert_eq!(
+            *sent.lock().unwrap(),
+            vec![MsiMessage::new(0xfee0_0000, 0x30)]
+        );
+    }
+}
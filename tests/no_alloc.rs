@@ -0,0 +1,137 @@
+// Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+// Check that dispatching accesses doesn't allocate. The check lives in its own test binary,
+// since it needs to replace the global allocator.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::hint::black_box;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use vm_device::bus::{
+    AccessSize, Bus, MmioAddress, MmioOffset, MmioRange, PioAddress, PioOffset, PioRange,
+};
+use vm_device::device_manager::{IoManager, MmioManager, PioManager};
+use vm_device::{DeviceMmio, DevicePio};
+
+// Counts the allocations of the current thread, so the tests running concurrently don't
+// interfere with each other.
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<u64> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+// Return the number of allocations performed by `f`.
+fn allocations<F: FnMut()>(mut f: F) -> u64 {
+    let before = ALLOCATIONS.with(|count| count.get());
+    f();
+    ALLOCATIONS.with(|count| count.get()) - before
+}
+
+#[derive(Default)]
+struct Register(AtomicU64);
+
+impl Register {
+    fn read(&self, data: &mut [u8]) {
+        let value = self.0.load(Ordering::Relaxed).to_le_bytes();
+        let len = data.len().min(value.len());
+        data[..len].copy_from_slice(&value[..len]);
+    }
+
+    fn write(&self, data: &[u8]) {
+        self.0.store(u64::from(data[0]), Ordering::Relaxed);
+    }
+}
+
+impl DeviceMmio for Register {
+    fn mmio_read(&self, _base: MmioAddress, _offset: MmioOffset, data: &mut [u8]) {
+        self.read(data);
+    }
+
+    fn mmio_write(&self, _base: MmioAddress, _offset: MmioOffset, data: &[u8]) {
+        self.write(data);
+    }
+}
+
+impl DevicePio for Register {
+    fn pio_read(&self, _base: PioAddress, _offset: PioOffset, data: &mut [u8]) {
+        self.read(data);
+    }
+
+    fn pio_write(&self, _base: PioAddress, _offset: PioOffset, data: &[u8]) {
+        self.write(data);
+    }
+}
+
+#[test]
+fn test_bus_access_no_alloc() {
+    // Make sure the allocations are actually counted.
+    assert_eq!(allocations(|| drop(black_box(vec![0u8; 16]))), 1);
+
+    let mut bus = Bus::new();
+    for idx in 0..16 {
+        let range = MmioRange::new(MmioAddress(idx * 0x1000), 0x1000).unwrap();
+        bus.register(range, Register::default()).unwrap();
+    }
+    let size = AccessSize::new(4).unwrap();
+    let count = allocations(|| {
+        for idx in 0..16 {
+            let addr = MmioAddress(idx * 0x1000 + 8);
+            bus.access(addr, size)
+                .unwrap()
+                .mmio_write(addr, MmioOffset(8), &[1]);
+            assert!(bus.check_access(addr, 4).is_ok());
+        }
+        assert!(bus.access(MmioAddress(0x10_0000), size).is_err());
+    });
+    assert_eq!(count, 0);
+}
+
+#[test]
+fn test_dispatch_no_alloc() {
+    let mut io_mgr = IoManager::new();
+    let device = Arc::new(Register::default());
+    io_mgr
+        .register_mmio(
+            MmioRange::new(MmioAddress(0xd000_0000), 0x1000).unwrap(),
+            device.clone(),
+        )
+        .unwrap();
+    io_mgr
+        .register_pio(PioRange::new(PioAddress(0x3f8), 8).unwrap(), device)
+        .unwrap();
+
+    let mut data = [0u8; 4];
+    let count = allocations(|| {
+        io_mgr.mmio_write(MmioAddress(0xd000_0010), &[5]).unwrap();
+        io_mgr
+            .mmio_read(MmioAddress(0xd000_0010), &mut data)
+            .unwrap();
+        io_mgr.pio_write(PioAddress(0x3f8), &[6]).unwrap();
+        io_mgr.pio_read(PioAddress(0x3f8), &mut data).unwrap();
+    });
+    assert_eq!(count, 0);
+    assert_eq!(data, [6, 0, 0, 0]);
+}